@@ -32,13 +32,13 @@
 
 use failure::Error;
 use libc;
-use log::warn;
 use parking_lot::Mutex;
 use std::mem;
 use std::sync::{mpsc, Arc};
 use std::thread;
 use std::time::Duration as StdDuration;
 use time::{Duration, Timespec};
+use tracing::warn;
 
 /// Abstract interface to the system clocks. This is for testability.
 pub trait Clocks: Send + Sync + 'static {
@@ -0,0 +1,488 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Cryptographic primitives used by the database, with a choice of backend.
+//!
+//! By default these are pure-Rust implementations, which simplify static and cross-compiled
+//! builds (such as for ARM-based NAS devices). The `openssl` feature of this crate switches SHA-1
+//! and random number generation to OpenSSL instead. BLAKE3 always uses the pure-Rust `blake3`
+//! crate, which has its own SIMD implementations, and SHA-256 and SHA-512 (used for export
+//! manifests, OIDC, and SAML) always use the pure-Rust `sha2` crate. MD5 (used only for RTSP
+//! digest authentication) always uses the pure-Rust `md-5` crate. AES-GCM and HMAC-SHA256 (used
+//! for sample file encryption) always use the pure-Rust `aes`, `ghash`, and `hmac` crates;
+//! OpenSSL's AEAD interface can't decrypt from an arbitrary offset as described in `Aes256Gcm`.
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockEncrypt, NewBlockCipher};
+use failure::Error;
+use ghash::universal_hash::{NewUniversalHash, UniversalHash};
+use std::cmp;
+
+/// A SHA-1 hasher.
+pub struct Sha1(imp::Sha1);
+
+impl Sha1 {
+    pub fn new() -> Self {
+        Sha1(imp::Sha1::new())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data)
+    }
+
+    pub fn finish(self) -> [u8; 20] {
+        self.0.finish()
+    }
+}
+
+impl Default for Sha1 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the SHA-1 digest of `data`.
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h = Sha1::new();
+    h.update(data);
+    h.finish()
+}
+
+/// A BLAKE3 hasher, as used for sample file digests. This is much faster than SHA-1.
+pub struct Blake3(blake3::Hasher);
+
+impl Blake3 {
+    pub fn new() -> Self {
+        Blake3(blake3::Hasher::new())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> [u8; 32] {
+        *self.0.finalize().as_bytes()
+    }
+}
+
+impl Default for Blake3 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the BLAKE3 digest of `data`.
+pub fn blake3(data: &[u8]) -> [u8; 32] {
+    *blake3::hash(data).as_bytes()
+}
+
+/// A SHA-256 hasher, as used for the checksums in export manifests.
+pub struct Sha256(sha2::Sha256);
+
+impl Sha256 {
+    pub fn new() -> Self {
+        use sha2::Digest;
+        Sha256(sha2::Sha256::new())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        use sha2::Digest;
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> [u8; 32] {
+        use sha2::Digest;
+        let mut out = [0u8; 32];
+        out.copy_from_slice(&self.0.finalize()[..]);
+        out
+    }
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the SHA-256 digest of `data`.
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut h = Sha256::new();
+    h.update(data);
+    h.finish()
+}
+
+/// Returns the SHA-512 digest of `data`.
+pub fn sha512(data: &[u8]) -> [u8; 64] {
+    use sha2::Digest;
+    let mut out = [0u8; 64];
+    out.copy_from_slice(&sha2::Sha512::digest(data)[..]);
+    out
+}
+
+/// Returns the MD5 digest of `data`. This is broken as a cryptographic hash; it's here only for
+/// protocols which require it, such as HTTP digest authentication.
+pub fn md5(data: &[u8]) -> [u8; 16] {
+    use md5::Digest;
+    let mut out = [0u8; 16];
+    out.copy_from_slice(&md5::Md5::digest(data)[..]);
+    out
+}
+
+/// Returns the HMAC-SHA256 of `data` under `key`.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    use hmac::{Mac, NewMac};
+    let mut m = hmac::Hmac::<sha2::Sha256>::new_varkey(key).expect("HMAC takes any key length");
+    m.update(data);
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&m.finalize().into_bytes());
+    out
+}
+
+/// AES-256 in Galois/Counter Mode with a 96-bit nonce and no additional authenticated data.
+///
+/// Rather than the usual one-shot AEAD interface, this exposes GCM's structure: the ciphertext is
+/// the plaintext XORed with a counter-mode keystream, which `apply_keystream` can produce from any
+/// offset, and the tag is a function of the whole ciphertext, which `Authenticator` computes
+/// incrementally. This allows a large file to be written as it arrives and read at random, with
+/// the tag verified only when the whole file is read.
+pub struct Aes256Gcm {
+    cipher: aes::Aes256,
+
+    /// The GHASH key, `E(K, 0^128)`.
+    h: [u8; 16],
+
+    /// The pre-counter block: the nonce followed by a 32-bit counter of 1.
+    j0: [u8; 16],
+}
+
+impl Aes256Gcm {
+    pub fn new(key: &[u8; 32], nonce: &[u8; 12]) -> Self {
+        let cipher = aes::Aes256::new(GenericArray::from_slice(key));
+        let mut j0 = [0u8; 16];
+        j0[..12].copy_from_slice(nonce);
+        j0[15] = 1;
+        let mut g = Aes256Gcm {
+            cipher,
+            h: [0u8; 16],
+            j0,
+        };
+        let mut h = GenericArray::clone_from_slice(&[0u8; 16]);
+        g.cipher.encrypt_block(&mut h);
+        g.h.copy_from_slice(&h);
+        g
+    }
+
+    /// Returns the encryption of the counter block with the given counter value.
+    fn counter_block(&self, counter: u32) -> [u8; 16] {
+        let mut b = GenericArray::clone_from_slice(&self.j0);
+        b[12..].copy_from_slice(&counter.to_be_bytes());
+        self.cipher.encrypt_block(&mut b);
+        let mut out = [0u8; 16];
+        out.copy_from_slice(&b);
+        out
+    }
+
+    /// Encrypts or decrypts `buf` in place, as bytes `offset..offset+buf.len()` of the message.
+    /// This doesn't authenticate anything.
+    pub fn apply_keystream(&self, offset: u64, buf: &mut [u8]) {
+        let mut pos = offset;
+        let mut i = 0;
+        while i < buf.len() {
+            // Block 0 of the message uses counter 2; counter 1 is reserved for the tag.
+            let keystream = self.counter_block(((pos / 16) as u32).wrapping_add(2));
+            let skip = (pos % 16) as usize;
+            let n = cmp::min(16 - skip, buf.len() - i);
+            for (b, k) in buf[i..i + n].iter_mut().zip(&keystream[skip..skip + n]) {
+                *b ^= k;
+            }
+            i += n;
+            pos += n as u64;
+        }
+    }
+
+    /// Returns an `Authenticator` for computing the tag of a message encrypted with this key and
+    /// nonce.
+    pub fn authenticator(&self) -> Authenticator {
+        Authenticator {
+            ghash: ghash::GHash::new(GenericArray::from_slice(&self.h)),
+            partial: [0u8; 16],
+            partial_len: 0,
+            len: 0,
+            mask: self.counter_block(1),
+        }
+    }
+}
+
+/// Incrementally computes the GCM tag of a ciphertext; see `Aes256Gcm`.
+pub struct Authenticator {
+    ghash: ghash::GHash,
+
+    /// The start of an incomplete block, which GHASH can't take until the rest arrives.
+    partial: [u8; 16],
+    partial_len: usize,
+
+    /// The total length of the ciphertext so far, in bytes.
+    len: u64,
+
+    /// The encrypted pre-counter block, XORed with the GHASH output to produce the tag.
+    mask: [u8; 16],
+}
+
+impl Authenticator {
+    /// Adds the next bytes of ciphertext.
+    pub fn update(&mut self, mut ciphertext: &[u8]) {
+        self.len += ciphertext.len() as u64;
+        if self.partial_len > 0 {
+            let n = cmp::min(16 - self.partial_len, ciphertext.len());
+            self.partial[self.partial_len..self.partial_len + n].copy_from_slice(&ciphertext[..n]);
+            self.partial_len += n;
+            ciphertext = &ciphertext[n..];
+            if self.partial_len < 16 {
+                return;
+            }
+            self.ghash.update(GenericArray::from_slice(&self.partial));
+            self.partial_len = 0;
+        }
+        let mut blocks = ciphertext.chunks_exact(16);
+        for b in &mut blocks {
+            self.ghash.update(GenericArray::from_slice(b));
+        }
+        let rest = blocks.remainder();
+        self.partial[..rest.len()].copy_from_slice(rest);
+        self.partial_len = rest.len();
+    }
+
+    /// Returns the tag of the complete ciphertext.
+    pub fn finish(mut self) -> [u8; 16] {
+        if self.partial_len > 0 {
+            self.ghash.update_padded(&self.partial[..self.partial_len]);
+        }
+
+        // The final block holds the bit lengths of the (empty) additional data and ciphertext.
+        let mut lens = [0u8; 16];
+        lens[8..].copy_from_slice(&(self.len * 8).to_be_bytes());
+        self.ghash.update(GenericArray::from_slice(&lens));
+        let mut tag = self.mask;
+        for (t, s) in tag.iter_mut().zip(self.ghash.finalize().into_bytes().iter()) {
+            *t ^= s;
+        }
+        tag
+    }
+}
+
+/// Fills `buf` with cryptographically secure random bytes.
+pub fn rand_bytes(buf: &mut [u8]) -> Result<(), Error> {
+    imp::rand_bytes(buf)
+}
+
+#[cfg(not(feature = "openssl"))]
+mod imp {
+    use failure::{format_err, Error};
+    use sha1::Digest;
+
+    pub struct Sha1(sha1::Sha1);
+
+    impl Sha1 {
+        pub fn new() -> Self {
+            Sha1(sha1::Sha1::new())
+        }
+
+        pub fn update(&mut self, data: &[u8]) {
+            self.0.update(data)
+        }
+
+        pub fn finish(self) -> [u8; 20] {
+            let mut out = [0u8; 20];
+            out.copy_from_slice(&self.0.finalize()[..]);
+            out
+        }
+    }
+
+    pub fn rand_bytes(buf: &mut [u8]) -> Result<(), Error> {
+        getrandom::getrandom(buf).map_err(|e| format_err!("unable to get random bytes: {}", e))
+    }
+}
+
+#[cfg(feature = "openssl")]
+mod imp {
+    use failure::Error;
+
+    pub struct Sha1(openssl::sha::Sha1);
+
+    impl Sha1 {
+        pub fn new() -> Self {
+            Sha1(openssl::sha::Sha1::new())
+        }
+
+        pub fn update(&mut self, data: &[u8]) {
+            self.0.update(data)
+        }
+
+        pub fn finish(self) -> [u8; 20] {
+            self.0.finish()
+        }
+    }
+
+    pub fn rand_bytes(buf: &mut [u8]) -> Result<(), Error> {
+        Ok(openssl::rand::rand_bytes(buf)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strutil;
+
+    #[test]
+    fn sha1_vectors() {
+        assert_eq!(
+            strutil::hex(&sha1(b"")),
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+        );
+        let mut h = Sha1::new();
+        h.update(b"a");
+        h.update(b"bc");
+        assert_eq!(
+            strutil::hex(&h.finish()),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+    }
+
+    #[test]
+    fn blake3_vectors() {
+        assert_eq!(
+            strutil::hex(&blake3(b"")),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+        let mut h = Blake3::new();
+        h.update(b"a");
+        h.update(b"bc");
+        assert_eq!(h.finish(), blake3(b"abc"));
+    }
+
+    #[test]
+    fn sha256_vectors() {
+        assert_eq!(
+            strutil::hex(&Sha256::new().finish()),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        let mut h = Sha256::new();
+        h.update(b"a");
+        h.update(b"bc");
+        assert_eq!(
+            strutil::hex(&h.finish()),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn sha512_vectors() {
+        assert_eq!(
+            strutil::hex(&sha512(b"abc")),
+            "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+             2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+        );
+    }
+
+    #[test]
+    fn md5_vectors() {
+        // From RFC 1321 appendix A.5.
+        assert_eq!(strutil::hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(
+            strutil::hex(&md5(b"abc")),
+            "900150983cd24fb0d6963f7d28e17f72"
+        );
+    }
+
+    #[test]
+    fn hmac_sha256_vectors() {
+        // RFC 4231 test case 2.
+        assert_eq!(
+            strutil::hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn aes256_gcm_vectors() {
+        // Test cases 13 and 14 from the original GCM specification.
+        let g = Aes256Gcm::new(&[0u8; 32], &[0u8; 12]);
+        assert_eq!(
+            strutil::hex(&g.authenticator().finish()),
+            "530f8afbc74536b9a963b4f1c4cb738b"
+        );
+        let mut buf = [0u8; 16];
+        g.apply_keystream(0, &mut buf);
+        assert_eq!(strutil::hex(&buf), "cea7403d4d606b6e074ec5d3baf39d18");
+        let mut a = g.authenticator();
+        a.update(&buf);
+        assert_eq!(
+            strutil::hex(&a.finish()),
+            "d0d1c8a799996bf0265b98b5d48ab919"
+        );
+    }
+
+    #[test]
+    fn aes256_gcm_incremental() {
+        let g = Aes256Gcm::new(&[7u8; 32], &[3u8; 12]);
+        let plaintext: Vec<u8> = (0..100u8).collect();
+        let mut whole = plaintext.clone();
+        g.apply_keystream(0, &mut whole);
+        let mut a = g.authenticator();
+        a.update(&whole);
+        let whole_tag = a.finish();
+
+        // Encrypting and authenticating in odd-sized pieces should match.
+        let mut a = g.authenticator();
+        let mut pos = 0;
+        for &len in &[1, 20, 15, 31, 33] {
+            let mut piece = plaintext[pos..pos + len].to_vec();
+            g.apply_keystream(pos as u64, &mut piece);
+            assert_eq!(&piece[..], &whole[pos..pos + len]);
+            a.update(&piece);
+            pos += len;
+        }
+        assert_eq!(a.finish(), whole_tag);
+
+        // Decrypting from an arbitrary offset should recover the plaintext.
+        let mut piece = whole[37..59].to_vec();
+        g.apply_keystream(37, &mut piece);
+        assert_eq!(&piece[..], &plaintext[37..59]);
+    }
+
+    #[test]
+    fn rand() {
+        let mut a = [0u8; 32];
+        let mut b = [0u8; 32];
+        rand_bytes(&mut a).unwrap();
+        rand_bytes(&mut b).unwrap();
+        assert_ne!(a, b);
+    }
+}
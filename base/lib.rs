@@ -29,6 +29,7 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 pub mod clock;
+pub mod crypto;
 mod error;
 pub mod strutil;
 pub mod time;
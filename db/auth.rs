@@ -29,21 +29,22 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::schema::Permissions;
-use base::strutil;
+use base::{crypto, strutil};
 use blake2_rfc::blake2b::blake2b;
-use failure::{bail, format_err, Error};
+use failure::{bail, format_err, Error, Fail};
 use fnv::FnvHashMap;
 use lazy_static::lazy_static;
 use libpasta;
-use log::info;
 use parking_lot::Mutex;
 use protobuf::Message;
 use rusqlite::{params, Connection, Transaction};
-use std::collections::BTreeMap;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::net::IpAddr;
 use std::str::FromStr;
 use std::sync::Arc;
+use tracing::info;
 
 lazy_static! {
     static ref PASTA_CONFIG: Mutex<Arc<libpasta::Config>> =
@@ -61,6 +62,7 @@ pub(crate) fn set_test_config() {
 
 enum UserFlag {
     Disabled = 1,
+    CameraRestricted = 2,
 }
 
 #[derive(Debug)]
@@ -74,6 +76,13 @@ pub struct User {
     pub unix_uid: Option<i32>,
     pub permissions: Permissions,
 
+    /// If set, the ids of the only cameras this user may access.
+    /// Mirrors the `CameraRestricted` flag and the `user_camera` table.
+    pub cameras: Option<BTreeSet<i32>>,
+
+    /// If set, the time (in seconds since epoch) at which this user expires.
+    pub expiration_time_sec: Option<i64>,
+
     /// True iff this `User` has changed since the last flush.
     /// Only a couple things are flushed lazily: `password_failure_count` and (on upgrade to a new
     /// algorithm) `password_hash`.
@@ -89,15 +98,41 @@ impl User {
             set_password_hash: None,
             unix_uid: self.unix_uid,
             permissions: self.permissions.clone(),
+            cameras: self.cameras.clone(),
+            expiration_time_sec: self.expiration_time_sec,
         }
     }
 
     pub fn has_password(&self) -> bool {
         self.password_hash.is_some()
     }
-    fn disabled(&self) -> bool {
+
+    /// Returns the user's password hash, in the PHC string format, for export.
+    pub fn password_hash(&self) -> Option<&str> {
+        self.password_hash.as_deref()
+    }
+
+    pub fn disabled(&self) -> bool {
         (self.flags & UserFlag::Disabled as i32) != 0
     }
+
+    /// Returns true iff this user has expired as of `now_sec`.
+    /// A request without a known time can't be judged expired; the maintenance task will
+    /// disable the user soon enough.
+    fn expired(&self, now_sec: Option<i64>) -> bool {
+        match (self.expiration_time_sec, now_sec) {
+            (Some(e), Some(n)) => n >= e,
+            _ => false,
+        }
+    }
+
+    /// Returns true iff this user may access the given camera.
+    pub fn may_access_camera(&self, camera_id: i32) -> bool {
+        self.cameras
+            .as_ref()
+            .map(|c| c.contains(&camera_id))
+            .unwrap_or(true)
+    }
 }
 
 /// A change to a user.
@@ -114,6 +149,13 @@ pub struct UserChange {
     set_password_hash: Option<Option<String>>,
     pub unix_uid: Option<i32>,
     pub permissions: Permissions,
+
+    /// If set, restricts the user to the given cameras. The `CameraRestricted` bit of `flags` is
+    /// ignored in favor of this field.
+    pub cameras: Option<BTreeSet<i32>>,
+
+    /// If set, the time (in seconds since epoch) at which the user expires, as for a guest.
+    pub expiration_time_sec: Option<i64>,
 }
 
 impl UserChange {
@@ -125,6 +167,8 @@ impl UserChange {
             set_password_hash: None,
             unix_uid: None,
             permissions: Permissions::default(),
+            cameras: None,
+            expiration_time_sec: None,
         }
     }
 
@@ -137,9 +181,28 @@ impl UserChange {
         self.set_password_hash = Some(None);
     }
 
+    /// Sets a password hash as returned by `User::password_hash`, such as from another instance.
+    pub fn set_password_hash(&mut self, hash: String) {
+        self.set_password_hash = Some(Some(hash));
+    }
+
     pub fn disable(&mut self) {
         self.flags |= UserFlag::Disabled as i32;
     }
+
+    pub fn enable(&mut self) {
+        self.flags &= !(UserFlag::Disabled as i32);
+    }
+
+    /// Returns `flags` with the `CameraRestricted` bit matching `cameras`.
+    fn effective_flags(&self) -> i32 {
+        let f = self.flags & !(UserFlag::CameraRestricted as i32);
+        if self.cameras.is_some() {
+            f | UserFlag::CameraRestricted as i32
+        } else {
+            f
+        }
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -224,10 +287,14 @@ impl FromStr for SessionFlag {
 #[derive(Copy, Clone)]
 pub enum RevocationReason {
     LoggedOut = 1,
+    RevokedByUser = 2,
+    UserExpired = 3,
 }
 
 #[derive(Debug, Default)]
 pub struct Session {
+    /// The identifier used to refer to this session through the API; see `user_session.id`.
+    pub id: i32,
     user_id: i32,
     flags: i32, // bitmask of SessionFlag enum values
     domain: Option<Vec<u8>>,
@@ -257,6 +324,34 @@ impl Session {
     }
 }
 
+/// An unrevoked session, as returned by `State::list_sessions`.
+#[derive(Debug)]
+pub struct ListedSession {
+    pub id: i32,
+    pub description: Option<String>,
+    pub creation: Request,
+    pub last_use: Request,
+    pub use_count: i32,
+}
+
+/// A long-lived API token, as described by the `access_token` table in `schema.sql`.
+/// The raw token is a `RawSessionId`; only its hash is stored.
+#[derive(Debug)]
+pub struct AccessToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub description: Option<String>,
+    pub creation_time_sec: i64,
+    pub revocation_time_sec: Option<i64>,
+    pub last_use_time_sec: Option<i64>,
+    pub use_count: i64,
+    pub permissions: Permissions,
+    hash: SessionHash,
+
+    /// True iff `last_use_time_sec` or `use_count` has changed since the last flush.
+    dirty: bool,
+}
+
 /// A raw session id (not base64-encoded). Sensitive. Never stored in the database.
 pub struct RawSessionId([u8; 48]);
 
@@ -352,6 +447,147 @@ impl rusqlite::types::FromSql for Seed {
     }
 }
 
+/// Seconds after the last failed login for a username or client address at which its failures are
+/// forgotten.
+const LOGIN_FAILURE_MEMORY_SEC: i64 = 24 * 60 * 60;
+
+/// Throttling of password logins, as recorded in the `login_failure` table.
+///
+/// Each username and each client address may fail a few times freely. After that, each failure
+/// locks it out for exponentially longer: 1 second, then 2, 4, and so on up to
+/// `max_lockout_sec`. A successful login clears the username's failures but not the address's,
+/// so an attacker can't reset its budget by logging into an account of its own.
+#[derive(Clone, Debug)]
+pub struct LoginThrottle {
+    /// The number of failures allowed per username before lockouts start.
+    pub free_attempts_per_user: u32,
+
+    /// The number of failures allowed per client address before lockouts start.
+    pub free_attempts_per_addr: u32,
+
+    /// The longest single lockout.
+    pub max_lockout_sec: i64,
+}
+
+impl Default for LoginThrottle {
+    fn default() -> Self {
+        LoginThrottle {
+            free_attempts_per_user: 5,
+            free_attempts_per_addr: 20,
+            max_lockout_sec: 15 * 60,
+        }
+    }
+}
+
+/// The error returned by `State::login_by_password` while a username or client address is
+/// locked out.
+#[derive(Debug, Fail)]
+#[fail(
+    display = "too many failed logins; try again in {} seconds",
+    retry_after_sec
+)]
+pub struct LoginThrottled {
+    pub retry_after_sec: i64,
+}
+
+/// A key of the `login_failure` table.
+struct ThrottleKey {
+    kind: &'static str,
+    key: String,
+    free_attempts: u32,
+}
+
+impl LoginThrottle {
+    /// Returns the keys to throttle for a login attempt, the username's first.
+    fn keys(&self, req: &Request, username: &str) -> Vec<ThrottleKey> {
+        let mut keys = vec![ThrottleKey {
+            kind: "user",
+            key: username.to_owned(),
+            free_attempts: self.free_attempts_per_user,
+        }];
+        if let Some(a) = req.addr {
+            keys.push(ThrottleKey {
+                kind: "addr",
+                key: a.to_string(),
+                free_attempts: self.free_attempts_per_addr,
+            });
+        }
+        keys
+    }
+
+    /// Fails with `LoginThrottled` if any of the given keys is locked out.
+    fn check(&self, conn: &Connection, keys: &[ThrottleKey], now: i64) -> Result<(), Error> {
+        let mut stmt = conn.prepare_cached(
+            "select locked_until_sec from login_failure where kind = ? and key = ?",
+        )?;
+        let mut retry_after_sec = 0;
+        for k in keys {
+            let mut rows = stmt.query(params![k.kind, &k.key])?;
+            if let Some(row) = rows.next()? {
+                let locked_until_sec: Option<i64> = row.get(0)?;
+                if let Some(u) = locked_until_sec {
+                    retry_after_sec = cmp::max(retry_after_sec, u - now);
+                }
+            }
+        }
+        if retry_after_sec > 0 {
+            return Err(LoginThrottled { retry_after_sec }.into());
+        }
+        Ok(())
+    }
+
+    fn record_failure(
+        &self,
+        conn: &Connection,
+        keys: &[ThrottleKey],
+        now: i64,
+    ) -> Result<(), Error> {
+        conn.prepare_cached("delete from login_failure where last_failure_sec <= ?")?
+            .execute(params![now - LOGIN_FAILURE_MEMORY_SEC])?;
+        let mut select =
+            conn.prepare_cached("select failures from login_failure where kind = ? and key = ?")?;
+        let mut upsert = conn.prepare_cached(
+            r#"
+            insert or replace into login_failure (kind, key, failures, last_failure_sec,
+                                                  locked_until_sec)
+                                          values (?, ?, ?, ?, ?)
+        "#,
+        )?;
+        for k in keys {
+            let failures: i64 = {
+                let mut rows = select.query(params![k.kind, &k.key])?;
+                match rows.next()? {
+                    None => 1,
+                    Some(row) => row.get::<_, i64>(0)? + 1,
+                }
+            };
+            let locked_until_sec = self.lockout_sec(failures, k.free_attempts).map(|s| now + s);
+            upsert.execute(params![k.kind, &k.key, failures, now, locked_until_sec])?;
+        }
+        Ok(())
+    }
+
+    /// Returns the lockout after the given number of recent failures, if any.
+    fn lockout_sec(&self, failures: i64, free_attempts: u32) -> Option<i64> {
+        let excess = failures - i64::from(free_attempts);
+        if excess <= 0 {
+            return None;
+        }
+        let sec = if excess > 32 {
+            i64::max_value()
+        } else {
+            1 << (excess - 1)
+        };
+        Some(cmp::min(sec, self.max_lockout_sec))
+    }
+
+    fn reset(conn: &Connection, key: &ThrottleKey) -> Result<(), Error> {
+        conn.prepare_cached("delete from login_failure where kind = ? and key = ?")?
+            .execute(params![key.kind, &key.key])?;
+        Ok(())
+    }
+}
+
 pub(crate) struct State {
     users_by_id: BTreeMap<i32, User>,
     users_by_name: BTreeMap<String, i32>,
@@ -364,6 +600,15 @@ pub(crate) struct State {
     /// evict the oldest when its size exceeds a threshold. Or just evict everything on every flush
     /// (and accept more frequent database accesses).
     sessions: FnvHashMap<SessionHash, Session>,
+
+    /// All access tokens, including revoked ones.
+    tokens_by_id: BTreeMap<i32, AccessToken>,
+
+    /// The ids of unrevoked access tokens, by hash.
+    tokens_by_hash: FnvHashMap<SessionHash, i32>,
+
+    /// If set, password logins are throttled as described there.
+    login_throttle: Option<LoginThrottle>,
 }
 
 impl State {
@@ -372,6 +617,9 @@ impl State {
             users_by_id: BTreeMap::new(),
             users_by_name: BTreeMap::new(),
             sessions: FnvHashMap::default(),
+            tokens_by_id: BTreeMap::new(),
+            tokens_by_hash: FnvHashMap::default(),
+            login_throttle: Some(LoginThrottle::default()),
         };
         let mut stmt = conn.prepare(
             r#"
@@ -383,7 +631,8 @@ impl State {
                 password_id,
                 password_failure_count,
                 unix_uid,
-                permissions
+                permissions,
+                expiration_time_sec
             from
                 user
         "#,
@@ -394,22 +643,93 @@ impl State {
             let name: String = row.get(1)?;
             let mut permissions = Permissions::new();
             permissions.merge_from_bytes(row.get_raw_checked(7)?.as_blob()?)?;
+            let flags: i32 = row.get(2)?;
+            let cameras = if (flags & UserFlag::CameraRestricted as i32) != 0 {
+                Some(BTreeSet::new())
+            } else {
+                None
+            };
             state.users_by_id.insert(
                 id,
                 User {
                     id,
                     username: name.clone(),
-                    flags: row.get(2)?,
+                    flags,
                     password_hash: row.get(3)?,
                     password_id: row.get(4)?,
                     password_failure_count: row.get(5)?,
                     unix_uid: row.get(6)?,
                     dirty: false,
                     permissions,
+                    cameras,
+                    expiration_time_sec: row.get(8)?,
                 },
             );
             state.users_by_name.insert(name, id);
         }
+        let mut stmt = conn.prepare("select user_id, camera_id from user_camera")?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let user_id: i32 = row.get(0)?;
+            let camera_id: i32 = row.get(1)?;
+            let u = state
+                .users_by_id
+                .get_mut(&user_id)
+                .ok_or_else(|| format_err!("user_camera row for missing user {}", user_id))?;
+            match u.cameras {
+                Some(ref mut c) => {
+                    c.insert(camera_id);
+                }
+                None => bail!(
+                    "user {} has user_camera rows but isn't camera-restricted",
+                    user_id
+                ),
+            }
+        }
+        let mut stmt = conn.prepare(
+            r#"
+            select
+                id,
+                token_hash,
+                user_id,
+                description,
+                creation_time_sec,
+                revocation_time_sec,
+                last_use_time_sec,
+                use_count,
+                permissions
+            from
+                access_token
+        "#,
+        )?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let id = row.get(0)?;
+            let mut hash = SessionHash([0u8; 24]);
+            let h = row.get_raw_checked(1)?.as_blob()?;
+            if h.len() != 24 {
+                bail!("access token {} has bad hash length {}", id, h.len());
+            }
+            hash.0.copy_from_slice(h);
+            let mut permissions = Permissions::new();
+            permissions.merge_from_bytes(row.get_raw_checked(8)?.as_blob()?)?;
+            let t = AccessToken {
+                id,
+                user_id: row.get(2)?,
+                description: row.get(3)?,
+                creation_time_sec: row.get(4)?,
+                revocation_time_sec: row.get(5)?,
+                last_use_time_sec: row.get(6)?,
+                use_count: row.get(7)?,
+                permissions,
+                hash,
+                dirty: false,
+            };
+            if t.revocation_time_sec.is_none() {
+                state.tokens_by_hash.insert(hash, id);
+            }
+            state.tokens_by_id.insert(id, t);
+        }
         Ok(state)
     }
 
@@ -425,6 +745,26 @@ impl State {
         &self.users_by_id
     }
 
+    /// Disables each enabled user whose expiration time has passed and revokes its sessions.
+    /// Returns the ids of the users disabled.
+    pub fn expire_users(&mut self, conn: &Connection, req: Request) -> Result<Vec<i32>, Error> {
+        let expired: Vec<i32> = self
+            .users_by_id
+            .values()
+            .filter(|u| !u.disabled() && u.expired(req.when_sec))
+            .map(|u| u.id)
+            .collect();
+        for &id in &expired {
+            let u = self.users_by_id.get_mut(&id).expect("id from users_by_id");
+            let flags = u.flags | UserFlag::Disabled as i32;
+            conn.prepare_cached("update user set flags = ? where id = ?")?
+                .execute(params![flags, id])?;
+            u.flags = flags;
+            self.revoke_all_sessions(conn, RevocationReason::UserExpired, req.clone(), id, None)?;
+        }
+        Ok(expired)
+    }
+
     fn update_user(
         &mut self,
         conn: &Connection,
@@ -441,7 +781,8 @@ impl State {
                 password_failure_count = :password_failure_count,
                 flags = :flags,
                 unix_uid = :unix_uid,
-                permissions = :permissions
+                permissions = :permissions,
+                expiration_time_sec = :expiration_time_sec
             where
                 id = :id
         "#,
@@ -468,12 +809,14 @@ impl State {
                 (":password_hash", phash),
                 (":password_id", &pid),
                 (":password_failure_count", &pcount),
-                (":flags", &change.flags),
+                (":flags", &change.effective_flags()),
                 (":unix_uid", &change.unix_uid),
                 (":id", &id),
                 (":permissions", &permissions),
+                (":expiration_time_sec", &change.expiration_time_sec),
             ])?;
         }
+        set_cameras(conn, id, change.cameras.as_ref())?;
         let u = e.into_mut();
         u.username = change.username;
         if let Some(h) = change.set_password_hash {
@@ -481,17 +824,21 @@ impl State {
             u.password_id += 1;
             u.password_failure_count = 0;
         }
-        u.flags = change.flags;
+        u.flags = change.effective_flags();
         u.unix_uid = change.unix_uid;
         u.permissions = change.permissions;
+        u.cameras = change.cameras;
+        u.expiration_time_sec = change.expiration_time_sec;
         Ok(u)
     }
 
     fn add_user(&mut self, conn: &Connection, change: UserChange) -> Result<&User, Error> {
         let mut stmt = conn.prepare_cached(
             r#"
-            insert into user (username,  password_hash,  flags,  unix_uid,  permissions)
-                      values (:username, :password_hash, :flags, :unix_uid, :permissions)
+            insert into user (username,  password_hash,  flags,  unix_uid,  permissions,
+                              expiration_time_sec)
+                      values (:username, :password_hash, :flags, :unix_uid, :permissions,
+                              :expiration_time_sec)
         "#,
         )?;
         let password_hash = change.set_password_hash.unwrap_or(None);
@@ -502,11 +849,13 @@ impl State {
         stmt.execute_named(&[
             (":username", &&change.username[..]),
             (":password_hash", &password_hash),
-            (":flags", &change.flags),
+            (":flags", &change.effective_flags()),
             (":unix_uid", &change.unix_uid),
             (":permissions", &permissions),
+            (":expiration_time_sec", &change.expiration_time_sec),
         ])?;
         let id = conn.last_insert_rowid() as i32;
+        set_cameras(conn, id, change.cameras.as_ref())?;
         self.users_by_name.insert(change.username.clone(), id);
         let e = self.users_by_id.entry(id);
         let e = match e {
@@ -515,20 +864,28 @@ impl State {
         };
         Ok(e.insert(User {
             id,
+            flags: change.effective_flags(),
             username: change.username,
-            flags: change.flags,
             password_hash,
             password_id: 0,
             password_failure_count: 0,
             unix_uid: change.unix_uid,
             dirty: false,
             permissions: change.permissions,
+            cameras: change.cameras,
+            expiration_time_sec: change.expiration_time_sec,
         }))
     }
 
     pub fn delete_user(&mut self, conn: &mut Connection, id: i32) -> Result<(), Error> {
         let tx = conn.transaction()?;
         tx.execute("delete from user_session where user_id = ?", params![id])?;
+        tx.execute(
+            "delete from user_notification_policy where user_id = ?",
+            params![id],
+        )?;
+        tx.execute("delete from user_camera where user_id = ?", params![id])?;
+        tx.execute("delete from access_token where user_id = ?", params![id])?;
         {
             let mut user_stmt = tx.prepare_cached("delete from user where id = ?")?;
             if user_stmt.execute(params![id])? != 1 {
@@ -539,9 +896,28 @@ impl State {
         let name = self.users_by_id.remove(&id).unwrap().username;
         self.users_by_name.remove(&name).unwrap();
         self.sessions.retain(|_k, ref mut v| v.user_id != id);
+        let tokens_by_hash = &mut self.tokens_by_hash;
+        self.tokens_by_id.retain(|_k, t| {
+            if t.user_id == id {
+                tokens_by_hash.remove(&t.hash);
+                return false;
+            }
+            true
+        });
         Ok(())
     }
 
+    /// Removes a deleted camera from all users' camera lists.
+    /// The caller must also have called `delete_camera` within the committed transaction.
+    /// Restricted users remain restricted, so this never widens access.
+    pub fn camera_deleted(&mut self, camera_id: i32) {
+        for u in self.users_by_id.values_mut() {
+            if let Some(ref mut c) = u.cameras {
+                c.remove(&camera_id);
+            }
+        }
+    }
+
     pub fn get_user(&self, username: &str) -> Option<&User> {
         self.users_by_name.get(username).map(|id| {
             self.users_by_id
@@ -550,6 +926,14 @@ impl State {
         })
     }
 
+    pub fn set_login_throttle(&mut self, throttle: Option<LoginThrottle>) {
+        self.login_throttle = throttle;
+    }
+
+    /// Logs in with a password, creating a session.
+    ///
+    /// If login throttling is enabled, fails with `LoginThrottled` while the username or the
+    /// client address is locked out, and records failures toward future lockouts.
     pub fn login_by_password(
         &mut self,
         conn: &Connection,
@@ -559,17 +943,64 @@ impl State {
         domain: Option<Vec<u8>>,
         session_flags: i32,
     ) -> Result<(RawSessionId, &Session), Error> {
-        let id = self
+        let throttle = match (self.login_throttle.as_ref(), req.when_sec) {
+            (Some(t), Some(now)) => {
+                let keys = t.keys(&req, username);
+                t.check(conn, &keys, now)?;
+                Some((t.clone(), keys, now))
+            }
+            _ => None,
+        };
+        let id = match self.check_password(&req, username, password) {
+            Ok(id) => id,
+            Err(e) => {
+                if let Some((t, keys, now)) = throttle {
+                    t.record_failure(conn, &keys, now)?;
+                }
+                return Err(e);
+            }
+        };
+        if let Some((_, keys, _)) = throttle {
+            LoginThrottle::reset(conn, &keys[0])?;
+        }
+        let u = self
+            .users_by_id
+            .get_mut(&id)
+            .expect("check_password returns a valid id");
+        let password_id = u.password_id;
+        State::make_session_int(
+            conn,
+            req,
+            u,
+            domain,
+            Some(password_id),
+            session_flags,
+            &mut self.sessions,
+            u.permissions.clone(),
+        )
+    }
+
+    /// Checks the given user's password, returning the user's id on success.
+    fn check_password(
+        &mut self,
+        req: &Request,
+        username: &str,
+        password: String,
+    ) -> Result<i32, Error> {
+        let id = *self
             .users_by_name
             .get(username)
             .ok_or_else(|| format_err!("no such user {:?}", username))?;
         let u = self
             .users_by_id
-            .get_mut(id)
+            .get_mut(&id)
             .expect("users_by_name implies users_by_id");
         if u.disabled() {
             bail!("user {:?} is disabled", username);
         }
+        if u.expired(req.when_sec) {
+            bail!("user {:?} has expired", username);
+        }
         let new_hash = {
             let hash = match u.password_hash.as_ref() {
                 None => bail!("no password set for user {:?}", username),
@@ -589,17 +1020,7 @@ impl State {
             u.password_hash = Some(h);
             u.dirty = true;
         }
-        let password_id = u.password_id;
-        State::make_session_int(
-            conn,
-            req,
-            u,
-            domain,
-            Some(password_id),
-            session_flags,
-            &mut self.sessions,
-            u.permissions.clone(),
-        )
+        Ok(id)
     }
 
     /// Makes a session directly (no password required).
@@ -619,6 +1040,9 @@ impl State {
         if u.disabled() {
             bail!("user is disabled");
         }
+        if u.expired(creation.when_sec) {
+            bail!("user has expired");
+        }
         State::make_session_int(
             conn,
             creation,
@@ -642,20 +1066,25 @@ impl State {
         permissions: Permissions,
     ) -> Result<(RawSessionId, &'s Session), Error> {
         let mut session_id = RawSessionId::new();
-        ::openssl::rand::rand_bytes(&mut session_id.0).unwrap();
+        crypto::rand_bytes(&mut session_id.0)?;
         let mut seed = [0u8; 32];
-        ::openssl::rand::rand_bytes(&mut seed).unwrap();
+        crypto::rand_bytes(&mut seed)?;
         let hash = session_id.hash();
+        let id: i32 = conn.query_row(
+            "select ifnull(max(id), 0) + 1 from user_session",
+            params![],
+            |row| row.get(0),
+        )?;
         let mut stmt = conn.prepare_cached(
             r#"
             insert into user_session (session_id_hash,  user_id,  seed,  flags,  domain,
                                       creation_password_id,  creation_time_sec,
                                       creation_user_agent,  creation_peer_addr,
-                                      permissions)
+                                      permissions,  id)
                               values (:session_id_hash, :user_id, :seed, :flags, :domain,
                                       :creation_password_id, :creation_time_sec,
                                       :creation_user_agent, :creation_peer_addr,
-                                      :permissions)
+                                      :permissions, :id)
         "#,
         )?;
         let addr = creation.addr_buf();
@@ -674,12 +1103,14 @@ impl State {
             (":creation_user_agent", &creation.user_agent),
             (":creation_peer_addr", &addr),
             (":permissions", &permissions_blob),
+            (":id", &id),
         ])?;
         let e = match sessions.entry(hash) {
             ::std::collections::hash_map::Entry::Occupied(_) => panic!("duplicate session hash!"),
             ::std::collections::hash_map::Entry::Vacant(e) => e,
         };
         let session = e.insert(Session {
+            id,
             user_id: user.id,
             flags,
             domain,
@@ -715,6 +1146,9 @@ impl State {
         if u.disabled() {
             bail!("user {:?} is disabled", &u.username);
         }
+        if u.expired(s.last_use.when_sec) {
+            bail!("user {:?} has expired", &u.username);
+        }
         Ok((s, u))
     }
 
@@ -756,10 +1190,238 @@ impl State {
             ])?;
             s.revocation = req;
             s.revocation_reason = Some(reason as i32);
+            s.revocation_reason_detail = detail;
         }
         Ok(())
     }
 
+    /// Lists the given user's unrevoked sessions, ordered by id.
+    ///
+    /// Usage information reflects requests not yet flushed to the database.
+    pub fn list_sessions(
+        &self,
+        conn: &Connection,
+        user_id: i32,
+    ) -> Result<Vec<ListedSession>, Error> {
+        let mut stmt = conn.prepare_cached(
+            r#"
+            select
+                session_id_hash,
+                id,
+                description,
+                creation_time_sec,
+                creation_user_agent,
+                creation_peer_addr,
+                last_use_time_sec,
+                last_use_user_agent,
+                last_use_peer_addr,
+                use_count
+            from
+                user_session
+            where
+                user_id = ? and
+                revocation_reason is null
+            order by
+                id
+        "#,
+        )?;
+        let mut rows = stmt.query(params![user_id])?;
+        let mut sessions = Vec::new();
+        while let Some(row) = rows.next()? {
+            let hash = session_hash(row.get_raw_checked(0)?.as_blob()?)?;
+            let creation_addr: FromSqlIpAddr = row.get(5)?;
+            let last_use_addr: FromSqlIpAddr = row.get(8)?;
+            let mut l = ListedSession {
+                id: row.get(1)?,
+                description: row.get(2)?,
+                creation: Request {
+                    when_sec: row.get(3)?,
+                    user_agent: row.get(4)?,
+                    addr: creation_addr.0,
+                },
+                last_use: Request {
+                    when_sec: row.get(6)?,
+                    user_agent: row.get(7)?,
+                    addr: last_use_addr.0,
+                },
+                use_count: row.get(9)?,
+            };
+            if let Some(s) = self.sessions.get(&hash) {
+                if s.revocation_reason.is_some() {
+                    continue;
+                }
+                l.last_use = s.last_use.clone();
+                l.use_count = s.use_count;
+            }
+            sessions.push(l);
+        }
+        Ok(sessions)
+    }
+
+    /// Revokes the given user's session with the given id.
+    ///
+    /// Returns false if the user has no such session. Revoking an already-revoked session
+    /// succeeds without changing the original reason.
+    pub fn revoke_session_by_id(
+        &mut self,
+        conn: &Connection,
+        reason: RevocationReason,
+        req: Request,
+        user_id: i32,
+        id: i32,
+    ) -> Result<bool, Error> {
+        let hash = {
+            let mut stmt = conn.prepare_cached(
+                "select session_id_hash from user_session where user_id = ? and id = ?",
+            )?;
+            let mut rows = stmt.query(params![user_id, id])?;
+            match rows.next()? {
+                None => return Ok(false),
+                Some(row) => session_hash(row.get_raw_checked(0)?.as_blob()?)?,
+            }
+        };
+        self.revoke_session(conn, reason, None, req, &hash)?;
+        Ok(true)
+    }
+
+    /// Revokes all of the given user's unrevoked sessions, except the one with id `except`.
+    /// Returns the number of sessions revoked.
+    pub fn revoke_all_sessions(
+        &mut self,
+        conn: &Connection,
+        reason: RevocationReason,
+        req: Request,
+        user_id: i32,
+        except: Option<i32>,
+    ) -> Result<usize, Error> {
+        let hashes = {
+            let mut stmt = conn.prepare_cached(
+                r#"
+                select
+                    session_id_hash
+                from
+                    user_session
+                where
+                    user_id = ? and
+                    id is not ? and
+                    revocation_reason is null
+            "#,
+            )?;
+            let mut rows = stmt.query(params![user_id, except])?;
+            let mut hashes = Vec::new();
+            while let Some(row) = rows.next()? {
+                hashes.push(session_hash(row.get_raw_checked(0)?.as_blob()?)?);
+            }
+            hashes
+        };
+        for hash in &hashes {
+            self.revoke_session(conn, reason, None, req.clone(), hash)?;
+        }
+        Ok(hashes.len())
+    }
+
+    pub fn tokens_by_id(&self) -> &BTreeMap<i32, AccessToken> {
+        &self.tokens_by_id
+    }
+
+    /// Creates an access token for the given user, returning the raw token.
+    pub fn create_token(
+        &mut self,
+        conn: &Connection,
+        user_id: i32,
+        description: Option<String>,
+        permissions: Permissions,
+        when_sec: i64,
+    ) -> Result<(RawSessionId, &AccessToken), Error> {
+        match self.users_by_id.get(&user_id) {
+            None => bail!("no such uid {:?}", user_id),
+            Some(u) if u.disabled() => bail!("user is disabled"),
+            Some(u) if u.expired(Some(when_sec)) => bail!("user has expired"),
+            Some(_) => {}
+        }
+        let mut raw = RawSessionId::new();
+        crypto::rand_bytes(&mut raw.0)?;
+        let hash = raw.hash();
+        let permissions_blob = permissions
+            .write_to_bytes()
+            .expect("proto3->vec is infallible");
+        conn.prepare_cached(
+            r#"
+            insert into access_token (token_hash,  user_id,  description,  creation_time_sec,
+                                      permissions)
+                              values (:token_hash, :user_id, :description, :creation_time_sec,
+                                      :permissions)
+        "#,
+        )?
+        .execute_named(&[
+            (":token_hash", &&hash.0[..]),
+            (":user_id", &user_id),
+            (":description", &description),
+            (":creation_time_sec", &when_sec),
+            (":permissions", &permissions_blob),
+        ])?;
+        let id = conn.last_insert_rowid() as i32;
+        self.tokens_by_hash.insert(hash, id);
+        let t = self.tokens_by_id.entry(id).or_insert(AccessToken {
+            id,
+            user_id,
+            description,
+            creation_time_sec: when_sec,
+            revocation_time_sec: None,
+            last_use_time_sec: None,
+            use_count: 0,
+            permissions,
+            hash,
+            dirty: false,
+        });
+        Ok((raw, t))
+    }
+
+    /// Revokes the given access token. Revoking an already-revoked token is a no-op.
+    pub fn revoke_token(&mut self, conn: &Connection, id: i32, when_sec: i64) -> Result<(), Error> {
+        let t = self
+            .tokens_by_id
+            .get_mut(&id)
+            .ok_or_else(|| format_err!("no such access token {}", id))?;
+        if t.revocation_time_sec.is_some() {
+            return Ok(());
+        }
+        conn.prepare_cached("update access_token set revocation_time_sec = ? where id = ?")?
+            .execute(params![when_sec, id])?;
+        t.revocation_time_sec = Some(when_sec);
+        self.tokens_by_hash.remove(&t.hash);
+        Ok(())
+    }
+
+    pub fn authenticate_token(
+        &mut self,
+        req: Request,
+        hash: &SessionHash,
+    ) -> Result<(&AccessToken, &User), Error> {
+        let id = self
+            .tokens_by_hash
+            .get(hash)
+            .ok_or_else(|| format_err!("no such access token"))?;
+        let t = self
+            .tokens_by_id
+            .get_mut(id)
+            .expect("tokens_by_hash implies tokens_by_id");
+        let u = match self.users_by_id.get(&t.user_id) {
+            None => bail!("access token references nonexistent user!"),
+            Some(u) => u,
+        };
+        if u.disabled() {
+            bail!("user {:?} is disabled", &u.username);
+        }
+        if u.expired(req.when_sec) {
+            bail!("user {:?} has expired", &u.username);
+        }
+        t.last_use_time_sec = req.when_sec;
+        t.use_count += 1;
+        t.dirty = true;
+        Ok((t, u))
+    }
+
     /// Flushes all pending database changes to the given transaction.
     ///
     /// The caller is expected to call `post_flush` afterward if the transaction is
@@ -801,6 +1463,26 @@ impl State {
                 (":id", &id),
             ])?;
         }
+        let mut t_stmt = tx.prepare(
+            r#"
+            update access_token
+            set
+                last_use_time_sec = :last_use_time_sec,
+                use_count = :use_count
+            where
+                id = :id
+        "#,
+        )?;
+        for (&id, t) in &self.tokens_by_id {
+            if !t.dirty {
+                continue;
+            }
+            t_stmt.execute_named(&[
+                (":last_use_time_sec", &t.last_use_time_sec),
+                (":use_count", &t.use_count),
+                (":id", &id),
+            ])?;
+        }
         for (_, s) in &self.sessions {
             if !s.dirty {
                 continue;
@@ -827,9 +1509,21 @@ impl State {
         for (_, s) in &mut self.sessions {
             s.dirty = false;
         }
+        for (_, t) in &mut self.tokens_by_id {
+            t.dirty = false;
+        }
     }
 }
 
+fn session_hash(b: &[u8]) -> Result<SessionHash, Error> {
+    if b.len() != 24 {
+        bail!("session_id_hash has unexpected length {}", b.len());
+    }
+    let mut hash = SessionHash([0u8; 24]);
+    hash.0.copy_from_slice(b);
+    Ok(hash)
+}
+
 fn lookup_session(conn: &Connection, hash: &SessionHash) -> Result<Session, Error> {
     let mut stmt = conn.prepare_cached(
         r#"
@@ -852,7 +1546,8 @@ fn lookup_session(conn: &Connection, hash: &SessionHash) -> Result<Session, Erro
             last_use_user_agent,
             last_use_peer_addr,
             use_count,
-            permissions
+            permissions,
+            id
         from
             user_session
         where
@@ -867,6 +1562,7 @@ fn lookup_session(conn: &Connection, hash: &SessionHash) -> Result<Session, Erro
     let mut permissions = Permissions::new();
     permissions.merge_from_bytes(row.get_raw_checked(18)?.as_blob()?)?;
     Ok(Session {
+        id: row.get(19)?,
         user_id: row.get(0)?,
         seed: row.get(1)?,
         flags: row.get(2)?,
@@ -896,6 +1592,83 @@ fn lookup_session(conn: &Connection, hash: &SessionHash) -> Result<Session, Erro
     })
 }
 
+/// Replaces the `user_camera` rows for the given user.
+fn set_cameras(
+    conn: &Connection,
+    user_id: i32,
+    cameras: Option<&BTreeSet<i32>>,
+) -> Result<(), Error> {
+    conn.prepare_cached("delete from user_camera where user_id = ?")?
+        .execute(params![user_id])?;
+    if let Some(cameras) = cameras {
+        let mut stmt =
+            conn.prepare_cached("insert into user_camera (user_id, camera_id) values (?, ?)")?;
+        for &camera_id in cameras {
+            stmt.execute(params![user_id, camera_id])?;
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the `user_camera` rows for a camera which is about to be deleted.
+/// After commit, the caller should call `State::camera_deleted`.
+/// The names of all permissions, as in `schema.proto`.
+pub const PERMISSION_NAMES: &[&str] = &[
+    "view_video",
+    "read_camera_configs",
+    "update_signals",
+    "update_detections",
+    "control_ptz",
+    "protect_recordings",
+    "write_notes",
+    "read_logs",
+    "read_playback_heat",
+    "update_camera_configs",
+    "delete_recordings",
+    "talkback",
+    "admin_users",
+    "update_log_filter",
+];
+
+/// Returns the permission with the given name (one of `PERMISSION_NAMES`), if any.
+pub fn permission_mut<'a>(p: &'a mut Permissions, name: &str) -> Option<&'a mut bool> {
+    Some(match name {
+        "view_video" => &mut p.view_video,
+        "read_camera_configs" => &mut p.read_camera_configs,
+        "update_signals" => &mut p.update_signals,
+        "update_detections" => &mut p.update_detections,
+        "control_ptz" => &mut p.control_ptz,
+        "protect_recordings" => &mut p.protect_recordings,
+        "write_notes" => &mut p.write_notes,
+        "read_logs" => &mut p.read_logs,
+        "read_playback_heat" => &mut p.read_playback_heat,
+        "update_camera_configs" => &mut p.update_camera_configs,
+        "delete_recordings" => &mut p.delete_recordings,
+        "talkback" => &mut p.talkback,
+        "admin_users" => &mut p.admin_users,
+        "update_log_filter" => &mut p.update_log_filter,
+        _ => return None,
+    })
+}
+
+/// Returns the names of the permissions `p` grants.
+pub fn permission_names(p: &Permissions) -> Vec<&'static str> {
+    let mut p = p.clone();
+    PERMISSION_NAMES
+        .iter()
+        .cloned()
+        .filter(|n| *permission_mut(&mut p, n).unwrap())
+        .collect()
+}
+
+pub(crate) fn delete_camera(tx: &Transaction, camera_id: i32) -> Result<(), Error> {
+    tx.execute(
+        "delete from user_camera where camera_id = ?",
+        params![camera_id],
+    )?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -903,6 +1676,19 @@ mod tests {
     use crate::testutil;
     use rusqlite::Connection;
 
+    #[test]
+    fn permission_names() {
+        let mut p = Permissions::new();
+        for n in PERMISSION_NAMES {
+            assert!(!*permission_mut(&mut p, n).unwrap());
+        }
+        assert!(permission_mut(&mut p, "fly").is_none());
+        *permission_mut(&mut p, "view_video").unwrap() = true;
+        *permission_mut(&mut p, "talkback").unwrap() = true;
+        assert!(p.view_video && p.talkback);
+        assert_eq!(super::permission_names(&p), &["view_video", "talkback"]);
+    }
+
     #[test]
     fn open_empty_db() {
         testutil::init();
@@ -1050,6 +1836,199 @@ mod tests {
         assert_eq!(format!("{}", e), "session is no longer valid (reason=1)");
     }
 
+    #[test]
+    fn list_and_revoke_sessions() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let req = Request {
+            when_sec: Some(42),
+            addr: None,
+            user_agent: Some(b"some ua".to_vec()),
+        };
+        let uid = {
+            let mut c = UserChange::add_user("slamb".to_owned());
+            c.set_password("hunter2".to_owned());
+            state.apply(&conn, c).unwrap().id
+        };
+        let mut sids = Vec::new();
+        for _ in 0..3 {
+            let (sid, _) = state
+                .login_by_password(&conn, req.clone(), "slamb", "hunter2".to_owned(), None, 0)
+                .unwrap();
+            sids.push(sid);
+        }
+        let use_req = Request {
+            when_sec: Some(43),
+            ..req.clone()
+        };
+        let current = state
+            .authenticate_session(&conn, use_req, &sids[0].hash())
+            .unwrap()
+            .0
+            .id;
+        let listed = state.list_sessions(&conn, uid).unwrap();
+        assert_eq!(listed.len(), 3);
+        assert_eq!(listed[0].id, current);
+        assert_eq!(listed[0].creation.when_sec, Some(42));
+        assert_eq!(listed[0].last_use.when_sec, Some(43));
+        assert_eq!(listed[0].use_count, 1);
+
+        // Revoke a single session, then drop the cache to ensure the revocation is persisted.
+        let id = listed[1].id;
+        assert!(state
+            .revoke_session_by_id(&conn, RevocationReason::RevokedByUser, req.clone(), uid, id)
+            .unwrap());
+        assert!(!state
+            .revoke_session_by_id(
+                &conn,
+                RevocationReason::RevokedByUser,
+                req.clone(),
+                uid + 1,
+                id
+            )
+            .unwrap());
+        drop(state);
+        let mut state = State::init(&conn).unwrap();
+        let e = state
+            .authenticate_session(&conn, req.clone(), &sids[1].hash())
+            .unwrap_err();
+        assert_eq!(format!("{}", e), "session is no longer valid (reason=2)");
+        assert_eq!(state.list_sessions(&conn, uid).unwrap().len(), 2);
+
+        // Revoke all but the current session.
+        assert_eq!(
+            state
+                .revoke_all_sessions(
+                    &conn,
+                    RevocationReason::RevokedByUser,
+                    req.clone(),
+                    uid,
+                    Some(current)
+                )
+                .unwrap(),
+            1
+        );
+        state
+            .authenticate_session(&conn, req.clone(), &sids[0].hash())
+            .unwrap();
+        state
+            .authenticate_session(&conn, req.clone(), &sids[2].hash())
+            .unwrap_err();
+        let listed = state.list_sessions(&conn, uid).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, current);
+    }
+
+    #[test]
+    fn login_throttle() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        state.set_login_throttle(Some(LoginThrottle {
+            free_attempts_per_user: 2,
+            free_attempts_per_addr: 3,
+            max_lockout_sec: 4,
+        }));
+        {
+            let mut c = UserChange::add_user("slamb".to_owned());
+            c.set_password("hunter2".to_owned());
+            state.apply(&conn, c).unwrap();
+        }
+
+        // Returns the error of a failed login: None for a bad password, or the seconds until
+        // retry if throttled.
+        let login = |state: &mut State, when_sec, addr: [u8; 4], user: &str, pwd: &str| {
+            let req = Request {
+                when_sec: Some(when_sec),
+                addr: Some(addr.into()),
+                user_agent: None,
+            };
+            state
+                .login_by_password(&conn, req, user, pwd.to_owned(), None, 0)
+                .map(|_| ())
+                .map_err(|e| {
+                    e.downcast::<LoginThrottled>()
+                        .ok()
+                        .map(|t| t.retry_after_sec)
+                })
+        };
+        let a = [192, 168, 1, 1];
+
+        // Two free failures, then lockouts of 1, 2, 4, 4... seconds.
+        assert_eq!(login(&mut state, 0, a, "slamb", "x"), Err(None));
+        assert_eq!(login(&mut state, 0, a, "slamb", "x"), Err(None));
+        assert_eq!(login(&mut state, 0, a, "slamb", "x"), Err(None));
+        assert_eq!(login(&mut state, 0, a, "slamb", "hunter2"), Err(Some(1)));
+        assert_eq!(login(&mut state, 1, a, "slamb", "x"), Err(None));
+        assert_eq!(login(&mut state, 2, a, "slamb", "hunter2"), Err(Some(1)));
+
+        // The correct password succeeds once the lockout passes and resets the user's count...
+        login(&mut state, 3, a, "slamb", "hunter2").unwrap();
+        assert_eq!(login(&mut state, 3, a, "slamb", "x"), Err(None));
+
+        // ...but not the address's, which is now over its own limit for any username.
+        assert_eq!(login(&mut state, 3, a, "other", "x"), Err(Some(2)));
+        login(&mut state, 3, [192, 168, 1, 2], "slamb", "hunter2").unwrap();
+
+        // Failures are forgotten after a day.
+        let later = LOGIN_FAILURE_MEMORY_SEC + 3;
+        assert_eq!(login(&mut state, later, a, "other", "x"), Err(None));
+        assert_eq!(login(&mut state, later, a, "other", "x"), Err(None));
+    }
+
+    #[test]
+    fn expire_user() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let req = Request {
+            when_sec: Some(42),
+            addr: None,
+            user_agent: None,
+        };
+        let uid = {
+            let mut c = UserChange::add_user("guest".to_owned());
+            c.set_password("hunter2".to_owned());
+            c.expiration_time_sec = Some(100);
+            state.apply(&conn, c).unwrap().id
+        };
+        let sid = state
+            .login_by_password(&conn, req.clone(), "guest", "hunter2".to_owned(), None, 0)
+            .unwrap()
+            .0;
+        assert!(state.expire_users(&conn, req.clone()).unwrap().is_empty());
+
+        // Authentication fails at expiry, even before the user is disabled.
+        let later = Request {
+            when_sec: Some(100),
+            ..req.clone()
+        };
+        let e = state
+            .authenticate_session(&conn, later.clone(), &sid.hash())
+            .unwrap_err();
+        assert_eq!(format!("{}", e), "user \"guest\" has expired");
+        let e = state
+            .login_by_password(&conn, later.clone(), "guest", "hunter2".to_owned(), None, 0)
+            .unwrap_err();
+        assert_eq!(format!("{}", e), "user \"guest\" has expired");
+
+        assert_eq!(state.expire_users(&conn, later.clone()).unwrap(), vec![uid]);
+        assert!(state.expire_users(&conn, later.clone()).unwrap().is_empty());
+
+        // The change should persist across reload.
+        drop(state);
+        let mut state = State::init(&conn).unwrap();
+        assert!(state.users_by_id().get(&uid).unwrap().disabled());
+        let e = state
+            .authenticate_session(&conn, req, &sid.hash())
+            .unwrap_err();
+        assert_eq!(format!("{}", e), "session is no longer valid (reason=3)");
+    }
+
     #[test]
     fn upgrade_hash() {
         // This hash is generated with cost=1 vs the cost=2 of PASTA_CONFIG.
@@ -1267,4 +2246,105 @@ mod tests {
         assert!(u.permissions.view_video);
         assert!(u.permissions.update_signals);
     }
+
+    #[test]
+    fn access_tokens() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let uid = state
+            .apply(&conn, UserChange::add_user("slamb".to_owned()))
+            .unwrap()
+            .id;
+        let mut permissions = Permissions::new();
+        permissions.view_video = true;
+        let (raw, t) = state
+            .create_token(&conn, uid, Some("robot".to_owned()), permissions, 42)
+            .unwrap();
+        let (id, hash) = (t.id, raw.hash());
+        let req = Request {
+            when_sec: Some(43),
+            user_agent: None,
+            addr: None,
+        };
+        let (t, u) = state.authenticate_token(req.clone(), &hash).unwrap();
+        assert!(t.permissions.view_video);
+        assert_eq!(u.id, uid);
+        assert_eq!(t.use_count, 1);
+        {
+            let tx = conn.transaction().unwrap();
+            state.flush(&tx).unwrap();
+            tx.commit().unwrap();
+        }
+        state.post_flush();
+
+        // The token survives a reload, including its last use.
+        let mut state = State::init(&conn).unwrap();
+        let t = state.tokens_by_id().get(&id).unwrap();
+        assert_eq!(t.description.as_deref(), Some("robot"));
+        assert_eq!(t.last_use_time_sec, Some(43));
+        assert_eq!(t.use_count, 1);
+        state.authenticate_token(req.clone(), &hash).unwrap();
+
+        // Revocation is immediate and persistent.
+        state.revoke_token(&conn, id, 44).unwrap();
+        let e = state.authenticate_token(req.clone(), &hash).unwrap_err();
+        assert_eq!(format!("{}", e), "no such access token");
+        let mut state = State::init(&conn).unwrap();
+        assert_eq!(
+            state.tokens_by_id().get(&id).unwrap().revocation_time_sec,
+            Some(44)
+        );
+        state.authenticate_token(req, &hash).unwrap_err();
+    }
+
+    #[test]
+    fn camera_restrictions() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        for id in &[1, 2] {
+            conn.execute(
+                "insert into camera (id, uuid, short_name) values (?, randomblob(16), 'c')",
+                params![id],
+            )
+            .unwrap();
+        }
+        let mut state = State::init(&conn).unwrap();
+        let u = state
+            .apply(&conn, UserChange::add_user("slamb".to_owned()))
+            .unwrap();
+        assert!(u.may_access_camera(1));
+        assert!(u.may_access_camera(2));
+        let mut change = u.change();
+        change.cameras = Some([2].iter().cloned().collect());
+        let u = state.apply(&conn, change).unwrap();
+        assert!(!u.may_access_camera(1));
+        assert!(u.may_access_camera(2));
+        let uid = u.id;
+
+        // The restriction survives a reload, and deleting the only allowed camera leaves the
+        // user restricted to nothing rather than unrestricted.
+        let mut state = State::init(&conn).unwrap();
+        assert!(!state.users_by_id().get(&uid).unwrap().may_access_camera(1));
+        {
+            let tx = conn.transaction().unwrap();
+            delete_camera(&tx, 2).unwrap();
+            tx.commit().unwrap();
+        }
+        state.camera_deleted(2);
+        let u = state.users_by_id().get(&uid).unwrap();
+        assert_eq!(u.cameras.as_ref().map(|c| c.len()), Some(0));
+        let mut state = State::init(&conn).unwrap();
+        let u = state.users_by_id().get(&uid).unwrap();
+        assert_eq!(u.cameras.as_ref().map(|c| c.len()), Some(0));
+        assert!(!u.may_access_camera(2));
+
+        // Clearing the restriction restores access.
+        let mut change = u.change();
+        change.cameras = None;
+        let u = state.apply(&conn, change).unwrap();
+        assert!(u.may_access_camera(1));
+    }
 }
@@ -0,0 +1,245 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Bookmarks: labeled time ranges whose recordings are protected against deletion.
+//!
+//! A bookmark applies to one camera's streams or to all cameras. Creating one sets the
+//! `Protected` flag on overlapping committed recordings, and `LockedDatabase::flush` sets it on
+//! newly committed recordings which overlap a bookmark, so footage of an incident still being
+//! recorded is protected too. Retention skips protected recordings. Like notes, bookmarks aren't
+//! cached in RAM.
+
+use crate::db::{self, CompositeId};
+use crate::recording;
+use base::{bail_t, ErrorKind, ResultExt};
+use rusqlite::named_params;
+use std::ops::Range;
+
+const INSERT_SQL: &str = r#"
+    insert into bookmark (camera_id,  start_time_90k,  end_time_90k,  label,  creation_time_sec,
+                          author)
+                  values (:camera_id, :start_time_90k, :end_time_90k, :label, :creation_time_sec,
+                          :author)
+"#;
+
+const LIST_SQL: &str = r#"
+    select
+      id,
+      camera_id,
+      start_time_90k,
+      end_time_90k,
+      label,
+      creation_time_sec,
+      author
+    from
+      bookmark
+    where
+      (:id is null or id = :id) and
+      start_time_90k < :end_time_90k and
+      end_time_90k > :start_time_90k
+    order by
+      start_time_90k,
+      id
+"#;
+
+/// A bookmark to add via `LockedDatabase::add_bookmark`.
+#[derive(Clone, Debug)]
+pub struct BookmarkToInsert {
+    /// The camera whose recordings to protect, or `None` for all cameras.
+    pub camera_id: Option<i32>,
+
+    /// The span of time to protect. Must be non-empty.
+    pub time: Range<recording::Time>,
+
+    pub label: String,
+
+    /// The time at which this bookmark was created, in seconds since epoch.
+    pub creation_time_sec: i64,
+
+    /// The name of the user who created this bookmark, if known.
+    pub author: Option<String>,
+}
+
+/// A bookmark, as returned by `LockedDatabase::list_bookmarks`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bookmark {
+    pub id: i64,
+    pub camera_id: Option<i32>,
+    pub time: Range<recording::Time>,
+    pub label: String,
+    pub creation_time_sec: i64,
+    pub author: Option<String>,
+}
+
+impl Bookmark {
+    /// Returns true iff this bookmark protects recordings of the given camera.
+    pub fn applies_to(&self, camera_id: i32) -> bool {
+        self.camera_id.map(|c| c == camera_id).unwrap_or(true)
+    }
+}
+
+/// Validates and inserts the given bookmark, returning its id.
+pub(crate) fn insert(
+    conn: &rusqlite::Connection,
+    b: &BookmarkToInsert,
+) -> Result<i64, base::Error> {
+    if b.label.trim().is_empty() {
+        bail_t!(InvalidArgument, "bookmark has empty label");
+    }
+    if b.time.start >= b.time.end {
+        bail_t!(
+            InvalidArgument,
+            "bookmark has empty time range {:?}",
+            b.time
+        );
+    }
+    let mut stmt = conn
+        .prepare_cached(INSERT_SQL)
+        .err_kind(ErrorKind::Internal)?;
+    stmt.execute_named(named_params! {
+        ":camera_id": b.camera_id,
+        ":start_time_90k": b.time.start.0,
+        ":end_time_90k": b.time.end.0,
+        ":label": b.label,
+        ":creation_time_sec": b.creation_time_sec,
+        ":author": b.author,
+    })
+    .err_kind(ErrorKind::Internal)?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Deletes the given bookmark, returning it.
+pub(crate) fn delete(conn: &rusqlite::Connection, id: i64) -> Result<Bookmark, base::Error> {
+    let mut b = None;
+    list_inner(
+        conn,
+        Some(id),
+        recording::Time::min_value()..recording::Time::max_value(),
+        &mut |row| {
+            b = Some(row);
+            Ok(())
+        },
+    )?;
+    let b = match b {
+        None => bail_t!(NotFound, "no such bookmark {}", id),
+        Some(b) => b,
+    };
+    let mut stmt = conn
+        .prepare_cached("delete from bookmark where id = :id")
+        .err_kind(ErrorKind::Internal)?;
+    stmt.execute_named(named_params! {":id": id})
+        .err_kind(ErrorKind::Internal)?;
+    Ok(b)
+}
+
+/// Deletes the bookmarks of the given camera, as when it's removed.
+pub(crate) fn delete_camera(
+    tx: &rusqlite::Transaction,
+    camera_id: i32,
+) -> Result<(), failure::Error> {
+    let mut stmt = tx.prepare_cached("delete from bookmark where camera_id = :camera_id")?;
+    stmt.execute_named(named_params! {":camera_id": camera_id})?;
+    Ok(())
+}
+
+/// Lists bookmarks overlapping `time`, in order of start time.
+pub(crate) fn list(
+    conn: &rusqlite::Connection,
+    time: Range<recording::Time>,
+    f: &mut dyn FnMut(Bookmark) -> Result<(), base::Error>,
+) -> Result<(), base::Error> {
+    list_inner(conn, None, time, f)
+}
+
+fn list_inner(
+    conn: &rusqlite::Connection,
+    id: Option<i64>,
+    time: Range<recording::Time>,
+    f: &mut dyn FnMut(Bookmark) -> Result<(), base::Error>,
+) -> Result<(), base::Error> {
+    let mut stmt = conn
+        .prepare_cached(LIST_SQL)
+        .err_kind(ErrorKind::Internal)?;
+    let mut rows = stmt
+        .query_named(named_params! {
+            ":id": id,
+            ":start_time_90k": time.start.0,
+            ":end_time_90k": time.end.0,
+        })
+        .err_kind(ErrorKind::Internal)?;
+    while let Some(row) = rows.next().err_kind(ErrorKind::Internal)? {
+        f(Bookmark {
+            id: row.get(0).err_kind(ErrorKind::Internal)?,
+            camera_id: row.get(1).err_kind(ErrorKind::Internal)?,
+            time: recording::Time(row.get(2).err_kind(ErrorKind::Internal)?)
+                ..recording::Time(row.get(3).err_kind(ErrorKind::Internal)?),
+            label: row.get(4).err_kind(ErrorKind::Internal)?,
+            creation_time_sec: row.get(5).err_kind(ErrorKind::Internal)?,
+            author: row.get(6).err_kind(ErrorKind::Internal)?,
+        })?;
+    }
+    Ok(())
+}
+
+/// Protects the given newly inserted recordings of a stream of `camera_id` which overlap a
+/// bookmark. Called by `LockedDatabase::flush` within its transaction.
+pub(crate) fn protect_new_recordings(
+    tx: &rusqlite::Transaction,
+    camera_id: i32,
+    ids: Range<CompositeId>,
+) -> Result<(), failure::Error> {
+    let mut stmt = tx.prepare_cached(
+        r#"
+        update recording
+        set
+          flags = flags | :flag
+        where
+          :start <= composite_id and
+          composite_id < :end and
+          exists (
+            select
+              1
+            from
+              bookmark
+            where
+              (bookmark.camera_id is null or bookmark.camera_id = :camera_id) and
+              bookmark.start_time_90k < recording.start_time_90k + recording.duration_90k and
+              bookmark.end_time_90k > recording.start_time_90k
+          )
+    "#,
+    )?;
+    stmt.execute_named(named_params! {
+        ":flag": db::RecordingFlags::Protected as i32,
+        ":start": ids.start.0,
+        ":end": ids.end.0,
+        ":camera_id": camera_id,
+    })?;
+    Ok(())
+}
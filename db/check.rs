@@ -36,19 +36,35 @@ use crate::dir;
 use crate::raw;
 use crate::recording;
 use crate::schema;
-use failure::Error;
+use crate::scrub;
+use base::crypto;
+use failure::{bail, Error};
 use fnv::FnvHashMap;
-use log::error;
-use nix::fcntl::AtFlags;
+use nix::fcntl::{AtFlags, FlockArg};
 use protobuf::prelude::MessageField;
-use rusqlite::params;
+use rusqlite::{named_params, params};
+use std::io::Read;
 use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+use uuid::Uuid;
 
 pub struct Options {
     pub compare_lens: bool,
+
+    /// Read each committed recording's sample file, checking its length and digest and noting
+    /// any corrupt ones in the `corrupt_recording` table. See `scrub.rs`.
+    pub scrub: bool,
+
+    /// The key with which to decrypt encrypted sample files while scrubbing or adopting.
+    pub sample_file_key: Option<Arc<dir::SampleFileKey>>,
+
+    /// Reconcile a database restored from an older backup with its (newer) sample file
+    /// directories. See `adopt_orphans`.
+    pub adopt_orphans: bool,
 }
 
-pub fn run(conn: &rusqlite::Connection, opts: &Options) -> Result<(), Error> {
+pub fn run(conn: &mut rusqlite::Connection, opts: &Options) -> Result<(), Error> {
     // Compare schemas.
     {
         let mut expected = rusqlite::Connection::open_in_memory()?;
@@ -62,6 +78,7 @@ pub fn run(conn: &rusqlite::Connection, opts: &Options) -> Result<(), Error> {
 
     // Scan directories.
     let mut streams_by_dir: FnvHashMap<i32, Dir> = FnvHashMap::default();
+    let mut dirs_by_id: FnvHashMap<i32, Arc<dir::SampleFileDir>> = FnvHashMap::default();
     {
         let mut dir_stmt = conn.prepare(
             r#"
@@ -87,8 +104,12 @@ pub fn run(conn: &rusqlite::Connection, opts: &Options) -> Result<(), Error> {
                 o.uuid.extend_from_slice(&open_uuid.0.as_bytes()[..]);
             }
 
+            if opts.adopt_orphans {
+                reset_dir_meta(&dir_path, &meta)?;
+            }
+
             // Open the directory (checking its metadata) and hold it open (for the lock).
-            let dir = dir::SampleFileDir::open(&dir_path, &meta)?;
+            let dir = dir::SampleFileDir::open(&dir_path, &meta, opts.sample_file_key.clone())?;
             let mut streams = read_dir(&dir, opts)?;
             let mut rows = garbage_stmt.query(params![dir_id])?;
             while let Some(row) = rows.next()? {
@@ -99,25 +120,94 @@ pub fn run(conn: &rusqlite::Connection, opts: &Options) -> Result<(), Error> {
                     .garbage_row = true;
             }
             streams_by_dir.insert(dir_id, streams);
+            dirs_by_id.insert(dir_id, dir);
         }
     }
 
     // Scan known streams.
+    let mut streams = Vec::new();
     {
         let mut stmt = conn.prepare(
             r#"
-            select id, sample_file_dir_id from stream where sample_file_dir_id is not null
+            select
+              id,
+              sample_file_dir_id,
+              failover_sample_file_dir_id
+            from
+              stream
+            where
+              sample_file_dir_id is not null
         "#,
         )?;
+        let mut stripe_stmt = conn.prepare_cached(
+            "select sample_file_dir_id from stream_stripe where stream_id = ? order by idx",
+        )?;
         let mut rows = stmt.query(params![])?;
         while let Some(row) = rows.next()? {
             let stream_id = row.get(0)?;
-            let dir_id = row.get(1)?;
-            let stream = match streams_by_dir.get_mut(&dir_id) {
-                None => Stream::default(),
-                Some(d) => d.remove(&stream_id).unwrap_or_else(Stream::default),
+            let mut dir_ids: Vec<i32> = vec![row.get(1)?];
+            let failover_dir_id: Option<i32> = row.get(2)?;
+            let mut stripe_rows = stripe_stmt.query(params![stream_id])?;
+            while let Some(row) = stripe_rows.next()? {
+                dir_ids.push(row.get(0)?);
+            }
+            streams.push((stream_id, dir_ids, failover_dir_id));
+        }
+    }
+    for (stream_id, dir_ids, failover_dir_id) in streams {
+        // Merge the stream's recordings from each of its stripe directories (checking that
+        // each file lives in the directory the writer would have chosen for it) and its
+        // failover directory (which may hold any recording).
+        let mut stream = Stream::default();
+        let stripes = dir_ids.iter().enumerate().map(|(i, &d)| (Some(i), d));
+        for (i, dir_id) in stripes.chain(failover_dir_id.map(|d| (None, d))) {
+            let s = match streams_by_dir.get_mut(&dir_id) {
+                None => continue,
+                Some(d) => match d.remove(&stream_id) {
+                    None => continue,
+                    Some(s) => s,
+                },
+            };
+            for (recording_id, r) in s {
+                let expected = db::stripe_index(recording_id, dir_ids.len());
+                if r.file.is_some() && i.map(|i| i != expected).unwrap_or(false) {
+                    error!(
+                        "recording {} is in dir {}; expected dir {}",
+                        CompositeId::new(stream_id, recording_id),
+                        dir_id,
+                        dir_ids[expected]
+                    );
+                }
+                let e = stream
+                    .entry(recording_id)
+                    .or_insert_with(Recording::default);
+                if e.file.is_none() && r.file.is_some() {
+                    e.file = r.file;
+                    e.failover = i.is_none();
+                }
+                e.garbage_row |= r.garbage_row;
+            }
+        }
+        if opts.adopt_orphans {
+            let dirs = dir::StreamDirs {
+                stripes: dir_ids
+                    .iter()
+                    .filter_map(|id| dirs_by_id.get(id).cloned())
+                    .collect(),
+                failover: failover_dir_id.and_then(|id| dirs_by_id.get(&id).cloned()),
             };
-            compare_stream(conn, stream_id, opts, stream)?;
+            if dirs.stripes.len() == dir_ids.len() {
+                adopt_orphans(conn, stream_id, &dir_ids, failover_dir_id, &dirs, &stream)?;
+            } else {
+                error!(
+                    "stream {} is missing sample file dirs; can't adopt",
+                    stream_id
+                );
+            }
+        }
+        compare_stream(conn, stream_id, opts, stream)?;
+        if opts.scrub {
+            scrub_stream(conn, stream_id, &dir_ids, failover_dir_id, &dirs_by_id)?;
         }
     }
 
@@ -169,6 +259,13 @@ struct Recording {
 
     /// True iff a `garbage` row is present.
     garbage_row: bool,
+
+    /// True iff the file was found in the stream's failover directory.
+    failover: bool,
+
+    /// True iff the `recording` row has the encrypted flag, so the file has
+    /// `dir::ENCRYPTION_OVERHEAD` bytes more than the recording.
+    encrypted: bool,
 }
 
 type Stream = FnvHashMap<i32, Recording>;
@@ -237,6 +334,328 @@ fn read_dir(d: &dir::SampleFileDir, opts: &Options) -> Result<Dir, Error> {
     Ok(dir)
 }
 
+/// Reads each of a known stream's committed recordings, noting any corrupt ones.
+fn scrub_stream(
+    conn: &rusqlite::Connection,
+    stream_id: i32,
+    dir_ids: &[i32],
+    failover_dir_id: Option<i32>,
+    dirs_by_id: &FnvHashMap<i32, Arc<dir::SampleFileDir>>,
+) -> Result<(), Error> {
+    let mut next = CompositeId::new(stream_id, 0);
+    let end = CompositeId::new(stream_id, i32::max_value());
+    let now = time::get_time().sec;
+    loop {
+        let batch = scrub::list_candidates(conn, next..end, 1000)?;
+        let last = match batch.last() {
+            None => return Ok(()),
+            Some(c) => c.id,
+        };
+        next = CompositeId(last.0 + 1);
+        for c in batch {
+            let dir_id = if (c.flags & db::RecordingFlags::Failover as i32) != 0 {
+                failover_dir_id
+            } else {
+                Some(dir_ids[db::stripe_index(c.id.recording(), dir_ids.len())])
+            };
+            let dir = match dir_id.and_then(|id| dirs_by_id.get(&id)) {
+                None => {
+                    error!("recording {} has no sample file dir", c.id);
+                    continue;
+                }
+                Some(d) => d,
+            };
+            if let Some(reason) = scrub::check_file(dir, &c, &mut |_| {}) {
+                error!("recording {} is corrupt: {}", c.id, reason);
+                scrub::mark_corrupt(conn, c.id, now, &reason)?;
+            }
+        }
+    }
+}
+
+/// For `--adopt-orphans`, rewrites a sample file directory's metadata to match the database's
+/// when the directory has been opened more recently than the database knows about, as happens
+/// when the database is restored from an older backup. `SampleFileDir::open` otherwise refuses
+/// such a directory.
+fn reset_dir_meta(path: &str, db_meta: &schema::DirMeta) -> Result<(), Error> {
+    let fd = dir::Fd::open(path, false)?;
+    fd.lock(FlockArg::LockExclusiveNonblock)?;
+    let dir_meta = dir::read_meta(&fd)?;
+    if dir_meta.db_uuid != db_meta.db_uuid
+        || dir_meta.dir_uuid != db_meta.dir_uuid
+        || dir::SampleFileDir::consistent(db_meta, &dir_meta)
+    {
+        return Ok(()); // nothing to reset, or not ours to reset; let open() decide.
+    }
+    warn!(
+        "sample file dir {} was opened after this database's last open of it; resetting its \
+         metadata.\nwas: {:#?}",
+        path, &dir_meta
+    );
+    dir::write_meta(fd.as_raw_fd(), db_meta)
+}
+
+/// Reconciles a known stream's recording rows with its sample files.
+///
+/// This is for a database restored from a backup older than its sample file directories. Rows
+/// whose files have since been deleted (by retention) are removed. Files written since (those at
+/// or after the stream's `next_recording_id`), which `moonfire-nvr run` would otherwise abandon,
+/// get recording rows reconstructed from their contents. Sample files don't record timing, so
+/// it's estimated: each adopted recording is assumed to have the frame rate of the stream's
+/// previous recording and to have ended when its file was last modified.
+fn adopt_orphans(
+    conn: &mut rusqlite::Connection,
+    stream_id: i32,
+    dir_ids: &[i32],
+    failover_dir_id: Option<i32>,
+    dirs: &dir::StreamDirs,
+    stream: &Stream,
+) -> Result<(), Error> {
+    let start = CompositeId::new(stream_id, 0);
+    let end = CompositeId::new(stream_id, i32::max_value());
+    let tx = conn.transaction()?;
+    let next_recording_id: i32 = tx.query_row(
+        "select next_recording_id from stream where id = ?",
+        params![stream_id],
+        |row| row.get(0),
+    )?;
+
+    // Remove rows for files which are gone.
+    let mut gone = Vec::new();
+    {
+        let mut stmt = tx.prepare_cached(
+            "select composite_id from recording where composite_id between ? and ?",
+        )?;
+        let mut rows = stmt.query(params![start.0, end.0])?;
+        while let Some(row) = rows.next()? {
+            let id = CompositeId(row.get(0)?);
+            if stream.get(&id.recording()).and_then(|r| r.file).is_none() {
+                gone.push(id);
+            }
+        }
+    }
+    for &id in &gone {
+        raw::delete_recordings(&tx, dir_ids, failover_dir_id, id..CompositeId(id.0 + 1))?;
+    }
+    raw::mark_sample_files_deleted(&tx, &gone)?;
+    if !gone.is_empty() {
+        warn!(
+            "stream {}: removed {} recording rows whose files are gone",
+            stream_id,
+            gone.len()
+        );
+    }
+
+    // Adopt files which have no rows.
+    let mut orphans: Vec<i32> = stream
+        .iter()
+        .filter(|&(&id, r)| id >= next_recording_id && r.file.is_some() && !r.garbage_row)
+        .map(|(&id, _)| id)
+        .collect();
+    orphans.sort_unstable();
+    let mut open: Option<db::Open> = None;
+    let mut adopted = 0;
+    let mut new_next_recording_id = next_recording_id;
+    for recording_id in orphans {
+        let id = CompositeId::new(stream_id, recording_id);
+        let (prev_end, frame_duration_90k, video_sample_entry_id) =
+            match prev_recording(&tx, start, id)? {
+                None => {
+                    error!(
+                        "recording {}: no earlier recording from which to estimate its timing; \
+                         not adopting",
+                        id
+                    );
+                    continue;
+                }
+                Some(p) => p,
+            };
+        let failover = stream[&recording_id].failover;
+        let dir = match dirs.get(
+            id,
+            if failover {
+                db::RecordingFlags::Failover as i32
+            } else {
+                0
+            },
+        ) {
+            None => continue,
+            Some(d) => d,
+        };
+        let r = match reconstruct(dir, id, frame_duration_90k, video_sample_entry_id, prev_end) {
+            Ok(mut r) => {
+                if failover {
+                    r.flags |= db::RecordingFlags::Failover as i32;
+                }
+                r
+            }
+            Err(e) => {
+                error!("recording {}: unable to adopt: {}", id, e);
+                continue;
+            }
+        };
+        if open.is_none() {
+            let uuid = Uuid::new_v4();
+            let now = recording::Time::new(time::get_time());
+            tx.execute(
+                "insert into open (uuid, start_time_90k) values (?, ?)",
+                params![&uuid.as_bytes()[..], now.0],
+            )?;
+            open = Some(db::Open {
+                id: tx.last_insert_rowid() as u32,
+                uuid,
+            });
+        }
+        raw::insert_recording(&tx, open.as_ref().unwrap(), id, &r)?;
+        info!(
+            "recording {}: adopted {} samples ({} bytes), starting at estimated time {}",
+            id, r.video_samples, r.sample_file_bytes, r.start
+        );
+        adopted += 1;
+        new_next_recording_id = recording_id + 1;
+    }
+    if adopted > 0 {
+        tx.execute(
+            "update stream set next_recording_id = ? where id = ?",
+            params![new_next_recording_id, stream_id],
+        )?;
+        warn!(
+            "stream {}: adopted {} recordings with estimated times",
+            stream_id, adopted
+        );
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Returns the end time, average frame duration, and video sample entry id of the recording
+/// before `id` in its stream, if any.
+fn prev_recording(
+    tx: &rusqlite::Transaction,
+    start: CompositeId,
+    id: CompositeId,
+) -> Result<Option<(i64, i32, i32)>, Error> {
+    let mut stmt = tx.prepare_cached(
+        r#"
+        select
+          start_time_90k,
+          duration_90k,
+          video_samples,
+          video_sample_entry_id
+        from
+          recording
+        where
+          composite_id between :start and :id - 1
+        order by
+          composite_id desc
+        limit 1
+    "#,
+    )?;
+    let mut rows = stmt.query_named(named_params! {
+        ":start": start.0,
+        ":id": id.0,
+    })?;
+    let row = match rows.next()? {
+        None => return Ok(None),
+        Some(r) => r,
+    };
+    let start_90k: i64 = row.get(0)?;
+    let duration_90k: i32 = row.get(1)?;
+    let video_samples: i32 = row.get(2)?;
+    Ok(Some((
+        start_90k + i64::from(duration_90k),
+        std::cmp::max(1, duration_90k / std::cmp::max(1, video_samples)),
+        row.get(3)?,
+    )))
+}
+
+/// Reconstructs a recording row for an orphaned sample file, as described at `adopt_orphans`.
+fn reconstruct(
+    dir: &dir::SampleFileDir,
+    id: CompositeId,
+    frame_duration_90k: i32,
+    video_sample_entry_id: i32,
+    prev_end: i64,
+) -> Result<db::RecordingToInsert, Error> {
+    let encrypted = dir.is_encrypted(id)?;
+    let mut data = Vec::new();
+    dir.open_reader(id, encrypted)?.read_to_end(&mut data)?;
+    let st = nix::sys::stat::fstat(dir.open_file(id)?.as_raw_fd())?;
+    let mtime = recording::Time::new(time::Timespec::new(st.st_mtime, st.st_mtime_nsec as i32));
+    let mut r = db::RecordingToInsert {
+        video_sample_entry_id,
+        ..Default::default()
+    };
+    if encrypted {
+        r.flags |= db::RecordingFlags::Encrypted as i32;
+    }
+    let mut encoder = recording::SampleIndexEncoder::new();
+    let len = split_samples(&data, &mut |bytes, is_key| {
+        if r.video_samples == 0 && !is_key {
+            bail!("doesn't start with a key frame");
+        }
+        encoder.add_sample(frame_duration_90k, bytes as i32, is_key, &mut r)
+    })?;
+    if r.video_samples == 0 {
+        bail!("has no complete samples");
+    }
+    if len < data.len() {
+        warn!(
+            "recording {}: ignoring {} trailing bytes of incomplete sample",
+            id,
+            data.len() - len
+        );
+    }
+    r.sample_file_blake3 = Some(crypto::blake3(&data[..len]));
+    r.start = recording::Time(std::cmp::max(prev_end, mtime.0 - i64::from(r.duration_90k)));
+    Ok(r)
+}
+
+/// Splits the contents of a sample file (H.264 NAL units, each prefixed with its 4-byte length)
+/// into samples (access units), calling `f` with the length and key-ness of each. Returns the
+/// number of bytes in complete samples.
+///
+/// An access unit starts with an access unit delimiter, SPS, PPS, or SEI NAL unit or with a
+/// picture's first slice (one with `first_mb_in_slice` 0), as in ITU-T H.264 section 7.4.1.2.3.
+fn split_samples(
+    data: &[u8],
+    f: &mut dyn FnMut(usize, bool) -> Result<(), Error>,
+) -> Result<usize, Error> {
+    let mut pos = 0;
+    let mut sample_start = 0;
+    let mut sample_has_slice = false;
+    let mut sample_is_key = false;
+    while pos + 5 <= data.len() {
+        let len =
+            u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        if len == 0 || pos + 4 + len > data.len() {
+            break;
+        }
+        let nal = &data[pos + 4..pos + 4 + len];
+        let nal_type = nal[0] & 0x1f;
+        let is_slice = nal_type == 1 || nal_type == 5;
+        let starts_sample = match nal_type {
+            6..=9 | 14..=18 => sample_has_slice,
+            1 | 5 => sample_has_slice && nal.len() > 1 && (nal[1] & 0x80) != 0,
+            _ => false,
+        };
+        if starts_sample {
+            f(pos - sample_start, sample_is_key)?;
+            sample_start = pos;
+            sample_has_slice = false;
+            sample_is_key = false;
+        }
+        sample_has_slice |= is_slice;
+        sample_is_key |= nal_type == 5;
+        pos += 4 + len;
+    }
+    if sample_has_slice {
+        f(pos - sample_start, sample_is_key)?;
+        return Ok(pos);
+    }
+    Ok(sample_start)
+}
+
 /// Looks through a known stream for errors.
 fn compare_stream(
     conn: &rusqlite::Connection,
@@ -267,17 +686,20 @@ fn compare_stream(
         let mut rows = stmt.query(params![start.0, end.0])?;
         while let Some(row) = rows.next()? {
             let id = CompositeId(row.get(0)?);
+            let flags: i32 = row.get(1)?;
             let s = RecordingSummary {
-                flags: row.get(1)?,
+                // Only the trailing zero flag can be derived from the index.
+                flags: flags & db::RecordingFlags::TrailingZero as i32,
                 bytes: row.get::<_, i64>(2)? as u64,
                 duration: row.get(3)?,
                 video_samples: row.get(4)?,
                 video_sync_samples: row.get(5)?,
             };
-            stream
+            let r = stream
                 .entry(id.recording())
-                .or_insert_with(Recording::default)
-                .recording_row = Some(s);
+                .or_insert_with(Recording::default);
+            r.recording_row = Some(s);
+            r.encrypted = (flags & db::RecordingFlags::Encrypted as i32) != 0;
         }
     }
 
@@ -287,7 +709,8 @@ fn compare_stream(
             r#"
             select
               composite_id,
-              video_index
+              video_index,
+              flags
             from
               recording_playback
             where
@@ -297,7 +720,13 @@ fn compare_stream(
         let mut rows = stmt.query(params![start.0, end.0])?;
         while let Some(row) = rows.next()? {
             let id = CompositeId(row.get(0)?);
-            let video_index: Vec<u8> = row.get(1)?;
+            let video_index = match raw::decode_video_index(row.get(1)?, row.get(2)?) {
+                Ok(i) => i,
+                Err(e) => {
+                    error!("id {} has bad video_index: {}", id, e);
+                    continue;
+                }
+            };
             let s = match summarize_index(&video_index) {
                 Ok(s) => s,
                 Err(e) => {
@@ -361,7 +790,12 @@ fn compare_stream(
         }
         match recording.file {
             Some(len) => {
-                if opts.compare_lens && r.bytes != len {
+                let expected = if recording.encrypted {
+                    r.bytes + dir::ENCRYPTION_OVERHEAD
+                } else {
+                    r.bytes
+                };
+                if opts.compare_lens && expected != len {
                     error!("Recording {} length mismatch: {:#?}", id, recording);
                 }
             }
@@ -371,3 +805,31 @@ fn compare_stream(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn split_samples() {
+        let mut data = Vec::new();
+        for nal in &[
+            &b"\x67\x64"[..],     // SPS
+            &b"\x68\xee"[..],     // PPS
+            &b"\x65\x88\x80"[..], // IDR slice, first_mb_in_slice = 0
+            &b"\x41\x9a\x00"[..], // non-IDR slice, first_mb_in_slice = 0
+            &b"\x41\x40"[..],     // non-IDR slice, first_mb_in_slice = 1
+            &b"\x41\x9a"[..],     // non-IDR slice, first_mb_in_slice = 0
+        ] {
+            data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            data.extend_from_slice(nal);
+        }
+        data.extend_from_slice(b"\x00\x00\x00\x0a\x41\x9a\x00"); // truncated.
+        let mut samples = Vec::new();
+        let len = super::split_samples(&data, &mut |bytes, is_key| {
+            samples.push((bytes, is_key));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(samples, &[(19, true), (13, false), (6, false)]);
+        assert_eq!(len, 38);
+    }
+}
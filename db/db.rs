@@ -53,19 +53,30 @@
 //!     cycles.
 
 use crate::auth;
+use crate::bookmark;
+use crate::detection;
 use crate::dir;
+use crate::export;
+use crate::gaps;
+use crate::mask;
+use crate::notify;
+use crate::playback;
 use crate::raw;
 use crate::recording::{self, TIME_UNITS_PER_SEC};
+use crate::retention;
 use crate::schema;
+use crate::scrub;
+use crate::search;
 use crate::signal;
+use crate::timeline;
 use base::clock::{self, Clocks};
+use base::crypto;
 use base::strutil::encode_size;
+use base::{bail_t, ErrorKind, ResultExt};
 use failure::{bail, format_err, Error};
 use fnv::{FnvHashMap, FnvHashSet};
 use itertools::Itertools;
-use log::{error, info, trace};
 use lru_cache::LruCache;
-use openssl::hash;
 use parking_lot::{Mutex, MutexGuard};
 use protobuf::prelude::MessageField;
 use rusqlite::{named_params, params};
@@ -79,17 +90,21 @@ use std::mem;
 use std::ops::Range;
 use std::str;
 use std::string::String;
+use std::sync::atomic::{self, AtomicU64};
 use std::sync::Arc;
+use std::time::Duration as StdDuration;
 use std::vec::Vec;
 use time;
+use tracing::{debug, error, info, trace};
 use uuid::Uuid;
 
 /// Expected schema version. See `guide/schema.md` for more information.
-pub const EXPECTED_VERSION: i32 = 5;
+pub const EXPECTED_VERSION: i32 = 6;
 
 const GET_RECORDING_PLAYBACK_SQL: &'static str = r#"
     select
-      video_index
+      video_index,
+      flags
     from
       recording_playback
     where
@@ -97,8 +112,8 @@ const GET_RECORDING_PLAYBACK_SQL: &'static str = r#"
 "#;
 
 const INSERT_VIDEO_SAMPLE_ENTRY_SQL: &'static str = r#"
-    insert into video_sample_entry (sha1,  width,  height,  rfc6381_codec, data)
-                            values (:sha1, :width, :height, :rfc6381_codec, :data)
+    insert into video_sample_entry (sha1,  width,  height,  rfc6381_codec,  data,  encoder)
+                            values (:sha1, :width, :height, :rfc6381_codec, :data, :encoder)
 "#;
 
 const UPDATE_NEXT_RECORDING_ID_SQL: &'static str =
@@ -128,14 +143,6 @@ impl rusqlite::types::FromSql for FromSqlUuid {
     }
 }
 
-struct VideoIndex(Box<[u8]>);
-
-impl rusqlite::types::FromSql for VideoIndex {
-    fn column_result(value: rusqlite::types::ValueRef) -> rusqlite::types::FromSqlResult<Self> {
-        Ok(VideoIndex(value.as_blob()?.to_vec().into_boxed_slice()))
-    }
-}
-
 /// A concrete box derived from a ISO/IEC 14496-12 section 8.5.2 VisualSampleEntry box. Describes
 /// the codec, width, height, etc.
 #[derive(Debug)]
@@ -146,6 +153,9 @@ pub struct VideoSampleEntry {
     pub width: u16,
     pub height: u16,
     pub sha1: [u8; 20],
+
+    /// For video transcoded by Moonfire NVR, the encoder's ffmpeg arguments.
+    pub encoder: Option<String>,
 }
 
 /// A row used in `list_recordings_by_time` and `list_recordings_by_id`.
@@ -207,6 +217,71 @@ impl ListAggregatedRecordingsRow {
     }
 }
 
+/// Adds `row` to the aggregated rows of `aggs`, keyed by run start id, passing any finished
+/// aggregated row for the same run to `f`. See `LockedDatabase::list_aggregated_recordings`.
+fn aggregate_recording(
+    stream_id: i32,
+    aggs: &mut BTreeMap<i32, ListAggregatedRecordingsRow>,
+    row: ListRecordingsRow,
+    forced_split: recording::Duration,
+    f: &mut dyn FnMut(&ListAggregatedRecordingsRow) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let recording_id = row.id.recording();
+    let run_start_id = recording_id - row.run_offset;
+    let uncommitted = (row.flags & RecordingFlags::Uncommitted as i32) != 0;
+    let growing = (row.flags & RecordingFlags::Growing as i32) != 0;
+    use std::collections::btree_map::Entry;
+    match aggs.entry(run_start_id) {
+        Entry::Occupied(mut e) => {
+            let a = e.get_mut();
+            let new_dur = a.time.end - a.time.start + recording::Duration(row.duration_90k as i64);
+            let needs_flush = a.ids.end != recording_id
+                || row.video_sample_entry_id != a.video_sample_entry_id
+                || new_dur >= forced_split;
+            if needs_flush {
+                // flush then start a new entry.
+                f(a)?;
+                *a = ListAggregatedRecordingsRow::from(row);
+            } else {
+                // append.
+                if a.time.end != row.start {
+                    bail!(
+                        "stream {} recording {} ends at {} but {} starts at {}",
+                        stream_id,
+                        a.ids.end - 1,
+                        a.time.end,
+                        row.id,
+                        row.start
+                    );
+                }
+                if a.open_id != row.open_id {
+                    bail!(
+                        "stream {} recording {} has open id {} but {} has {}",
+                        stream_id,
+                        a.ids.end - 1,
+                        a.open_id,
+                        row.id,
+                        row.open_id
+                    );
+                }
+                a.time.end.0 += row.duration_90k as i64;
+                a.ids.end = recording_id + 1;
+                a.video_samples += row.video_samples as i64;
+                a.video_sync_samples += row.video_sync_samples as i64;
+                a.sample_file_bytes += row.sample_file_bytes as i64;
+                if uncommitted {
+                    a.first_uncommitted = a.first_uncommitted.or(Some(recording_id));
+                }
+                a.growing = growing;
+            }
+        }
+        Entry::Vacant(e) => {
+            e.insert(ListAggregatedRecordingsRow::from(row));
+        }
+    }
+    Ok(())
+}
+
 /// Select fields from the `recordings_playback` table. Retrieve with `with_recording_playback`.
 #[derive(Debug)]
 pub struct RecordingPlayback<'a> {
@@ -216,6 +291,9 @@ pub struct RecordingPlayback<'a> {
 /// Bitmask in the `flags` field in the `recordings` table; see `schema.sql`.
 pub enum RecordingFlags {
     TrailingZero = 1,
+    Protected = 2,
+    Failover = 4,
+    Encrypted = 8,
 
     // These values (starting from high bit on down) are never written to the database.
     Growing = 1 << 30,
@@ -235,7 +313,16 @@ pub struct RecordingToInsert {
     pub video_sync_samples: i32,
     pub video_sample_entry_id: i32,
     pub video_index: Vec<u8>,
-    pub sample_file_sha1: [u8; 20],
+
+    /// The BLAKE3 digest of the sample file, filled in when the recording is closed.
+    pub sample_file_blake3: Option<[u8; 32]>,
+
+    /// The camera's RTP timestamp of the first frame and the clock rate of its units, if
+    /// received over RTP. See `recording_integrity.rtp_timestamp` in `schema.sql`.
+    pub rtp_timestamp: Option<(i64, i32)>,
+
+    /// A JPEG preview of the recording, as in the `thumbnail` table.
+    pub thumbnail: Option<Vec<u8>>,
 }
 
 impl RecordingToInsert {
@@ -262,6 +349,48 @@ pub(crate) struct ListOldestRecordingsRow {
     pub start: recording::Time,
     pub duration: i32,
     pub sample_file_bytes: i32,
+    pub flags: i32,
+}
+
+/// A recording which would be deleted, as returned by `LockedDatabase::preview_deletions`.
+#[derive(Clone, Debug)]
+pub struct PreviewedDeletion {
+    pub id: CompositeId,
+    pub time: Range<recording::Time>,
+    pub sample_file_bytes: i32,
+}
+
+/// A stream's recordings which would be deleted, as returned by
+/// `LockedDatabase::preview_deletions`.
+#[derive(Clone, Debug, Default)]
+pub struct StreamDeletionPreview {
+    /// The recordings, oldest first. Protected recordings are skipped, as when deleting.
+    pub recordings: Vec<PreviewedDeletion>,
+    pub sample_file_bytes: i64,
+    pub fs_bytes: i64,
+
+    /// The start of the stream's retained recordings afterward, or `None` if none would remain.
+    pub oldest_remaining: Option<recording::Time>,
+}
+
+/// A committed recording's sample file, as returned by `LockedDatabase::list_sample_files`.
+#[derive(Clone, Debug)]
+pub struct SampleFileRow {
+    pub id: CompositeId,
+    pub start: recording::Time,
+    pub duration_90k: i32,
+    pub sample_file_bytes: i32,
+    pub flags: i32,
+
+    /// The digest of the sample file as written, if the `recording_integrity` row has one.
+    pub digest: Option<scrub::Digest>,
+
+    /// The difference between the local clock and the recording's start time, as described at
+    /// `recording_integrity.local_time_delta_90k` in `schema.sql`.
+    pub local_time_delta_90k: Option<i64>,
+
+    /// The camera's RTP timestamp of the first frame and its clock rate, if known.
+    pub rtp_timestamp: Option<(i64, i32)>,
 }
 
 /// A calendar day in `YYYY-mm-dd` format.
@@ -316,6 +445,10 @@ pub struct SampleFileDir {
     dir: Option<Arc<dir::SampleFileDir>>,
     last_complete_open: Option<Open>,
 
+    /// If set, the time (in seconds since epoch) at which writing to this directory most recently
+    /// failed repeatedly. Streams with a failover directory avoid unhealthy directories.
+    pub unhealthy_since_sec: Option<i64>,
+
     /// ids which are in the `garbage` database table (rather than `recording`) as of last commit
     /// but may still exist on disk. These can't be safely removed from the database yet.
     pub(crate) garbage_needs_unlink: FnvHashSet<CompositeId>,
@@ -368,6 +501,9 @@ pub struct Camera {
     pub onvif_host: String,
     pub username: String,
     pub password: String,
+
+    /// True iff the camera supports ONVIF PTZ (pan-tilt-zoom) control.
+    pub ptz: bool,
     pub streams: [Option<i32>; 2],
 }
 
@@ -417,15 +553,200 @@ impl ::std::fmt::Display for StreamType {
 
 pub const ALL_STREAM_TYPES: [StreamType; 2] = [StreamType::MAIN, StreamType::SUB];
 
+/// The source of a virtual stream: a cropped and scaled region of another stream, as in the
+/// `virtual_stream` table.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VirtualSource {
+    pub source_stream_id: i32,
+    pub crop_x: i32,
+    pub crop_y: i32,
+    pub crop_width: i32,
+    pub crop_height: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl VirtualSource {
+    fn check(&self) -> Result<(), Error> {
+        if self.crop_x < 0 || self.crop_y < 0 {
+            bail!("virtual source crop offset can't be negative");
+        }
+        // The transcoded stream uses 4:2:0 chroma subsampling, so dimensions must be even.
+        for &d in &[self.crop_width, self.crop_height, self.width, self.height] {
+            if d <= 0 || d % 2 != 0 {
+                bail!(
+                    "virtual source dimensions must be positive and even; got {}",
+                    d
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The lower-layer transport of a stream's RTSP session.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RtspTransport {
+    /// RTP interleaved on the RTSP TCP connection. The default, as it works through NAT and
+    /// firewalls and doesn't lose packets.
+    Tcp,
+
+    /// RTP over separate UDP ports, which some cameras handle better.
+    Udp,
+}
+
+impl RtspTransport {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RtspTransport::Tcp => "tcp",
+            RtspTransport::Udp => "udp",
+        }
+    }
+
+    pub fn parse(transport: &str) -> Option<Self> {
+        match transport {
+            "tcp" => Some(RtspTransport::Tcp),
+            "udp" => Some(RtspTransport::Udp),
+            _ => None,
+        }
+    }
+}
+
+/// How a stream's RTSP session is opened and, after it fails, reopened.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RtspPolicy {
+    pub transport: RtspTransport,
+
+    /// The delay before retrying after a failed session. It doubles with each consecutive
+    /// failure, up to `reconnect_max_sec`, and resets once a session receives a key frame.
+    pub reconnect_min_sec: i64,
+    pub reconnect_max_sec: i64,
+
+    /// How long to wait for data from the camera before giving up on the session.
+    pub session_timeout_sec: i64,
+}
+
+impl Default for RtspPolicy {
+    fn default() -> Self {
+        RtspPolicy {
+            transport: RtspTransport::Tcp,
+            reconnect_min_sec: 1,
+            reconnect_max_sec: 1,
+            session_timeout_sec: 10,
+        }
+    }
+}
+
+impl RtspPolicy {
+    fn check(&self) -> Result<(), Error> {
+        if self.reconnect_min_sec <= 0 || self.reconnect_max_sec < self.reconnect_min_sec {
+            bail!(
+                "bad reconnect backoff {}..{} sec",
+                self.reconnect_min_sec,
+                self.reconnect_max_sec
+            );
+        }
+        if self.session_timeout_sec <= 0 {
+            bail!("bad session timeout {} sec", self.session_timeout_sec);
+        }
+        Ok(())
+    }
+
+    /// Returns how long to wait before reopening the session after `failures` consecutive
+    /// failures (at least 1).
+    pub fn reconnect_delay_sec(&self, failures: u32) -> i64 {
+        let shift = failures.saturating_sub(1).min(32);
+        self.reconnect_min_sec
+            .saturating_mul(1 << shift)
+            .min(self.reconnect_max_sec)
+    }
+}
+
+/// The ffmpeg input options which a stream's `input_options` may set. These are tuning knobs
+/// for quirky cameras; options which would change what's recorded (such as
+/// `allowed_media_types`) aren't allowed. `framerate`, `input_format`, and `video_size` select
+/// the capture mode of a V4L2 device; `latency` sets an SRT stream's latency window.
+pub const ALLOWED_INPUT_OPTIONS: [&str; 12] = [
+    "analyzeduration",
+    "buffer_size",
+    "fflags",
+    "framerate",
+    "input_format",
+    "latency",
+    "max_delay",
+    "probesize",
+    "reorder_queue_size",
+    "rtsp_flags",
+    "stimeout",
+    "video_size",
+];
+
+/// Parses a stream's `input_options`: whitespace-separated `name=value` pairs, each naming one
+/// of `ALLOWED_INPUT_OPTIONS`.
+pub fn parse_input_options(s: &str) -> Result<Vec<(&str, &str)>, Error> {
+    let mut opts: Vec<(&str, &str)> = Vec::new();
+    for pair in s.split_whitespace() {
+        let mut parts = pair.splitn(2, '=');
+        let name = parts.next().unwrap();
+        let value = match parts.next() {
+            Some(v) if !v.is_empty() => v,
+            _ => bail!("input option {:?} isn't of the form name=value", pair),
+        };
+        if !ALLOWED_INPUT_OPTIONS.contains(&name) {
+            bail!(
+                "input option {:?} isn't allowed; try one of {}",
+                name,
+                ALLOWED_INPUT_OPTIONS.join(", ")
+            );
+        }
+        if !value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"+-_.:,".contains(&b))
+        {
+            bail!("input option {} has invalid value {:?}", name, value);
+        }
+        if opts.iter().any(|&(n, _)| n == name) {
+            bail!("input option {} is given more than once", name);
+        }
+        opts.push((name, value));
+    }
+    Ok(opts)
+}
+
 pub struct Stream {
     pub id: i32,
     pub camera_id: i32,
     pub sample_file_dir_id: Option<i32>,
+
+    /// Additional sample file directories across which recordings are striped; see `dir_id_for`.
+    /// Only allowed when `sample_file_dir_id` is set.
+    pub stripe_dir_ids: Vec<i32>,
+
+    /// A sample file directory to which new recordings go when their usual directory is
+    /// unhealthy. Such recordings are marked with `RecordingFlags::Failover`.
+    pub failover_sample_file_dir_id: Option<i32>,
+
     pub type_: StreamType,
     pub rtsp_url: String,
+
+    /// If this is a virtual stream, what it's transcoded from. `rtsp_url` is then empty.
+    pub virtual_source: Option<VirtualSource>,
+
     pub retain_bytes: i64,
     pub flush_if_sec: i64,
 
+    /// Flush as soon as the synced but unflushed recordings total at least this many bytes;
+    /// 0 for no limit.
+    pub flush_if_bytes: i64,
+
+    /// Extra ffmpeg input options, as accepted by `parse_input_options`.
+    pub input_options: String,
+
+    pub rtsp: RtspPolicy,
+
+    /// Regions to black out of images decoded from this stream; see `mask::Mask`.
+    pub privacy_mask: mask::Mask,
+
     /// The time range of recorded data associated with this stream (minimum start time and maximum
     /// end time). `None` iff there are no recordings for this camera.
     pub range: Option<Range<recording::Time>>,
@@ -439,7 +760,7 @@ pub struct Stream {
     pub fs_bytes: i64,
 
     /// On flush, delete the following recordings (move them to the `garbage` table, to be
-    /// collected later). Note they must be the oldest recordings. The later collection involves
+    /// collected later). Note they must be in id order. The later collection involves
     /// the syncer unlinking the files on disk and syncing the directory then enqueueing for
     /// another following flush removal from the `garbage` table.
     to_delete: Vec<ListOldestRecordingsRow>,
@@ -453,6 +774,10 @@ pub struct Stream {
     pub bytes_to_add: i64,
     pub fs_bytes_to_add: i64,
 
+    /// Time ranges passed to `LockedDatabase::protect_recordings` which may overlap recordings
+    /// that haven't been committed yet. Those recordings are protected as they're flushed.
+    protect_pending: Vec<Range<recording::Time>>,
+
     /// The total duration of recorded data. This may not be `range.end - range.start` due to
     /// gaps and overlap.
     pub duration: recording::Duration,
@@ -462,6 +787,14 @@ pub struct Stream {
     pub committed_days: BTreeMap<StreamDayKey, StreamDayValue>,
     pub record: bool,
 
+    /// True if recording is paused because the sample file directory is out of space and the
+    /// `DiskFullPolicy` couldn't free any. Cleared when a later recording writes successfully.
+    pub disk_full: bool,
+
+    /// Symptoms suggesting another client, such as a second NVR, is also connected to the
+    /// camera and resetting its encoder, if any. Set by the streamer; not persisted.
+    pub multi_homed: Option<String>,
+
     /// The `next_recording_id` currently committed to the database.
     pub(crate) next_recording_id: i32,
 
@@ -492,12 +825,46 @@ pub struct LiveSegment {
     pub off_90k: Range<i32>,
 }
 
+/// A change to database state of interest to clients; see `LockedDatabase::watch_events`.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// A recording was committed to the database by a flush.
+    RecordingCommitted {
+        id: CompositeId,
+        time: Range<recording::Time>,
+        sample_file_bytes: i32,
+    },
+
+    /// Signal states were changed as in `LockedDatabase::update_signals`.
+    SignalsChanged {
+        time: Range<recording::Time>,
+        signals: Vec<u32>,
+        states: Vec<u16>,
+    },
+
+    /// The given stream's `disk_full` flag was set or cleared.
+    DiskFull { stream_id: i32, full: bool },
+
+    /// The given sample file directory was marked unhealthy or healthy.
+    DirUnhealthy { dir_id: i32, unhealthy: bool },
+
+    /// Cameras or streams were added, changed, or removed, as by `LockedDatabase::add_camera`,
+    /// `update_camera`, `delete_camera`, or `update_retention`.
+    StreamsChanged,
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct StreamChange {
     pub sample_file_dir_id: Option<i32>,
+    pub stripe_dir_ids: Vec<i32>,
+    pub failover_sample_file_dir_id: Option<i32>,
     pub rtsp_url: String,
+    pub virtual_source: Option<VirtualSource>,
     pub record: bool,
     pub flush_if_sec: i64,
+    pub flush_if_bytes: i64,
+    pub input_options: String,
+    pub rtsp: RtspPolicy,
 }
 
 /// Information about a camera, used by `add_camera` and `update_camera`.
@@ -508,6 +875,7 @@ pub struct CameraChange {
     pub onvif_host: String,
     pub username: String,
     pub password: String,
+    pub ptz: bool,
 
     /// `StreamType t` is represented by `streams[t.index()]`. A default StreamChange will
     /// correspond to no stream in the database, provided there are no existing recordings for that
@@ -607,6 +975,28 @@ fn adjust_days(
 }
 
 impl Stream {
+    /// Returns the sample file directories across which this stream's recordings are striped:
+    /// `sample_file_dir_id` (if any) followed by `stripe_dir_ids`.
+    pub fn dir_ids(&self) -> Vec<i32> {
+        self.sample_file_dir_id
+            .iter()
+            .chain(self.stripe_dir_ids.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the sample file directory holding the recording with the given id and flags.
+    pub fn dir_id_for(&self, recording_id: i32, flags: i32) -> Option<i32> {
+        let d = self.sample_file_dir_id?;
+        if (flags & RecordingFlags::Failover as i32) != 0 {
+            return self.failover_sample_file_dir_id;
+        }
+        match stripe_index(recording_id, 1 + self.stripe_dir_ids.len()) {
+            0 => Some(d),
+            i => Some(self.stripe_dir_ids[i - 1]),
+        }
+    }
+
     /// Adds a single fully committed recording with the given properties to the in-memory state.
     fn add_recording(&mut self, r: Range<recording::Time>, sample_file_bytes: i32) {
         self.range = Some(match self.range {
@@ -632,6 +1022,14 @@ impl Stream {
         }
         days
     }
+
+    /// Returns true iff a recording of this stream is in progress.
+    pub fn is_recording(&self) -> bool {
+        self.uncommitted
+            .back()
+            .map(|u| (u.lock().flags & RecordingFlags::Growing as i32) != 0)
+            .unwrap_or(false)
+    }
 }
 
 /// Initializes the recordings associated with the given camera.
@@ -687,14 +1085,171 @@ pub struct LockedDatabase {
 
     auth: auth::State,
     signal: signal::State,
+    playback: playback::State,
 
     sample_file_dirs_by_id: BTreeMap<i32, SampleFileDir>,
     cameras_by_id: BTreeMap<i32, Camera>,
     streams_by_id: BTreeMap<i32, Stream>,
     cameras_by_uuid: BTreeMap<Uuid, i32>, // values are ids.
     video_sample_entries_by_id: BTreeMap<i32, Arc<VideoSampleEntry>>,
-    video_index_cache: RefCell<LruCache<i64, Box<[u8]>, fnv::FnvBuildHasher>>,
+    video_index_cache: RefCell<VideoIndexCache>,
     on_flush: Vec<Box<dyn Fn() + Send>>,
+    event_watchers: Vec<Box<dyn FnMut(&LockedDatabase, &Event) -> bool + Send>>,
+    uncommitted_limits: UncommittedLimits,
+    max_unflushed_recordings: Option<usize>,
+
+    /// If set, each camera's sub stream keeps recordings spanning at most this multiple of its
+    /// main stream's retained history; see `set_sub_retention_multiple`.
+    sub_retention_multiple: Option<f64>,
+    disk_full_policy: DiskFullPolicy,
+    preallocation: Preallocation,
+
+    /// The key with which sample file directories opened from now on encrypt new files.
+    sample_file_key: Option<Arc<dir::SampleFileKey>>,
+
+    /// The number of failed flushes and the most recent failure's error message.
+    flush_failures: u64,
+    last_flush_failure: Option<String>,
+
+    /// True iff the most recent flush failed.
+    flush_failing: bool,
+}
+
+/// The default limit on the total size of cached video indexes.
+pub const DEFAULT_VIDEO_INDEX_CACHE_BYTES: usize = 8 << 20;
+
+/// An LRU cache of committed recordings' `video_index` blobs, keyed by composite id and bounded
+/// by their total size rather than count, as index sizes vary with frame rate and recording
+/// length.
+struct VideoIndexCache {
+    entries: LruCache<i64, Box<[u8]>, fnv::FnvBuildHasher>,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl VideoIndexCache {
+    fn new(max_bytes: usize) -> Self {
+        VideoIndexCache {
+            entries: LruCache::with_hasher(usize::max_value(), Default::default()),
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    fn get(&mut self, id: CompositeId) -> Option<&[u8]> {
+        self.entries.get_mut(&id.0).map(|v| &v[..])
+    }
+
+    /// Inserts an entry, evicting the least recently used ones to fit. An index larger than the
+    /// whole cache isn't inserted.
+    fn insert(&mut self, id: CompositeId, video_index: Box<[u8]>) {
+        if video_index.len() > self.max_bytes {
+            return;
+        }
+        self.bytes += video_index.len();
+        if let Some(old) = self.entries.insert(id.0, video_index) {
+            self.bytes -= old.len();
+        }
+        self.evict();
+    }
+
+    fn remove(&mut self, id: CompositeId) {
+        if let Some(old) = self.entries.remove(&id.0) {
+            self.bytes -= old.len();
+        }
+    }
+
+    fn set_max_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.bytes > self.max_bytes {
+            match self.entries.remove_lru() {
+                Some((_, v)) => self.bytes -= v.len(),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Soft limits on the recordings which have been added via `LockedDatabase::add_recording` but
+/// not yet committed, summed across all streams. These bound the in-memory state when flushes
+/// are failing or slow, such as when the database is on a dying SD card.
+///
+/// When either limit is exceeded, new runs of low-priority (sub) streams are refused. When
+/// either is exceeded by a factor of two, all new recordings are refused. Recordings already
+/// in progress are unaffected, so the excess is bounded by one recording per stream.
+#[derive(Clone, Debug, Default)]
+pub struct UncommittedLimits {
+    pub max_recordings: Option<usize>,
+    pub max_bytes: Option<i64>,
+}
+
+/// What the writer does when a sample file write fails with `ENOSPC`.
+#[derive(Clone, Debug)]
+pub enum DiskFullPolicy {
+    /// Fail the write, pausing the stream until space is available.
+    Pause,
+
+    /// Delete the stream's oldest recordings, even beyond its `retain_bytes`, until the write
+    /// succeeds. Pauses as above after deleting `max_bytes` in total without success.
+    EmergencyDelete { max_bytes: i64 },
+}
+
+impl Default for DiskFullPolicy {
+    fn default() -> Self {
+        DiskFullPolicy::Pause
+    }
+}
+
+/// How much space the writer reserves with `fallocate` when creating each sample file, so that
+/// filesystems such as ext4 and XFS can lay out long recordings contiguously. The unused portion
+/// is released when the recording is closed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Preallocation {
+    Off,
+
+    /// Reserve this many bytes for every recording.
+    Fixed(u64),
+
+    /// Reserve the size of the stream's previous recording plus 1/8, or nothing for the first
+    /// recording of a run.
+    Previous,
+}
+
+impl Default for Preallocation {
+    fn default() -> Self {
+        Preallocation::Off
+    }
+}
+
+impl str::FromStr for Preallocation {
+    type Err = Error;
+
+    /// Parses `off`, `previous`, or a size as in `base::strutil::decode_size` (eg `64M`).
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "off" => Ok(Preallocation::Off),
+            "previous" => Ok(Preallocation::Previous),
+            _ => match base::strutil::decode_size(s) {
+                Ok(0) => Ok(Preallocation::Off),
+                Ok(b) if b > 0 => Ok(Preallocation::Fixed(b as u64)),
+                _ => bail!(
+                    "bad preallocation {:?}; expected off, previous, or a size",
+                    s
+                ),
+            },
+        }
+    }
+}
+
+/// Returns the index within a stream's `stripes` (as in `Stream::dir_ids`) of the directory
+/// holding the given recording. This depends on the number of stripes, which is why they can't
+/// change once the stream has recordings.
+pub fn stripe_index(recording_id: i32, stripes: usize) -> usize {
+    recording_id as usize % stripes
 }
 
 /// Represents a row of the `open` database table.
@@ -748,10 +1303,63 @@ impl StreamStateChanger {
         let existing_streams = existing.map(|e| e.streams).unwrap_or_default();
         for (i, ref mut sc) in change.streams.iter_mut().enumerate() {
             let type_ = StreamType::from_index(i).unwrap();
+            if !sc.stripe_dir_ids.is_empty() {
+                let d = match sc.sample_file_dir_id {
+                    None => bail!("{} stream has stripe dirs but no sample file dir", type_),
+                    Some(d) => d,
+                };
+                let mut all = sc.stripe_dir_ids.clone();
+                all.push(d);
+                all.sort();
+                if all.windows(2).any(|w| w[0] == w[1]) {
+                    bail!("{} stream has duplicate sample file dirs", type_);
+                }
+            }
+            parse_input_options(&sc.input_options)
+                .map_err(|e| format_err!("{} stream: {}", type_, e))?;
+            sc.rtsp
+                .check()
+                .map_err(|e| format_err!("{} stream: {}", type_, e))?;
+            if let Some(v) = sc.virtual_source {
+                if !sc.rtsp_url.is_empty() {
+                    bail!("{} stream has both an RTSP URL and a virtual source", type_);
+                }
+                v.check()?;
+                if existing_streams[i] == Some(v.source_stream_id) {
+                    bail!("{} stream can't be its own virtual source", type_);
+                }
+                match streams_by_id.get(&v.source_stream_id) {
+                    None => bail!(
+                        "{} stream's virtual source stream {} doesn't exist",
+                        type_,
+                        v.source_stream_id
+                    ),
+                    Some(s) if s.virtual_source.is_some() => bail!(
+                        "{} stream's virtual source stream {} is itself virtual",
+                        type_,
+                        v.source_stream_id
+                    ),
+                    Some(_) => {}
+                }
+            }
+            if let Some(f) = sc.failover_sample_file_dir_id {
+                if sc.sample_file_dir_id.is_none() {
+                    bail!("{} stream has a failover dir but no sample file dir", type_);
+                }
+                if sc.sample_file_dir_id == Some(f) || sc.stripe_dir_ids.contains(&f) {
+                    bail!(
+                        "{} stream's failover dir is one of its sample file dirs",
+                        type_
+                    );
+                }
+            }
             let mut have_data = false;
             if let Some(sid) = existing_streams[i] {
                 let s = streams_by_id.get(&sid).unwrap();
-                if s.range.is_some() {
+
+                // A recording in progress will be committed to the stream's current directories,
+                // so treat it like a committed one.
+                if s.range.is_some() || !s.uncommitted.is_empty() {
                     have_data = true;
                     if let (Some(d), false) = (
                         s.sample_file_dir_id,
@@ -764,13 +1372,40 @@ impl StreamStateChanger {
                             sid
                         );
                     }
+                    if s.stripe_dir_ids != sc.stripe_dir_ids {
+                        bail!(
+                            "can't change stripe_dir_ids {:?}->{:?} for non-empty stream {}",
+                            s.stripe_dir_ids,
+                            sc.stripe_dir_ids,
+                            sid
+                        );
+                    }
+
+                    // Adding a failover dir is fine; existing recordings can't be in it.
+                    if let (Some(f), false) = (
+                        s.failover_sample_file_dir_id,
+                        s.failover_sample_file_dir_id == sc.failover_sample_file_dir_id,
+                    ) {
+                        bail!(
+                            "can't change failover_sample_file_dir_id {:?}->{:?} for non-empty \
+                             stream {}",
+                            f,
+                            sc.failover_sample_file_dir_id,
+                            sid
+                        );
+                    }
                 }
                 if !have_data
                     && sc.rtsp_url.is_empty()
+                    && sc.virtual_source.is_none()
                     && sc.sample_file_dir_id.is_none()
                     && !sc.record
                 {
                     // Delete stream.
+                    check_not_virtual_source(streams_by_id, sid)?;
+                    raw::set_stream_stripes(tx, sid, &[])?;
+                    raw::set_virtual_source(tx, sid, None)?;
+                    playback::delete_stream(tx, sid)?;
                     let mut stmt = tx.prepare_cached(
                         r#"
                         delete from stream where id = ?
@@ -788,7 +1423,14 @@ impl StreamStateChanger {
                             rtsp_url = :rtsp_url,
                             record = :record,
                             flush_if_sec = :flush_if_sec,
-                            sample_file_dir_id = :sample_file_dir_id
+                            flush_if_bytes = :flush_if_bytes,
+                            input_options = :input_options,
+                            rtsp_transport = :rtsp_transport,
+                            reconnect_min_sec = :reconnect_min_sec,
+                            reconnect_max_sec = :reconnect_max_sec,
+                            session_timeout_sec = :session_timeout_sec,
+                            sample_file_dir_id = :sample_file_dir_id,
+                            failover_sample_file_dir_id = :failover_sample_file_dir_id
                         where
                             id = :id
                     "#,
@@ -797,18 +1439,31 @@ impl StreamStateChanger {
                         ":rtsp_url": &sc.rtsp_url,
                         ":record": sc.record,
                         ":flush_if_sec": sc.flush_if_sec,
+                        ":flush_if_bytes": sc.flush_if_bytes,
+                        ":input_options": &sc.input_options,
+                        ":rtsp_transport": sc.rtsp.transport.as_str(),
+                        ":reconnect_min_sec": sc.rtsp.reconnect_min_sec,
+                        ":reconnect_max_sec": sc.rtsp.reconnect_max_sec,
+                        ":session_timeout_sec": sc.rtsp.session_timeout_sec,
                         ":sample_file_dir_id": sc.sample_file_dir_id,
+                        ":failover_sample_file_dir_id": sc.failover_sample_file_dir_id,
                         ":id": sid,
                     })?;
                     if rows != 1 {
                         bail!("missing stream {}", sid);
                     }
+                    raw::set_stream_stripes(tx, sid, &sc.stripe_dir_ids)?;
+                    raw::set_virtual_source(tx, sid, sc.virtual_source.as_ref())?;
                     sids[i] = Some(sid);
                     let sc = mem::replace(*sc, StreamChange::default());
                     streams.push((sid, Some((camera_id, type_, sc))));
                 }
             } else {
-                if sc.rtsp_url.is_empty() && sc.sample_file_dir_id.is_none() && !sc.record {
+                if sc.rtsp_url.is_empty()
+                    && sc.virtual_source.is_none()
+                    && sc.sample_file_dir_id.is_none()
+                    && !sc.record
+                {
                     // Do nothing; there is no record and we want to keep it that way.
                     continue;
                 }
@@ -816,9 +1471,15 @@ impl StreamStateChanger {
                 let mut stmt = tx.prepare_cached(
                     r#"
                     insert into stream (camera_id,  sample_file_dir_id,  type,  rtsp_url,  record,
-                                        retain_bytes, flush_if_sec,  next_recording_id)
+                                        retain_bytes, flush_if_sec,  next_recording_id,
+                                        failover_sample_file_dir_id,  flush_if_bytes,
+                                        input_options,  rtsp_transport,  reconnect_min_sec,
+                                        reconnect_max_sec,  session_timeout_sec)
                                 values (:camera_id, :sample_file_dir_id, :type, :rtsp_url, :record,
-                                        0,            :flush_if_sec, 1)
+                                        0,            :flush_if_sec, 1,
+                                        :failover_sample_file_dir_id, :flush_if_bytes,
+                                        :input_options, :rtsp_transport, :reconnect_min_sec,
+                                        :reconnect_max_sec, :session_timeout_sec)
                 "#,
                 )?;
                 stmt.execute_named(named_params! {
@@ -828,8 +1489,17 @@ impl StreamStateChanger {
                     ":rtsp_url": &sc.rtsp_url,
                     ":record": sc.record,
                     ":flush_if_sec": sc.flush_if_sec,
+                    ":failover_sample_file_dir_id": sc.failover_sample_file_dir_id,
+                    ":flush_if_bytes": sc.flush_if_bytes,
+                    ":input_options": &sc.input_options,
+                    ":rtsp_transport": sc.rtsp.transport.as_str(),
+                    ":reconnect_min_sec": sc.rtsp.reconnect_min_sec,
+                    ":reconnect_max_sec": sc.rtsp.reconnect_max_sec,
+                    ":session_timeout_sec": sc.rtsp.session_timeout_sec,
                 })?;
                 let id = tx.last_insert_rowid() as i32;
+                raw::set_stream_stripes(tx, id, &sc.stripe_dir_ids)?;
+                raw::set_virtual_source(tx, id, sc.virtual_source.as_ref())?;
                 sids[i] = Some(id);
                 let sc = mem::replace(*sc, StreamChange::default());
                 streams.push((id, Some((camera_id, type_, sc))));
@@ -850,9 +1520,16 @@ impl StreamStateChanger {
                         type_,
                         camera_id,
                         sample_file_dir_id: sc.sample_file_dir_id,
+                        stripe_dir_ids: mem::replace(&mut sc.stripe_dir_ids, Vec::new()),
+                        failover_sample_file_dir_id: sc.failover_sample_file_dir_id,
                         rtsp_url: mem::replace(&mut sc.rtsp_url, String::new()),
+                        virtual_source: sc.virtual_source,
                         retain_bytes: 0,
                         flush_if_sec: sc.flush_if_sec,
+                        flush_if_bytes: sc.flush_if_bytes,
+                        input_options: mem::replace(&mut sc.input_options, String::new()),
+                        rtsp: sc.rtsp,
+                        privacy_mask: mask::Mask::default(),
                         range: None,
                         sample_file_bytes: 0,
                         fs_bytes: 0,
@@ -861,9 +1538,12 @@ impl StreamStateChanger {
                         fs_bytes_to_delete: 0,
                         bytes_to_add: 0,
                         fs_bytes_to_add: 0,
+                        protect_pending: Vec::new(),
                         duration: recording::Duration(0),
                         committed_days: BTreeMap::new(),
                         record: sc.record,
+                        disk_full: false,
+                        multi_homed: None,
                         next_recording_id: 1,
                         uncommitted: VecDeque::new(),
                         synced_recordings: 0,
@@ -874,9 +1554,15 @@ impl StreamStateChanger {
                 (Entry::Occupied(e), Some((_, _, sc))) => {
                     let e = e.into_mut();
                     e.sample_file_dir_id = sc.sample_file_dir_id;
+                    e.stripe_dir_ids = sc.stripe_dir_ids;
+                    e.failover_sample_file_dir_id = sc.failover_sample_file_dir_id;
                     e.rtsp_url = sc.rtsp_url;
+                    e.virtual_source = sc.virtual_source;
                     e.record = sc.record;
                     e.flush_if_sec = sc.flush_if_sec;
+                    e.flush_if_bytes = sc.flush_if_bytes;
+                    e.input_options = sc.input_options;
+                    e.rtsp = sc.rtsp;
                 }
                 (Entry::Occupied(e), None) => {
                     e.remove();
@@ -887,6 +1573,16 @@ impl StreamStateChanger {
     }
 }
 
+/// Fails if the given stream is the source of a virtual stream, so it can't be deleted.
+fn check_not_virtual_source(streams_by_id: &BTreeMap<i32, Stream>, id: i32) -> Result<(), Error> {
+    for s in streams_by_id.values() {
+        if s.virtual_source.map(|v| v.source_stream_id) == Some(id) {
+            bail!("stream {} is the source of virtual stream {}", id, s.id);
+        }
+    }
+    Ok(())
+}
+
 /// A retention change as expected by `LockedDatabase::update_retention`.
 pub struct RetentionChange {
     pub stream_id: i32,
@@ -908,47 +1604,279 @@ impl LockedDatabase {
         self.flush_count
     }
 
-    /// Adds a placeholder for an uncommitted recording.
-    /// The caller should write samples and fill the returned `RecordingToInsert` as it goes
-    /// (noting that while holding the lock, it should not perform I/O or acquire the database
-    /// lock). Then it should sync to permanent storage and call `mark_synced`. The data will
-    /// be written to the database on the next `flush`.
-    pub(crate) fn add_recording(
-        &mut self,
-        stream_id: i32,
-        r: RecordingToInsert,
-    ) -> Result<(CompositeId, Arc<Mutex<RecordingToInsert>>), Error> {
-        let stream = match self.streams_by_id.get_mut(&stream_id) {
-            None => bail!("no such stream {}", stream_id),
-            Some(s) => s,
-        };
-        let id = CompositeId::new(
-            stream_id,
-            stream.next_recording_id + (stream.uncommitted.len() as i32),
-        );
-        let recording = Arc::new(Mutex::new(r));
-        stream.uncommitted.push_back(Arc::clone(&recording));
-        Ok((id, recording))
+    /// Returns the number of failed flushes since startup and the most recent failure's error.
+    /// Recordings are lost if flushes keep failing until the server stops.
+    pub fn flush_failures(&self) -> (u64, Option<&str>) {
+        (self.flush_failures, self.last_flush_failure.as_deref())
     }
 
-    /// Marks the given uncomitted recording as synced and ready to flush.
-    /// This must be the next unsynced recording.
-    pub(crate) fn mark_synced(&mut self, id: CompositeId) -> Result<(), Error> {
-        let stream = match self.streams_by_id.get_mut(&id.stream()) {
-            None => bail!("no stream for recording {}", id),
-            Some(s) => s,
-        };
-        let next_unsynced = stream.next_recording_id + (stream.synced_recordings as i32);
-        if id.recording() != next_unsynced {
-            bail!(
-                "can't sync {} when next unsynced recording is {} (next unflushed is {})",
-                id,
-                next_unsynced,
-                stream.next_recording_id
-            );
-        }
-        if stream.synced_recordings == stream.uncommitted.len() {
-            bail!("can't sync un-added recording {}", id);
+    /// Returns true iff the most recent flush failed, so recordings aren't being committed.
+    pub fn flush_failing(&self) -> bool {
+        self.flush_failing
+    }
+
+    /// Returns the value of the given key in the server-wide `config` table, if set.
+    pub fn get_config(&self, key: &str) -> Result<Option<String>, Error> {
+        raw::get_config(&self.conn, key)
+    }
+
+    /// Sets or (if `value` is `None`) clears the given key in the server-wide `config` table.
+    pub fn set_config(&mut self, key: &str, value: Option<&str>) -> Result<(), Error> {
+        raw::set_config(&self.conn, key, value)
+    }
+
+    /// Sets the limits checked by `add_recording`.
+    pub fn set_uncommitted_limits(&mut self, limits: UncommittedLimits) {
+        self.uncommitted_limits = limits;
+    }
+
+    /// Sets the number of synced but unflushed recordings, across all streams, at which syncers
+    /// flush immediately rather than waiting for `flush_if_sec`. `None` means no limit.
+    pub fn set_max_unflushed_recordings(&mut self, max: Option<usize>) {
+        self.max_unflushed_recordings = max;
+    }
+
+    /// Ties each camera's sub stream retention to its main stream's: whenever a syncer rotates a
+    /// main stream's recordings, it also deletes the sub stream's recordings ending before
+    /// `multiple` times the main stream's remaining span. Each stream's `retain_bytes` still
+    /// applies. `None` (the default) retains the streams independently.
+    pub fn set_sub_retention_multiple(&mut self, multiple: Option<f64>) {
+        self.sub_retention_multiple = multiple;
+    }
+
+    pub(crate) fn sub_retention_multiple(&self) -> Option<f64> {
+        self.sub_retention_multiple
+    }
+
+    /// Returns why the database should be flushed right away after syncing a recording of the
+    /// given stream, if its `flush_if_bytes` or the `max_unflushed_recordings` is reached.
+    pub(crate) fn flush_threshold_reached(&self, stream_id: i32) -> Option<String> {
+        let s = self.streams_by_id.get(&stream_id)?;
+        if s.flush_if_bytes > 0 {
+            let bytes: i64 = s
+                .uncommitted
+                .iter()
+                .take(s.synced_recordings)
+                .map(|u| i64::from(u.lock().sample_file_bytes))
+                .sum();
+            if bytes >= s.flush_if_bytes {
+                return Some(format!(
+                    "{} unflushed bytes reached flush_if_bytes={}",
+                    bytes, s.flush_if_bytes
+                ));
+            }
+        }
+        if let Some(max) = self.max_unflushed_recordings {
+            let n: usize = self
+                .streams_by_id
+                .values()
+                .map(|s| s.synced_recordings)
+                .sum();
+            if n >= max {
+                return Some(format!(
+                    "{} unflushed recordings reached limit of {}",
+                    n, max
+                ));
+            }
+        }
+        None
+    }
+
+    pub fn set_disk_full_policy(&mut self, policy: DiskFullPolicy) {
+        self.disk_full_policy = policy;
+    }
+
+    pub fn disk_full_policy(&self) -> &DiskFullPolicy {
+        &self.disk_full_policy
+    }
+
+    /// Sets the limit on the total size of video indexes cached for `with_recording_playback`.
+    pub fn set_video_index_cache_bytes(&mut self, max_bytes: usize) {
+        self.video_index_cache.get_mut().set_max_bytes(max_bytes);
+    }
+
+    pub fn set_preallocation(&mut self, preallocation: Preallocation) {
+        self.preallocation = preallocation;
+    }
+
+    pub fn preallocation(&self) -> &Preallocation {
+        &self.preallocation
+    }
+
+    /// Sets the key with which to encrypt new sample files and decrypt existing ones. This
+    /// affects only directories opened after the call, so it should precede
+    /// `open_sample_file_dirs`.
+    pub fn set_sample_file_key(&mut self, key: Option<Arc<dir::SampleFileKey>>) {
+        self.sample_file_key = key;
+    }
+
+    /// Marks the given sample file directory as unhealthy as of the given time, or (with `None`)
+    /// as healthy.
+    pub fn set_sample_file_dir_unhealthy(
+        &mut self,
+        dir_id: i32,
+        since_sec: Option<i64>,
+    ) -> Result<(), Error> {
+        let d = self
+            .sample_file_dirs_by_id
+            .get_mut(&dir_id)
+            .ok_or_else(|| format_err!("no such dir {}", dir_id))?;
+        let mut stmt = self.conn.prepare_cached(
+            "update sample_file_dir set unhealthy_since_sec = :since_sec where id = :id",
+        )?;
+        stmt.execute_named(named_params! {
+            ":since_sec": since_sec,
+            ":id": dir_id,
+        })?;
+        let changed = d.unhealthy_since_sec.is_some() != since_sec.is_some();
+        d.unhealthy_since_sec = since_sec;
+        if changed {
+            self.send_event(Event::DirUnhealthy {
+                dir_id,
+                unhealthy: since_sec.is_some(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Sets or clears the given stream's `disk_full` flag.
+    pub fn set_disk_full(&mut self, stream_id: i32, disk_full: bool) -> Result<(), Error> {
+        let s = match self.streams_by_id.get_mut(&stream_id) {
+            None => bail!("no stream {}", stream_id),
+            Some(s) => s,
+        };
+        if s.disk_full != disk_full {
+            s.disk_full = disk_full;
+            self.send_event(Event::DiskFull {
+                stream_id,
+                full: disk_full,
+            });
+        }
+        Ok(())
+    }
+
+    /// Sets or clears the given stream's `multi_homed` warning.
+    pub fn set_multi_homed(
+        &mut self,
+        stream_id: i32,
+        warning: Option<String>,
+    ) -> Result<(), Error> {
+        match self.streams_by_id.get_mut(&stream_id) {
+            None => bail!("no stream {}", stream_id),
+            Some(s) => s.multi_homed = warning,
+        }
+        Ok(())
+    }
+
+    /// Returns the number and total sample file bytes of uncommitted recordings.
+    pub fn uncommitted_totals(&self) -> (usize, i64) {
+        let mut recordings = 0;
+        let mut bytes = 0;
+        for s in self.streams_by_id.values() {
+            recordings += s.uncommitted.len();
+            for u in &s.uncommitted {
+                bytes += i64::from(u.lock().sample_file_bytes);
+            }
+        }
+        (recordings, bytes)
+    }
+
+    /// Checks if a recording with the given run offset should be admitted to the given stream
+    /// under `uncommitted_limits`.
+    fn check_uncommitted_limits(&self, stream: &Stream, run_offset: i32) -> Result<(), Error> {
+        let limits = &self.uncommitted_limits;
+        if limits.max_recordings.is_none() && limits.max_bytes.is_none() {
+            return Ok(());
+        }
+        let (recordings, bytes) = self.uncommitted_totals();
+        let over = |recordings_limit: Option<usize>, bytes_limit: Option<i64>| {
+            recordings_limit.map(|l| recordings > l).unwrap_or(false)
+                || bytes_limit.map(|l| bytes > l).unwrap_or(false)
+        };
+        let over_hard = over(
+            limits.max_recordings.map(|l| l.saturating_mul(2)),
+            limits.max_bytes.map(|l| l.saturating_mul(2)),
+        );
+        let over_soft = over(limits.max_recordings, limits.max_bytes);
+        if over_hard || (over_soft && stream.type_ == StreamType::SUB && run_offset == 0) {
+            bail_t!(
+                ResourceExhausted,
+                "refusing new {} recording: {} uncommitted recordings of {} bytes exceed {} \
+                 limits {:?}; is the database able to flush?",
+                stream.type_.as_str(),
+                recordings,
+                bytes,
+                if over_hard { "twice the" } else { "the" },
+                limits
+            );
+        }
+        Ok(())
+    }
+
+    /// Adds a placeholder for an uncommitted recording.
+    /// The caller should write samples and fill the returned `RecordingToInsert` as it goes
+    /// (noting that while holding the lock, it should not perform I/O or acquire the database
+    /// lock). Then it should sync to permanent storage and call `mark_synced`. The data will
+    /// be written to the database on the next `flush`.
+    ///
+    /// Fails with `ErrorKind::ResourceExhausted` if refused by the `UncommittedLimits`.
+    pub(crate) fn add_recording(
+        &mut self,
+        stream_id: i32,
+        r: RecordingToInsert,
+    ) -> Result<(CompositeId, Arc<Mutex<RecordingToInsert>>), Error> {
+        match self.streams_by_id.get(&stream_id) {
+            None => bail!("no such stream {}", stream_id),
+            Some(s) => self.check_uncommitted_limits(s, r.run_offset)?,
+        };
+        let stream = self.streams_by_id.get_mut(&stream_id).unwrap();
+        let id = CompositeId::new(
+            stream_id,
+            stream.next_recording_id + (stream.uncommitted.len() as i32),
+        );
+        let recording = Arc::new(Mutex::new(r));
+        stream.uncommitted.push_back(Arc::clone(&recording));
+        Ok((id, recording))
+    }
+
+    /// Discards the given uncommitted recording, which must be its stream's most recently added
+    /// and not yet synced. This is for a recording whose writer can't finish it, as when its
+    /// streamer panics. Its id will be reused by the stream's next recording, so the caller must
+    /// first unlink its sample file.
+    pub(crate) fn abandon_recording(&mut self, id: CompositeId) -> Result<(), Error> {
+        let stream = match self.streams_by_id.get_mut(&id.stream()) {
+            None => bail!("no stream for recording {}", id),
+            Some(s) => s,
+        };
+        let last = stream.next_recording_id + (stream.uncommitted.len() as i32) - 1;
+        if id.recording() != last || stream.synced_recordings == stream.uncommitted.len() {
+            bail!(
+                "can't abandon {}: not the latest unsynced recording of its stream",
+                id
+            );
+        }
+        stream.uncommitted.pop_back();
+        Ok(())
+    }
+
+    /// Marks the given uncomitted recording as synced and ready to flush.
+    /// This must be the next unsynced recording.
+    pub(crate) fn mark_synced(&mut self, id: CompositeId) -> Result<(), Error> {
+        let stream = match self.streams_by_id.get_mut(&id.stream()) {
+            None => bail!("no stream for recording {}", id),
+            Some(s) => s,
+        };
+        let next_unsynced = stream.next_recording_id + (stream.synced_recordings as i32);
+        if id.recording() != next_unsynced {
+            bail!(
+                "can't sync {} when next unsynced recording is {} (next unflushed is {})",
+                id,
+                next_unsynced,
+                stream.next_recording_id
+            );
+        }
+        if stream.synced_recordings == stream.uncommitted.len() {
+            bail!("can't sync un-added recording {}", id);
         }
         let l = stream.uncommitted[stream.synced_recordings].lock();
         let bytes = i64::from(l.sample_file_bytes);
@@ -1006,6 +1934,7 @@ impl LockedDatabase {
         for (_, s) in &mut self.streams_by_id {
             s.on_live_segment.clear();
         }
+        self.event_watchers.clear();
     }
 
     pub(crate) fn send_live_segment(&mut self, stream: i32, l: LiveSegment) -> Result<(), Error> {
@@ -1029,6 +1958,7 @@ impl LockedDatabase {
         let tx = self.conn.transaction()?;
         let mut new_ranges =
             FnvHashMap::with_capacity_and_hasher(self.streams_by_id.len(), Default::default());
+        let mut events = Vec::new();
         {
             let mut stmt = tx.prepare_cached(UPDATE_NEXT_RECORDING_ID_SQL)?;
             for (&stream_id, s) in &self.streams_by_id {
@@ -1043,6 +1973,18 @@ impl LockedDatabase {
                     )?;
                 }
                 if s.synced_recordings > 0 {
+                    bookmark::protect_new_recordings(
+                        &tx,
+                        s.camera_id,
+                        CompositeId::new(stream_id, s.next_recording_id)
+                            ..CompositeId::new(
+                                stream_id,
+                                s.next_recording_id + s.synced_recordings as i32,
+                            ),
+                    )?;
+                    for t in &s.protect_pending {
+                        raw::protect_recordings(&tx, stream_id, t.clone(), true)?;
+                    }
                     new_ranges.entry(stream_id).or_insert(None);
                     stmt.execute_named(named_params! {
                         ":stream_id": stream_id,
@@ -1051,26 +1993,38 @@ impl LockedDatabase {
                 }
 
                 // Process deletions.
-                if let Some(l) = s.to_delete.last() {
+                if !s.to_delete.is_empty() {
                     new_ranges.entry(stream_id).or_insert(None);
-                    let dir = match s.sample_file_dir_id {
-                        None => bail!("stream {} has no directory!", stream_id),
-                        Some(d) => d,
-                    };
+                    if s.sample_file_dir_id.is_none() {
+                        bail!("stream {} has no directory!", stream_id);
+                    }
 
                     // raw::delete_recordings does a bulk transfer of a range from recording to
-                    // garbage, rather than operating on each element of to_delete. This is
-                    // guaranteed to give the same result because to_delete is guaranteed to be the
-                    // oldest recordings for the stream.
-                    let start = CompositeId::new(stream_id, 0);
-                    let end = CompositeId(l.id.0 + 1);
-                    let n = raw::delete_recordings(&tx, dir, start..end)? as usize;
+                    // garbage, rather than operating on each element of to_delete. to_delete is
+                    // in id order but may have gaps, for the protected recordings skipped by
+                    // delete_oldest_recordings and around ranges queued by delete_recordings, so
+                    // do a transfer for each run of consecutive ids.
+                    let mut n = 0;
+                    let mut i = 0;
+                    while i < s.to_delete.len() {
+                        let mut j = i + 1;
+                        while j < s.to_delete.len()
+                            && s.to_delete[j].id.0 == s.to_delete[j - 1].id.0 + 1
+                        {
+                            j += 1;
+                        }
+                        n += raw::delete_recordings(
+                            &tx,
+                            &s.dir_ids(),
+                            s.failover_sample_file_dir_id,
+                            s.to_delete[i].id..CompositeId(s.to_delete[j - 1].id.0 + 1),
+                        )?;
+                        i = j;
+                    }
                     if n != s.to_delete.len() {
                         bail!(
-                            "Found {} rows in {} .. {}, expected {}: {:?}",
+                            "Found {} rows, expected {}: {:?}",
                             n,
-                            start,
-                            end,
                             s.to_delete.len(),
                             &s.to_delete
                         );
@@ -1099,6 +2053,7 @@ impl LockedDatabase {
         }
         self.auth.flush(&tx)?;
         self.signal.flush(&tx)?;
+        self.playback.flush(&tx, &self.streams_by_id)?;
         tx.commit()?;
 
         #[derive(Default)]
@@ -1125,7 +2080,6 @@ impl LockedDatabase {
         for (stream_id, new_range) in new_ranges.drain() {
             let s = self.streams_by_id.get_mut(&stream_id).unwrap();
             let dir_id = s.sample_file_dir_id.unwrap();
-            let dir = self.sample_file_dirs_by_id.get_mut(&dir_id).unwrap();
             let log = dir_logs.entry(dir_id).or_default();
 
             // Process delete_oldest_recordings.
@@ -1135,8 +2089,12 @@ impl LockedDatabase {
             s.bytes_to_delete = 0;
             s.fs_bytes_to_delete = 0;
             log.deleted.reserve(s.to_delete.len());
-            for row in s.to_delete.drain(..) {
+            let mut cache = self.video_index_cache.borrow_mut();
+            for row in mem::replace(&mut s.to_delete, Vec::new()) {
+                cache.remove(row.id);
                 log.deleted.push(row.id);
+                let dir_id = s.dir_id_for(row.id.recording(), row.flags).unwrap();
+                let dir = self.sample_file_dirs_by_id.get_mut(&dir_id).unwrap();
                 dir.garbage_needs_unlink.insert(row.id);
                 let d = recording::Duration(row.duration as i64);
                 s.duration -= d;
@@ -1150,20 +2108,34 @@ impl LockedDatabase {
             log.added.reserve(s.synced_recordings);
             for _ in 0..s.synced_recordings {
                 let u = s.uncommitted.pop_front().unwrap();
-                log.added
-                    .push(CompositeId::new(stream_id, s.next_recording_id));
+                let id = CompositeId::new(stream_id, s.next_recording_id);
+                debug!(recording = %id, "committed recording");
+                log.added.push(id);
                 s.next_recording_id += 1;
                 let l = u.lock();
                 let end = l.start + recording::Duration(l.duration_90k as i64);
                 s.add_recording(l.start..end, l.sample_file_bytes);
+                events.push(Event::RecordingCommitted {
+                    id,
+                    time: l.start..end,
+                    sample_file_bytes: l.sample_file_bytes,
+                });
             }
             s.synced_recordings = 0;
 
             // Fix the range.
             s.range = new_range;
+
+            // Later recordings start after this range, so pending protections which end before it
+            // are done.
+            if let Some(r) = s.range.as_ref() {
+                let end = r.end;
+                s.protect_pending.retain(|t| t.end > end);
+            }
         }
         self.auth.post_flush();
         self.signal.post_flush();
+        self.playback.post_flush();
         self.flush_count += 1;
         let mut log_msg = String::with_capacity(256);
         for (&dir_id, log) in &dir_logs {
@@ -1191,6 +2163,9 @@ impl LockedDatabase {
         for cb in &self.on_flush {
             cb();
         }
+        for e in events {
+            self.send_event(e);
+        }
         Ok(())
     }
 
@@ -1206,6 +2181,24 @@ impl LockedDatabase {
         self.on_flush.clear();
     }
 
+    /// Registers a callback to run on every `Event`.
+    /// As with `watch_live`, the callback is run with the database lock held, so it must not
+    /// block. It's given the database so it can look up names for the ids in the event. The
+    /// callback should return false to unregister.
+    pub fn watch_events(&mut self, cb: Box<dyn FnMut(&LockedDatabase, &Event) -> bool + Send>) {
+        self.event_watchers.push(cb);
+    }
+
+    fn send_event(&mut self, e: Event) {
+        if self.event_watchers.is_empty() {
+            return;
+        }
+        use odds::vec::VecExt;
+        let mut watchers = mem::take(&mut self.event_watchers);
+        watchers.retain_mut(|cb| cb(self, &e));
+        self.event_watchers = watchers;
+    }
+
     /// Opens the given sample file directories.
     ///
     /// `ids` is implicitly de-duplicated.
@@ -1240,7 +2233,7 @@ impl LockedDatabase {
                 open.id = o.id;
                 open.uuid.extend_from_slice(&o.uuid.as_bytes()[..]);
             }
-            let d = dir::SampleFileDir::open(&dir.path, &meta)?;
+            let d = dir::SampleFileDir::open(&dir.path, &meta, self.sample_file_key.clone())?;
             if self.open.is_none() {
                 // read-only mode; it's already fully opened.
                 dir.dir = Some(d);
@@ -1403,61 +2396,7 @@ impl LockedDatabase {
         // ascending order by id, and after any committed recordings with lower ids.
         let mut aggs: BTreeMap<i32, ListAggregatedRecordingsRow> = BTreeMap::new();
         self.list_recordings_by_time(stream_id, desired_time, &mut |row| {
-            let recording_id = row.id.recording();
-            let run_start_id = recording_id - row.run_offset;
-            let uncommitted = (row.flags & RecordingFlags::Uncommitted as i32) != 0;
-            let growing = (row.flags & RecordingFlags::Growing as i32) != 0;
-            use std::collections::btree_map::Entry;
-            match aggs.entry(run_start_id) {
-                Entry::Occupied(mut e) => {
-                    let a = e.get_mut();
-                    let new_dur =
-                        a.time.end - a.time.start + recording::Duration(row.duration_90k as i64);
-                    let needs_flush = a.ids.end != recording_id
-                        || row.video_sample_entry_id != a.video_sample_entry_id
-                        || new_dur >= forced_split;
-                    if needs_flush {
-                        // flush then start a new entry.
-                        f(a)?;
-                        *a = ListAggregatedRecordingsRow::from(row);
-                    } else {
-                        // append.
-                        if a.time.end != row.start {
-                            bail!(
-                                "stream {} recording {} ends at {} but {} starts at {}",
-                                stream_id,
-                                a.ids.end - 1,
-                                a.time.end,
-                                row.id,
-                                row.start
-                            );
-                        }
-                        if a.open_id != row.open_id {
-                            bail!(
-                                "stream {} recording {} has open id {} but {} has {}",
-                                stream_id,
-                                a.ids.end - 1,
-                                a.open_id,
-                                row.id,
-                                row.open_id
-                            );
-                        }
-                        a.time.end.0 += row.duration_90k as i64;
-                        a.ids.end = recording_id + 1;
-                        a.video_samples += row.video_samples as i64;
-                        a.video_sync_samples += row.video_sync_samples as i64;
-                        a.sample_file_bytes += row.sample_file_bytes as i64;
-                        if uncommitted {
-                            a.first_uncommitted = a.first_uncommitted.or(Some(recording_id));
-                        }
-                        a.growing = growing;
-                    }
-                }
-                Entry::Vacant(e) => {
-                    e.insert(ListAggregatedRecordingsRow::from(row));
-                }
-            }
-            Ok(())
+            aggregate_recording(stream_id, &mut aggs, row, forced_split, f)
         })?;
         for a in aggs.values() {
             f(a)?;
@@ -1465,6 +2404,73 @@ impl LockedDatabase {
         Ok(())
     }
 
+    /// Like `list_aggregated_recordings`, but visits at most `limit` recordings with ids of at
+    /// least `min_id`, in ascending order by id, so that callers can page through long time
+    /// ranges. Returns the `min_id` of the next page, or `None` if this is the last page.
+    /// Runs may be split into separate rows at page boundaries.
+    pub fn list_aggregated_recordings_page(
+        &self,
+        stream_id: i32,
+        desired_time: Range<recording::Time>,
+        forced_split: recording::Duration,
+        min_id: i32,
+        limit: usize,
+        f: &mut dyn FnMut(&ListAggregatedRecordingsRow) -> Result<(), Error>,
+    ) -> Result<Option<i32>, Error> {
+        let s = match self.streams_by_id.get(&stream_id) {
+            None => bail!("no such stream {}", stream_id),
+            Some(s) => s,
+        };
+
+        // Fetch one extra row to learn where the next page starts.
+        let mut rows = Vec::new();
+        if min_id < s.next_recording_id {
+            raw::list_recordings_by_id_and_time(
+                &self.conn,
+                stream_id,
+                min_id..s.next_recording_id,
+                desired_time.clone(),
+                limit + 1,
+                &mut |row| {
+                    rows.push(row);
+                    Ok(())
+                },
+            )?;
+        }
+        for (i, u) in s.uncommitted.iter().enumerate() {
+            let id = s.next_recording_id + i as i32;
+            if rows.len() > limit {
+                break;
+            }
+            if id < min_id {
+                continue;
+            }
+            let l = u.lock();
+            if l.video_samples > 0 {
+                let end = l.start + recording::Duration(l.duration_90k as i64);
+                if l.start > desired_time.end || end < desired_time.start {
+                    continue; // there's no overlap with the requested range.
+                }
+                rows.push(l.to_list_row(CompositeId::new(stream_id, id), self.open.unwrap().id));
+            }
+        }
+        let next = if rows.len() > limit {
+            let next = rows[limit].id.recording();
+            rows.truncate(limit);
+            Some(next)
+        } else {
+            None
+        };
+        let mut aggs: BTreeMap<i32, ListAggregatedRecordingsRow> = BTreeMap::new();
+        for row in rows {
+            aggregate_recording(stream_id, &mut aggs, row, forced_split, f)?;
+        }
+        for a in aggs.values() {
+            f(a)?;
+        }
+        Ok(next)
+    }
+
     /// Calls `f` with a single `recording_playback` row.
     /// Note the lock is held for the duration of `f`.
     /// This uses a LRU cache to reduce the number of retrievals from the database.
@@ -1496,7 +2502,7 @@ impl LockedDatabase {
 
         // Committed path.
         let mut cache = self.video_index_cache.borrow_mut();
-        if let Some(video_index) = cache.get_mut(&id.0) {
+        if let Some(video_index) = cache.get(id) {
             trace!("cache hit for recording {}", id);
             return f(&RecordingPlayback { video_index });
         }
@@ -1504,18 +2510,98 @@ impl LockedDatabase {
         let mut stmt = self.conn.prepare_cached(GET_RECORDING_PLAYBACK_SQL)?;
         let mut rows = stmt.query_named(named_params! {":composite_id": id.0})?;
         if let Some(row) = rows.next()? {
-            let video_index: VideoIndex = row.get(0)?;
+            let video_index = raw::decode_video_index(row.get(0)?, row.get(1)?)
+                .map_err(|e| format_err!("recording {}: {}", id, e))?
+                .into_boxed_slice();
             let result = f(&RecordingPlayback {
-                video_index: &video_index.0[..],
+                video_index: &video_index[..],
             });
-            cache.insert(id.0, video_index.0);
+            cache.insert(id, video_index);
             return result;
         }
         Err(format_err!("no such recording {}", id))
     }
 
-    /// Queues for deletion the oldest recordings that aren't already queued.
-    /// `f` should return true for each row that should be deleted.
+    /// Returns the uncommitted recording `id`, `None` if it's committed, or an error if it
+    /// doesn't exist yet.
+    fn uncommitted_recording(
+        &self,
+        id: CompositeId,
+    ) -> Result<Option<&Arc<Mutex<RecordingToInsert>>>, Error> {
+        let s = self
+            .streams_by_id
+            .get(&id.stream())
+            .ok_or_else(|| format_err!("no stream for {}", id))?;
+        if s.next_recording_id > id.recording() {
+            return Ok(None);
+        }
+        let i = id.recording() - s.next_recording_id;
+        match s.uncommitted.get(i as usize) {
+            Some(r) => Ok(Some(r)),
+            None => bail!(
+                "no such recording {}; latest committed is {}, latest is {}",
+                id,
+                s.next_recording_id,
+                s.next_recording_id + s.uncommitted.len() as i32
+            ),
+        }
+    }
+
+    /// Returns the JPEG thumbnail of the given recording, if it has one.
+    pub fn get_thumbnail(&self, id: CompositeId) -> Result<Option<Vec<u8>>, Error> {
+        match self.uncommitted_recording(id)? {
+            Some(r) => Ok(r.lock().thumbnail.clone()),
+            None => raw::get_thumbnail(&self.conn, id),
+        }
+    }
+
+    /// Sets the JPEG thumbnail of the given recording. An uncommitted recording's thumbnail is
+    /// saved along with it on the next flush; a committed recording's is saved immediately.
+    pub fn set_thumbnail(&mut self, id: CompositeId, jpeg: Vec<u8>) -> Result<(), Error> {
+        match self.uncommitted_recording(id)? {
+            Some(r) => {
+                r.lock().thumbnail = Some(jpeg);
+                Ok(())
+            }
+            None => raw::insert_thumbnail(&self.conn, id, &jpeg),
+        }
+    }
+
+    /// Returns the difference between the local clock and the given recording's start time,
+    /// as described at `recording_integrity.local_time_delta_90k` in `schema.sql`.
+    /// Returns `None` if unknown, as for the first recording of a run.
+    pub fn get_local_time_delta(
+        &self,
+        id: CompositeId,
+    ) -> Result<Option<recording::Duration>, Error> {
+        // Check for uncommitted path.
+        let s = self
+            .streams_by_id
+            .get(&id.stream())
+            .ok_or_else(|| format_err!("no stream for {}", id))?;
+        if s.next_recording_id <= id.recording() {
+            let i = id.recording() - s.next_recording_id;
+            if i as usize >= s.uncommitted.len() {
+                bail!(
+                    "no such recording {}; latest committed is {}, latest is {}",
+                    id,
+                    s.next_recording_id,
+                    s.next_recording_id + s.uncommitted.len() as i32
+                );
+            }
+            let l = s.uncommitted[i as usize].lock();
+            return Ok(match l.run_offset {
+                0 => None,
+                _ => Some(l.local_time_delta),
+            });
+        }
+
+        // Committed path.
+        raw::get_local_time_delta(&self.conn, id)
+    }
+
+    /// Queues for deletion the oldest unprotected recordings that aren't already queued.
+    /// `f` should return true for each row that should be deleted; the first false ends the scan.
     pub(crate) fn delete_oldest_recordings(
         &mut self,
         stream_id: i32,
@@ -1525,13 +2611,20 @@ impl LockedDatabase {
             None => bail!("no stream {}", stream_id),
             Some(s) => s,
         };
-        let end = match s.to_delete.last() {
-            None => 0,
-            Some(row) => row.id.recording() + 1,
-        };
-        raw::list_oldest_recordings(&self.conn, CompositeId::new(stream_id, end), &mut |r| {
+
+        // Scan from the start of the stream: delete_recordings may have queued recordings newer
+        // than ones which remain.
+        raw::list_oldest_recordings(&self.conn, CompositeId::new(stream_id, 0), &mut |r| {
+            // Skip protected recordings, deleting newer ones instead.
+            if (r.flags & RecordingFlags::Protected as i32) != 0 {
+                return true;
+            }
+            let i = match s.to_delete.binary_search_by_key(&r.id.0, |d| d.id.0) {
+                Ok(_) => return true, // already queued.
+                Err(i) => i,
+            };
             if f(&r) {
-                s.to_delete.push(r);
+                s.to_delete.insert(i, r);
                 let bytes = i64::from(r.sample_file_bytes);
                 s.bytes_to_delete += bytes;
                 s.fs_bytes_to_delete += round_up(bytes);
@@ -1541,30 +2634,119 @@ impl LockedDatabase {
         })
     }
 
-    /// Initializes the video_sample_entries. To be called during construction.
-    fn init_video_sample_entries(&mut self) -> Result<(), Error> {
-        info!("Loading video sample entries");
-        let mut stmt = self.conn.prepare(
-            r#"
-            select
-                id,
-                sha1,
-                width,
-                height,
-                rfc6381_codec,
-                data
-            from
-                video_sample_entry
-        "#,
-        )?;
-        let mut rows = stmt.query(params![])?;
-        while let Some(row) = rows.next()? {
-            let id = row.get(0)?;
-            let mut sha1 = [0u8; 20];
-            let sha1_vec: Vec<u8> = row.get(1)?;
-            if sha1_vec.len() != 20 {
-                bail!(
-                    "video sample entry id {} has sha1 {} of wrong length",
+    /// Returns the time range of the given stream's recorded data that will remain after the next
+    /// flush's deletions, starting with the oldest recording not queued for deletion. (Older
+    /// protected recordings may remain while newer ones are deleted.) `None` iff there are no
+    /// recordings or all are to be deleted.
+    pub(crate) fn retained_range(
+        &self,
+        stream_id: i32,
+    ) -> Result<Option<Range<recording::Time>>, Error> {
+        let s = match self.streams_by_id.get(&stream_id) {
+            None => bail!("no stream {}", stream_id),
+            Some(s) => s,
+        };
+        let range = match s.range {
+            None => return Ok(None),
+            Some(ref r) => r,
+        };
+        let mut start = None;
+        raw::list_oldest_recordings(&self.conn, CompositeId::new(stream_id, 0), &mut |r| {
+            // to_delete is in id order.
+            if s.to_delete
+                .binary_search_by_key(&r.id.0, |d| d.id.0)
+                .is_ok()
+            {
+                return true;
+            }
+            start = Some(r.start);
+            false
+        })?;
+        Ok(start.map(|t| t..range.end))
+    }
+
+    /// Runs `f`, which may queue deletions via `delete_oldest_recordings`, then unqueues what it
+    /// queued, returning it by stream id. Streams which would lose nothing are omitted. Nothing
+    /// is written to the database, and as the lock is held throughout, no one else sees the
+    /// queued deletions.
+    pub(crate) fn preview_deletions(
+        &mut self,
+        f: &mut dyn FnMut(&mut LockedDatabase) -> Result<(), Error>,
+    ) -> Result<BTreeMap<i32, StreamDeletionPreview>, Error> {
+        // The ids already queued, in order. f may queue others before them.
+        let queued: FnvHashMap<i32, Vec<i64>> = self
+            .streams_by_id
+            .iter()
+            .map(|(&id, s)| (id, s.to_delete.iter().map(|r| r.id.0).collect()))
+            .collect();
+        let result = f(self);
+        let changed: Vec<i32> = self
+            .streams_by_id
+            .iter()
+            .filter(|(id, s)| s.to_delete.len() > queued[*id].len())
+            .map(|(&id, _)| id)
+            .collect();
+
+        // Find what would remain before unqueueing, but unqueue even if that fails.
+        let retained: Vec<_> = changed.iter().map(|&id| self.retained_range(id)).collect();
+        let mut previews = BTreeMap::new();
+        for (id, retained) in changed.into_iter().zip(retained) {
+            let s = self.streams_by_id.get_mut(&id).unwrap();
+            let before = &queued[&id];
+            let (added, kept): (Vec<_>, Vec<_>) = mem::replace(&mut s.to_delete, Vec::new())
+                .into_iter()
+                .partition(|r| before.binary_search(&r.id.0).is_err());
+            s.to_delete = kept;
+            let mut p = StreamDeletionPreview::default();
+            for r in added {
+                let bytes = i64::from(r.sample_file_bytes);
+                s.bytes_to_delete -= bytes;
+                s.fs_bytes_to_delete -= round_up(bytes);
+                p.sample_file_bytes += bytes;
+                p.fs_bytes += round_up(bytes);
+                p.recordings.push(PreviewedDeletion {
+                    id: r.id,
+                    time: r.start..r.start + recording::Duration(i64::from(r.duration)),
+                    sample_file_bytes: r.sample_file_bytes,
+                });
+            }
+            previews.insert(id, (p, retained));
+        }
+        result?;
+        previews
+            .into_iter()
+            .map(|(id, (mut p, retained))| {
+                p.oldest_remaining = retained?.map(|r| r.start);
+                Ok((id, p))
+            })
+            .collect()
+    }
+
+    /// Initializes the video_sample_entries. To be called during construction.
+    fn init_video_sample_entries(&mut self) -> Result<(), Error> {
+        info!("Loading video sample entries");
+        let mut stmt = self.conn.prepare(
+            r#"
+            select
+                id,
+                sha1,
+                width,
+                height,
+                rfc6381_codec,
+                data,
+                encoder
+            from
+                video_sample_entry
+        "#,
+        )?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let id = row.get(0)?;
+            let mut sha1 = [0u8; 20];
+            let sha1_vec: Vec<u8> = row.get(1)?;
+            if sha1_vec.len() != 20 {
+                bail!(
+                    "video sample entry id {} has sha1 {} of wrong length",
                     id,
                     sha1_vec.len()
                 );
@@ -1581,6 +2763,7 @@ impl LockedDatabase {
                     sha1,
                     data,
                     rfc6381_codec: row.get(4)?,
+                    encoder: row.get(6)?,
                 }),
             );
         }
@@ -1602,7 +2785,8 @@ impl LockedDatabase {
               d.path,
               d.uuid,
               d.last_complete_open_id,
-              o.uuid
+              o.uuid,
+              d.unhealthy_since_sec
             from
               sample_file_dir d left join open o on (d.last_complete_open_id = o.id);
         "#,
@@ -1626,6 +2810,7 @@ impl LockedDatabase {
                     path: row.get(1)?,
                     dir: None,
                     last_complete_open,
+                    unhealthy_since_sec: row.get(5)?,
                     garbage_needs_unlink: raw::list_garbage(&self.conn, id)?,
                     garbage_unlinked: Vec::new(),
                 },
@@ -1651,7 +2836,8 @@ impl LockedDatabase {
               description,
               onvif_host,
               username,
-              password
+              password,
+              ptz
             from
               camera;
         "#,
@@ -1670,6 +2856,7 @@ impl LockedDatabase {
                     onvif_host: row.get(4)?,
                     username: row.get(5)?,
                     password: row.get(6)?,
+                    ptz: row.get(7)?,
                     streams: Default::default(),
                 },
             );
@@ -1694,7 +2881,15 @@ impl LockedDatabase {
               retain_bytes,
               flush_if_sec,
               next_recording_id,
-              record
+              record,
+              failover_sample_file_dir_id,
+              flush_if_bytes,
+              input_options,
+              rtsp_transport,
+              reconnect_min_sec,
+              reconnect_max_sec,
+              session_timeout_sec,
+              privacy_mask
             from
               stream;
         "#,
@@ -1711,6 +2906,17 @@ impl LockedDatabase {
                 .get_mut(&camera_id)
                 .ok_or_else(|| format_err!("missing camera {} for stream {}", camera_id, id))?;
             let flush_if_sec = row.get(6)?;
+            let transport: String = row.get(12)?;
+            let rtsp = RtspPolicy {
+                transport: RtspTransport::parse(&transport)
+                    .ok_or_else(|| format_err!("no such RTSP transport {}", transport))?,
+                reconnect_min_sec: row.get(13)?,
+                reconnect_max_sec: row.get(14)?,
+                session_timeout_sec: row.get(15)?,
+            };
+            let privacy_mask: String = row.get(16)?;
+            let privacy_mask = mask::Mask::parse(&privacy_mask)
+                .map_err(|e| format_err!("stream {} has bad privacy_mask: {}", id, e))?;
             self.streams_by_id.insert(
                 id,
                 Stream {
@@ -1718,9 +2924,16 @@ impl LockedDatabase {
                     type_,
                     camera_id,
                     sample_file_dir_id: row.get(3)?,
+                    stripe_dir_ids: Vec::new(),
+                    failover_sample_file_dir_id: row.get(9)?,
                     rtsp_url: row.get(4)?,
+                    virtual_source: None,
                     retain_bytes: row.get(5)?,
                     flush_if_sec,
+                    flush_if_bytes: row.get(10)?,
+                    input_options: row.get(11)?,
+                    rtsp,
+                    privacy_mask,
                     range: None,
                     sample_file_bytes: 0,
                     fs_bytes: 0,
@@ -1729,10 +2942,13 @@ impl LockedDatabase {
                     fs_bytes_to_delete: 0,
                     bytes_to_add: 0,
                     fs_bytes_to_add: 0,
+                    protect_pending: Vec::new(),
                     duration: recording::Duration(0),
                     committed_days: BTreeMap::new(),
                     next_recording_id: row.get(7)?,
                     record: row.get(8)?,
+                    disk_full: false,
+                    multi_homed: None,
                     uncommitted: VecDeque::new(),
                     synced_recordings: 0,
                     on_live_segment: Vec::new(),
@@ -1740,6 +2956,59 @@ impl LockedDatabase {
             );
             c.streams[type_.index()] = Some(id);
         }
+        let mut stmt = self.conn.prepare(
+            r#"
+            select
+              stream_id,
+              sample_file_dir_id
+            from
+              stream_stripe
+            order by
+              stream_id,
+              idx
+        "#,
+        )?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let stream_id = row.get(0)?;
+            let s = self
+                .streams_by_id
+                .get_mut(&stream_id)
+                .ok_or_else(|| format_err!("missing stream {} for stripe", stream_id))?;
+            s.stripe_dir_ids.push(row.get(1)?);
+        }
+        let mut stmt = self.conn.prepare(
+            r#"
+            select
+              stream_id,
+              source_stream_id,
+              crop_x,
+              crop_y,
+              crop_width,
+              crop_height,
+              width,
+              height
+            from
+              virtual_stream
+        "#,
+        )?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let stream_id = row.get(0)?;
+            let s = self
+                .streams_by_id
+                .get_mut(&stream_id)
+                .ok_or_else(|| format_err!("missing virtual stream {}", stream_id))?;
+            s.virtual_source = Some(VirtualSource {
+                source_stream_id: row.get(1)?,
+                crop_x: row.get(2)?,
+                crop_y: row.get(3)?,
+                crop_width: row.get(4)?,
+                crop_height: row.get(5)?,
+                width: row.get(6)?,
+                height: row.get(7)?,
+            });
+        }
         info!("Loaded {} streams", self.streams_by_id.len());
         Ok(())
     }
@@ -1753,9 +3022,21 @@ impl LockedDatabase {
         data: Vec<u8>,
         rfc6381_codec: String,
     ) -> Result<i32, Error> {
-        let sha1 = hash::hash(hash::MessageDigest::sha1(), &data)?;
-        let mut sha1_bytes = [0u8; 20];
-        sha1_bytes.copy_from_slice(&sha1);
+        self.insert_encoded_video_sample_entry(width, height, data, rfc6381_codec, None)
+    }
+
+    /// Inserts the specified video sample entry if absent, as with `insert_video_sample_entry`,
+    /// noting the ffmpeg arguments of the encoder which produced it. An existing row keeps its
+    /// original `encoder`.
+    pub fn insert_encoded_video_sample_entry(
+        &mut self,
+        width: u16,
+        height: u16,
+        data: Vec<u8>,
+        rfc6381_codec: String,
+        encoder: Option<String>,
+    ) -> Result<i32, Error> {
+        let sha1_bytes = crypto::sha1(&data);
 
         // Check if it already exists.
         // There shouldn't be too many entries, so it's fine to enumerate everything.
@@ -1784,6 +3065,7 @@ impl LockedDatabase {
             ":height": i32::from(height),
             ":rfc6381_codec": &rfc6381_codec,
             ":data": &data,
+            ":encoder": &encoder,
         })?;
 
         let id = self.conn.last_insert_rowid() as i32;
@@ -1796,6 +3078,7 @@ impl LockedDatabase {
                 sha1: sha1_bytes,
                 data,
                 rfc6381_codec,
+                encoder,
             }),
         );
 
@@ -1838,6 +3121,7 @@ impl LockedDatabase {
                 uuid,
                 dir: Some(dir),
                 last_complete_open: None,
+                unhealthy_since_sec: None,
                 garbage_needs_unlink: FnvHashSet::default(),
                 garbage_unlinked: Vec::new(),
             }),
@@ -1851,7 +3135,10 @@ impl LockedDatabase {
 
     pub fn delete_sample_file_dir(&mut self, dir_id: i32) -> Result<(), Error> {
         for (&id, s) in self.streams_by_id.iter() {
-            if s.sample_file_dir_id == Some(dir_id) {
+            if s.sample_file_dir_id == Some(dir_id)
+                || s.stripe_dir_ids.contains(&dir_id)
+                || s.failover_sample_file_dir_id == Some(dir_id)
+            {
                 bail!("can't delete dir referenced by stream {}", id);
             }
         }
@@ -1866,7 +3153,11 @@ impl LockedDatabase {
             );
         }
         let dir = match d.get_mut().dir.take() {
-            None => dir::SampleFileDir::open(&d.get().path, &d.get().meta(&self.uuid))?,
+            None => dir::SampleFileDir::open(
+                &d.get().path,
+                &d.get().meta(&self.uuid),
+                self.sample_file_key.clone(),
+            )?,
             Some(arc) => match Arc::strong_count(&arc) {
                 1 => {
                     d.get_mut().dir = Some(arc); // put it back.
@@ -1909,9 +3200,9 @@ impl LockedDatabase {
             let mut stmt = tx.prepare_cached(
                 r#"
                 insert into camera (uuid,  short_name,  description,  onvif_host,  username,
-                                    password)
+                                    password,  ptz)
                             values (:uuid, :short_name, :description, :onvif_host, :username,
-                                    :password)
+                                    :password, :ptz)
             "#,
             )?;
             stmt.execute_named(named_params! {
@@ -1921,6 +3212,7 @@ impl LockedDatabase {
                 ":onvif_host": &camera.onvif_host,
                 ":username": &camera.username,
                 ":password": &camera.password,
+                ":ptz": &camera.ptz,
             })?;
             camera_id = tx.last_insert_rowid() as i32;
             streams =
@@ -1938,10 +3230,12 @@ impl LockedDatabase {
                 onvif_host: camera.onvif_host,
                 username: camera.username,
                 password: camera.password,
+                ptz: camera.ptz,
                 streams,
             },
         );
         self.cameras_by_uuid.insert(uuid, camera_id);
+        self.send_event(Event::StreamsChanged);
         Ok(camera_id)
     }
 
@@ -1963,7 +3257,8 @@ impl LockedDatabase {
                     description = :description,
                     onvif_host = :onvif_host,
                     username = :username,
-                    password = :password
+                    password = :password,
+                    ptz = :ptz
                 where
                     id = :id
             "#,
@@ -1975,6 +3270,7 @@ impl LockedDatabase {
                 ":onvif_host": &camera.onvif_host,
                 ":username": &camera.username,
                 ":password": &camera.password,
+                ":ptz": &camera.ptz,
             })?;
             if rows != 1 {
                 bail!("Camera {} missing from database", camera_id);
@@ -1986,7 +3282,9 @@ impl LockedDatabase {
         c.onvif_host = camera.onvif_host;
         c.username = camera.username;
         c.password = camera.password;
+        c.ptz = camera.ptz;
         c.streams = streams.apply(&mut self.streams_by_id);
+        self.send_event(Event::StreamsChanged);
         Ok(())
     }
 
@@ -2005,15 +3303,38 @@ impl LockedDatabase {
                 if stream.camera_id != id {
                     continue;
                 };
-                if stream.range.is_some() {
+                if stream.range.is_some() || !stream.uncommitted.is_empty() {
                     bail!("Can't remove camera {}; has recordings.", id);
                 }
+                if let Some(v) = self.streams_by_id.values().find(|v| {
+                    v.camera_id != id
+                        && v.virtual_source.map(|s| s.source_stream_id) == Some(*stream_id)
+                }) {
+                    bail!(
+                        "Can't remove camera {}; its stream {} is the source of virtual stream {}.",
+                        id,
+                        stream_id,
+                        v.id
+                    );
+                }
+                raw::set_virtual_source(&tx, *stream_id, None)?;
+                playback::delete_stream(&tx, *stream_id)?;
                 let rows = stream_stmt.execute_named(named_params! {":id": stream_id})?;
                 if rows != 1 {
                     bail!("Stream {} missing from database", id);
                 }
                 streams_to_delete.push(*stream_id);
             }
+            bookmark::delete_camera(&tx, id)?;
+            auth::delete_camera(&tx, id)?;
+
+            // Keep the camera's notes, detached from it.
+            let mut note_stmt =
+                tx.prepare_cached(r"update note set camera_id = null where camera_id = :id")?;
+            note_stmt.execute_named(named_params! {":id": id})?;
+            let mut signal_stmt =
+                tx.prepare_cached(r"delete from signal_camera where camera_id = :id")?;
+            signal_stmt.execute_named(named_params! {":id": id})?;
             let mut cam_stmt = tx.prepare_cached(r"delete from camera where id = :id")?;
             let rows = cam_stmt.execute_named(named_params! {":id": id})?;
             if rows != 1 {
@@ -2026,9 +3347,126 @@ impl LockedDatabase {
         }
         self.cameras_by_id.remove(&id);
         self.cameras_by_uuid.remove(&uuid);
+        self.auth.camera_deleted(id);
+        self.signal.camera_deleted(id);
+        self.send_event(Event::StreamsChanged);
         return Ok(());
     }
 
+    /// Deletes a stream along with its recordings, as while the server is running.
+    ///
+    /// The stream's committed recordings, and any which are synced but not yet committed, are
+    /// moved to the `garbage` table immediately rather than on the next flush; their sample files
+    /// are unlinked by the directories' syncers (or, if none are running, when they next start).
+    /// Any flushes planned for the stream's recordings become unnecessary.
+    ///
+    /// Fails with `ErrorKind::Unavailable` while one of the stream's recordings is still being
+    /// written or synced. The caller should first stop recording the stream, as by
+    /// `update_retention`, and retry once its writer has finished.
+    pub fn delete_stream(&mut self, stream_id: i32) -> Result<(), base::Error> {
+        let s = match self.streams_by_id.get(&stream_id) {
+            None => bail_t!(NotFound, "no such stream {}", stream_id),
+            Some(s) => s,
+        };
+        if s.synced_recordings < s.uncommitted.len() {
+            bail_t!(
+                Unavailable,
+                "stream {} has a recording in progress",
+                stream_id
+            );
+        }
+        check_not_virtual_source(&self.streams_by_id, stream_id)
+            .err_kind(ErrorKind::FailedPrecondition)?;
+
+        // Find the sample files to unlink, by directory.
+        let mut garbage = Vec::new();
+        raw::list_oldest_recordings(&self.conn, CompositeId::new(stream_id, 0), &mut |row| {
+            garbage.push((s.dir_id_for(row.id.recording(), row.flags), row.id));
+            true
+        })
+        .err_kind(ErrorKind::Internal)?;
+        let committed = garbage.len();
+        for (i, u) in s.uncommitted.iter().enumerate() {
+            let id = CompositeId::new(stream_id, s.next_recording_id + i as i32);
+            garbage.push((s.dir_id_for(id.recording(), u.lock().flags), id));
+        }
+        let garbage: Vec<(i32, CompositeId)> = garbage
+            .into_iter()
+            .map(|(dir_id, id)| {
+                dir_id
+                    .map(|d| (d, id))
+                    .ok_or_else(|| format_err!("no sample file dir for recording {}", id))
+            })
+            .collect::<Result<_, Error>>()
+            .err_kind(ErrorKind::Internal)?;
+
+        let tx = self.conn.transaction().err_kind(ErrorKind::Internal)?;
+        {
+            let n = raw::delete_recordings(
+                &tx,
+                &s.dir_ids(),
+                s.failover_sample_file_dir_id,
+                CompositeId::new(stream_id, 0)..CompositeId::new(stream_id + 1, 0),
+            )
+            .err_kind(ErrorKind::Internal)?;
+            if n != committed {
+                bail_t!(
+                    Internal,
+                    "stream {} has {} recordings but {} were deleted",
+                    stream_id,
+                    committed,
+                    n
+                );
+            }
+            let mut garbage_stmt = tx
+                .prepare_cached(
+                    r#"
+                    insert into garbage (sample_file_dir_id, composite_id) values (?, ?)
+                "#,
+                )
+                .err_kind(ErrorKind::Internal)?;
+            for &(dir_id, id) in &garbage[committed..] {
+                garbage_stmt
+                    .execute(params![dir_id, id.0])
+                    .err_kind(ErrorKind::Internal)?;
+            }
+            raw::set_stream_stripes(&tx, stream_id, &[]).err_kind(ErrorKind::Internal)?;
+            raw::set_virtual_source(&tx, stream_id, None).err_kind(ErrorKind::Internal)?;
+            playback::delete_stream(&tx, stream_id).err_kind(ErrorKind::Internal)?;
+            let rows = tx
+                .execute("delete from stream where id = ?", params![stream_id])
+                .err_kind(ErrorKind::Internal)?;
+            if rows != 1 {
+                bail_t!(Internal, "stream {} missing from database", stream_id);
+            }
+        }
+        tx.commit().err_kind(ErrorKind::Internal)?;
+
+        let s = self.streams_by_id.remove(&stream_id).unwrap();
+        if let Some(c) = self.cameras_by_id.get_mut(&s.camera_id) {
+            c.streams[s.type_.index()] = None;
+        }
+        let mut cache = self.video_index_cache.borrow_mut();
+        for (dir_id, id) in garbage {
+            cache.remove(id);
+            if let Some(d) = self.sample_file_dirs_by_id.get_mut(&dir_id) {
+                d.garbage_needs_unlink.insert(id);
+            }
+        }
+        drop(cache);
+        info!(
+            "Deleted stream {}; its recordings are now garbage",
+            stream_id
+        );
+
+        // Prompt the syncers to collect the garbage, as after a flush.
+        for cb in &self.on_flush {
+            cb();
+        }
+        self.send_event(Event::StreamsChanged);
+        Ok(())
+    }
+
     pub fn update_retention(&mut self, changes: &[RetentionChange]) -> Result<(), Error> {
         let tx = self.conn.transaction()?;
         {
@@ -2069,6 +3507,24 @@ impl LockedDatabase {
             s.record = c.new_record;
             s.retain_bytes = c.new_limit;
         }
+        self.send_event(Event::StreamsChanged);
+        Ok(())
+    }
+
+    /// Replaces the given stream's privacy mask. Unlike most stream settings, this takes effect
+    /// immediately, without restarting the server.
+    pub fn set_privacy_mask(&mut self, stream_id: i32, mask: mask::Mask) -> Result<(), Error> {
+        let rows = self.conn.execute(
+            "update stream set privacy_mask = ? where id = ?",
+            params![mask.to_string(), stream_id],
+        )?;
+        if rows != 1 {
+            bail!("no such stream {}", stream_id);
+        }
+        self.streams_by_id
+            .get_mut(&stream_id)
+            .expect("stream in db but not state")
+            .privacy_mask = mask;
         Ok(())
     }
 
@@ -2086,6 +3542,14 @@ impl LockedDatabase {
         self.auth.delete_user(&mut self.conn, id)
     }
 
+    pub fn set_login_throttle(&mut self, throttle: Option<auth::LoginThrottle>) {
+        self.auth.set_login_throttle(throttle)
+    }
+
+    pub fn expire_users(&mut self, req: auth::Request) -> Result<Vec<i32>, Error> {
+        self.auth.expire_users(&self.conn, req)
+    }
+
     pub fn get_user(&self, username: &str) -> Option<&User> {
         self.auth.get_user(username)
     }
@@ -2133,6 +3597,59 @@ impl LockedDatabase {
             .revoke_session(&self.conn, reason, detail, req, hash)
     }
 
+    pub fn list_sessions(&self, user_id: i32) -> Result<Vec<auth::ListedSession>, Error> {
+        self.auth.list_sessions(&self.conn, user_id)
+    }
+
+    pub fn revoke_session_by_id(
+        &mut self,
+        reason: auth::RevocationReason,
+        req: auth::Request,
+        user_id: i32,
+        id: i32,
+    ) -> Result<bool, Error> {
+        self.auth
+            .revoke_session_by_id(&self.conn, reason, req, user_id, id)
+    }
+
+    pub fn revoke_all_sessions(
+        &mut self,
+        reason: auth::RevocationReason,
+        req: auth::Request,
+        user_id: i32,
+        except: Option<i32>,
+    ) -> Result<usize, Error> {
+        self.auth
+            .revoke_all_sessions(&self.conn, reason, req, user_id, except)
+    }
+
+    pub fn tokens_by_id(&self) -> &BTreeMap<i32, auth::AccessToken> {
+        self.auth.tokens_by_id()
+    }
+
+    pub fn create_token(
+        &mut self,
+        uid: i32,
+        description: Option<String>,
+        permissions: schema::Permissions,
+        when_sec: i64,
+    ) -> Result<(RawSessionId, &auth::AccessToken), Error> {
+        self.auth
+            .create_token(&self.conn, uid, description, permissions, when_sec)
+    }
+
+    pub fn revoke_token(&mut self, id: i32, when_sec: i64) -> Result<(), Error> {
+        self.auth.revoke_token(&self.conn, id, when_sec)
+    }
+
+    pub fn authenticate_token(
+        &mut self,
+        req: auth::Request,
+        hash: &auth::SessionHash,
+    ) -> Result<(&auth::AccessToken, &User), Error> {
+        self.auth.authenticate_token(req, hash)
+    }
+
     // ---- signal ----
 
     pub fn signals_by_id(&self) -> &BTreeMap<u32, signal::Signal> {
@@ -2154,99 +3671,716 @@ impl LockedDatabase {
         signals: &[u32],
         states: &[u16],
     ) -> Result<(), base::Error> {
-        self.signal.update_signals(when, signals, states)
+        self.signal.update_signals(when.clone(), signals, states)?;
+        self.send_event(Event::SignalsChanged {
+            time: when,
+            signals: signals.to_vec(),
+            states: states.to_vec(),
+        });
+        Ok(())
     }
-}
-
-/// Sets pragmas for full database integrity.
-pub(crate) fn set_integrity_pragmas(conn: &mut rusqlite::Connection) -> Result<(), Error> {
-    // Enforce foreign keys. This is on by default with --features=bundled (as rusqlite
-    // compiles the SQLite3 amalgamation with -DSQLITE_DEFAULT_FOREIGN_KEYS=1). Ensure it's
-    // always on. Note that our foreign keys are immediate rather than deferred, so we have to
-    // be careful about the order of operations during the upgrade.
-    conn.execute("pragma foreign_keys = on", params![])?;
-
-    // Make the database actually durable.
-    conn.execute("pragma fullfsync = on", params![])?;
-    conn.execute("pragma synchronous = 3", params![])?;
-    Ok(())
-}
 
-/// Initializes a database.
-/// Note this doesn't set journal options, so that it can be used on in-memory databases for
-/// test code.
-pub fn init(conn: &mut rusqlite::Connection) -> Result<(), Error> {
-    set_integrity_pragmas(conn)?;
-    let tx = conn.transaction()?;
-    tx.execute_batch(include_str!("schema.sql"))?;
-    {
-        let uuid = ::uuid::Uuid::new_v4();
-        let uuid_bytes = &uuid.as_bytes()[..];
-        tx.execute("insert into meta (uuid) values (?)", params![uuid_bytes])?;
+    /// Adds a signal, as in `signal::State::add_signal`, returning its id.
+    pub fn add_signal(
+        &mut self,
+        source: Uuid,
+        type_: Uuid,
+        short_name: String,
+        states: Vec<signal::TypeState>,
+        cameras: Vec<signal::SignalCamera>,
+    ) -> Result<u32, base::Error> {
+        self.check_signal_cameras(&cameras)?;
+        let tx = self.conn.transaction().err_kind(ErrorKind::Internal)?;
+        let id = self
+            .signal
+            .add_signal(&tx, source, type_, short_name, states, cameras)?;
+        tx.commit().err_kind(ErrorKind::Internal)?;
+        Ok(id)
     }
-    tx.commit()?;
-    Ok(())
-}
 
-/// Gets the schema version from the given database connection.
-/// A fully initialized database will return `Ok(Some(version))` where `version` is an integer that
-/// can be compared to `EXPECTED_VERSION`. An empty database will return `Ok(None)`. A partially
-/// initialized database (in particular, one without a version row) will return some error.
-pub fn get_schema_version(conn: &rusqlite::Connection) -> Result<Option<i32>, Error> {
-    let ver_tables: i32 = conn.query_row_and_then(
-        "select count(*) from sqlite_master where name = 'version'",
-        params![],
-        |row| row.get(0),
-    )?;
-    if ver_tables == 0 {
-        return Ok(None);
+    /// Replaces the given signal's short name and cameras.
+    pub fn update_signal(
+        &mut self,
+        id: u32,
+        short_name: String,
+        cameras: Vec<signal::SignalCamera>,
+    ) -> Result<(), base::Error> {
+        self.check_signal_cameras(&cameras)?;
+        let tx = self.conn.transaction().err_kind(ErrorKind::Internal)?;
+        self.signal.update_signal(&tx, id, short_name, cameras)?;
+        tx.commit().err_kind(ErrorKind::Internal)?;
+        Ok(())
     }
-    Ok(Some(conn.query_row_and_then(
-        "select max(id) from version",
-        params![],
-        |row| row.get(0),
-    )?))
-}
-
-/// The recording database. Abstracts away SQLite queries. Also maintains in-memory state
-/// (loaded on startup, and updated on successful commit) to avoid expensive scans over the
-/// recording table on common queries.
-pub struct Database<C: Clocks + Clone = clock::RealClocks> {
-    /// This is wrapped in an `Option` to allow the `Drop` implementation and `close` to coexist.
-    db: Option<Mutex<LockedDatabase>>,
 
-    /// This is kept separately from the `LockedDatabase` to allow the `lock()` operation itself to
-    /// access it. It doesn't need a `Mutex` anyway; it's `Sync`, and all operations work on
-    /// `&self`.
-    clocks: C,
-}
+    /// Deletes the given signal along with its history. Unlike other signal changes, the
+    /// history is rewritten immediately rather than on the next flush.
+    pub fn delete_signal(&mut self, id: u32) -> Result<(), base::Error> {
+        let tx = self.conn.transaction().err_kind(ErrorKind::Internal)?;
+        self.signal.delete_signal(&tx, id)?;
+        self.signal.flush(&tx).err_kind(ErrorKind::Internal)?;
+        tx.commit().err_kind(ErrorKind::Internal)?;
+        self.signal.post_flush();
+        Ok(())
+    }
 
-impl<C: Clocks + Clone> Drop for Database<C> {
-    fn drop(&mut self) {
-        if ::std::thread::panicking() {
-            return; // don't flush while panicking.
-        }
-        if let Some(m) = self.db.take() {
-            if let Err(e) = m.into_inner().flush(&self.clocks, "drop") {
-                error!("Final database flush failed: {}", e);
+    fn check_signal_cameras(&self, cameras: &[signal::SignalCamera]) -> Result<(), base::Error> {
+        for c in cameras {
+            if !self.cameras_by_id.contains_key(&c.camera_id) {
+                bail_t!(NotFound, "no such camera {}", c.camera_id);
             }
         }
+        Ok(())
     }
-}
 
-// Helpers for Database::lock(). Closures don't implement Fn.
-fn acquisition() -> &'static str {
-    "database lock acquisition"
-}
-fn operation() -> &'static str {
-    "database operation"
-}
+    /// Returns the id of the given camera's privacy signal, if it has one.
+    fn privacy_signal(&self, camera_uuid: Uuid) -> Option<u32> {
+        self.signal
+            .signals_by_id()
+            .values()
+            .find(|s| s.source == camera_uuid && s.type_ == signal::PRIVACY_TYPE_UUID)
+            .map(|s| s.id)
+    }
 
-impl<C: Clocks + Clone> Database<C> {
-    /// Creates the database from a caller-supplied SQLite connection.
-    pub fn new(
-        clocks: C,
-        mut conn: rusqlite::Connection,
+    /// Returns if the given camera is in privacy mode as of `when`.
+    pub fn privacy_at(&self, camera_id: i32, when: recording::Time) -> bool {
+        let uuid = match self.cameras_by_id.get(&camera_id) {
+            None => return false,
+            Some(c) => c.uuid,
+        };
+        match self.privacy_signal(uuid) {
+            None => false,
+            Some(s) => self.signal.state_at(s, when) == 2,
+        }
+    }
+
+    /// Returns the first time after `when` at which the given camera's privacy mode changes.
+    pub fn privacy_changes_after(
+        &self,
+        camera_id: i32,
+        when: recording::Time,
+    ) -> Option<recording::Time> {
+        let id = self
+            .cameras_by_id
+            .get(&camera_id)
+            .and_then(|c| self.privacy_signal(c.uuid))?;
+        let mut next = None;
+        self.signal.list_changes_by_time(
+            recording::Time(when.0 + 1)..recording::Time::max_value(),
+            &mut |r| {
+                if next.is_none() && r.signal == id && r.when > when {
+                    next = Some(r.when);
+                }
+            },
+        );
+        next
+    }
+
+    /// Puts the given camera into (`private` true) or out of privacy mode for `when`,
+    /// creating its privacy signal if necessary. The change itself is stored on the next
+    /// flush, which also serves as the record of when the camera was private.
+    pub fn set_privacy(
+        &mut self,
+        camera_id: i32,
+        when: Range<recording::Time>,
+        private: bool,
+    ) -> Result<(), Error> {
+        let (uuid, short_name) = match self.cameras_by_id.get(&camera_id) {
+            None => bail!("no such camera {}", camera_id),
+            Some(c) => (c.uuid, c.short_name.clone()),
+        };
+        let id = match self.privacy_signal(uuid) {
+            Some(id) => id,
+            None => {
+                let tx = self.conn.transaction()?;
+                let id = self.signal.add_signal(
+                    &tx,
+                    uuid,
+                    signal::PRIVACY_TYPE_UUID,
+                    format!("{} privacy", short_name),
+                    vec![
+                        signal::TypeState {
+                            value: 1,
+                            name: "normal".to_owned(),
+                            motion: false,
+                            color: "#888888".to_owned(),
+                        },
+                        signal::TypeState {
+                            value: 2,
+                            name: "private".to_owned(),
+                            motion: false,
+                            color: "#000000".to_owned(),
+                        },
+                    ],
+                    vec![signal::SignalCamera {
+                        camera_id,
+                        type_: signal::SignalCameraType::Direct,
+                    }],
+                )?;
+                tx.commit()?;
+                id
+            }
+        };
+        self.signal
+            .update_signals(when, &[id], &[if private { 2 } else { 1 }])?;
+        Ok(())
+    }
+
+    /// Adds the given detections to committed recordings of the given stream.
+    /// Either all are added or (on error) none are.
+    pub fn add_detections(
+        &mut self,
+        stream_id: i32,
+        detections: &[detection::DetectionToInsert],
+    ) -> Result<(), base::Error> {
+        let tx = self.conn.transaction().err_kind(ErrorKind::Internal)?;
+        detection::insert(&tx, stream_id, detections)?;
+        tx.commit().err_kind(ErrorKind::Internal)?;
+        Ok(())
+    }
+
+    /// Lists detections in the given stream which overlap the given time range, optionally
+    /// restricted to the given label.
+    pub fn list_detections(
+        &self,
+        stream_id: i32,
+        time: Range<recording::Time>,
+        label: Option<&str>,
+        f: &mut dyn FnMut(detection::ListDetectionsRow) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        detection::list(&self.conn, stream_id, time, label, f)
+    }
+
+    /// Adds the given note, returning its id.
+    pub fn add_note(&mut self, n: &search::NoteToInsert) -> Result<i64, base::Error> {
+        if let Some(id) = n.camera_id {
+            if !self.cameras_by_id.contains_key(&id) {
+                bail_t!(NotFound, "no such camera {}", id);
+            }
+        }
+        search::insert_note(&self.conn, n)
+    }
+
+    /// Deletes the given note.
+    pub fn delete_note(&mut self, id: i64) -> Result<(), base::Error> {
+        search::delete_note(&self.conn, id)
+    }
+
+    /// Searches camera names and descriptions, notes, and detection labels for up to `limit`
+    /// matches of all the terms in `q`, best first. Notes and detections must overlap `time`.
+    pub fn search(
+        &self,
+        q: &str,
+        time: Range<recording::Time>,
+        limit: u32,
+        f: &mut dyn FnMut(search::SearchRow) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        search::search(&self.conn, q, time, limit, f)
+    }
+
+    /// Lists the committed recordings within `ids` in ascending order, for mapping sample file
+    /// names to what they contain.
+    pub fn list_sample_files(
+        &self,
+        ids: Range<CompositeId>,
+        f: &mut dyn FnMut(SampleFileRow) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        raw::list_sample_files(&self.conn, ids, f)
+    }
+
+    /// Lists up to `limit` committed recordings within `ids` to check for corruption; see
+    /// `scrub::Scrubber`.
+    pub fn list_scrub_candidates(
+        &self,
+        ids: Range<CompositeId>,
+        limit: i64,
+    ) -> Result<Vec<scrub::ScrubCandidate>, Error> {
+        scrub::list_candidates(&self.conn, ids, limit)
+    }
+
+    /// Notes that the given committed recording's sample file is corrupt.
+    pub fn mark_corrupt_recording(
+        &mut self,
+        id: CompositeId,
+        detection_time_sec: i64,
+        reason: &str,
+    ) -> Result<(), Error> {
+        scrub::mark_corrupt(&self.conn, id, detection_time_sec, reason)
+    }
+
+    /// Lists all recordings known to be corrupt.
+    pub fn list_corrupt_recordings(
+        &self,
+        f: &mut dyn FnMut(scrub::CorruptRecording),
+    ) -> Result<(), Error> {
+        scrub::list_corrupt(&self.conn, f)
+    }
+
+    /// Counts a playback or export request covering the given ranges of a stream's recorded
+    /// time. The counts are saved on the next flush.
+    pub fn record_playback(&mut self, stream_id: i32, ranges: &[Range<recording::Time>]) {
+        if self.streams_by_id.contains_key(&stream_id) {
+            self.playback.add(stream_id, ranges);
+        }
+    }
+
+    /// Lists playback counts for buckets starting within `time`, ordered by stream and time.
+    pub fn list_playback_heat(
+        &self,
+        time: Range<recording::Time>,
+        f: &mut dyn FnMut(playback::HeatRow),
+    ) -> Result<(), Error> {
+        self.playback.list(&self.conn, time, f)
+    }
+
+    /// Lists per-`bucket` totals of each stream's recordings and detections starting within
+    /// `time`, including uncommitted recordings, ordered by stream and time. Empty buckets are
+    /// omitted. See `timeline.rs`.
+    pub fn list_timeline(
+        &self,
+        time: Range<recording::Time>,
+        bucket: recording::Duration,
+        f: &mut dyn FnMut(timeline::Row),
+    ) -> Result<(), Error> {
+        for (&stream_id, s) in &self.streams_by_id {
+            let mut b = timeline::Buckets::new(stream_id, time.clone(), bucket);
+            b.add_committed(&self.conn)?;
+            for u in &s.uncommitted {
+                let l = u.lock();
+                if l.video_samples > 0 {
+                    b.add_recording(l.start, l.duration_90k, l.sample_file_bytes);
+                }
+            }
+            b.finish(f);
+        }
+        Ok(())
+    }
+
+    /// Lists the gaps of at least `min_gap` in the given stream's recordings within `time`,
+    /// including uncommitted recordings, in ascending order. See `gaps.rs`.
+    pub fn list_gaps(
+        &self,
+        stream_id: i32,
+        time: Range<recording::Time>,
+        min_gap: recording::Duration,
+    ) -> Result<Vec<gaps::Gap>, Error> {
+        let s = match self.streams_by_id.get(&stream_id) {
+            None => bail!("no such stream {}", stream_id),
+            Some(s) => s,
+        };
+        let mut recordings = Vec::new();
+        self.list_recordings_by_time(stream_id, time.clone(), &mut |r| {
+            recordings.push(gaps::Recording::from(&r));
+            Ok(())
+        })?;
+        recordings.sort_by_key(|r| r.time.start);
+        let preceding = gaps::preceding(&self.conn, stream_id, time.start)?;
+        let oldest = s
+            .range
+            .as_ref()
+            .map(|r| r.start)
+            .or_else(|| recordings.first().map(|r| r.time.start));
+        Ok(gaps::find(
+            time,
+            min_gap,
+            &recordings,
+            preceding.as_ref(),
+            oldest,
+            self.open.map(|o| o.id),
+        ))
+    }
+
+    /// Measures the given stream's disk usage over `time`, for predicting how much footage it
+    /// would keep under a different retention limit. See `retention.rs`.
+    ///
+    /// Only committed recordings count; those straddling the start of `time` are prorated.
+    pub fn stream_usage(
+        &self,
+        stream_id: i32,
+        time: Range<recording::Time>,
+    ) -> Result<retention::Usage, Error> {
+        let s = match self.streams_by_id.get(&stream_id) {
+            None => bail!("no such stream {}", stream_id),
+            Some(s) => s,
+        };
+        let start = match s.range {
+            Some(ref r) => cmp::min(cmp::max(time.start, r.start), time.end),
+            None => time.end,
+        };
+        let time = start..time.end;
+        let mut recorded = recording::Duration(0);
+        let mut sample_file_bytes = 0;
+        self.list_recordings_by_time(stream_id, time.clone(), &mut |r| {
+            if (r.flags & RecordingFlags::Uncommitted as i32) != 0 || r.duration_90k <= 0 {
+                return Ok(());
+            }
+            let end = r.start + recording::Duration(i64::from(r.duration_90k));
+            let included = cmp::min(end, time.end) - cmp::max(r.start, time.start);
+            recorded += included;
+            sample_file_bytes +=
+                i64::from(r.sample_file_bytes) * included.0 / i64::from(r.duration_90k);
+            Ok(())
+        })?;
+
+        // Account for the wasted space in each file's last block in proportion to the stream
+        // as a whole.
+        let fs_bytes = if s.sample_file_bytes > 0 {
+            (i128::from(sample_file_bytes) * i128::from(s.fs_bytes)
+                / i128::from(s.sample_file_bytes)) as i64
+        } else {
+            sample_file_bytes
+        };
+        Ok(retention::Usage {
+            time,
+            recorded,
+            fs_bytes,
+        })
+    }
+
+    /// Returns the given user's notification policy, if any.
+    pub fn get_notification_policy(
+        &self,
+        user_id: i32,
+    ) -> Result<Option<notify::NotificationPolicy>, Error> {
+        let mut policy = None;
+        notify::list(&self.conn, Some(user_id), &mut |_, p| policy = Some(p))?;
+        Ok(policy)
+    }
+
+    /// Sets (or, if `policy` is `None`, clears) the given user's notification policy.
+    pub fn set_notification_policy(
+        &mut self,
+        user_id: i32,
+        policy: Option<&notify::NotificationPolicy>,
+    ) -> Result<(), base::Error> {
+        if !self.auth.users_by_id().contains_key(&user_id) {
+            bail_t!(NotFound, "no such user {}", user_id);
+        }
+        notify::set(&self.conn, user_id, policy)
+    }
+
+    /// Lists all users' notification policies, in order of user id.
+    pub fn list_notification_policies(
+        &self,
+        f: &mut dyn FnMut(i32, notify::NotificationPolicy),
+    ) -> Result<(), Error> {
+        notify::list(&self.conn, None, f)
+    }
+
+    /// Protects (or, if `protect` is false, unprotects) recordings of the given stream which
+    /// overlap the given time range. Retention skips protected recordings, deleting newer ones
+    /// instead. Recordings which haven't been committed yet, including the one in progress, are
+    /// protected as they're flushed. Returns the number of committed recordings changed.
+    pub fn protect_recordings(
+        &mut self,
+        stream_id: i32,
+        time: Range<recording::Time>,
+        protect: bool,
+    ) -> Result<usize, Error> {
+        let s = match self.streams_by_id.get_mut(&stream_id) {
+            None => bail!("no stream {}", stream_id),
+            Some(s) => s,
+        };
+        let n = raw::protect_recordings(&self.conn, stream_id, time.clone(), protect)?;
+        if protect {
+            // Cancel deletions of newly protected recordings which haven't been committed yet.
+            let (bytes_to_delete, fs_bytes_to_delete) =
+                (&mut s.bytes_to_delete, &mut s.fs_bytes_to_delete);
+            s.to_delete.retain(|r| {
+                let end = r.start + recording::Duration(r.duration as i64);
+                if r.start < time.end && end > time.start {
+                    let bytes = i64::from(r.sample_file_bytes);
+                    *bytes_to_delete -= bytes;
+                    *fs_bytes_to_delete -= round_up(bytes);
+                    return false;
+                }
+                true
+            });
+            if s.range.as_ref().map(|r| time.end > r.end).unwrap_or(true) {
+                s.protect_pending.push(time);
+            }
+        } else {
+            s.protect_pending
+                .retain(|t| t.start >= time.end || t.end <= time.start);
+        }
+        Ok(n)
+    }
+
+    /// Queues for deletion the committed recordings of the given stream which overlap the given
+    /// time range, such as to honor a privacy request, returning the number queued. Fails if any
+    /// of them is protected. Recordings which haven't been committed yet are left alone.
+    ///
+    /// As with `delete_oldest_recordings`, the next flush moves them to the garbage table, after
+    /// which the syncer unlinks their files. Until then, retention still considers the older
+    /// recordings around them.
+    pub fn delete_recordings(
+        &mut self,
+        stream_id: i32,
+        time: Range<recording::Time>,
+    ) -> Result<usize, base::Error> {
+        if self.open.is_none() {
+            bail_t!(FailedPrecondition, "database is read-only");
+        }
+        let s = match self.streams_by_id.get_mut(&stream_id) {
+            None => bail_t!(NotFound, "no stream {}", stream_id),
+            Some(s) => s,
+        };
+        let mut rows = Vec::new();
+        let mut protected = 0;
+        raw::list_recordings_by_time(&self.conn, stream_id, time, &mut |r| {
+            if (r.flags & RecordingFlags::Protected as i32) != 0 {
+                protected += 1;
+            } else if !s.to_delete.iter().any(|d| d.id == r.id) {
+                rows.push(ListOldestRecordingsRow {
+                    id: r.id,
+                    start: r.start,
+                    duration: r.duration_90k,
+                    sample_file_bytes: r.sample_file_bytes,
+                    flags: r.flags,
+                });
+            }
+            Ok(())
+        })
+        .err_kind(ErrorKind::Internal)?;
+        if protected > 0 {
+            bail_t!(
+                FailedPrecondition,
+                "{} recordings in range are protected; unprotect them first",
+                protected
+            );
+        }
+        for r in &rows {
+            let bytes = i64::from(r.sample_file_bytes);
+            s.bytes_to_delete += bytes;
+            s.fs_bytes_to_delete += round_up(bytes);
+        }
+        let n = rows.len();
+        s.to_delete.extend(rows);
+
+        // flush and delete_oldest_recordings expect to_delete to be in id order.
+        s.to_delete.sort_by_key(|r| r.id.0);
+        Ok(n)
+    }
+
+    /// Protects or unprotects the recordings of the given camera's streams (or all streams) which
+    /// overlap the given time range.
+    fn protect_camera_recordings(
+        &mut self,
+        camera_id: Option<i32>,
+        time: Range<recording::Time>,
+        protect: bool,
+    ) -> Result<(), base::Error> {
+        let stream_ids: Vec<i32> = self
+            .streams_by_id
+            .values()
+            .filter(|s| camera_id.map(|c| c == s.camera_id).unwrap_or(true))
+            .map(|s| s.id)
+            .collect();
+        for stream_id in stream_ids {
+            self.protect_recordings(stream_id, time.clone(), protect)
+                .err_kind(ErrorKind::Internal)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the given bookmark, returning its id, and protects the committed recordings it
+    /// covers. Recordings committed later are protected as they're flushed.
+    pub fn add_bookmark(&mut self, b: &bookmark::BookmarkToInsert) -> Result<i64, base::Error> {
+        if let Some(c) = b.camera_id {
+            if !self.cameras_by_id.contains_key(&c) {
+                bail_t!(NotFound, "no such camera {}", c);
+            }
+        }
+        let id = bookmark::insert(&self.conn, b)?;
+        self.protect_camera_recordings(b.camera_id, b.time.clone(), true)?;
+        Ok(id)
+    }
+
+    /// Deletes the given bookmark, unprotecting the recordings it covered except those covered
+    /// by other bookmarks.
+    pub fn delete_bookmark(&mut self, id: i64) -> Result<(), base::Error> {
+        let b = bookmark::delete(&self.conn, id)?;
+        self.protect_camera_recordings(b.camera_id, b.time.clone(), false)?;
+        let mut others = Vec::new();
+        bookmark::list(&self.conn, b.time, &mut |o| {
+            others.push(o);
+            Ok(())
+        })?;
+        for o in others {
+            self.protect_camera_recordings(o.camera_id, o.time, true)?;
+        }
+        Ok(())
+    }
+
+    /// Lists bookmarks overlapping `time`, in order of start time.
+    pub fn list_bookmarks(
+        &self,
+        time: Range<recording::Time>,
+        f: &mut dyn FnMut(bookmark::Bookmark) -> Result<(), base::Error>,
+    ) -> Result<(), base::Error> {
+        bookmark::list(&self.conn, time, f)
+    }
+
+    /// Queues the given export job, returning its id.
+    pub fn add_export_job(&mut self, j: &export::ExportJobToInsert) -> Result<i64, base::Error> {
+        for id in &j.stream_ids {
+            if !self.streams_by_id.contains_key(id) {
+                bail_t!(NotFound, "no such stream {}", id);
+            }
+        }
+        export::insert(&self.conn, j)
+    }
+
+    pub fn get_export_job(&self, id: i64) -> Result<export::ExportJob, base::Error> {
+        export::get(&self.conn, id)
+    }
+
+    /// Lists all export jobs, in order of id.
+    pub fn list_export_jobs(
+        &self,
+        f: &mut dyn FnMut(export::ExportJob) -> Result<(), base::Error>,
+    ) -> Result<(), base::Error> {
+        export::list(&self.conn, f)
+    }
+
+    /// Marks the oldest queued export job as running and returns it, if there is one.
+    pub fn start_export_job(&mut self) -> Result<Option<export::ExportJob>, base::Error> {
+        export::start_next(&self.conn)
+    }
+
+    /// Records a running export job's progress. Returns false if it's been canceled.
+    pub fn set_export_progress(
+        &mut self,
+        id: i64,
+        progress_bytes: i64,
+        total_bytes: i64,
+    ) -> Result<bool, base::Error> {
+        export::set_progress(&self.conn, id, progress_bytes, total_bytes)
+    }
+
+    /// Finishes a running export job with a file name or error. Returns false if it's been
+    /// canceled, in which case the caller should discard the file.
+    pub fn finish_export_job(
+        &mut self,
+        id: i64,
+        result: Result<&str, &str>,
+        finish_time_sec: i64,
+    ) -> Result<bool, base::Error> {
+        export::finish(&self.conn, id, result, finish_time_sec)
+    }
+
+    /// Cancels the given export job if it's queued or running, returning its prior state.
+    pub fn cancel_export_job(
+        &mut self,
+        id: i64,
+        finish_time_sec: i64,
+    ) -> Result<export::ExportState, base::Error> {
+        export::cancel(&self.conn, id, finish_time_sec)
+    }
+
+    /// Deletes the given export job, returning it so the caller can remove its file.
+    pub fn delete_export_job(&mut self, id: i64) -> Result<export::ExportJob, base::Error> {
+        export::delete(&self.conn, id)
+    }
+
+    /// Queues again export jobs left running when the server last stopped.
+    pub fn requeue_export_jobs(&mut self) -> Result<usize, base::Error> {
+        export::requeue_running(&self.conn)
+    }
+}
+
+/// Sets pragmas for full database integrity.
+pub(crate) fn set_integrity_pragmas(conn: &mut rusqlite::Connection) -> Result<(), Error> {
+    // Enforce foreign keys. This is on by default with --features=bundled (as rusqlite
+    // compiles the SQLite3 amalgamation with -DSQLITE_DEFAULT_FOREIGN_KEYS=1). Ensure it's
+    // always on. Note that our foreign keys are immediate rather than deferred, so we have to
+    // be careful about the order of operations during the upgrade.
+    conn.execute("pragma foreign_keys = on", params![])?;
+
+    // Make the database actually durable.
+    conn.execute("pragma fullfsync = on", params![])?;
+    conn.execute("pragma synchronous = 3", params![])?;
+    Ok(())
+}
+
+/// Initializes a database.
+/// Note this doesn't set journal options, so that it can be used on in-memory databases for
+/// test code.
+pub fn init(conn: &mut rusqlite::Connection) -> Result<(), Error> {
+    set_integrity_pragmas(conn)?;
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("schema.sql"))?;
+    {
+        let uuid = ::uuid::Uuid::new_v4();
+        let uuid_bytes = &uuid.as_bytes()[..];
+        tx.execute("insert into meta (uuid) values (?)", params![uuid_bytes])?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Gets the schema version from the given database connection.
+/// A fully initialized database will return `Ok(Some(version))` where `version` is an integer that
+/// can be compared to `EXPECTED_VERSION`. An empty database will return `Ok(None)`. A partially
+/// initialized database (in particular, one without a version row) will return some error.
+pub fn get_schema_version(conn: &rusqlite::Connection) -> Result<Option<i32>, Error> {
+    let ver_tables: i32 = conn.query_row_and_then(
+        "select count(*) from sqlite_master where name = 'version'",
+        params![],
+        |row| row.get(0),
+    )?;
+    if ver_tables == 0 {
+        return Ok(None);
+    }
+    Ok(Some(conn.query_row_and_then(
+        "select max(id) from version",
+        params![],
+        |row| row.get(0),
+    )?))
+}
+
+/// The recording database. Abstracts away SQLite queries. Also maintains in-memory state
+/// (loaded on startup, and updated on successful commit) to avoid expensive scans over the
+/// recording table on common queries.
+pub struct Database<C: Clocks + Clone = clock::RealClocks> {
+    /// This is wrapped in an `Option` to allow the `Drop` implementation and `close` to coexist.
+    db: Option<Mutex<LockedDatabase>>,
+
+    /// This is kept separately from the `LockedDatabase` to allow the `lock()` operation itself to
+    /// access it. It doesn't need a `Mutex` anyway; it's `Sync`, and all operations work on
+    /// `&self`.
+    clocks: C,
+
+    /// A moving average of the time `lock()` callers have waited to acquire the lock, in
+    /// nanoseconds. See `recent_lock_wait`.
+    lock_wait_nanos: AtomicU64,
+}
+
+impl<C: Clocks + Clone> Drop for Database<C> {
+    fn drop(&mut self) {
+        if ::std::thread::panicking() {
+            return; // don't flush while panicking.
+        }
+        if let Some(m) = self.db.take() {
+            if let Err(e) = m.into_inner().flush(&self.clocks, "drop") {
+                error!("Final database flush failed: {}", e);
+            }
+        }
+    }
+}
+
+// Helpers for Database::lock(). Closures don't implement Fn.
+fn acquisition() -> &'static str {
+    "database lock acquisition"
+}
+fn operation() -> &'static str {
+    "database operation"
+}
+
+impl<C: Clocks + Clone> Database<C> {
+    /// Creates the database from a caller-supplied SQLite connection.
+    pub fn new(
+        clocks: C,
+        mut conn: rusqlite::Connection,
         read_write: bool,
     ) -> Result<Database<C>, Error> {
         set_integrity_pragmas(&mut conn)?;
@@ -2307,15 +4441,29 @@ impl<C: Clocks + Clone> Database<C> {
                 open_monotonic,
                 auth,
                 signal,
+                playback: playback::State::default(),
                 sample_file_dirs_by_id: BTreeMap::new(),
                 cameras_by_id: BTreeMap::new(),
                 cameras_by_uuid: BTreeMap::new(),
                 streams_by_id: BTreeMap::new(),
                 video_sample_entries_by_id: BTreeMap::new(),
-                video_index_cache: RefCell::new(LruCache::with_hasher(1024, Default::default())),
+                video_index_cache: RefCell::new(VideoIndexCache::new(
+                    DEFAULT_VIDEO_INDEX_CACHE_BYTES,
+                )),
                 on_flush: Vec::new(),
+                event_watchers: Vec::new(),
+                uncommitted_limits: UncommittedLimits::default(),
+                max_unflushed_recordings: None,
+                sub_retention_multiple: None,
+                disk_full_policy: DiskFullPolicy::default(),
+                preallocation: Preallocation::default(),
+                sample_file_key: None,
+                flush_failures: 0,
+                last_flush_failure: None,
+                flush_failing: false,
             })),
             clocks,
+            lock_wait_nanos: AtomicU64::new(0),
         };
         {
             let l = &mut *db.lock();
@@ -2341,7 +4489,18 @@ impl<C: Clocks + Clone> Database<C> {
     /// operations.
     pub fn lock(&self) -> DatabaseGuard<C> {
         let timer = clock::TimerGuard::new(&self.clocks, acquisition);
+        let start = self.clocks.monotonic();
         let db = self.db.as_ref().unwrap().lock();
+        let waited = (self.clocks.monotonic() - start)
+            .num_nanoseconds()
+            .unwrap_or(i64::max_value())
+            .max(0) as u64;
+
+        // An exponentially-weighted moving average with alpha = 1/8. Concurrent updates may
+        // clobber each other; that's fine for a load signal.
+        let avg = self.lock_wait_nanos.load(atomic::Ordering::Relaxed);
+        self.lock_wait_nanos
+            .store(avg - avg / 8 + waited / 8, atomic::Ordering::Relaxed);
         drop(timer);
         let _timer = clock::TimerGuard::<C, &'static str, fn() -> &'static str>::new(
             &self.clocks,
@@ -2354,6 +4513,12 @@ impl<C: Clocks + Clone> Database<C> {
         }
     }
 
+    /// Returns a moving average of recent waits to acquire the lock, as a sign of contention
+    /// between the writers and (e.g.) the web server.
+    pub fn recent_lock_wait(&self) -> StdDuration {
+        StdDuration::from_nanos(self.lock_wait_nanos.load(atomic::Ordering::Relaxed))
+    }
+
     /// For testing: closes the database (without flushing) and returns the connection.
     /// This allows verification that a newly opened database is in an acceptable state.
     #[cfg(test)]
@@ -2378,8 +4543,14 @@ impl<'db, C: Clocks + Clone> DatabaseGuard<'db, C> {
     ///
     /// On success, for each affected sample file directory with a flush watcher set, sends a
     /// `Flush` event.
-    pub(crate) fn flush(&mut self, reason: &str) -> Result<(), Error> {
-        self.db.flush(self.clocks, reason)
+    pub fn flush(&mut self, reason: &str) -> Result<(), Error> {
+        let r = self.db.flush(self.clocks, reason);
+        if let Err(ref e) = r {
+            self.db.flush_failures += 1;
+            self.db.last_flush_failure = Some(e.to_string());
+        }
+        self.db.flush_failing = r.is_err();
+        r
     }
 }
 
@@ -2648,12 +4819,12 @@ mod tests {
     fn test_version_too_old() {
         testutil::init();
         let c = setup_conn();
-        c.execute_batch("delete from version; insert into version values (4, 0, '');")
+        c.execute_batch("delete from version; insert into version values (5, 0, '');")
             .unwrap();
         let e = Database::new(clock::RealClocks {}, c, false).err().unwrap();
         assert!(
             e.to_string()
-                .starts_with("Database schema version 4 is too old (expected 5)"),
+                .starts_with("Database schema version 5 is too old (expected 6)"),
             "got: {:?}",
             e
         );
@@ -2663,12 +4834,12 @@ mod tests {
     fn test_version_too_new() {
         testutil::init();
         let c = setup_conn();
-        c.execute_batch("delete from version; insert into version values (6, 0, '');")
+        c.execute_batch("delete from version; insert into version values (7, 0, '');")
             .unwrap();
         let e = Database::new(clock::RealClocks {}, c, false).err().unwrap();
         assert!(
             e.to_string()
-                .starts_with("Database schema version 6 is too new (expected 5)"),
+                .starts_with("Database schema version 7 is too new (expected 6)"),
             "got: {:?}",
             e
         );
@@ -2699,18 +4870,31 @@ mod tests {
             onvif_host: "test-camera".to_owned(),
             username: "foo".to_owned(),
             password: "bar".to_owned(),
+            ptz: false,
             streams: [
                 StreamChange {
                     sample_file_dir_id: Some(sample_file_dir_id),
+                    stripe_dir_ids: Vec::new(),
+                    failover_sample_file_dir_id: None,
                     rtsp_url: "rtsp://test-camera/main".to_owned(),
+                    virtual_source: None,
                     record: false,
                     flush_if_sec: 1,
+                    flush_if_bytes: 0,
+                    input_options: String::new(),
+                    rtsp: Default::default(),
                 },
                 StreamChange {
                     sample_file_dir_id: Some(sample_file_dir_id),
+                    stripe_dir_ids: Vec::new(),
+                    failover_sample_file_dir_id: None,
                     rtsp_url: "rtsp://test-camera/sub".to_owned(),
+                    virtual_source: None,
                     record: true,
                     flush_if_sec: 1,
+                    flush_if_bytes: 0,
+                    input_options: String::new(),
+                    rtsp: Default::default(),
                 },
             ],
         };
@@ -2746,6 +4930,8 @@ mod tests {
                 l.streams_by_id().get(&sub_stream_id).unwrap().flush_if_sec,
                 2
             );
+            l.set_privacy_mask(sub_stream_id, mask::Mask::parse("0,0 1,0 1,0.5").unwrap())
+                .unwrap();
         }
         let camera_uuid = { db.lock().cameras_by_id().get(&camera_id).unwrap().uuid };
         assert_no_recordings(&db, camera_uuid);
@@ -2761,6 +4947,15 @@ mod tests {
                 .flush_if_sec,
             2
         );
+        assert_eq!(
+            db.lock()
+                .streams_by_id()
+                .get(&sub_stream_id)
+                .unwrap()
+                .privacy_mask
+                .to_string(),
+            "0,0 1,0 1,0.5"
+        );
         assert_no_recordings(&db, camera_uuid);
 
         // TODO: assert_eq!(db.lock().list_garbage(sample_file_dir_id).unwrap(), &[]);
@@ -2789,13 +4984,27 @@ mod tests {
             video_sync_samples: 1,
             video_sample_entry_id: vse_id,
             video_index: [0u8; 100].to_vec(),
-            sample_file_sha1: [0u8; 20],
+            sample_file_blake3: Some([1u8; 32]),
+            rtp_timestamp: Some((12345, 90000)),
+            thumbnail: None,
         };
         let id = {
             let mut db = db.lock();
             let (id, _) = db.add_recording(main_stream_id, recording.clone()).unwrap();
+            assert_eq!(db.get_thumbnail(id).unwrap(), None);
+            db.set_thumbnail(id, b"\xff\xd8uncommitted".to_vec())
+                .unwrap();
+            assert_eq!(
+                db.get_thumbnail(id).unwrap().as_deref(),
+                Some(&b"\xff\xd8uncommitted"[..])
+            );
             db.mark_synced(id).unwrap();
             db.flush("add test").unwrap();
+            assert_eq!(
+                db.get_thumbnail(id).unwrap().as_deref(),
+                Some(&b"\xff\xd8uncommitted"[..])
+            );
+            db.set_thumbnail(id, b"\xff\xd8committed".to_vec()).unwrap();
             id
         };
         assert_eq!(
@@ -2809,12 +5018,101 @@ mod tests {
 
         // Queries should return the correct result (with caches update on insert).
         assert_single_recording(&db, main_stream_id, &recording);
+        let mut files = Vec::new();
+        db.lock()
+            .list_sample_files(
+                CompositeId::new(main_stream_id, 0)..CompositeId::new(main_stream_id + 1, 0),
+                &mut |r| {
+                    files.push(r);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].id, id);
+        assert_eq!(files[0].start, start);
+        assert_eq!(files[0].sample_file_bytes, 42);
+        assert_eq!(files[0].digest, Some(scrub::Digest::Blake3([1u8; 32])));
+        assert_eq!(files[0].local_time_delta_90k, None); // first of its run.
+        assert_eq!(files[0].rtp_timestamp, Some((12345, 90000)));
 
         // Queries on a fresh database should return the correct result (with caches populated from
         // existing database contents rather than built on insert).
         let conn = db.close();
         let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
         assert_single_recording(&db, main_stream_id, &recording);
+        assert_eq!(
+            db.lock().get_thumbnail(id).unwrap().as_deref(),
+            Some(&b"\xff\xd8committed"[..])
+        );
+
+        // Detections must be within the recording's bounds.
+        {
+            let mut db = db.lock();
+            let mut d = detection::DetectionToInsert {
+                recording_id: id.recording(),
+                time: start..start + recording::Duration(TIME_UNITS_PER_SEC + 1),
+                label: "person".to_owned(),
+                confidence: Some(0.9),
+                source: None,
+            };
+            let e = db.add_detections(main_stream_id, &[d.clone()]).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidArgument);
+            d.time.end = start + recording::Duration(TIME_UNITS_PER_SEC);
+            db.add_detections(main_stream_id, &[d]).unwrap();
+            let mut rows = Vec::new();
+            db.list_detections(
+                main_stream_id,
+                start..start + recording::Duration(1),
+                None,
+                &mut |r| {
+                    rows.push(r);
+                    Ok(())
+                },
+            )
+            .unwrap();
+            assert_eq!(rows.len(), 1);
+            assert_eq!(rows[0].recording_id, id);
+            assert_eq!(rows[0].label, "person");
+        }
+
+        // A protected recording can't be deleted, and protecting cancels a pending deletion.
+        {
+            let mut db = db.lock();
+            let all = recording::Time(0)..recording::Time(i64::max_value());
+            assert_eq!(
+                db.protect_recordings(main_stream_id, all.clone(), true)
+                    .unwrap(),
+                1
+            );
+            let mut n = 0;
+            db.delete_oldest_recordings(main_stream_id, &mut |_| {
+                n += 1;
+                true
+            })
+            .unwrap();
+            assert_eq!(n, 0);
+            assert_eq!(
+                db.protect_recordings(main_stream_id, all.clone(), false)
+                    .unwrap(),
+                1
+            );
+            db.delete_oldest_recordings(main_stream_id, &mut |_| {
+                n += 1;
+                true
+            })
+            .unwrap();
+            assert_eq!(n, 1);
+            assert_eq!(
+                db.protect_recordings(main_stream_id, all.clone(), true)
+                    .unwrap(),
+                1
+            );
+            let s = db.streams_by_id().get(&main_stream_id).unwrap();
+            assert_eq!(s.bytes_to_delete, 0);
+            assert_eq!(s.fs_bytes_to_delete, 0);
+            db.protect_recordings(main_stream_id, all, false).unwrap();
+        }
 
         // Deleting a recording should succeed, update the min/max times, and mark it as garbage.
         {
@@ -2875,6 +5173,661 @@ mod tests {
         assert_eq!(&g, &[]);
     }
 
+    #[test]
+    fn uncommitted_limits() {
+        testutil::init();
+        let (db, _tmpdir, dir_ids) = testutil::new_db(clock::RealClocks {}, 1);
+        let mut c = testutil::test_camera(Some(dir_ids[0]));
+        c.streams[1] = StreamChange {
+            rtsp_url: "rtsp://test-camera/sub".to_owned(),
+            ..c.streams[0].clone()
+        };
+        let camera_id = db.lock().add_camera(c).unwrap();
+        let mut l = db.lock();
+        let (main, sub) = {
+            let c = l.cameras_by_id().get(&camera_id).unwrap();
+            (c.streams[0].unwrap(), c.streams[1].unwrap())
+        };
+        l.set_uncommitted_limits(UncommittedLimits {
+            max_recordings: Some(1),
+            max_bytes: None,
+        });
+        let r = |run_offset| RecordingToInsert {
+            run_offset,
+            ..Default::default()
+        };
+
+        // Under the limit, everything is admitted.
+        l.add_recording(main, r(0)).unwrap();
+        l.add_recording(sub, r(0)).unwrap();
+        assert_eq!(l.uncommitted_totals(), (2, 0));
+
+        // Over the soft limit, only new runs of sub streams are refused.
+        l.add_recording(sub, r(0)).unwrap_err();
+        l.add_recording(main, r(0)).unwrap();
+
+        // Over the hard limit, everything is refused.
+        l.add_recording(sub, r(1)).unwrap_err();
+        l.add_recording(main, r(1)).unwrap_err();
+        assert_eq!(l.uncommitted_totals(), (3, 0));
+    }
+
+    #[test]
+    fn bookmarks() {
+        testutil::init();
+        let (db, _tmpdir, dir_ids) = testutil::new_db(clock::RealClocks {}, 1);
+        let camera_id = db
+            .lock()
+            .add_camera(testutil::test_camera(Some(dir_ids[0])))
+            .unwrap();
+        let mut l = db.lock();
+        let stream_id = l.cameras_by_id().get(&camera_id).unwrap().streams[0].unwrap();
+        let video_sample_entry_id = l
+            .insert_video_sample_entry(1920, 1080, vec![0u8; 100], "avc1.4d0029".to_owned())
+            .unwrap();
+        let sec = |s| recording::Time(s * TIME_UNITS_PER_SEC);
+        let add = |l: &mut LockedDatabase, ids: Range<i32>| {
+            for i in ids {
+                let (id, _) = l
+                    .add_recording(
+                        stream_id,
+                        RecordingToInsert {
+                            run_offset: i,
+                            start: sec(i64::from(i)),
+                            duration_90k: TIME_UNITS_PER_SEC as i32,
+                            video_samples: 1,
+                            video_sample_entry_id,
+                            ..Default::default()
+                        },
+                    )
+                    .unwrap();
+                l.mark_synced(id).unwrap();
+            }
+            l.flush("bookmarks").unwrap();
+        };
+        let delete_all = |l: &mut LockedDatabase| {
+            l.delete_oldest_recordings(stream_id, &mut |_| true)
+                .unwrap();
+            l.flush("bookmarks").unwrap();
+            let mut ids = Vec::new();
+            l.list_recordings_by_id(stream_id, 0..i32::max_value(), &mut |r| {
+                ids.push(r.id.recording());
+                Ok(())
+            })
+            .unwrap();
+            ids
+        };
+        let bookmark = |camera_id, time| bookmark::BookmarkToInsert {
+            camera_id,
+            time,
+            label: "break-in".to_owned(),
+            creation_time_sec: 0,
+            author: None,
+        };
+
+        // A bookmark protects the committed recordings it covers as it's added, and later ones
+        // as they're committed. Retention skips them.
+        add(&mut l, 0..3);
+        let b1 = l
+            .add_bookmark(&bookmark(Some(camera_id), sec(1)..sec(2)))
+            .unwrap();
+        let b2 = l.add_bookmark(&bookmark(None, sec(4)..sec(5))).unwrap();
+        assert_eq!(
+            l.add_bookmark(&bookmark(Some(camera_id + 1), sec(0)..sec(1)))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+        add(&mut l, 3..5);
+        assert_eq!(delete_all(&mut l), vec![1, 4]);
+
+        // Deleting a bookmark unprotects its recordings.
+        l.delete_bookmark(b1).unwrap();
+        assert_eq!(
+            l.delete_bookmark(b1).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(delete_all(&mut l), vec![4]);
+        let mut listed = Vec::new();
+        l.list_bookmarks(sec(0)..sec(10), &mut |b| {
+            listed.push(b.id);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(listed, vec![b2]);
+    }
+
+    #[test]
+    fn delete_recordings() {
+        testutil::init();
+        let (db, _tmpdir, dir_ids) = testutil::new_db(clock::RealClocks {}, 1);
+        let sample_file_dir_id = dir_ids[0];
+        let camera_id = db
+            .lock()
+            .add_camera(testutil::test_camera(Some(dir_ids[0])))
+            .unwrap();
+        let mut l = db.lock();
+        let stream_id = l.cameras_by_id().get(&camera_id).unwrap().streams[0].unwrap();
+        let video_sample_entry_id = l
+            .insert_video_sample_entry(1920, 1080, vec![0u8; 100], "avc1.4d0029".to_owned())
+            .unwrap();
+        let sec = |s| recording::Time(s * TIME_UNITS_PER_SEC);
+        let add = |l: &mut LockedDatabase, ids: Range<i32>| {
+            for i in ids {
+                let (id, _) = l
+                    .add_recording(
+                        stream_id,
+                        RecordingToInsert {
+                            run_offset: i,
+                            start: sec(i64::from(i)),
+                            duration_90k: TIME_UNITS_PER_SEC as i32,
+                            video_samples: 1,
+                            video_sample_entry_id,
+                            ..Default::default()
+                        },
+                    )
+                    .unwrap();
+                l.mark_synced(id).unwrap();
+            }
+            l.flush("delete_recordings").unwrap();
+        };
+        let ids = |l: &LockedDatabase| {
+            let mut ids = Vec::new();
+            l.list_recordings_by_id(stream_id, 0..i32::max_value(), &mut |r| {
+                ids.push(r.id.recording());
+                Ok(())
+            })
+            .unwrap();
+            ids
+        };
+        add(&mut l, 0..5);
+
+        // Deleting from the middle of the stream leaves the recordings around it.
+        assert_eq!(l.delete_recordings(stream_id, sec(1)..sec(3)).unwrap(), 2);
+
+        // Until the flush, retention still starts with the older recording.
+        let mut first = None;
+        l.delete_oldest_recordings(stream_id, &mut |r| {
+            first = Some(r.id.recording());
+            false
+        })
+        .unwrap();
+        assert_eq!(first, Some(0));
+        l.flush("delete_recordings").unwrap();
+        assert_eq!(ids(&l), vec![0, 3, 4]);
+        let s = l.streams_by_id().get(&stream_id).unwrap();
+        assert_eq!(s.bytes_to_delete, 0);
+        assert_eq!(s.duration, recording::Duration(3 * TIME_UNITS_PER_SEC));
+        let garbage = &l
+            .sample_file_dirs_by_id()
+            .get(&sample_file_dir_id)
+            .unwrap()
+            .garbage_needs_unlink;
+        assert!(garbage.contains(&CompositeId::new(stream_id, 1)));
+        assert!(garbage.contains(&CompositeId::new(stream_id, 2)));
+
+        // Protected recordings must be unprotected first.
+        l.protect_recordings(stream_id, sec(3)..sec(4), true)
+            .unwrap();
+
+        // Retention skips the protected recording, which then starts the retained range.
+        let previews = l
+            .preview_deletions(&mut |l| l.delete_oldest_recordings(stream_id, &mut |_| true))
+            .unwrap();
+        let p = previews.get(&stream_id).unwrap();
+        assert_eq!(p.recordings.len(), 2);
+        assert_eq!(p.oldest_remaining, Some(sec(3)));
+        assert_eq!(
+            l.delete_recordings(stream_id, sec(0)..sec(5))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::FailedPrecondition
+        );
+        assert_eq!(
+            l.delete_recordings(stream_id + 1, sec(0)..sec(5))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+        l.protect_recordings(stream_id, sec(3)..sec(4), false)
+            .unwrap();
+        assert_eq!(l.delete_recordings(stream_id, sec(0)..sec(5)).unwrap(), 3);
+        l.flush("delete_recordings").unwrap();
+        assert_eq!(ids(&l), Vec::<i32>::new());
+
+        // Protecting a range with no committed recordings protects them as they're flushed.
+        assert_eq!(
+            l.protect_recordings(stream_id, sec(5)..sec(7), true)
+                .unwrap(),
+            0
+        );
+        add(&mut l, 5..8);
+        assert_eq!(
+            l.delete_recordings(stream_id, sec(5)..sec(8))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::FailedPrecondition
+        );
+        assert_eq!(l.delete_recordings(stream_id, sec(7)..sec(8)).unwrap(), 1);
+    }
+
+    #[test]
+    fn list_aggregated_recordings_page() {
+        testutil::init();
+        let (db, _tmpdir, dir_ids) = testutil::new_db(clock::RealClocks {}, 1);
+        let camera_id = db
+            .lock()
+            .add_camera(testutil::test_camera(Some(dir_ids[0])))
+            .unwrap();
+        let mut l = db.lock();
+        let stream_id = l.cameras_by_id().get(&camera_id).unwrap().streams[0].unwrap();
+        let video_sample_entry_id = l
+            .insert_video_sample_entry(1920, 1080, vec![0u8; 100], "avc1.4d0029".to_owned())
+            .unwrap();
+
+        // A single run of five recordings, the last two uncommitted.
+        let mut ids = Vec::new();
+        for i in 0..5 {
+            let (id, _) = l
+                .add_recording(
+                    stream_id,
+                    RecordingToInsert {
+                        run_offset: i,
+                        start: recording::Time(i64::from(i) * 90_000),
+                        duration_90k: 90_000,
+                        video_samples: 1,
+                        video_sample_entry_id,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            l.mark_synced(id).unwrap();
+            if i == 2 {
+                l.flush("list_aggregated_recordings_page").unwrap();
+            }
+            ids.push(id.recording());
+        }
+
+        let all_time = recording::Time::min_value()..recording::Time::max_value();
+        let no_split = recording::Duration(i64::max_value());
+        let mut min_id = 0;
+        let mut pages = Vec::new();
+        loop {
+            let mut page = Vec::new();
+            let next = l
+                .list_aggregated_recordings_page(
+                    stream_id,
+                    all_time.clone(),
+                    no_split,
+                    min_id,
+                    2,
+                    &mut |row| {
+                        page.push(row.ids.clone());
+                        Ok(())
+                    },
+                )
+                .unwrap();
+            pages.push(page);
+            match next {
+                None => break,
+                Some(n) => min_id = n,
+            }
+        }
+        assert_eq!(
+            pages,
+            vec![
+                vec![ids[0]..ids[2]],
+                vec![ids[2]..ids[4]],
+                vec![ids[4]..ids[4] + 1],
+            ]
+        );
+    }
+
+    #[test]
+    fn stream_usage() {
+        testutil::init();
+        let (db, _tmpdir, dir_ids) = testutil::new_db(clock::RealClocks {}, 1);
+        let camera_id = db
+            .lock()
+            .add_camera(testutil::test_camera(Some(dir_ids[0])))
+            .unwrap();
+        let mut l = db.lock();
+        let stream_id = l.cameras_by_id().get(&camera_id).unwrap().streams[0].unwrap();
+        let video_sample_entry_id = l
+            .insert_video_sample_entry(1920, 1080, vec![0u8; 100], "avc1.4d0029".to_owned())
+            .unwrap();
+
+        // Four one-second recordings, each followed by a one-second gap. Each takes up a whole
+        // 4 KiB block but only half of it is sample data.
+        for i in 0..4 {
+            let (id, _) = l
+                .add_recording(
+                    stream_id,
+                    RecordingToInsert {
+                        run_offset: i,
+                        start: recording::Time(i64::from(i) * 180_000),
+                        duration_90k: 90_000,
+                        video_samples: 1,
+                        video_sample_entry_id,
+                        sample_file_bytes: 2048,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            l.mark_synced(id).unwrap();
+        }
+        l.flush("stream_usage").unwrap();
+
+        // The first recording is half within the window.
+        let u = l
+            .stream_usage(stream_id, recording::Time(45_000)..recording::Time(720_000))
+            .unwrap();
+        assert_eq!(u.time, recording::Time(45_000)..recording::Time(720_000));
+        assert_eq!(u.recorded, recording::Duration(315_000));
+        assert_eq!(u.fs_bytes, 2 * (1024 + 3 * 2048));
+
+        // The window is limited to the oldest recording.
+        let u = l
+            .stream_usage(
+                stream_id,
+                recording::Time(-90_000)..recording::Time(720_000),
+            )
+            .unwrap();
+        assert_eq!(u.time.start, recording::Time(0));
+        assert_eq!(u.fs_bytes, 4 * 4096);
+    }
+
+    #[test]
+    fn striped_stream() {
+        testutil::init();
+        let (db, _tmpdir, dir_ids) = testutil::new_db(clock::RealClocks {}, 3);
+        let mut c = testutil::test_camera(Some(dir_ids[0]));
+        c.streams[0].stripe_dir_ids = vec![dir_ids[1], dir_ids[0]];
+
+        // Duplicate directories are rejected.
+        db.lock().add_camera(c.clone()).unwrap_err();
+
+        c.streams[0].stripe_dir_ids = vec![dir_ids[1], dir_ids[2]];
+        let camera_id = db.lock().add_camera(c.clone()).unwrap();
+        let l = db.lock();
+        let stream_id = l.cameras_by_id().get(&camera_id).unwrap().streams[0].unwrap();
+        let s = l.streams_by_id().get(&stream_id).unwrap();
+        assert_eq!(s.dir_ids(), dir_ids);
+        assert_eq!(s.dir_id_for(1, 0), Some(dir_ids[1]));
+        assert_eq!(s.dir_id_for(2, 0), Some(dir_ids[2]));
+        assert_eq!(s.dir_id_for(3, 0), Some(dir_ids[0]));
+    }
+
+    #[test]
+    fn virtual_stream() {
+        testutil::init();
+        let (db, _tmpdir, _) = testutil::new_db(clock::RealClocks {}, 0);
+        let mut c = testutil::test_camera(None);
+        c.short_name = "pano".to_owned();
+        let pano_id = db.lock().add_camera(c.clone()).unwrap();
+        let source_stream_id = db.lock().cameras_by_id().get(&pano_id).unwrap().streams[0].unwrap();
+        let v = VirtualSource {
+            source_stream_id,
+            crop_x: 1920,
+            crop_y: 0,
+            crop_width: 1920,
+            crop_height: 1080,
+            width: 1280,
+            height: 720,
+        };
+        c.short_name = "pano-right".to_owned();
+        c.streams[0] = StreamChange {
+            virtual_source: Some(VirtualSource { width: 1279, ..v }),
+            ..Default::default()
+        };
+        db.lock().add_camera(c.clone()).unwrap_err(); // odd width.
+        c.streams[0].virtual_source = Some(v);
+        let right_id = db.lock().add_camera(c).unwrap();
+
+        // The source can't be removed while the virtual stream uses it.
+        db.lock().delete_camera(pano_id).unwrap_err();
+
+        // Reloading preserves the definition.
+        let conn = db.close();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let mut l = db.lock();
+        let stream_id = l.cameras_by_id().get(&right_id).unwrap().streams[0].unwrap();
+        assert_eq!(
+            l.streams_by_id().get(&stream_id).unwrap().virtual_source,
+            Some(v)
+        );
+        l.delete_camera(right_id).unwrap();
+        l.delete_camera(pano_id).unwrap();
+    }
+
+    #[test]
+    fn notes_search() {
+        testutil::init();
+        let (db, _tmpdir, _) = testutil::new_db(clock::RealClocks {}, 0);
+        let mut c = testutil::test_camera(None);
+        c.short_name = "driveway".to_owned();
+        c.description = "looks at the street".to_owned();
+        let camera_id = db.lock().add_camera(c).unwrap();
+        let mut l = db.lock();
+        let note_id = l
+            .add_note(&search::NoteToInsert {
+                camera_id: Some(camera_id),
+                time: recording::Time(100)..recording::Time(200),
+                creation_time_sec: 0,
+                author: Some("slamb".to_owned()),
+                text: "A white van parked across the driveway".to_owned(),
+            })
+            .unwrap();
+        let all = recording::Time::min_value()..recording::Time::max_value();
+        let find = |l: &LockedDatabase, q: &str, time: Range<recording::Time>| {
+            let mut hits = Vec::new();
+            l.search(q, time, 10, &mut |r| {
+                hits.push(r.hit);
+                Ok(())
+            })
+            .unwrap();
+            hits
+        };
+        let note_hit = search::SearchHit::Note {
+            id: note_id,
+            camera_id: Some(camera_id),
+            time: recording::Time(100)..recording::Time(200),
+        };
+        assert_eq!(find(&l, "white van", all.clone()), vec![note_hit]);
+        assert_eq!(find(&l, "van white", all.clone()).len(), 1);
+        assert_eq!(find(&l, "white car", all.clone()), vec![]);
+        assert_eq!(
+            find(&l, "street", all.clone()),
+            vec![search::SearchHit::Camera { camera_id }]
+        );
+        assert_eq!(find(&l, "driveway", all.clone()).len(), 2);
+
+        // The time range filters notes but not cameras.
+        let later = recording::Time(200)..recording::Time(300);
+        assert_eq!(
+            find(&l, "driveway", later),
+            vec![search::SearchHit::Camera { camera_id }]
+        );
+
+        l.delete_note(note_id).unwrap();
+        l.delete_note(note_id).unwrap_err();
+        assert_eq!(find(&l, "white van", all.clone()), vec![]);
+    }
+
+    #[test]
+    fn export_jobs() {
+        testutil::init();
+        let (db, _tmpdir, _) = testutil::new_db(clock::RealClocks {}, 0);
+        let mut c = testutil::test_camera(None);
+        c.short_name = "driveway".to_owned();
+        let camera_id = db.lock().add_camera(c).unwrap();
+        let mut l = db.lock();
+        let stream_id = l.cameras_by_id().get(&camera_id).unwrap().streams[0].unwrap();
+        let job = |stream_ids| export::ExportJobToInsert {
+            creation_time_sec: 0,
+            author: Some("slamb".to_owned()),
+            time: recording::Time(100)..recording::Time(200),
+            stream_ids,
+            sign: false,
+            mask: false,
+        };
+        assert_eq!(
+            l.add_export_job(&job(vec![stream_id + 1]))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+        let j1 = l.add_export_job(&job(vec![stream_id])).unwrap();
+        let j2 = l.add_export_job(&job(vec![stream_id])).unwrap();
+
+        // Jobs run in order; one left running by a stopped server is queued again.
+        assert_eq!(l.start_export_job().unwrap().unwrap().id, j1);
+        assert_eq!(l.requeue_export_jobs().unwrap(), 1);
+        let j = l.start_export_job().unwrap().unwrap();
+        assert_eq!(j.id, j1);
+        assert_eq!(j.stream_ids, vec![stream_id]);
+        assert!(l.set_export_progress(j1, 10, 20).unwrap());
+        assert!(l.finish_export_job(j1, Ok("1.mp4"), 1).unwrap());
+        let j = l.get_export_job(j1).unwrap();
+        assert_eq!(j.state, export::ExportState::Done);
+        assert_eq!(j.file_name.as_deref(), Some("1.mp4"));
+
+        // A canceled job doesn't take further progress.
+        assert_eq!(l.start_export_job().unwrap().unwrap().id, j2);
+        assert_eq!(
+            l.cancel_export_job(j2, 2).unwrap(),
+            export::ExportState::Running
+        );
+        assert!(!l.set_export_progress(j2, 10, 20).unwrap());
+        assert!(!l.finish_export_job(j2, Ok("2.mp4"), 3).unwrap());
+        assert!(l.start_export_job().unwrap().is_none());
+
+        l.delete_export_job(j1).unwrap();
+        assert_eq!(
+            l.get_export_job(j1).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        let mut ids = Vec::new();
+        l.list_export_jobs(&mut |j| {
+            ids.push(j.id);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(ids, vec![j2]);
+    }
+
+    #[test]
+    fn streams_changed() {
+        testutil::init();
+        let (db, _tmpdir, dir_ids) = testutil::new_db(clock::RealClocks {}, 2);
+        let changes = Arc::new(Mutex::new(0));
+        let changes2 = changes.clone();
+        db.lock().watch_events(Box::new(move |_, e| {
+            if let Event::StreamsChanged = e {
+                *changes2.lock() += 1;
+            }
+            true
+        }));
+        let mut c = testutil::test_camera(Some(dir_ids[0]));
+        let camera_id = db.lock().add_camera(c.clone()).unwrap();
+        assert_eq!(*changes.lock(), 1);
+        let mut l = db.lock();
+        let stream_id = l.cameras_by_id().get(&camera_id).unwrap().streams[0].unwrap();
+        let video_sample_entry_id = l
+            .insert_video_sample_entry(1920, 1080, vec![0u8; 100], "avc1.4d0029".to_owned())
+            .unwrap();
+        l.add_recording(
+            stream_id,
+            RecordingToInsert {
+                video_sample_entry_id,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // While a recording is in progress, the stream's directory can't change and the camera
+        // can't be deleted, but other changes are fine.
+        c.streams[0].sample_file_dir_id = Some(dir_ids[1]);
+        l.update_camera(camera_id, c.clone()).unwrap_err();
+        l.delete_camera(camera_id).unwrap_err();
+        assert_eq!(*changes.lock(), 1);
+        c.streams[0].sample_file_dir_id = Some(dir_ids[0]);
+        c.streams[0].rtsp_url = "rtsp://test-camera/other".to_owned();
+        l.update_camera(camera_id, c).unwrap();
+        l.update_retention(&[RetentionChange {
+            stream_id,
+            new_record: false,
+            new_limit: 0,
+        }])
+        .unwrap();
+        assert_eq!(*changes.lock(), 3);
+    }
+
+    #[test]
+    fn delete_stream() {
+        testutil::init();
+        let (db, _tmpdir, dir_ids) = testutil::new_db(clock::RealClocks {}, 1);
+        let sample_file_dir_id = dir_ids[0];
+        let camera_id = db
+            .lock()
+            .add_camera(testutil::test_camera(Some(dir_ids[0])))
+            .unwrap();
+        let mut l = db.lock();
+        let stream_id = l.cameras_by_id().get(&camera_id).unwrap().streams[0].unwrap();
+        let video_sample_entry_id = l
+            .insert_video_sample_entry(1920, 1080, vec![0u8; 100], "avc1.4d0029".to_owned())
+            .unwrap();
+
+        // Two committed recordings, one synced but uncommitted, and one in progress.
+        let mut ids = Vec::new();
+        for i in 0..4 {
+            let (id, _) = l
+                .add_recording(
+                    stream_id,
+                    RecordingToInsert {
+                        run_offset: i,
+                        start: recording::Time(i64::from(i) * 90_000),
+                        duration_90k: 90_000,
+                        video_samples: 1,
+                        video_sample_entry_id,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            if i < 3 {
+                l.mark_synced(id).unwrap();
+            }
+            if i == 1 {
+                l.flush("delete_stream").unwrap();
+            }
+            ids.push(id);
+        }
+        assert_eq!(
+            l.delete_stream(stream_id).unwrap_err().kind(),
+            ErrorKind::Unavailable
+        );
+
+        l.mark_synced(ids[3]).unwrap();
+        l.delete_stream(stream_id).unwrap();
+        assert!(l.streams_by_id().get(&stream_id).is_none());
+        assert_eq!(l.cameras_by_id().get(&camera_id).unwrap().streams[0], None);
+        let mut garbage: Vec<_> = l.sample_file_dirs_by_id()[&sample_file_dir_id]
+            .garbage_needs_unlink
+            .iter()
+            .copied()
+            .collect();
+        garbage.sort_by_key(|id| id.0);
+        assert_eq!(garbage, ids);
+        assert_eq!(
+            l.delete_stream(stream_id).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+
+        // The next flush doesn't trip over the deleted stream.
+        l.flush("after delete_stream").unwrap();
+        l.delete_camera(camera_id).unwrap();
+    }
+
     #[test]
     fn round_up() {
         assert_eq!(super::round_up(0), 0);
@@ -2882,4 +5835,100 @@ mod tests {
         assert_eq!(super::round_up(8_192), 8_192);
         assert_eq!(super::round_up(8_193), 12_288);
     }
+
+    #[test]
+    fn input_options() {
+        assert!(super::parse_input_options("").unwrap().is_empty());
+        assert_eq!(
+            super::parse_input_options(" stimeout=5000000  rtsp_flags=prefer_tcp").unwrap(),
+            vec![("stimeout", "5000000"), ("rtsp_flags", "prefer_tcp")]
+        );
+        super::parse_input_options("allowed_media_types=video,audio").unwrap_err();
+        super::parse_input_options("probesize").unwrap_err();
+        super::parse_input_options("probesize=").unwrap_err();
+        super::parse_input_options("fflags=+genpts;rm").unwrap_err();
+        super::parse_input_options("probesize=32 probesize=64").unwrap_err();
+    }
+
+    #[test]
+    fn rtsp_policy() {
+        let p = super::RtspPolicy {
+            reconnect_min_sec: 2,
+            reconnect_max_sec: 30,
+            ..Default::default()
+        };
+        p.check().unwrap();
+        let delays: Vec<i64> = (1..=6).map(|f| p.reconnect_delay_sec(f)).collect();
+        assert_eq!(delays, vec![2, 4, 8, 16, 30, 30]);
+        assert_eq!(p.reconnect_delay_sec(1000), 30);
+        super::RtspPolicy {
+            reconnect_max_sec: 1,
+            ..p
+        }
+        .check()
+        .unwrap_err();
+        super::RtspPolicy {
+            session_timeout_sec: 0,
+            ..p
+        }
+        .check()
+        .unwrap_err();
+    }
+
+    #[test]
+    fn parse_preallocation() {
+        use super::Preallocation;
+        assert_eq!("off".parse::<Preallocation>().unwrap(), Preallocation::Off);
+        assert_eq!("0".parse::<Preallocation>().unwrap(), Preallocation::Off);
+        assert_eq!(
+            "previous".parse::<Preallocation>().unwrap(),
+            Preallocation::Previous
+        );
+        assert_eq!(
+            "64M".parse::<Preallocation>().unwrap(),
+            Preallocation::Fixed(64 << 20)
+        );
+        "-1".parse::<Preallocation>().unwrap_err();
+        "lots".parse::<Preallocation>().unwrap_err();
+    }
+
+    #[test]
+    fn video_index_cache_evicts_by_size() {
+        use super::VideoIndexCache;
+        let mut c = VideoIndexCache::new(10);
+        let id = |r| CompositeId::new(1, r);
+        c.insert(id(1), vec![1u8; 4].into_boxed_slice());
+        c.insert(id(2), vec![2u8; 4].into_boxed_slice());
+        assert_eq!(c.get(id(1)), Some(&[1u8; 4][..])); // now most recently used.
+        c.insert(id(3), vec![3u8; 4].into_boxed_slice());
+        assert_eq!(c.bytes, 8);
+        assert!(c.get(id(2)).is_none());
+        assert!(c.get(id(1)).is_some());
+
+        c.remove(id(1));
+        assert_eq!(c.bytes, 4);
+        c.insert(id(4), vec![4u8; 11].into_boxed_slice()); // too big to cache.
+        assert!(c.get(id(4)).is_none());
+        c.set_max_bytes(0);
+        assert!(c.get(id(3)).is_none());
+        assert_eq!(c.bytes, 0);
+    }
+
+    #[test]
+    fn video_index_compression() {
+        // Small indexes are stored as-is.
+        let (stored, flags) = raw::encode_video_index(&[1u8; 100]).unwrap();
+        assert_eq!(flags, 0);
+        assert_eq!(&stored[..], &[1u8; 100][..]);
+
+        // Large, repetitive ones (like a constant-frame-rate stream's) compress well.
+        let index: Vec<u8> = (0..100_000).map(|i| (i % 7) as u8).collect();
+        let (stored, flags) = raw::encode_video_index(&index).unwrap();
+        assert_ne!(flags, 0);
+        assert!(stored.len() < index.len() / 10);
+        assert_eq!(
+            raw::decode_video_index(stored.into_owned(), flags).unwrap(),
+            index
+        );
+    }
 }
@@ -0,0 +1,209 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Detections: labels attached to recordings by external analysis services.
+//!
+//! Unlike signals, detections aren't cached in RAM; they're written immediately and read back
+//! from the database on demand. See the `detection` table in `schema.sql`.
+
+use crate::db::CompositeId;
+use crate::recording;
+use base::{bail_t, ErrorKind, ResultExt};
+use failure::Error;
+use rusqlite::named_params;
+use std::ops::Range;
+
+const GET_RECORDING_BOUNDS_SQL: &'static str = r#"
+    select
+      start_time_90k,
+      duration_90k
+    from
+      recording
+    where
+      composite_id = :composite_id
+"#;
+
+const INSERT_DETECTION_SQL: &'static str = r#"
+    insert into detection (composite_id,  start_time_90k,  end_time_90k,  label,  confidence,
+                           source)
+                   values (:composite_id, :start_time_90k, :end_time_90k, :label, :confidence,
+                           :source)
+"#;
+
+const LIST_DETECTIONS_SQL: &'static str = r#"
+    select
+      id,
+      composite_id,
+      start_time_90k,
+      end_time_90k,
+      label,
+      confidence,
+      source
+    from
+      detection
+    where
+      composite_id >= :start_composite_id and
+      composite_id < :end_composite_id and
+      start_time_90k < :end_time_90k and
+      end_time_90k > :start_time_90k and
+      (:label is null or label = :label)
+    order by
+      start_time_90k
+"#;
+
+/// A detection to add via `LockedDatabase::add_detections`.
+#[derive(Clone, Debug)]
+pub struct DetectionToInsert {
+    /// The id of a committed recording within the stream.
+    pub recording_id: i32,
+
+    /// The span of the detection, which must be within the recording's bounds.
+    pub time: Range<recording::Time>,
+
+    pub label: String,
+
+    /// The confidence, in the range `[0, 1]`, if known.
+    pub confidence: Option<f64>,
+
+    pub source: Option<String>,
+}
+
+/// A row used in `LockedDatabase::list_detections`.
+#[derive(Debug)]
+pub struct ListDetectionsRow {
+    pub id: i64,
+    pub recording_id: CompositeId,
+    pub time: Range<recording::Time>,
+    pub label: String,
+    pub confidence: Option<f64>,
+    pub source: Option<String>,
+}
+
+/// Validates and inserts the given detections. Either all are inserted or none are.
+pub(crate) fn insert(
+    tx: &rusqlite::Transaction,
+    stream_id: i32,
+    detections: &[DetectionToInsert],
+) -> Result<(), base::Error> {
+    let mut bounds_stmt = tx
+        .prepare_cached(GET_RECORDING_BOUNDS_SQL)
+        .err_kind(ErrorKind::Internal)?;
+    let mut insert_stmt = tx
+        .prepare_cached(INSERT_DETECTION_SQL)
+        .err_kind(ErrorKind::Internal)?;
+    for d in detections {
+        let id = CompositeId::new(stream_id, d.recording_id);
+        if d.label.is_empty() {
+            bail_t!(
+                InvalidArgument,
+                "detection for recording {} has empty label",
+                id
+            );
+        }
+        if let Some(c) = d.confidence {
+            if !(c >= 0. && c <= 1.) {
+                bail_t!(
+                    InvalidArgument,
+                    "detection for recording {} has confidence {}, not in [0, 1]",
+                    id,
+                    c
+                );
+            }
+        }
+        if d.time.start >= d.time.end {
+            bail_t!(
+                InvalidArgument,
+                "detection for recording {} has empty time range {:?}",
+                id,
+                d.time
+            );
+        }
+        let mut rows = bounds_stmt
+            .query_named(named_params! {":composite_id": id.0})
+            .err_kind(ErrorKind::Internal)?;
+        let row = match rows.next().err_kind(ErrorKind::Internal)? {
+            None => bail_t!(NotFound, "no such committed recording {}", id),
+            Some(r) => r,
+        };
+        let start = recording::Time(row.get(0).err_kind(ErrorKind::Internal)?);
+        let duration = recording::Duration(row.get(1).err_kind(ErrorKind::Internal)?);
+        let rec = start..start + duration;
+        if d.time.start < rec.start || d.time.end > rec.end {
+            bail_t!(
+                InvalidArgument,
+                "detection time range {:?} isn't within recording {}'s {:?}",
+                d.time,
+                id,
+                rec
+            );
+        }
+        insert_stmt
+            .execute_named(named_params! {
+                ":composite_id": id.0,
+                ":start_time_90k": d.time.start.0,
+                ":end_time_90k": d.time.end.0,
+                ":label": d.label,
+                ":confidence": d.confidence,
+                ":source": d.source,
+            })
+            .err_kind(ErrorKind::Internal)?;
+    }
+    Ok(())
+}
+
+/// Lists all detections in the given stream overlapping the given time range, optionally
+/// restricted to the given label, in ascending order of start time.
+pub(crate) fn list(
+    conn: &rusqlite::Connection,
+    stream_id: i32,
+    time: Range<recording::Time>,
+    label: Option<&str>,
+    f: &mut dyn FnMut(ListDetectionsRow) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(LIST_DETECTIONS_SQL)?;
+    let mut rows = stmt.query_named(named_params! {
+        ":start_composite_id": CompositeId::new(stream_id, 0).0,
+        ":end_composite_id": CompositeId::new(stream_id + 1, 0).0,
+        ":start_time_90k": time.start.0,
+        ":end_time_90k": time.end.0,
+        ":label": label,
+    })?;
+    while let Some(row) = rows.next()? {
+        f(ListDetectionsRow {
+            id: row.get(0)?,
+            recording_id: CompositeId(row.get(1)?),
+            time: recording::Time(row.get(2)?)..recording::Time(row.get(3)?),
+            label: row.get(4)?,
+            confidence: row.get(5)?,
+            source: row.get(6)?,
+        })?;
+    }
+    Ok(())
+}
@@ -31,31 +31,129 @@
 //! Sample file directory management.
 //!
 //! This includes opening files for serving, rotating away old files, and saving new files.
+//!
+//! When the database has a `SampleFileKey`, new sample files are encrypted with AES-256-GCM. Each
+//! encrypted file starts with a header (magic, key id, and a random salt from which the file's
+//! key is derived) and ends with the GCM tag. The recording's `RecordingFlags::Encrypted` marks
+//! it as such; its `sample_file_bytes` and video index still describe the plaintext.
 
 use crate::coding;
-use crate::db::CompositeId;
+use crate::db::{self, CompositeId};
 use crate::schema;
+use base::crypto;
 use cstr::*;
-use failure::{bail, format_err, Error, Fail};
-use log::warn;
+use failure::{bail, format_err, Error, Fail, ResultExt};
+use fnv::FnvHashMap;
 use nix::sys::statvfs::Statvfs;
 use nix::{
-    fcntl::{FlockArg, OFlag},
+    fcntl::{AtFlags, FlockArg, OFlag},
     sys::stat::Mode,
     NixPath,
 };
+use parking_lot::Mutex;
 use protobuf::Message;
+use std::cmp;
 use std::ffi::CStr;
+use std::fmt;
 use std::fs;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use tracing::warn;
 
 /// The fixed length of a directory's `meta` file.
 ///
 /// See DirMeta comments within proto/schema.proto for more explanation.
 const FIXED_DIR_META_LEN: usize = 512;
 
+/// The first bytes of every encrypted sample file.
+const ENCRYPTED_MAGIC: &[u8; 8] = b"MNVRenc1";
+
+/// An encrypted sample file's header: the magic, the `SampleFileKey` id, and the file's salt.
+const ENCRYPTED_HEADER_LEN: usize = 32;
+
+/// The length of the GCM tag which ends an encrypted sample file.
+const ENCRYPTED_TAG_LEN: usize = 16;
+
+/// The number of bytes by which a (complete) encrypted sample file exceeds its contents.
+pub const ENCRYPTION_OVERHEAD: u64 = (ENCRYPTED_HEADER_LEN + ENCRYPTED_TAG_LEN) as u64;
+
+/// A master key for sample file encryption. This is deliberately kept outside the database, so
+/// that a copy of the database and sample file directories alone doesn't reveal the video.
+pub struct SampleFileKey {
+    key: [u8; 32],
+
+    /// Identifies the key in encrypted files' headers, so that using the wrong key produces a
+    /// clear error rather than garbage.
+    id: [u8; 8],
+}
+
+impl fmt::Debug for SampleFileKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SampleFileKey {{ id: {} }}",
+            base::strutil::hex(&self.id)
+        )
+    }
+}
+
+impl SampleFileKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        let mut id = [0u8; 8];
+        id.copy_from_slice(&crypto::hmac_sha256(&key, b"moonfire-nvr key id")[..8]);
+        SampleFileKey { key, id }
+    }
+
+    /// Reads a key from `path`, which should hold 64 hexadecimal digits, such as the output of
+    /// `openssl rand -hex 32`.
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let hexed = fs::read_to_string(path)
+            .with_context(|_| format!("unable to read sample file key {}", path.display()))?;
+        let hexed = hexed.trim();
+        let mut key = [0u8; 32];
+        if hexed.len() != 64 || !hexed.is_ascii() {
+            bail!("sample file key {} isn't 64 hex digits", path.display());
+        }
+        for (i, k) in key.iter_mut().enumerate() {
+            *k = u8::from_str_radix(&hexed[2 * i..2 * i + 2], 16)
+                .map_err(|_| format_err!("sample file key {} isn't hex", path.display()))?;
+        }
+        Ok(SampleFileKey::new(key))
+    }
+
+    /// Returns a new random header for an encrypted sample file.
+    fn new_header(&self) -> Result<[u8; ENCRYPTED_HEADER_LEN], Error> {
+        let mut h = [0u8; ENCRYPTED_HEADER_LEN];
+        h[..8].copy_from_slice(ENCRYPTED_MAGIC);
+        h[8..16].copy_from_slice(&self.id);
+        crypto::rand_bytes(&mut h[16..])?;
+        Ok(h)
+    }
+
+    /// Returns the cipher for the file with the given header.
+    fn cipher(&self, header: &[u8; ENCRYPTED_HEADER_LEN]) -> Result<crypto::Aes256Gcm, Error> {
+        if &header[..8] != ENCRYPTED_MAGIC {
+            bail!("not an encrypted sample file");
+        }
+        if header[8..16] != self.id {
+            bail!(
+                "sample file was encrypted with key {}, not the configured key {}",
+                base::strutil::hex(&header[8..16]),
+                base::strutil::hex(&self.id)
+            );
+        }
+
+        // Each file has its own key, derived from a random salt, so a fixed nonce is safe.
+        let file_key = crypto::hmac_sha256(&self.key, &header[16..]);
+        Ok(crypto::Aes256Gcm::new(&file_key, &[0u8; 12]))
+    }
+}
+
 /// A sample file directory. Typically one per physical disk drive.
 ///
 /// If the directory is used for writing, the `start_syncer` function should be called to start
@@ -67,6 +165,63 @@ pub struct SampleFileDir {
     /// The open file descriptor for the directory. The worker uses it to create files and sync the
     /// directory. Other threads use it to open sample files for reading during video serving.
     pub(crate) fd: Fd,
+
+    /// The key with which to encrypt new sample files and decrypt existing ones, if any.
+    key: Option<Arc<SampleFileKey>>,
+
+    /// Set once the filesystem has rejected `O_TMPFILE`; see `create_writer`.
+    tmpfile_unsupported: AtomicBool,
+
+    /// The file descriptors of writers' `O_TMPFILE`s which haven't been given names yet, so
+    /// `open_file` can read recordings in progress. Each writer removes its entry before closing
+    /// the file descriptor.
+    unnamed: Arc<Mutex<FnvHashMap<CompositeId, RawFd>>>,
+
+    /// Whether new sample files are written with `O_DIRECT`; see `set_direct_io`.
+    direct_io: AtomicBool,
+}
+
+/// The directories holding a stream's sample files, for serving.
+#[derive(Clone, Debug)]
+pub struct StreamDirs {
+    /// As in `db::Stream::dir_ids`.
+    pub stripes: Vec<Arc<SampleFileDir>>,
+
+    /// As in `db::Stream::failover_sample_file_dir_id`.
+    pub failover: Option<Arc<SampleFileDir>>,
+}
+
+impl StreamDirs {
+    /// Returns the directory holding the recording with the given id and flags.
+    pub fn get(&self, id: CompositeId, flags: i32) -> Option<&Arc<SampleFileDir>> {
+        if (flags & db::RecordingFlags::Failover as i32) != 0 {
+            return self.failover.as_ref();
+        }
+        Some(&self.stripes[db::stripe_index(id.recording(), self.stripes.len())])
+    }
+
+    /// Returns the directories of every stream which has any, by stream id.
+    /// The directories must already be open.
+    pub fn all(l: &db::LockedDatabase) -> Result<FnvHashMap<i32, StreamDirs>, Error> {
+        let mut d =
+            FnvHashMap::with_capacity_and_hasher(l.streams_by_id().len(), Default::default());
+        for (&id, s) in l.streams_by_id().iter() {
+            let dir_ids = s.dir_ids();
+            if dir_ids.is_empty() {
+                continue;
+            }
+            let mut stripes = Vec::with_capacity(dir_ids.len());
+            for dir_id in dir_ids {
+                stripes.push(l.sample_file_dirs_by_id().get(&dir_id).unwrap().get()?);
+            }
+            let failover = match s.failover_sample_file_dir_id {
+                None => None,
+                Some(dir_id) => Some(l.sample_file_dirs_by_id().get(&dir_id).unwrap().get()?),
+            };
+            d.insert(id, StreamDirs { stripes, failover });
+        }
+        Ok(d)
+    }
 }
 
 pub(crate) struct CompositeIdPath([u8; 17]);
@@ -212,26 +367,70 @@ pub(crate) fn write_meta(dirfd: RawFd, meta: &schema::DirMeta) -> Result<(), Err
     Ok(())
 }
 
+/// Checks that each of `sample_file_dirs` (with their paths, for error messages) is on a
+/// different filesystem than both the database directory and the root filesystem, failing fast
+/// rather than recording to the root filesystem when a disk isn't mounted or putting the
+/// database and recordings in the same failure domain. This is enabled by the
+/// `require_separate_mounts` config key.
+pub fn check_separate_mounts(
+    db_dir: &Fd,
+    sample_file_dirs: &[(&str, &SampleFileDir)],
+) -> Result<(), Error> {
+    let db_dev = nix::sys::stat::fstat(db_dir.0)?.st_dev;
+    let root_dev = nix::sys::stat::stat("/")?.st_dev;
+    for &(path, d) in sample_file_dirs {
+        let dev = nix::sys::stat::fstat(d.fd.0)?.st_dev;
+        if dev == root_dev {
+            bail!(
+                "sample file dir {} is on the root filesystem, but require_separate_mounts is \
+                 set. Is its disk mounted?",
+                path
+            );
+        }
+        if dev == db_dev {
+            bail!(
+                "sample file dir {} is on the same filesystem as the database, but \
+                 require_separate_mounts is set",
+                path
+            );
+        }
+    }
+    Ok(())
+}
+
 impl SampleFileDir {
     /// Opens the directory using the given metadata.
     ///
     /// `db_meta.in_progress_open` should be filled if the directory should be opened in read/write
     /// mode; absent in read-only mode.
-    pub fn open(path: &str, db_meta: &schema::DirMeta) -> Result<Arc<SampleFileDir>, Error> {
+    pub fn open(
+        path: &str,
+        db_meta: &schema::DirMeta,
+        key: Option<Arc<SampleFileKey>>,
+    ) -> Result<Arc<SampleFileDir>, Error> {
         let read_write = db_meta.in_progress_open.is_some();
-        let s = SampleFileDir::open_self(path, false)?;
+        let s = SampleFileDir::open_self(path, false, key)?;
         s.fd.lock(if read_write {
             FlockArg::LockExclusiveNonblock
         } else {
             FlockArg::LockSharedNonblock
         })?;
         let dir_meta = read_meta(&s.fd)?;
+        if dir_meta.dir_uuid.is_empty() && !db_meta.dir_uuid.is_empty() {
+            bail!(
+                "sample file dir {} has no meta file. If it's on a separate disk, is that disk \
+                 mounted?",
+                path
+            );
+        }
         if !SampleFileDir::consistent(db_meta, &dir_meta) {
             let serialized = db_meta
                 .write_length_delimited_to_bytes()
                 .expect("proto3->vec is infallible");
             bail!(
-                "metadata mismatch.\ndb: {:#?}\ndir: {:#?}\nserialized db: {:#?}",
+                "metadata mismatch. If the database was restored from a backup, see \
+                 `moonfire-nvr check --adopt-orphans`.\ndb: {:#?}\ndir: {:#?}\n\
+                 serialized db: {:#?}",
                 db_meta,
                 &dir_meta,
                 &serialized
@@ -271,7 +470,7 @@ impl SampleFileDir {
         path: &str,
         db_meta: &schema::DirMeta,
     ) -> Result<Arc<SampleFileDir>, Error> {
-        let s = SampleFileDir::open_self(path, true)?;
+        let s = SampleFileDir::open_self(path, true, None)?;
         s.fd.lock(FlockArg::LockExclusiveNonblock)?;
         let old_meta = read_meta(&s.fd)?;
 
@@ -314,15 +513,108 @@ impl SampleFileDir {
         Ok(true)
     }
 
-    fn open_self(path: &str, create: bool) -> Result<Arc<SampleFileDir>, Error> {
+    fn open_self(
+        path: &str,
+        create: bool,
+        key: Option<Arc<SampleFileKey>>,
+    ) -> Result<Arc<SampleFileDir>, Error> {
         let fd = Fd::open(path, create)
             .map_err(|e| format_err!("unable to open sample file dir {}: {}", path, e))?;
-        Ok(Arc::new(SampleFileDir { fd }))
+        Ok(Arc::new(SampleFileDir {
+            fd,
+            key,
+            tmpfile_unsupported: AtomicBool::new(false),
+            unnamed: Arc::new(Mutex::new(FnvHashMap::default())),
+            direct_io: AtomicBool::new(false),
+        }))
     }
 
-    /// Opens the given sample file for reading.
+    fn key(&self) -> Result<&SampleFileKey, Error> {
+        match self.key {
+            None => bail!("sample file is encrypted, but no sample file key is configured"),
+            Some(ref k) => Ok(k),
+        }
+    }
+
+    /// Opens the given sample file for sequential reading, decrypting it if `encrypted` (as
+    /// indicated by `RecordingFlags::Encrypted`). If so, the last `read` checks the file's
+    /// authentication tag, failing with `io::ErrorKind::InvalidData` if it's been altered.
+    pub fn open_reader(
+        &self,
+        composite_id: CompositeId,
+        encrypted: bool,
+    ) -> Result<SampleFileReader, Error> {
+        let mut file = self.open_file(composite_id)?;
+        if !encrypted {
+            return Ok(SampleFileReader { file, dec: None });
+        }
+        let mut header = [0u8; ENCRYPTED_HEADER_LEN];
+        file.read_exact(&mut header)?;
+        let cipher = self.key()?.cipher(&header)?;
+        let file_len = file.metadata()?.len();
+        if file_len < ENCRYPTION_OVERHEAD {
+            bail!("{}: encrypted sample file is truncated", composite_id);
+        }
+        Ok(SampleFileReader {
+            file,
+            dec: Some(Decryption {
+                auth: Some(cipher.authenticator()),
+                cipher,
+                pos: 0,
+                len: file_len - ENCRYPTION_OVERHEAD,
+            }),
+        })
+    }
+
+    /// Reads the given byte range of an encrypted sample file's contents, for serving.
+    ///
+    /// This doesn't check the authentication tag, which covers the whole file (and which a
+    /// recording still being written doesn't have yet). `moonfire-nvr check --scrub` does.
+    pub fn read_encrypted(
+        &self,
+        composite_id: CompositeId,
+        range: Range<u64>,
+    ) -> Result<Vec<u8>, Error> {
+        let file = self.open_file(composite_id)?;
+        let mut header = [0u8; ENCRYPTED_HEADER_LEN];
+        file.read_exact_at(&mut header, 0)?;
+        let cipher = self.key()?.cipher(&header)?;
+        let mut buf = vec![0u8; (range.end - range.start) as usize];
+        file.read_exact_at(&mut buf, ENCRYPTED_HEADER_LEN as u64 + range.start)?;
+        cipher.apply_keystream(range.start, &mut buf);
+        Ok(buf)
+    }
+
+    /// Returns true if the given sample file starts with the encrypted file magic.
+    pub(crate) fn is_encrypted(&self, composite_id: CompositeId) -> Result<bool, Error> {
+        let file = self.open_file(composite_id)?;
+        let mut magic = [0u8; 8];
+        match file.read_exact_at(&mut magic, 0) {
+            Ok(()) => Ok(&magic == ENCRYPTED_MAGIC),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Opens the given sample file for reading. This works for a file which is still being
+    /// written, even if it has no name yet (see `create_writer`).
     pub fn open_file(&self, composite_id: CompositeId) -> Result<fs::File, nix::Error> {
         let p = CompositeIdPath::from(composite_id);
+        match crate::fs::openat(self.fd.0, &p, OFlag::O_RDONLY, Mode::empty()) {
+            Err(nix::Error::Sys(nix::errno::Errno::ENOENT)) => {}
+            r => return r,
+        }
+        {
+            let unnamed = self.unnamed.lock();
+            if let Some(&fd) = unnamed.get(&composite_id) {
+                // Opening the writer's fd via /proc gives this reader its own file offset, as
+                // dup wouldn't. The writer can't close the fd while the lock is held.
+                let from = format!("/proc/self/fd/{}", fd);
+                return crate::fs::openat(self.fd.0, from.as_str(), OFlag::O_RDONLY, Mode::empty());
+            }
+        }
+
+        // The writer may have given the file its name between the first attempt and the lookup.
         crate::fs::openat(self.fd.0, &p, OFlag::O_RDONLY, Mode::empty())
     }
 
@@ -336,6 +628,130 @@ impl SampleFileDir {
         )
     }
 
+    /// Creates the given sample file as an unnamed `O_TMPFILE`, returning `None` if the
+    /// filesystem doesn't support that. Fails with `EEXIST` if the name is already taken, as
+    /// `create_file` would.
+    fn create_tmpfile(&self, composite_id: CompositeId) -> Result<Option<fs::File>, nix::Error> {
+        if self.tmpfile_unsupported.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let p = CompositeIdPath::from(composite_id);
+        match nix::sys::stat::fstatat(self.fd.0, &p, AtFlags::AT_SYMLINK_NOFOLLOW) {
+            Ok(_) => return Err(nix::Error::Sys(nix::errno::Errno::EEXIST)),
+            Err(nix::Error::Sys(nix::errno::Errno::ENOENT)) => {}
+            Err(e) => return Err(e),
+        }
+        match crate::fs::openat(
+            self.fd.0,
+            cstr!("."),
+            OFlag::O_TMPFILE | OFlag::O_WRONLY,
+            Mode::S_IRUSR | Mode::S_IWUSR,
+        ) {
+            Ok(f) => Ok(Some(f)),
+            Err(nix::Error::Sys(e))
+                if e == nix::errno::Errno::EOPNOTSUPP || e == nix::errno::Errno::EISDIR =>
+            {
+                // EISDIR means the kernel predates O_TMPFILE (3.11) and treated it as O_DIRECTORY.
+                if !self.tmpfile_unsupported.swap(true, Ordering::Relaxed) {
+                    warn!("sample file dir doesn't support O_TMPFILE; using named files");
+                }
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sets whether files created by `create_writer` from now on bypass the page cache. If the
+    /// filesystem doesn't support `O_DIRECT`, this is turned back off on the next attempt.
+    pub fn set_direct_io(&self, enable: bool) {
+        self.direct_io.store(enable, Ordering::Relaxed);
+    }
+
+    /// Creates a `Sink` for `file`, whose next write should be at `pos`.
+    fn sink(&self, file: fs::File, pos: u64) -> Sink {
+        if !self.direct_io.load(Ordering::Relaxed) {
+            return Sink::new(file, pos);
+        }
+        match crate::direct::File::new(file, pos) {
+            Ok(f) => Sink::Direct(f),
+            Err((file, e)) => {
+                if self.direct_io.swap(false, Ordering::Relaxed) {
+                    warn!(
+                        "sample file dir doesn't support O_DIRECT; using buffered writes: {}",
+                        e
+                    );
+                }
+                Sink::new(file, pos)
+            }
+        }
+    }
+
+    /// Creates the given sample file for writing, encrypting it if this directory has a key.
+    ///
+    /// Where the filesystem supports it, the file has no name until `SampleFileWriter::sync_all`
+    /// has synced its contents, so a crash mid-recording leaves nothing behind. Until then,
+    /// `open_file` reads it through the writer's file descriptor. Recordings which were
+    /// completely written but not yet committed to the database are still left behind and are
+    /// abandoned on the next startup, as with named files.
+    pub fn create_writer(&self, composite_id: CompositeId) -> Result<SampleFileWriter, nix::Error> {
+        let (mut file, link) = match self.create_tmpfile(composite_id)? {
+            Some(f) => {
+                let dir = Fd(nix::unistd::dup(self.fd.0)?);
+                self.unnamed.lock().insert(composite_id, f.as_raw_fd());
+                (
+                    f,
+                    Some(Link {
+                        dir,
+                        id: composite_id,
+                        unnamed: self.unnamed.clone(),
+                    }),
+                )
+            }
+            None => (self.create_file(composite_id)?, None),
+        };
+        let key = match self.key {
+            None => {
+                return Ok(SampleFileWriter {
+                    file: self.sink(file, 0),
+                    link,
+                    len: 0,
+                    preallocated: false,
+                    enc: None,
+                })
+            }
+            Some(ref k) => k,
+        };
+        let header = key.new_header().map_err(|e| {
+            warn!(
+                "{}: unable to create sample file header: {}",
+                composite_id, e
+            );
+            nix::Error::Sys(nix::errno::Errno::EIO)
+        })?;
+        if let Err(e) = file.write_all(&header) {
+            // Don't leave behind a file which would prevent a retry from succeeding.
+            let _ = self.unlink_file(composite_id);
+            return Err(nix::Error::Sys(nix::errno::Errno::from_i32(
+                e.raw_os_error().unwrap_or(libc::EIO),
+            )));
+        }
+        let cipher = key.cipher(&header).expect("new header should be valid");
+        Ok(SampleFileWriter {
+            file: self.sink(file, header.len() as u64),
+            link,
+            len: header.len() as u64,
+            preallocated: false,
+            enc: Some(Encryption {
+                auth: Some(cipher.authenticator()),
+                cipher,
+                pos: 0,
+                tag: [0u8; ENCRYPTED_TAG_LEN],
+                tag_written: 0,
+                buf: Vec::new(),
+            }),
+        })
+    }
+
     pub(crate) fn write_meta(&self, meta: &schema::DirMeta) -> Result<(), Error> {
         write_meta(self.fd.0, meta)
     }
@@ -356,10 +772,294 @@ impl SampleFileDir {
     }
 }
 
+/// A sample file being written; see `SampleFileDir::create_writer`.
+pub struct SampleFileWriter {
+    file: Sink,
+
+    /// For an `O_TMPFILE`, the name to give it once synced.
+    link: Option<Link>,
+
+    /// The number of bytes written to the file so far, including any encryption header.
+    len: u64,
+
+    /// True iff `preallocate` has extended the file, so `sync_all` must truncate it to `len`.
+    preallocated: bool,
+
+    enc: Option<Encryption>,
+}
+
+struct Link {
+    /// A duplicate of the directory's fd, so the writer doesn't borrow the `SampleFileDir`.
+    dir: Fd,
+    id: CompositeId,
+
+    /// The directory's `SampleFileDir::unnamed`, from which this file is removed on drop.
+    unnamed: Arc<Mutex<FnvHashMap<CompositeId, RawFd>>>,
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.unnamed.lock().remove(&self.id);
+    }
+}
+
+/// Where a `SampleFileWriter`'s bytes go.
+enum Sink {
+    File(fs::File),
+    Direct(crate::direct::File),
+    #[cfg(feature = "io-uring")]
+    Uring(crate::uring::File),
+}
+
+/// Set when io_uring setup fails, so later files don't retry (and warn) on each recording.
+#[cfg(feature = "io-uring")]
+static URING_UNAVAILABLE: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+impl Sink {
+    /// Creates a sink for `file`, whose next write should be at `pos`.
+    #[cfg(not(feature = "io-uring"))]
+    fn new(file: fs::File, _pos: u64) -> Self {
+        Sink::File(file)
+    }
+
+    /// Creates a sink for `file`, whose next write should be at `pos`.
+    #[cfg(feature = "io-uring")]
+    fn new(file: fs::File, pos: u64) -> Self {
+        use std::sync::atomic::Ordering;
+        if URING_UNAVAILABLE.load(Ordering::Relaxed) {
+            return Sink::File(file);
+        }
+        match crate::uring::File::new(file, pos) {
+            Ok(f) => Sink::Uring(f),
+            Err((file, e)) => {
+                if !URING_UNAVAILABLE.swap(true, Ordering::Relaxed) {
+                    warn!("io_uring is unavailable; using plain writes: {}", e);
+                }
+                Sink::File(file)
+            }
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        match self {
+            Sink::File(f) => f.write(buf),
+            Sink::Direct(f) => f.write(buf),
+            #[cfg(feature = "io-uring")]
+            Sink::Uring(f) => f.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        match self {
+            Sink::File(_) => Ok(()),
+            Sink::Direct(f) => f.flush(),
+            #[cfg(feature = "io-uring")]
+            Sink::Uring(f) => f.flush(),
+        }
+    }
+
+    fn sync_all(&mut self) -> Result<(), io::Error> {
+        match self {
+            Sink::File(f) => f.sync_all(),
+            Sink::Direct(f) => f.sync_all(),
+            #[cfg(feature = "io-uring")]
+            Sink::Uring(f) => f.sync_all(),
+        }
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Sink::File(f) => f.as_raw_fd(),
+            Sink::Direct(f) => f.as_raw_fd(),
+            #[cfg(feature = "io-uring")]
+            Sink::Uring(f) => f.as_raw_fd(),
+        }
+    }
+}
+
+struct Encryption {
+    cipher: crypto::Aes256Gcm,
+
+    /// The authenticator, until the tag has been computed.
+    auth: Option<crypto::Authenticator>,
+
+    /// The number of content bytes written so far.
+    pos: u64,
+
+    tag: [u8; ENCRYPTED_TAG_LEN],
+    tag_written: usize,
+
+    /// A buffer for ciphertext, kept to avoid an allocation per write.
+    buf: Vec<u8>,
+}
+
+impl SampleFileWriter {
+    /// Returns true iff the file is encrypted, so its recording should be marked with
+    /// `RecordingFlags::Encrypted`.
+    pub fn encrypted(&self) -> bool {
+        self.enc.is_some()
+    }
+
+    /// As in `std::io::Write::write`.
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        let e = match self.enc {
+            None => {
+                let n = self.file.write(buf)?;
+                self.len += n as u64;
+                return Ok(n);
+            }
+            Some(ref mut e) => e,
+        };
+        assert!(e.auth.is_some(), "write after sync_all");
+        e.buf.clear();
+        e.buf.extend_from_slice(buf);
+        e.cipher.apply_keystream(e.pos, &mut e.buf);
+        let n = self.file.write(&e.buf)?;
+        e.auth.as_mut().unwrap().update(&e.buf[..n]);
+        e.pos += n as u64;
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    /// Makes everything written so far readable through other file handles, as is necessary
+    /// before announcing a live segment. Readers find the file via `SampleFileDir::open_file`,
+    /// whether or not it has a name yet.
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        self.file.flush()
+    }
+
+    /// Allocates disk space for the next `len` bytes, so the filesystem can keep the file
+    /// contiguous. This extends the file with zeros; `sync_all` truncates it back to what was
+    /// actually written. Does nothing if the filesystem doesn't support `fallocate`.
+    pub fn preallocate(&mut self, len: u64) -> Result<(), io::Error> {
+        match nix::fcntl::fallocate(
+            self.file.as_raw_fd(),
+            nix::fcntl::FallocateFlags::empty(),
+            self.len as libc::off_t,
+            len as libc::off_t,
+        ) {
+            Ok(()) => {
+                self.preallocated = true;
+                Ok(())
+            }
+            Err(nix::Error::Sys(nix::errno::Errno::EOPNOTSUPP)) => Ok(()),
+            Err(nix::Error::Sys(e)) => Err(io::Error::from_raw_os_error(e as i32)),
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+        }
+    }
+
+    /// Completes the file (writing the authentication tag if encrypted) and syncs it to disk,
+    /// then gives it its name if it was created with `O_TMPFILE`.
+    /// This may be retried on failure but no further `write` calls are allowed.
+    pub fn sync_all(&mut self) -> Result<(), io::Error> {
+        if let Some(ref mut e) = self.enc {
+            if let Some(a) = e.auth.take() {
+                e.tag = a.finish();
+            }
+            while e.tag_written < ENCRYPTED_TAG_LEN {
+                let n = self.file.write(&e.tag[e.tag_written..])?;
+                e.tag_written += n;
+                self.len += n as u64;
+            }
+        }
+        if self.preallocated {
+            // Release the unused preallocation. ftruncate doesn't affect the file position, and
+            // nothing is written past `len`.
+            nix::unistd::ftruncate(self.file.as_raw_fd(), self.len as libc::off_t).map_err(
+                |e| match e {
+                    nix::Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+                    e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+                },
+            )?;
+            self.preallocated = false;
+        }
+        self.file.sync_all()?;
+        if let Some(ref l) = self.link {
+            // Linking via /proc rather than AT_EMPTY_PATH avoids needing CAP_DAC_READ_SEARCH.
+            // The caller syncs the directory to make the new name durable.
+            let from = format!("/proc/self/fd/{}", self.file.as_raw_fd());
+            let to = format!("{:016x}", l.id.0); // matches CompositeIdPath.
+            nix::unistd::linkat(
+                None,
+                from.as_str(),
+                Some(l.dir.0),
+                to.as_str(),
+                nix::unistd::LinkatFlags::SymlinkFollow,
+            )
+            .map_err(|e| match e {
+                nix::Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+                e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+            })?;
+            self.link = None;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SampleFileWriter {
+    fn drop(&mut self) {
+        // Remove an unnamed file from `SampleFileDir::unnamed` before its fd is closed.
+        self.link = None;
+    }
+}
+
+/// A sample file being read; see `SampleFileDir::open_reader`.
+pub struct SampleFileReader {
+    file: fs::File,
+    dec: Option<Decryption>,
+}
+
+struct Decryption {
+    cipher: crypto::Aes256Gcm,
+
+    /// The authenticator, until the tag has been checked.
+    auth: Option<crypto::Authenticator>,
+
+    /// The number of content bytes read so far, and the total.
+    pos: u64,
+    len: u64,
+}
+
+impl Read for SampleFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let d = match self.dec {
+            None => return self.file.read(buf),
+            Some(ref mut d) => d,
+        };
+        if d.pos == d.len {
+            if let Some(a) = d.auth.take() {
+                let mut tag = [0u8; ENCRYPTED_TAG_LEN];
+                self.file.read_exact(&mut tag)?;
+                if a.finish() != tag {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "encrypted sample file failed authentication",
+                    ));
+                }
+            }
+            return Ok(0);
+        }
+        let max = cmp::min(buf.len() as u64, d.len - d.pos) as usize;
+        let n = self.file.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "encrypted sample file is truncated",
+            ));
+        }
+        if let Some(ref mut a) = d.auth {
+            a.update(&buf[..n]);
+        }
+        d.cipher.apply_keystream(d.pos, &mut buf[..n]);
+        d.pos += n as u64;
+        Ok(n)
+    }
+}
+
 /// Parses a composite id filename.
 ///
 /// These are exactly 16 bytes, lowercase hex.
-pub(crate) fn parse_id(id: &[u8]) -> Result<CompositeId, ()> {
+pub fn parse_id(id: &[u8]) -> Result<CompositeId, ()> {
     if id.len() != 16 {
         return Err(());
     }
@@ -418,4 +1118,115 @@ mod tests {
             FIXED_DIR_META_LEN
         );
     }
+
+    #[test]
+    fn encryption() {
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let path = tmpdir.path().to_str().unwrap();
+        let key = Arc::new(SampleFileKey::new([1u8; 32]));
+        let dir = SampleFileDir::open_self(path, false, Some(key)).unwrap();
+        let id = CompositeId::new(1, 1);
+        let mut w = dir.create_writer(id).unwrap();
+        assert!(w.encrypted());
+        let contents: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        assert_eq!(w.write(&contents[..300]).unwrap(), 300);
+        assert_eq!(w.write(&contents[300..]).unwrap(), 700);
+        w.sync_all().unwrap();
+        drop(w);
+
+        let mut raw = Vec::new();
+        dir.open_file(id).unwrap().read_to_end(&mut raw).unwrap();
+        assert_eq!(raw.len() as u64, 1000 + ENCRYPTION_OVERHEAD);
+        assert!(!raw.windows(100).any(|w| w == &contents[..100]));
+
+        let mut plain = Vec::new();
+        dir.open_reader(id, true)
+            .unwrap()
+            .read_to_end(&mut plain)
+            .unwrap();
+        assert_eq!(plain, contents);
+        assert_eq!(
+            dir.read_encrypted(id, 123..456).unwrap(),
+            &contents[123..456]
+        );
+
+        // A different key should be detected.
+        let other =
+            SampleFileDir::open_self(path, false, Some(Arc::new(SampleFileKey::new([2u8; 32]))))
+                .unwrap();
+        other.open_reader(id, true).unwrap_err();
+
+        // So should tampering with the contents.
+        dir.unlink_file(id).unwrap();
+        raw[ENCRYPTED_HEADER_LEN + 500] ^= 1;
+        dir.create_file(id).unwrap().write_all(&raw).unwrap();
+        let e = dir
+            .open_reader(id, true)
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn named_on_sync() {
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let dir = SampleFileDir::open_self(tmpdir.path().to_str().unwrap(), false, None).unwrap();
+        let id = CompositeId::new(1, 1);
+        let mut w = dir.create_writer(id).unwrap();
+        assert_eq!(w.write(b"asdf").unwrap(), 4);
+
+        // If the filesystem supports O_TMPFILE, the file has no name until synced, but a reader
+        // of the growing recording still sees what's been written so far.
+        let tmpfile = w.link.is_some();
+        w.flush().unwrap();
+        let p = CompositeIdPath::from(id);
+        let named = || nix::sys::stat::fstatat(dir.fd.0, &p, AtFlags::AT_SYMLINK_NOFOLLOW).is_ok();
+        assert_eq!(named(), !tmpfile);
+        let mut contents = Vec::new();
+        dir.open_file(id)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(&contents[..], b"asdf");
+
+        assert_eq!(w.write(b"jkl").unwrap(), 3);
+        w.sync_all().unwrap();
+        assert!(named());
+        drop(w);
+        contents.clear();
+        dir.open_file(id)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(&contents[..], b"asdfjkl");
+
+        // A second writer for the same id should fail up front, as with a named file.
+        assert_eq!(
+            dir.create_writer(id).err(),
+            Some(nix::Error::Sys(nix::errno::Errno::EEXIST))
+        );
+
+        // A file dropped before it's synced is gone, as after a crash.
+        let id = CompositeId::new(1, 2);
+        let mut w = dir.create_writer(id).unwrap();
+        assert_eq!(w.write(b"asdf").unwrap(), 4);
+        w.flush().unwrap();
+        assert!(dir.open_file(id).is_ok());
+        drop(w);
+        assert_eq!(dir.open_file(id).is_ok(), !tmpfile);
+    }
+
+    #[test]
+    fn preallocate_then_truncate() {
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let dir = SampleFileDir::open_self(tmpdir.path().to_str().unwrap(), false, None).unwrap();
+        let id = CompositeId::new(1, 1);
+        let mut w = dir.create_writer(id).unwrap();
+        w.preallocate(1 << 20).unwrap();
+        assert_eq!(w.write(b"asdf").unwrap(), 4);
+        w.sync_all().unwrap();
+        drop(w);
+        assert_eq!(dir.open_file(id).unwrap().metadata().unwrap().len(), 4);
+    }
 }
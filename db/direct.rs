@@ -0,0 +1,219 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2016-2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! `O_DIRECT` sample file writing; see `dir::SampleFileDir::set_direct_io`.
+//!
+//! Recording many streams otherwise fills the page cache with video which is unlikely to be read
+//! back soon, evicting the pages of recent playback and the SQLite index. `File` instead
+//! collects writes in an aligned buffer and writes whole blocks around the page cache. Readers of
+//! a growing recording (for live view) need its bytes on disk, so `flush` also writes out the
+//! partial block at the end, padded with zeros, and keeps it to be rewritten as it fills. The file
+//! may thus temporarily be longer than what's been written; `sync_all` truncates it.
+
+use std::alloc::{self, Layout};
+use std::cmp;
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+/// The alignment `O_DIRECT` requires of buffers, offsets, and lengths. 4096 is a multiple of
+/// every common logical block size.
+const ALIGN: usize = 4096;
+
+/// The size of the buffer; each full buffer is written with one syscall.
+const BUF_LEN: usize = 1 << 20;
+
+/// A `BUF_LEN`-byte, `ALIGN`-aligned heap buffer.
+struct AlignedBuf(*mut u8);
+
+// The buffer is exclusively owned, like a `Box<[u8]>`.
+unsafe impl Send for AlignedBuf {}
+
+impl AlignedBuf {
+    fn layout() -> Layout {
+        Layout::from_size_align(BUF_LEN, ALIGN).unwrap()
+    }
+
+    fn new() -> Self {
+        let p = unsafe { alloc::alloc_zeroed(Self::layout()) };
+        if p.is_null() {
+            alloc::handle_alloc_error(Self::layout());
+        }
+        AlignedBuf(p)
+    }
+
+    fn get(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.0, BUF_LEN) }
+    }
+
+    fn get_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.0, BUF_LEN) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.0, Self::layout()) }
+    }
+}
+
+pub(crate) struct File {
+    file: fs::File,
+    buf: AlignedBuf,
+
+    /// The file offset of `buf[0]`, a multiple of `ALIGN`.
+    buf_pos: u64,
+
+    /// The number of valid bytes in `buf`.
+    buf_len: usize,
+}
+
+impl File {
+    /// Switches `file`, whose next write should be at `pos`, to `O_DIRECT`. On failure (such as
+    /// `EINVAL` from a filesystem which doesn't support it), returns the file unchanged.
+    pub(crate) fn new(file: fs::File, pos: u64) -> Result<Self, (fs::File, io::Error)> {
+        let mut buf = AlignedBuf::new();
+        let buf_pos = pos & !(ALIGN as u64 - 1);
+        let buf_len = (pos - buf_pos) as usize;
+
+        // Read back anything (such as an encryption header) written before the first block
+        // boundary, while reads can still be unaligned. It'll be rewritten with the first block.
+        if let Err(e) = file.read_exact_at(&mut buf.get_mut()[..buf_len], buf_pos) {
+            return Err((file, e));
+        }
+        let fd = file.as_raw_fd();
+        let r = unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags < 0 {
+                flags
+            } else {
+                libc::fcntl(fd, libc::F_SETFL, flags | libc::O_DIRECT)
+            }
+        };
+        if r < 0 {
+            return Err((file, io::Error::last_os_error()));
+        }
+        Ok(File {
+            file,
+            buf,
+            buf_pos,
+            buf_len,
+        })
+    }
+
+    pub(crate) fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.file.as_raw_fd()
+    }
+
+    /// As in `std::io::Write::write`.
+    pub(crate) fn write(&mut self, data: &[u8]) -> Result<usize, io::Error> {
+        let n = cmp::min(data.len(), BUF_LEN - self.buf_len);
+        self.buf.get_mut()[self.buf_len..self.buf_len + n].copy_from_slice(&data[..n]);
+        self.buf_len += n;
+        if self.buf_len == BUF_LEN {
+            self.file.write_all_at(self.buf.get(), self.buf_pos)?;
+            self.buf_pos += BUF_LEN as u64;
+            self.buf_len = 0;
+        }
+        Ok(n)
+    }
+
+    /// Writes out everything written so far, so other file handles can read it.
+    pub(crate) fn flush(&mut self) -> Result<(), io::Error> {
+        if self.buf_len == 0 {
+            return Ok(());
+        }
+        let full = self.buf_len & !(ALIGN - 1);
+        let padded = (self.buf_len + ALIGN - 1) & !(ALIGN - 1);
+        let b = self.buf.get_mut();
+        for byte in &mut b[self.buf_len..padded] {
+            *byte = 0;
+        }
+        self.file.write_all_at(&b[..padded], self.buf_pos)?;
+
+        // Keep the partial block at the start of the buffer.
+        b.copy_within(full..self.buf_len, 0);
+        self.buf_pos += full as u64;
+        self.buf_len -= full;
+        Ok(())
+    }
+
+    /// As in `std::fs::File::sync_all`. This may be retried on failure.
+    pub(crate) fn sync_all(&mut self) -> Result<(), io::Error> {
+        self.flush()?;
+        self.file.set_len(self.buf_pos + self.buf_len as u64)?;
+        self.file.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use tracing::warn;
+
+    #[test]
+    fn write_flush_and_sync() {
+        testutil::init();
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let path = tmpdir.path().join("f");
+        let f = fs::File::create(&path).unwrap();
+
+        // Start with an unaligned prefix, as with an encryption header.
+        f.write_all_at(b"header", 0).unwrap();
+        let mut f = match File::new(f, 6) {
+            Ok(f) => f,
+            Err((_, e)) => {
+                warn!("skipping test; O_DIRECT is unavailable: {}", e);
+                return;
+            }
+        };
+        let data: Vec<u8> = b"header"
+            .iter()
+            .cloned()
+            .chain((0..BUF_LEN * 2 + 12_345).map(|i| i as u8))
+            .collect();
+        let mut written = 6;
+        while written < data.len() {
+            let end = cmp::min(written + 100_003, data.len());
+            written += f.write(&data[written..end]).unwrap();
+            if written > BUF_LEN && written - 100_003 <= BUF_LEN {
+                // A reader should see everything so far after a flush.
+                f.flush().unwrap();
+                let contents = fs::read(&path).unwrap();
+                assert!(contents[..written] == data[..written]);
+            }
+        }
+        f.sync_all().unwrap();
+        drop(f);
+        assert!(fs::read(&path).unwrap() == data);
+    }
+}
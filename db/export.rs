@@ -0,0 +1,383 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Export jobs: clips of one or more streams over a time range, built in the background.
+//!
+//! The database only tracks each job's request and progress; the server's exporter claims
+//! queued jobs with `LockedDatabase::start_export_job`, builds the file, and reports progress
+//! and completion here. Jobs which were running when the server stopped are queued again on
+//! the next start. Like bookmarks, export jobs aren't cached in RAM.
+
+use crate::recording;
+use base::{bail_t, format_err_t, ErrorKind, ResultExt};
+use rusqlite::{named_params, params};
+use std::ops::Range;
+
+const INSERT_SQL: &str = r#"
+    insert into export_job (creation_time_sec,  author,  start_time_90k,  end_time_90k,
+                            stream_ids,  sign,  mask,  state)
+                    values (:creation_time_sec, :author, :start_time_90k, :end_time_90k,
+                            :stream_ids, :sign, :mask, 0)
+"#;
+
+const LIST_SQL: &str = r#"
+    select
+      id,
+      creation_time_sec,
+      author,
+      start_time_90k,
+      end_time_90k,
+      stream_ids,
+      state,
+      progress_bytes,
+      total_bytes,
+      file_name,
+      error,
+      finish_time_sec,
+      sign,
+      mask
+    from
+      export_job
+    where
+      (:id is null or id = :id) and
+      (:state is null or state = :state)
+    order by
+      id
+"#;
+
+/// The state of an export job, as stored in `export_job.state`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExportState {
+    Queued = 0,
+    Running = 1,
+    Done = 2,
+    Failed = 3,
+    Canceled = 4,
+}
+
+impl ExportState {
+    fn from_i32(s: i32) -> Option<Self> {
+        Some(match s {
+            0 => ExportState::Queued,
+            1 => ExportState::Running,
+            2 => ExportState::Done,
+            3 => ExportState::Failed,
+            4 => ExportState::Canceled,
+            _ => return None,
+        })
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ExportState::Queued => "queued",
+            ExportState::Running => "running",
+            ExportState::Done => "done",
+            ExportState::Failed => "failed",
+            ExportState::Canceled => "canceled",
+        }
+    }
+
+    /// Returns true if the job will make no further progress.
+    pub fn is_finished(self) -> bool {
+        match self {
+            ExportState::Queued | ExportState::Running => false,
+            _ => true,
+        }
+    }
+}
+
+/// An export job to add via `LockedDatabase::add_export_job`.
+#[derive(Clone, Debug)]
+pub struct ExportJobToInsert {
+    /// The time at which this export was requested, in seconds since epoch.
+    pub creation_time_sec: i64,
+
+    /// The name of the user who requested this export, if known.
+    pub author: Option<String>,
+
+    /// The span of time to export. Must be non-empty.
+    pub time: Range<recording::Time>,
+
+    /// The streams to export, in the order their files should appear. Must be non-empty.
+    pub stream_ids: Vec<i32>,
+
+    /// Whether to sign the export's manifest.
+    pub sign: bool,
+
+    /// Whether to transcode streams with privacy masks to black them out.
+    pub mask: bool,
+}
+
+/// An export job, as returned by `LockedDatabase::list_export_jobs`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExportJob {
+    pub id: i64,
+    pub creation_time_sec: i64,
+    pub author: Option<String>,
+    pub time: Range<recording::Time>,
+    pub stream_ids: Vec<i32>,
+    pub sign: bool,
+    pub mask: bool,
+    pub state: ExportState,
+    pub progress_bytes: i64,
+    pub total_bytes: Option<i64>,
+    pub file_name: Option<String>,
+    pub error: Option<String>,
+    pub finish_time_sec: Option<i64>,
+}
+
+/// Validates and inserts the given export job, returning its id.
+pub(crate) fn insert(
+    conn: &rusqlite::Connection,
+    j: &ExportJobToInsert,
+) -> Result<i64, base::Error> {
+    if j.stream_ids.is_empty() {
+        bail_t!(InvalidArgument, "export has no streams");
+    }
+    if j.time.start >= j.time.end {
+        bail_t!(InvalidArgument, "export has empty time range {:?}", j.time);
+    }
+    let stream_ids: Vec<String> = j.stream_ids.iter().map(|id| id.to_string()).collect();
+    let mut stmt = conn
+        .prepare_cached(INSERT_SQL)
+        .err_kind(ErrorKind::Internal)?;
+    stmt.execute_named(named_params! {
+        ":creation_time_sec": j.creation_time_sec,
+        ":author": j.author,
+        ":start_time_90k": j.time.start.0,
+        ":end_time_90k": j.time.end.0,
+        ":stream_ids": stream_ids.join(","),
+        ":sign": j.sign,
+        ":mask": j.mask,
+    })
+    .err_kind(ErrorKind::Internal)?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Returns the given export job.
+pub(crate) fn get(conn: &rusqlite::Connection, id: i64) -> Result<ExportJob, base::Error> {
+    let mut j = None;
+    list_inner(conn, Some(id), None, &mut |row| {
+        j = Some(row);
+        Ok(())
+    })?;
+    match j {
+        None => bail_t!(NotFound, "no such export {}", id),
+        Some(j) => Ok(j),
+    }
+}
+
+/// Lists all export jobs, in order of id.
+pub(crate) fn list(
+    conn: &rusqlite::Connection,
+    f: &mut dyn FnMut(ExportJob) -> Result<(), base::Error>,
+) -> Result<(), base::Error> {
+    list_inner(conn, None, None, f)
+}
+
+/// Marks the oldest queued export job as running and returns it, if there is one.
+pub(crate) fn start_next(conn: &rusqlite::Connection) -> Result<Option<ExportJob>, base::Error> {
+    let mut j = None;
+    list_inner(conn, None, Some(ExportState::Queued), &mut |row| {
+        if j.is_none() {
+            j = Some(row);
+        }
+        Ok(())
+    })?;
+    let mut j = match j {
+        None => return Ok(None),
+        Some(j) => j,
+    };
+    conn.execute(
+        "update export_job set state = 1, progress_bytes = 0, total_bytes = null where id = ?",
+        params![j.id],
+    )
+    .err_kind(ErrorKind::Internal)?;
+    j.state = ExportState::Running;
+    j.progress_bytes = 0;
+    j.total_bytes = None;
+    Ok(Some(j))
+}
+
+/// Records a running job's progress. Returns false if it's no longer running (as when canceled).
+pub(crate) fn set_progress(
+    conn: &rusqlite::Connection,
+    id: i64,
+    progress_bytes: i64,
+    total_bytes: i64,
+) -> Result<bool, base::Error> {
+    let mut stmt = conn
+        .prepare_cached(
+            r#"
+            update export_job
+            set
+              progress_bytes = :progress_bytes,
+              total_bytes = :total_bytes
+            where
+              id = :id and
+              state = 1
+            "#,
+        )
+        .err_kind(ErrorKind::Internal)?;
+    let n = stmt
+        .execute_named(named_params! {
+            ":id": id,
+            ":progress_bytes": progress_bytes,
+            ":total_bytes": total_bytes,
+        })
+        .err_kind(ErrorKind::Internal)?;
+    Ok(n == 1)
+}
+
+/// Finishes a running job with the given outcome: `Done` with a file name or `Failed` with an
+/// error. Returns false if it's no longer running (as when canceled).
+pub(crate) fn finish(
+    conn: &rusqlite::Connection,
+    id: i64,
+    result: Result<&str, &str>,
+    finish_time_sec: i64,
+) -> Result<bool, base::Error> {
+    let (state, file_name, error) = match result {
+        Ok(f) => (ExportState::Done, Some(f), None),
+        Err(e) => (ExportState::Failed, None, Some(e)),
+    };
+    let mut stmt = conn
+        .prepare_cached(
+            r#"
+            update export_job
+            set
+              state = :state,
+              file_name = :file_name,
+              error = :error,
+              finish_time_sec = :finish_time_sec
+            where
+              id = :id and
+              state = 1
+            "#,
+        )
+        .err_kind(ErrorKind::Internal)?;
+    let n = stmt
+        .execute_named(named_params! {
+            ":id": id,
+            ":state": state as i32,
+            ":file_name": file_name,
+            ":error": error,
+            ":finish_time_sec": finish_time_sec,
+        })
+        .err_kind(ErrorKind::Internal)?;
+    Ok(n == 1)
+}
+
+/// Cancels the given job if it's queued or running. Returns its state beforehand.
+pub(crate) fn cancel(
+    conn: &rusqlite::Connection,
+    id: i64,
+    finish_time_sec: i64,
+) -> Result<ExportState, base::Error> {
+    let j = get(conn, id)?;
+    if !j.state.is_finished() {
+        conn.execute(
+            "update export_job set state = 4, finish_time_sec = ? where id = ?",
+            params![finish_time_sec, id],
+        )
+        .err_kind(ErrorKind::Internal)?;
+    }
+    Ok(j.state)
+}
+
+/// Deletes the given job, returning it.
+pub(crate) fn delete(conn: &rusqlite::Connection, id: i64) -> Result<ExportJob, base::Error> {
+    let j = get(conn, id)?;
+    conn.execute("delete from export_job where id = ?", params![id])
+        .err_kind(ErrorKind::Internal)?;
+    Ok(j)
+}
+
+/// Queues again all jobs which were running, as when the server was stopped partway.
+pub(crate) fn requeue_running(conn: &rusqlite::Connection) -> Result<usize, base::Error> {
+    conn.execute(
+        "update export_job set state = 0, progress_bytes = 0, total_bytes = null where state = 1",
+        params![],
+    )
+    .err_kind(ErrorKind::Internal)
+}
+
+fn list_inner(
+    conn: &rusqlite::Connection,
+    id: Option<i64>,
+    state: Option<ExportState>,
+    f: &mut dyn FnMut(ExportJob) -> Result<(), base::Error>,
+) -> Result<(), base::Error> {
+    let mut stmt = conn
+        .prepare_cached(LIST_SQL)
+        .err_kind(ErrorKind::Internal)?;
+    let mut rows = stmt
+        .query_named(named_params! {
+            ":id": id,
+            ":state": state.map(|s| s as i32),
+        })
+        .err_kind(ErrorKind::Internal)?;
+    while let Some(row) = rows.next().err_kind(ErrorKind::Internal)? {
+        let id: i64 = row.get(0).err_kind(ErrorKind::Internal)?;
+        let stream_ids: String = row.get(5).err_kind(ErrorKind::Internal)?;
+        let stream_ids = stream_ids
+            .split(',')
+            .map(|s| s.parse())
+            .collect::<Result<Vec<i32>, _>>()
+            .map_err(|_| {
+                format_err_t!(
+                    Internal,
+                    "export {} has bad stream_ids {:?}",
+                    id,
+                    stream_ids
+                )
+            })?;
+        let state: i32 = row.get(6).err_kind(ErrorKind::Internal)?;
+        let state = ExportState::from_i32(state)
+            .ok_or_else(|| format_err_t!(Internal, "export {} has bad state {}", id, state))?;
+        f(ExportJob {
+            id,
+            creation_time_sec: row.get(1).err_kind(ErrorKind::Internal)?,
+            author: row.get(2).err_kind(ErrorKind::Internal)?,
+            time: recording::Time(row.get(3).err_kind(ErrorKind::Internal)?)
+                ..recording::Time(row.get(4).err_kind(ErrorKind::Internal)?),
+            stream_ids,
+            sign: row.get(12).err_kind(ErrorKind::Internal)?,
+            mask: row.get(13).err_kind(ErrorKind::Internal)?,
+            state,
+            progress_bytes: row.get(7).err_kind(ErrorKind::Internal)?,
+            total_bytes: row.get(8).err_kind(ErrorKind::Internal)?,
+            file_name: row.get(9).err_kind(ErrorKind::Internal)?,
+            error: row.get(10).err_kind(ErrorKind::Internal)?,
+            finish_time_sec: row.get(11).err_kind(ErrorKind::Internal)?,
+        })?;
+    }
+    Ok(())
+}
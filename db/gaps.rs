@@ -0,0 +1,287 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Reports of the intervals in which a stream has no recordings, for compliance reporting.
+//!
+//! The reason for each gap is inferred from the recordings on either side of it: whether the
+//! later one was made by a later run of the server, was written to the failover directory, or
+//! started a new run after the earlier one's stream ended.
+
+use crate::db::{ListRecordingsRow, RecordingFlags};
+use crate::recording;
+use failure::Error;
+use rusqlite::{named_params, Connection};
+use std::cmp;
+use std::ops::Range;
+
+const PRECEDING_SQL: &'static str = r#"
+    select
+      start_time_90k,
+      duration_90k,
+      run_offset,
+      open_id,
+      flags
+    from
+      recording
+    where
+      stream_id = :stream_id and
+      start_time_90k < :start_time_90k
+    order by
+      start_time_90k desc
+    limit 1
+"#;
+
+/// Why a stream has a gap in its recordings.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Reason {
+    /// The gap precedes the stream's oldest recording: the stream wasn't being recorded yet, or
+    /// its recordings have since been deleted to make room for new ones.
+    BeforeOldest,
+
+    /// Moonfire NVR wasn't running.
+    ServerDown,
+
+    /// The stream disconnected while Moonfire NVR was running, as when the camera goes offline
+    /// or the network fails.
+    Disconnected,
+
+    /// The stream's sample file directory failed; recording resumed in its failover directory.
+    DiskError,
+
+    Unknown,
+}
+
+impl Reason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Reason::BeforeOldest => "beforeOldest",
+            Reason::ServerDown => "serverDown",
+            Reason::Disconnected => "disconnected",
+            Reason::DiskError => "diskError",
+            Reason::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Gap {
+    pub time: Range<recording::Time>,
+    pub reason: Reason,
+}
+
+/// The parts of a recording needed to find gaps.
+#[derive(Clone, Debug)]
+pub(crate) struct Recording {
+    pub(crate) time: Range<recording::Time>,
+    run_offset: i32,
+    open_id: u32,
+    flags: i32,
+}
+
+impl From<&ListRecordingsRow> for Recording {
+    fn from(r: &ListRecordingsRow) -> Self {
+        Recording {
+            time: r.start..r.start + recording::Duration(i64::from(r.duration_90k)),
+            run_offset: r.run_offset,
+            open_id: r.open_id,
+            flags: r.flags,
+        }
+    }
+}
+
+/// Returns the last committed recording of the stream starting before `t`, if any.
+pub(crate) fn preceding(
+    conn: &Connection,
+    stream_id: i32,
+    t: recording::Time,
+) -> Result<Option<Recording>, Error> {
+    let mut stmt = conn.prepare_cached(PRECEDING_SQL)?;
+    let mut rows = stmt.query_named(named_params! {
+        ":stream_id": stream_id,
+        ":start_time_90k": t.0,
+    })?;
+    let row = match rows.next()? {
+        None => return Ok(None),
+        Some(r) => r,
+    };
+    let start = recording::Time(row.get(0)?);
+    Ok(Some(Recording {
+        time: start..start + recording::Duration(row.get(1)?),
+        run_offset: row.get(2)?,
+        open_id: row.get(3)?,
+        flags: row.get(4)?,
+    }))
+}
+
+/// Finds the gaps of at least `min_gap` within `range`.
+///
+/// `recordings` are those overlapping `range`, in ascending order by start time. `preceding` is
+/// the last recording starting before `range`, `oldest` is the start of the stream's oldest
+/// recording, and `open_id` is the current open if the server is running.
+pub(crate) fn find(
+    range: Range<recording::Time>,
+    min_gap: recording::Duration,
+    recordings: &[Recording],
+    preceding: Option<&Recording>,
+    oldest: Option<recording::Time>,
+    open_id: Option<u32>,
+) -> Vec<Gap> {
+    let mut out = Vec::new();
+    let mut cursor = range.start;
+    let mut prev = preceding;
+    for r in recordings {
+        if r.time.start > cursor && r.time.start - cursor >= min_gap {
+            out.push(Gap {
+                time: cursor..r.time.start,
+                reason: reason(prev, Some(r), r.time.start, oldest, open_id),
+            });
+        }
+        cursor = cmp::max(cursor, r.time.end);
+        prev = Some(r);
+    }
+    if cursor < range.end && range.end - cursor >= min_gap {
+        out.push(Gap {
+            time: cursor..range.end,
+            reason: reason(prev, None, range.end, oldest, open_id),
+        });
+    }
+    out
+}
+
+/// Infers the reason for a gap ending at `end` between `prev` and `next`.
+fn reason(
+    prev: Option<&Recording>,
+    next: Option<&Recording>,
+    end: recording::Time,
+    oldest: Option<recording::Time>,
+    open_id: Option<u32>,
+) -> Reason {
+    if oldest.map(|o| end <= o).unwrap_or(true) {
+        return Reason::BeforeOldest;
+    }
+    let failover = |r: &Recording| r.flags & (RecordingFlags::Failover as i32) != 0;
+    if let Some(n) = next {
+        if failover(n) && !prev.map(failover).unwrap_or(false) {
+            return Reason::DiskError;
+        }
+    }
+    let prev = match prev {
+        None => return Reason::Unknown,
+        Some(p) => p,
+    };
+    if next.map(|n| n.open_id).or(open_id) != Some(prev.open_id) {
+        return Reason::ServerDown;
+    }
+    let run_ended = prev.flags & (RecordingFlags::TrailingZero as i32) != 0;
+    if run_ended && next.map(|n| n.run_offset == 0).unwrap_or(true) {
+        return Reason::Disconnected;
+    }
+    Reason::Unknown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::{Duration, Time};
+
+    fn rec(start: i64, end: i64, run_offset: i32, open_id: u32, flags: i32) -> Recording {
+        Recording {
+            time: Time(start)..Time(end),
+            run_offset,
+            open_id,
+            flags,
+        }
+    }
+
+    #[test]
+    fn find_gaps() {
+        const TRAILING_ZERO: i32 = RecordingFlags::TrailingZero as i32;
+        const FAILOVER: i32 = RecordingFlags::Failover as i32;
+        let recordings = [
+            rec(100, 200, 0, 1, 0),
+            rec(200, 300, 1, 1, TRAILING_ZERO),
+            rec(400, 500, 0, 1, 0),          // after a disconnect.
+            rec(500, 600, 1, 1, 0),          // the server stopped without a trailing zero.
+            rec(700, 800, 0, 2, 0),          // after a restart.
+            rec(900, 1000, 0, 2, FAILOVER),  // after a disk error.
+            rec(1001, 1100, 1, 2, FAILOVER), // too short a gap to report.
+        ];
+        let gaps = find(
+            Time(0)..Time(1200),
+            Duration(10),
+            &recordings,
+            None,
+            Some(Time(100)),
+            Some(2),
+        );
+        assert_eq!(
+            gaps,
+            vec![
+                Gap {
+                    time: Time(0)..Time(100),
+                    reason: Reason::BeforeOldest
+                },
+                Gap {
+                    time: Time(300)..Time(400),
+                    reason: Reason::Disconnected
+                },
+                Gap {
+                    time: Time(600)..Time(700),
+                    reason: Reason::ServerDown
+                },
+                Gap {
+                    time: Time(800)..Time(900),
+                    reason: Reason::DiskError
+                },
+                Gap {
+                    time: Time(1100)..Time(1200),
+                    reason: Reason::Unknown
+                },
+            ]
+        );
+
+        // With the server stopped, a trailing gap is due to the server being down.
+        let gaps = find(
+            Time(1100)..Time(1200),
+            Duration(10),
+            &[],
+            Some(&recordings[6]),
+            Some(Time(100)),
+            None,
+        );
+        assert_eq!(
+            gaps,
+            vec![Gap {
+                time: Time(1100)..Time(1200),
+                reason: Reason::ServerDown
+            }]
+        );
+    }
+}
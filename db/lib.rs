@@ -31,17 +31,32 @@
 #![cfg_attr(all(feature = "nightly", test), feature(test))]
 
 pub mod auth;
+pub mod bookmark;
 pub mod check;
 mod coding;
 mod compare;
 pub mod db;
+pub mod detection;
+mod direct;
 pub mod dir;
+pub mod export;
 mod fs;
+pub mod gaps;
+pub mod maintenance;
+pub mod mask;
+pub mod notify;
+pub mod playback;
 mod raw;
 pub mod recording;
+pub mod retention;
 mod schema;
+pub mod scrub;
+pub mod search;
 pub mod signal;
+pub mod timeline;
 pub mod upgrade;
+#[cfg(feature = "io-uring")]
+mod uring;
 pub mod writer;
 
 // This is only for #[cfg(test)], but it's also used by the dependent crate, and it appears that
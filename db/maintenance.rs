@@ -0,0 +1,87 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Periodic database maintenance, done in the background by `moonfire-nvr run`.
+//!
+//! Currently this disables guest users whose `user.expiration_time_sec` has passed and revokes
+//! their sessions. Authentication already fails for expired users; this makes the expiry
+//! visible in the configuration UI and the session list.
+
+use crate::auth;
+use crate::db;
+use base::clock::Clocks;
+use failure::Error;
+use std::sync::{mpsc, Arc};
+use std::time::Duration as StdDuration;
+use tracing::{info, warn};
+
+/// The time between maintenance passes.
+const PASS_INTERVAL_SEC: u64 = 60;
+
+pub struct Maintainer<C: Clocks + Clone> {
+    db: Arc<db::Database<C>>,
+}
+
+impl<C: Clocks + Clone> Maintainer<C> {
+    pub fn new(db: Arc<db::Database<C>>) -> Self {
+        Maintainer { db }
+    }
+
+    /// Runs until `shutdown_rx` receives a message or its sender is dropped.
+    pub fn run(self, shutdown_rx: mpsc::Receiver<()>) {
+        let clocks = self.db.clocks();
+        loop {
+            if let Err(e) = self.pass() {
+                warn!("maintenance: pass failed: {}", e);
+            }
+            match clocks.recv_timeout(&shutdown_rx, StdDuration::from_secs(PASS_INTERVAL_SEC)) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Ok(()) | Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    fn pass(&self) -> Result<(), Error> {
+        let req = auth::Request {
+            when_sec: Some(self.db.clocks().realtime().sec),
+            ..Default::default()
+        };
+        let mut l = self.db.lock();
+        for id in l.expire_users(req)? {
+            let name = l
+                .users_by_id()
+                .get(&id)
+                .map(|u| u.username.clone())
+                .unwrap_or_default();
+            info!("maintenance: user {} ({}) expired", id, name);
+        }
+        Ok(())
+    }
+}
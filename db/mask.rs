@@ -0,0 +1,162 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Per-stream privacy masks: regions of the image to black out, such as a neighbor's garden.
+//!
+//! A mask is a list of polygons in normalized coordinates, where `[0, 0]` is the top left of
+//! the image and `[1, 1]` the bottom right, so it applies regardless of the stream's
+//! resolution. It's stored in the `stream.privacy_mask` column in a compact text form: polygons
+//! separated by `;`, each a whitespace-separated list of `x,y` points. Masks apply to decoded
+//! images (thumbnails and live JPEGs) and to exports which ask for transcoding; recordings
+//! themselves are stored unmodified.
+
+use failure::{bail, Error};
+use std::fmt;
+
+/// The maximum number of polygons in a mask.
+pub const MAX_POLYGONS: usize = 16;
+
+/// The maximum number of points in a polygon.
+pub const MAX_POINTS: usize = 32;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Mask {
+    polygons: Vec<Vec<[f32; 2]>>,
+}
+
+impl Mask {
+    /// Creates a mask, checking that there are at most `MAX_POLYGONS` polygons, each with 3 to
+    /// `MAX_POINTS` points within the image.
+    pub fn new(polygons: Vec<Vec<[f32; 2]>>) -> Result<Self, Error> {
+        if polygons.len() > MAX_POLYGONS {
+            bail!(
+                "mask has {} polygons; at most {} are allowed",
+                polygons.len(),
+                MAX_POLYGONS
+            );
+        }
+        for p in &polygons {
+            if p.len() < 3 || p.len() > MAX_POINTS {
+                bail!(
+                    "mask polygon has {} points; expected 3 to {}",
+                    p.len(),
+                    MAX_POINTS
+                );
+            }
+            for &[x, y] in p {
+                if !(0.0..=1.0).contains(&x) || !(0.0..=1.0).contains(&y) {
+                    bail!("mask point {},{} isn't within [0, 1]", x, y);
+                }
+            }
+        }
+        Ok(Mask { polygons })
+    }
+
+    /// Parses the text form stored in the database, as produced by `Display`.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let mut polygons = Vec::new();
+        for p in s.split(';') {
+            if p.trim().is_empty() {
+                continue;
+            }
+            let mut points = Vec::new();
+            for pt in p.split_whitespace() {
+                let mut parts = pt.splitn(2, ',');
+                let x = parts.next().unwrap().parse();
+                let y = parts.next().map(str::parse);
+                match (x, y) {
+                    (Ok(x), Some(Ok(y))) => points.push([x, y]),
+                    _ => bail!("mask point {:?} isn't of the form x,y", pt),
+                }
+            }
+            polygons.push(points);
+        }
+        Mask::new(polygons)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.polygons.is_empty()
+    }
+
+    pub fn polygons(&self) -> &[Vec<[f32; 2]>] {
+        &self.polygons
+    }
+}
+
+impl fmt::Display for Mask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, p) in self.polygons.iter().enumerate() {
+            if i > 0 {
+                f.write_str(";")?;
+            }
+            for (j, &[x, y]) in p.iter().enumerate() {
+                if j > 0 {
+                    f.write_str(" ")?;
+                }
+                write!(f, "{},{}", x, y)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        assert!(Mask::parse("").unwrap().is_empty());
+        let m = Mask::parse(" 0,0 0.5,0  0.5,0.25; 1,1 0.75,1 1,0.5 ").unwrap();
+        assert_eq!(
+            m.polygons(),
+            &[
+                vec![[0.0, 0.0], [0.5, 0.0], [0.5, 0.25]],
+                vec![[1.0, 1.0], [0.75, 1.0], [1.0, 0.5]],
+            ][..]
+        );
+        assert_eq!(m.to_string(), "0,0 0.5,0 0.5,0.25;1,1 0.75,1 1,0.5");
+        assert_eq!(Mask::parse(&m.to_string()).unwrap(), m);
+    }
+
+    #[test]
+    fn invalid() {
+        Mask::parse("0,0 1,1").unwrap_err(); // too few points
+        Mask::parse("0,0 1,1 1.5,0").unwrap_err(); // out of range
+        Mask::parse("0,0 1,1 1").unwrap_err(); // missing y
+        Mask::parse("0,0 1,1 a,0").unwrap_err(); // not a number
+        Mask::parse("0,0 1,1 NaN,0").unwrap_err();
+        Mask::new(vec![
+            vec![[0.0, 0.0], [1.0, 0.0], [1.0, 1.0]];
+            MAX_POLYGONS + 1
+        ])
+        .unwrap_err();
+    }
+}
@@ -0,0 +1,227 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Per-user notification policies: quiet hours and escalation.
+//!
+//! Policies are stored in the `user_notification_policy` table (see `schema.sql`) and evaluated
+//! by the webhook dispatcher in the `moonfire-nvr` crate. Like detections, they aren't cached in
+//! RAM; the dispatcher reads them back on each poll so that edits take effect immediately.
+
+use base::{bail_t, ErrorKind, ResultExt};
+use failure::Error;
+use rusqlite::named_params;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+const LIST_SQL: &'static str = r#"
+    select
+      user_id,
+      webhook_url,
+      quiet_start_min,
+      quiet_end_min,
+      offline_after_sec,
+      escalate_after_sec,
+      escalate_email
+    from
+      user_notification_policy
+    where
+      :user_id is null or user_id = :user_id
+    order by
+      user_id
+"#;
+
+const UPSERT_SQL: &'static str = r#"
+    insert or replace into user_notification_policy (user_id,  webhook_url,  quiet_start_min,
+                                                     quiet_end_min,  offline_after_sec,
+                                                     escalate_after_sec,  escalate_email)
+                                             values (:user_id, :webhook_url, :quiet_start_min,
+                                                     :quiet_end_min, :offline_after_sec,
+                                                     :escalate_after_sec, :escalate_email)
+"#;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NotificationPolicy {
+    /// A URL to which this user's notifications are POSTed, in addition to the server-wide
+    /// `webhook_urls`.
+    pub webhook_url: Option<String>,
+
+    /// When to suppress webhook notifications to this user.
+    pub quiet_hours: Option<QuietHours>,
+
+    /// How long a stream must be offline before notifying this user. If `None`, the server-wide
+    /// `webhook_offline_sec` applies.
+    pub offline_after_sec: Option<i64>,
+
+    pub escalation: Option<Escalation>,
+}
+
+/// A daily range of local time, as minutes after midnight. The range is half-open and wraps past
+/// midnight if `end_min < start_min`; if they're equal, it's empty.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuietHours {
+    pub start_min: u16,
+    pub end_min: u16,
+}
+
+impl QuietHours {
+    /// Returns true iff the given minute after local midnight is within quiet hours.
+    pub fn contains(&self, min: u16) -> bool {
+        if self.start_min <= self.end_min {
+            self.start_min <= min && min < self.end_min
+        } else {
+            min >= self.start_min || min < self.end_min
+        }
+    }
+}
+
+/// Emails `email` once a stream has been offline for `after_sec`, regardless of quiet hours.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Escalation {
+    pub after_sec: i64,
+    pub email: String,
+}
+
+/// Lists policies, either for all users or (if `user_id` is specified) just one.
+pub(crate) fn list(
+    conn: &rusqlite::Connection,
+    user_id: Option<i32>,
+    f: &mut dyn FnMut(i32, NotificationPolicy),
+) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(LIST_SQL)?;
+    let mut rows = stmt.query_named(named_params! {":user_id": user_id})?;
+    while let Some(row) = rows.next()? {
+        let quiet_start_min: Option<u16> = row.get(2)?;
+        let quiet_end_min: Option<u16> = row.get(3)?;
+        let escalate_after_sec: Option<i64> = row.get(5)?;
+        let escalate_email: Option<String> = row.get(6)?;
+        f(
+            row.get(0)?,
+            NotificationPolicy {
+                webhook_url: row.get(1)?,
+                quiet_hours: match (quiet_start_min, quiet_end_min) {
+                    (Some(start_min), Some(end_min)) => Some(QuietHours { start_min, end_min }),
+                    _ => None,
+                },
+                offline_after_sec: row.get(4)?,
+                escalation: match (escalate_after_sec, escalate_email) {
+                    (Some(after_sec), Some(email)) => Some(Escalation { after_sec, email }),
+                    _ => None,
+                },
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Validates and sets (or, if `policy` is `None`, clears) the given user's policy.
+pub(crate) fn set(
+    conn: &rusqlite::Connection,
+    user_id: i32,
+    policy: Option<&NotificationPolicy>,
+) -> Result<(), base::Error> {
+    let p = match policy {
+        None => {
+            conn.execute_named(
+                "delete from user_notification_policy where user_id = :user_id",
+                named_params! {":user_id": user_id},
+            )
+            .err_kind(ErrorKind::Internal)?;
+            return Ok(());
+        }
+        Some(p) => p,
+    };
+    if let Some(q) = p.quiet_hours {
+        if q.start_min >= MINUTES_PER_DAY || q.end_min >= MINUTES_PER_DAY {
+            bail_t!(InvalidArgument, "quiet hours {:?} out of range", q);
+        }
+    }
+    if let Some(s) = p.offline_after_sec {
+        if s <= 0 {
+            bail_t!(InvalidArgument, "offline_after_sec must be positive");
+        }
+    }
+    if let Some(ref e) = p.escalation {
+        if e.after_sec <= 0 {
+            bail_t!(InvalidArgument, "escalation after_sec must be positive");
+        }
+
+        // The address is used as a mail header, so be strict.
+        if !e.email.contains('@')
+            || e.email
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control() || c == ',' || c == '<')
+        {
+            bail_t!(InvalidArgument, "bad escalation email {:?}", e.email);
+        }
+    }
+    let mut stmt = conn
+        .prepare_cached(UPSERT_SQL)
+        .err_kind(ErrorKind::Internal)?;
+    stmt.execute_named(named_params! {
+        ":user_id": user_id,
+        ":webhook_url": &p.webhook_url,
+        ":quiet_start_min": p.quiet_hours.map(|q| q.start_min),
+        ":quiet_end_min": p.quiet_hours.map(|q| q.end_min),
+        ":offline_after_sec": p.offline_after_sec,
+        ":escalate_after_sec": p.escalation.as_ref().map(|e| e.after_sec),
+        ":escalate_email": p.escalation.as_ref().map(|e| &e.email),
+    })
+    .err_kind(ErrorKind::Internal)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quiet_hours() {
+        let day = QuietHours {
+            start_min: 9 * 60,
+            end_min: 17 * 60,
+        };
+        assert!(!day.contains(9 * 60 - 1));
+        assert!(day.contains(9 * 60));
+        assert!(!day.contains(17 * 60));
+        let night = QuietHours {
+            start_min: 22 * 60,
+            end_min: 7 * 60,
+        };
+        assert!(night.contains(23 * 60));
+        assert!(night.contains(0));
+        assert!(!night.contains(7 * 60));
+        assert!(!night.contains(12 * 60));
+        let empty = QuietHours {
+            start_min: 0,
+            end_min: 0,
+        };
+        assert!(!empty.contains(0));
+    }
+}
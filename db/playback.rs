@@ -0,0 +1,197 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Aggregated playback access counts, for the playback heatmap.
+//!
+//! Each playback or export request adds one view to every hour of recorded time it covers on
+//! its stream. Counts are kept in RAM and added to the `playback_heat` table on the next database
+//! flush, as with signal changes. Nothing about who made a request is kept, and counts are
+//! only available at hour granularity.
+
+use crate::recording::{self, TIME_UNITS_PER_SEC};
+use failure::Error;
+use rusqlite::{named_params, params, Connection, Transaction};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+
+/// The duration of each bucket.
+pub const BUCKET_DURATION: recording::Duration = recording::Duration(3600 * TIME_UNITS_PER_SEC);
+
+const LIST_SQL: &'static str = r#"
+    select
+      stream_id,
+      start_time_90k,
+      views
+    from
+      playback_heat
+    where
+      start_time_90k >= :start_time_90k and
+      start_time_90k < :end_time_90k
+"#;
+
+/// A row used in `LockedDatabase::list_playback_heat`.
+#[derive(Debug, Eq, PartialEq)]
+pub struct HeatRow {
+    pub stream_id: i32,
+
+    /// The start of the bucket, a multiple of `BUCKET_DURATION`.
+    pub start: recording::Time,
+    pub views: i64,
+}
+
+#[derive(Default)]
+pub(crate) struct State {
+    /// Views not yet added to the database, keyed by stream id and bucket start.
+    pending: BTreeMap<(i32, i64), i64>,
+}
+
+fn bucket_start(t: recording::Time) -> i64 {
+    t.0 - t.0.rem_euclid(BUCKET_DURATION.0)
+}
+
+impl State {
+    /// Adds a view of the given ranges of the stream. Each bucket is counted once, even if it
+    /// overlaps several of the ranges.
+    pub(crate) fn add(&mut self, stream_id: i32, ranges: &[Range<recording::Time>]) {
+        let mut buckets = BTreeSet::new();
+        for r in ranges {
+            let mut b = bucket_start(r.start);
+            loop {
+                buckets.insert(b);
+                b += BUCKET_DURATION.0;
+                if b >= r.end.0 {
+                    break;
+                }
+            }
+        }
+        for b in buckets {
+            *self.pending.entry((stream_id, b)).or_insert(0) += 1;
+        }
+    }
+
+    /// Adds pending views to the database, skipping those of streams which no longer exist.
+    pub(crate) fn flush<T>(
+        &self,
+        tx: &Transaction,
+        streams_by_id: &BTreeMap<i32, T>,
+    ) -> Result<(), Error> {
+        let mut u_stmt = tx.prepare_cached(
+            r#"
+            update playback_heat set views = views + :views
+            where stream_id = :stream_id and start_time_90k = :start_time_90k
+            "#,
+        )?;
+        let mut i_stmt = tx.prepare_cached(
+            r#"
+            insert into playback_heat (stream_id,  start_time_90k,  views)
+                               values (:stream_id, :start_time_90k, :views)
+            "#,
+        )?;
+        for (&(stream_id, start_time_90k), &views) in &self.pending {
+            if !streams_by_id.contains_key(&stream_id) {
+                continue;
+            }
+            let p = named_params! {
+                ":stream_id": stream_id,
+                ":start_time_90k": start_time_90k,
+                ":views": views,
+            };
+            if u_stmt.execute_named(p)? == 0 {
+                i_stmt.execute_named(p)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks that the previous `flush` was completed successfully.
+    pub(crate) fn post_flush(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Lists buckets starting within `time`, including those not yet flushed, ordered by stream
+    /// and time.
+    pub(crate) fn list(
+        &self,
+        conn: &Connection,
+        time: Range<recording::Time>,
+        f: &mut dyn FnMut(HeatRow),
+    ) -> Result<(), Error> {
+        let mut all = BTreeMap::new();
+        let mut stmt = conn.prepare_cached(LIST_SQL)?;
+        let mut rows = stmt.query_named(named_params! {
+            ":start_time_90k": time.start.0,
+            ":end_time_90k": time.end.0,
+        })?;
+        while let Some(row) = rows.next()? {
+            all.insert((row.get(0)?, row.get(1)?), row.get::<_, i64>(2)?);
+        }
+        for (&(stream_id, start), &views) in &self.pending {
+            if time.start.0 <= start && start < time.end.0 {
+                *all.entry((stream_id, start)).or_insert(0) += views;
+            }
+        }
+        for ((stream_id, start), views) in all {
+            f(HeatRow {
+                stream_id,
+                start: recording::Time(start),
+                views,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Deletes all counts for the given stream, which is being deleted within `tx`.
+pub(crate) fn delete_stream(tx: &Transaction, stream_id: i32) -> Result<(), Error> {
+    tx.execute(
+        "delete from playback_heat where stream_id = ?",
+        params![stream_id],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add() {
+        let mut s = State::default();
+        let h = BUCKET_DURATION.0;
+        let t = |t| recording::Time(t);
+
+        // Two ranges within one bucket count once; a range crossing a boundary counts in both.
+        s.add(1, &[t(h + 1)..t(h + 2), t(h + 3)..t(2 * h + 1)]);
+        s.add(1, &[t(h)..t(2 * h)]);
+        s.add(2, &[t(0)..t(0)]);
+        let pending: Vec<_> = s.pending.iter().map(|(&k, &v)| (k, v)).collect();
+        assert_eq!(pending, vec![((1, h), 2), ((1, 2 * h), 1), ((2, 0), 1)]);
+    }
+}
@@ -32,9 +32,11 @@
 
 use crate::db::{self, CompositeId, FromSqlUuid};
 use crate::recording;
+use crate::scrub;
 use failure::{bail, Error, ResultExt};
 use fnv::FnvHashSet;
 use rusqlite::{named_params, params};
+use std::borrow::Cow;
 use std::ops::Range;
 use uuid::Uuid;
 
@@ -83,6 +85,52 @@ const LIST_RECORDINGS_BY_ID_SQL: &'static str = r#"
         recording.composite_id
 "#;
 
+const LIST_RECORDINGS_BY_ID_AND_TIME_SQL: &'static str = r#"
+    select
+        recording.composite_id,
+        recording.run_offset,
+        recording.flags,
+        recording.start_time_90k,
+        recording.duration_90k,
+        recording.sample_file_bytes,
+        recording.video_samples,
+        recording.video_sync_samples,
+        recording.video_sample_entry_id,
+        recording.open_id
+    from
+        recording
+    where
+        :start <= composite_id and
+        composite_id < :end and
+        recording.start_time_90k < :end_time_90k and
+        recording.start_time_90k + recording.duration_90k > :start_time_90k
+    order by
+        recording.composite_id
+    limit :limit
+"#;
+
+const LIST_SAMPLE_FILES_SQL: &'static str = r#"
+    select
+        r.composite_id,
+        r.start_time_90k,
+        r.duration_90k,
+        r.sample_file_bytes,
+        r.flags,
+        i.sample_file_blake3,
+        i.sample_file_sha1,
+        i.local_time_delta_90k,
+        i.rtp_timestamp,
+        i.rtp_clock_rate
+    from
+        recording r
+        left join recording_integrity i on (r.composite_id = i.composite_id)
+    where
+        :start <= r.composite_id and
+        r.composite_id < :end
+    order by
+        r.composite_id
+"#;
+
 const STREAM_MIN_START_SQL: &'static str = r#"
     select
       start_time_90k
@@ -109,7 +157,8 @@ const LIST_OLDEST_RECORDINGS_SQL: &'static str = r#"
       composite_id,
       start_time_90k,
       duration_90k,
-      sample_file_bytes
+      sample_file_bytes,
+      flags
     from
       recording
     where
@@ -151,6 +200,27 @@ pub(crate) fn list_recordings_by_id(
     list_recordings_inner(rows, f)
 }
 
+/// Lists up to `limit` of the specified recordings which overlap `desired_time`, in ascending
+/// order by id.
+pub(crate) fn list_recordings_by_id_and_time(
+    conn: &rusqlite::Connection,
+    stream_id: i32,
+    desired_ids: Range<i32>,
+    desired_time: Range<recording::Time>,
+    limit: usize,
+    f: &mut dyn FnMut(db::ListRecordingsRow) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(LIST_RECORDINGS_BY_ID_AND_TIME_SQL)?;
+    let rows = stmt.query_named(named_params! {
+        ":start": CompositeId::new(stream_id, desired_ids.start).0,
+        ":end": CompositeId::new(stream_id, desired_ids.end).0,
+        ":start_time_90k": desired_time.start.0,
+        ":end_time_90k": desired_time.end.0,
+        ":limit": limit as i64,
+    })?;
+    list_recordings_inner(rows, f)
+}
+
 fn list_recordings_inner(
     mut rows: rusqlite::Rows,
     f: &mut dyn FnMut(db::ListRecordingsRow) -> Result<(), Error>,
@@ -172,6 +242,35 @@ fn list_recordings_inner(
     Ok(())
 }
 
+/// Lists the committed recordings within `ids` in ascending order.
+pub(crate) fn list_sample_files(
+    conn: &rusqlite::Connection,
+    ids: Range<CompositeId>,
+    f: &mut dyn FnMut(db::SampleFileRow) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(LIST_SAMPLE_FILES_SQL)?;
+    let mut rows = stmt.query_named(named_params! {
+        ":start": ids.start.0,
+        ":end": ids.end.0,
+    })?;
+    while let Some(row) = rows.next()? {
+        f(db::SampleFileRow {
+            id: CompositeId(row.get(0)?),
+            start: recording::Time(row.get(1)?),
+            duration_90k: row.get(2)?,
+            sample_file_bytes: row.get(3)?,
+            flags: row.get(4)?,
+            digest: scrub::Digest::from_columns(row.get(5)?, row.get(6)?),
+            local_time_delta_90k: row.get(7)?,
+            rtp_timestamp: match (row.get(8)?, row.get(9)?) {
+                (Some(ts), Some(rate)) => Some((ts, rate)),
+                _ => None,
+            },
+        })?;
+    }
+    Ok(())
+}
+
 pub(crate) fn get_db_uuid(conn: &rusqlite::Connection) -> Result<Uuid, Error> {
     Ok(conn.query_row(
         "select uuid from meta",
@@ -183,6 +282,180 @@ pub(crate) fn get_db_uuid(conn: &rusqlite::Connection) -> Result<Uuid, Error> {
     )?)
 }
 
+/// Gets the `local_time_delta_90k` of a committed recording, if known.
+/// Returns `None` for the first recording of a run (which has a null delta) or for a recording
+/// without a `recording_integrity` row.
+pub(crate) fn get_local_time_delta(
+    conn: &rusqlite::Connection,
+    id: CompositeId,
+) -> Result<Option<recording::Duration>, Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        select
+          local_time_delta_90k
+        from
+          recording_integrity
+        where
+          composite_id = :composite_id
+        "#,
+    )?;
+    let mut rows = stmt.query_named(named_params! {":composite_id": id.0})?;
+    if let Some(row) = rows.next()? {
+        let delta: Option<i64> = row.get(0)?;
+        return Ok(delta.map(recording::Duration));
+    }
+    Ok(None)
+}
+
+/// Gets the value of the given key in the `config` table, if any.
+pub(crate) fn get_config(conn: &rusqlite::Connection, key: &str) -> Result<Option<String>, Error> {
+    let mut stmt = conn.prepare_cached("select value from config where key = :key")?;
+    let mut rows = stmt.query_named(named_params! {":key": key})?;
+    if let Some(row) = rows.next()? {
+        return Ok(Some(row.get(0)?));
+    }
+    Ok(None)
+}
+
+/// Sets or (if `value` is `None`) clears the value of the given key in the `config` table.
+pub(crate) fn set_config(
+    conn: &rusqlite::Connection,
+    key: &str,
+    value: Option<&str>,
+) -> Result<(), Error> {
+    match value {
+        Some(v) => {
+            let mut stmt = conn.prepare_cached(
+                "insert or replace into config (key, value) values (:key, :value)",
+            )?;
+            stmt.execute_named(named_params! {":key": key, ":value": v})?;
+        }
+        None => {
+            let mut stmt = conn.prepare_cached("delete from config where key = :key")?;
+            stmt.execute_named(named_params! {":key": key})?;
+        }
+    }
+    Ok(())
+}
+
+/// Sets or clears the protected flag on committed recordings of the given stream which overlap
+/// the given time range. Returns the number of recordings changed.
+pub(crate) fn protect_recordings(
+    conn: &rusqlite::Connection,
+    stream_id: i32,
+    time: Range<recording::Time>,
+    protect: bool,
+) -> Result<usize, Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        update recording
+        set
+          flags = case when :protect then flags | :flag else flags & ~:flag end
+        where
+          stream_id = :stream_id and
+          start_time_90k < :end_time_90k and
+          start_time_90k + duration_90k > :start_time_90k and
+          (flags & :flag != 0) != :protect
+    "#,
+    )?;
+    Ok(stmt.execute_named(named_params! {
+        ":stream_id": stream_id,
+        ":start_time_90k": time.start.0,
+        ":end_time_90k": time.end.0,
+        ":protect": protect,
+        ":flag": db::RecordingFlags::Protected as i32,
+    })?)
+}
+
+/// Replaces the stripes of the given stream, as in `db::Stream::stripe_dir_ids`.
+pub(crate) fn set_stream_stripes(
+    tx: &rusqlite::Transaction,
+    stream_id: i32,
+    sample_file_dir_ids: &[i32],
+) -> Result<(), Error> {
+    let mut del = tx.prepare_cached("delete from stream_stripe where stream_id = :stream_id")?;
+    del.execute_named(named_params! {":stream_id": stream_id})?;
+    let mut insert = tx.prepare_cached(
+        r#"
+        insert into stream_stripe (stream_id,  idx,  sample_file_dir_id)
+                           values (:stream_id, :idx, :sample_file_dir_id)
+    "#,
+    )?;
+    for (i, &d) in sample_file_dir_ids.iter().enumerate() {
+        insert.execute_named(named_params! {
+            ":stream_id": stream_id,
+            ":idx": i as i32 + 1,
+            ":sample_file_dir_id": d,
+        })?;
+    }
+    Ok(())
+}
+
+/// Replaces the virtual source of the given stream, as in `db::Stream::virtual_source`.
+pub(crate) fn set_virtual_source(
+    tx: &rusqlite::Transaction,
+    stream_id: i32,
+    v: Option<&db::VirtualSource>,
+) -> Result<(), Error> {
+    let mut del = tx.prepare_cached("delete from virtual_stream where stream_id = :stream_id")?;
+    del.execute_named(named_params! {":stream_id": stream_id})?;
+    let v = match v {
+        None => return Ok(()),
+        Some(v) => v,
+    };
+    let mut insert = tx.prepare_cached(
+        r#"
+        insert into virtual_stream (stream_id,  source_stream_id,  crop_x,  crop_y,  crop_width,
+                                    crop_height,  width,  height)
+                            values (:stream_id, :source_stream_id, :crop_x, :crop_y, :crop_width,
+                                    :crop_height, :width, :height)
+    "#,
+    )?;
+    insert.execute_named(named_params! {
+        ":stream_id": stream_id,
+        ":source_stream_id": v.source_stream_id,
+        ":crop_x": v.crop_x,
+        ":crop_y": v.crop_y,
+        ":crop_width": v.crop_width,
+        ":crop_height": v.crop_height,
+        ":width": v.width,
+        ":height": v.height,
+    })?;
+    Ok(())
+}
+
+/// Bits of the `recording_playback.flags` column.
+enum PlaybackFlags {
+    ZstdVideoIndex = 1,
+}
+
+/// The smallest `video_index` worth compressing. Short recordings' indexes are only a few hundred
+/// bytes; long, high-fps ones are hundreds of kilobytes and compress well.
+const MIN_COMPRESSED_VIDEO_INDEX_BYTES: usize = 1024;
+
+/// Returns the `video_index` and `flags` to store in `recording_playback` for the given index.
+/// The index is compressed only when it's large enough for that to pay off.
+pub(crate) fn encode_video_index(video_index: &[u8]) -> Result<(Cow<[u8]>, i32), Error> {
+    if video_index.len() < MIN_COMPRESSED_VIDEO_INDEX_BYTES {
+        return Ok((Cow::Borrowed(video_index), 0));
+    }
+    let compressed = zstd::encode_all(video_index, 0)
+        .with_context(|e| format!("unable to compress video_index: {}", e))?;
+    if compressed.len() >= video_index.len() {
+        return Ok((Cow::Borrowed(video_index), 0));
+    }
+    Ok((Cow::Owned(compressed), PlaybackFlags::ZstdVideoIndex as i32))
+}
+
+/// Returns the uncompressed form of a `video_index` stored in `recording_playback`.
+pub(crate) fn decode_video_index(stored: Vec<u8>, flags: i32) -> Result<Vec<u8>, Error> {
+    if (flags & PlaybackFlags::ZstdVideoIndex as i32) == 0 {
+        return Ok(stored);
+    }
+    Ok(zstd::decode_all(&stored[..])
+        .with_context(|e| format!("unable to decompress video_index: {}", e))?)
+}
+
 /// Inserts the specified recording (for from `try_flush` only).
 pub(crate) fn insert_recording(
     tx: &rusqlite::Transaction,
@@ -226,12 +499,16 @@ pub(crate) fn insert_recording(
     let mut stmt = tx
         .prepare_cached(
             r#"
-        insert into recording_integrity (composite_id,  local_time_delta_90k,  sample_file_sha1)
-                                 values (:composite_id, :local_time_delta_90k, :sample_file_sha1)
+        insert into recording_integrity (composite_id,  local_time_delta_90k,
+                                         sample_file_blake3,  rtp_timestamp,
+                                         rtp_clock_rate)
+                                 values (:composite_id, :local_time_delta_90k,
+                                         :sample_file_blake3, :rtp_timestamp,
+                                         :rtp_clock_rate)
     "#,
         )
         .with_context(|e| format!("can't prepare recording_integrity insert: {}", e))?;
-    let sha1 = &r.sample_file_sha1[..];
+    let blake3 = r.sample_file_blake3.as_ref().map(|b| &b[..]);
     let delta = match r.run_offset {
         0 => None,
         _ => Some(r.local_time_delta.0),
@@ -239,34 +516,79 @@ pub(crate) fn insert_recording(
     stmt.execute_named(named_params! {
         ":composite_id": id.0,
         ":local_time_delta_90k": delta,
-        ":sample_file_sha1": sha1,
+        ":sample_file_blake3": blake3,
+        ":rtp_timestamp": r.rtp_timestamp.map(|(ts, _)| ts),
+        ":rtp_clock_rate": r.rtp_timestamp.map(|(_, rate)| rate),
     })
     .with_context(|e| format!("unable to insert recording_integrity for {:#?}: {}", r, e))?;
 
+    let (video_index, playback_flags) = encode_video_index(&r.video_index)?;
     let mut stmt = tx
         .prepare_cached(
             r#"
-        insert into recording_playback (composite_id,  video_index)
-                                values (:composite_id, :video_index)
+        insert into recording_playback (composite_id,  video_index,  flags)
+                                values (:composite_id, :video_index, :flags)
     "#,
         )
         .with_context(|e| format!("can't prepare recording_playback insert: {}", e))?;
     stmt.execute_named(named_params! {
         ":composite_id": id.0,
-        ":video_index": &r.video_index,
+        ":video_index": &video_index[..],
+        ":flags": playback_flags,
     })
     .with_context(|e| format!("unable to insert recording_playback for {:#?}: {}", r, e))?;
 
+    if let Some(ref jpeg) = r.thumbnail {
+        insert_thumbnail(tx, id, jpeg)?;
+    }
+
+    Ok(())
+}
+
+/// Inserts or replaces the thumbnail of the given recording.
+pub(crate) fn insert_thumbnail(
+    conn: &rusqlite::Connection,
+    id: CompositeId,
+    jpeg: &[u8],
+) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        insert or replace into thumbnail (composite_id,  jpeg)
+                                  values (:composite_id, :jpeg)
+    "#,
+    )?;
+    stmt.execute_named(named_params! {
+        ":composite_id": id.0,
+        ":jpeg": jpeg,
+    })
+    .with_context(|e| format!("unable to insert thumbnail for {}: {}", id, e))?;
     Ok(())
 }
 
+/// Gets the thumbnail of the given committed recording, if any.
+pub(crate) fn get_thumbnail(
+    conn: &rusqlite::Connection,
+    id: CompositeId,
+) -> Result<Option<Vec<u8>>, Error> {
+    let mut stmt = conn.prepare_cached("select jpeg from thumbnail where composite_id = ?")?;
+    let mut rows = stmt.query(params![id.0])?;
+    Ok(match rows.next()? {
+        Some(row) => Some(row.get(0)?),
+        None => None,
+    })
+}
+
 /// Tranfers the given recording range from the `recording` and `recording_playback` tables to the
-/// `garbage` table. `sample_file_dir_id` is assumed to be correct.
+/// `garbage` table, discarding any associated detections, corruption reports, and thumbnails.
+/// `sample_file_dir_ids` are the stream's stripes, as in `db::Stream::dir_ids`; recordings
+/// flagged `Failover` are instead in `failover_sample_file_dir_id`. Both are assumed to be
+/// correct.
 ///
 /// Returns the number of recordings which were deleted.
 pub(crate) fn delete_recordings(
     tx: &rusqlite::Transaction,
-    sample_file_dir_id: i32,
+    sample_file_dir_ids: &[i32],
+    failover_sample_file_dir_id: Option<i32>,
     ids: Range<CompositeId>,
 ) -> Result<usize, Error> {
     let mut insert = tx.prepare_cached(
@@ -279,7 +601,9 @@ pub(crate) fn delete_recordings(
           recording
         where
           :start <= composite_id and
-          composite_id < :end
+          composite_id < :end and
+          ((flags & 4) != 0) = :failover and
+          (:failover or (composite_id & 4294967295) % :stripes = :stripe)
     "#,
     )?;
     let mut del1 = tx.prepare_cached(
@@ -298,6 +622,30 @@ pub(crate) fn delete_recordings(
           composite_id < :end
     "#,
     )?;
+    let mut del_corrupt = tx.prepare_cached(
+        r#"
+        delete from corrupt_recording
+        where
+          :start <= composite_id and
+          composite_id < :end
+    "#,
+    )?;
+    let mut del_detections = tx.prepare_cached(
+        r#"
+        delete from detection
+        where
+          :start <= composite_id and
+          composite_id < :end
+    "#,
+    )?;
+    let mut del_thumbnails = tx.prepare_cached(
+        r#"
+        delete from thumbnail
+        where
+          :start <= composite_id and
+          composite_id < :end
+    "#,
+    )?;
     let mut del3 = tx.prepare_cached(
         r#"
         delete from recording
@@ -306,11 +654,27 @@ pub(crate) fn delete_recordings(
           composite_id < :end
     "#,
     )?;
-    let n = insert.execute_named(named_params! {
-        ":sample_file_dir_id": sample_file_dir_id,
-        ":start": ids.start.0,
-        ":end": ids.end.0,
-    })?;
+    let mut n = 0;
+    for (i, &sample_file_dir_id) in sample_file_dir_ids.iter().enumerate() {
+        n += insert.execute_named(named_params! {
+            ":sample_file_dir_id": sample_file_dir_id,
+            ":start": ids.start.0,
+            ":end": ids.end.0,
+            ":stripes": sample_file_dir_ids.len() as i64,
+            ":stripe": i as i64,
+            ":failover": false,
+        })?;
+    }
+    if let Some(sample_file_dir_id) = failover_sample_file_dir_id {
+        n += insert.execute_named(named_params! {
+            ":sample_file_dir_id": sample_file_dir_id,
+            ":start": ids.start.0,
+            ":end": ids.end.0,
+            ":stripes": sample_file_dir_ids.len() as i64,
+            ":stripe": 0,
+            ":failover": true,
+        })?;
+    }
     let p = named_params! {
         ":start": ids.start.0,
         ":end": ids.end.0,
@@ -332,6 +696,9 @@ pub(crate) fn delete_recordings(
             n2
         );
     }
+    del_corrupt.execute_named(p)?;
+    del_detections.execute_named(p)?;
+    del_thumbnails.execute_named(p)?;
     let n3 = del3.execute_named(p)?;
     if n3 != n {
         bail!(
@@ -445,6 +812,7 @@ pub(crate) fn list_oldest_recordings(
             start: recording::Time(row.get(1)?),
             duration: row.get(2)?,
             sample_file_bytes: row.get(3)?,
+            flags: row.get(4)?,
         });
         if !should_continue {
             break;
@@ -0,0 +1,127 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Predictions of how much footage streams would keep under hypothetical retention limits, for
+//! `POST /api/retentionSimulation` and `moonfire-nvr retention`.
+//!
+//! A stream keeps its newest recordings up to `retain_bytes` of filesystem usage, so the span of
+//! footage it keeps is that limit divided by its usage per day of wall time. That rate is
+//! measured over a recent window (see `LockedDatabase::stream_usage`) rather than the stream's
+//! whole history, so it reflects the current bitrate and any downtime. Predictions assume the
+//! rate stays the same.
+
+use crate::recording;
+use std::ops::Range;
+
+/// The length of a day, in 90 kHz units.
+const DAY_90K: i64 = 24 * 60 * 60 * 90_000;
+
+/// The default and maximum number of days over which to measure usage.
+pub const DEFAULT_WINDOW_DAYS: i64 = 7;
+pub const MAX_WINDOW_DAYS: i64 = 90;
+
+/// Returns the window of `days` days ending at `now`, to pass to `LockedDatabase::stream_usage`.
+pub fn window(now: recording::Time, days: i64) -> Range<recording::Time> {
+    now - recording::Duration(days * DAY_90K)..now
+}
+
+/// A hypothetical retention limit.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Limit {
+    Bytes(i64),
+
+    /// Keep this many days of footage, with `retain_bytes` set to match.
+    Days(f64),
+}
+
+/// A stream's disk usage over a recent span of time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Usage {
+    /// The span measured: the requested window, less any part before the oldest recording.
+    pub time: Range<recording::Time>,
+
+    /// The total duration of recordings within `time`.
+    pub recorded: recording::Duration,
+
+    /// The estimated filesystem bytes used by the recordings within `time`.
+    pub fs_bytes: i64,
+}
+
+impl Usage {
+    /// Returns the average filesystem bytes used per day, or `None` if nothing was recorded.
+    pub fn fs_bytes_per_day(&self) -> Option<i64> {
+        let span = (self.time.end - self.time.start).0;
+        if span <= 0 || self.fs_bytes <= 0 {
+            return None;
+        }
+        Some((i128::from(self.fs_bytes) * i128::from(DAY_90K) / i128::from(span)) as i64)
+    }
+
+    /// Predicts the `retain_bytes` and the days of footage kept under `limit`. Either is `None`
+    /// if it can't be predicted, as when nothing was recorded.
+    pub fn simulate(&self, limit: Limit) -> (Option<i64>, Option<f64>) {
+        let per_day = self.fs_bytes_per_day();
+        match limit {
+            Limit::Bytes(b) => (Some(b), per_day.map(|d| b as f64 / d as f64)),
+            Limit::Days(days) => (per_day.map(|d| (d as f64 * days).ceil() as i64), Some(days)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::{Duration, Time};
+
+    #[test]
+    fn simulate() {
+        // 2 GiB over 2 days, recording half the time.
+        let u = Usage {
+            time: Time(0)..Time(2 * DAY_90K),
+            recorded: Duration(DAY_90K),
+            fs_bytes: 2 << 30,
+        };
+        assert_eq!(u.fs_bytes_per_day(), Some(1 << 30));
+        assert_eq!(
+            u.simulate(Limit::Bytes(10 << 30)),
+            (Some(10 << 30), Some(10.))
+        );
+        assert_eq!(u.simulate(Limit::Days(30.)), (Some(30 << 30), Some(30.)));
+
+        let empty = Usage {
+            time: Time(0)..Time(0),
+            recorded: Duration(0),
+            fs_bytes: 0,
+        };
+        assert_eq!(empty.fs_bytes_per_day(), None);
+        assert_eq!(empty.simulate(Limit::Bytes(1 << 30)), (Some(1 << 30), None));
+        assert_eq!(empty.simulate(Limit::Days(7.)), (None, Some(7.)));
+    }
+}
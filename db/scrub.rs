@@ -0,0 +1,450 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Sample file integrity scrubbing.
+//!
+//! Scrubbing re-reads the sample file of each committed recording, checking its length against
+//! `recording.sample_file_bytes` and (where known) its contents against
+//! `recording_integrity.sample_file_blake3` or, for recordings written by older versions,
+//! `sample_file_sha1`. Encrypted sample files are also checked against their authentication tags.
+//! This catches bit rot and truncation on disks which otherwise report no errors. Corrupt
+//! recordings are noted in the `corrupt_recording` table and skipped by later passes. Scrubbing
+//! is done by `moonfire-nvr check --scrub` and, if enabled, in the background by
+//! `moonfire-nvr run` via `Scrubber`.
+
+use crate::db::{self, CompositeId};
+use crate::dir;
+use base::clock::Clocks;
+use base::crypto;
+use failure::Error;
+use rusqlite::named_params;
+use std::io::Read;
+use std::ops::Range;
+use std::sync::{mpsc, Arc};
+use std::time::Duration as StdDuration;
+use time::{Duration, Timespec};
+use tracing::{info, warn};
+
+const LIST_CANDIDATES_SQL: &'static str = r#"
+    select
+      r.composite_id,
+      r.flags,
+      r.sample_file_bytes,
+      i.sample_file_blake3,
+      i.sample_file_sha1
+    from
+      recording r
+      left join recording_integrity i on (r.composite_id = i.composite_id)
+    where
+      :start <= r.composite_id and
+      r.composite_id < :end and
+      not exists (select 1 from corrupt_recording c where c.composite_id = r.composite_id)
+    order by
+      r.composite_id
+    limit :limit
+"#;
+
+// Only marks recordings which still exist. A recording deleted since it was listed may have had
+// its file unlinked, which isn't corruption.
+const MARK_CORRUPT_SQL: &'static str = r#"
+    insert or replace into corrupt_recording (composite_id, detection_time_sec, reason)
+    select
+      composite_id,
+      :detection_time_sec,
+      :reason
+    from
+      recording
+    where
+      composite_id = :composite_id
+"#;
+
+const LIST_CORRUPT_SQL: &'static str = r#"
+    select
+      composite_id,
+      detection_time_sec,
+      reason
+    from
+      corrupt_recording
+    order by
+      composite_id
+"#;
+
+/// The number of recordings to list with each database lock acquisition.
+const BATCH_SIZE: i64 = 100;
+
+/// The time between the end of one background scrub pass and the start of the next.
+const PASS_INTERVAL_SEC: u64 = 24 * 60 * 60;
+
+const READ_BUF_SIZE: usize = 1 << 16;
+
+/// A committed recording to scrub, as returned by `LockedDatabase::list_scrub_candidates`.
+#[derive(Clone, Debug)]
+pub struct ScrubCandidate {
+    pub id: CompositeId,
+    pub flags: i32,
+    pub sample_file_bytes: i64,
+
+    /// The expected digest of the sample file, if the `recording_integrity` row has one.
+    pub digest: Option<Digest>,
+}
+
+/// A sample file digest, as stored in `recording_integrity`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Digest {
+    Blake3([u8; 32]),
+
+    /// Written by versions prior to schema version 6.
+    Sha1([u8; 20]),
+}
+
+impl Digest {
+    /// Returns the digest given `recording_integrity`'s `sample_file_blake3` and
+    /// `sample_file_sha1` columns, preferring the former.
+    pub(crate) fn from_columns(blake3: Option<Vec<u8>>, sha1: Option<Vec<u8>>) -> Option<Self> {
+        match (blake3, sha1) {
+            (Some(b), _) if b.len() == 32 => {
+                let mut a = [0u8; 32];
+                a.copy_from_slice(&b);
+                Some(Digest::Blake3(a))
+            }
+            (_, Some(s)) if s.len() == 20 => {
+                let mut a = [0u8; 20];
+                a.copy_from_slice(&s);
+                Some(Digest::Sha1(a))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A row of the `corrupt_recording` table, as returned by `LockedDatabase::list_corrupt_recordings`.
+#[derive(Clone, Debug)]
+pub struct CorruptRecording {
+    pub id: CompositeId,
+    pub detection_time_sec: i64,
+    pub reason: String,
+}
+
+/// Lists up to `limit` committed recordings within `ids` which aren't already known to be corrupt.
+pub(crate) fn list_candidates(
+    conn: &rusqlite::Connection,
+    ids: Range<CompositeId>,
+    limit: i64,
+) -> Result<Vec<ScrubCandidate>, Error> {
+    let mut stmt = conn.prepare_cached(LIST_CANDIDATES_SQL)?;
+    let mut rows = stmt.query_named(named_params! {
+        ":start": ids.start.0,
+        ":end": ids.end.0,
+        ":limit": limit,
+    })?;
+    let mut out = Vec::new();
+    while let Some(row) = rows.next()? {
+        out.push(ScrubCandidate {
+            id: CompositeId(row.get(0)?),
+            flags: row.get(1)?,
+            sample_file_bytes: row.get(2)?,
+            digest: Digest::from_columns(row.get(3)?, row.get(4)?),
+        });
+    }
+    Ok(out)
+}
+
+/// Notes that the given recording is corrupt, if it still exists.
+pub(crate) fn mark_corrupt(
+    conn: &rusqlite::Connection,
+    id: CompositeId,
+    detection_time_sec: i64,
+    reason: &str,
+) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(MARK_CORRUPT_SQL)?;
+    stmt.execute_named(named_params! {
+        ":composite_id": id.0,
+        ":detection_time_sec": detection_time_sec,
+        ":reason": reason,
+    })?;
+    Ok(())
+}
+
+pub(crate) fn list_corrupt(
+    conn: &rusqlite::Connection,
+    f: &mut dyn FnMut(CorruptRecording),
+) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(LIST_CORRUPT_SQL)?;
+    let mut rows = stmt.query(rusqlite::params![])?;
+    while let Some(row) = rows.next()? {
+        f(CorruptRecording {
+            id: CompositeId(row.get(0)?),
+            detection_time_sec: row.get(1)?,
+            reason: row.get(2)?,
+        });
+    }
+    Ok(())
+}
+
+/// Reads the candidate's sample file from `dir`, calling `on_read` with the size of each chunk
+/// read (for throttling). Returns a description of the problem if the file is corrupt.
+pub fn check_file(
+    dir: &dir::SampleFileDir,
+    c: &ScrubCandidate,
+    on_read: &mut dyn FnMut(usize),
+) -> Option<String> {
+    let encrypted = (c.flags & db::RecordingFlags::Encrypted as i32) != 0;
+    let mut f = match dir.open_reader(c.id, encrypted) {
+        Ok(f) => f,
+        Err(e) => return Some(format!("unable to open: {}", e)),
+    };
+    let mut blake3 = crypto::Blake3::new();
+    let mut sha1 = crypto::Sha1::new();
+    let mut buf = vec![0u8; READ_BUF_SIZE];
+    let mut len = 0i64;
+    loop {
+        let n = match f.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Some(format!("read error at byte {}: {}", len, e)),
+        };
+        match c.digest {
+            Some(Digest::Blake3(_)) => blake3.update(&buf[..n]),
+            Some(Digest::Sha1(_)) => sha1.update(&buf[..n]),
+            None => {}
+        }
+        len += n as i64;
+        on_read(n);
+    }
+    if len != c.sample_file_bytes {
+        return Some(format!("length {}; expected {}", len, c.sample_file_bytes));
+    }
+    match c.digest {
+        Some(Digest::Blake3(ref expected)) if blake3.finish() != *expected => {
+            Some("blake3 mismatch".to_owned())
+        }
+        Some(Digest::Sha1(ref expected)) if sha1.finish() != *expected => {
+            Some("sha1 mismatch".to_owned())
+        }
+        _ => None,
+    }
+}
+
+/// Limits reads to a given rate by sleeping as necessary.
+struct Throttle<'a, C: Clocks> {
+    clocks: &'a C,
+    bytes_per_sec: u64,
+    start: Timespec,
+    bytes: u64,
+}
+
+impl<'a, C: Clocks> Throttle<'a, C> {
+    fn new(clocks: &'a C, bytes_per_sec: u64) -> Self {
+        Throttle {
+            clocks,
+            bytes_per_sec,
+            start: clocks.monotonic(),
+            bytes: 0,
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.bytes += n as u64;
+        let due =
+            self.start + Duration::milliseconds((self.bytes * 1000 / self.bytes_per_sec) as i64);
+        let now = self.clocks.monotonic();
+        if due > now {
+            self.clocks.sleep(due - now);
+        }
+    }
+}
+
+/// Scrubs all committed recordings in the background, at a limited rate so as not to compete
+/// with recording for disk bandwidth, then waits a day and starts again.
+pub struct Scrubber<C: Clocks + Clone> {
+    db: Arc<db::Database<C>>,
+    bytes_per_sec: u64,
+}
+
+impl<C: Clocks + Clone> Scrubber<C> {
+    pub fn new(db: Arc<db::Database<C>>, bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0);
+        Scrubber { db, bytes_per_sec }
+    }
+
+    /// Runs until `shutdown_rx` receives a message or its sender is dropped.
+    pub fn run(self, shutdown_rx: mpsc::Receiver<()>) {
+        let clocks = self.db.clocks();
+        loop {
+            match self.pass(&shutdown_rx) {
+                Ok(false) => return,
+                Ok(true) => {}
+                Err(e) => warn!("scrub: pass failed: {}", e),
+            }
+            match clocks.recv_timeout(&shutdown_rx, StdDuration::from_secs(PASS_INTERVAL_SEC)) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Ok(()) | Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    /// Scrubs each committed recording once. Returns false if interrupted by shutdown.
+    fn pass(&self, shutdown_rx: &mpsc::Receiver<()>) -> Result<bool, Error> {
+        let clocks = self.db.clocks();
+        let mut throttle = Throttle::new(&clocks, self.bytes_per_sec);
+        let stream_ids: Vec<i32> = self.db.lock().streams_by_id().keys().cloned().collect();
+        let (mut recordings, mut corrupt) = (0, 0);
+        info!("scrub: starting pass");
+        for stream_id in stream_ids {
+            let mut next = CompositeId::new(stream_id, 0);
+            let end = CompositeId::new(stream_id, i32::max_value());
+            loop {
+                let mut batch = Vec::new();
+                {
+                    let l = self.db.lock();
+                    let s = match l.streams_by_id().get(&stream_id) {
+                        None => break,
+                        Some(s) => s,
+                    };
+                    for c in l.list_scrub_candidates(next..end, BATCH_SIZE)? {
+                        let dir = s
+                            .dir_id_for(c.id.recording(), c.flags)
+                            .and_then(|id| l.sample_file_dirs_by_id().get(&id))
+                            .and_then(|d| d.get().ok());
+                        batch.push((c, dir));
+                    }
+                }
+                let last = match batch.last() {
+                    None => break,
+                    Some((c, _)) => c.id,
+                };
+                next = CompositeId(last.0 + 1);
+                for (c, dir) in batch {
+                    match shutdown_rx.try_recv() {
+                        Err(mpsc::TryRecvError::Empty) => {}
+                        Ok(()) | Err(mpsc::TryRecvError::Disconnected) => return Ok(false),
+                    }
+                    let dir = match dir {
+                        None => continue, // directory isn't open.
+                        Some(d) => d,
+                    };
+                    recordings += 1;
+                    if let Some(reason) = check_file(&dir, &c, &mut |n| throttle.consume(n)) {
+                        warn!("scrub: recording {} is corrupt: {}", c.id, reason);
+                        corrupt += 1;
+                        let now = clocks.realtime().sec;
+                        self.db.lock().mark_corrupt_recording(c.id, now, &reason)?;
+                    }
+                }
+            }
+        }
+        info!(
+            "scrub: pass complete; checked {} recordings, found {} newly corrupt",
+            recordings, corrupt
+        );
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Digest;
+    use crate::db;
+    use crate::recording::SampleIndexEncoder;
+    use crate::testutil::{self, TestDb, TEST_STREAM_ID};
+    use base::clock::RealClocks;
+    use base::crypto;
+    use std::io::Write;
+
+    #[test]
+    fn scrub() {
+        testutil::init();
+        let tdb = TestDb::new(RealClocks {});
+        let contents = b"sample file contents";
+        let mut r = db::RecordingToInsert::default();
+        SampleIndexEncoder::new()
+            .add_sample(1, contents.len() as i32, true, &mut r)
+            .unwrap();
+        r.sample_file_blake3 = Some(crypto::blake3(contents));
+        let id = tdb.insert_recording_from_encoder(r).id;
+        let dir = tdb.dirs_by_stream_id.get(&TEST_STREAM_ID).unwrap().stripes[0].clone();
+        let all = db::CompositeId::new(TEST_STREAM_ID, 0)
+            ..db::CompositeId::new(TEST_STREAM_ID, i32::max_value());
+
+        // A missing file is corrupt.
+        let candidates = tdb
+            .db
+            .lock()
+            .list_scrub_candidates(all.clone(), 10)
+            .unwrap();
+        assert_eq!(candidates.len(), 1);
+        let c = &candidates[0];
+        assert_eq!(c.id, id);
+        assert_eq!(c.digest, Some(Digest::Blake3(crypto::blake3(contents))));
+        assert!(super::check_file(&dir, c, &mut |_| {}).is_some());
+
+        // A correct file is fine.
+        let mut f = dir.create_file(id).unwrap();
+        f.write_all(contents).unwrap();
+        drop(f);
+        let mut read = 0;
+        assert_eq!(super::check_file(&dir, c, &mut |n| read += n), None);
+        assert_eq!(read, contents.len());
+
+        // A file of the right length but wrong contents is corrupt.
+        let mut f = std::fs::OpenOptions::new()
+            .write(true)
+            .open(tdb.tmpdir.path().join(format!("{:016x}", id.0)))
+            .unwrap();
+        f.write_all(b"SAMPLE").unwrap();
+        drop(f);
+        assert_eq!(
+            super::check_file(&dir, c, &mut |_| {}).as_deref(),
+            Some("blake3 mismatch")
+        );
+
+        // Legacy sha1 digests are checked too.
+        let legacy = super::ScrubCandidate {
+            digest: Some(Digest::Sha1(crypto::sha1(contents))),
+            ..c.clone()
+        };
+        assert_eq!(
+            super::check_file(&dir, &legacy, &mut |_| {}).as_deref(),
+            Some("sha1 mismatch")
+        );
+
+        // Once marked corrupt, it's listed as such and no longer a candidate.
+        let mut l = tdb.db.lock();
+        l.mark_corrupt_recording(id, 42, "blake3 mismatch").unwrap();
+        assert!(l.list_scrub_candidates(all, 10).unwrap().is_empty());
+        let mut corrupt = Vec::new();
+        l.list_corrupt_recordings(&mut |c| corrupt.push(c)).unwrap();
+        assert_eq!(corrupt.len(), 1);
+        assert_eq!(corrupt[0].id, id);
+        assert_eq!(corrupt[0].detection_time_sec, 42);
+        assert_eq!(corrupt[0].reason, "blake3 mismatch");
+    }
+}
@@ -0,0 +1,282 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//! Notes and full-text search.
+//!
+//! Notes are free-form text (such as incident descriptions) attached to a time range and
+//! optionally a camera. Like detections, they aren't cached in RAM. Search covers camera names
+//! and descriptions, notes, and detection labels via the SQLite FTS5 tables `camera_fts`,
+//! `note_fts`, and `detection_fts`, which triggers keep up-to-date. See `schema.sql`.
+
+use crate::db::CompositeId;
+use crate::recording;
+use base::{bail_t, ErrorKind, ResultExt};
+use failure::Error;
+use rusqlite::named_params;
+use std::ops::Range;
+
+const INSERT_NOTE_SQL: &'static str = r#"
+    insert into note (camera_id,  start_time_90k,  end_time_90k,  creation_time_sec,  author,
+                      text)
+              values (:camera_id, :start_time_90k, :end_time_90k, :creation_time_sec, :author,
+                      :text)
+"#;
+
+const DELETE_NOTE_SQL: &'static str = "delete from note where id = :id";
+
+// Each match's kind, id, camera id or composite id, time range, score, and snippet. Camera
+// matches have no time range, so they're returned regardless of the time filter. bm25 scores are
+// lower for better matches.
+const SEARCH_SQL: &'static str = r#"
+    select
+      'camera' as kind,
+      rowid,
+      rowid,
+      null,
+      null,
+      bm25(camera_fts) as score,
+      snippet(camera_fts, -1, '[', ']', '…', 16)
+    from
+      camera_fts
+    where
+      camera_fts match :query
+    union all
+    select
+      'note',
+      note.id,
+      note.camera_id,
+      note.start_time_90k,
+      note.end_time_90k,
+      bm25(note_fts),
+      snippet(note_fts, 0, '[', ']', '…', 16)
+    from
+      note_fts
+      join note on (note_fts.rowid = note.id)
+    where
+      note_fts match :query and
+      note.start_time_90k < :end_time_90k and
+      note.end_time_90k > :start_time_90k
+    union all
+    select
+      'detection',
+      detection.id,
+      detection.composite_id,
+      detection.start_time_90k,
+      detection.end_time_90k,
+      bm25(detection_fts),
+      snippet(detection_fts, 0, '[', ']', '…', 16)
+    from
+      detection_fts
+      join detection on (detection_fts.rowid = detection.id)
+    where
+      detection_fts match :query and
+      detection.start_time_90k < :end_time_90k and
+      detection.end_time_90k > :start_time_90k
+    order by
+      score
+    limit :limit
+"#;
+
+/// A note to add via `LockedDatabase::add_note`.
+#[derive(Clone, Debug)]
+pub struct NoteToInsert {
+    /// The camera this note is about, if any.
+    pub camera_id: Option<i32>,
+
+    /// The span of time this note is about. May be empty for a single instant.
+    pub time: Range<recording::Time>,
+
+    /// The time at which this note was written, in seconds since epoch.
+    pub creation_time_sec: i64,
+
+    /// The name of the user who wrote this note, if known.
+    pub author: Option<String>,
+
+    pub text: String,
+}
+
+/// What matched a search in `LockedDatabase::search`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SearchHit {
+    /// The camera's short name or description.
+    Camera { camera_id: i32 },
+
+    /// A note, as added via `LockedDatabase::add_note`.
+    Note {
+        id: i64,
+        camera_id: Option<i32>,
+        time: Range<recording::Time>,
+    },
+
+    /// A detection's label, as added via `LockedDatabase::add_detections`.
+    Detection {
+        id: i64,
+        recording_id: CompositeId,
+        time: Range<recording::Time>,
+    },
+}
+
+/// A row used in `LockedDatabase::search`.
+#[derive(Debug)]
+pub struct SearchRow {
+    pub hit: SearchHit,
+
+    /// The relevance of this match; higher is better.
+    pub score: f64,
+
+    /// An excerpt of the matching text, with matched terms enclosed in `[` and `]`.
+    pub snippet: String,
+}
+
+/// Converts a user's search string into a FTS5 query matching all of its terms, quoting each so
+/// that FTS5 syntax characters are taken literally. Returns `None` if there are no terms.
+fn fts_query(q: &str) -> Option<String> {
+    let mut out = String::with_capacity(q.len() + 8);
+    for term in q.split_whitespace() {
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        out.push('"');
+        out.push_str(&term.replace('"', "\"\""));
+        out.push('"');
+    }
+    if out.is_empty() {
+        return None;
+    }
+    Some(out)
+}
+
+/// Validates and inserts the given note, returning its id.
+pub(crate) fn insert_note(
+    conn: &rusqlite::Connection,
+    n: &NoteToInsert,
+) -> Result<i64, base::Error> {
+    if n.text.trim().is_empty() {
+        bail_t!(InvalidArgument, "note has empty text");
+    }
+    if n.time.start > n.time.end {
+        bail_t!(InvalidArgument, "note has inverted time range {:?}", n.time);
+    }
+    let mut stmt = conn
+        .prepare_cached(INSERT_NOTE_SQL)
+        .err_kind(ErrorKind::Internal)?;
+    stmt.execute_named(named_params! {
+        ":camera_id": n.camera_id,
+        ":start_time_90k": n.time.start.0,
+        ":end_time_90k": n.time.end.0,
+        ":creation_time_sec": n.creation_time_sec,
+        ":author": n.author,
+        ":text": n.text,
+    })
+    .err_kind(ErrorKind::Internal)?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Deletes the given note.
+pub(crate) fn delete_note(conn: &rusqlite::Connection, id: i64) -> Result<(), base::Error> {
+    let mut stmt = conn
+        .prepare_cached(DELETE_NOTE_SQL)
+        .err_kind(ErrorKind::Internal)?;
+    if stmt
+        .execute_named(named_params! {":id": id})
+        .err_kind(ErrorKind::Internal)?
+        != 1
+    {
+        bail_t!(NotFound, "no such note {}", id);
+    }
+    Ok(())
+}
+
+/// Searches for up to `limit` matches of all terms in `q`, in descending order of relevance.
+/// Notes and detections must overlap `time`.
+pub(crate) fn search(
+    conn: &rusqlite::Connection,
+    q: &str,
+    time: Range<recording::Time>,
+    limit: u32,
+    f: &mut dyn FnMut(SearchRow) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let query = match fts_query(q) {
+        None => return Ok(()),
+        Some(q) => q,
+    };
+    let mut stmt = conn.prepare_cached(SEARCH_SQL)?;
+    let mut rows = stmt.query_named(named_params! {
+        ":query": query,
+        ":start_time_90k": time.start.0,
+        ":end_time_90k": time.end.0,
+        ":limit": limit,
+    })?;
+    while let Some(row) = rows.next()? {
+        let kind: String = row.get(0)?;
+        let time = || -> Result<_, rusqlite::Error> {
+            Ok(recording::Time(row.get(3)?)..recording::Time(row.get(4)?))
+        };
+        let hit = match kind.as_str() {
+            "camera" => SearchHit::Camera {
+                camera_id: row.get(2)?,
+            },
+            "note" => SearchHit::Note {
+                id: row.get(1)?,
+                camera_id: row.get(2)?,
+                time: time()?,
+            },
+            "detection" => SearchHit::Detection {
+                id: row.get(1)?,
+                recording_id: CompositeId(row.get(2)?),
+                time: time()?,
+            },
+            _ => unreachable!(),
+        };
+        let score: f64 = row.get(5)?;
+        f(SearchRow {
+            hit,
+            score: -score,
+            snippet: row.get(6)?,
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn fts_query() {
+        assert_eq!(super::fts_query(""), None);
+        assert_eq!(super::fts_query("  "), None);
+        assert_eq!(
+            super::fts_query("white  van").as_deref(),
+            Some(r#""white" "van""#)
+        );
+        assert_eq!(
+            super::fts_query(r#"a"b OR"#).as_deref(),
+            Some(r#""a""b" "OR""#)
+        );
+    }
+}
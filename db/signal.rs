@@ -31,16 +31,38 @@
 use crate::coding;
 use crate::db::FromSqlUuid;
 use crate::recording;
-use base::bail_t;
+use base::{bail_t, ErrorKind, ResultExt};
 use failure::{bail, format_err, Error};
 use fnv::FnvHashMap;
-use log::debug;
 use rusqlite::{params, Connection, Transaction};
 use std::collections::btree_map::Entry;
 use std::collections::{BTreeMap, BTreeSet};
 use std::ops::Range;
+use tracing::debug;
 use uuid::Uuid;
 
+/// The type uuid of a signal driven by a camera's ONVIF motion events.
+///
+/// A signal with this type and a `source` matching a camera's uuid will be updated from that
+/// camera's ONVIF event service by `moonfire-nvr run`. It should use state 1 for no motion and
+/// state 2 for motion.
+pub const ONVIF_MOTION_TYPE_UUID: Uuid = Uuid::from_bytes([
+    0x6f, 0x6e, 0x1c, 0x2d, 0x4a, 0x53, 0x4d, 0x5e, 0x9a, 0x2f, 0x52, 0x8e, 0x3c, 0x8a, 0x71, 0x01,
+]);
+
+/// The type uuid of a signal driven by a camera's ONVIF tamper events, as with
+/// `ONVIF_MOTION_TYPE_UUID`. It should use state 1 for normal and state 2 for tampered.
+pub const ONVIF_TAMPER_TYPE_UUID: Uuid = Uuid::from_bytes([
+    0x6f, 0x6e, 0x1c, 0x2d, 0x4a, 0x53, 0x4d, 0x5e, 0x9a, 0x2f, 0x52, 0x8e, 0x3c, 0x8a, 0x71, 0x02,
+]);
+
+/// The type uuid of a camera's privacy mode signal. Its `source` is the camera's uuid; it uses
+/// state 1 for normal and state 2 for private. While private, `moonfire-nvr run` neither records
+/// nor serves live view from the camera. The signal is created on first use.
+pub const PRIVACY_TYPE_UUID: Uuid = Uuid::from_bytes([
+    0x6f, 0x6e, 0x1c, 0x2d, 0x4a, 0x53, 0x4d, 0x5e, 0x9a, 0x2f, 0x52, 0x8e, 0x3c, 0x8a, 0x71, 0x03,
+]);
+
 /// All state associated with signals. This is the entry point to this module.
 pub(crate) struct State {
     signals_by_id: BTreeMap<u32, Signal>,
@@ -150,6 +172,58 @@ fn serialize(from: &BTreeMap<u32, u16>) -> Vec<u8> {
     to
 }
 
+fn validate_short_name(short_name: &str) -> Result<(), base::Error> {
+    if short_name.trim().is_empty() {
+        bail_t!(InvalidArgument, "signal short name must be non-empty");
+    }
+    Ok(())
+}
+
+/// Sorts `states` by value, checking that values are unique and within the `signal_type_enum`
+/// table's allowed range.
+fn validate_states(states: &mut Vec<TypeState>) -> Result<(), base::Error> {
+    states.sort_by_key(|s| s.value);
+    for (i, s) in states.iter().enumerate() {
+        if s.value == 0 || s.value >= 16 {
+            bail_t!(InvalidArgument, "state value {} isn't in [1, 15]", s.value);
+        }
+        if i > 0 && states[i - 1].value == s.value {
+            bail_t!(
+                InvalidArgument,
+                "state value {} is given more than once",
+                s.value
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Sorts `cameras` by id, checking that each is given only once.
+fn validate_cameras(mut cameras: Vec<SignalCamera>) -> Result<Vec<SignalCamera>, base::Error> {
+    cameras.sort_by_key(|c| c.camera_id);
+    for w in cameras.windows(2) {
+        if w[0].camera_id == w[1].camera_id {
+            bail_t!(
+                InvalidArgument,
+                "camera {} is given more than once",
+                w[0].camera_id
+            );
+        }
+    }
+    Ok(cameras)
+}
+
+fn insert_cameras(conn: &Connection, id: u32, cameras: &[SignalCamera]) -> Result<(), base::Error> {
+    let mut stmt = conn
+        .prepare_cached("insert into signal_camera (signal_id, camera_id, type) values (?, ?, ?)")
+        .err_kind(ErrorKind::Internal)?;
+    for c in cameras {
+        stmt.execute(params![id, c.camera_id, c.type_ as i32])
+            .err_kind(ErrorKind::Internal)?;
+    }
+    Ok(())
+}
+
 struct PointDataIterator<'a> {
     data: &'a [u8],
     cur_pos: usize,
@@ -203,14 +277,14 @@ impl<'a> PointDataIterator<'a> {
 
 /// Representation of a `signal_camera` row.
 /// `signal_id` is implied by the `Signal` which owns this struct.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct SignalCamera {
     pub camera_id: i32,
     pub type_: SignalCameraType,
 }
 
 /// Representation of the `type` field in a `signal_camera` row.
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum SignalCameraType {
     Direct = 0,
     Indirect = 1,
@@ -269,6 +343,186 @@ impl State {
         }
     }
 
+    /// Returns the state of `signal` as of `when`, which is 0 (unknown) if it has never been set.
+    pub fn state_at(&self, signal: u32, when: recording::Time) -> u16 {
+        match self.points_by_time.range(..=when).next_back() {
+            None => 0,
+            Some((_, p)) => p.after().get(&signal).copied().unwrap_or(0),
+        }
+    }
+
+    /// Adds a signal, returning its id. If `type_` has no known states, it is given `states`.
+    ///
+    /// The rows are inserted through `conn`; if it is a transaction which is later rolled
+    /// back, the in-memory state will be out of sync with the database.
+    pub fn add_signal(
+        &mut self,
+        conn: &Connection,
+        source: Uuid,
+        type_: Uuid,
+        short_name: String,
+        mut states: Vec<TypeState>,
+        cameras: Vec<SignalCamera>,
+    ) -> Result<u32, base::Error> {
+        validate_short_name(&short_name)?;
+        if self
+            .signals_by_id
+            .values()
+            .any(|s| s.source == source && s.type_ == type_)
+        {
+            bail_t!(
+                AlreadyExists,
+                "there's already a signal with source {} and type {}",
+                source,
+                type_
+            );
+        }
+        let define_type = !states.is_empty() && !self.types_by_uuid.contains_key(&type_);
+        if define_type {
+            validate_states(&mut states)?;
+        }
+        let cameras = validate_cameras(cameras)?;
+        let id = self
+            .signals_by_id
+            .keys()
+            .next_back()
+            .map(|&id| id + 1)
+            .unwrap_or(1);
+        conn.execute(
+            r#"
+            insert into signal (id, source_uuid, type_uuid, short_name) values (?, ?, ?, ?)
+        "#,
+            params![
+                id,
+                &source.as_bytes()[..],
+                &type_.as_bytes()[..],
+                &short_name
+            ],
+        )
+        .err_kind(ErrorKind::Internal)?;
+        if define_type {
+            let mut stmt = conn
+                .prepare(
+                    r#"
+                    insert into signal_type_enum (type_uuid, value, name, motion, color)
+                                          values (?,         ?,     ?,    ?,      ?)
+                "#,
+                )
+                .err_kind(ErrorKind::Internal)?;
+            for s in &states {
+                stmt.execute(params![
+                    &type_.as_bytes()[..],
+                    s.value,
+                    &s.name,
+                    s.motion,
+                    &s.color
+                ])
+                .err_kind(ErrorKind::Internal)?;
+            }
+        }
+        insert_cameras(conn, id, &cameras)?;
+        if define_type {
+            self.types_by_uuid.insert(type_, Type { states });
+        }
+        self.signals_by_id.insert(
+            id,
+            Signal {
+                id,
+                source,
+                type_,
+                short_name,
+                cameras,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Replaces the given signal's short name and cameras, as with `add_signal`.
+    pub fn update_signal(
+        &mut self,
+        conn: &Connection,
+        id: u32,
+        short_name: String,
+        cameras: Vec<SignalCamera>,
+    ) -> Result<(), base::Error> {
+        if !self.signals_by_id.contains_key(&id) {
+            bail_t!(NotFound, "no such signal {}", id);
+        }
+        validate_short_name(&short_name)?;
+        let cameras = validate_cameras(cameras)?;
+        conn.execute(
+            "update signal set short_name = ? where id = ?",
+            params![&short_name, id],
+        )
+        .err_kind(ErrorKind::Internal)?;
+        conn.execute("delete from signal_camera where signal_id = ?", params![id])
+            .err_kind(ErrorKind::Internal)?;
+        insert_cameras(conn, id, &cameras)?;
+        let s = self.signals_by_id.get_mut(&id).unwrap();
+        s.short_name = short_name;
+        s.cameras = cameras;
+        Ok(())
+    }
+
+    /// Deletes the given signal and removes it from every point in history.
+    ///
+    /// The caller should `flush` through the same transaction as `conn`, so that a later signal
+    /// can't reuse the id and inherit this one's history.
+    pub fn delete_signal(&mut self, conn: &Connection, id: u32) -> Result<(), base::Error> {
+        if !self.signals_by_id.contains_key(&id) {
+            bail_t!(NotFound, "no such signal {}", id);
+        }
+        conn.execute("delete from signal_camera where signal_id = ?", params![id])
+            .err_kind(ErrorKind::Internal)?;
+        conn.execute("delete from signal where id = ?", params![id])
+            .err_kind(ErrorKind::Internal)?;
+        self.signals_by_id.remove(&id);
+
+        // Rebuild every point, as each one's prior state may mention the signal.
+        let mut cur = BTreeMap::new(); // latest signal -> state, where state != 0
+        let mut to_remove = Vec::new();
+        for (&t, p) in self.points_by_time.iter_mut() {
+            let mut changes = BTreeMap::new();
+            let mut affected = false;
+            {
+                let mut it = p.changes();
+                while let Some((signal, state)) = it.next().expect("in-mem changes is valid") {
+                    if signal == id {
+                        affected = true;
+                    } else {
+                        changes.insert(signal, state);
+                    }
+                }
+            }
+            if affected {
+                self.dirty_by_time.insert(t);
+            }
+            if changes.is_empty() {
+                to_remove.push(t);
+                continue;
+            }
+            *p = Point::new(&cur, &serialize(&changes));
+            for (&signal, &state) in &changes {
+                if state == 0 {
+                    cur.remove(&signal);
+                } else {
+                    cur.insert(signal, state);
+                }
+            }
+        }
+        for t in to_remove {
+            self.points_by_time.remove(&t);
+        }
+        Ok(())
+    }
+
+    /// Removes the given (just-deleted) camera from all signals' `cameras`.
+    pub fn camera_deleted(&mut self, camera_id: i32) {
+        for s in self.signals_by_id.values_mut() {
+            s.cameras.retain(|c| c.camera_id != camera_id);
+        }
+    }
+
     pub fn update_signals(
         &mut self,
         when: Range<recording::Time>,
@@ -910,4 +1164,119 @@ mod tests {
         );
         assert_eq!(&rows[..], EXPECTED2);
     }
+
+    #[test]
+    fn add_update_delete() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut s = State::init(&conn).unwrap();
+        let source = Uuid::parse_str("1b3889c0-a59f-400d-a24c-94ebeb19cc3a").unwrap();
+        let source2 = Uuid::parse_str("a4a73d9a-5342-4ebc-b9f6-366f1e5617fa").unwrap();
+        let states = || {
+            vec![TypeState {
+                value: 2,
+                name: "private".to_owned(),
+                motion: false,
+                color: "#000000".to_owned(),
+            }]
+        };
+        let id = s
+            .add_signal(
+                &conn,
+                source,
+                PRIVACY_TYPE_UUID,
+                "a".to_owned(),
+                states(),
+                Vec::new(),
+            )
+            .unwrap();
+        assert_eq!(id, 1);
+        let id2 = s
+            .add_signal(
+                &conn,
+                source,
+                ONVIF_MOTION_TYPE_UUID,
+                "b".to_owned(),
+                Vec::new(),
+                Vec::new(),
+            )
+            .unwrap();
+        assert_eq!(id2, 2);
+        let id3 = s
+            .add_signal(
+                &conn,
+                source2,
+                PRIVACY_TYPE_UUID,
+                "c".to_owned(),
+                Vec::new(),
+                Vec::new(),
+            )
+            .unwrap();
+        assert_eq!(id3, 3);
+        let e = s
+            .add_signal(
+                &conn,
+                source,
+                PRIVACY_TYPE_UUID,
+                "d".to_owned(),
+                Vec::new(),
+                Vec::new(),
+            )
+            .unwrap_err();
+        assert_eq!(e.kind(), base::ErrorKind::AlreadyExists);
+        s.update_signal(&conn, id2, "b2".to_owned(), Vec::new())
+            .unwrap();
+        s.update_signal(&conn, 42, "x".to_owned(), Vec::new())
+            .unwrap_err();
+
+        const START: recording::Time = recording::Time(140067462600000); // 2019-04-26T11:59:00
+        const NOW: recording::Time = recording::Time(140067468000000); // 2019-04-26T12:00:00
+        const SOON: recording::Time = recording::Time(140067473400000); // 2019-04-26T12:01:00
+        s.update_signals(START..NOW, &[id, id3], &[2, 2]).unwrap();
+        s.update_signals(NOW..SOON, &[id], &[2]).unwrap();
+        assert_eq!(s.state_at(id, recording::Time(START.0 - 1)), 0);
+        assert_eq!(s.state_at(id, START), 2);
+        assert_eq!(s.state_at(id, SOON), 0);
+        assert_eq!(s.state_at(id2, START), 0);
+        assert_eq!(s.state_at(id3, START), 2);
+
+        // Deleting a signal removes it from history, including points which then become empty.
+        s.delete_signal(&conn, id).unwrap();
+        {
+            let tx = conn.transaction().unwrap();
+            s.flush(&tx).unwrap();
+            tx.commit().unwrap();
+        }
+        s.post_flush();
+        drop(s);
+        let s = State::init(&conn).unwrap();
+        assert_eq!(
+            s.signals_by_id().keys().copied().collect::<Vec<_>>(),
+            &[id2, id3]
+        );
+        assert_eq!(s.signals_by_id()[&id2].short_name, "b2");
+        assert_eq!(s.types_by_uuid()[&PRIVACY_TYPE_UUID].states.len(), 1);
+        assert!(!s.types_by_uuid().contains_key(&ONVIF_MOTION_TYPE_UUID));
+        let mut rows = Vec::new();
+        s.list_changes_by_time(
+            recording::Time::min_value()..recording::Time::max_value(),
+            &mut |r| rows.push(*r),
+        );
+        assert_eq!(
+            &rows[..],
+            &[
+                ListStateChangesRow {
+                    when: START,
+                    signal: id3,
+                    state: 2,
+                },
+                ListStateChangesRow {
+                    when: NOW,
+                    signal: id3,
+                    state: 0,
+                },
+            ]
+        );
+    }
 }
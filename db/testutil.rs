@@ -33,7 +33,6 @@ use crate::dir;
 use crate::writer;
 use base::clock::Clocks;
 use fnv::FnvHashMap;
-use mylog;
 use rusqlite;
 use std::env;
 use std::sync::Arc;
@@ -64,20 +63,65 @@ pub const TEST_VIDEO_SAMPLE_ENTRY_DATA: &[u8] =
 ///    * use a fast but insecure password hashing format.
 pub fn init() {
     INIT.call_once(|| {
-        let h = mylog::Builder::new()
-            .set_spec(&::std::env::var("MOONFIRE_LOG").unwrap_or("info".to_owned()))
-            .build();
-        h.install().unwrap();
+        tracing_subscriber::fmt()
+            .with_env_filter(tracing_subscriber::EnvFilter::new(
+                ::std::env::var("MOONFIRE_LOG").unwrap_or("info".to_owned()),
+            ))
+            .with_test_writer()
+            .init();
         env::set_var("TZ", "America/Los_Angeles");
         time::tzset();
         crate::auth::set_test_config();
     });
 }
 
+/// Creates a database with `num_dirs` sample file directories but no cameras, for tests which
+/// set up their own. Returns the database, the temporary directory holding the sample file
+/// directories, and their ids.
+pub fn new_db<C: Clocks + Clone>(
+    clocks: C,
+    num_dirs: usize,
+) -> (db::Database<C>, TempDir, Vec<i32>) {
+    let tmpdir = TempDir::new("moonfire-nvr-test").unwrap();
+    let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+    db::init(&mut conn).unwrap();
+    let db = db::Database::new(clocks, conn, true).unwrap();
+    let dir_ids = (0..num_dirs)
+        .map(|i| {
+            let path = tmpdir.path().join(i.to_string());
+            let path = path.to_str().unwrap().to_owned();
+            db.lock().add_sample_file_dir(path).unwrap()
+        })
+        .collect();
+    (db, tmpdir, dir_ids)
+}
+
+/// Returns a camera like the one added by `TestDb::new`: its main stream records to
+/// `sample_file_dir_id` if supplied, and it has no sub stream.
+pub fn test_camera(sample_file_dir_id: Option<i32>) -> db::CameraChange {
+    db::CameraChange {
+        short_name: "test camera".to_owned(),
+        description: "".to_owned(),
+        onvif_host: "test-camera".to_owned(),
+        username: "foo".to_owned(),
+        password: "bar".to_owned(),
+        ptz: false,
+        streams: [
+            db::StreamChange {
+                sample_file_dir_id,
+                rtsp_url: "rtsp://test-camera/main".to_owned(),
+                record: sample_file_dir_id.is_some(),
+                ..Default::default()
+            },
+            Default::default(),
+        ],
+    }
+}
+
 pub struct TestDb<C: Clocks + Clone> {
     pub db: Arc<db::Database<C>>,
-    pub dirs_by_stream_id: Arc<FnvHashMap<i32, Arc<dir::SampleFileDir>>>,
-    pub syncer_channel: writer::SyncerChannel<::std::fs::File>,
+    pub dirs_by_stream_id: Arc<FnvHashMap<i32, dir::StreamDirs>>,
+    pub syncer_channel: writer::SyncerChannel<dir::SampleFileWriter>,
     pub syncer_join: thread::JoinHandle<()>,
     pub tmpdir: TempDir,
     pub test_camera_uuid: Uuid,
@@ -90,37 +134,17 @@ impl<C: Clocks + Clone> TestDb<C> {
     }
 
     pub(crate) fn new_with_flush_if_sec(clocks: C, flush_if_sec: i64) -> Self {
-        let tmpdir = TempDir::new("moonfire-nvr-test").unwrap();
-
-        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
-        db::init(&mut conn).unwrap();
-        let db = Arc::new(db::Database::new(clocks, conn, true).unwrap());
+        let (db, tmpdir, _) = new_db(clocks, 0);
+        let db = Arc::new(db);
         let (test_camera_uuid, sample_file_dir_id);
         let path = tmpdir.path().to_str().unwrap().to_owned();
         let dir;
         {
             let mut l = db.lock();
             sample_file_dir_id = l.add_sample_file_dir(path.to_owned()).unwrap();
-            assert_eq!(
-                TEST_CAMERA_ID,
-                l.add_camera(db::CameraChange {
-                    short_name: "test camera".to_owned(),
-                    description: "".to_owned(),
-                    onvif_host: "test-camera".to_owned(),
-                    username: "foo".to_owned(),
-                    password: "bar".to_owned(),
-                    streams: [
-                        db::StreamChange {
-                            sample_file_dir_id: Some(sample_file_dir_id),
-                            rtsp_url: "rtsp://test-camera/main".to_owned(),
-                            record: true,
-                            flush_if_sec,
-                        },
-                        Default::default(),
-                    ],
-                })
-                .unwrap()
-            );
+            let mut camera = test_camera(Some(sample_file_dir_id));
+            camera.streams[0].flush_if_sec = flush_if_sec;
+            assert_eq!(TEST_CAMERA_ID, l.add_camera(camera).unwrap());
             test_camera_uuid = l.cameras_by_id().get(&TEST_CAMERA_ID).unwrap().uuid;
             l.update_retention(&[db::RetentionChange {
                 stream_id: TEST_STREAM_ID,
@@ -136,9 +160,15 @@ impl<C: Clocks + Clone> TestDb<C> {
                 .unwrap();
         }
         let mut dirs_by_stream_id = FnvHashMap::default();
-        dirs_by_stream_id.insert(TEST_STREAM_ID, dir.clone());
+        dirs_by_stream_id.insert(
+            TEST_STREAM_ID,
+            dir::StreamDirs {
+                stripes: vec![dir.clone()],
+                failover: None,
+            },
+        );
         let (syncer_channel, syncer_join) =
-            writer::start_syncer(db.clone(), sample_file_dir_id).unwrap();
+            writer::start_syncer(db.clone(), sample_file_dir_id, || {}).unwrap();
         TestDb {
             db,
             dirs_by_stream_id: Arc::new(dirs_by_stream_id),
@@ -0,0 +1,219 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Per-interval summaries of recordings and detections, for rendering timelines and calendars
+//! without listing every recording.
+//!
+//! Buckets are `bucket` long, starting at the beginning of the requested range. Each recording
+//! and detection is counted entirely in the bucket in which it starts, even if it extends into
+//! the next.
+
+use crate::db::CompositeId;
+use crate::recording;
+use failure::Error;
+use rusqlite::{named_params, Connection};
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+const RECORDINGS_SQL: &'static str = r#"
+    select
+      (start_time_90k - :start_time_90k) / :bucket_90k as bucket,
+      count(*),
+      sum(duration_90k),
+      sum(sample_file_bytes)
+    from
+      recording
+    where
+      stream_id = :stream_id and
+      start_time_90k >= :start_time_90k and
+      start_time_90k < :end_time_90k
+    group by
+      bucket
+"#;
+
+const DETECTIONS_SQL: &'static str = r#"
+    select
+      (start_time_90k - :start_time_90k) / :bucket_90k as bucket,
+      count(*)
+    from
+      detection
+    where
+      composite_id >= :start_id and
+      composite_id < :end_id and
+      start_time_90k >= :start_time_90k and
+      start_time_90k < :end_time_90k
+    group by
+      bucket
+"#;
+
+/// A row used in `LockedDatabase::list_timeline`.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Row {
+    pub stream_id: i32,
+
+    /// The start of the bucket.
+    pub start: recording::Time,
+
+    /// The number of recordings starting in this bucket.
+    pub recordings: i64,
+
+    /// The total duration of those recordings.
+    pub duration: recording::Duration,
+    pub sample_file_bytes: i64,
+
+    /// The number of detections starting in this bucket.
+    pub detections: i64,
+}
+
+/// Buckets of a single stream, keyed by index within the requested range.
+pub(crate) struct Buckets {
+    stream_id: i32,
+    time: Range<recording::Time>,
+    bucket: recording::Duration,
+    rows: BTreeMap<i64, Row>,
+}
+
+impl Buckets {
+    pub(crate) fn new(
+        stream_id: i32,
+        time: Range<recording::Time>,
+        bucket: recording::Duration,
+    ) -> Self {
+        Buckets {
+            stream_id,
+            time,
+            bucket,
+            rows: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, i: i64) -> &mut Row {
+        let (stream_id, start) = (
+            self.stream_id,
+            self.time.start + recording::Duration(i * self.bucket.0),
+        );
+        self.rows.entry(i).or_insert_with(|| Row {
+            stream_id,
+            start,
+            ..Default::default()
+        })
+    }
+
+    /// Adds the committed recordings and detections starting within the range.
+    pub(crate) fn add_committed(&mut self, conn: &Connection) -> Result<(), Error> {
+        let mut stmt = conn.prepare_cached(RECORDINGS_SQL)?;
+        let mut rows = stmt.query_named(named_params! {
+            ":stream_id": self.stream_id,
+            ":start_time_90k": self.time.start.0,
+            ":end_time_90k": self.time.end.0,
+            ":bucket_90k": self.bucket.0,
+        })?;
+        while let Some(row) = rows.next()? {
+            let r = self.get(row.get(0)?);
+            r.recordings += row.get::<_, i64>(1)?;
+            r.duration.0 += row.get::<_, i64>(2)?;
+            r.sample_file_bytes += row.get::<_, i64>(3)?;
+        }
+        let mut stmt = conn.prepare_cached(DETECTIONS_SQL)?;
+        let mut rows = stmt.query_named(named_params! {
+            ":start_id": CompositeId::new(self.stream_id, 0).0,
+            ":end_id": CompositeId::new(self.stream_id + 1, 0).0,
+            ":start_time_90k": self.time.start.0,
+            ":end_time_90k": self.time.end.0,
+            ":bucket_90k": self.bucket.0,
+        })?;
+        while let Some(row) = rows.next()? {
+            self.get(row.get(0)?).detections += row.get::<_, i64>(1)?;
+        }
+        Ok(())
+    }
+
+    /// Adds an uncommitted recording, if it starts within the range.
+    pub(crate) fn add_recording(
+        &mut self,
+        start: recording::Time,
+        duration_90k: i32,
+        sample_file_bytes: i32,
+    ) {
+        if start < self.time.start || start >= self.time.end {
+            return;
+        }
+        let r = self.get((start - self.time.start).0 / self.bucket.0);
+        r.recordings += 1;
+        r.duration.0 += i64::from(duration_90k);
+        r.sample_file_bytes += i64::from(sample_file_bytes);
+    }
+
+    /// Passes the non-empty buckets to `f` in ascending order by time.
+    pub(crate) fn finish(self, f: &mut dyn FnMut(Row)) {
+        for (_, row) in self.rows {
+            f(row);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_recording() {
+        let t = |t| recording::Time(t);
+        let mut b = Buckets::new(1, t(100)..t(400), recording::Duration(100));
+        b.add_recording(t(50), 10, 1); // before the range.
+        b.add_recording(t(100), 150, 2);
+        b.add_recording(t(199), 10, 3);
+        b.add_recording(t(350), 10, 4);
+        b.add_recording(t(400), 10, 5); // after the range.
+        let mut rows = Vec::new();
+        b.finish(&mut |r| rows.push(r));
+        assert_eq!(
+            rows,
+            vec![
+                Row {
+                    stream_id: 1,
+                    start: t(100),
+                    recordings: 2,
+                    duration: recording::Duration(160),
+                    sample_file_bytes: 5,
+                    detections: 0,
+                },
+                Row {
+                    stream_id: 1,
+                    start: t(300),
+                    recordings: 1,
+                    duration: recording::Duration(10),
+                    sample_file_bytes: 4,
+                    detections: 0,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,187 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Estimates of the disk space and time an upgrade needs, checked before starting it.
+//!
+//! Running out of space partway through an upgrade leaves a half-upgraded database (and possibly
+//! journaled sample file renames) to recover from, so it's better to refuse to start.
+
+use failure::Error;
+use rusqlite::params;
+use std::fmt;
+
+/// Assumed rate at which SQLite rewrites the database when copying tables or vacuuming.
+/// Deliberately conservative (slow SD cards and hard disks) so the time estimate errs long.
+const ASSUMED_REWRITE_BYTES_PER_SEC: u64 = 10 << 20;
+
+/// Assumed rate of sample file renames.
+const ASSUMED_FILE_OPS_PER_SEC: i64 = 500;
+
+/// The estimated needs of upgrading from `from_ver` to `to_ver`.
+#[derive(Debug)]
+pub struct Estimate {
+    pub from_ver: i32,
+    pub to_ver: i32,
+
+    /// The current size of the database file.
+    pub db_bytes: u64,
+
+    /// The name and row count of each table.
+    pub tables: Vec<(String, i64)>,
+
+    /// The number of sample files the upgrade will rename.
+    pub sample_file_ops: i64,
+
+    /// The free space the upgrade needs on the database's filesystem: one copy of the database
+    /// for the rollback journal and duplicate tables (unless the journal is off) and one for the
+    /// final vacuum (unless skipped). See `guide/schema.md`.
+    pub bytes_needed: u64,
+
+    /// The free space available to unprivileged users on the database's filesystem.
+    pub bytes_free: u64,
+
+    /// A rough, pessimistic estimate of how long the upgrade will take.
+    pub duration: std::time::Duration,
+}
+
+impl Estimate {
+    /// Returns true iff there's enough free space to upgrade.
+    pub fn sufficient(&self) -> bool {
+        self.bytes_free >= self.bytes_needed
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Upgrade from version {} to version {}:",
+            self.from_ver, self.to_ver
+        )?;
+        writeln!(
+            f,
+            "  database size: {}",
+            base::strutil::encode_size(self.db_bytes as i64)
+        )?;
+        for (name, rows) in &self.tables {
+            writeln!(f, "    {}: {} rows", name, rows)?;
+        }
+        writeln!(f, "  sample files to rename: {}", self.sample_file_ops)?;
+        writeln!(
+            f,
+            "  free space needed: {} (available: {})",
+            base::strutil::encode_size(self.bytes_needed as i64),
+            base::strutil::encode_size(self.bytes_free as i64)
+        )?;
+        write!(
+            f,
+            "  estimated time: up to {} minutes",
+            (self.duration.as_secs() + 59) / 60
+        )
+    }
+}
+
+/// Estimates the needs of upgrading the database to `to_ver` with the given args.
+/// Returns `None` if the database is already at (or beyond) `to_ver`.
+pub fn estimate(
+    args: &super::Args,
+    conn: &rusqlite::Connection,
+    to_ver: i32,
+) -> Result<Option<Estimate>, Error> {
+    let from_ver: i32 =
+        conn.query_row("select max(id) from version", params![], |row| row.get(0))?;
+    if from_ver >= to_ver {
+        return Ok(None);
+    }
+    let page_count: i64 = conn.query_row("pragma page_count", params![], |row| row.get(0))?;
+    let page_size: i64 = conn.query_row("pragma page_size", params![], |row| row.get(0))?;
+    let db_bytes = (page_count * page_size) as u64;
+
+    let mut tables = Vec::new();
+    {
+        let mut stmt = conn.prepare(
+            r#"
+            select name from sqlite_master
+            where type = 'table' and name not like 'sqlite_%'
+            order by name
+            "#,
+        )?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let name: String = row.get(0)?;
+            let count: i64 = conn.query_row(
+                &format!("select count(*) from \"{}\"", name.replace('"', "\"\"")),
+                params![],
+                |row| row.get(0),
+            )?;
+            tables.push((name, count));
+        }
+    }
+    let rows_in = |table: &str| {
+        tables
+            .iter()
+            .find(|(n, _)| n == table)
+            .map(|&(_, c)| c)
+            .unwrap_or(0)
+    };
+
+    // Version 3 renames every sample file to its composite id.
+    let sample_file_ops = if from_ver < 3 {
+        rows_in("recording")
+    } else {
+        0
+    };
+
+    let mut copies = 0;
+    if args.preset_journal != "off" {
+        copies += 1;
+    }
+    if !args.no_vacuum {
+        copies += 1;
+    }
+    let bytes_needed = copies * db_bytes;
+    let stat = nix::sys::statvfs::statvfs(args.db_dir)?;
+    let bytes_free = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+
+    // Each step may rewrite the database, as may the vacuum.
+    let rewrites = (to_ver - from_ver) as u64 + if args.no_vacuum { 0 } else { 1 };
+    let secs = rewrites * db_bytes / ASSUMED_REWRITE_BYTES_PER_SEC
+        + (sample_file_ops / ASSUMED_FILE_OPS_PER_SEC) as u64;
+    Ok(Some(Estimate {
+        from_ver,
+        to_ver,
+        db_bytes,
+        tables,
+        sample_file_ops,
+        bytes_needed,
+        bytes_free,
+        duration: std::time::Duration::from_secs(secs),
+    }))
+}
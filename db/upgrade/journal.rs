@@ -0,0 +1,277 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+/// Journal of the sample file changes made by an upgrade step.
+///
+/// Each step's database changes happen in one SQLite transaction, but some steps also rename or
+/// unlink sample files, which can't be rolled back with it. If such a step is interrupted after
+/// changing files but before committing, the database is left at the old version while its
+/// directory is partly in the new layout. To make this detectable and recoverable, the step
+/// records its file changes in a journal in the database directory and syncs it before making
+/// them. The journal is removed once the transaction commits, so one found on startup either
+/// belongs to a step which committed (and can simply be removed) or one which didn't, which
+/// `upgrade --resume` finishes by replaying the journal then retrying the step.
+use crate::dir;
+use failure::{bail, format_err, Error};
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+pub(crate) const FILENAME: &str = "upgrade-journal";
+const HEADER: &str = "moonfire-nvr upgrade journal";
+
+/// A sample file change, relative to a directory.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Op {
+    Rename { from: String, to: String },
+    Unlink { name: String },
+}
+
+/// The journal for a single upgrade step, created on its first `record`.
+pub struct Journal<'a> {
+    db_dir: &'a Path,
+    from_ver: i32,
+    f: Option<BufWriter<fs::File>>,
+    cur_dir: Option<PathBuf>,
+}
+
+impl<'a> Journal<'a> {
+    pub(crate) fn new(db_dir: &'a Path, from_ver: i32) -> Self {
+        Journal {
+            db_dir,
+            from_ver,
+            f: None,
+            cur_dir: None,
+        }
+    }
+
+    /// Records that `op` will be done within `dir`. This isn't durable until `sync`.
+    pub(crate) fn record(&mut self, dir: &Path, op: &Op) -> Result<(), Error> {
+        if self.f.is_none() {
+            let mut f = BufWriter::new(fs::File::create(self.db_dir.join(FILENAME))?);
+            writeln!(&mut f, "{}", HEADER)?;
+            writeln!(&mut f, "upgrade {} {}", self.from_ver, self.from_ver + 1)?;
+            self.f = Some(f);
+        }
+        let f = self.f.as_mut().unwrap();
+        if self.cur_dir.as_ref().map(PathBuf::as_path) != Some(dir) {
+            let d = dir
+                .to_str()
+                .ok_or_else(|| format_err!("path {} is not valid UTF-8", dir.display()))?;
+            if d.contains('\n') {
+                bail!("path {:?} contains a newline", d);
+            }
+            writeln!(f, "dir {}", d)?;
+            self.cur_dir = Some(dir.to_owned());
+        }
+        match op {
+            Op::Rename { from, to } => writeln!(f, "rename {} {}", from, to)?,
+            Op::Unlink { name } => writeln!(f, "unlink {}", name)?,
+        }
+        Ok(())
+    }
+
+    /// Makes all recorded operations durable. Callers must call this before doing them.
+    pub(crate) fn sync(&mut self) -> Result<(), Error> {
+        if let Some(ref mut f) = self.f {
+            f.flush()?;
+            f.get_ref().sync_all()?;
+            dir::Fd::open(self.db_dir, false)?.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Removes the journal, if any. Call after the step's transaction commits.
+    ///
+    /// This also removes one left by an earlier, resumed attempt at the step.
+    pub(crate) fn finish(self) -> Result<(), Error> {
+        match fs::remove_file(self.db_dir.join(FILENAME)) {
+            Ok(()) => dir::Fd::open(self.db_dir, false)?.sync()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
+    }
+}
+
+/// A journal left behind by an earlier run.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Leftover {
+    pub(crate) from_ver: i32,
+    pub(crate) ops: Vec<(PathBuf, Op)>,
+}
+
+/// Reads the journal in `db_dir`, if any.
+pub(crate) fn read(db_dir: &Path) -> Result<Option<Leftover>, Error> {
+    let data = match fs::read_to_string(db_dir.join(FILENAME)) {
+        Ok(d) => d,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    parse(&data).map(Some)
+}
+
+fn parse(data: &str) -> Result<Leftover, Error> {
+    let mut lines = data.split('\n');
+    if lines.next() != Some(HEADER) {
+        bail!("{} is not an upgrade journal", FILENAME);
+    }
+    let from_ver = match lines
+        .next()
+        .map(|l| l.split(' ').collect::<Vec<_>>())
+        .as_deref()
+    {
+        Some(["upgrade", from, to]) => {
+            let from: i32 = from.parse()?;
+            if to.parse::<i32>()? != from + 1 {
+                bail!("{} doesn't describe a single upgrade step", FILENAME);
+            }
+            from
+        }
+        _ => bail!("{} is missing its version line", FILENAME),
+    };
+    let mut ops = Vec::new();
+    let mut cur_dir = None;
+    let mut lines = lines.peekable();
+    while let Some(l) = lines.next() {
+        if lines.peek().is_none() {
+            // This is either empty or an incomplete line written before a crash. Either way,
+            // nothing was done based on it: operations are only done after they're synced.
+            break;
+        }
+        if l.starts_with("dir ") {
+            cur_dir = Some(PathBuf::from(&l[4..]));
+            continue;
+        }
+        let dir = cur_dir
+            .clone()
+            .ok_or_else(|| format_err!("{} has an operation before any dir", FILENAME))?;
+        let op = match l.split(' ').collect::<Vec<_>>()[..] {
+            ["rename", from, to] => Op::Rename {
+                from: from.to_owned(),
+                to: to.to_owned(),
+            },
+            ["unlink", name] => Op::Unlink {
+                name: name.to_owned(),
+            },
+            _ => bail!("{} has unparseable line {:?}", FILENAME, l),
+        };
+        ops.push((dir, op));
+    }
+    Ok(Leftover { from_ver, ops })
+}
+
+/// Redoes every operation in `leftover`, skipping those which were already done.
+pub(crate) fn replay(leftover: &Leftover) -> Result<(), Error> {
+    let mut i = 0;
+    while i < leftover.ops.len() {
+        let path = &leftover.ops[i].0;
+        let d = dir::Fd::open(path.as_path(), false)?;
+        let mut done = 0;
+        while i < leftover.ops.len() && &leftover.ops[i].0 == path {
+            let r = match &leftover.ops[i].1 {
+                Op::Rename { from, to } => nix::fcntl::renameat(
+                    Some(d.as_raw_fd()),
+                    from.as_str(),
+                    Some(d.as_raw_fd()),
+                    to.as_str(),
+                ),
+                Op::Unlink { name } => nix::unistd::unlinkat(
+                    Some(d.as_raw_fd()),
+                    name.as_str(),
+                    nix::unistd::UnlinkatFlags::NoRemoveDir,
+                ),
+            };
+            match r {
+                Ok(()) => done += 1,
+                Err(nix::Error::Sys(nix::errno::Errno::ENOENT)) => {} // already done.
+                Err(e) => return Err(e.into()),
+            }
+            i += 1;
+        }
+        d.sync()?;
+        info!(
+            "...redid {} interrupted operations in {}.",
+            done,
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn round_trip() {
+        testutil::init();
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let sample_dir = tmpdir.path().join("sample");
+        fs::create_dir(&sample_dir).unwrap();
+        fs::File::create(sample_dir.join("a")).unwrap();
+        fs::File::create(sample_dir.join("c")).unwrap();
+        let mut j = Journal::new(tmpdir.path(), 2);
+        let rename = Op::Rename {
+            from: "a".to_owned(),
+            to: "b".to_owned(),
+        };
+        let unlink = Op::Unlink {
+            name: "c".to_owned(),
+        };
+        j.record(&sample_dir, &rename).unwrap();
+        j.record(&sample_dir, &unlink).unwrap();
+        j.sync().unwrap();
+
+        // Simulate a crash after the rename but before the unlink.
+        fs::rename(sample_dir.join("a"), sample_dir.join("b")).unwrap();
+        drop(j);
+        let leftover = read(tmpdir.path()).unwrap().unwrap();
+        assert_eq!(
+            leftover,
+            Leftover {
+                from_ver: 2,
+                ops: vec![(sample_dir.clone(), rename), (sample_dir.clone(), unlink)],
+            }
+        );
+        replay(&leftover).unwrap();
+        assert!(sample_dir.join("b").exists());
+        assert!(!sample_dir.join("c").exists());
+    }
+
+    #[test]
+    fn ignores_incomplete_line() {
+        let l = parse("moonfire-nvr upgrade journal\nupgrade 4 5\ndir /d\nunlink x\nunl").unwrap();
+        assert_eq!(l.ops.len(), 1);
+    }
+}
@@ -33,29 +33,50 @@
 /// See `guide/schema.md` for more information.
 use crate::db;
 use failure::{bail, Error};
-use log::info;
+use tracing::info;
 use nix::NixPath;
 use rusqlite::params;
 use std::ffi::CStr;
 use std::io::Write;
+use std::path::Path;
 use uuid::Uuid;
 
+pub mod estimate;
+mod journal;
 mod v0_to_v1;
 mod v1_to_v2;
 mod v2_to_v3;
 mod v3_to_v4;
 mod v4_to_v5;
+mod v5_to_v6;
+
+mod v5_to_v4;
+mod v6_to_v5;
 
 const UPGRADE_NOTES: &'static str =
     concat!("upgraded using moonfire-db ", env!("CARGO_PKG_VERSION"));
 
 #[derive(Debug)]
 pub struct Args<'a> {
+    /// The database directory, which holds the journal of each step's sample file changes.
+    pub db_dir: &'a Path,
     pub sample_file_dir: Option<&'a std::path::Path>,
     pub preset_journal: &'a str,
     pub no_vacuum: bool,
 }
 
+/// The earliest version `downgrade` can reach. Older schemas require restoring a backup.
+pub const MIN_DOWNGRADE_VERSION: i32 = 4;
+
+#[derive(Debug)]
+pub struct DowngradeArgs<'a> {
+    pub preset_journal: &'a str,
+    pub no_vacuum: bool,
+
+    /// Discard data which has no representation in the target version, rather than failing.
+    pub discard_data: bool,
+}
+
 fn set_journal_mode(conn: &rusqlite::Connection, requested: &str) -> Result<(), Error> {
     assert!(!requested.contains(';')); // quick check for accidental sql injection.
     let actual = conn.query_row(
@@ -77,6 +98,7 @@ fn upgrade(args: &Args, target_ver: i32, conn: &mut rusqlite::Connection) -> Res
         v2_to_v3::run,
         v3_to_v4::run,
         v4_to_v5::run,
+        v5_to_v6::run,
     ];
 
     {
@@ -98,8 +120,9 @@ fn upgrade(args: &Args, target_ver: i32, conn: &mut rusqlite::Connection) -> Res
         set_journal_mode(&conn, args.preset_journal)?;
         for ver in old_ver..target_ver {
             info!("...from version {} to version {}", ver, ver + 1);
+            let mut journal = journal::Journal::new(args.db_dir, ver);
             let tx = conn.transaction()?;
-            upgraders[ver as usize](&args, &tx)?;
+            upgraders[ver as usize](&args, &tx, &mut journal)?;
             tx.execute(
                 r#"
                 insert into version (id, unix_time, notes)
@@ -108,12 +131,61 @@ fn upgrade(args: &Args, target_ver: i32, conn: &mut rusqlite::Connection) -> Res
                 params![ver + 1, UPGRADE_NOTES],
             )?;
             tx.commit()?;
+            journal.finish()?;
         }
     }
 
     Ok(())
 }
 
+/// Handles the journal left by an interrupted upgrade step, if any. See `journal.rs`.
+///
+/// The journal of a step which committed is simply removed. Otherwise, this fails unless `resume`
+/// is set, in which case it redoes the step's journaled sample file changes so `run` can retry it.
+pub fn recover(db_dir: &Path, conn: &rusqlite::Connection, resume: bool) -> Result<(), Error> {
+    let leftover = match journal::read(db_dir)? {
+        None => {
+            if resume {
+                info!("No interrupted upgrade to resume.");
+            }
+            return Ok(());
+        }
+        Some(l) => l,
+    };
+    let cur_ver: i32 =
+        conn.query_row("select max(id) from version", params![], |row| row.get(0))?;
+    if cur_ver > leftover.from_ver {
+        info!(
+            "Removing the journal of the completed upgrade from version {} to version {}.",
+            leftover.from_ver,
+            leftover.from_ver + 1
+        );
+        std::fs::remove_file(db_dir.join(journal::FILENAME))?;
+        return Ok(());
+    } else if cur_ver < leftover.from_ver {
+        bail!(
+            "Database is at version {}, but {} is from an upgrade from version {}. Was the \
+             database restored from a backup?",
+            cur_ver,
+            db_dir.join(journal::FILENAME).display(),
+            leftover.from_ver
+        );
+    } else if !resume {
+        bail!(
+            "The upgrade from version {} to version {} was interrupted after changing sample \
+             files. Run `moonfire-nvr upgrade --resume` to finish it.",
+            cur_ver,
+            cur_ver + 1
+        );
+    }
+    info!(
+        "Resuming the interrupted upgrade from version {} to version {}...",
+        cur_ver,
+        cur_ver + 1
+    );
+    journal::replay(&leftover)
+}
+
 pub fn run(args: &Args, conn: &mut rusqlite::Connection) -> Result<(), Error> {
     db::set_integrity_pragmas(conn)?;
     upgrade(args, db::EXPECTED_VERSION, conn)?;
@@ -135,6 +207,90 @@ pub fn run(args: &Args, conn: &mut rusqlite::Connection) -> Result<(), Error> {
     Ok(())
 }
 
+fn downgrade(
+    args: &DowngradeArgs,
+    target_ver: i32,
+    conn: &mut rusqlite::Connection,
+) -> Result<(), Error> {
+    // downgraders[i] takes version MIN_DOWNGRADE_VERSION + i + 1 to MIN_DOWNGRADE_VERSION + i.
+    let downgraders = [v5_to_v4::run, v6_to_v5::run];
+    assert_eq!(
+        downgraders.len(),
+        (db::EXPECTED_VERSION - MIN_DOWNGRADE_VERSION) as usize
+    );
+    let old_ver = conn.query_row("select max(id) from version", params![], |row| row.get(0))?;
+    if old_ver > db::EXPECTED_VERSION {
+        bail!(
+            "Database is at version {}, later than expected {}",
+            old_ver,
+            db::EXPECTED_VERSION
+        );
+    } else if target_ver < MIN_DOWNGRADE_VERSION {
+        bail!(
+            "Can't downgrade to version {}; the earliest supported is {}. Restore a backup \
+             instead.",
+            target_ver,
+            MIN_DOWNGRADE_VERSION
+        );
+    } else if target_ver > old_ver {
+        bail!(
+            "Database is at version {}, earlier than requested {}; use upgrade instead.",
+            old_ver,
+            target_ver
+        );
+    }
+    info!(
+        "Downgrading database from version {} to version {}...",
+        old_ver, target_ver
+    );
+    set_journal_mode(&conn, args.preset_journal)?;
+
+    // Downgraders rebuild tables, which is only possible with foreign key enforcement off. This
+    // pragma is a no-op within a transaction, so set it here and instead check the keys
+    // explicitly before committing each step.
+    conn.execute("pragma foreign_keys = off", params![])?;
+    for ver in (target_ver..old_ver).rev() {
+        info!("...from version {} to version {}", ver + 1, ver);
+        let tx = conn.transaction()?;
+        downgraders[(ver - MIN_DOWNGRADE_VERSION) as usize](&args, &tx)?;
+        {
+            let mut stmt = tx.prepare("pragma foreign_key_check")?;
+            let mut rows = stmt.query(params![])?;
+            if let Some(row) = rows.next()? {
+                let table: String = row.get(0)?;
+                let parent: String = row.get(2)?;
+                bail!(
+                    "Downgrade to version {} would violate a foreign key from {} to {}",
+                    ver,
+                    table,
+                    parent
+                );
+            }
+        }
+        tx.execute("delete from version where id > ?", params![ver])?;
+        tx.commit()?;
+    }
+    conn.execute("pragma foreign_keys = on", params![])?;
+    Ok(())
+}
+
+/// Downgrades the database schema to `target_ver`. See `guide/schema.md`.
+pub fn run_downgrade(
+    args: &DowngradeArgs,
+    target_ver: i32,
+    conn: &mut rusqlite::Connection,
+) -> Result<(), Error> {
+    db::set_integrity_pragmas(conn)?;
+    downgrade(args, target_ver, conn)?;
+    set_journal_mode(&conn, "wal")?;
+    if !args.no_vacuum {
+        info!("...vacuuming database after downgrade.");
+        conn.execute("vacuum", params![])?;
+    }
+    info!("...done.");
+    Ok(())
+}
+
 /// A uuid-based path, as used in version 0 and version 1 schemas.
 struct UuidPath([u8; 37]);
 
@@ -194,6 +350,7 @@ mod tests {
     fn upgrade_and_compare() -> Result<(), Error> {
         testutil::init();
         let tmpdir = tempdir::TempDir::new("moonfire-nvr-test")?;
+        let db_dir = tempdir::TempDir::new("moonfire-nvr-test")?;
         //let path = tmpdir.path().to_str().ok_or_else(|| format_err!("invalid UTF-8"))?.to_owned();
         let mut upgraded = new_conn()?;
         upgraded.execute_batch(include_str!("v0.sql"))?;
@@ -230,15 +387,29 @@ mod tests {
         std::fs::File::create(&rec1)?;
         std::fs::File::create(&garbage)?;
 
+        let args = Args {
+            db_dir: db_dir.path(),
+            sample_file_dir: Some(&tmpdir.path()),
+            preset_journal: "delete",
+            no_vacuum: false,
+        };
+        let e = estimate::estimate(&args, &upgraded, db::EXPECTED_VERSION)?.unwrap();
+        assert_eq!(e.from_ver, 0);
+        assert_eq!(e.sample_file_ops, 1);
+        assert_eq!(e.bytes_needed, 2 * e.db_bytes);
+        assert!(e.tables.contains(&("recording".to_owned(), 1)));
+
         for (ver, fresh_sql) in &[
             (1, Some(include_str!("v1.sql"))),
             (2, None), // transitional; don't compare schemas.
             (3, Some(include_str!("v3.sql"))),
             (4, None), // transitional; don't compare schemas.
-            (5, Some(include_str!("../schema.sql"))),
+            (5, Some(include_str!("v5.sql"))),
+            (6, Some(include_str!("../schema.sql"))),
         ] {
             upgrade(
                 &Args {
+                    db_dir: db_dir.path(),
                     sample_file_dir: Some(&tmpdir.path()),
                     preset_journal: "delete",
                     no_vacuum: false,
@@ -259,6 +430,8 @@ mod tests {
             }
         }
 
+        assert!(estimate::estimate(&args, &upgraded, db::EXPECTED_VERSION)?.is_none());
+
         // Check that recording files get renamed.
         assert!(!rec1.exists());
         assert!(tmpdir.path().join("0000000100000001").exists());
@@ -266,6 +439,40 @@ mod tests {
         // Check that garbage files get cleaned up.
         assert!(!garbage.exists());
 
+        // Check that the journal was removed after the steps which wrote it committed.
+        assert!(!db_dir.path().join(journal::FILENAME).exists());
+
+        // Downgrade as far as possible, then upgrade again.
+        for (ver, fresh_sql) in &[(5, include_str!("v5.sql")), (4, include_str!("v5.sql"))] {
+            downgrade(
+                &DowngradeArgs {
+                    preset_journal: "delete",
+                    no_vacuum: false,
+                    discard_data: false,
+                },
+                *ver,
+                &mut upgraded,
+            )
+            .context(format!("downgrading to version {}", ver))?;
+            compare(&upgraded, *ver, fresh_sql)?;
+        }
+        upgrade(
+            &Args {
+                db_dir: db_dir.path(),
+                sample_file_dir: Some(&tmpdir.path()),
+                preset_journal: "delete",
+                no_vacuum: false,
+            },
+            db::EXPECTED_VERSION,
+            &mut upgraded,
+        )
+        .context("re-upgrading")?;
+        compare(
+            &upgraded,
+            db::EXPECTED_VERSION,
+            include_str!("../schema.sql"),
+        )?;
+
         Ok(())
     }
 }
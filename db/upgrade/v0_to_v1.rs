@@ -32,11 +32,15 @@
 use crate::db;
 use crate::recording;
 use failure::Error;
-use log::warn;
 use rusqlite::params;
 use std::collections::HashMap;
+use tracing::warn;
 
-pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+pub fn run(
+    _args: &super::Args,
+    tx: &rusqlite::Transaction,
+    _journal: &mut super::journal::Journal,
+) -> Result<(), Error> {
     // These create statements match the schema.sql when version 1 was the latest.
     tx.execute_batch(
         r#"
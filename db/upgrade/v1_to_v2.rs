@@ -39,7 +39,11 @@ use rusqlite::params;
 use std::os::unix::io::AsRawFd;
 use uuid::Uuid;
 
-pub fn run(args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+pub fn run(
+    args: &super::Args,
+    tx: &rusqlite::Transaction,
+    _journal: &mut super::journal::Journal,
+) -> Result<(), Error> {
     let sample_file_path = args.sample_file_dir.ok_or_else(|| {
         format_err!(
             "--sample-file-dir required when upgrading from \
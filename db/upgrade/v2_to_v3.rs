@@ -31,6 +31,7 @@
 /// Upgrades a version 2 schema to a version 3 schema.
 /// Note that a version 2 schema is never actually used; so we know the upgrade from version 1 was
 /// completed, and possibly an upgrade from 2 to 3 is half-finished.
+use super::journal::{Journal, Op};
 use crate::db::{self, FromSqlUuid};
 use crate::dir;
 use crate::schema;
@@ -38,14 +39,17 @@ use failure::Error;
 use protobuf::prelude::MessageField;
 use rusqlite::params;
 use std::os::unix::io::AsRawFd;
+use std::path::Path;
 use std::sync::Arc;
 
-/// Opens the sample file dir.
+/// Opens the sample file dir, returning its path and the opened dir.
 ///
 /// Makes a couple simplifying assumptions valid for version 2:
 /// *   there's only one dir.
 /// *   it has a last completed open.
-fn open_sample_file_dir(tx: &rusqlite::Transaction) -> Result<Arc<dir::SampleFileDir>, Error> {
+fn open_sample_file_dir(
+    tx: &rusqlite::Transaction,
+) -> Result<(String, Arc<dir::SampleFileDir>), Error> {
     let (p, s_uuid, o_id, o_uuid, db_uuid): (String, FromSqlUuid, i32, FromSqlUuid, FromSqlUuid) =
         tx.query_row(
             r#"
@@ -75,11 +79,16 @@ fn open_sample_file_dir(tx: &rusqlite::Transaction) -> Result<Arc<dir::SampleFil
         open.id = o_id as u32;
         open.uuid.extend_from_slice(&o_uuid.0.as_bytes()[..]);
     }
-    dir::SampleFileDir::open(&p, &meta)
+    let d = dir::SampleFileDir::open(&p, &meta, None)?;
+    Ok((p, d))
 }
 
-pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
-    let d = open_sample_file_dir(&tx)?;
+pub fn run(
+    _args: &super::Args,
+    tx: &rusqlite::Transaction,
+    journal: &mut Journal,
+) -> Result<(), Error> {
+    let (path, d) = open_sample_file_dir(&tx)?;
     let mut stmt = tx.prepare(
         r#"
         select
@@ -90,16 +99,29 @@ pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error>
     "#,
     )?;
     let mut rows = stmt.query(params![])?;
+    let mut renames = Vec::new();
     while let Some(row) = rows.next()? {
         let id = db::CompositeId(row.get(0)?);
         let sample_file_uuid: FromSqlUuid = row.get(1)?;
-        let from_path = super::UuidPath::from(sample_file_uuid.0);
-        let to_path = crate::dir::CompositeIdPath::from(id);
+        let from = sample_file_uuid.0.to_hyphenated_ref().to_string();
+        let to = format!("{:016x}", id.0); // matches dir::CompositeIdPath.
+        let op = Op::Rename {
+            from: from.clone(),
+            to: to.clone(),
+        };
+        journal.record(Path::new(&path), &op)?;
+        renames.push((from, to));
+    }
+
+    // The renames can't be rolled back with the transaction, so make sure an interrupted upgrade
+    // can find out about them before doing any.
+    journal.sync()?;
+    for (from, to) in &renames {
         if let Err(e) = nix::fcntl::renameat(
             Some(d.fd.as_raw_fd()),
-            &from_path,
+            from.as_str(),
             Some(d.fd.as_raw_fd()),
-            &to_path,
+            to.as_str(),
         ) {
             if e == nix::Error::Sys(nix::errno::Errno::ENOENT) {
                 continue; // assume it was already moved.
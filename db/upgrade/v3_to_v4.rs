@@ -31,7 +31,11 @@
 /// Upgrades a version 3 schema to a version 4 schema.
 use failure::Error;
 
-pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+pub fn run(
+    _args: &super::Args,
+    tx: &rusqlite::Transaction,
+    _journal: &mut super::journal::Journal,
+) -> Result<(), Error> {
     // These create statements match the schema.sql when version 4 was the latest.
     tx.execute_batch(
         r#"
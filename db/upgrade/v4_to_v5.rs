@@ -32,17 +32,19 @@
 ///
 /// This just handles the directory meta files. If they're already in the new format, great.
 /// Otherwise, verify they are consistent with the database then upgrade them.
+use super::journal::{Journal, Op};
 use crate::db::FromSqlUuid;
 use crate::{dir, schema};
 use cstr::*;
 use failure::{bail, Error, Fail};
-use log::info;
 use nix::fcntl::{FlockArg, OFlag};
 use nix::sys::stat::Mode;
 use protobuf::{prelude::MessageField, Message};
 use rusqlite::params;
 use std::io::{Read, Write};
 use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use tracing::info;
 use uuid::Uuid;
 
 const FIXED_DIR_META_LEN: usize = 512;
@@ -105,8 +107,12 @@ fn maybe_upgrade_meta(dir: &dir::Fd, db_meta: &schema::DirMeta) -> Result<bool,
 /// at v5.
 ///
 /// Returns true if something was done (and thus a sync is needed).
-fn maybe_cleanup_garbage_uuids(dir: &dir::Fd) -> Result<bool, Error> {
-    let mut need_sync = false;
+fn maybe_cleanup_garbage_uuids(
+    path: &str,
+    dir: &dir::Fd,
+    journal: &mut Journal,
+) -> Result<bool, Error> {
+    let mut garbage = Vec::new();
     let mut dir2 = nix::dir::Dir::openat(
         dir.as_raw_fd(),
         ".",
@@ -122,20 +128,33 @@ fn maybe_cleanup_garbage_uuids(dir: &dir::Fd) -> Result<bool, Error> {
             Err(_) => continue,
         };
         if Uuid::parse_str(f_str).is_ok() {
-            info!("removing leftover garbage file {}", f_str);
-            nix::unistd::unlinkat(
-                Some(dir.as_raw_fd()),
-                f,
-                nix::unistd::UnlinkatFlags::NoRemoveDir,
+            journal.record(
+                Path::new(path),
+                &Op::Unlink {
+                    name: f_str.to_owned(),
+                },
             )?;
-            need_sync = true;
+            garbage.push(f_str.to_owned());
         }
     }
 
-    Ok(need_sync)
+    journal.sync()?;
+    for f in &garbage {
+        info!("removing leftover garbage file {}", f);
+        nix::unistd::unlinkat(
+            Some(dir.as_raw_fd()),
+            f.as_str(),
+            nix::unistd::UnlinkatFlags::NoRemoveDir,
+        )?;
+    }
+    Ok(!garbage.is_empty())
 }
 
-pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+pub fn run(
+    _args: &super::Args,
+    tx: &rusqlite::Transaction,
+    journal: &mut Journal,
+) -> Result<(), Error> {
     let db_uuid: FromSqlUuid =
         tx.query_row_and_then(r"select uuid from meta", params![], |row| row.get(0))?;
     let mut stmt = tx.prepare(
@@ -176,7 +195,7 @@ pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error>
         dir.lock(FlockArg::LockExclusiveNonblock)?;
 
         let mut need_sync = maybe_upgrade_meta(&dir, &db_meta)?;
-        if maybe_cleanup_garbage_uuids(&dir)? {
+        if maybe_cleanup_garbage_uuids(path, &dir, journal)? {
             need_sync = true;
         }
 
@@ -0,0 +1,98 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+/// Downgrades a version 5 schema to a version 4 schema.
+///
+/// The tables are unchanged; this just rewrites the directory meta files from the fixed-length,
+/// length-delimited format back to a bare `DirMeta` message.
+use crate::db::FromSqlUuid;
+use crate::{dir, schema};
+use cstr::*;
+use failure::{bail, Error};
+use nix::fcntl::{FlockArg, OFlag};
+use nix::sys::stat::Mode;
+use protobuf::Message;
+use rusqlite::params;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use tracing::info;
+
+pub fn run(_args: &super::DowngradeArgs, tx: &rusqlite::Transaction) -> Result<(), Error> {
+    let db_uuid: FromSqlUuid =
+        tx.query_row_and_then(r"select uuid from meta", params![], |row| row.get(0))?;
+    let mut stmt = tx.prepare(r"select path, uuid from sample_file_dir")?;
+    let mut rows = stmt.query(params![])?;
+    while let Some(row) = rows.next()? {
+        let path = row.get_raw_checked(0)?.as_str()?;
+        let dir_uuid: FromSqlUuid = row.get(1)?;
+        let dir = dir::Fd::open(path, false)?;
+        dir.lock(FlockArg::LockExclusiveNonblock)?;
+        let dir_meta = dir::read_meta(&dir)?;
+        if dir_meta.db_uuid != &db_uuid.0.as_bytes()[..]
+            || dir_meta.dir_uuid != &dir_uuid.0.as_bytes()[..]
+        {
+            bail!(
+                "Inconsistent dir_meta={:?} for dir {} with uuid {} in db {}",
+                &dir_meta,
+                path,
+                dir_uuid.0,
+                db_uuid.0
+            );
+        }
+        write_old_meta(&dir, &dir_meta)?;
+        dir.sync()?;
+        info!("done with path: {}", path);
+    }
+    Ok(())
+}
+
+/// Atomically replaces the `meta` file with a bare (not length-delimited, unpadded) message.
+fn write_old_meta(dir: &dir::Fd, dir_meta: &schema::DirMeta) -> Result<(), Error> {
+    let tmp_path = cstr!("meta.tmp");
+    let meta_path = cstr!("meta");
+    let mut f = crate::fs::openat(
+        dir.as_raw_fd(),
+        tmp_path,
+        OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_WRONLY,
+        Mode::S_IRUSR | Mode::S_IWUSR,
+    )?;
+    let data = dir_meta
+        .write_to_bytes()
+        .expect("proto3->vec is infallible");
+    f.write_all(&data)?;
+    f.sync_all()?;
+    nix::fcntl::renameat(
+        Some(dir.as_raw_fd()),
+        tmp_path,
+        Some(dir.as_raw_fd()),
+        meta_path,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,296 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+/// Upgrades a version 5 schema to a version 6 schema.
+use failure::Error;
+use rusqlite::params;
+use tracing::info;
+
+pub fn run(
+    _args: &super::Args,
+    tx: &rusqlite::Transaction,
+    _journal: &mut super::journal::Journal,
+) -> Result<(), Error> {
+    // These create statements match the schema.sql when version 6 was the latest.
+    tx.execute_batch(
+        r#"
+        create table detection (
+          id integer primary key,
+          composite_id integer not null references recording (composite_id),
+          start_time_90k integer not null,
+          end_time_90k integer not null check (end_time_90k > start_time_90k),
+          label text not null check (length(label) > 0),
+          confidence real check (confidence >= 0 and confidence <= 1),
+          source text
+        );
+
+        create index detection_label on detection (label, start_time_90k);
+        create index detection_composite_id on detection (composite_id);
+
+        alter table camera add column ptz integer not null default 0 check (ptz in (1, 0));
+
+        create table config (
+          key text primary key,
+          value text not null
+        ) without rowid;
+
+        create table stream_stripe (
+          stream_id integer not null references stream (id),
+          idx integer not null check (idx > 0),
+          sample_file_dir_id integer not null references sample_file_dir (id),
+          primary key (stream_id, idx)
+        ) without rowid;
+
+        create table virtual_stream (
+          stream_id integer primary key references stream (id),
+          source_stream_id integer not null references stream (id),
+          crop_x integer not null check (crop_x >= 0),
+          crop_y integer not null check (crop_y >= 0),
+          crop_width integer not null check (crop_width > 0),
+          crop_height integer not null check (crop_height > 0),
+          width integer not null check (width > 0),
+          height integer not null check (height > 0),
+          check (source_stream_id != stream_id)
+        );
+
+        create table note (
+          id integer primary key,
+          camera_id integer references camera (id),
+          start_time_90k integer not null,
+          end_time_90k integer not null check (end_time_90k >= start_time_90k),
+          creation_time_sec integer not null,
+          author text,
+          text text not null check (length(text) > 0)
+        );
+
+        create index note_start_time_90k on note (start_time_90k);
+
+        create table bookmark (
+          id integer primary key,
+          camera_id integer references camera (id),
+          start_time_90k integer not null,
+          end_time_90k integer not null check (end_time_90k > start_time_90k),
+          label text not null check (length(label) > 0),
+          creation_time_sec integer not null,
+          author text
+        );
+
+        create index bookmark_start_time_90k on bookmark (start_time_90k);
+
+        create table export_job (
+          id integer primary key,
+          creation_time_sec integer not null,
+          author text,
+          start_time_90k integer not null,
+          end_time_90k integer not null check (end_time_90k > start_time_90k),
+          stream_ids text not null check (length(stream_ids) > 0),
+          sign integer not null default 0 check (sign in (0, 1)),
+          mask integer not null default 0 check (mask in (0, 1)),
+          state integer not null check (state between 0 and 4),
+          progress_bytes integer not null default 0,
+          total_bytes integer,
+          file_name text,
+          error text,
+          finish_time_sec integer
+        );
+
+        create virtual table camera_fts using fts5 (
+          short_name, description, content = 'camera', content_rowid = 'id'
+        );
+
+        create trigger camera_fts_insert after insert on camera begin
+          insert into camera_fts (rowid, short_name, description)
+                          values (new.id, new.short_name, new.description);
+        end;
+
+        create trigger camera_fts_delete after delete on camera begin
+          insert into camera_fts (camera_fts, rowid, short_name, description)
+                          values ('delete', old.id, old.short_name, old.description);
+        end;
+
+        create trigger camera_fts_update after update of short_name, description on camera begin
+          insert into camera_fts (camera_fts, rowid, short_name, description)
+                          values ('delete', old.id, old.short_name, old.description);
+          insert into camera_fts (rowid, short_name, description)
+                          values (new.id, new.short_name, new.description);
+        end;
+
+        create virtual table note_fts using fts5 (
+          text, content = 'note', content_rowid = 'id'
+        );
+
+        create trigger note_fts_insert after insert on note begin
+          insert into note_fts (rowid, text) values (new.id, new.text);
+        end;
+
+        create trigger note_fts_delete after delete on note begin
+          insert into note_fts (note_fts, rowid, text) values ('delete', old.id, old.text);
+        end;
+
+        create virtual table detection_fts using fts5 (
+          label, content = 'detection', content_rowid = 'id'
+        );
+
+        create trigger detection_fts_insert after insert on detection begin
+          insert into detection_fts (rowid, label) values (new.id, new.label);
+        end;
+
+        create trigger detection_fts_delete after delete on detection begin
+          insert into detection_fts (detection_fts, rowid, label)
+                             values ('delete', old.id, old.label);
+        end;
+
+        insert into camera_fts (camera_fts) values ('rebuild');
+
+        alter table sample_file_dir add column unhealthy_since_sec integer;
+        alter table stream add column failover_sample_file_dir_id integer
+            references sample_file_dir (id);
+        alter table stream add column flush_if_bytes integer not null default 0
+            check (flush_if_bytes >= 0);
+        alter table stream add column input_options text not null default '';
+        alter table stream add column rtsp_transport text not null default 'tcp'
+            check (rtsp_transport in ('tcp', 'udp'));
+        alter table stream add column reconnect_min_sec integer not null default 1
+            check (reconnect_min_sec > 0);
+        alter table stream add column reconnect_max_sec integer not null default 1
+            check (reconnect_max_sec >= reconnect_min_sec);
+        alter table stream add column session_timeout_sec integer not null default 10
+            check (session_timeout_sec > 0);
+        alter table stream add column privacy_mask text not null default '';
+
+        alter table recording_integrity add column sample_file_blake3 blob
+            check (length(sample_file_blake3) = 32);
+        alter table recording_integrity add column rtp_timestamp integer;
+        alter table recording_integrity add column rtp_clock_rate integer
+            check (rtp_clock_rate > 0);
+
+        alter table recording_playback add column flags integer not null default 0;
+
+        alter table video_sample_entry add column encoder text;
+
+        alter table user add column expiration_time_sec integer;
+
+        alter table user_session add column id integer;
+        update user_session set id = (select count(*) from user_session s
+                                      where s.session_id_hash <= user_session.session_id_hash);
+        create unique index user_session_id on user_session (id);
+
+        create table corrupt_recording (
+          composite_id integer primary key references recording (composite_id),
+          detection_time_sec integer not null,
+          reason text not null
+        );
+
+        create table thumbnail (
+          composite_id integer primary key references recording (composite_id),
+          jpeg blob not null check (length(jpeg) > 0)
+        );
+
+        create table user_camera (
+          user_id integer not null references user (id),
+          camera_id integer not null references camera (id),
+          primary key (user_id, camera_id)
+        ) without rowid;
+
+        create table user_notification_policy (
+          user_id integer primary key references user (id),
+          webhook_url text,
+          quiet_start_min integer check (quiet_start_min >= 0 and quiet_start_min < 1440),
+          quiet_end_min integer check (quiet_end_min >= 0 and quiet_end_min < 1440),
+          offline_after_sec integer check (offline_after_sec > 0),
+          escalate_after_sec integer check (escalate_after_sec > 0),
+          escalate_email text,
+          check ((quiet_start_min is null) = (quiet_end_min is null)),
+          check ((escalate_after_sec is null) = (escalate_email is null))
+        );
+
+        create table playback_heat (
+          stream_id integer not null references stream (id),
+          start_time_90k integer not null check (start_time_90k % 324000000 = 0),
+          views integer not null check (views > 0),
+          primary key (stream_id, start_time_90k)
+        ) without rowid;
+
+        create table login_failure (
+          kind text not null check (kind in ('user', 'addr')),
+          key text not null,
+          failures integer not null check (failures > 0),
+          last_failure_sec integer not null,
+          locked_until_sec integer,
+          primary key (kind, key)
+        ) without rowid;
+
+        create table access_token (
+          id integer primary key,
+          token_hash blob unique not null check (length(token_hash) = 24),
+          user_id integer not null references user (id),
+          description text,
+          creation_time_sec integer not null,
+          revocation_time_sec integer,
+          last_use_time_sec integer,
+          use_count integer not null default 0,
+          permissions blob not null default X''
+        );
+        "#,
+    )?;
+    compress_video_indexes(tx)?;
+    Ok(())
+}
+
+/// Compresses existing large `video_index` blobs, as `raw::insert_recording` does for new ones.
+fn compress_video_indexes(tx: &rusqlite::Transaction) -> Result<(), Error> {
+    // Collect ids first rather than updating rows while a select on the same table is in progress.
+    let ids = tx
+        .prepare("select composite_id from recording_playback where length(video_index) >= 1024")?
+        .query_map(params![], |row| row.get(0))?
+        .collect::<Result<Vec<i64>, _>>()?;
+    let mut select =
+        tx.prepare("select video_index from recording_playback where composite_id = ?")?;
+    let mut update = tx.prepare(
+        "update recording_playback set video_index = ?, flags = 1 where composite_id = ?",
+    )?;
+    let (mut n, mut before, mut after) = (0, 0, 0);
+    for id in ids {
+        let video_index: Vec<u8> = select.query_row(params![id], |row| row.get(0))?;
+        let compressed = zstd::encode_all(&video_index[..], 0)?;
+        if compressed.len() >= video_index.len() {
+            continue;
+        }
+        n += 1;
+        before += video_index.len();
+        after += compressed.len();
+        update.execute(params![&compressed[..], id])?;
+    }
+    info!(
+        "...compressed {} video indexes from {} to {} bytes.",
+        n, before, after
+    );
+    Ok(())
+}
@@ -0,0 +1,328 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+/// Downgrades a version 6 schema to a version 5 schema.
+///
+/// Version 6 data with no version 5 equivalent (notes, detections, configuration, and so on) is
+/// discarded only if the caller allows it. Recordings a version 5 server couldn't find or read
+/// stop the downgrade.
+use failure::{bail, Error};
+use rusqlite::params;
+use tracing::{info, warn};
+
+pub fn run(args: &super::DowngradeArgs, tx: &rusqlite::Transaction) -> Result<(), Error> {
+    let unreadable: i64 = tx.query_row(
+        "select count(*) from recording where flags & ? != 0",
+        params![4 | 8], // Failover | Encrypted
+        |row| row.get(0),
+    )?;
+    if unreadable > 0 {
+        bail!(
+            "{} recordings are in failover directories or encrypted; version 5 can't read them. \
+             Delete them before downgrading.",
+            unreadable
+        );
+    }
+    let stripes: i64 = tx.query_row("select count(*) from stream_stripe", params![], |row| {
+        row.get(0)
+    })?;
+    if stripes > 0 {
+        bail!(
+            "{} stream stripes are configured; version 5 doesn't support striping. Remove them \
+             before downgrading.",
+            stripes
+        );
+    }
+
+    let mut discarded = Vec::new();
+    for &(what, query) in &[
+        ("notes", "select count(*) from note"),
+        ("bookmarks", "select count(*) from bookmark"),
+        ("export jobs", "select count(*) from export_job"),
+        ("detections", "select count(*) from detection"),
+        ("configuration entries", "select count(*) from config"),
+        (
+            "playback heatmap buckets",
+            "select count(*) from playback_heat",
+        ),
+        (
+            "notification policies",
+            "select count(*) from user_notification_policy",
+        ),
+        ("API tokens", "select count(*) from access_token"),
+        (
+            "camera restrictions (the restricted users will be disabled)",
+            "select count(*) from user where flags & 2 != 0",
+        ),
+        (
+            "corrupt recording reports",
+            "select count(*) from corrupt_recording",
+        ),
+        ("recording thumbnails", "select count(*) from thumbnail"),
+        (
+            "protected recordings",
+            "select count(*) from recording where flags & 2 != 0",
+        ),
+        (
+            "virtual stream definitions",
+            "select count(*) from virtual_stream",
+        ),
+        (
+            "stream flush_if_bytes settings",
+            "select count(*) from stream where flush_if_bytes != 0",
+        ),
+        (
+            "stream input options",
+            "select count(*) from stream where input_options != ''",
+        ),
+        (
+            "stream privacy masks",
+            "select count(*) from stream where privacy_mask != ''",
+        ),
+        (
+            "stream RTSP transport and reconnect settings",
+            "select count(*) from stream where rtsp_transport != 'tcp' or \
+             reconnect_min_sec != 1 or reconnect_max_sec != 1 or session_timeout_sec != 10",
+        ),
+        (
+            "failover directories",
+            "select count(*) from stream where failover_sample_file_dir_id is not null",
+        ),
+        (
+            "video sample entry encoders",
+            "select count(*) from video_sample_entry where encoder is not null",
+        ),
+        (
+            "recording RTP timestamps",
+            "select count(*) from recording_integrity where rtp_timestamp is not null",
+        ),
+    ] {
+        let n: i64 = tx.query_row(query, params![], |row| row.get(0))?;
+        if n > 0 {
+            discarded.push(format!("{} {}", n, what));
+        }
+    }
+    if !discarded.is_empty() {
+        if !args.discard_data {
+            bail!(
+                "Downgrading would discard {}; pass --discard-data to proceed anyway.",
+                discarded.join(", ")
+            );
+        }
+        warn!("Discarding {}.", discarded.join(", "));
+    }
+
+    decompress_video_indexes(tx)?;
+
+    // These create statements match the schema.sql when version 5 was the latest.
+    tx.execute_batch(
+        r#"
+        drop trigger camera_fts_insert;
+        drop trigger camera_fts_delete;
+        drop trigger camera_fts_update;
+        drop trigger note_fts_insert;
+        drop trigger note_fts_delete;
+        drop trigger detection_fts_insert;
+        drop trigger detection_fts_delete;
+        drop table camera_fts;
+        drop table note_fts;
+        drop table detection_fts;
+
+        drop table user_notification_policy;
+        drop table user_camera;
+        drop table access_token;
+        drop table login_failure;
+
+        -- Version 5 would grant camera-restricted users access to every camera.
+        update user set flags = (flags & ~2) | 1 where flags & 2 != 0;
+
+        -- Version 5 wouldn't expire guest users.
+        update user set flags = flags | 1 where expiration_time_sec is not null;
+        create table new_user (
+          id integer primary key,
+          username unique not null,
+          flags integer not null,
+          password_hash text,
+          password_id integer not null default 0,
+          password_failure_count integer not null default 0,
+          unix_uid integer,
+          permissions blob not null default X''
+        );
+        insert into new_user
+        select id, username, flags, password_hash, password_id, password_failure_count, unix_uid,
+               permissions from user;
+        drop table user;
+        alter table new_user rename to user;
+        drop table playback_heat;
+        drop table corrupt_recording;
+        drop table thumbnail;
+        drop table note;
+        drop table bookmark;
+        drop table export_job;
+        drop table stream_stripe;
+        drop table virtual_stream;
+        drop table config;
+        drop table detection;
+
+        create table new_sample_file_dir (
+          id integer primary key,
+          path text unique not null,
+          uuid blob unique not null check (length(uuid) = 16),
+          last_complete_open_id integer references open (id)
+        );
+        insert into new_sample_file_dir
+        select id, path, uuid, last_complete_open_id from sample_file_dir;
+        drop table sample_file_dir;
+        alter table new_sample_file_dir rename to sample_file_dir;
+
+        create table new_camera (
+          id integer primary key,
+          uuid blob unique not null check (length(uuid) = 16),
+          short_name text not null,
+          description text,
+          onvif_host text,
+          username text,
+          password text
+        );
+        insert into new_camera
+        select id, uuid, short_name, description, onvif_host, username, password from camera;
+        drop table camera;
+        alter table new_camera rename to camera;
+
+        create table new_stream (
+          id integer primary key,
+          camera_id integer not null references camera (id),
+          sample_file_dir_id integer references sample_file_dir (id),
+          type text not null check (type in ('main', 'sub')),
+          record integer not null check (record in (1, 0)),
+          rtsp_url text not null,
+          retain_bytes integer not null check (retain_bytes >= 0),
+          flush_if_sec integer not null,
+          next_recording_id integer not null check (next_recording_id >= 0),
+          unique (camera_id, type)
+        );
+        insert into new_stream
+        select id, camera_id, sample_file_dir_id, type, record, rtsp_url, retain_bytes,
+               flush_if_sec, next_recording_id from stream;
+        drop table stream;
+        alter table new_stream rename to stream;
+
+        create table new_recording_integrity (
+          composite_id integer primary key references recording (composite_id),
+          local_time_delta_90k integer,
+          local_time_since_open_90k integer,
+          wall_time_delta_90k integer,
+          sample_file_sha1 blob check (length(sample_file_sha1) <= 20)
+        );
+        insert into new_recording_integrity
+        select composite_id, local_time_delta_90k, local_time_since_open_90k,
+               wall_time_delta_90k, sample_file_sha1 from recording_integrity;
+        drop table recording_integrity;
+        alter table new_recording_integrity rename to recording_integrity;
+
+        create table new_video_sample_entry (
+          id integer primary key,
+          sha1 blob unique not null check (length(sha1) = 20),
+          width integer not null check (width > 0),
+          height integer not null check (height > 0),
+          rfc6381_codec text not null,
+          data blob not null check (length(data) > 86)
+        );
+        insert into new_video_sample_entry
+        select id, sha1, width, height, rfc6381_codec, data from video_sample_entry;
+        drop table video_sample_entry;
+        alter table new_video_sample_entry rename to video_sample_entry;
+
+        create table new_recording_playback (
+          composite_id integer primary key references recording (composite_id),
+          video_index blob not null check (length(video_index) > 0)
+        );
+        insert into new_recording_playback
+        select composite_id, video_index from recording_playback;
+        drop table recording_playback;
+        alter table new_recording_playback rename to recording_playback;
+
+        create table new_user_session (
+          session_id_hash blob primary key not null,
+          user_id integer references user (id) not null,
+          seed blob not null,
+          flags integer not null,
+          domain text,
+          description text,
+          creation_password_id integer,
+          creation_time_sec integer not null,
+          creation_user_agent text,
+          creation_peer_addr blob,
+          revocation_time_sec integer,
+          revocation_user_agent text,
+          revocation_peer_addr blob,
+          revocation_reason integer,
+          revocation_reason_detail text,
+          last_use_time_sec integer,
+          last_use_user_agent text,
+          last_use_peer_addr blob,
+          use_count not null default 0,
+          permissions blob not null default X''
+        ) without rowid;
+        insert into new_user_session
+        select session_id_hash, user_id, seed, flags, domain, description, creation_password_id,
+               creation_time_sec, creation_user_agent, creation_peer_addr, revocation_time_sec,
+               revocation_user_agent, revocation_peer_addr, revocation_reason,
+               revocation_reason_detail, last_use_time_sec, last_use_user_agent,
+               last_use_peer_addr, use_count, permissions from user_session;
+        drop table user_session;
+        alter table new_user_session rename to user_session;
+        create index user_session_uid on user_session (user_id);
+
+        update recording set flags = flags & 1; -- keep only TrailingZero.
+        "#,
+    )?;
+    Ok(())
+}
+
+/// Decompresses `video_index` blobs, which version 5 only understands in raw form.
+fn decompress_video_indexes(tx: &rusqlite::Transaction) -> Result<(), Error> {
+    let ids = tx
+        .prepare("select composite_id from recording_playback where flags & 1 != 0")?
+        .query_map(params![], |row| row.get(0))?
+        .collect::<Result<Vec<i64>, _>>()?;
+    let mut select =
+        tx.prepare("select video_index from recording_playback where composite_id = ?")?;
+    let mut update = tx.prepare(
+        "update recording_playback set video_index = ?, flags = 0 where composite_id = ?",
+    )?;
+    for &id in &ids {
+        let compressed: Vec<u8> = select.query_row(params![id], |row| row.get(0))?;
+        let video_index = zstd::decode_all(&compressed[..])?;
+        update.execute(params![&video_index[..], id])?;
+    }
+    info!("...decompressed {} video indexes.", ids.len());
+    Ok(())
+}
@@ -0,0 +1,286 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! io_uring-based sample file writing, enabled by the `io-uring` feature (Linux 5.6+).
+//!
+//! With many high-bitrate streams, the plain writer's one `write` syscall per frame adds up.
+//! `File` instead copies frames into buffers and hands each full buffer to the kernel as an
+//! io_uring write without waiting for it. `sync_all` queues the `fsync` behind the outstanding
+//! writes, so a recording costs a handful of `io_uring_enter` calls total. Any write which fails
+//! or comes up short is redone synchronously within `sync_all`, so errors are still reported
+//! there and it can be retried as with the plain writer.
+
+use io_uring::{opcode, squeue, types, IoUring};
+use std::cmp;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use tracing::warn;
+
+/// The size of each buffer handed to the kernel.
+const BUF_LEN: usize = 256 << 10;
+
+/// The maximum number of buffers being written at once, bounding memory use per file.
+const MAX_IN_FLIGHT: usize = 8;
+
+/// The submission queue size: room for every in-flight write plus the fsync.
+const RING_ENTRIES: u32 = 16;
+
+/// The `user_data` of the fsync operation; writes use their index in `File::in_flight`.
+const FSYNC: u64 = u64::max_value();
+
+pub(crate) struct File {
+    file: fs::File,
+    ring: IoUring,
+
+    /// The file offset at which `buf` starts.
+    pos: u64,
+
+    /// Data not yet handed to the kernel.
+    buf: Vec<u8>,
+
+    /// The offset and buffer of each write the kernel may be doing, indexed by `user_data`.
+    /// The buffers must not be dropped or modified until the write completes.
+    in_flight: Vec<Option<(u64, Vec<u8>)>>,
+
+    /// The offset and remaining data of writes which failed or came up short.
+    redo: Vec<(u64, Vec<u8>)>,
+
+    /// Empty buffers to reuse.
+    spare: Vec<Vec<u8>>,
+
+    fsync_result: Option<i32>,
+}
+
+impl File {
+    /// Wraps `file`, whose next write should be at `pos`. Returns `file` on failure, such as when
+    /// the kernel doesn't support io_uring, so the caller can fall back to plain writes.
+    pub(crate) fn new(file: fs::File, pos: u64) -> Result<Self, (fs::File, io::Error)> {
+        let ring = match IoUring::new(RING_ENTRIES) {
+            Ok(r) => r,
+            Err(e) => return Err((file, e)),
+        };
+        Ok(File {
+            file,
+            ring,
+            pos,
+            buf: Vec::with_capacity(BUF_LEN),
+            in_flight: (0..MAX_IN_FLIGHT).map(|_| None).collect(),
+            redo: Vec::new(),
+            spare: Vec::new(),
+            fsync_result: None,
+        })
+    }
+
+    pub(crate) fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.file.as_raw_fd()
+    }
+
+    /// As in `std::io::Write::write`. Errors from earlier writes aren't reported here.
+    pub(crate) fn write(&mut self, data: &[u8]) -> Result<usize, io::Error> {
+        let n = cmp::min(data.len(), BUF_LEN - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == BUF_LEN {
+            self.submit_buf()?;
+        }
+        Ok(n)
+    }
+
+    /// Hands `buf` to the kernel, first waiting for a free slot if necessary.
+    fn submit_buf(&mut self) -> Result<(), io::Error> {
+        let slot = loop {
+            if let Some(s) = self.in_flight.iter().position(Option::is_none) {
+                break s;
+            }
+            self.reap(1)?;
+        };
+        let next = self
+            .spare
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(BUF_LEN));
+        let buf = mem::replace(&mut self.buf, next);
+        let e = opcode::Write::new(
+            types::Fd(self.file.as_raw_fd()),
+            buf.as_ptr(),
+            buf.len() as u32,
+        )
+        .offset(self.pos as i64)
+        .build()
+        .user_data(slot as u64);
+        let len = buf.len() as u64;
+
+        // Moving the Vec into `in_flight` doesn't move its heap allocation, which the kernel
+        // reads until the write completes.
+        self.in_flight[slot] = Some((self.pos, buf));
+        self.pos += len;
+        self.push(e)?;
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    fn push(&mut self, e: squeue::Entry) -> Result<(), io::Error> {
+        // SAFETY: the buffer referenced by a write entry lives in `in_flight` until its
+        // completion is reaped; `Drop` waits for that.
+        unsafe { self.ring.submission().available().push(e) }
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "io_uring submission queue full"))
+    }
+
+    /// Waits for at least `want` completions, then processes all which are available.
+    fn reap(&mut self, want: usize) -> Result<(), io::Error> {
+        self.ring.submit_and_wait(want)?;
+        let completed: Vec<_> = self
+            .ring
+            .completion()
+            .available()
+            .map(|c| (c.user_data(), c.result()))
+            .collect();
+        for (user_data, result) in completed {
+            if user_data == FSYNC {
+                self.fsync_result = Some(result);
+                continue;
+            }
+            let (off, mut buf) = self.in_flight[user_data as usize]
+                .take()
+                .expect("completion should match an in-flight write");
+            if result < 0 {
+                warn!(
+                    "io_uring write at offset {} failed: {}; will retry",
+                    off,
+                    io::Error::from_raw_os_error(-result)
+                );
+                self.redo.push((off, buf));
+            } else if (result as usize) < buf.len() {
+                buf.drain(..result as usize);
+                self.redo.push((off + result as u64, buf));
+            } else {
+                buf.clear();
+                self.spare.push(buf);
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for everything written so far to reach the file (though not necessarily the disk),
+    /// so other file handles can read it.
+    pub(crate) fn flush(&mut self) -> Result<(), io::Error> {
+        if !self.buf.is_empty() {
+            self.submit_buf()?;
+        }
+        while self.in_flight.iter().any(Option::is_some) {
+            self.reap(1)?;
+        }
+        while let Some(&(off, ref data)) = self.redo.last() {
+            self.file.write_all_at(data, off)?;
+            self.redo.pop();
+        }
+        Ok(())
+    }
+
+    /// As in `std::fs::File::sync_all`. This may be retried on failure.
+    pub(crate) fn sync_all(&mut self) -> Result<(), io::Error> {
+        if !self.buf.is_empty() {
+            self.submit_buf()?;
+        }
+
+        // IO_DRAIN orders the fsync after the outstanding writes, so one wait covers both.
+        let e = opcode::Fsync::new(types::Fd(self.file.as_raw_fd()))
+            .build()
+            .flags(squeue::Flags::IO_DRAIN)
+            .user_data(FSYNC);
+        self.fsync_result = None;
+        self.push(e)?;
+        while self.fsync_result.is_none() || self.in_flight.iter().any(Option::is_some) {
+            self.reap(1)?;
+        }
+        if self.redo.is_empty() && self.fsync_result.unwrap() >= 0 {
+            return Ok(());
+        }
+
+        // Something went wrong. Fall back to synchronous syscalls, which report errors directly.
+        while let Some(&(off, ref data)) = self.redo.last() {
+            self.file.write_all_at(data, off)?;
+            self.redo.pop();
+        }
+        self.file.sync_all()
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        // The kernel may still be reading buffers in `in_flight`; they can't be freed until it's
+        // done. If waiting fails, leak them rather than risk corrupting memory.
+        while self.in_flight.iter().any(Option::is_some) {
+            if let Err(e) = self.reap(1) {
+                warn!(
+                    "unable to wait for io_uring writes; leaking their buffers: {}",
+                    e
+                );
+                mem::forget(mem::replace(&mut self.in_flight, Vec::new()));
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn write_and_sync() {
+        testutil::init();
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let path = tmpdir.path().join("f");
+        let f = fs::File::create(&path).unwrap();
+        let mut f = match File::new(f, 0) {
+            Ok(f) => f,
+            Err((_, e)) => {
+                warn!("skipping test; io_uring is unavailable: {}", e);
+                return;
+            }
+        };
+
+        // Write enough to fill every in-flight slot and then some, in odd-sized pieces.
+        let data: Vec<u8> = (0..(BUF_LEN * (MAX_IN_FLIGHT + 2) + 12_345))
+            .map(|i| i as u8)
+            .collect();
+        let mut written = 0;
+        while written < data.len() {
+            let end = cmp::min(written + 100_003, data.len());
+            written += f.write(&data[written..end]).unwrap();
+        }
+        f.sync_all().unwrap();
+        drop(f);
+        assert!(fs::read(&path).unwrap() == data);
+    }
+}
@@ -36,20 +36,24 @@ use crate::db::{self, CompositeId};
 use crate::dir;
 use crate::recording;
 use base::clock::{self, Clocks};
+use base::crypto;
+use base::strutil::encode_size;
+use base::{bail_t, ErrorKind};
 use failure::{bail, format_err, Error};
 use fnv::FnvHashMap;
-use log::{debug, trace, warn};
-use openssl::hash;
 use parking_lot::Mutex;
 use std::cmp;
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::io;
 use std::mem;
+use std::sync::atomic::{self, AtomicU64, AtomicUsize};
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration as StdDuration;
 use time::{Duration, Timespec};
+use tracing::{debug, error, info, trace, warn};
 
 pub trait DirWriter: 'static + Send {
     type File: FileWriter;
@@ -60,18 +64,27 @@ pub trait DirWriter: 'static + Send {
 }
 
 pub trait FileWriter: 'static {
-    /// As in `std::fs::File::sync_all`.
-    fn sync_all(&self) -> Result<(), io::Error>;
+    /// As in `std::fs::File::sync_all`, after writing any trailer. No writes may follow.
+    fn sync_all(&mut self) -> Result<(), io::Error>;
 
     /// As in `std::io::Writer::write`.
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error>;
+
+    /// Makes everything written so far readable; see `dir::SampleFileWriter::flush`.
+    fn flush(&mut self) -> Result<(), io::Error>;
+
+    /// Reserves space for the next `len` bytes; see `dir::SampleFileWriter::preallocate`.
+    fn preallocate(&mut self, len: u64) -> Result<(), io::Error>;
+
+    /// Returns true iff the file is encrypted; see `dir::SampleFileWriter::encrypted`.
+    fn encrypted(&self) -> bool;
 }
 
 impl DirWriter for Arc<dir::SampleFileDir> {
-    type File = ::std::fs::File;
+    type File = dir::SampleFileWriter;
 
     fn create_file(&self, id: CompositeId) -> Result<Self::File, nix::Error> {
-        dir::SampleFileDir::create_file(self, id)
+        dir::SampleFileDir::create_writer(self, id)
     }
     fn sync(&self) -> Result<(), nix::Error> {
         dir::SampleFileDir::sync(self)
@@ -81,12 +94,21 @@ impl DirWriter for Arc<dir::SampleFileDir> {
     }
 }
 
-impl FileWriter for ::std::fs::File {
-    fn sync_all(&self) -> Result<(), io::Error> {
-        self.sync_all()
+impl FileWriter for dir::SampleFileWriter {
+    fn sync_all(&mut self) -> Result<(), io::Error> {
+        dir::SampleFileWriter::sync_all(self)
     }
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
-        io::Write::write(self, buf)
+        dir::SampleFileWriter::write(self, buf)
+    }
+    fn flush(&mut self) -> Result<(), io::Error> {
+        dir::SampleFileWriter::flush(self)
+    }
+    fn preallocate(&mut self, len: u64) -> Result<(), io::Error> {
+        dir::SampleFileWriter::preallocate(self, len)
+    }
+    fn encrypted(&self) -> bool {
+        dir::SampleFileWriter::encrypted(self)
     }
 }
 
@@ -97,13 +119,64 @@ enum SyncerCommand<F> {
     Flush(mpsc::SyncSender<()>),
 }
 
+/// The number of commands which can be queued to a syncer before writers stall.
+const SYNCER_QUEUE_CAPACITY: usize = 64;
+
 /// A channel which can be used to send commands to the syncer.
 /// Can be cloned to allow multiple threads to send commands.
-pub struct SyncerChannel<F>(mpsc::Sender<SyncerCommand<F>>);
+///
+/// The channel is bounded. When the syncer falls behind (such as when the disk is stuck), writers
+/// stall in `Writer::close` rather than queueing an unbounded number of recordings in RAM.
+pub struct SyncerChannel<F> {
+    snd: mpsc::SyncSender<SyncerCommand<F>>,
+    stats: Arc<QueueStats>,
+}
 
 impl<F> ::std::clone::Clone for SyncerChannel<F> {
     fn clone(&self) -> Self {
-        SyncerChannel(self.0.clone())
+        SyncerChannel {
+            snd: self.snd.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+/// Statistics about a syncer's command queue, shared by its `SyncerChannel`s and the syncer.
+#[derive(Default)]
+struct QueueStats {
+    depth: AtomicUsize,
+    stalls: AtomicU64,
+    stalled_nanos: AtomicU64,
+}
+
+/// A snapshot of a syncer's command queue, as returned by `SyncerChannel::queue_status`.
+#[derive(Clone, Debug)]
+pub struct QueueStatus {
+    /// The number of commands waiting for the syncer.
+    pub depth: usize,
+    pub capacity: usize,
+
+    /// The number of times a writer has stalled waiting for room in the queue.
+    pub stalls: u64,
+
+    /// The total time writers have spent stalled.
+    pub stalled: StdDuration,
+}
+
+/// A handle for observing a syncer's command queue, as returned by `SyncerChannel::queue_monitor`.
+/// Unlike `SyncerChannel`, this is `Sync`, so it can be shared with (e.g.) the web server.
+#[derive(Clone)]
+pub struct QueueMonitor(Arc<QueueStats>);
+
+impl QueueMonitor {
+    /// Returns a snapshot of the syncer's command queue.
+    pub fn status(&self) -> QueueStatus {
+        QueueStatus {
+            depth: self.0.depth.load(atomic::Ordering::SeqCst),
+            capacity: SYNCER_QUEUE_CAPACITY,
+            stalls: self.0.stalls.load(atomic::Ordering::SeqCst),
+            stalled: StdDuration::from_nanos(self.0.stalled_nanos.load(atomic::Ordering::SeqCst)),
+        }
     }
 }
 
@@ -113,6 +186,7 @@ struct Syncer<C: Clocks + Clone, D: DirWriter> {
     dir: D,
     db: Arc<db::Database<C>>,
     planned_flushes: std::collections::BinaryHeap<PlannedFlush>,
+    stats: Arc<QueueStats>,
 }
 
 struct PlannedFlush {
@@ -163,37 +237,46 @@ impl Eq for PlannedFlush {}
 ///
 /// Returns a `SyncerChannel` which can be used to send commands (and can be cloned freely) and
 /// a `JoinHandle` for the syncer thread. Commands sent on the channel will be executed or retried
-/// forever. (TODO: provide some manner of pushback during retry.) At program shutdown, all
-/// `SyncerChannel` clones should be dropped and then the handle joined to allow all recordings to
-/// be persisted.
+/// forever; senders stall while the channel is full. At program shutdown, all `SyncerChannel`
+/// clones should be dropped and then the handle joined to allow all recordings to be persisted.
 ///
 /// Note that dropping all `SyncerChannel` clones currently includes calling
 /// `LockedDatabase::clear_on_flush`, as this function installs a hook to watch database flushes.
 /// TODO: add a join wrapper which arranges for the on flush hook to be removed automatically.
-pub fn start_syncer<C>(
+///
+/// `on_start` is called from the syncer thread before it does anything else, such as to set its
+/// scheduling priority.
+pub fn start_syncer<C, F>(
     db: Arc<db::Database<C>>,
     dir_id: i32,
-) -> Result<(SyncerChannel<::std::fs::File>, thread::JoinHandle<()>), Error>
+    on_start: F,
+) -> Result<(SyncerChannel<dir::SampleFileWriter>, thread::JoinHandle<()>), Error>
 where
     C: Clocks + Clone,
+    F: FnOnce() + Send + 'static,
 {
     let db2 = db.clone();
     let (mut syncer, path) = Syncer::new(&db.lock(), db2, dir_id)?;
     syncer.initial_rotation()?;
-    let (snd, rcv) = mpsc::channel();
+    let (snd, rcv) = mpsc::sync_channel(SYNCER_QUEUE_CAPACITY);
+    let channel = SyncerChannel {
+        snd,
+        stats: syncer.stats.clone(),
+    };
     db.lock().on_flush(Box::new({
-        let snd = snd.clone();
-        move || {
-            if let Err(e) = snd.send(SyncerCommand::DatabaseFlushed) {
-                warn!("Unable to notify syncer for dir {} of flush: {}", dir_id, e);
-            }
-        }
+        let channel = channel.clone();
+        move || channel.database_flushed(dir_id)
     }));
     Ok((
-        SyncerChannel(snd),
+        channel,
         thread::Builder::new()
             .name(format!("sync-{}", path))
-            .spawn(move || while syncer.iter(&rcv) {})
+            .spawn(move || {
+                let span = tracing::info_span!("syncer", dir_id);
+                let _enter = span.enter();
+                on_start();
+                while syncer.iter(&rcv) {}
+            })
             .unwrap(),
     ))
 }
@@ -213,25 +296,75 @@ pub fn lower_retention(
 ) -> Result<(), Error> {
     let db2 = db.clone();
     let (mut syncer, _) = Syncer::new(&db.lock(), db2, dir_id)?;
-    syncer.do_rotation(|db| {
-        for l in limits {
-            let (fs_bytes_before, extra);
-            {
-                let stream = db
-                    .streams_by_id()
-                    .get(&l.stream_id)
-                    .ok_or_else(|| format_err!("no such stream {}", l.stream_id))?;
-                fs_bytes_before =
-                    stream.fs_bytes + stream.fs_bytes_to_add - stream.fs_bytes_to_delete;
-                extra = stream.retain_bytes - l.limit;
-            }
-            if l.limit >= fs_bytes_before {
-                continue;
-            }
-            delete_recordings(db, l.stream_id, extra)?;
+    syncer.do_rotation(|db| queue_retention_deletions(db, limits))?;
+
+    // Recordings of striped streams or in failover directories may have landed in other
+    // directories' garbage; unlink them now rather than waiting for those directories' syncers to
+    // next start.
+    let other_dir_ids: Vec<i32> = {
+        let l = db.lock();
+        let mut ids: Vec<i32> = limits
+            .iter()
+            .filter_map(|limit| l.streams_by_id().get(&limit.stream_id))
+            .flat_map(|s| s.dir_ids().into_iter().chain(s.failover_sample_file_dir_id))
+            .filter(|&id| id != dir_id)
+            .collect();
+        ids.sort();
+        ids.dedup();
+        ids
+    };
+    for other_dir_id in other_dir_ids {
+        let (mut syncer, _) = Syncer::new(&db.lock(), db.clone(), other_dir_id)?;
+        syncer.do_rotation(|_| Ok(()))?;
+    }
+    Ok(())
+}
+
+/// Returns what `lower_retention` would delete for the given limits, by stream id, without
+/// deleting anything. This includes sub stream recordings deleted in lock-step with their main
+/// streams. Every stream in `limits` has an entry, even if it would lose nothing.
+pub fn preview_lower_retention(
+    db: &mut db::LockedDatabase,
+    limits: &[NewLimit],
+) -> Result<BTreeMap<i32, db::StreamDeletionPreview>, Error> {
+    let mut previews = db.preview_deletions(&mut |db| queue_retention_deletions(db, limits))?;
+    for l in limits {
+        if previews.contains_key(&l.stream_id) {
+            continue;
         }
-        Ok(())
-    })
+        previews.insert(
+            l.stream_id,
+            db::StreamDeletionPreview {
+                oldest_remaining: db.retained_range(l.stream_id)?.map(|r| r.start),
+                ..Default::default()
+            },
+        );
+    }
+    Ok(previews)
+}
+
+/// Queues deletion of the recordings needed to fit within `limits`, for `lower_retention` and
+/// `preview_lower_retention`.
+fn queue_retention_deletions(
+    db: &mut db::LockedDatabase,
+    limits: &[NewLimit],
+) -> Result<(), Error> {
+    for l in limits {
+        let (fs_bytes_before, extra);
+        {
+            let stream = db
+                .streams_by_id()
+                .get(&l.stream_id)
+                .ok_or_else(|| format_err!("no such stream {}", l.stream_id))?;
+            fs_bytes_before = stream.fs_bytes + stream.fs_bytes_to_add - stream.fs_bytes_to_delete;
+            extra = stream.retain_bytes - l.limit;
+        }
+        if l.limit >= fs_bytes_before {
+            continue;
+        }
+        delete_recordings(db, l.stream_id, extra)?;
+    }
+    Ok(())
 }
 
 /// Deletes recordings to bring a stream's disk usage within bounds.
@@ -255,7 +388,7 @@ fn delete_recordings(
             stream_id,
             base::strutil::encode_size(-fs_bytes_needed)
         );
-        return Ok(());
+        return delete_sub_recordings(db, stream_id);
     }
     let mut n = 0;
     db.delete_oldest_recordings(stream_id, &mut |row| {
@@ -266,16 +399,249 @@ fn delete_recordings(
         }
         false
     })?;
+    delete_sub_recordings(db, stream_id)
+}
+
+/// If `LockedDatabase::set_sub_retention_multiple` is in effect and the given stream is a main
+/// stream, deletes the recordings of its camera's sub stream which end before the allowed
+/// multiple of the main stream's retained history.
+fn delete_sub_recordings(db: &mut db::LockedDatabase, stream_id: i32) -> Result<(), Error> {
+    let multiple = match db.sub_retention_multiple() {
+        None => return Ok(()),
+        Some(m) => m,
+    };
+    let (sub_id, cutoff) = {
+        let main = match db.streams_by_id().get(&stream_id) {
+            None => bail!("no stream {}", stream_id),
+            Some(s) => s,
+        };
+        if main.type_ != db::StreamType::MAIN {
+            return Ok(());
+        }
+        let sub_id = match db.cameras_by_id().get(&main.camera_id).unwrap().streams
+            [db::StreamType::SUB.index()]
+        {
+            None => return Ok(()),
+            Some(id) => id,
+        };
+
+        // Without any retained main stream history, leave the sub stream alone rather than
+        // deleting all of its recordings.
+        let retained = match db.retained_range(stream_id)? {
+            None => return Ok(()),
+            Some(r) => r,
+        };
+        let span = ((retained.end - retained.start).0 as f64 * multiple) as i64;
+        (sub_id, retained.end - recording::Duration(span))
+    };
+    let mut n = 0;
+    db.delete_oldest_recordings(sub_id, &mut |row| {
+        if row.start + recording::Duration(i64::from(row.duration)) <= cutoff {
+            n += 1;
+            return true;
+        }
+        false
+    })?;
+    if n > 0 {
+        debug!(
+            "{}: deleting {} recordings in lock-step with main stream {}",
+            sub_id, n, stream_id
+        );
+    }
+    Ok(())
+}
+
+/// The most to delete from a stream in response to a single `ENOSPC` before retrying the write.
+const EMERGENCY_DELETE_CHUNK_BYTES: i64 = 64 << 20;
+
+/// The number of consecutive failures to create or write a sample file after which a stream with
+/// a failover directory marks its usual directory unhealthy and fails over.
+const FAILOVER_ATTEMPTS: usize = 3;
+
+/// How long after a directory is marked unhealthy that streams try it again.
+const FAILOVER_RETRY_SEC: i64 = 600;
+
+/// Tries to create the given recording's sample file in `dir`, giving up after
+/// `FAILOVER_ATTEMPTS` failures.
+fn create_file_with_attempts<C: Clocks, D: DirWriter>(
+    clocks: &C,
+    dir: &D,
+    id: CompositeId,
+) -> Option<D::File> {
+    for attempt in 1..=FAILOVER_ATTEMPTS {
+        match dir.create_file(id) {
+            Ok(f) => return Some(f),
+            Err(e) => warn!(
+                "{}: unable to create sample file (attempt {}/{}): {}",
+                id, attempt, FAILOVER_ATTEMPTS, e
+            ),
+        }
+        if attempt < FAILOVER_ATTEMPTS {
+            clocks.sleep(Duration::seconds(1));
+        }
+    }
+    None
+}
+
+/// Under `DiskFullPolicy::EmergencyDelete`, schedules deletion of the stream's oldest recordings
+/// beyond its `retain_bytes`, leaving the stream with no less than `retain_bytes - max_bytes`.
+/// Returns the (rounded-up) number of bytes to be freed, which is 0 if nothing could be deleted.
+fn emergency_delete(
+    db: &mut db::LockedDatabase,
+    stream_id: i32,
+    max_bytes: i64,
+) -> Result<i64, Error> {
+    let (mut remaining, floor) = {
+        let stream = match db.streams_by_id().get(&stream_id) {
+            None => bail!("no stream {}", stream_id),
+            Some(s) => s,
+        };
+        (
+            stream.fs_bytes + stream.fs_bytes_to_add - stream.fs_bytes_to_delete,
+            stream.retain_bytes - max_bytes,
+        )
+    };
+    let mut fs_bytes_to_delete = 0;
+    db.delete_oldest_recordings(stream_id, &mut |row| {
+        let bytes = db::round_up(i64::from(row.sample_file_bytes));
+        if fs_bytes_to_delete < EMERGENCY_DELETE_CHUNK_BYTES && remaining - bytes >= floor {
+            remaining -= bytes;
+            fs_bytes_to_delete += bytes;
+            return true;
+        }
+        false
+    })?;
+    Ok(fs_bytes_to_delete)
+}
+
+/// Writes all of `buf` to `f`, applying the database's `DiskFullPolicy` on `ENOSPC` and retrying
+/// other errors. If the stream is paused for lack of space, marks it as `disk_full` and fails with
+/// `ErrorKind::ResourceExhausted`, leaving the unwritten portion in `buf`. Other errors are retried
+/// forever unless `failover_from` is set; then after `FAILOVER_ATTEMPTS` consecutive failures,
+/// marks that directory unhealthy and fails with `ErrorKind::Unavailable`.
+fn write_all<C: Clocks + Clone, F: FileWriter>(
+    db: &db::Database<C>,
+    stream_id: i32,
+    failover_from: Option<i32>,
+    f: &mut F,
+    buf: &mut &[u8],
+) -> Result<(), Error> {
+    let mut failures = 0;
+    while !buf.is_empty() {
+        let e = match f.write(buf) {
+            Ok(written) => {
+                *buf = &buf[written..];
+                continue;
+            }
+            Err(e) => e,
+        };
+        if e.raw_os_error() == Some(libc::ENOSPC) {
+            let mut l = db.lock();
+            let freed = match *l.disk_full_policy() {
+                db::DiskFullPolicy::Pause => 0,
+                db::DiskFullPolicy::EmergencyDelete { max_bytes } => {
+                    emergency_delete(&mut l, stream_id, max_bytes)?
+                }
+            };
+            if freed == 0 {
+                l.set_disk_full(stream_id, true)?;
+                error!(
+                    "{}: sample file directory is full; pausing stream",
+                    stream_id
+                );
+                bail_t!(ResourceExhausted, "no space to write stream {}", stream_id);
+            }
+            warn!(
+                "{}: sample file directory is full; deleting {} of old recordings beyond \
+                 retention limit",
+                stream_id,
+                encode_size(freed)
+            );
+
+            // The syncer unlinks the deleted recordings' files after the flush.
+            l.flush("emergency deletion")?;
+        } else {
+            failures += 1;
+            if let (Some(dir_id), true) = (failover_from, failures >= FAILOVER_ATTEMPTS) {
+                error!(
+                    "{}: writes to sample file dir {} failed {} times; failing over: {}",
+                    stream_id, dir_id, failures, e
+                );
+                let now = db.clocks().realtime();
+                db.lock()
+                    .set_sample_file_dir_unhealthy(dir_id, Some(now.sec))?;
+                bail_t!(Unavailable, "sample file dir {} is unhealthy", dir_id);
+            }
+            warn!("sleeping for 1 s after error: {:?}", e);
+        }
+        db.clocks().sleep(Duration::seconds(1));
+    }
     Ok(())
 }
 
 impl<F: FileWriter> SyncerChannel<F> {
     /// Asynchronously syncs the given writer, closes it, records it into the database, and
-    /// starts rotation.
-    fn async_save_recording(&self, id: CompositeId, duration: recording::Duration, f: F) {
-        self.0
-            .send(SyncerCommand::AsyncSaveRecording(id, duration, f))
-            .unwrap();
+    /// starts rotation. If the queue is full, stalls until the syncer catches up.
+    fn async_save_recording<C: Clocks>(
+        &self,
+        clocks: &C,
+        id: CompositeId,
+        duration: recording::Duration,
+        f: F,
+    ) {
+        self.stats.depth.fetch_add(1, atomic::Ordering::SeqCst);
+        let cmd = match self
+            .snd
+            .try_send(SyncerCommand::AsyncSaveRecording(id, duration, f))
+        {
+            Ok(()) => return,
+            Err(mpsc::TrySendError::Full(cmd)) => cmd,
+            Err(e @ mpsc::TrySendError::Disconnected(_)) => panic!("{}", e),
+        };
+        warn!(
+            "{}: syncer queue is full ({} commands); stalling until it catches up",
+            id, SYNCER_QUEUE_CAPACITY
+        );
+        let start = clocks.monotonic();
+        self.snd.send(cmd).unwrap();
+        let stalled = clocks.monotonic() - start;
+        self.stats.stalls.fetch_add(1, atomic::Ordering::SeqCst);
+        self.stats.stalled_nanos.fetch_add(
+            stalled.num_nanoseconds().unwrap_or(0) as u64,
+            atomic::Ordering::SeqCst,
+        );
+        info!("{}: resumed after stalling for {:?}", id, stalled);
+    }
+
+    /// Notifies the syncer of a database flush, so that it collects garbage. This is called
+    /// with the database lock held, so it never blocks. If the queue is full, the notification
+    /// is dropped; the queued recordings will prompt another flush soon.
+    fn database_flushed(&self, dir_id: i32) {
+        self.stats.depth.fetch_add(1, atomic::Ordering::SeqCst);
+        if let Err(e) = self.snd.try_send(SyncerCommand::DatabaseFlushed) {
+            self.stats.depth.fetch_sub(1, atomic::Ordering::SeqCst);
+            match e {
+                mpsc::TrySendError::Full(_) => {
+                    debug!(
+                        "Syncer for dir {} is busy; dropping flush notification",
+                        dir_id
+                    )
+                }
+                mpsc::TrySendError::Disconnected(_) => {
+                    warn!("Unable to notify syncer for dir {} of flush: {}", dir_id, e)
+                }
+            }
+        }
+    }
+
+    /// Returns a snapshot of the syncer's command queue.
+    pub fn queue_status(&self) -> QueueStatus {
+        self.queue_monitor().status()
+    }
+
+    /// Returns a handle which can observe the syncer's command queue without sending commands.
+    pub fn queue_monitor(&self) -> QueueMonitor {
+        QueueMonitor(self.stats.clone())
     }
 
     /// For testing: flushes the syncer, waiting for all currently-queued commands to complete,
@@ -283,13 +649,16 @@ impl<F: FileWriter> SyncerChannel<F> {
     /// post-database flush garbage collection.
     pub fn flush(&self) {
         let (snd, rcv) = mpsc::sync_channel(0);
-        self.0.send(SyncerCommand::Flush(snd)).unwrap();
+        self.stats.depth.fetch_add(1, atomic::Ordering::SeqCst);
+        self.snd.send(SyncerCommand::Flush(snd)).unwrap();
         rcv.recv().unwrap_err(); // syncer should just drop the channel, closing it.
     }
 }
 
 /// Lists files which should be "abandoned" (deleted without ever recording in the database)
-/// on opening.
+/// on opening. Where the filesystem supports `O_TMPFILE`, these are only recordings which were
+/// synced but not committed; partially written ones never get a name (see
+/// `dir::SampleFileDir::create_writer`).
 fn list_files_to_abandon(
     dir: &dir::SampleFileDir,
     streams_to_next: FnvHashMap<i32, i32>,
@@ -331,7 +700,7 @@ impl<C: Clocks + Clone> Syncer<C, Arc<dir::SampleFileDir>> {
             .streams_by_id()
             .iter()
             .filter_map(|(&k, v)| {
-                if v.sample_file_dir_id == Some(dir_id) {
+                if v.dir_ids().contains(&dir_id) {
                     Some((k, v.next_recording_id))
                 } else {
                     None
@@ -360,6 +729,7 @@ impl<C: Clocks + Clone> Syncer<C, Arc<dir::SampleFileDir>> {
                 dir,
                 db,
                 planned_flushes: std::collections::BinaryHeap::new(),
+                stats: Arc::new(QueueStats::default()),
             },
             d.path.clone(),
         ))
@@ -446,6 +816,7 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
         };
 
         // Have a command; handle it.
+        self.stats.depth.fetch_sub(1, atomic::Ordering::SeqCst);
         match cmd {
             SyncerCommand::AsyncSaveRecording(id, dur, f) => self.save(id, dur, f),
             SyncerCommand::DatabaseFlushed => self.collect_garbage(),
@@ -497,7 +868,9 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
     /// so that there can be only one dir sync and database transaction per save.
     /// Internal helper for `save`. This is separated out so that the question-mark operator
     /// can be used in the many error paths.
-    fn save(&mut self, id: CompositeId, duration: recording::Duration, f: D::File) {
+    fn save(&mut self, id: CompositeId, duration: recording::Duration, mut f: D::File) {
+        let span = tracing::info_span!("recording", %id);
+        let _enter = span.enter();
         trace!("Processing save for {}", id);
         let stream_id = id.stream();
 
@@ -510,18 +883,33 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
         let s = db.streams_by_id().get(&stream_id).unwrap();
         let c = db.cameras_by_id().get(&s.camera_id).unwrap();
 
-        // Schedule a flush.
-        let how_soon = Duration::seconds(s.flush_if_sec) - duration.to_tm_duration();
+        // Schedule a flush: immediately if a size threshold has been reached, or otherwise
+        // according to `flush_if_sec`.
         let now = self.db.clocks().monotonic();
+        let (how_soon, reason) = match db.flush_threshold_reached(stream_id) {
+            Some(r) => (
+                Duration::seconds(0),
+                format!(
+                    "{} after {}-{} recording {}",
+                    r,
+                    c.short_name,
+                    s.type_.as_str(),
+                    id
+                ),
+            ),
+            None => (
+                Duration::seconds(s.flush_if_sec) - duration.to_tm_duration(),
+                format!(
+                    "{} sec after start of {} {}-{} recording {}",
+                    s.flush_if_sec,
+                    duration,
+                    c.short_name,
+                    s.type_.as_str(),
+                    id
+                ),
+            ),
+        };
         let when = now + how_soon;
-        let reason = format!(
-            "{} sec after start of {} {}-{} recording {}",
-            s.flush_if_sec,
-            duration,
-            c.short_name,
-            s.type_.as_str(),
-            id
-        );
         trace!("scheduling flush in {} because {}", how_soon, &reason);
         self.planned_flushes.push(PlannedFlush {
             when,
@@ -545,12 +933,9 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
             let s = match l.streams_by_id().get(&f.recording.stream()) {
                 Some(s) => s,
                 None => {
-                    // Removing streams while running hasn't been implemented yet, so this should
-                    // be impossible.
-                    warn!(
-                        "bug: no stream for {} which was scheduled to be flushed",
-                        f.recording
-                    );
+                    // The stream was deleted (see `LockedDatabase::delete_stream`), taking this
+                    // recording with it, so there's nothing left to flush.
+                    trace!("planned flush ({}) canceled; stream was deleted", &f.reason);
                     PeekMut::pop(f);
                     continue;
                 }
@@ -597,11 +982,18 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
 /// saves the recording to the database (if I/O errors do not prevent this), retries forever,
 /// or panics (if further writing on this stream is impossible).
 pub struct Writer<'a, C: Clocks + Clone, D: DirWriter> {
-    dir: &'a D,
+    /// The directories and their syncers across which recordings are striped, in the order of
+    /// `db::Stream::dir_ids`.
+    stripes: Vec<(&'a D, &'a SyncerChannel<D::File>)>,
+
+    /// The stream's failover directory and its syncer, if any; see `with_failover`.
+    failover: Option<(&'a D, &'a SyncerChannel<D::File>)>,
     db: &'a db::Database<C>,
-    channel: &'a SyncerChannel<D::File>,
     stream_id: i32,
     video_sample_entry_id: i32,
+
+    /// The clock rate of the RTP timestamps passed as pts to `write`, if they are such.
+    rtp_clock_rate: Option<i32>,
     state: WriterState<D::File>,
 }
 
@@ -625,7 +1017,7 @@ struct InnerWriter<F: FileWriter> {
     /// segments have been sent out. Initially 0.
     completed_live_segment_off_90k: i32,
 
-    hasher: hash::Hasher,
+    hasher: crypto::Blake3,
 
     /// The start time of this segment, based solely on examining the local clock after frames in
     /// this segment were received. Frames can suffer from various kinds of delay (initial
@@ -644,6 +1036,20 @@ struct InnerWriter<F: FileWriter> {
     ///
     /// Invariant: this should always be `Some` (briefly violated during `write` call only).
     unflushed_sample: Option<UnflushedSample>,
+
+    /// True if the stream was marked `disk_full` when this writer was opened. The flag is
+    /// cleared after the first successful write.
+    disk_full: bool,
+
+    /// The index within `Writer::stripes` of this recording's usual directory.
+    stripe: usize,
+
+    /// True iff this recording is in `Writer::failover` rather than its usual directory.
+    failover: bool,
+
+    /// The usual directory to mark unhealthy if writes fail repeatedly, if this recording isn't
+    /// already in the failover directory.
+    failover_from: Option<i32>,
 }
 
 /// Adjusts durations given by the camera to correct its clock frequency error.
@@ -709,6 +1115,7 @@ struct PreviousWriter {
     end: recording::Time,
     local_time_delta: recording::Duration,
     run_offset: i32,
+    sample_file_bytes: i32,
 }
 
 impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
@@ -720,16 +1127,53 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
         stream_id: i32,
         video_sample_entry_id: i32,
     ) -> Self {
+        Self::new_striped(vec![(dir, channel)], db, stream_id, video_sample_entry_id)
+    }
+
+    /// Creates a writer for a stream striped across several directories. `stripes` must match
+    /// the stream's `db::Stream::dir_ids`.
+    /// `db` must not be locked.
+    pub fn new_striped(
+        stripes: Vec<(&'a D, &'a SyncerChannel<D::File>)>,
+        db: &'a db::Database<C>,
+        stream_id: i32,
+        video_sample_entry_id: i32,
+    ) -> Self {
+        assert!(!stripes.is_empty());
         Writer {
-            dir,
+            stripes,
+            failover: None,
             db,
-            channel,
             stream_id,
             video_sample_entry_id,
+            rtp_clock_rate: None,
             state: WriterState::Unopened,
         }
     }
 
+    /// Notes that the pts passed to `write` are the camera's RTP timestamps at the given clock
+    /// rate, so that each recording's first one is saved for later comparison with its start time.
+    pub fn with_rtp_clock_rate(mut self, rate: Option<i32>) -> Self {
+        self.rtp_clock_rate = rate;
+        self
+    }
+
+    /// Sets the stream's failover directory, as in `db::Stream::failover_sample_file_dir_id`.
+    /// When the directory a recording would otherwise use fails repeatedly, the writer marks it
+    /// unhealthy and writes to this one instead.
+    pub fn with_failover(mut self, dir: &'a D, channel: &'a SyncerChannel<D::File>) -> Self {
+        self.failover = Some((dir, channel));
+        self
+    }
+
+    /// Returns the syncer for the given recording's directory.
+    fn channel(&self, w: &InnerWriter<D::File>) -> &'a SyncerChannel<D::File> {
+        match (w.failover, self.failover) {
+            (true, Some((_, c))) => c,
+            _ => self.stripes[w.stripe].1,
+        }
+    }
+
     /// Opens a new writer.
     /// On successful return, `self.state` will be `WriterState::Open(w)` with `w` violating the
     /// invariant that `unflushed_sample` is `Some`. The caller (`write`) is responsible for
@@ -740,7 +1184,8 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
             WriterState::Open(_) => return Ok(()),
             WriterState::Closed(prev) => Some(prev),
         };
-        let (id, r) = self.db.lock().add_recording(
+        let mut l = self.db.lock();
+        let (id, r) = l.add_recording(
             self.stream_id,
             db::RecordingToInsert {
                 run_offset: prev.map(|p| p.run_offset + 1).unwrap_or(0),
@@ -752,7 +1197,83 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
                 ..Default::default()
             },
         )?;
-        let f = clock::retry_forever(&self.db.clocks(), &mut || self.dir.create_file(id));
+        let stripe = db::stripe_index(id.recording(), self.stripes.len());
+        let s = l.streams_by_id().get(&self.stream_id).unwrap();
+        let disk_full = s.disk_full;
+        let dir_id = s.dir_ids()[stripe];
+        let unhealthy_since_sec = l
+            .sample_file_dirs_by_id()
+            .get(&dir_id)
+            .and_then(|d| d.unhealthy_since_sec);
+        let prealloc = match *l.preallocation() {
+            db::Preallocation::Off => 0,
+            db::Preallocation::Fixed(b) => b,
+            db::Preallocation::Previous => prev.map_or(0, |p| p.sample_file_bytes as u64 * 9 / 8),
+        };
+        drop(l);
+        let dir = self.stripes[stripe].0;
+        let clocks = self.db.clocks();
+        let (mut f, failover) = match self.failover {
+            None => (
+                clock::retry_forever(&clocks, &mut || dir.create_file(id)),
+                false,
+            ),
+            Some((failover_dir, _)) => {
+                let now_sec = clocks.realtime().sec;
+                let retry = match unhealthy_since_sec {
+                    None => true,
+                    Some(t) => now_sec >= t + FAILOVER_RETRY_SEC,
+                };
+                let f = if retry {
+                    create_file_with_attempts(&clocks, dir, id)
+                } else {
+                    None
+                };
+                let since_sec = match (&f, retry) {
+                    (Some(_), _) => None,
+                    (None, true) => {
+                        error!(
+                            "{}: sample file dir {} failed {} times; failing over",
+                            id, dir_id, FAILOVER_ATTEMPTS
+                        );
+                        Some(now_sec)
+                    }
+                    (None, false) => unhealthy_since_sec,
+                };
+                if since_sec != unhealthy_since_sec {
+                    if since_sec.is_none() {
+                        info!("{}: sample file dir {} is healthy again", id, dir_id);
+                    }
+
+                    // Don't fail here; the recording must be sent to the syncer.
+                    if let Err(e) = self
+                        .db
+                        .lock()
+                        .set_sample_file_dir_unhealthy(dir_id, since_sec)
+                    {
+                        warn!("{}: unable to record dir {} health: {}", id, dir_id, e);
+                    }
+                }
+                match f {
+                    Some(f) => (f, false),
+                    None => {
+                        let f = clock::retry_forever(&clocks, &mut || failover_dir.create_file(id));
+                        r.lock().flags |= db::RecordingFlags::Failover as i32;
+                        (f, true)
+                    }
+                }
+            }
+        };
+        debug!(recording = %id, dir_id, failover, "opened recording");
+        if f.encrypted() {
+            r.lock().flags |= db::RecordingFlags::Encrypted as i32;
+        }
+        if prealloc > 0 {
+            if let Err(e) = f.preallocate(prealloc) {
+                // Not fatal; the recording just may be more fragmented.
+                warn!("{}: unable to preallocate {} bytes: {}", id, prealloc, e);
+            }
+        }
 
         self.state = WriterState::Open(InnerWriter {
             f,
@@ -760,10 +1281,17 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
             e: recording::SampleIndexEncoder::new(),
             id,
             completed_live_segment_off_90k: 0,
-            hasher: hash::Hasher::new(hash::MessageDigest::sha1())?,
+            hasher: crypto::Blake3::new(),
             local_start: recording::Time(i64::max_value()),
             adjuster: ClockAdjuster::new(prev.map(|p| p.local_time_delta.0)),
             unflushed_sample: None,
+            disk_full,
+            stripe,
+            failover,
+            failover_from: match (failover, self.failover) {
+                (false, Some(_)) => Some(dir_id),
+                _ => None,
+            },
         });
         Ok(())
     }
@@ -776,6 +1304,14 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
         })
     }
 
+    /// Returns the id of the recording currently being written, if any.
+    pub fn recording_id(&self) -> Option<CompositeId> {
+        match self.state {
+            WriterState::Open(ref w) => Some(w.id),
+            _ => None,
+        }
+    }
+
     /// Writes a new frame to this segment.
     /// `local_time` should be the local clock's time as of when this packet was received.
     pub fn write(
@@ -790,6 +1326,17 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
             WriterState::Open(ref mut w) => w,
             _ => unreachable!(),
         };
+        if w.unflushed_sample.is_none() {
+            // This is the recording's first sample.
+            if let Some(rate) = self.rtp_clock_rate {
+                w.r.lock().rtp_timestamp = Some((pts_90k, rate));
+            }
+        }
+
+        // A key frame ends a live segment (below), whose bytes must be readable before it's sent.
+        if is_key && w.unflushed_sample.is_some() {
+            w.f.flush()?;
+        }
 
         // Note w's invariant that `unflushed_sample` is `None` may currently be violated.
         // We must restore it on all success or error paths.
@@ -837,17 +1384,30 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
             }
         }
         let mut remaining = pkt;
-        while !remaining.is_empty() {
-            let written = clock::retry_forever(&self.db.clocks(), &mut || w.f.write(remaining));
-            remaining = &remaining[written..];
-        }
+        let result = write_all(
+            self.db,
+            self.stream_id,
+            w.failover_from,
+            &mut w.f,
+            &mut remaining,
+        );
+
+        // On failure, record the truncated sample anyway. This restores the invariant and keeps
+        // the index consistent with the file's contents when the caller closes the writer.
+        let written = &pkt[..pkt.len() - remaining.len()];
         w.unflushed_sample = Some(UnflushedSample {
             local_time,
             pts_90k,
-            len: pkt.len() as i32,
+            len: written.len() as i32,
             is_key,
         });
-        w.hasher.update(pkt).unwrap();
+        w.hasher.update(written);
+        result?;
+        if w.disk_full {
+            info!("{}: sample file directory has space again", self.stream_id);
+            self.db.lock().set_disk_full(self.stream_id, false)?;
+            w.disk_full = false;
+        }
         Ok(())
     }
 
@@ -857,7 +1417,8 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
     pub fn close(&mut self, next_pts: Option<i64>) -> Result<(), Error> {
         self.state = match mem::replace(&mut self.state, WriterState::Unopened) {
             WriterState::Open(w) => {
-                let prev = w.close(self.channel, next_pts, self.db, self.stream_id)?;
+                let channel = self.channel(&w);
+                let prev = w.close(channel, next_pts, self.db, self.stream_id)?;
                 WriterState::Closed(prev)
             }
             s => s,
@@ -897,16 +1458,21 @@ impl<F: FileWriter> InnerWriter<F> {
             .unflushed_sample
             .take()
             .expect("should always be an unflushed sample");
-        let (last_sample_duration, flags) = match next_pts {
+        let (last_sample_duration, mut flags) = match next_pts {
             None => (
                 self.adjuster.adjust(0),
                 db::RecordingFlags::TrailingZero as i32,
             ),
             Some(p) => (self.adjuster.adjust((p - unflushed.pts_90k) as i32), 0),
         };
-        let mut sha1_bytes = [0u8; 20];
-        sha1_bytes.copy_from_slice(&self.hasher.finish().unwrap()[..]);
-        let (local_time_delta, run_offset, end);
+        if self.failover {
+            flags |= db::RecordingFlags::Failover as i32;
+        }
+        if self.f.encrypted() {
+            flags |= db::RecordingFlags::Encrypted as i32;
+        }
+        let blake3 = self.hasher.finish();
+        let (local_time_delta, run_offset, end, sample_file_bytes);
         let d = self.add_sample(
             last_sample_duration,
             unflushed.len,
@@ -915,6 +1481,10 @@ impl<F: FileWriter> InnerWriter<F> {
         )?;
 
         // This always ends a live segment.
+        if let Err(e) = self.f.flush() {
+            // The syncer's sync_all will retry.
+            warn!("{}: unable to flush final live segment: {}", self.id, e);
+        }
         db.lock()
             .send_live_segment(
                 stream_id,
@@ -930,17 +1500,20 @@ impl<F: FileWriter> InnerWriter<F> {
             l.flags = flags;
             local_time_delta = self.local_start - l.start;
             l.local_time_delta = local_time_delta;
-            l.sample_file_sha1 = sha1_bytes;
+            l.sample_file_blake3 = Some(blake3);
             total_duration = recording::Duration(l.duration_90k as i64);
             run_offset = l.run_offset;
+            sample_file_bytes = l.sample_file_bytes;
             end = l.start + total_duration;
         }
         drop(self.r);
-        channel.async_save_recording(self.id, total_duration, self.f);
+        debug!(recording = %self.id, sample_file_bytes, "closed recording");
+        channel.async_save_recording(&db.clocks(), self.id, total_duration, self.f);
         Ok(PreviousWriter {
             end,
             local_time_delta,
             run_offset,
+            sample_file_bytes,
         })
     }
 }
@@ -948,14 +1521,34 @@ impl<F: FileWriter> InnerWriter<F> {
 impl<'a, C: Clocks + Clone, D: DirWriter> Drop for Writer<'a, C, D> {
     fn drop(&mut self) {
         if ::std::thread::panicking() {
-            // This will probably panic again. Don't do it.
+            // Closing normally would probably panic again, aborting the process. Instead, discard
+            // the recording, so the stream's later recordings can still be committed when its
+            // streamer is restarted.
+            if let WriterState::Open(w) = mem::replace(&mut self.state, WriterState::Unopened) {
+                let dir = match (w.failover, self.failover) {
+                    (true, Some((d, _))) => d,
+                    _ => self.stripes[w.stripe].0,
+                };
+                let id = w.id;
+                drop(w);
+                if let Err(e) = dir.unlink_file(id) {
+                    error!("{}: unable to unlink abandoned recording: {}", id, e);
+                    return;
+                }
+                if let Err(e) = self.db.lock().abandon_recording(id) {
+                    error!("{}: unable to abandon recording: {}", id, e);
+                    return;
+                }
+                warn!("{}: abandoned recording after panic", id);
+            }
             return;
         }
         if let WriterState::Open(w) = mem::replace(&mut self.state, WriterState::Unopened) {
             // Swallow any error. The caller should only drop the Writer without calling close()
             // if there's already been an error. The caller should report that. No point in
             // complaining again.
-            let _ = w.close(self.channel, None, self.db, self.stream_id);
+            let channel = self.channel(&w);
+            let _ = w.close(channel, None, self.db, self.stream_id);
         }
     }
 }
@@ -967,12 +1560,12 @@ mod tests {
     use crate::recording;
     use crate::testutil;
     use base::clock::{Clocks, SimulatedClocks};
-    use log::{trace, warn};
     use parking_lot::Mutex;
     use std::collections::VecDeque;
     use std::io;
     use std::sync::mpsc;
     use std::sync::Arc;
+    use tracing::trace;
 
     #[derive(Clone)]
     struct MockDir(Arc<Mutex<VecDeque<MockDirAction>>>);
@@ -1074,7 +1667,7 @@ mod tests {
     }
 
     impl super::FileWriter for MockFile {
-        fn sync_all(&self) -> Result<(), io::Error> {
+        fn sync_all(&mut self) -> Result<(), io::Error> {
             match self
                 .0
                 .lock()
@@ -1096,6 +1689,15 @@ mod tests {
                 _ => panic!("got write({:?}), expected something else", buf),
             }
         }
+        fn flush(&mut self) -> Result<(), io::Error> {
+            Ok(())
+        }
+        fn preallocate(&mut self, _len: u64) -> Result<(), io::Error> {
+            Ok(())
+        }
+        fn encrypted(&self) -> bool {
+            false
+        }
     }
 
     struct Harness {
@@ -1137,22 +1739,23 @@ mod tests {
             dir: dir.clone(),
             db: tdb.db.clone(),
             planned_flushes: std::collections::BinaryHeap::new(),
+            stats: Arc::new(super::QueueStats::default()),
+        };
+        let (syncer_snd, syncer_rcv) = mpsc::sync_channel(super::SYNCER_QUEUE_CAPACITY);
+        let channel = super::SyncerChannel {
+            snd: syncer_snd,
+            stats: syncer.stats.clone(),
         };
-        let (syncer_snd, syncer_rcv) = mpsc::channel();
         tdb.db.lock().on_flush(Box::new({
-            let snd = syncer_snd.clone();
-            move || {
-                if let Err(e) = snd.send(super::SyncerCommand::DatabaseFlushed) {
-                    warn!("Unable to notify syncer for dir {} of flush: {}", dir_id, e);
-                }
-            }
+            let channel = channel.clone();
+            move || channel.database_flushed(dir_id)
         }));
         Harness {
             dir_id,
             dir,
             db: tdb.db,
             _tmpdir: tdb.tmpdir,
-            channel: super::SyncerChannel(syncer_snd),
+            channel,
             syncer,
             syncer_rcv,
         }
@@ -1338,11 +1941,15 @@ mod tests {
             .expect(MockDirAction::Sync(Box::new(|| Err(nix_eio()))));
         h.dir.expect(MockDirAction::Sync(Box::new(|| Ok(()))));
         drop(w);
+        assert_eq!(h.channel.queue_status().depth, 1);
         assert!(h.syncer.iter(&h.syncer_rcv)); // AsyncSave
+        assert_eq!(h.channel.queue_status().depth, 0);
         assert_eq!(h.syncer.planned_flushes.len(), 1);
         assert!(h.syncer.iter(&h.syncer_rcv)); // planned flush
         assert_eq!(h.syncer.planned_flushes.len(), 0);
+        assert_eq!(h.channel.queue_status().depth, 1);
         assert!(h.syncer.iter(&h.syncer_rcv)); // DatabaseFlushed
+        assert_eq!(h.channel.queue_status().depth, 0);
         f.ensure_done();
         h.dir.ensure_done();
 
@@ -1363,6 +1970,72 @@ mod tests {
         assert!(h.syncer.planned_flushes.is_empty());
     }
 
+    #[test]
+    fn write_path_pauses_on_enospc() {
+        testutil::init();
+        let mut h = new_harness(0);
+        let video_sample_entry_id = h
+            .db
+            .lock()
+            .insert_video_sample_entry(1920, 1080, [0u8; 100].to_vec(), "avc1.000000".to_owned())
+            .unwrap();
+        let mut w = Writer::new(
+            &h.dir,
+            &h.db,
+            &h.channel,
+            testutil::TEST_STREAM_ID,
+            video_sample_entry_id,
+        );
+        let f = MockFile::new();
+        h.dir.expect(MockDirAction::Create(
+            CompositeId::new(1, 1),
+            Box::new({
+                let f = f.clone();
+                move |_id| Ok(f.clone())
+            }),
+        ));
+        f.expect(MockFileAction::Write(Box::new(|buf| {
+            assert_eq!(buf, b"1234");
+            Ok(1)
+        })));
+        f.expect(MockFileAction::Write(Box::new(|buf| {
+            assert_eq!(buf, b"234");
+            Err(io::Error::from_raw_os_error(libc::ENOSPC))
+        })));
+        w.write(b"1234", recording::Time(1), 0, true).unwrap_err();
+        assert!(
+            h.db.lock()
+                .streams_by_id()
+                .get(&testutil::TEST_STREAM_ID)
+                .unwrap()
+                .disk_full
+        );
+
+        // Closing saves the portion which was written.
+        f.expect(MockFileAction::SyncAll(Box::new(|| Ok(()))));
+        h.dir.expect(MockDirAction::Sync(Box::new(|| Ok(()))));
+        drop(w);
+        assert!(h.syncer.iter(&h.syncer_rcv)); // AsyncSave
+        assert!(h.syncer.iter(&h.syncer_rcv)); // planned flush
+        assert!(h.syncer.iter(&h.syncer_rcv)); // DatabaseFlushed
+        f.ensure_done();
+        h.dir.ensure_done();
+        {
+            let l = h.db.lock();
+            let s = l.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
+            assert_eq!(s.sample_file_bytes, 1);
+        }
+
+        // The syncer should shut down cleanly.
+        drop(h.channel);
+        h.db.lock().clear_on_flush();
+        assert_eq!(
+            h.syncer_rcv.try_recv().err(),
+            Some(std::sync::mpsc::TryRecvError::Disconnected)
+        );
+        assert!(h.syncer.planned_flushes.is_empty());
+    }
+
     #[test]
     fn gc_path_retries() {
         testutil::init();
@@ -1606,6 +2279,267 @@ mod tests {
         assert!(h.syncer.planned_flushes.is_empty());
     }
 
+    /// Tests that reaching `max_unflushed_recordings` flushes right away, despite `flush_if_sec`.
+    #[test]
+    fn max_unflushed_recordings() {
+        testutil::init();
+        let mut h = new_harness(60); // flush_if_sec=60
+        h.db.lock().set_max_unflushed_recordings(Some(1));
+        h.db.clocks().sleep(time::Duration::seconds(1));
+        let video_sample_entry_id = h
+            .db
+            .lock()
+            .insert_video_sample_entry(1920, 1080, [0u8; 100].to_vec(), "avc1.000000".to_owned())
+            .unwrap();
+        let mut w = Writer::new(
+            &h.dir,
+            &h.db,
+            &h.channel,
+            testutil::TEST_STREAM_ID,
+            video_sample_entry_id,
+        );
+        let f = MockFile::new();
+        h.dir.expect(MockDirAction::Create(
+            CompositeId::new(1, 1),
+            Box::new({
+                let f = f.clone();
+                move |_id| Ok(f.clone())
+            }),
+        ));
+        f.expect(MockFileAction::Write(Box::new(|buf| {
+            assert_eq!(buf, b"123");
+            Ok(3)
+        })));
+        f.expect(MockFileAction::SyncAll(Box::new(|| Ok(()))));
+        w.write(
+            b"123",
+            recording::Time(recording::TIME_UNITS_PER_SEC),
+            0,
+            true,
+        )
+        .unwrap();
+        h.dir.expect(MockDirAction::Sync(Box::new(|| Ok(()))));
+        drop(w);
+
+        assert!(h.syncer.iter(&h.syncer_rcv)); // AsyncSave
+        assert_eq!(h.syncer.planned_flushes.len(), 1);
+        let db_flush_count_before = h.db.lock().flushes();
+        assert!(h.syncer.iter(&h.syncer_rcv)); // planned flush, without waiting.
+        assert_eq!(h.db.clocks().monotonic(), time::Timespec::new(1, 0));
+        assert_eq!(h.db.lock().flushes(), db_flush_count_before + 1);
+        assert!(h.syncer.iter(&h.syncer_rcv)); // DatabaseFlushed
+        f.ensure_done();
+        h.dir.ensure_done();
+    }
+
+    /// Tests that with a sub retention multiple, rotating the main stream trims the sub stream
+    /// to the allowed multiple of the main stream's retained history.
+    #[test]
+    fn sub_retention_multiple() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(SimulatedClocks::new(::time::Timespec::new(0, 0)));
+        let mut l = tdb.db.lock();
+        let main_change = {
+            let main = l.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
+            db::StreamChange {
+                sample_file_dir_id: main.sample_file_dir_id,
+                rtsp_url: main.rtsp_url.clone(),
+                record: true,
+                flush_if_sec: main.flush_if_sec,
+                ..Default::default()
+            }
+        };
+        let sub_change = db::StreamChange {
+            rtsp_url: "rtsp://test-camera/sub".to_owned(),
+            ..main_change.clone()
+        };
+        l.update_camera(
+            testutil::TEST_CAMERA_ID,
+            db::CameraChange {
+                short_name: "test camera".to_owned(),
+                description: "".to_owned(),
+                onvif_host: "test-camera".to_owned(),
+                username: "foo".to_owned(),
+                password: "bar".to_owned(),
+                ptz: false,
+                streams: [main_change, sub_change],
+            },
+        )
+        .unwrap();
+        let sub_id = l
+            .cameras_by_id()
+            .get(&testutil::TEST_CAMERA_ID)
+            .unwrap()
+            .streams[db::StreamType::SUB.index()]
+        .unwrap();
+        let video_sample_entry_id = l
+            .insert_video_sample_entry(1920, 1080, [0u8; 100].to_vec(), "avc1.000000".to_owned())
+            .unwrap();
+
+        // The sub stream has four minutes of recordings; the main stream only the last two.
+        let minute = 60 * recording::TIME_UNITS_PER_SEC;
+        for &(stream_id, first) in &[(testutil::TEST_STREAM_ID, 2), (sub_id, 0)] {
+            for i in first..4 {
+                let (id, _) = l
+                    .add_recording(
+                        stream_id,
+                        db::RecordingToInsert {
+                            start: recording::Time(i * minute),
+                            duration_90k: minute as i32,
+                            sample_file_bytes: 1000,
+                            video_sample_entry_id,
+                            ..Default::default()
+                        },
+                    )
+                    .unwrap();
+                l.mark_synced(id).unwrap();
+            }
+        }
+        l.flush("sub_retention_multiple").unwrap();
+
+        l.set_sub_retention_multiple(Some(2.0));
+        super::delete_recordings(&mut l, testutil::TEST_STREAM_ID, 0).unwrap();
+        assert_eq!(l.streams_by_id().get(&sub_id).unwrap().bytes_to_delete, 0);
+
+        l.set_sub_retention_multiple(Some(1.0));
+        super::delete_recordings(&mut l, testutil::TEST_STREAM_ID, 0).unwrap();
+        assert_eq!(
+            l.streams_by_id().get(&sub_id).unwrap().bytes_to_delete,
+            2000
+        );
+        l.flush("sub_retention_multiple").unwrap();
+        assert_eq!(
+            l.streams_by_id().get(&sub_id).unwrap().range,
+            Some(recording::Time(2 * minute)..recording::Time(4 * minute))
+        );
+    }
+
+    /// Tests that previewing a retention change reports what would be deleted without queueing it.
+    #[test]
+    fn preview_lower_retention() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(SimulatedClocks::new(::time::Timespec::new(0, 0)));
+        let mut l = tdb.db.lock();
+        let video_sample_entry_id = l
+            .insert_video_sample_entry(1920, 1080, [0u8; 100].to_vec(), "avc1.000000".to_owned())
+            .unwrap();
+        let minute = 60 * recording::TIME_UNITS_PER_SEC;
+        for i in 0..4 {
+            let (id, _) = l
+                .add_recording(
+                    testutil::TEST_STREAM_ID,
+                    db::RecordingToInsert {
+                        start: recording::Time(i * minute),
+                        duration_90k: minute as i32,
+                        sample_file_bytes: 1000,
+                        video_sample_entry_id,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            l.mark_synced(id).unwrap();
+        }
+        l.flush("preview_lower_retention").unwrap();
+
+        // A limit above current usage deletes nothing.
+        let previews = super::preview_lower_retention(
+            &mut l,
+            &[super::NewLimit {
+                stream_id: testutil::TEST_STREAM_ID,
+                limit: 1 << 20,
+            }],
+        )
+        .unwrap();
+        let p = previews.get(&testutil::TEST_STREAM_ID).unwrap();
+        assert!(p.recordings.is_empty());
+        assert_eq!(p.oldest_remaining, Some(recording::Time(0)));
+
+        // A limit of 0 deletes everything.
+        let previews = super::preview_lower_retention(
+            &mut l,
+            &[super::NewLimit {
+                stream_id: testutil::TEST_STREAM_ID,
+                limit: 0,
+            }],
+        )
+        .unwrap();
+        let p = previews.get(&testutil::TEST_STREAM_ID).unwrap();
+        assert_eq!(p.recordings.len(), 4);
+        assert_eq!(
+            p.recordings[0].time,
+            recording::Time(0)..recording::Time(minute)
+        );
+        assert_eq!(p.sample_file_bytes, 4000);
+        assert_eq!(p.oldest_remaining, None);
+
+        // Nothing was actually queued.
+        let s = l.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
+        assert_eq!(s.bytes_to_delete, 0);
+        assert_eq!(s.fs_bytes_to_delete, 0);
+        l.flush("preview_lower_retention").unwrap();
+        assert_eq!(
+            l.streams_by_id()
+                .get(&testutil::TEST_STREAM_ID)
+                .unwrap()
+                .range,
+            Some(recording::Time(0)..recording::Time(4 * minute))
+        );
+    }
+
+    /// Readers of a live segment can open the recording's file before it's closed, even while it
+    /// has no name.
+    #[test]
+    fn live_segment_readable_before_close() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(SimulatedClocks::new(::time::Timespec::new(0, 0)));
+        let video_sample_entry_id = tdb
+            .db
+            .lock()
+            .insert_video_sample_entry(1920, 1080, [0u8; 100].to_vec(), "avc1.000000".to_owned())
+            .unwrap();
+        let recordings = Arc::new(Mutex::new(Vec::new()));
+        tdb.db
+            .lock()
+            .watch_live(
+                testutil::TEST_STREAM_ID,
+                Box::new({
+                    let recordings = recordings.clone();
+                    move |l| {
+                        recordings.lock().push(l.recording);
+                        true
+                    }
+                }),
+            )
+            .unwrap();
+        let dir = &tdb.dirs_by_stream_id[&testutil::TEST_STREAM_ID].stripes[0];
+        let mut w = Writer::new(
+            dir,
+            &tdb.db,
+            &tdb.syncer_channel,
+            testutil::TEST_STREAM_ID,
+            video_sample_entry_id,
+        );
+        w.write(b"123", recording::Time(1), 0, true).unwrap();
+        w.write(b"45", recording::Time(2), 1, true).unwrap();
+        let recording = {
+            let l = recordings.lock();
+            assert_eq!(l.len(), 1);
+            l[0]
+        };
+        let mut contents = Vec::new();
+        io::Read::read_to_end(
+            &mut dir
+                .open_file(CompositeId::new(testutil::TEST_STREAM_ID, recording))
+                .unwrap(),
+            &mut contents,
+        )
+        .unwrap();
+        assert!(contents.starts_with(b"123"), "contents={:?}", contents); // "45" may be buffered.
+        w.close(Some(2)).unwrap();
+        drop(w);
+        tdb.syncer_channel.flush();
+    }
+
     #[test]
     fn adjust() {
         testutil::init();
@@ -40,7 +40,7 @@ fi
 NODE_MIN_VERSION="10"
 YARN_MIN_VERSION="1.0"
 CARGO_MIN_VERSION="0.2"
-RUSTC_MIN_VERSION="1.40"
+RUSTC_MIN_VERSION="1.49"
 
 normalizeDirPath()
 {
@@ -0,0 +1,193 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Priority-aware admission of web requests, to keep recording healthy under heavy browsing.
+//!
+//! The web server shares the database lock with the streamers and syncers. Slow timeline queries
+//! and long exports can make writers wait on that lock or let the syncers' command queues fill,
+//! at which point recording stalls. `Scheduler` watches the recent lock wait and the fullest
+//! syncer queue. While either is over its threshold, it delays or sheds lower-priority requests;
+//! live viewing and unclassified requests (login, configuration, health checks) always proceed.
+
+use db::writer::QueueMonitor;
+use std::sync::Arc;
+use std::time::{Duration as StdDuration, Instant};
+
+/// How often a delayed request rechecks the load.
+const POLL_INTERVAL: StdDuration = StdDuration::from_millis(100);
+
+/// The longest a request is delayed before it's shed instead.
+const MAX_DELAY: StdDuration = StdDuration::from_secs(5);
+
+/// The priority of a request, from highest to lowest.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Priority {
+    /// Live view: live segments, MJPEG, PTZ.
+    Live,
+
+    /// Timeline queries: recording lists, thumbnails, search, and other playback browsing.
+    Query,
+
+    /// Exports: `.mp4` files and other bulk reads of sample data.
+    Export,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Load {
+    Normal,
+
+    /// At least one measure is over its threshold.
+    Elevated,
+
+    /// At least one measure is over twice its threshold (or a syncer queue is full).
+    Overloaded,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Decision {
+    Admit,
+    Delay,
+    Shed,
+}
+
+fn decide(p: Priority, load: Load) -> Decision {
+    match (p, load) {
+        (Priority::Live, _) | (_, Load::Normal) | (Priority::Query, Load::Elevated) => {
+            Decision::Admit
+        }
+        (Priority::Query, Load::Overloaded) | (Priority::Export, Load::Elevated) => Decision::Delay,
+        (Priority::Export, Load::Overloaded) => Decision::Shed,
+    }
+}
+
+/// Thresholds above which the server is considered loaded.
+#[derive(Clone, Debug)]
+pub struct Thresholds {
+    /// The recent average wait to acquire the database lock.
+    pub lock_wait: StdDuration,
+
+    /// The depth of a syncer's command queue, as a fraction of its capacity.
+    pub queue_fraction: f64,
+}
+
+impl Thresholds {
+    fn load(&self, lock_wait: StdDuration, queue_fraction: f64) -> Load {
+        if lock_wait >= self.lock_wait * 2 || queue_fraction >= (self.queue_fraction * 2.).min(1.) {
+            Load::Overloaded
+        } else if lock_wait >= self.lock_wait || queue_fraction >= self.queue_fraction {
+            Load::Elevated
+        } else {
+            Load::Normal
+        }
+    }
+}
+
+pub struct Scheduler {
+    db: Arc<db::Database>,
+    queues: Vec<QueueMonitor>,
+    thresholds: Thresholds,
+}
+
+impl Scheduler {
+    pub fn new(db: Arc<db::Database>, queues: Vec<QueueMonitor>, thresholds: Thresholds) -> Self {
+        Scheduler {
+            db,
+            queues,
+            thresholds,
+        }
+    }
+
+    /// Returns the current load.
+    pub fn load(&self) -> Load {
+        let queue_fraction = self
+            .queues
+            .iter()
+            .map(|q| {
+                let s = q.status();
+                s.depth as f64 / s.capacity as f64
+            })
+            .fold(0., f64::max);
+        self.thresholds
+            .load(self.db.recent_lock_wait(), queue_fraction)
+    }
+
+    /// Waits until a request of the given priority may proceed.
+    /// Returns false if the request should be shed.
+    pub async fn admit(&self, p: Priority) -> bool {
+        let start = Instant::now();
+        loop {
+            match decide(p, self.load()) {
+                Decision::Admit => return true,
+                Decision::Shed => return false,
+                Decision::Delay if start.elapsed() >= MAX_DELAY => return false,
+                Decision::Delay => tokio::time::delay_for(POLL_INTERVAL).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load() {
+        let t = Thresholds {
+            lock_wait: StdDuration::from_millis(100),
+            queue_fraction: 0.5,
+        };
+        let ms = StdDuration::from_millis;
+        assert_eq!(t.load(ms(0), 0.), Load::Normal);
+        assert_eq!(t.load(ms(99), 0.49), Load::Normal);
+        assert_eq!(t.load(ms(100), 0.), Load::Elevated);
+        assert_eq!(t.load(ms(0), 0.5), Load::Elevated);
+        assert_eq!(t.load(ms(200), 0.), Load::Overloaded);
+        assert_eq!(t.load(ms(0), 1.), Load::Overloaded);
+
+        // A full queue is overloaded even with a high queue threshold.
+        let t = Thresholds {
+            queue_fraction: 0.75,
+            ..t
+        };
+        assert_eq!(t.load(ms(0), 1.), Load::Overloaded);
+    }
+
+    #[test]
+    fn decisions() {
+        for &l in &[Load::Normal, Load::Elevated, Load::Overloaded] {
+            assert_eq!(decide(Priority::Live, l), Decision::Admit);
+        }
+        assert_eq!(decide(Priority::Query, Load::Elevated), Decision::Admit);
+        assert_eq!(decide(Priority::Query, Load::Overloaded), Decision::Delay);
+        assert_eq!(decide(Priority::Export, Load::Normal), Decision::Admit);
+        assert_eq!(decide(Priority::Export, Load::Elevated), Decision::Delay);
+        assert_eq!(decide(Priority::Export, Load::Overloaded), Decision::Shed);
+    }
+}
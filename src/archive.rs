@@ -0,0 +1,501 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Continuous export to an external archive, such as a removable disk.
+//!
+//! With `moonfire-nvr run --archive-dir`, a background thread keeps the given directory
+//! populated with the last `--archive-days` days of the selected streams as standalone `.mp4`
+//! files, one per recording, so the disk can be unplugged and taken away at any time. The layout
+//! is:
+//!
+//! * `index.json`: describes each stream's files, as `Index`.
+//! * `<camera>-<stream>/<YYYY-MM-DD>/<YYYYMMDDTHHMMSS>-<recording id>.mp4`: a recording, named
+//!   by its start in the server's local time.
+//!
+//! The archiver polls for newly committed recordings and prunes files older than the retention
+//! period, or the oldest files if the disk fills. The directory must already exist; it should
+//! be a subdirectory of the disk's mount point, so that nothing is written to the root
+//! filesystem while the disk is unplugged. A freshly attached disk is filled with the
+//! retention period's recordings, and a disk reattached later picks up where it left off.
+
+use crate::mp4;
+use base::clock::Clocks;
+use bytes::Buf;
+use db::dir::StreamDirs;
+use db::recording;
+use failure::{bail, format_err, Error};
+use fnv::FnvHashMap;
+use futures::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::Duration as StdDuration;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// How often to look for new recordings.
+const POLL_INTERVAL: StdDuration = StdDuration::from_secs(30);
+
+/// The most recordings to export per stream in a single pass, so other streams aren't starved.
+const MAX_RECORDINGS_PER_PASS: usize = 100;
+
+const INDEX_NAME: &str = "index.json";
+
+/// The archive's `index.json`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Index {
+    pub streams: Vec<IndexStream>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexStream {
+    pub camera_uuid: Uuid,
+    pub camera_short_name: String,
+    pub stream: String,
+
+    /// The id of the last recording exported, which may since have been pruned.
+    pub last_recording_id: i32,
+
+    /// The exported recordings, oldest first.
+    pub files: Vec<IndexFile>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexFile {
+    /// The path relative to the archive directory.
+    pub path: String,
+    pub recording_id: i32,
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub bytes: u64,
+}
+
+impl Index {
+    fn read(dir: &Path) -> Result<Self, Error> {
+        match fs::read(dir.join(INDEX_NAME)) {
+            Ok(b) => Ok(serde_json::from_slice(&b)
+                .map_err(|e| format_err!("unable to parse {}: {}", INDEX_NAME, e))?),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Index::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Atomically replaces the index.
+    fn write(&self, dir: &Path) -> Result<(), Error> {
+        let tmp = dir.join(format!("{}.tmp", INDEX_NAME));
+        let mut f = fs::File::create(&tmp)?;
+        serde_json::to_writer_pretty(&mut f, self)?;
+        f.sync_all()?;
+        fs::rename(&tmp, dir.join(INDEX_NAME))?;
+        Ok(())
+    }
+
+    /// Deletes the files of recordings which ended before `cutoff`.
+    fn prune(&mut self, dir: &Path, cutoff: recording::Time) -> Result<usize, Error> {
+        let mut n = 0;
+        for s in &mut self.streams {
+            let keep = s
+                .files
+                .iter()
+                .position(|f| f.end_time_90k > cutoff.0)
+                .unwrap_or_else(|| s.files.len());
+            for f in s.files.drain(..keep) {
+                remove_file(dir, &f.path)?;
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+
+    /// Deletes the oldest file of any stream, returning false if there are none.
+    fn prune_oldest(&mut self, dir: &Path) -> Result<bool, Error> {
+        let s = self
+            .streams
+            .iter_mut()
+            .filter(|s| !s.files.is_empty())
+            .min_by_key(|s| s.files[0].start_time_90k);
+        let s = match s {
+            None => return Ok(false),
+            Some(s) => s,
+        };
+        let f = s.files.remove(0);
+        remove_file(dir, &f.path)?;
+        Ok(true)
+    }
+}
+
+/// Removes a file from the archive, along with its day directory if that is now empty.
+fn remove_file(dir: &Path, path: &str) -> Result<(), Error> {
+    let p = dir.join(path);
+    match fs::remove_file(&p) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => bail!("unable to remove {}: {}", p.display(), e),
+    }
+    if let Some(parent) = p.parent() {
+        let _ = fs::remove_dir(parent); // fails harmlessly unless empty.
+    }
+    Ok(())
+}
+
+/// Replaces characters which are awkward in file names.
+pub(crate) fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// A stream to archive.
+struct Stream {
+    id: i32,
+    camera_uuid: Uuid,
+    camera_short_name: String,
+    type_: db::StreamType,
+}
+
+pub struct Archiver<C: Clocks + Clone> {
+    db: Arc<db::Database<C>>,
+    dirs_by_stream_id: Arc<FnvHashMap<i32, StreamDirs>>,
+    dir: PathBuf,
+    days: u32,
+    streams: Vec<Stream>,
+}
+
+impl<C: Clocks + Clone> Archiver<C> {
+    /// Creates an archiver for the given streams, each as `<camera short name>/<main|sub>`, or
+    /// for every camera's main stream if none are given.
+    pub fn new(
+        db: Arc<db::Database<C>>,
+        dir: PathBuf,
+        days: u32,
+        streams: &[String],
+    ) -> Result<Self, Error> {
+        if days == 0 {
+            bail!("--archive-days must be positive");
+        }
+        let (resolved, dirs_by_stream_id) = {
+            let l = db.lock();
+            let mut resolved = Vec::new();
+            let mut add = |camera: &db::Camera, type_: db::StreamType| -> Result<(), Error> {
+                let id = camera.streams[type_.index()].ok_or_else(|| {
+                    format_err!("camera {} has no {} stream", camera.short_name, type_)
+                })?;
+                resolved.push(Stream {
+                    id,
+                    camera_uuid: camera.uuid,
+                    camera_short_name: camera.short_name.clone(),
+                    type_,
+                });
+                Ok(())
+            };
+            if streams.is_empty() {
+                for c in l.cameras_by_id().values() {
+                    if c.streams[db::StreamType::MAIN.index()].is_some() {
+                        add(c, db::StreamType::MAIN)?;
+                    }
+                }
+            }
+            for s in streams {
+                let slash = s
+                    .rfind('/')
+                    .ok_or_else(|| format_err!("archive stream {:?} isn't camera/stream", s))?;
+                let type_ = db::StreamType::parse(&s[slash + 1..])
+                    .ok_or_else(|| format_err!("archive stream {:?} has bad stream type", s))?;
+                let camera = l
+                    .cameras_by_id()
+                    .values()
+                    .find(|c| c.short_name == s[..slash])
+                    .ok_or_else(|| format_err!("no camera named {:?}", &s[..slash]))?;
+                add(camera, type_)?;
+            }
+            (resolved, StreamDirs::all(&l)?)
+        };
+        Ok(Archiver {
+            db,
+            dirs_by_stream_id: Arc::new(dirs_by_stream_id),
+            dir,
+            days,
+            streams: resolved,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Runs until `shutdown_rx` is signalled or dropped.
+    pub fn run(self, shutdown_rx: mpsc::Receiver<()>) {
+        let clocks = self.db.clocks();
+        loop {
+            match self.pass(&shutdown_rx) {
+                Ok(false) => return,
+                Ok(true) => {}
+                Err(e) => warn!("archive: pass failed: {}", e),
+            }
+            match clocks.recv_timeout(&shutdown_rx, POLL_INTERVAL) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Ok(()) | Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    /// Prunes old files and exports new recordings. Returns false if interrupted by shutdown.
+    fn pass(&self, shutdown_rx: &mpsc::Receiver<()>) -> Result<bool, Error> {
+        if !self.dir.is_dir() {
+            debug!("archive: {} isn't present; skipping", self.dir.display());
+            return Ok(true);
+        }
+        let mut index = Index::read(&self.dir)?;
+        let now = recording::Time::new(self.db.clocks().realtime());
+        let cutoff = now
+            - recording::Duration(i64::from(self.days) * 86_400 * recording::TIME_UNITS_PER_SEC);
+        let pruned = index.prune(&self.dir, cutoff)?;
+        if pruned > 0 {
+            info!("archive: pruned {} expired recordings", pruned);
+            index.write(&self.dir)?;
+        }
+        for s in &self.streams {
+            let i = match index
+                .streams
+                .iter()
+                .position(|i| i.camera_uuid == s.camera_uuid && i.stream == s.type_.as_str())
+            {
+                Some(i) => i,
+                None => {
+                    index.streams.push(IndexStream {
+                        camera_uuid: s.camera_uuid,
+                        camera_short_name: s.camera_short_name.clone(),
+                        stream: s.type_.as_str().to_owned(),
+                        last_recording_id: -1,
+                        files: Vec::new(),
+                    });
+                    index.streams.len() - 1
+                }
+            };
+            let rows = self.list_new(s.id, index.streams[i].last_recording_id, cutoff)?;
+            if rows.is_empty() {
+                continue;
+            }
+            for row in rows {
+                match shutdown_rx.try_recv() {
+                    Err(mpsc::TryRecvError::Empty) => {}
+                    Ok(()) | Err(mpsc::TryRecvError::Disconnected) => {
+                        index.write(&self.dir)?;
+                        return Ok(false);
+                    }
+                }
+                let id = row.id.recording();
+                let f = self.export(&mut index, s, row)?;
+                let is = &mut index.streams[i];
+                is.last_recording_id = id;
+                is.files.push(f);
+            }
+            index.write(&self.dir)?;
+        }
+        Ok(true)
+    }
+
+    /// Lists committed recordings of the stream after `after_id` which end after `cutoff`.
+    fn list_new(
+        &self,
+        stream_id: i32,
+        after_id: i32,
+        cutoff: recording::Time,
+    ) -> Result<Vec<db::ListRecordingsRow>, Error> {
+        let mut rows = Vec::new();
+        let mut uncommitted = false;
+        self.db.lock().list_recordings_by_id(
+            stream_id,
+            after_id + 1..i32::max_value(),
+            &mut |r| {
+                // Stop at the first uncommitted recording so it's exported once committed.
+                uncommitted |= (r.flags & db::RecordingFlags::Uncommitted as i32) != 0;
+                if !uncommitted
+                    && rows.len() < MAX_RECORDINGS_PER_PASS
+                    && r.start + recording::Duration(i64::from(r.duration_90k)) > cutoff
+                {
+                    rows.push(r);
+                }
+                Ok(())
+            },
+        )?;
+        Ok(rows)
+    }
+
+    /// Writes a recording's `.mp4`, deleting the oldest files if the disk is full.
+    fn export(
+        &self,
+        index: &mut Index,
+        s: &Stream,
+        row: db::ListRecordingsRow,
+    ) -> Result<IndexFile, Error> {
+        let start = time::at(time::Timespec::new(row.start.unix_seconds(), 0));
+        let path = format!(
+            "{}-{}/{}/{}-{}.mp4",
+            sanitize(&s.camera_short_name),
+            s.type_.as_str(),
+            start.strftime("%Y-%m-%d")?,
+            start.strftime("%Y%m%dT%H%M%S")?,
+            row.id.recording()
+        );
+        let f = IndexFile {
+            path,
+            recording_id: row.id.recording(),
+            start_time_90k: row.start.0,
+            end_time_90k: row.start.0 + i64::from(row.duration_90k),
+            bytes: 0,
+        };
+        let mp4 = {
+            let l = self.db.lock();
+            let mut builder = mp4::FileBuilder::new(mp4::Type::Normal);
+            builder.append(&l, row, 0..(f.end_time_90k - f.start_time_90k) as i32)?;
+            builder
+        }
+        .build(self.db.clone(), self.dirs_by_stream_id.clone())?;
+        loop {
+            match self.write_mp4(&f.path, &mp4) {
+                Ok(bytes) => return Ok(IndexFile { bytes, ..f }),
+                Err(e) if is_enospc(&e) => {
+                    if !index.prune_oldest(&self.dir)? {
+                        bail!("archive disk is too small to hold {}", f.path);
+                    }
+                    warn!("archive: disk full; pruned oldest recording");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Writes `mp4` to `path`, returning its length.
+    fn write_mp4(&self, path: &str, mp4: &mp4::File) -> Result<u64, Error> {
+        use http_serve::Entity;
+        let p = self.dir.join(path);
+        fs::create_dir_all(p.parent().unwrap())?;
+        let tmp = p.with_extension("mp4.tmp");
+        let result = (|| -> Result<u64, Error> {
+            let mut f = fs::File::create(&tmp)?;
+            let mut body = std::pin::Pin::from(mp4.get_range(0..mp4.len()));
+            futures::executor::block_on(async {
+                while let Some(chunk) = body.next().await {
+                    let chunk = chunk.map_err(failure::Error::from_boxed_compat)?;
+                    f.write_all(chunk.bytes())?;
+                }
+                Ok::<_, Error>(())
+            })?;
+            f.sync_all()?;
+            fs::rename(&tmp, &p)?;
+            Ok(mp4.len())
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+}
+
+fn is_enospc(e: &Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .and_then(io::Error::raw_os_error)
+        .map(|errno| errno == libc::ENOSPC)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(dir: &Path, path: &str, start: i64, end: i64) -> IndexFile {
+        let p = dir.join(path);
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        fs::write(&p, b"mp4").unwrap();
+        IndexFile {
+            path: path.to_owned(),
+            recording_id: 0,
+            start_time_90k: start,
+            end_time_90k: end,
+            bytes: 3,
+        }
+    }
+
+    #[test]
+    fn sanitize_names() {
+        assert_eq!(sanitize("back yard/2"), "back_yard_2");
+        assert_eq!(sanitize("driveway-1.cam"), "driveway-1.cam");
+    }
+
+    #[test]
+    fn prune() {
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let dir = tmpdir.path();
+        let mut index = Index {
+            streams: vec![
+                IndexStream {
+                    camera_uuid: Uuid::nil(),
+                    camera_short_name: "a".to_owned(),
+                    stream: "main".to_owned(),
+                    last_recording_id: 2,
+                    files: vec![
+                        file(dir, "a-main/2020-01-01/1.mp4", 0, 10),
+                        file(dir, "a-main/2020-01-02/2.mp4", 10, 20),
+                    ],
+                },
+                IndexStream {
+                    camera_uuid: Uuid::nil(),
+                    camera_short_name: "b".to_owned(),
+                    stream: "main".to_owned(),
+                    last_recording_id: 1,
+                    files: vec![file(dir, "b-main/2020-01-02/1.mp4", 5, 25)],
+                },
+            ],
+        };
+        index.write(dir).unwrap();
+        let mut index = Index::read(dir).unwrap();
+
+        // Expiry removes the file and its now-empty day directory.
+        assert_eq!(index.prune(dir, recording::Time(10)).unwrap(), 1);
+        assert!(!dir.join("a-main/2020-01-01").exists());
+        assert!(dir.join("a-main/2020-01-02/2.mp4").exists());
+
+        // Pruning for space takes the oldest file of any stream.
+        assert!(index.prune_oldest(dir).unwrap());
+        assert!(!dir.join("b-main/2020-01-02/1.mp4").exists());
+        assert!(dir.join("a-main/2020-01-02/2.mp4").exists());
+        assert!(index.prune_oldest(dir).unwrap());
+        assert!(!index.prune_oldest(dir).unwrap());
+    }
+}
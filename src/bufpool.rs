@@ -0,0 +1,137 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2016-2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A pool of fixed-size buffers for reading sample files.
+//!
+//! By default, `mp4.rs` serves sample data by `mmap()`ing each recording's range, which on
+//! low-memory devices can mean a storm of page faults and a lot of page cache churn during
+//! concurrent playback. With `moonfire-nvr run --read-ahead-bytes=N`, it instead reads each range
+//! sequentially in `N`-byte chunks into buffers from this pool, hinting the kernel to prefetch the
+//! chunk after each one. Buffers are returned to the pool once hyper has sent them, so steady
+//! state playback allocates nothing.
+
+use parking_lot::Mutex;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+/// The most memory to keep in idle buffers, regardless of buffer size.
+const MAX_IDLE_BYTES: usize = 8 << 20;
+
+pub struct Pool {
+    buf_len: usize,
+    max_idle: usize,
+    idle: Mutex<Vec<Vec<u8>>>,
+}
+
+impl Pool {
+    pub fn new(buf_len: usize) -> Arc<Self> {
+        assert!(buf_len > 0);
+        Arc::new(Pool {
+            buf_len,
+            max_idle: std::cmp::max(1, MAX_IDLE_BYTES / buf_len),
+            idle: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// The length of each buffer, which is also the read-ahead distance.
+    pub fn buf_len(&self) -> usize {
+        self.buf_len
+    }
+
+    /// Returns an empty buffer with capacity `buf_len`, reusing an idle one if possible.
+    pub fn get(self: &Arc<Self>) -> Buf {
+        let v = self
+            .idle
+            .lock()
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(self.buf_len));
+        Buf {
+            v,
+            pool: self.clone(),
+        }
+    }
+}
+
+/// A buffer which returns itself to its `Pool` when dropped.
+pub struct Buf {
+    v: Vec<u8>,
+    pool: Arc<Pool>,
+}
+
+impl Deref for Buf {
+    type Target = Vec<u8>;
+    fn deref(&self) -> &Vec<u8> {
+        &self.v
+    }
+}
+
+impl DerefMut for Buf {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.v
+    }
+}
+
+impl Drop for Buf {
+    fn drop(&mut self) {
+        let mut v = std::mem::replace(&mut self.v, Vec::new());
+        if v.capacity() != self.pool.buf_len {
+            return; // resized by the user; don't keep it around.
+        }
+        let mut idle = self.pool.idle.lock();
+        if idle.len() < self.pool.max_idle {
+            v.clear();
+            idle.push(v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse() {
+        let pool = Pool::new(MAX_IDLE_BYTES / 2);
+        let mut a = pool.get();
+        a.extend_from_slice(b"asdf");
+        let a_ptr = a.as_ptr();
+        let b = pool.get();
+        let c = pool.get();
+        drop(a);
+        drop(b);
+        drop(c); // exceeds max_idle of 2, so it's freed.
+        assert_eq!(pool.idle.lock().len(), 2);
+        let d = pool.get();
+        assert!(d.is_empty());
+        assert_eq!(d.capacity(), MAX_IDLE_BYTES / 2);
+        let e = pool.get();
+        assert!(d.as_ptr() == a_ptr || e.as_ptr() == a_ptr);
+    }
+}
@@ -0,0 +1,192 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! User interface files bundled into the binary at compile time.
+//!
+//! With the `bundled-ui` feature, the contents of `ui-dist` (as produced by `yarn build`) are
+//! embedded via `include_dir!`, so the server and UI always come from the same build.
+//! Precompressed `.br` and `.gz` siblings written by webpack are served in place of the original
+//! when the client accepts them.
+
+use crate::body::{BoxedError, Chunk};
+use base::strutil;
+use fnv::FnvHashMap;
+use futures::{stream, Stream};
+use http::header::{self, HeaderMap, HeaderValue};
+use include_dir::{include_dir, Dir};
+use lazy_static::lazy_static;
+use std::ops::Range;
+use std::time::SystemTime;
+
+static UI: Dir = include_dir!("ui-dist");
+
+/// Content codings to try, in order of preference, with the suffix webpack gives such files.
+const ENCODINGS: [(&str, &str); 2] = [("br", ".br"), ("gzip", ".gz")];
+
+lazy_static! {
+    /// Strong etags of every bundled file, keyed by path relative to `ui-dist`.
+    static ref ETAGS: FnvHashMap<&'static str, HeaderValue> = {
+        let mut m = FnvHashMap::default();
+        add_etags(&UI, &mut m);
+        m
+    };
+}
+
+fn add_etags(dir: &Dir<'static>, m: &mut FnvHashMap<&'static str, HeaderValue>) {
+    for f in dir.files() {
+        let path = f.path().to_str().expect("bundled UI paths should be UTF-8");
+        let digest = base::crypto::sha1(f.contents());
+        let etag = format!("\"{}\"", strutil::hex(&digest));
+        m.insert(path, HeaderValue::from_str(&etag).unwrap());
+    }
+    for d in dir.dirs() {
+        add_etags(d, m);
+    }
+}
+
+/// Returns true iff `req_hdrs` has an `Accept-Encoding` which allows `coding`.
+fn accepts_encoding(req_hdrs: &HeaderMap, coding: &str) -> bool {
+    for v in req_hdrs.get_all(header::ACCEPT_ENCODING) {
+        let v = match v.to_str() {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        for item in v.split(',') {
+            let mut parts = item.split(';');
+            let name = parts.next().unwrap().trim();
+            if name != coding && name != "*" {
+                continue;
+            }
+            let q_zero = parts.any(|p| {
+                let p = p.trim();
+                p.starts_with("q=") && p[2..].trim().parse::<f32>().ok() == Some(0.)
+            });
+            return !q_zero;
+        }
+    }
+    false
+}
+
+/// A bundled file, possibly in a precompressed representation.
+pub struct Asset {
+    contents: &'static [u8],
+    encoding: Option<&'static str>,
+    etag: HeaderValue,
+    hdrs: HeaderMap,
+}
+
+impl Asset {
+    /// Looks up `path` (relative to `ui-dist`), choosing the best representation the request
+    /// accepts. Returns `None` if there's no such file.
+    pub fn get(path: &str, req_hdrs: &HeaderMap) -> Option<Self> {
+        let f = UI.get_file(path)?;
+        let mut asset = Asset {
+            contents: f.contents(),
+            encoding: None,
+            etag: ETAGS.get(path)?.clone(),
+            hdrs: HeaderMap::new(),
+        };
+        for &(coding, suffix) in &ENCODINGS {
+            let compressed_path = format!("{}{}", path, suffix);
+            let compressed = match UI.get_file(&compressed_path) {
+                None => continue,
+                Some(c) => c,
+            };
+            if accepts_encoding(req_hdrs, coding) {
+                asset.contents = compressed.contents();
+                asset.encoding = Some(coding);
+                asset.etag = ETAGS.get(&compressed_path[..])?.clone();
+                break;
+            }
+        }
+        Some(asset)
+    }
+
+    /// Adds `Content-Encoding` and `Vary` headers as appropriate for the chosen representation.
+    pub fn add_encoding_headers(&self, hdrs: &mut HeaderMap) {
+        if let Some(e) = self.encoding {
+            hdrs.insert(header::CONTENT_ENCODING, HeaderValue::from_static(e));
+        }
+        hdrs.insert(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+
+    /// Converts into an entity which will serve `hdrs` along with the contents.
+    pub fn into_entity(mut self, hdrs: HeaderMap) -> Self {
+        self.hdrs = hdrs;
+        self
+    }
+}
+
+impl http_serve::Entity for Asset {
+    type Data = Chunk;
+    type Error = BoxedError;
+
+    fn add_headers(&self, hdrs: &mut HeaderMap) {
+        for (k, v) in &self.hdrs {
+            hdrs.insert(k, v.clone());
+        }
+    }
+    fn last_modified(&self) -> Option<SystemTime> {
+        None
+    }
+    fn etag(&self) -> Option<HeaderValue> {
+        Some(self.etag.clone())
+    }
+    fn len(&self) -> u64 {
+        self.contents.len() as u64
+    }
+    fn get_range(
+        &self,
+        range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        let chunk = &self.contents[range.start as usize..range.end as usize];
+        Box::new(stream::once(futures::future::ok(chunk.into())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::accepts_encoding;
+    use http::header::{self, HeaderMap, HeaderValue};
+
+    #[test]
+    fn accept_encoding() {
+        let mut h = HeaderMap::new();
+        assert!(!accepts_encoding(&h, "gzip"));
+        h.insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_static("gzip, deflate, br;q=0"),
+        );
+        assert!(accepts_encoding(&h, "gzip"));
+        assert!(!accepts_encoding(&h, "br"));
+        h.insert(header::ACCEPT_ENCODING, HeaderValue::from_static("*"));
+        assert!(accepts_encoding(&h, "br"));
+    }
+}
@@ -0,0 +1,150 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Subcommand to take an online backup of the SQLite database.
+
+use base::clock;
+use failure::{bail, Error};
+use rusqlite::backup::{Backup, StepResult};
+use std::io::Write as _;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use structopt::StructOpt;
+use tracing::{info, warn};
+
+/// The maximum number of attempts to copy the database while it's busy.
+const MAX_ATTEMPTS: u32 = 100;
+
+#[derive(StructOpt)]
+pub struct Args {
+    /// Directory holding the SQLite3 index database.
+    #[structopt(
+        long,
+        default_value = "/var/lib/moonfire-nvr/db",
+        value_name = "path",
+        parse(from_os_str)
+    )]
+    db_dir: PathBuf,
+
+    /// Path to write the copy of the database to. It must not already exist.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    out: PathBuf,
+
+    /// Also writes a manifest of the sample files referenced by the copy.
+    ///
+    /// Each line has a sample file's full path and its length in bytes, separated by a tab.
+    /// This can drive a backup of the matching sample files, as in
+    /// "cut -f1 manifest | rsync --files-from=- / backup-host:dest/".
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    manifest: Option<PathBuf>,
+}
+
+pub fn run(args: &Args) -> Result<(), Error> {
+    // Unlike other subcommands, this doesn't lock the database directory; the point is to run
+    // alongside the server, which holds an exclusive lock. SQLite's own locking keeps the copy
+    // consistent.
+    let db_path = args.db_dir.join("db");
+    if !db_path.exists() {
+        bail!("no database in {}", args.db_dir.display());
+    }
+    if args.out.exists() {
+        bail!("{} already exists", args.out.display());
+    }
+    let src = rusqlite::Connection::open_with_flags(
+        &db_path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    let mut dst = rusqlite::Connection::open(&args.out)?;
+    {
+        let backup = Backup::new(&src, &mut dst)?;
+        let mut attempts = 0;
+        loop {
+            // Copy all pages in one step so the copy is a consistent snapshot. In WAL mode, this
+            // doesn't block the server's writes.
+            match backup.step(-1)? {
+                StepResult::Done => break,
+                StepResult::More => {}
+                StepResult::Busy | StepResult::Locked => {
+                    attempts += 1;
+                    if attempts >= MAX_ATTEMPTS {
+                        bail!("database stayed busy through {} attempts", attempts);
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                }
+            }
+        }
+    }
+    let check: String =
+        dst.query_row("pragma quick_check", rusqlite::params![], |row| row.get(0))?;
+    if check != "ok" {
+        bail!("copy failed quick_check: {}", check);
+    }
+    info!("Wrote database copy to {}", args.out.display());
+
+    if let Some(ref manifest) = args.manifest {
+        let db = db::Database::new(clock::RealClocks {}, dst, false)?;
+        let l = db.lock();
+        let mut f = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(manifest)?;
+        let mut files = 0;
+        for s in l.streams_by_id().values() {
+            l.list_recordings_by_id(s.id, 0..i32::max_value(), &mut |r| {
+                let dir = s
+                    .dir_id_for(r.id.recording(), r.flags)
+                    .and_then(|d| l.sample_file_dirs_by_id().get(&d));
+                match dir {
+                    None => warn!("{}: no sample file dir", r.id),
+                    Some(d) => {
+                        writeln!(
+                            &mut f,
+                            "{}/{:016x}\t{}",
+                            d.path, r.id.0, r.sample_file_bytes
+                        )?;
+                        files += 1;
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        f.sync_all()?;
+        info!(
+            "Wrote manifest of {} sample files to {}",
+            files,
+            manifest.display()
+        );
+    } else {
+        drop(dst);
+    }
+    std::fs::File::open(&args.out)?.sync_all()?;
+    Ok(())
+}
@@ -30,9 +30,11 @@
 
 //! Subcommand to check the database and sample file dir for errors.
 
-use db::check;
-use failure::Error;
+use base::clock;
+use db::{check, dir, recording};
+use failure::{format_err, Error};
 use std::path::PathBuf;
+use std::sync::Arc;
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -49,15 +51,121 @@ pub struct Args {
     /// Compare sample file lengths on disk to the database.
     #[structopt(long)]
     compare_lens: bool,
+
+    /// Read every recording's sample file, checking its length and digest against the database.
+    ///
+    /// Corrupt recordings are logged and noted in the database. This reads all recorded video,
+    /// so it may take a long time.
+    #[structopt(long)]
+    scrub: bool,
+
+    /// File holding the key with which to decrypt encrypted sample files when scrubbing or
+    /// adopting orphans, as given to `moonfire-nvr run --sample-file-key`.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    sample_file_key: Option<PathBuf>,
+
+    /// Reconcile a database restored from an older backup with its newer sample file dirs.
+    ///
+    /// Removes recordings whose files have since been deleted and re-adopts files written since
+    /// the backup, reconstructing their recording rows with estimated times, rather than letting
+    /// `moonfire-nvr run` abandon them. This modifies the database and directory metadata.
+    #[structopt(long)]
+    adopt_orphans: bool,
+
+    /// Rather than checking the database, list the intervals in which each stream wasn't
+    /// recorded, with the reason when known.
+    ///
+    /// Reasons are inferred from the recordings on either side of each gap: the server being
+    /// down, the stream disconnecting (such as when the camera goes offline), or a disk error
+    /// (recording resumed in the failover directory).
+    #[structopt(long)]
+    report_gaps: bool,
+
+    /// The start of the range for --report-gaps, such as "2020-07-01T00:00:00". Defaults to
+    /// each stream's oldest recording.
+    #[structopt(long, value_name = "time")]
+    gaps_start: Option<String>,
+
+    /// The end of the range for --report-gaps. Defaults to each stream's newest recording.
+    #[structopt(long, value_name = "time")]
+    gaps_end: Option<String>,
+
+    /// The shortest gap to report with --report-gaps, in seconds.
+    #[structopt(long, value_name = "secs", default_value = "1")]
+    min_gap_sec: i64,
+}
+
+fn parse_time(name: &str, t: &Option<String>) -> Result<Option<recording::Time>, Error> {
+    match t {
+        None => Ok(None),
+        Some(t) => recording::Time::parse(t)
+            .map(Some)
+            .map_err(|_| format_err!("unparseable --{} {:?}", name, t)),
+    }
+}
+
+/// Prints each stream's gaps for `--report-gaps`.
+fn report_gaps(args: &Args) -> Result<(), Error> {
+    let start = parse_time("gaps-start", &args.gaps_start)?;
+    let end = parse_time("gaps-end", &args.gaps_end)?;
+    let min_gap = recording::Duration(args.min_gap_sec * recording::TIME_UNITS_PER_SEC);
+    let (_db_dir, conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadOnly)?;
+    let db = db::Database::new(clock::RealClocks {}, conn, false)?;
+    let db = db.lock();
+    for (&stream_id, s) in db.streams_by_id() {
+        let camera = &db.cameras_by_id()[&s.camera_id];
+        let name = format!("{}/{}", camera.short_name, s.type_.as_str());
+        let range = match (start, end, s.range.as_ref()) {
+            (Some(s), Some(e), _) => s..e,
+            (s, e, Some(r)) => s.unwrap_or(r.start)..e.unwrap_or(r.end),
+            (_, _, None) => {
+                println!("{}: no recordings", name);
+                continue;
+            }
+        };
+        if range.start >= range.end {
+            continue;
+        }
+        let gaps = db.list_gaps(stream_id, range.clone(), min_gap)?;
+        let total = recording::Duration(gaps.iter().map(|g| (g.time.end - g.time.start).0).sum());
+        println!(
+            "{}: {} gaps totaling {} between {} and {}",
+            name,
+            gaps.len(),
+            total,
+            range.start,
+            range.end
+        );
+        for g in &gaps {
+            println!(
+                "    {} - {} ({}): {}",
+                g.time.start,
+                g.time.end,
+                g.time.end - g.time.start,
+                g.reason.as_str()
+            );
+        }
+    }
+    Ok(())
 }
 
 pub fn run(args: &Args) -> Result<(), Error> {
+    if args.report_gaps {
+        return report_gaps(args);
+    }
     // TODO: ReadOnly should be sufficient but seems to fail.
-    let (_db_dir, conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadWrite)?;
+    let (_db_dir, mut conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadWrite)?;
+    let sample_file_key = match args.sample_file_key {
+        None => None,
+        Some(ref p) => Some(Arc::new(dir::SampleFileKey::read(p)?)),
+    };
     check::run(
-        &conn,
+        &mut conn,
         &check::Options {
             compare_lens: args.compare_lens,
+            scrub: args.scrub,
+            sample_file_key,
+            adopt_orphans: args.adopt_orphans,
         },
     )
 }
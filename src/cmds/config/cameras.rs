@@ -34,7 +34,7 @@ use cursive::traits::{Boxable, Finder, Identifiable};
 use cursive::views;
 use cursive::Cursive;
 use db::writer;
-use failure::Error;
+use failure::{format_err, Error};
 use std::collections::BTreeMap;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -73,12 +73,17 @@ fn get_change(siv: &mut Cursive) -> db::CameraChange {
         .get_content()
         .as_str()
         .into();
+    let ptz = siv
+        .find_name::<views::Checkbox>("ptz")
+        .unwrap()
+        .is_checked();
     let mut c = db::CameraChange {
         short_name: sn,
         description: d,
         onvif_host: h,
         username: u,
         password: p,
+        ptz,
         streams: Default::default(),
     };
     for &t in &db::ALL_STREAM_TYPES {
@@ -99,31 +104,193 @@ fn get_change(siv: &mut Cursive) -> db::CameraChange {
                 .as_str(),
         )
         .unwrap_or(0);
+        let fb = decode_size(
+            siv.find_name::<views::EditView>(&format!("{}_flush_if_bytes", t.as_str()))
+                .unwrap()
+                .get_content()
+                .as_str(),
+        )
+        .unwrap_or(0);
+        let o = siv
+            .find_name::<views::EditView>(&format!("{}_input_options", t.as_str()))
+            .unwrap()
+            .get_content()
+            .as_str()
+            .into();
+        let transport = *siv
+            .find_name::<views::SelectView<db::RtspTransport>>(&format!(
+                "{}_rtsp_transport",
+                t.as_str()
+            ))
+            .unwrap()
+            .selection()
+            .unwrap();
+        let sec = |siv: &mut Cursive, name: &str| {
+            i64::from_str(
+                siv.find_name::<views::EditView>(&format!("{}_{}", t.as_str(), name))
+                    .unwrap()
+                    .get_content()
+                    .trim(),
+            )
+            .unwrap_or(0)
+        };
+        let rtsp = db::RtspPolicy {
+            transport,
+            reconnect_min_sec: sec(siv, "reconnect_min_sec"),
+            reconnect_max_sec: sec(siv, "reconnect_max_sec"),
+            session_timeout_sec: sec(siv, "session_timeout_sec"),
+        };
         let d = *siv
             .find_name::<views::SelectView<Option<i32>>>(&format!("{}_sample_file_dir", t.as_str()))
             .unwrap()
             .selection()
             .unwrap();
+        let failover = *siv
+            .find_name::<views::SelectView<Option<i32>>>(&format!("{}_failover_dir", t.as_str()))
+            .unwrap()
+            .selection()
+            .unwrap();
         c.streams[t.index()] = db::StreamChange {
             rtsp_url: u,
             sample_file_dir_id: d,
+            stripe_dir_ids: Vec::new(),
+            failover_sample_file_dir_id: failover,
+            virtual_source: None,
             record: r,
             flush_if_sec: f,
+            flush_if_bytes: fb,
+            input_options: o,
+            rtsp,
         };
     }
     c
 }
 
+/// Fills in each stream's `stripe_dir_ids` from the comma-separated paths in the active
+/// `edit_camera_dialog`.
+fn fill_stripe_dir_ids(
+    siv: &mut Cursive,
+    l: &db::LockedDatabase,
+    c: &mut db::CameraChange,
+) -> Result<(), Error> {
+    for &t in &db::ALL_STREAM_TYPES {
+        let paths = siv
+            .find_name::<views::EditView>(&format!("{}_stripe_dirs", t.as_str()))
+            .unwrap()
+            .get_content();
+        let ids = &mut c.streams[t.index()].stripe_dir_ids;
+        for p in paths.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let id = l
+                .sample_file_dirs_by_id()
+                .iter()
+                .find(|(_, d)| d.path == p)
+                .map(|(&id, _)| id)
+                .ok_or_else(|| format_err!("no such sample file dir {:?}", p))?;
+            ids.push(id);
+        }
+    }
+    Ok(())
+}
+
+/// Fills in each stream's `virtual_source` from the active `edit_camera_dialog`. The source is
+/// written as in `format_virtual_source`.
+fn fill_virtual_sources(
+    siv: &mut Cursive,
+    l: &db::LockedDatabase,
+    c: &mut db::CameraChange,
+) -> Result<(), Error> {
+    for &t in &db::ALL_STREAM_TYPES {
+        let content = siv
+            .find_name::<views::EditView>(&format!("{}_virtual_source", t.as_str()))
+            .unwrap()
+            .get_content();
+        if content.trim().is_empty() {
+            continue;
+        }
+        let v = parse_virtual_source(l, &content).ok_or_else(|| {
+            format_err!(
+                "bad {} virtual source {:?}; expected e.g. \"pano-main 1920x1080+0+0 1280x720\"",
+                t,
+                content
+            )
+        })?;
+        c.streams[t.index()].virtual_source = Some(v);
+    }
+    Ok(())
+}
+
+/// Parses a size such as `1280x720`.
+fn parse_size(s: &str) -> Option<(i32, i32)> {
+    let mut parts = s.splitn(2, 'x');
+    let w = parts.next()?.parse().ok()?;
+    let h = parts.next()?.parse().ok()?;
+    Some((w, h))
+}
+
+/// Parses a virtual source such as `pano-main 1920x1080+1920+0 1280x720`: the source camera's
+/// short name and stream type, the crop region (size and offset), and the output size.
+fn parse_virtual_source(l: &db::LockedDatabase, s: &str) -> Option<db::VirtualSource> {
+    let mut parts = s.split_whitespace();
+    let (source, crop, size) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+    let mut source = source.rsplitn(2, '-');
+    let type_ = db::StreamType::parse(source.next()?)?;
+    let camera_name = source.next()?;
+    let source_stream_id = l
+        .cameras_by_id()
+        .values()
+        .find(|c| c.short_name == camera_name)?
+        .streams[type_.index()]?;
+    let mut crop = crop.split('+');
+    let (crop_width, crop_height) = parse_size(crop.next()?)?;
+    let crop_x = crop.next()?.parse().ok()?;
+    let crop_y = crop.next()?.parse().ok()?;
+    if crop.next().is_some() {
+        return None;
+    }
+    let (width, height) = parse_size(size)?;
+    Some(db::VirtualSource {
+        source_stream_id,
+        crop_x,
+        crop_y,
+        crop_width,
+        crop_height,
+        width,
+        height,
+    })
+}
+
+fn format_virtual_source(l: &db::LockedDatabase, v: &db::VirtualSource) -> String {
+    let s = &l.streams_by_id()[&v.source_stream_id];
+    format!(
+        "{}-{} {}x{}+{}+{} {}x{}",
+        l.cameras_by_id()[&s.camera_id].short_name,
+        s.type_.as_str(),
+        v.crop_width,
+        v.crop_height,
+        v.crop_x,
+        v.crop_y,
+        v.width,
+        v.height
+    )
+}
+
 fn press_edit(siv: &mut Cursive, db: &Arc<db::Database>, id: Option<i32>) {
-    let change = get_change(siv);
+    let mut change = get_change(siv);
 
     let result = {
         let mut l = db.lock();
-        if let Some(id) = id {
-            l.update_camera(id, change)
-        } else {
-            l.add_camera(change).map(|_| ())
-        }
+        fill_stripe_dir_ids(siv, &l, &mut change)
+            .and_then(|()| fill_virtual_sources(siv, &l, &mut change))
+            .and_then(|()| {
+                if let Some(id) = id {
+                    l.update_camera(id, change)
+                } else {
+                    l.add_camera(change).map(|_| ())
+                }
+            })
     };
     if let Err(e) = result {
         siv.add_layer(
@@ -140,10 +307,38 @@ fn press_edit(siv: &mut Cursive, db: &Arc<db::Database>, id: Option<i32>) {
     }
 }
 
-fn press_test_inner(url: &Url) -> Result<String, Error> {
-    let stream = stream::FFMPEG.open(stream::Source::Rtsp {
-        url: url.as_str(),
-        redacted_url: url.as_str(), // don't need redaction in config UI.
+fn press_test_inner(url: &Url, password: &str, s: &db::StreamChange) -> Result<String, Error> {
+    let v4l2 = stream::V4l2Device::parse(url)?;
+    let mjpeg = stream::Mjpeg::parse(url)?;
+    let srt = stream::Srt::parse(url, password)?;
+    let mut url = url.clone();
+    url.set_fragment(None);
+    let stream = stream::FFMPEG.open(match (v4l2, mjpeg, srt) {
+        (Some(ref device), _, _) => stream::Source::V4l2 {
+            device,
+            input_options: &s.input_options,
+            sched: &Default::default(),
+        },
+        (None, Some(mjpeg), _) => stream::Source::Mjpeg {
+            url: url.as_str(),
+            redacted_url: url.as_str(),
+            encode: mjpeg.encode,
+            input_options: &s.input_options,
+            sched: &Default::default(),
+        },
+        (None, None, Some(ref srt)) => stream::Source::Srt {
+            url: url.as_str(),
+            redacted_url: url.as_str(),
+            passphrase: srt.passphrase.as_deref(),
+            input_options: &s.input_options,
+            rtsp: s.rtsp,
+        },
+        (None, None, None) => stream::Source::Rtsp {
+            url: url.as_str(),
+            redacted_url: url.as_str(), // don't need redaction in config UI.
+            input_options: &s.input_options,
+            rtsp: s.rtsp,
+        },
     })?;
     let extra_data = stream.get_extra_data()?;
     Ok(format!(
@@ -153,7 +348,7 @@ fn press_test_inner(url: &Url) -> Result<String, Error> {
 }
 
 fn press_test(siv: &mut Cursive, t: db::StreamType) {
-    let c = get_change(siv);
+    let mut c = get_change(siv);
     let mut url = match Url::parse(&c.streams[t.index()].rtsp_url) {
         Ok(u) => u,
         Err(e) => {
@@ -166,7 +361,8 @@ fn press_test(siv: &mut Cursive, t: db::StreamType) {
         }
     };
 
-    if !c.username.is_empty() {
+    // An SRT stream's passphrase is passed separately.
+    if !c.username.is_empty() && url.scheme() != "srt" {
         let _ = url.set_username(&c.username);
         let _ = url.set_password(Some(&c.password));
     }
@@ -184,8 +380,10 @@ fn press_test(siv: &mut Cursive, t: db::StreamType) {
     // siv.cb_sink doesn't actually wake up the event loop. Tell siv to poll, as a workaround.
     siv.set_fps(5);
     let sink = siv.cb_sink().clone();
+    let s = std::mem::replace(&mut c.streams[t.index()], Default::default());
+    let password = c.password;
     ::std::thread::spawn(move || {
-        let r = press_test_inner(&url);
+        let r = press_test_inner(&url, &password, &s);
         sink.send(Box::new(move |siv: &mut Cursive| {
             // Polling is no longer necessary.
             siv.set_fps(0);
@@ -305,7 +503,20 @@ fn lower_retention(
     db: &Arc<db::Database>,
     zero_limits: BTreeMap<i32, Vec<writer::NewLimit>>,
 ) -> Result<(), Error> {
-    let dirs_to_open: Vec<_> = zero_limits.keys().map(|id| *id).collect();
+    let dirs_to_open: Vec<_> = {
+        let l = db.lock();
+        let mut dirs: Vec<i32> = zero_limits
+            .values()
+            .flat_map(|limits| limits.iter())
+            .flat_map(|limit| {
+                let s = &l.streams_by_id()[&limit.stream_id];
+                s.dir_ids().into_iter().chain(s.failover_sample_file_dir_id)
+            })
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+        dirs
+    };
     db.lock().open_sample_file_dirs(&dirs_to_open[..])?;
     for (&dir_id, l) in &zero_limits {
         writer::lower_retention(db.clone(), dir_id, &l)?;
@@ -348,7 +559,8 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
         .child("onvif_host", views::EditView::new().with_name("onvif_host"))
         .child("username", views::EditView::new().with_name("username"))
         .child("password", views::EditView::new().with_name("password"))
-        .min_height(6);
+        .child("ptz", views::Checkbox::new().with_name("ptz"))
+        .min_height(7);
     let mut layout = views::LinearLayout::vertical()
         .child(camera_list)
         .child(views::TextView::new("description"))
@@ -366,6 +578,7 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
                 .map(|(&id, d)| (d.path.as_str().to_owned(), Some(id))),
         )
         .collect();
+    let rtsp = db::RtspPolicy::default();
     for &type_ in &db::ALL_STREAM_TYPES {
         let list = views::ListView::new()
             .child(
@@ -381,6 +594,10 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
                         press_test(siv, type_)
                     })),
             )
+            .child(
+                "virtual source",
+                views::EditView::new().with_name(format!("{}_virtual_source", type_.as_str())),
+            )
             .child(
                 "sample file dir",
                 views::SelectView::<Option<i32>>::new()
@@ -388,6 +605,17 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
                     .popup()
                     .with_name(format!("{}_sample_file_dir", type_.as_str())),
             )
+            .child(
+                "stripe dirs",
+                views::EditView::new().with_name(format!("{}_stripe_dirs", type_.as_str())),
+            )
+            .child(
+                "failover dir",
+                views::SelectView::<Option<i32>>::new()
+                    .with_all(dirs.iter().map(|d| d.clone()))
+                    .popup()
+                    .with_name(format!("{}_failover_dir", type_.as_str())),
+            )
             .child(
                 "record",
                 views::Checkbox::new().with_name(format!("{}_record", type_.as_str())),
@@ -396,11 +624,48 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
                 "flush_if_sec",
                 views::EditView::new().with_name(format!("{}_flush_if_sec", type_.as_str())),
             )
+            .child(
+                "flush_if_bytes",
+                views::EditView::new().with_name(format!("{}_flush_if_bytes", type_.as_str())),
+            )
+            .child(
+                "input options",
+                views::EditView::new().with_name(format!("{}_input_options", type_.as_str())),
+            )
+            .child(
+                "rtsp transport",
+                views::SelectView::<db::RtspTransport>::new()
+                    .with_all(
+                        [db::RtspTransport::Tcp, db::RtspTransport::Udp]
+                            .iter()
+                            .map(|&t| (t.as_str(), t)),
+                    )
+                    .popup()
+                    .with_name(format!("{}_rtsp_transport", type_.as_str())),
+            )
+            .child(
+                "reconnect min sec",
+                views::EditView::new()
+                    .content(rtsp.reconnect_min_sec.to_string())
+                    .with_name(format!("{}_reconnect_min_sec", type_.as_str())),
+            )
+            .child(
+                "reconnect max sec",
+                views::EditView::new()
+                    .content(rtsp.reconnect_max_sec.to_string())
+                    .with_name(format!("{}_reconnect_max_sec", type_.as_str())),
+            )
+            .child(
+                "session timeout sec",
+                views::EditView::new()
+                    .content(rtsp.session_timeout_sec.to_string())
+                    .with_name(format!("{}_session_timeout_sec", type_.as_str())),
+            )
             .child(
                 "usage/capacity",
                 views::TextView::new("").with_name(format!("{}_usage_cap", type_.as_str())),
             )
-            .min_height(5);
+            .min_height(7);
         layout.add_child(views::DummyView);
         layout.add_child(views::TextView::new(format!("{} stream", type_.as_str())));
         layout.add_child(list);
@@ -420,15 +685,17 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
         for (i, sid) in camera.streams.iter().enumerate() {
             let t = db::StreamType::from_index(i).unwrap();
 
-            // Find the index into dirs of the stored sample file dir.
-            let mut selected_dir = 0;
+            // Find the index into dirs of the stored sample file and failover dirs.
+            let (mut selected_dir, mut selected_failover_dir) = (0, 0);
             if let Some(s) = sid.map(|sid| l.streams_by_id().get(&sid).unwrap()) {
-                if let Some(id) = s.sample_file_dir_id {
-                    for (i, &(_, d_id)) in dirs.iter().skip(1).enumerate() {
-                        if Some(id) == d_id {
-                            selected_dir = i + 1;
-                            break;
-                        }
+                for (i, &(_, d_id)) in dirs.iter().enumerate().skip(1) {
+                    if s.sample_file_dir_id.is_some() && s.sample_file_dir_id == d_id {
+                        selected_dir = i;
+                    }
+                    if s.failover_sample_file_dir_id.is_some()
+                        && s.failover_sample_file_dir_id == d_id
+                    {
+                        selected_failover_dir = i;
                     }
                 }
                 bytes += s.sample_file_bytes;
@@ -458,11 +725,58 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
                     &format!("{}_flush_if_sec", t.as_str()),
                     |v: &mut views::EditView| v.set_content(s.flush_if_sec.to_string()),
                 );
+                dialog.call_on_name(
+                    &format!("{}_flush_if_bytes", t.as_str()),
+                    |v: &mut views::EditView| v.set_content(encode_size(s.flush_if_bytes)),
+                );
+                dialog.call_on_name(
+                    &format!("{}_input_options", t.as_str()),
+                    |v: &mut views::EditView| v.set_content(s.input_options.clone()),
+                );
+                dialog.call_on_name(
+                    &format!("{}_rtsp_transport", t.as_str()),
+                    |v: &mut views::SelectView<db::RtspTransport>| {
+                        v.set_selection(match s.rtsp.transport {
+                            db::RtspTransport::Tcp => 0,
+                            db::RtspTransport::Udp => 1,
+                        })
+                    },
+                );
+                for &(name, value) in &[
+                    ("reconnect_min_sec", s.rtsp.reconnect_min_sec),
+                    ("reconnect_max_sec", s.rtsp.reconnect_max_sec),
+                    ("session_timeout_sec", s.rtsp.session_timeout_sec),
+                ] {
+                    dialog.call_on_name(
+                        &format!("{}_{}", t.as_str(), name),
+                        |v: &mut views::EditView| v.set_content(value.to_string()),
+                    );
+                }
+                let stripe_dirs: Vec<&str> = s
+                    .stripe_dir_ids
+                    .iter()
+                    .map(|id| l.sample_file_dirs_by_id()[id].path.as_str())
+                    .collect();
+                dialog.call_on_name(
+                    &format!("{}_stripe_dirs", t.as_str()),
+                    |v: &mut views::EditView| v.set_content(stripe_dirs.join(", ")),
+                );
+                if let Some(ref vs) = s.virtual_source {
+                    let vs = format_virtual_source(&l, vs);
+                    dialog.call_on_name(
+                        &format!("{}_virtual_source", t.as_str()),
+                        |v: &mut views::EditView| v.set_content(vs),
+                    );
+                }
             }
             dialog.call_on_name(
                 &format!("{}_sample_file_dir", t.as_str()),
                 |v: &mut views::SelectView<Option<i32>>| v.set_selection(selected_dir),
             );
+            dialog.call_on_name(
+                &format!("{}_failover_dir", t.as_str()),
+                |v: &mut views::SelectView<Option<i32>>| v.set_selection(selected_failover_dir),
+            );
         }
         let name = camera.short_name.clone();
         for &(view_id, content) in &[
@@ -477,6 +791,9 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
                 })
                 .expect("missing EditView");
         }
+        dialog
+            .call_on_name("ptz", |v: &mut views::Checkbox| v.set_checked(camera.ptz))
+            .expect("missing Checkbox");
         dialog
             .call_on_name("description", |v: &mut views::TextArea| {
                 v.set_content(camera.description.to_string())
@@ -0,0 +1,514 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Non-interactive configuration verbs, such as `moonfire-nvr config camera add`, for scripts.
+//!
+//! Each prints a single line of JSON to stdout on success. Changes print an object with a
+//! `changed` boolean and the affected camera, stream, or user in the form written by
+//! `moonfire-nvr config dump`. Users are printed without their password hashes.
+
+use super::declarative;
+use base::strutil::decode_size;
+use db::{writer, StreamType};
+use failure::{bail, format_err, Error};
+use serde_json::json;
+use std::io::BufRead;
+use std::sync::Arc;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub enum CameraCommand {
+    /// Adds a camera.
+    Add {
+        short_name: String,
+
+        #[structopt(flatten)]
+        flags: CameraFlags,
+    },
+
+    /// Changes the given settings of a camera, leaving the rest alone.
+    Update {
+        short_name: String,
+
+        #[structopt(flatten)]
+        flags: CameraFlags,
+    },
+
+    /// Deletes a camera, which must have no recordings.
+    Delete { short_name: String },
+
+    /// Lists cameras.
+    List,
+}
+
+#[derive(StructOpt)]
+pub struct CameraFlags {
+    #[structopt(long)]
+    description: Option<String>,
+
+    #[structopt(long, value_name = "host")]
+    onvif_host: Option<String>,
+
+    #[structopt(long)]
+    username: Option<String>,
+
+    #[structopt(long)]
+    password: Option<String>,
+
+    /// Whether the camera supports ONVIF PTZ control (`true` or `false`).
+    #[structopt(long, value_name = "bool")]
+    ptz: Option<bool>,
+
+    /// The main stream's url.
+    #[structopt(long, value_name = "url")]
+    main_url: Option<String>,
+
+    /// The path of the main stream's sample file directory, which must already be added.
+    #[structopt(long, value_name = "path")]
+    main_dir: Option<String>,
+
+    /// Whether to record the main stream (`true` or `false`).
+    #[structopt(long, value_name = "bool")]
+    main_record: Option<bool>,
+
+    #[structopt(long, value_name = "secs")]
+    main_flush_if_sec: Option<i64>,
+
+    /// The sub stream's url.
+    #[structopt(long, value_name = "url")]
+    sub_url: Option<String>,
+
+    /// The path of the sub stream's sample file directory, which must already be added.
+    #[structopt(long, value_name = "path")]
+    sub_dir: Option<String>,
+
+    /// Whether to record the sub stream (`true` or `false`).
+    #[structopt(long, value_name = "bool")]
+    sub_record: Option<bool>,
+
+    #[structopt(long, value_name = "secs")]
+    sub_flush_if_sec: Option<i64>,
+}
+
+#[derive(StructOpt)]
+pub enum StreamCommand {
+    /// Sets a stream's retention limit and, optionally, whether it's recorded.
+    SetRetention {
+        camera: String,
+
+        /// `main` or `sub`.
+        #[structopt(parse(try_from_str = parse_stream_type))]
+        stream: StreamType,
+
+        /// The limit, such as `100G` or `512M`.
+        #[structopt(long, value_name = "size")]
+        retain: String,
+
+        /// Whether to record the stream (`true` or `false`); unchanged if absent.
+        #[structopt(long, value_name = "bool")]
+        record: Option<bool>,
+    },
+
+    /// Deletes a stream and all its recordings.
+    Delete {
+        camera: String,
+
+        /// `main` or `sub`.
+        #[structopt(parse(try_from_str = parse_stream_type))]
+        stream: StreamType,
+    },
+}
+
+#[derive(StructOpt)]
+pub enum UserCommand {
+    /// Adds a user.
+    Add {
+        username: String,
+
+        /// Reads the password from the first line of stdin. Without this, the user has no
+        /// password and can only log in by single sign-on or `moonfire-nvr login`.
+        #[structopt(long)]
+        password_stdin: bool,
+
+        /// Grants a permission, such as `view_video`. May be repeated.
+        #[structopt(long = "permission", value_name = "name")]
+        permissions: Vec<String>,
+
+        /// Restricts the user to the given camera (by short name). May be repeated.
+        #[structopt(long = "camera", value_name = "short_name")]
+        cameras: Vec<String>,
+
+        /// The Unix uid allowed to authenticate as this user over a Unix socket.
+        #[structopt(long, value_name = "uid")]
+        unix_uid: Option<i32>,
+    },
+
+    /// Deletes a user.
+    Delete { username: String },
+
+    /// Lists users.
+    List,
+}
+
+fn parse_stream_type(s: &str) -> Result<StreamType, Error> {
+    StreamType::parse(s).ok_or_else(|| format_err!("unknown stream type {:?}", s))
+}
+
+impl CameraFlags {
+    fn apply(&self, c: &mut declarative::Camera) {
+        let set = |dst: &mut String, src: &Option<String>| {
+            if let Some(ref v) = *src {
+                *dst = v.clone();
+            }
+        };
+        set(&mut c.description, &self.description);
+        set(&mut c.onvif_host, &self.onvif_host);
+        set(&mut c.username, &self.username);
+        set(&mut c.password, &self.password);
+        if let Some(ptz) = self.ptz {
+            c.ptz = ptz;
+        }
+        apply_stream(
+            &mut c.main,
+            &self.main_url,
+            &self.main_dir,
+            self.main_record,
+            self.main_flush_if_sec,
+        );
+        apply_stream(
+            &mut c.sub,
+            &self.sub_url,
+            &self.sub_dir,
+            self.sub_record,
+            self.sub_flush_if_sec,
+        );
+    }
+}
+
+fn apply_stream(
+    s: &mut Option<declarative::Stream>,
+    url: &Option<String>,
+    dir: &Option<String>,
+    record: Option<bool>,
+    flush_if_sec: Option<i64>,
+) {
+    if url.is_none() && dir.is_none() && record.is_none() && flush_if_sec.is_none() {
+        return;
+    }
+    let s = s.get_or_insert_with(declarative::Stream::default);
+    if let Some(ref u) = *url {
+        s.rtsp_url = u.clone();
+    }
+    if let Some(ref d) = *dir {
+        s.sample_file_dir = Some(d.clone()).filter(|d| !d.is_empty());
+    }
+    if let Some(r) = record {
+        s.record = r;
+    }
+    if let Some(f) = flush_if_sec {
+        s.flush_if_sec = f;
+    }
+}
+
+fn find_camera(l: &db::LockedDatabase, short_name: &str) -> Option<declarative::Camera> {
+    declarative::dump(l)
+        .cameras
+        .into_iter()
+        .find(|c| c.short_name == short_name)
+}
+
+fn find_user(l: &db::LockedDatabase, username: &str) -> Option<declarative::User> {
+    declarative::dump(l)
+        .users
+        .into_iter()
+        .find(|u| u.username == username)
+        .map(|mut u| {
+            u.password_hash = None;
+            u
+        })
+}
+
+/// Applies `c` alone, returning whether anything changed.
+fn apply_camera(l: &mut db::LockedDatabase, c: declarative::Camera) -> Result<bool, Error> {
+    let config = declarative::Config {
+        cameras: vec![c],
+        ..Default::default()
+    };
+    let mut actions = Vec::new();
+    declarative::apply(l, config, false, false, &mut actions)?;
+    Ok(!actions.is_empty())
+}
+
+pub fn camera(db: &db::Database, cmd: &CameraCommand) -> Result<serde_json::Value, Error> {
+    let mut l = db.lock();
+    match *cmd {
+        CameraCommand::Add {
+            ref short_name,
+            ref flags,
+        } => {
+            if declarative::camera_id(&l, short_name).is_some() {
+                bail!("camera {:?} already exists", short_name);
+            }
+            let mut c = declarative::Camera {
+                short_name: short_name.clone(),
+                description: String::new(),
+                onvif_host: String::new(),
+                username: String::new(),
+                password: String::new(),
+                ptz: false,
+                main: None,
+                sub: None,
+            };
+            flags.apply(&mut c);
+            apply_camera(&mut l, c)?;
+            Ok(json!({"changed": true, "camera": find_camera(&l, short_name)}))
+        }
+        CameraCommand::Update {
+            ref short_name,
+            ref flags,
+        } => {
+            let mut c = find_camera(&l, short_name)
+                .ok_or_else(|| format_err!("no camera {:?}", short_name))?;
+            flags.apply(&mut c);
+            let changed = apply_camera(&mut l, c)?;
+            Ok(json!({"changed": changed, "camera": find_camera(&l, short_name)}))
+        }
+        CameraCommand::Delete { ref short_name } => {
+            let id = declarative::camera_id(&l, short_name)
+                .ok_or_else(|| format_err!("no camera {:?}", short_name))?;
+            l.delete_camera(id)?;
+            Ok(json!({ "changed": true }))
+        }
+        CameraCommand::List => Ok(json!({ "cameras": declarative::dump(&l).cameras })),
+    }
+}
+
+pub fn stream(db: &Arc<db::Database>, cmd: &StreamCommand) -> Result<serde_json::Value, Error> {
+    let mut l = db.lock();
+    match *cmd {
+        StreamCommand::SetRetention {
+            ref camera,
+            stream,
+            ref retain,
+            record,
+        } => {
+            let new_limit =
+                decode_size(retain).map_err(|()| format_err!("bad retain {:?}", retain))?;
+            let camera_id = declarative::camera_id(&l, camera)
+                .ok_or_else(|| format_err!("no camera {:?}", camera))?;
+            let stream_id = l.cameras_by_id()[&camera_id].streams[stream.index()]
+                .ok_or_else(|| format_err!("camera {:?} has no {} stream", camera, stream))?;
+            let s = &l.streams_by_id()[&stream_id];
+            let new_record = record.unwrap_or(s.record);
+            let changed = new_record != s.record || new_limit != s.retain_bytes;
+            if changed {
+                l.update_retention(&[db::RetentionChange {
+                    stream_id,
+                    new_record,
+                    new_limit,
+                }])?;
+            }
+            let c = find_camera(&l, camera).unwrap();
+            let s = match stream {
+                StreamType::MAIN => c.main,
+                StreamType::SUB => c.sub,
+            };
+            Ok(json!({"changed": changed, "stream": s}))
+        }
+        StreamCommand::Delete { ref camera, stream } => {
+            let camera_id = declarative::camera_id(&l, camera)
+                .ok_or_else(|| format_err!("no camera {:?}", camera))?;
+            let stream_id = l.cameras_by_id()[&camera_id].streams[stream.index()]
+                .ok_or_else(|| format_err!("camera {:?} has no {} stream", camera, stream))?;
+            let s = &l.streams_by_id()[&stream_id];
+            let dir_ids: Vec<i32> = s
+                .dir_ids()
+                .into_iter()
+                .chain(s.failover_sample_file_dir_id)
+                .collect();
+            l.delete_stream(stream_id)?;
+
+            // No syncers are running to unlink the sample files, so do it now.
+            l.open_sample_file_dirs(&dir_ids)?;
+            drop(l);
+            for dir_id in dir_ids {
+                writer::lower_retention(db.clone(), dir_id, &[])?;
+            }
+            Ok(json!({ "changed": true }))
+        }
+    }
+}
+
+pub fn user(db: &db::Database, cmd: &UserCommand) -> Result<serde_json::Value, Error> {
+    let mut l = db.lock();
+    match *cmd {
+        UserCommand::Add {
+            ref username,
+            password_stdin,
+            ref permissions,
+            ref cameras,
+            unix_uid,
+        } => {
+            if l.get_user(username).is_some() {
+                bail!("user {:?} already exists", username);
+            }
+            let mut change = db::UserChange::add_user(username.clone());
+            change.permissions = declarative::permissions(permissions)?;
+            if !cameras.is_empty() {
+                change.cameras = Some(
+                    cameras
+                        .iter()
+                        .map(|n| {
+                            declarative::camera_id(&l, n)
+                                .ok_or_else(|| format_err!("no camera {:?}", n))
+                        })
+                        .collect::<Result<_, Error>>()?,
+                );
+            }
+            change.unix_uid = unix_uid;
+            if password_stdin {
+                let mut password = String::new();
+                std::io::stdin().lock().read_line(&mut password)?;
+                let password = password.trim_end_matches(&['\r', '\n'][..]);
+                if password.is_empty() {
+                    bail!("empty password on stdin");
+                }
+                change.set_password(password.to_owned());
+            }
+            l.apply_user_change(change)?;
+            Ok(json!({"changed": true, "user": find_user(&l, username)}))
+        }
+        UserCommand::Delete { ref username } => {
+            let id = l
+                .get_user(username)
+                .ok_or_else(|| format_err!("no user {:?}", username))?
+                .id;
+            l.delete_user(id)?;
+            Ok(json!({ "changed": true }))
+        }
+        UserCommand::List => {
+            let mut users = declarative::dump(&l).users;
+            for u in &mut users {
+                u.password_hash = None;
+            }
+            Ok(json!({ "users": users }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::clock::RealClocks;
+    use base::strutil::encode_size;
+    use db::testutil::{self, TestDb};
+
+    #[test]
+    fn camera_and_stream() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let dir = db.tmpdir.path().to_str().unwrap().to_owned();
+        let add = CameraCommand::from_iter(&[
+            "camera",
+            "add",
+            "driveway",
+            "--main-url",
+            "rtsp://driveway/main",
+            "--main-dir",
+            &dir,
+            "--main-record",
+            "true",
+        ]);
+        let out = camera(&db.db, &add).unwrap();
+        assert_eq!(out["changed"], true);
+        assert_eq!(out["camera"]["main"]["rtsp_url"], "rtsp://driveway/main");
+        assert!(camera(&db.db, &add).is_err());
+
+        let update = CameraCommand::from_iter(&["camera", "update", "driveway", "--ptz", "true"]);
+        assert_eq!(camera(&db.db, &update).unwrap()["camera"]["ptz"], true);
+        assert_eq!(camera(&db.db, &update).unwrap()["changed"], false);
+
+        let retain = StreamCommand::from_iter(&[
+            "stream",
+            "set-retention",
+            "driveway",
+            "main",
+            "--retain",
+            "10G",
+        ]);
+        let out = stream(&db.db, &retain).unwrap();
+        assert_eq!(out["changed"], true);
+        assert_eq!(out["stream"]["retain"], encode_size(10 << 30));
+        assert_eq!(out["stream"]["record"], true);
+        assert_eq!(stream(&db.db, &retain).unwrap()["changed"], false);
+
+        let delete_main = StreamCommand::from_iter(&["stream", "delete", "driveway", "main"]);
+        assert_eq!(stream(&db.db, &delete_main).unwrap()["changed"], true);
+        let list = camera(&db.db, &CameraCommand::List).unwrap();
+        let driveway = list["cameras"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|c| c["short_name"] == "driveway")
+            .unwrap();
+        assert!(driveway.get("main").is_none());
+        assert!(stream(&db.db, &delete_main).is_err());
+
+        let delete = CameraCommand::from_iter(&["camera", "delete", "driveway"]);
+        camera(&db.db, &delete).unwrap();
+        let list = camera(&db.db, &CameraCommand::List).unwrap();
+        assert_eq!(list["cameras"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn users() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let add = UserCommand::from_iter(&[
+            "user",
+            "add",
+            "slamb",
+            "--permission",
+            "view_video",
+            "--camera",
+            "test camera",
+        ]);
+        let out = super::user(&db.db, &add).unwrap();
+        assert_eq!(out["user"]["permissions"], json!(["view_video"]));
+        assert_eq!(out["user"]["cameras"], json!(["test camera"]));
+        assert!(super::user(&db.db, &add).is_err());
+        let bad = UserCommand::from_iter(&["user", "add", "a", "--permission", "fly"]);
+        assert!(super::user(&db.db, &bad).is_err());
+        let delete = UserCommand::from_iter(&["user", "delete", "slamb"]);
+        super::user(&db.db, &delete).unwrap();
+        let list = super::user(&db.db, &UserCommand::List).unwrap();
+        assert_eq!(list["users"], json!([]));
+    }
+}
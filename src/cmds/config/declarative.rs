@@ -0,0 +1,687 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Declarative configuration: `moonfire-nvr config dump` and `moonfire-nvr config apply`.
+//!
+//! `dump` writes cameras, streams, retention, and users as TOML; `apply` reconciles the database
+//! with such a file, so that an installation's configuration can be kept under version control.
+//! Cameras are matched by short name and users by username. Those absent from the file are left
+//! alone unless `--prune` is given. Sample file directories are named by path; `apply` adds any
+//! which are missing but never removes one.
+//!
+//! Passwords are given as hashes, as written by `dump`. A user without `password_hash` keeps
+//! whatever password it has. Temporary (guest) users are neither dumped nor pruned.
+
+use base::strutil::{decode_size, encode_size};
+use db::auth;
+use failure::{bail, format_err, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Paths of sample file directories.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sample_file_dirs: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cameras: Vec<Camera>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<User>,
+}
+
+// Note the TOML serializer requires plain values to precede tables, so `main` and `sub` come last.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Camera {
+    pub short_name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub onvif_host: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub username: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub password: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub ptz: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub main: Option<Stream>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub: Option<Stream>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Stream {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub rtsp_url: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub record: bool,
+
+    /// The retention limit, in the form accepted by `decode_size`, such as `100G`.
+    #[serde(default, skip_serializing_if = "is_zero_size")]
+    pub retain: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_file_dir: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stripe_dirs: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failover_dir: Option<String>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub flush_if_sec: i64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub flush_if_bytes: i64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub input_options: String,
+
+    // The RTSP policy. Each is omitted when it matches `db::RtspPolicy::default()`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtsp_transport: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reconnect_min_sec: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reconnect_max_sec: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_timeout_sec: Option<i64>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct User {
+    pub username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+
+    /// Names of granted permissions, as in `db::auth::PERMISSION_NAMES`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub permissions: Vec<String>,
+
+    /// If present, the short names of the only cameras this user may access.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cameras: Option<Vec<String>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unix_uid: Option<i32>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub disabled: bool,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+fn is_zero(i: &i64) -> bool {
+    *i == 0
+}
+
+#[allow(clippy::ptr_arg)] // serde passes a `&String`.
+fn is_zero_size(s: &String) -> bool {
+    s == "0"
+}
+
+impl Config {
+    /// Validates the config and puts it into the canonical form `dump` produces, so that
+    /// unchanged entries compare equal.
+    fn normalize(&mut self) -> Result<(), Error> {
+        let mut short_names = HashSet::new();
+        for c in &mut self.cameras {
+            if c.short_name.is_empty() {
+                bail!("camera with empty short_name");
+            }
+            if !short_names.insert(c.short_name.clone()) {
+                bail!("duplicate camera {:?}", c.short_name);
+            }
+            let name = &c.short_name;
+            for s in c.main.iter_mut().chain(c.sub.iter_mut()) {
+                s.normalize()
+                    .map_err(|e| format_err!("camera {:?}: {}", name, e))?;
+            }
+        }
+        let mut usernames = HashSet::new();
+        for u in &mut self.users {
+            if !usernames.insert(u.username.clone()) {
+                bail!("duplicate user {:?}", u.username);
+            }
+            permissions(&u.permissions).map_err(|e| format_err!("user {:?}: {}", u.username, e))?;
+            u.permissions
+                .sort_by_key(|p| auth::PERMISSION_NAMES.iter().position(|&n| n == p).unwrap());
+            u.permissions.dedup();
+            if let Some(ref mut cameras) = u.cameras {
+                cameras.sort();
+                cameras.dedup();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Stream {
+    fn normalize(&mut self) -> Result<(), Error> {
+        let retain = if self.retain.is_empty() {
+            0
+        } else {
+            decode_size(&self.retain).map_err(|()| format_err!("bad retain {:?}", self.retain))?
+        };
+        self.retain = encode_size(retain);
+        let d = db::RtspPolicy::default();
+        if let Some(ref t) = self.rtsp_transport {
+            db::RtspTransport::parse(t).ok_or_else(|| format_err!("bad rtsp_transport {:?}", t))?;
+        }
+        let d_transport = d.transport.as_str();
+        self.rtsp_transport = self.rtsp_transport.take().filter(|t| t != d_transport);
+        self.reconnect_min_sec = self.reconnect_min_sec.filter(|&v| v != d.reconnect_min_sec);
+        self.reconnect_max_sec = self.reconnect_max_sec.filter(|&v| v != d.reconnect_max_sec);
+        self.session_timeout_sec = self
+            .session_timeout_sec
+            .filter(|&v| v != d.session_timeout_sec);
+        Ok(())
+    }
+
+    fn rtsp(&self) -> db::RtspPolicy {
+        let d = db::RtspPolicy::default();
+        db::RtspPolicy {
+            transport: self
+                .rtsp_transport
+                .as_ref()
+                .and_then(|t| db::RtspTransport::parse(t))
+                .unwrap_or(d.transport),
+            reconnect_min_sec: self.reconnect_min_sec.unwrap_or(d.reconnect_min_sec),
+            reconnect_max_sec: self.reconnect_max_sec.unwrap_or(d.reconnect_max_sec),
+            session_timeout_sec: self.session_timeout_sec.unwrap_or(d.session_timeout_sec),
+        }
+    }
+}
+
+/// Parses permission names into a `db::Permissions`.
+pub fn permissions(names: &[String]) -> Result<db::Permissions, Error> {
+    let mut p = db::Permissions::default();
+    for n in names {
+        *auth::permission_mut(&mut p, n)
+            .ok_or_else(|| format_err!("unknown permission {:?}", n))? = true;
+    }
+    Ok(p)
+}
+
+/// Returns the current configuration in canonical form.
+pub fn dump(l: &db::LockedDatabase) -> Config {
+    let dir_path = |id: i32| l.sample_file_dirs_by_id()[&id].path.clone();
+    let mut sample_file_dirs: Vec<String> = l
+        .sample_file_dirs_by_id()
+        .values()
+        .map(|d| d.path.clone())
+        .collect();
+    sample_file_dirs.sort();
+    let d = db::RtspPolicy::default();
+    let stream = |id: Option<i32>| {
+        let s = l.streams_by_id().get(&id?)?;
+        Some(Stream {
+            rtsp_url: s.rtsp_url.clone(),
+            record: s.record,
+            retain: encode_size(s.retain_bytes),
+            sample_file_dir: s.sample_file_dir_id.map(dir_path),
+            stripe_dirs: s.stripe_dir_ids.iter().map(|&id| dir_path(id)).collect(),
+            failover_dir: s.failover_sample_file_dir_id.map(dir_path),
+            flush_if_sec: s.flush_if_sec,
+            flush_if_bytes: s.flush_if_bytes,
+            input_options: s.input_options.clone(),
+            rtsp_transport: Some(s.rtsp.transport)
+                .filter(|&t| t != d.transport)
+                .map(|t| t.as_str().to_owned()),
+            reconnect_min_sec: Some(s.rtsp.reconnect_min_sec).filter(|&v| v != d.reconnect_min_sec),
+            reconnect_max_sec: Some(s.rtsp.reconnect_max_sec).filter(|&v| v != d.reconnect_max_sec),
+            session_timeout_sec: Some(s.rtsp.session_timeout_sec)
+                .filter(|&v| v != d.session_timeout_sec),
+        })
+    };
+    let cameras = l
+        .cameras_by_id()
+        .values()
+        .map(|c| Camera {
+            short_name: c.short_name.clone(),
+            description: c.description.clone(),
+            onvif_host: c.onvif_host.clone(),
+            username: c.username.clone(),
+            password: c.password.clone(),
+            ptz: c.ptz,
+            main: stream(c.streams[db::StreamType::MAIN.index()]),
+            sub: stream(c.streams[db::StreamType::SUB.index()]),
+        })
+        .collect();
+    let users = l
+        .users_by_id()
+        .values()
+        .filter(|u| u.expiration_time_sec.is_none())
+        .map(|u| User {
+            username: u.username.clone(),
+            password_hash: u.password_hash().map(str::to_owned),
+            permissions: auth::permission_names(&u.permissions)
+                .into_iter()
+                .map(str::to_owned)
+                .collect(),
+            cameras: u.cameras.as_ref().map(|ids| {
+                let mut names: Vec<String> = ids
+                    .iter()
+                    .filter_map(|id| l.cameras_by_id().get(id))
+                    .map(|c| c.short_name.clone())
+                    .collect();
+                names.sort();
+                names
+            }),
+            unix_uid: u.unix_uid,
+            disabled: u.disabled(),
+        })
+        .collect();
+    Config {
+        sample_file_dirs,
+        cameras,
+        users,
+    }
+}
+
+/// Reconciles the database with `config`, appending a line describing each change to `actions`
+/// as it's made. With `dry_run`, only describes the changes.
+///
+/// Changes are made one at a time; on error, those already made (as listed in `actions`) remain.
+pub fn apply(
+    l: &mut db::LockedDatabase,
+    mut config: Config,
+    prune: bool,
+    dry_run: bool,
+    actions: &mut Vec<String>,
+) -> Result<(), Error> {
+    config.normalize()?;
+    let current = dump(l);
+
+    // Check references up front, so that errors in the file don't leave a half-applied change.
+    for c in &config.cameras {
+        for s in c.main.iter().chain(c.sub.iter()) {
+            for p in s
+                .sample_file_dir
+                .iter()
+                .chain(s.stripe_dirs.iter())
+                .chain(s.failover_dir.iter())
+            {
+                if !config.sample_file_dirs.contains(p) && !current.sample_file_dirs.contains(p) {
+                    bail!("camera {:?}: unknown sample file dir {:?}", c.short_name, p);
+                }
+            }
+        }
+    }
+    for u in &config.users {
+        for n in u.cameras.iter().flatten() {
+            if !config.cameras.iter().any(|c| &c.short_name == n)
+                && !current.cameras.iter().any(|c| &c.short_name == n)
+            {
+                bail!("user {:?}: unknown camera {:?}", u.username, n);
+            }
+        }
+    }
+
+    for p in &config.sample_file_dirs {
+        if current.sample_file_dirs.contains(p) {
+            continue;
+        }
+        actions.push(format!("add sample file dir {}", p));
+        if !dry_run {
+            l.add_sample_file_dir(p.clone())?;
+        }
+    }
+
+    for c in &config.cameras {
+        let existing = current
+            .cameras
+            .iter()
+            .find(|e| e.short_name == c.short_name);
+        if existing == Some(c) {
+            continue;
+        }
+        actions.push(format!(
+            "{} camera {}",
+            if existing.is_some() { "update" } else { "add" },
+            c.short_name
+        ));
+        if dry_run {
+            continue;
+        }
+        let id = match camera_id(l, &c.short_name) {
+            Some(id) => {
+                let change = camera_change(l, c, Some(id))?;
+                l.update_camera(id, change)?;
+                id
+            }
+            None => {
+                let change = camera_change(l, c, None)?;
+                l.add_camera(change)?
+            }
+        };
+        let streams = l.cameras_by_id()[&id].streams;
+        let mut changes = Vec::new();
+        for (&t, s) in db::ALL_STREAM_TYPES.iter().zip(&[&c.main, &c.sub]) {
+            if let (Some(stream_id), Some(s)) = (streams[t.index()], s) {
+                changes.push(db::RetentionChange {
+                    stream_id,
+                    new_record: s.record,
+                    new_limit: decode_size(&s.retain).unwrap(),
+                });
+            }
+        }
+        l.update_retention(&changes)?;
+    }
+
+    for u in &config.users {
+        let existing = current.users.iter().find(|e| e.username == u.username);
+        if let Some(e) = existing {
+            // A missing password hash means to leave the password as is.
+            let mut u = u.clone();
+            if u.password_hash.is_none() {
+                u.password_hash = e.password_hash.clone();
+            }
+            if e == &u {
+                continue;
+            }
+        }
+        actions.push(format!(
+            "{} user {}",
+            if existing.is_some() { "update" } else { "add" },
+            u.username
+        ));
+        if dry_run {
+            continue;
+        }
+        let mut change = match l.get_user(&u.username) {
+            Some(e) => e.change(),
+            None => db::UserChange::add_user(u.username.clone()),
+        };
+        if let Some(ref h) = u.password_hash {
+            if existing.and_then(|e| e.password_hash.as_ref()) != Some(h) {
+                change.set_password_hash(h.clone());
+            }
+        }
+        change.permissions = permissions(&u.permissions)?;
+        change.cameras = match u.cameras {
+            None => None,
+            Some(ref names) => Some(
+                names
+                    .iter()
+                    .map(|n| camera_id(l, n).ok_or_else(|| format_err!("no camera {:?}", n)))
+                    .collect::<Result<BTreeSet<i32>, Error>>()?,
+            ),
+        };
+        change.unix_uid = u.unix_uid;
+        if u.disabled {
+            change.disable();
+        } else {
+            change.enable();
+        }
+        l.apply_user_change(change)?;
+    }
+
+    if prune {
+        for c in &current.cameras {
+            if config.cameras.iter().any(|d| d.short_name == c.short_name) {
+                continue;
+            }
+            actions.push(format!("delete camera {}", c.short_name));
+            if !dry_run {
+                let id = camera_id(l, &c.short_name).unwrap();
+                l.delete_camera(id)?;
+            }
+        }
+        for u in &current.users {
+            if config.users.iter().any(|d| d.username == u.username) {
+                continue;
+            }
+            actions.push(format!("delete user {}", u.username));
+            if !dry_run {
+                let id = l.get_user(&u.username).unwrap().id;
+                l.delete_user(id)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn camera_id(l: &db::LockedDatabase, short_name: &str) -> Option<i32> {
+    l.cameras_by_id()
+        .values()
+        .find(|c| c.short_name == short_name)
+        .map(|c| c.id)
+}
+
+fn dir_id(l: &db::LockedDatabase, path: &str) -> Result<i32, Error> {
+    l.sample_file_dirs_by_id()
+        .values()
+        .find(|d| d.path == path)
+        .map(|d| d.id)
+        .ok_or_else(|| format_err!("no sample file dir {:?}", path))
+}
+
+fn camera_change(
+    l: &db::LockedDatabase,
+    c: &Camera,
+    existing_id: Option<i32>,
+) -> Result<db::CameraChange, Error> {
+    let mut change = db::CameraChange {
+        short_name: c.short_name.clone(),
+        description: c.description.clone(),
+        onvif_host: c.onvif_host.clone(),
+        username: c.username.clone(),
+        password: c.password.clone(),
+        ptz: c.ptz,
+        streams: Default::default(),
+    };
+    for (&t, s) in db::ALL_STREAM_TYPES.iter().zip(&[&c.main, &c.sub]) {
+        let s = match s {
+            None => continue,
+            Some(s) => s,
+        };
+
+        // Virtual streams aren't described by the file; keep any existing one's source.
+        let virtual_source = existing_id
+            .and_then(|id| l.cameras_by_id()[&id].streams[t.index()])
+            .and_then(|id| l.streams_by_id().get(&id))
+            .and_then(|s| s.virtual_source);
+        change.streams[t.index()] = db::StreamChange {
+            sample_file_dir_id: s
+                .sample_file_dir
+                .as_ref()
+                .map(|p| dir_id(l, p))
+                .transpose()?,
+            stripe_dir_ids: s
+                .stripe_dirs
+                .iter()
+                .map(|p| dir_id(l, p))
+                .collect::<Result<_, _>>()?,
+            failover_sample_file_dir_id: s
+                .failover_dir
+                .as_ref()
+                .map(|p| dir_id(l, p))
+                .transpose()?,
+            rtsp_url: s.rtsp_url.clone(),
+            virtual_source,
+            record: s.record,
+            flush_if_sec: s.flush_if_sec,
+            flush_if_bytes: s.flush_if_bytes,
+            input_options: s.input_options.clone(),
+            rtsp: s.rtsp(),
+        };
+    }
+    Ok(change)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::clock::RealClocks;
+    use db::testutil::{self, TestDb};
+
+    fn apply_str(db: &TestDb<RealClocks>, s: &str, prune: bool) -> Vec<String> {
+        let config: Config = toml::from_str(s).unwrap();
+        let mut actions = Vec::new();
+        apply(&mut db.db.lock(), config, prune, false, &mut actions).unwrap();
+        actions
+    }
+
+    #[test]
+    fn round_trip() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let dumped = toml::to_string(&dump(&db.db.lock())).unwrap();
+        assert!(dumped.contains("retain = \"1M\""), "{}", dumped);
+        assert_eq!(apply_str(&db, &dumped, true), Vec::<String>::new());
+        let mut l = db.db.lock();
+        let config: Config = toml::from_str(&dumped).unwrap();
+        let mut actions = Vec::new();
+        apply(&mut l, config, true, true, &mut actions).unwrap();
+        assert!(actions.is_empty());
+    }
+
+    #[test]
+    fn add_update_prune() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let dir = db.tmpdir.path().to_str().unwrap();
+        let config = format!(
+            r#"
+            [[cameras]]
+            short_name = "test camera"
+            onvif_host = "test-camera"
+            username = "foo"
+            password = "bar"
+
+            [cameras.main]
+            rtsp_url = "rtsp://test-camera/main"
+            record = true
+            retain = "1024K 1024K"
+            sample_file_dir = "{dir}"
+            rtsp_transport = "tcp"
+
+            [[cameras]]
+            short_name = "driveway"
+
+            [cameras.main]
+            rtsp_url = "rtsp://driveway/main"
+            retain = "5G"
+            sample_file_dir = "{dir}"
+            rtsp_transport = "udp"
+
+            [[users]]
+            username = "slamb"
+            password_hash = "$scrypt$fake"
+            permissions = ["view_video", "read_camera_configs"]
+            cameras = ["driveway"]
+            "#,
+            dir = dir
+        );
+        assert_eq!(
+            apply_str(&db, &config, false),
+            vec![
+                "update camera test camera",
+                "add camera driveway",
+                "add user slamb"
+            ]
+        );
+        assert_eq!(apply_str(&db, &config, false), Vec::<String>::new());
+        {
+            let l = db.db.lock();
+            let d = dump(&l);
+            let driveway = d
+                .cameras
+                .iter()
+                .find(|c| c.short_name == "driveway")
+                .unwrap();
+            let main = driveway.main.as_ref().unwrap();
+            assert_eq!(main.retain, "5G");
+            assert_eq!(
+                main.rtsp_transport.as_ref().map(String::as_str),
+                Some("udp")
+            );
+            assert_eq!(
+                d.users[0].permissions,
+                vec!["view_video".to_owned(), "read_camera_configs".to_owned()]
+            );
+            assert_eq!(d.users[0].cameras, Some(vec!["driveway".to_owned()]));
+        }
+
+        // Omitting the password hash leaves the password alone; pruning removes the rest.
+        let config = r#"
+            [[users]]
+            username = "slamb"
+            permissions = ["view_video"]
+            "#;
+        assert_eq!(
+            apply_str(&db, config, true),
+            vec![
+                "update user slamb",
+                "delete camera test camera",
+                "delete camera driveway",
+            ]
+        );
+        let l = db.db.lock();
+        assert!(l.cameras_by_id().is_empty());
+        assert_eq!(
+            l.get_user("slamb").unwrap().password_hash(),
+            Some("$scrypt$fake")
+        );
+    }
+
+    #[test]
+    fn bad_config() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        for (s, e) in &[
+            (
+                "[[users]]\nusername = \"a\"\npermissions = [\"fly\"]\n",
+                "unknown permission",
+            ),
+            (
+                "[[users]]\nusername = \"a\"\ncameras = [\"nope\"]\n",
+                "unknown camera",
+            ),
+            (
+                "[[cameras]]\nshort_name = \"a\"\n[cameras.main]\nretain = \"lots\"\n",
+                "bad retain",
+            ),
+            (
+                "[[cameras]]\nshort_name = \"a\"\n[cameras.main]\nsample_file_dir = \"/nope\"\n",
+                "unknown sample file dir",
+            ),
+        ] {
+            let config: Config = toml::from_str(s).unwrap();
+            let err = apply(&mut db.db.lock(), config, false, false, &mut Vec::new()).unwrap_err();
+            assert!(err.to_string().contains(e), "{}: {}", s, err);
+        }
+        assert!(toml::from_str::<Config>("[[cameras]]\nshort_name = \"a\"\nbogus = 1\n").is_err());
+    }
+}
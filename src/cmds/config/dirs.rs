@@ -34,11 +34,11 @@ use cursive::views;
 use cursive::Cursive;
 use db::writer;
 use failure::Error;
-use log::{debug, trace};
 use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::rc::Rc;
 use std::sync::Arc;
+use tracing::{debug, trace};
 
 struct Stream {
     label: String,
@@ -146,16 +146,50 @@ fn confirm_deletion(model: &RefCell<Model>, siv: &mut Cursive, to_delete: i64) {
     }
 }
 
-fn actually_delete(model: &RefCell<Model>, siv: &mut Cursive) {
-    let model = &*model.borrow();
-    let new_limits: Vec<_> = model
+fn new_limits(model: &Model) -> Vec<writer::NewLimit> {
+    model
         .streams
         .iter()
         .map(|(&id, s)| writer::NewLimit {
             stream_id: id,
             limit: s.retain.unwrap(),
         })
-        .collect();
+        .collect()
+}
+
+/// Describes what the new limits would delete, one line per affected stream.
+fn describe_deletions(model: &Model) -> String {
+    let previews = match writer::preview_lower_retention(&mut model.db.lock(), &new_limits(model)) {
+        Ok(p) => p,
+        Err(e) => return format!("Unable to preview deletions: {}", e),
+    };
+    let mut out = String::new();
+    for (id, p) in &previews {
+        if p.recordings.is_empty() {
+            continue;
+        }
+        let label = match model.streams.get(id) {
+            Some(s) => s.label.clone(),
+            None => format!("stream {}", id),
+        };
+        let oldest = match p.oldest_remaining {
+            Some(t) => t.to_string(),
+            None => "none".to_owned(),
+        };
+        out.push_str(&format!(
+            "{}: {} recordings ({}); oldest remaining: {}\n",
+            label,
+            p.recordings.len(),
+            encode_size(p.fs_bytes),
+            oldest
+        ));
+    }
+    out
+}
+
+fn actually_delete(model: &RefCell<Model>, siv: &mut Cursive) {
+    let model = &*model.borrow();
+    let new_limits = new_limits(model);
     siv.pop_layer(); // deletion confirmation
     siv.pop_layer(); // retention dialog
     {
@@ -186,8 +220,9 @@ fn press_change(model: &Rc<RefCell<Model>>, siv: &mut Cursive) {
     debug!("change press, to_delete={}", to_delete);
     if to_delete > 0 {
         let prompt = format!(
-            "Some streams' usage exceeds new limit. Please confirm the amount \
+            "Some streams' usage exceeds new limit.\n\n{}\nPlease confirm the amount \
                               of data to delete by typing it back:\n\n{}",
+            describe_deletions(&model.borrow()),
             encode_size(to_delete)
         );
         let dialog = views::Dialog::around(
@@ -228,12 +263,13 @@ pub fn top_dialog(db: &Arc<db::Database>, siv: &mut Cursive) {
                     }
                 })
                 .item("<new sample file dir>".to_string(), None)
-                .with_all(
-                    db.lock()
-                        .sample_file_dirs_by_id()
-                        .iter()
-                        .map(|(&id, d)| (d.path.to_string(), Some(id))),
-                )
+                .with_all(db.lock().sample_file_dirs_by_id().iter().map(|(&id, d)| {
+                    let label = match d.unhealthy_since_sec {
+                        Some(_) => format!("{} (unhealthy)", d.path),
+                        None => d.path.to_string(),
+                    };
+                    (label, Some(id))
+                }))
                 .full_width(),
         )
         .dismiss_button("Done")
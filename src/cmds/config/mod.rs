@@ -43,7 +43,10 @@ use std::sync::Arc;
 use structopt::StructOpt;
 
 mod cameras;
+mod cli;
+mod declarative;
 mod dirs;
+mod settings;
 mod users;
 
 #[derive(StructOpt)]
@@ -56,12 +59,105 @@ pub struct Args {
         parse(from_os_str)
     )]
     db_dir: PathBuf,
+
+    /// With no subcommand, runs the interactive configuration interface.
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Reconciles cameras, streams, retention, and users with a TOML file as written by `dump`.
+    Apply {
+        /// Path of the configuration file.
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        /// Deletes cameras and users which aren't in the file.
+        #[structopt(long)]
+        prune: bool,
+
+        /// Prints the changes which would be made without making them.
+        #[structopt(long)]
+        dry_run: bool,
+    },
+
+    /// Writes cameras, streams, retention, and users to stdout as TOML.
+    Dump,
+
+    /// Adds, updates, deletes, or lists cameras.
+    Camera {
+        #[structopt(subcommand)]
+        cmd: cli::CameraCommand,
+    },
+
+    /// Changes a stream's retention or deletes it.
+    Stream {
+        #[structopt(subcommand)]
+        cmd: cli::StreamCommand,
+    },
+
+    /// Adds, deletes, or lists users.
+    User {
+        #[structopt(subcommand)]
+        cmd: cli::UserCommand,
+    },
 }
 
 pub fn run(args: &Args) -> Result<(), Error> {
-    let (_db_dir, conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadWrite)?;
+    let mode = match args.cmd {
+        Some(Command::Dump)
+        | Some(Command::Camera {
+            cmd: cli::CameraCommand::List,
+        })
+        | Some(Command::User {
+            cmd: cli::UserCommand::List,
+        }) => super::OpenMode::ReadOnly,
+        _ => super::OpenMode::ReadWrite,
+    };
+    let (_db_dir, conn) = super::open_conn(&args.db_dir, mode)?;
     let clocks = clock::RealClocks {};
-    let db = Arc::new(db::Database::new(clocks, conn, true)?);
+    let db = Arc::new(db::Database::new(
+        clocks,
+        conn,
+        mode == super::OpenMode::ReadWrite,
+    )?);
+
+    match args.cmd {
+        None => {}
+        Some(Command::Dump) => {
+            print!("{}", toml::to_string(&declarative::dump(&db.lock()))?);
+            return Ok(());
+        }
+        Some(Command::Apply {
+            ref file,
+            prune,
+            dry_run,
+        }) => {
+            let config: declarative::Config = toml::from_str(&std::fs::read_to_string(file)?)?;
+            let mut actions = Vec::new();
+            let r = declarative::apply(&mut db.lock(), config, prune, dry_run, &mut actions);
+            for a in &actions {
+                println!("{}", a);
+            }
+            if actions.is_empty() && r.is_ok() {
+                println!("no changes");
+            }
+            return r;
+        }
+        Some(Command::Camera { ref cmd }) => {
+            println!("{}", cli::camera(&db, cmd)?);
+            return Ok(());
+        }
+        Some(Command::Stream { ref cmd }) => {
+            println!("{}", cli::stream(&db, cmd)?);
+            return Ok(());
+        }
+        Some(Command::User { ref cmd }) => {
+            println!("{}", cli::user(&db, cmd)?);
+            return Ok(());
+        }
+    }
 
     let mut siv = Cursive::ncurses()?;
     //siv.add_global_callback('q', |s| s.quit());
@@ -75,7 +171,18 @@ pub fn run(args: &Args) -> Result<(), Error> {
                 })
                 .item("Cameras and streams".to_string(), cameras::top_dialog)
                 .item("Directories and retention".to_string(), dirs::top_dialog)
-                .item("Users".to_string(), users::top_dialog),
+                .item("MQTT".to_string(), settings::mqtt_dialog)
+                .item(
+                    "OpenID Connect single sign-on".to_string(),
+                    settings::oidc_dialog,
+                )
+                .item("Protection".to_string(), settings::protect_dialog)
+                .item("RTMP ingest".to_string(), settings::rtmp_dialog)
+                .item("SAML single sign-on".to_string(), settings::saml_dialog)
+                .item("Storage".to_string(), settings::storage_dialog)
+                .item("TLS (https)".to_string(), settings::tls_dialog)
+                .item("Users".to_string(), users::top_dialog)
+                .item("Webhooks".to_string(), settings::webhook_dialog),
         )
         .button("Quit", |siv| siv.quit())
         .title("Main menu"),
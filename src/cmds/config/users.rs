@@ -31,8 +31,29 @@
 use cursive::traits::{Boxable, Identifiable};
 use cursive::views;
 use cursive::Cursive;
-use log::info;
+use db::recording;
+use failure::{format_err, Error};
 use std::sync::Arc;
+use tracing::info;
+
+/// Formats an expiration time for the `expires` field, in the local time zone.
+fn format_expiration(sec: i64) -> String {
+    let tm = time::at(time::Timespec { sec, nsec: 0 });
+    tm.strftime("%FT%T")
+        .map(|t| t.to_string())
+        .unwrap_or_else(|_| sec.to_string())
+}
+
+/// Parses the `expires` field; an empty field means the user never expires.
+fn parse_expiration(s: &str) -> Result<Option<i64>, Error> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(None);
+    }
+    let t = recording::Time::parse(s)
+        .map_err(|e| format_err!("unparseable expiration time {:?}: {}", s, e))?;
+    Ok(Some(t.0 / recording::TIME_UNITS_PER_SEC))
+}
 
 /// Builds a `UserChange` from an active `edit_user_dialog`.
 fn get_change(
@@ -40,7 +61,7 @@ fn get_change(
     db: &db::LockedDatabase,
     id: Option<i32>,
     pw: PasswordChange,
-) -> db::UserChange {
+) -> Result<db::UserChange, Error> {
     let mut change = match id {
         Some(id) => db.users_by_id().get(&id).unwrap().change(),
         None => db::UserChange::add_user(String::new()),
@@ -51,6 +72,12 @@ fn get_change(
         .unwrap()
         .get_content()
         .as_str();
+    change.expiration_time_sec = parse_expiration(
+        siv.find_name::<views::EditView>("expires")
+            .unwrap()
+            .get_content()
+            .as_str(),
+    )?;
     match pw {
         PasswordChange::Leave => {}
         PasswordChange::Set => {
@@ -72,18 +99,65 @@ fn get_change(
             "perm_update_signals",
             &mut change.permissions.update_signals,
         ),
+        (
+            "perm_update_detections",
+            &mut change.permissions.update_detections,
+        ),
+        ("perm_control_ptz", &mut change.permissions.control_ptz),
+        (
+            "perm_protect_recordings",
+            &mut change.permissions.protect_recordings,
+        ),
+        ("perm_write_notes", &mut change.permissions.write_notes),
+        ("perm_read_logs", &mut change.permissions.read_logs),
+        (
+            "perm_read_playback_heat",
+            &mut change.permissions.read_playback_heat,
+        ),
+        (
+            "perm_update_camera_configs",
+            &mut change.permissions.update_camera_configs,
+        ),
+        (
+            "perm_delete_recordings",
+            &mut change.permissions.delete_recordings,
+        ),
+        ("perm_talkback", &mut change.permissions.talkback),
+        ("perm_admin_users", &mut change.permissions.admin_users),
+        (
+            "perm_update_log_filter",
+            &mut change.permissions.update_log_filter,
+        ),
     ] {
         **b = siv.find_name::<views::Checkbox>(id).unwrap().is_checked();
         info!("{}: {}", id, **b);
     }
-    change
+    change.cameras = if siv
+        .find_name::<views::Checkbox>("restrict_cameras")
+        .unwrap()
+        .is_checked()
+    {
+        Some(
+            db.cameras_by_id()
+                .keys()
+                .filter(|&id| {
+                    siv.find_name::<views::Checkbox>(&format!("camera_{}", id))
+                        .unwrap()
+                        .is_checked()
+                })
+                .cloned()
+                .collect(),
+        )
+    } else {
+        None
+    };
+    Ok(change)
 }
 
 fn press_edit(siv: &mut Cursive, db: &Arc<db::Database>, id: Option<i32>, pw: PasswordChange) {
     let result = {
         let mut l = db.lock();
-        let c = get_change(siv, &l, id, pw);
-        l.apply_user_change(c).map(|_| ())
+        get_change(siv, &l, id, pw).and_then(|c| l.apply_user_change(c).map(|_| ()))
     };
     if let Err(e) = result {
         siv.add_layer(
@@ -147,7 +221,7 @@ fn select_set(siv: &mut Cursive) {
 /// Adds or updates a user.
 /// (The former if `item` is None; the latter otherwise.)
 fn edit_user_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: Option<i32>) {
-    let (username, id_str, has_password, permissions);
+    let (username, id_str, has_password, permissions, cameras, restricted, expires);
     let mut pw_group = views::RadioGroup::new();
     {
         let l = db.lock();
@@ -155,9 +229,26 @@ fn edit_user_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: Option<i32>
         username = u.map(|u| u.username.clone()).unwrap_or(String::new());
         id_str = item.map(|id| id.to_string()).unwrap_or("<new>".to_string());
         has_password = u.map(|u| u.has_password()).unwrap_or(false);
+        expires = u
+            .and_then(|u| u.expiration_time_sec)
+            .map(format_expiration)
+            .unwrap_or_default();
         permissions = u
             .map(|u| u.permissions.clone())
             .unwrap_or(db::Permissions::default());
+        let allowed = u.and_then(|u| u.cameras.as_ref());
+        cameras = l
+            .cameras_by_id()
+            .values()
+            .map(|c| {
+                (
+                    c.id,
+                    c.short_name.clone(),
+                    allowed.map(|a| a.contains(&c.id)).unwrap_or(false),
+                )
+            })
+            .collect::<Vec<_>>();
+        restricted = allowed.is_some();
     }
     let top_list = views::ListView::new()
         .child("id", views::TextView::new(id_str))
@@ -166,9 +257,17 @@ fn edit_user_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: Option<i32>
             views::EditView::new()
                 .content(username.clone())
                 .with_name("username"),
+        )
+        .child(
+            "expires",
+            views::EditView::new().content(expires).with_name("expires"),
         );
     let mut layout = views::LinearLayout::vertical()
         .child(top_list)
+        .child(views::TextView::new(
+            "Leave expires empty for a permanent user, or enter a local time such as \
+             2020-07-04T12:00:00 for a guest who is disabled at that time.",
+        ))
         .child(views::DummyView)
         .child(views::TextView::new("password"));
 
@@ -216,6 +315,17 @@ fn edit_user_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: Option<i32>
         ("view_video", permissions.view_video),
         ("read_camera_configs", permissions.read_camera_configs),
         ("update_signals", permissions.update_signals),
+        ("update_detections", permissions.update_detections),
+        ("control_ptz", permissions.control_ptz),
+        ("protect_recordings", permissions.protect_recordings),
+        ("write_notes", permissions.write_notes),
+        ("read_logs", permissions.read_logs),
+        ("read_playback_heat", permissions.read_playback_heat),
+        ("update_camera_configs", permissions.update_camera_configs),
+        ("delete_recordings", permissions.delete_recordings),
+        ("talkback", permissions.talkback),
+        ("admin_users", permissions.admin_users),
+        ("update_log_filter", permissions.update_log_filter),
     ] {
         let mut checkbox = views::Checkbox::new();
         checkbox.set_checked(*b);
@@ -223,6 +333,23 @@ fn edit_user_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: Option<i32>
     }
     layout.add_child(perms);
 
+    layout.add_child(views::DummyView);
+    let mut restrict = views::Checkbox::new();
+    restrict.set_checked(restricted);
+    layout.add_child(
+        views::LinearLayout::horizontal()
+            .child(restrict.with_name("restrict_cameras"))
+            .child(views::DummyView)
+            .child(views::TextView::new("restrict to cameras")),
+    );
+    let mut camera_list = views::ListView::new();
+    for (id, short_name, checked) in cameras {
+        let mut checkbox = views::Checkbox::new();
+        checkbox.set_checked(checked);
+        camera_list.add_child(&short_name, checkbox.with_name(format!("camera_{}", id)));
+    }
+    layout.add_child(camera_list);
+
     let dialog = views::Dialog::around(layout);
     let dialog = if let Some(id) = item {
         dialog
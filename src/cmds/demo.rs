@@ -0,0 +1,660 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Synthetic demo data, for exploring the UI and API without real cameras.
+//!
+//! `moonfire-nvr demo seed` creates a fresh database and sample file directory holding several
+//! days of recordings from a few imaginary cameras. The video is a tiny generated H.264 stream
+//! (an uncompressed key frame every ten seconds and skipped macroblocks in between), so it costs
+//! little disk space yet plays back in a browser. The recordings have realistic structure:
+//! one-minute recordings with network jitter in frame arrival times, occasional outages which
+//! start new runs, and motion signals with bigger frames while there's "motion".
+
+use crate::h264;
+use base::clock::{Clocks, RealClocks};
+use db::{recording, writer};
+use failure::{bail, format_err, Error};
+use rusqlite::params;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::StructOpt;
+use tracing::info;
+
+#[derive(StructOpt)]
+pub enum Args {
+    /// Creates a new database and sample file directory filled with synthetic recordings.
+    ///
+    /// This takes about a minute per camera-week and about 7 MiB of disk per camera-day. The
+    /// result can be served with the `moonfire-nvr run` command this prints.
+    Seed(SeedArgs),
+}
+
+#[derive(StructOpt)]
+pub struct SeedArgs {
+    /// How many days of recordings to generate, ending now.
+    #[structopt(long, default_value = "7")]
+    days: u32,
+
+    /// How many cameras to generate.
+    #[structopt(long, default_value = "4")]
+    cameras: u32,
+
+    /// Directory in which to create the `db` and `sample` directories. It must not already hold
+    /// a database.
+    ///
+    /// Defaults to a new directory within the system temporary directory.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    dir: Option<PathBuf>,
+
+    /// Seed for the pseudo-random outages, jitter, and motion, for reproducible data.
+    #[structopt(long, default_value = "1")]
+    seed: u64,
+}
+
+pub fn run(args: &Args) -> Result<(), Error> {
+    match args {
+        Args::Seed(a) => seed(a),
+    }
+}
+
+/// Frame size of the generated video: a single macroblock.
+const WIDTH: u16 = 16;
+const HEIGHT: u16 = 16;
+
+const FRAME_DURATION_90K: i64 = recording::TIME_UNITS_PER_SEC / 5;
+const FRAMES_PER_KEY_FRAME: u32 = 50;
+
+/// Recordings are split at the first key frame after this long, as `streamer` does.
+const RECORDING_DURATION_90K: i64 = 60 * recording::TIME_UNITS_PER_SEC;
+
+/// The chance of a camera outage starting in any given minute, and its maximum length.
+const OUTAGE_CHANCE_PER_MINUTE: f64 = 1. / 2000.;
+const MAX_OUTAGE_90K: i64 = 20 * 60 * recording::TIME_UNITS_PER_SEC;
+
+/// The maximum network jitter added to each frame's arrival time.
+const MAX_JITTER_90K: i64 = recording::TIME_UNITS_PER_SEC / 20;
+
+/// Bytes of filler added to each frame during motion, mimicking the bitrate increase.
+const MOTION_FILLER_BYTES: usize = 300;
+
+/// The type of the generated motion signals; see `guide/schema.md`.
+const MOTION_TYPE_UUID: &str = "x'7B94A8C1E2A44E4C9C3B0F6F8D3E1A52'";
+
+const CAMERA_NAMES: &[&str] = &[
+    "driveway",
+    "front door",
+    "back yard",
+    "garage",
+    "side gate",
+    "porch",
+    "patio",
+    "alley",
+];
+
+fn seed(args: &SeedArgs) -> Result<(), Error> {
+    if args.days == 0 || args.cameras == 0 {
+        bail!("--days and --cameras must be positive");
+    }
+    let dir = match args.dir {
+        Some(ref d) => d.clone(),
+        None => std::env::temp_dir().join(format!("moonfire-nvr-demo-{}", std::process::id())),
+    };
+    let db_dir = dir.join("db");
+    let sample_dir = dir.join("sample");
+    std::fs::create_dir_all(&sample_dir)?;
+    let sample_path = sample_dir
+        .to_str()
+        .ok_or_else(|| format_err!("{} isn't UTF-8", sample_dir.display()))?
+        .to_owned();
+    let mut rng = Rng::new(args.seed);
+
+    // Create the database, sample file directory, and cameras.
+    let mut cameras = Vec::new(); // (camera id, camera uuid, stream id).
+    let dir_id;
+    {
+        let (_db_dir, mut conn) = super::open_conn(&db_dir, super::OpenMode::Create)?;
+        if db::get_schema_version(&conn)?.is_some() {
+            bail!("{} already holds a database", db_dir.display());
+        }
+        conn.execute_batch(
+            r#"
+            pragma journal_mode = wal;
+            pragma page_size = 16384;
+            "#,
+        )?;
+        db::init(&mut conn)?;
+        let db = db::Database::new(RealClocks {}, conn, true)?;
+        let mut l = db.lock();
+        dir_id = l.add_sample_file_dir(sample_path)?;
+        for i in 0..args.cameras as usize {
+            let mut short_name = CAMERA_NAMES[i % CAMERA_NAMES.len()].to_owned();
+            if i >= CAMERA_NAMES.len() {
+                short_name.push_str(&format!(" {}", i / CAMERA_NAMES.len() + 1));
+            }
+            let camera_id = l.add_camera(db::CameraChange {
+                short_name,
+                description: "synthetic demo camera".to_owned(),
+                onvif_host: String::new(),
+                username: String::new(),
+                password: String::new(),
+                ptz: false,
+                streams: [
+                    db::StreamChange {
+                        sample_file_dir_id: Some(dir_id),
+                        stripe_dir_ids: Vec::new(),
+                        failover_sample_file_dir_id: None,
+                        rtsp_url: format!("rtsp://demo-camera-{}/main", i + 1),
+                        virtual_source: None,
+                        record: true,
+                        flush_if_sec: 60,
+                        flush_if_bytes: 0,
+                        input_options: String::new(),
+                        rtsp: Default::default(),
+                    },
+                    Default::default(),
+                ],
+            })?;
+            let c = l.cameras_by_id().get(&camera_id).unwrap();
+            let stream_id = c.streams[0].unwrap();
+            cameras.push((camera_id, c.uuid, stream_id));
+            l.update_retention(&[db::RetentionChange {
+                stream_id,
+                new_record: true,
+                new_limit: 1 << 30,
+            }])?;
+        }
+    }
+
+    // Add a motion signal for each camera. There's no API for this yet, so use SQL directly.
+    let (_db_dir, conn) = super::open_conn(&db_dir, super::OpenMode::ReadWrite)?;
+    conn.execute_batch(&format!(
+        r#"
+        insert into signal_type_enum (type_uuid, value, name, motion, color)
+            values ({0}, 1, 'still', 0, 'black'),
+                   ({0}, 2, 'moving', 1, 'red');
+        "#,
+        MOTION_TYPE_UUID
+    ))?;
+    for (i, &(camera_id, uuid, _)) in cameras.iter().enumerate() {
+        let signal_id = i as u32 + 1;
+        conn.execute(
+            &format!(
+                r#"
+                insert into signal (id, source_uuid, type_uuid, short_name)
+                            values (?, ?, {}, ?)
+                "#,
+                MOTION_TYPE_UUID
+            ),
+            params![
+                signal_id,
+                &uuid.as_bytes()[..],
+                format!("camera {} motion", i + 1)
+            ],
+        )?;
+        conn.execute(
+            "insert into signal_camera (signal_id, camera_id, type) values (?, ?, 0)",
+            params![signal_id, camera_id],
+        )?;
+    }
+
+    // Record.
+    let db = Arc::new(db::Database::new(RealClocks {}, conn, true)?);
+    let video = Video::new()?;
+    let video_sample_entry_id = db.lock().insert_video_sample_entry(
+        WIDTH,
+        HEIGHT,
+        video.extra_data.sample_entry.clone(),
+        video.extra_data.rfc6381_codec.clone(),
+    )?;
+    let sample_file_dir = db
+        .lock()
+        .sample_file_dirs_by_id()
+        .get(&dir_id)
+        .unwrap()
+        .get()?;
+    let (channel, join) = writer::start_syncer(db.clone(), dir_id, || {})?;
+    let end = recording::Time::new(db.clocks().realtime());
+    let start =
+        end - recording::Duration(i64::from(args.days) * 86_400 * recording::TIME_UNITS_PER_SEC);
+    for (i, &(_, _, stream_id)) in cameras.iter().enumerate() {
+        info!("Generating camera {}/{}", i + 1, cameras.len());
+        let motion = motion_events(&mut rng, start..end);
+        {
+            let mut l = db.lock();
+            let signal_id = i as u32 + 1;
+            l.update_signals(start..end, &[signal_id], &[1])?;
+            for m in &motion {
+                l.update_signals(m.clone(), &[signal_id], &[2])?;
+            }
+        }
+        let params = CameraParams {
+            stream_id,
+            video_sample_entry_id,
+            luma: 60 + (i as u8 % 6) * 24,
+            chroma: [128 - 16 + (i as u8 % 3) * 16, 128 + 16 - (i as u8 % 4) * 8],
+        };
+        record(
+            &db,
+            &sample_file_dir,
+            &channel,
+            &video,
+            &params,
+            &motion,
+            &mut rng,
+            start..end,
+        )?;
+    }
+    drop(channel);
+    join.join().map_err(|_| format_err!("syncer panicked"))?;
+    db.lock().clear_on_flush();
+    db.lock().flush("demo seed")?;
+
+    println!(
+        "Generated {} days of recordings from {} cameras in {}.\n\
+         To explore them, run:\n\n    \
+         moonfire-nvr run --db-dir={} \
+         --allow-unauthenticated-permissions='view_video: true read_camera_configs: true'",
+        args.days,
+        args.cameras,
+        dir.display(),
+        db_dir.display()
+    );
+    Ok(())
+}
+
+/// Returns random motion events within `time`, more frequent during the day.
+fn motion_events(
+    rng: &mut Rng,
+    time: std::ops::Range<recording::Time>,
+) -> Vec<std::ops::Range<recording::Time>> {
+    let mut events = Vec::new();
+    let mut t = time.start;
+    loop {
+        let hour = (t.0 / (3600 * recording::TIME_UNITS_PER_SEC)) % 24;
+        let mean_gap_sec = if (7..20).contains(&hour) {
+            15. * 60.
+        } else {
+            90. * 60.
+        };
+        let gap_sec = -mean_gap_sec * (1. - rng.next_f64()).ln();
+        t = t + recording::Duration((gap_sec * recording::TIME_UNITS_PER_SEC as f64) as i64);
+        let len_sec = 10 + (rng.next_f64() * 110.) as i64;
+        let event_end = t + recording::Duration(len_sec * recording::TIME_UNITS_PER_SEC);
+        if event_end >= time.end {
+            break;
+        }
+        events.push(t..event_end);
+        t = event_end;
+    }
+    events
+}
+
+struct CameraParams {
+    stream_id: i32,
+    video_sample_entry_id: i32,
+
+    /// Average brightness and tint of this camera's picture.
+    luma: u8,
+    chroma: [u8; 2],
+}
+
+/// Writes one camera's recordings for `time`.
+#[allow(clippy::too_many_arguments)]
+fn record(
+    db: &db::Database,
+    dir: &Arc<db::dir::SampleFileDir>,
+    channel: &writer::SyncerChannel<db::dir::SampleFileWriter>,
+    video: &Video,
+    params: &CameraParams,
+    motion: &[std::ops::Range<recording::Time>],
+    rng: &mut Rng,
+    time: std::ops::Range<recording::Time>,
+) -> Result<(), Error> {
+    let mut w = writer::Writer::new(
+        dir,
+        db,
+        channel,
+        params.stream_id,
+        params.video_sample_entry_id,
+    );
+    let mut motion = motion.iter().peekable();
+    let mut t = time.start;
+    let mut pts = 0;
+    let mut frame = 0u32;
+    let mut recording_start = pts;
+    let mut sample = Vec::new();
+    while t < time.end {
+        let is_key = frame % FRAMES_PER_KEY_FRAME == 0;
+        if is_key && frame > 0 {
+            // Outages are decided at key frames: one per minute of chances, as key frames are
+            // less frequent than that.
+            let chances = FRAMES_PER_KEY_FRAME as f64 * FRAME_DURATION_90K as f64
+                / (60 * recording::TIME_UNITS_PER_SEC) as f64;
+            if rng.next_f64() < OUTAGE_CHANCE_PER_MINUTE * chances {
+                w.close(None)?;
+                w = writer::Writer::new(
+                    dir,
+                    db,
+                    channel,
+                    params.stream_id,
+                    params.video_sample_entry_id,
+                );
+                t = t + recording::Duration((rng.next_f64() * MAX_OUTAGE_90K as f64) as i64);
+                pts = 0;
+                recording_start = pts;
+                frame = 0;
+                continue;
+            } else if pts - recording_start >= RECORDING_DURATION_90K {
+                w.close(Some(pts))?;
+                recording_start = pts;
+            }
+        }
+        while motion.peek().map(|m| m.end <= t).unwrap_or(false) {
+            motion.next();
+        }
+        let moving = motion.peek().map(|m| m.start <= t).unwrap_or(false);
+        let hour = (t.0 / (3600 * recording::TIME_UNITS_PER_SEC)) % 24;
+        let daylight = if (7..20).contains(&hour) { 40 } else { 0 };
+        video.frame(
+            is_key,
+            frame % FRAMES_PER_KEY_FRAME,
+            frame / FRAMES_PER_KEY_FRAME,
+            params.luma.saturating_add(daylight),
+            params.chroma,
+            if moving { MOTION_FILLER_BYTES } else { 0 },
+            &mut sample,
+        )?;
+        let jitter = (rng.next_f64() * MAX_JITTER_90K as f64) as i64;
+        w.write(&sample, t + recording::Duration(jitter), pts, is_key)?;
+        t = t + recording::Duration(FRAME_DURATION_90K);
+        pts += FRAME_DURATION_90K;
+        frame += 1;
+    }
+    w.close(Some(pts))?;
+    Ok(())
+}
+
+/// A small xorshift64* pseudo-random number generator. Demo data needn't be unpredictable, just
+/// varied and reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    /// Returns a value in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let v = self.0.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        (v >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Generates a minimal H.264 constrained baseline stream (ISO/IEC 14496-10): a single-macroblock
+/// picture whose key frames hold one `I_PCM` (uncompressed) macroblock and whose other frames
+/// skip it.
+struct Video {
+    extra_data: h264::ExtraData,
+}
+
+impl Video {
+    fn new() -> Result<Self, Error> {
+        let mut annex_b = Vec::new();
+
+        // Sequence parameter set, section 7.3.2.1.1.
+        let mut sps = BitWriter::default();
+        sps.u(8, 66); // profile_idc: baseline
+        sps.u(8, 0xc0); // constraint_set0_flag, constraint_set1_flag
+        sps.u(8, 10); // level_idc
+        sps.ue(0); // seq_parameter_set_id
+        sps.ue(0); // log2_max_frame_num_minus4
+        sps.ue(2); // pic_order_cnt_type
+        sps.ue(1); // max_num_ref_frames
+        sps.u(1, 0); // gaps_in_frame_num_value_allowed_flag
+        sps.ue(u32::from(WIDTH) / 16 - 1); // pic_width_in_mbs_minus1
+        sps.ue(u32::from(HEIGHT) / 16 - 1); // pic_height_in_map_units_minus1
+        sps.u(1, 1); // frame_mbs_only_flag
+        sps.u(1, 1); // direct_8x8_inference_flag
+        sps.u(1, 0); // frame_cropping_flag
+        sps.u(1, 0); // vui_parameters_present_flag
+        append_nal(0x67, &sps.finish(), &mut annex_b);
+
+        // Picture parameter set, section 7.3.2.2.
+        let mut pps = BitWriter::default();
+        pps.ue(0); // pic_parameter_set_id
+        pps.ue(0); // seq_parameter_set_id
+        pps.u(1, 0); // entropy_coding_mode_flag: CAVLC
+        pps.u(1, 0); // bottom_field_pic_order_in_frame_present_flag
+        pps.ue(0); // num_slice_groups_minus1
+        pps.ue(0); // num_ref_idx_l0_default_active_minus1
+        pps.ue(0); // num_ref_idx_l1_default_active_minus1
+        pps.u(1, 0); // weighted_pred_flag
+        pps.u(2, 0); // weighted_bipred_idc
+        pps.se(0); // pic_init_qp_minus26
+        pps.se(0); // pic_init_qs_minus26
+        pps.se(0); // chroma_qp_index_offset
+        pps.u(1, 1); // deblocking_filter_control_present_flag
+        pps.u(1, 0); // constrained_intra_pred_flag
+        pps.u(1, 0); // redundant_pic_cnt_present_flag
+        append_nal(0x68, &pps.finish(), &mut annex_b);
+
+        Ok(Video {
+            extra_data: h264::ExtraData::parse(&annex_b, WIDTH, HEIGHT)?,
+        })
+    }
+
+    /// Generates a frame in AVC format. `frame_num` is the frame's index within its group of
+    /// pictures; `idr_pic_id` distinguishes consecutive key frames.
+    #[allow(clippy::too_many_arguments)]
+    fn frame(
+        &self,
+        is_key: bool,
+        frame_num: u32,
+        idr_pic_id: u32,
+        luma: u8,
+        chroma: [u8; 2],
+        filler_bytes: usize,
+        out: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let mut annex_b = Vec::new();
+
+        // Slice header, section 7.3.3.
+        let mut s = BitWriter::default();
+        s.ue(0); // first_mb_in_slice
+        s.ue(if is_key { 7 } else { 5 }); // slice_type: all I or all P
+        s.ue(0); // pic_parameter_set_id
+        s.u(4, frame_num % 16); // frame_num
+        if is_key {
+            s.ue(idr_pic_id % 2); // idr_pic_id
+            s.u(1, 0); // no_output_of_prior_pics_flag
+            s.u(1, 0); // long_term_reference_flag
+        } else {
+            s.u(1, 0); // num_ref_idx_active_override_flag
+            s.u(1, 0); // ref_pic_list_modification_flag_l0
+            s.u(1, 0); // adaptive_ref_pic_marking_mode_flag
+        }
+        s.se(0); // slice_qp_delta
+        s.ue(1); // disable_deblocking_filter_idc
+
+        // Slice data, section 7.3.4.
+        if is_key {
+            s.ue(25); // mb_type: I_PCM
+            s.align_zero(); // pcm_alignment_zero_bit
+            for y in 0..16u8 {
+                for x in 0..16u8 {
+                    s.byte(luma.saturating_add(x * 2 + y).max(16).min(235)); // pcm_sample_luma
+                }
+            }
+            for &c in &chroma {
+                for _ in 0..64 {
+                    s.byte(c); // pcm_sample_chroma
+                }
+            }
+        } else {
+            s.ue(1); // mb_skip_run
+        }
+        append_nal(if is_key { 0x65 } else { 0x41 }, &s.finish(), &mut annex_b);
+
+        if filler_bytes > 0 {
+            // Filler data, section 7.3.2.7.
+            let mut f = vec![0xff; filler_bytes];
+            f.push(0x80); // rbsp_trailing_bits
+            append_nal(0x0c, &f, &mut annex_b);
+        }
+        h264::transform_sample_data(&annex_b, out)
+    }
+}
+
+/// Appends a NAL unit with the given header byte and RBSP to `out` in Annex B format, adding
+/// emulation prevention bytes (section 7.4.1).
+fn append_nal(header: u8, rbsp: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&[0, 0, 0, 1, header]);
+    let mut zeros = 0;
+    for &b in rbsp {
+        if zeros >= 2 && b <= 3 {
+            out.push(3);
+            zeros = 0;
+        }
+        out.push(b);
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+    }
+}
+
+/// Writes the bits of an H.264 RBSP, most significant first (section 7.2).
+#[derive(Default)]
+struct BitWriter {
+    buf: Vec<u8>,
+    cur: u8,
+    bits: u8,
+}
+
+impl BitWriter {
+    fn bit(&mut self, b: bool) {
+        self.cur = (self.cur << 1) | b as u8;
+        self.bits += 1;
+        if self.bits == 8 {
+            self.buf.push(self.cur);
+            self.cur = 0;
+            self.bits = 0;
+        }
+    }
+
+    /// Writes `v` as an unsigned `n`-bit integer, `u(n)`.
+    fn u(&mut self, n: u32, v: u32) {
+        for i in (0..n).rev() {
+            self.bit((v >> i) & 1 != 0);
+        }
+    }
+
+    /// Writes `v` as an unsigned Exp-Golomb code, `ue(v)` (section 9.1).
+    fn ue(&mut self, v: u32) {
+        let v = v + 1;
+        let len = 32 - v.leading_zeros();
+        self.u(len - 1, 0);
+        self.u(len, v);
+    }
+
+    /// Writes `v` as a signed Exp-Golomb code, `se(v)` (section 9.1.1).
+    fn se(&mut self, v: i32) {
+        self.ue(if v > 0 {
+            2 * v as u32 - 1
+        } else {
+            2 * (-v) as u32
+        });
+    }
+
+    fn align_zero(&mut self) {
+        while self.bits != 0 {
+            self.bit(false);
+        }
+    }
+
+    fn byte(&mut self, b: u8) {
+        debug_assert_eq!(self.bits, 0);
+        self.buf.push(b);
+    }
+
+    /// Adds `rbsp_trailing_bits` and returns the RBSP.
+    fn finish(mut self) -> Vec<u8> {
+        self.bit(true);
+        self.align_zero();
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exp_golomb() {
+        let mut w = BitWriter::default();
+        w.ue(0); // 1
+        w.ue(1); // 010
+        w.ue(2); // 011
+        w.se(-1); // 011
+        w.se(1); // 010
+        assert_eq!(w.finish(), vec![0b1010_0110, 0b1101_0100]);
+    }
+
+    #[test]
+    fn emulation_prevention() {
+        let mut out = Vec::new();
+        append_nal(0x0c, &[0, 0, 1, 0, 0, 0xff], &mut out);
+        assert_eq!(out, vec![0, 0, 0, 1, 0x0c, 0, 0, 3, 1, 0, 0, 0xff]);
+    }
+
+    #[test]
+    fn frames() {
+        let v = Video::new().unwrap();
+        assert_eq!(v.extra_data.rfc6381_codec, "avc1.42c00a");
+        let mut key = Vec::new();
+        v.frame(true, 0, 0, 100, [128, 128], 0, &mut key).unwrap();
+        assert!(key.len() > 384);
+        let mut p = Vec::new();
+        v.frame(false, 1, 0, 100, [128, 128], 0, &mut p).unwrap();
+        assert!(p.len() < 16);
+        let mut moving = Vec::new();
+        v.frame(
+            false,
+            1,
+            0,
+            100,
+            [128, 128],
+            MOTION_FILLER_BYTES,
+            &mut moving,
+        )
+        .unwrap();
+        assert!(moving.len() > MOTION_FILLER_BYTES);
+    }
+}
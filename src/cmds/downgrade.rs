@@ -0,0 +1,82 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+/// Downgrades the database schema.
+///
+/// See `guide/schema.md` for more information.
+use failure::Error;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Args {
+    #[structopt(
+        long,
+        help = "Directory holding the SQLite3 index database.",
+        default_value = "/var/lib/moonfire-nvr/db",
+        parse(from_os_str)
+    )]
+    db_dir: std::path::PathBuf,
+
+    #[structopt(long, help = "Schema version to downgrade to.")]
+    to: i32,
+
+    #[structopt(
+        help = "Discards data which the older schema can't represent (such as notes and \
+                detections) rather than failing.",
+        long
+    )]
+    discard_data: bool,
+
+    #[structopt(
+        help = "Resets the SQLite journal_mode to the specified mode prior to the \
+                downgrade. See the upgrade command's --preset-journal for more information.",
+        long,
+        default_value = "delete"
+    )]
+    preset_journal: String,
+
+    #[structopt(help = "Skips the normal post-downgrade vacuum operation.", long)]
+    no_vacuum: bool,
+}
+
+pub fn run(args: &Args) -> Result<(), Error> {
+    let (_db_dir, mut conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadWrite)?;
+    db::upgrade::recover(&args.db_dir, &conn, false)?;
+
+    db::upgrade::run_downgrade(
+        &db::upgrade::DowngradeArgs {
+            preset_journal: &args.preset_journal,
+            no_vacuum: args.no_vacuum,
+            discard_data: args.discard_data,
+        },
+        args.to,
+        &mut conn,
+    )
+}
@@ -0,0 +1,545 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2016-2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Hardware-in-the-loop compatibility testing against a real camera.
+//!
+//! `moonfire-nvr hil-test` records a single RTSP stream into a scratch database and sample file
+//! directory for a while, then checks the result the same ways a real installation would use it
+//! (ingest, clock handling, `.mp4` and `.m4s` playback) and writes a JSON report suitable for
+//! attaching to camera compatibility reports. It never touches the real database.
+
+use crate::mp4;
+use crate::stream;
+use crate::streamer;
+use base::clock::RealClocks;
+use base::crypto::Sha1;
+use db::{dir, recording};
+use failure::{bail, format_err, Error};
+use fnv::FnvHashMap;
+use serde::Serialize;
+use std::cmp;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+use tracing::{info, warn};
+use url::Url;
+
+/// Recordings whose average key frame interval exceeds this fail the key frame test. Recordings
+/// can only be split at key frames, so long intervals mean long recordings and slow live view
+/// startup.
+const MAX_KEY_FRAME_INTERVAL_90K: i64 = 10 * recording::TIME_UNITS_PER_SEC;
+
+/// The largest acceptable `local_time_delta`, beyond which the camera's clock is drifting faster
+/// than the writer can correct.
+const MAX_LOCAL_TIME_DELTA_90K: i64 = recording::TIME_UNITS_PER_SEC;
+
+/// The minimum fraction of the wall-clock test duration which should be covered by recordings.
+const MIN_COVERAGE: f64 = 0.95;
+
+#[derive(StructOpt)]
+pub struct Args {
+    /// RTSP URL of the camera stream to test, including any credentials.
+    #[structopt(long)]
+    url: String,
+
+    /// How long to record before running the playback tests.
+    #[structopt(long, default_value = "3600", value_name = "secs")]
+    duration_sec: u64,
+
+    /// ffmpeg input options, as in the camera configuration's "input options".
+    #[structopt(long, default_value = "")]
+    input_options: String,
+
+    /// RTSP transport to use: "tcp" or "udp".
+    #[structopt(long, default_value = "tcp", parse(try_from_str = parse_rtsp_transport))]
+    rtsp_transport: db::RtspTransport,
+
+    /// Directory in which to create the scratch sample file directory. It needs space for the
+    /// whole test's recordings: about 450 MiB per hour per Mbps of stream bitrate.
+    ///
+    /// Defaults to the system temporary directory.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    scratch_dir: Option<PathBuf>,
+
+    /// File to write the JSON report to. Defaults to standard output.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    report: Option<PathBuf>,
+}
+
+fn parse_rtsp_transport(s: &str) -> Result<db::RtspTransport, String> {
+    db::RtspTransport::parse(s).ok_or_else(|| format!("bad RTSP transport {:?}", s))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Report {
+    moonfire_version: &'static str,
+    url: String,
+    input_options: String,
+    rtsp_transport: &'static str,
+    duration_sec: f64,
+    video_sample_entries: Vec<VideoSampleEntry>,
+    recordings: usize,
+    video_samples: i64,
+    video_sync_samples: i64,
+    sample_file_bytes: i64,
+    media_duration_sec: f64,
+    tests: Vec<TestResult>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VideoSampleEntry {
+    rfc6381_codec: String,
+    width: u16,
+    height: u16,
+}
+
+#[derive(Serialize)]
+struct TestResult {
+    name: &'static str,
+    passed: bool,
+    detail: String,
+}
+
+/// The portion of a `db::ListRecordingsRow` needed for the tests.
+struct Row {
+    id: db::CompositeId,
+    duration_90k: i32,
+    video_samples: i32,
+    video_sync_samples: i32,
+    sample_file_bytes: i32,
+    run_offset: i32,
+    video_sample_entry_id: i32,
+}
+
+pub fn run(args: &Args) -> Result<(), Error> {
+    let mut redacted_url = Url::parse(&args.url)?;
+    if redacted_url.password().is_some() {
+        redacted_url.set_password(Some("redacted")).unwrap();
+    }
+    let tmpdir = match args.scratch_dir {
+        None => tempdir::TempDir::new("moonfire-nvr-hil-test")?,
+        Some(ref d) => tempdir::TempDir::new_in(d, "moonfire-nvr-hil-test")?,
+    };
+    let path = tmpdir
+        .path()
+        .to_str()
+        .ok_or_else(|| format_err!("scratch dir {:?} isn't UTF-8", tmpdir.path()))?
+        .to_owned();
+
+    // Set up a scratch database with one camera, much like db::testutil::TestDb.
+    let mut conn = rusqlite::Connection::open_in_memory()?;
+    db::init(&mut conn)?;
+    let db = Arc::new(db::Database::new(RealClocks {}, conn, true)?);
+    let (dir_id, stream_id, dir);
+    {
+        let mut l = db.lock();
+        dir_id = l.add_sample_file_dir(path)?;
+        let camera_id = l.add_camera(db::CameraChange {
+            short_name: "hil-test".to_owned(),
+            description: String::new(),
+            onvif_host: String::new(),
+            username: String::new(),
+            password: String::new(),
+            ptz: false,
+            streams: [
+                db::StreamChange {
+                    sample_file_dir_id: Some(dir_id),
+                    stripe_dir_ids: Vec::new(),
+                    failover_sample_file_dir_id: None,
+                    rtsp_url: args.url.clone(),
+                    virtual_source: None,
+                    record: true,
+                    flush_if_sec: 0,
+                    flush_if_bytes: 0,
+                    input_options: args.input_options.clone(),
+                    rtsp: db::RtspPolicy {
+                        transport: args.rtsp_transport,
+                        ..Default::default()
+                    },
+                },
+                Default::default(),
+            ],
+        })?;
+        stream_id = l.cameras_by_id().get(&camera_id).unwrap().streams[0].unwrap();
+        l.update_retention(&[db::RetentionChange {
+            stream_id,
+            new_record: true,
+            new_limit: i64::max_value() / 2,
+        }])?;
+        dir = l.sample_file_dirs_by_id().get(&dir_id).unwrap().get()?;
+    }
+    let mut dirs_by_stream_id = FnvHashMap::default();
+    dirs_by_stream_id.insert(
+        stream_id,
+        dir::StreamDirs {
+            stripes: vec![dir.clone()],
+            failover: None,
+        },
+    );
+    let dirs_by_stream_id = Arc::new(dirs_by_stream_id);
+    let (syncer_channel, syncer_join) = db::writer::start_syncer(db.clone(), dir_id, || {})?;
+
+    // Record.
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let mut streamer = {
+        let env = streamer::Environment {
+            db: &db,
+            opener: &*stream::FFMPEG,
+            shutdown: &shutdown,
+            health: &Default::default(),
+            events: &Default::default(),
+            thumbnail_width: None,
+            analytics_sched: &Default::default(),
+        };
+        let l = db.lock();
+        let s = l.streams_by_id().get(&stream_id).unwrap();
+        let c = l.cameras_by_id().get(&s.camera_id).unwrap();
+        streamer::Streamer::new(
+            &env,
+            vec![(dir, syncer_channel.clone())],
+            None,
+            stream_id,
+            c,
+            s,
+            None,
+            0,
+            streamer::ROTATE_INTERVAL_SEC,
+        )?
+    };
+    info!(
+        "Recording {} for {} seconds into {}",
+        redacted_url,
+        args.duration_sec,
+        tmpdir.path().display()
+    );
+    let start = Instant::now();
+    let streamer_join = thread::Builder::new()
+        .name("s-hil-test".to_owned())
+        .spawn(move || streamer.run())?;
+    let deadline = start + Duration::from_secs(args.duration_sec);
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        thread::sleep(cmp::min(deadline - now, Duration::from_secs(60)));
+        let bytes = db
+            .lock()
+            .streams_by_id()
+            .get(&stream_id)
+            .unwrap()
+            .sample_file_bytes;
+        info!(
+            "{}s elapsed: {} bytes recorded",
+            start.elapsed().as_secs(),
+            bytes
+        );
+    }
+    shutdown.store(true, Ordering::SeqCst);
+    streamer_join
+        .join()
+        .map_err(|_| format_err!("streamer panicked"))?;
+    let elapsed = start.elapsed();
+
+    // Gather what was recorded.
+    let mut rows = Vec::new();
+    let all_time = recording::Time(i64::min_value())..recording::Time(i64::max_value());
+    db.lock()
+        .list_recordings_by_time(stream_id, all_time, &mut |r| {
+            rows.push(Row {
+                id: r.id,
+                duration_90k: r.duration_90k,
+                video_samples: r.video_samples,
+                video_sync_samples: r.video_sync_samples,
+                sample_file_bytes: r.sample_file_bytes,
+                run_offset: r.run_offset,
+                video_sample_entry_id: r.video_sample_entry_id,
+            });
+            Ok(())
+        })?;
+    let mut vse_ids: Vec<i32> = rows.iter().map(|r| r.video_sample_entry_id).collect();
+    vse_ids.sort();
+    vse_ids.dedup();
+    let video_sample_entries = {
+        let l = db.lock();
+        vse_ids
+            .iter()
+            .map(|id| {
+                let e = l.video_sample_entries_by_id().get(id).unwrap();
+                VideoSampleEntry {
+                    rfc6381_codec: e.rfc6381_codec.clone(),
+                    width: e.width,
+                    height: e.height,
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    let media_duration_90k: i64 = rows.iter().map(|r| r.duration_90k as i64).sum();
+    let mut report = Report {
+        moonfire_version: env!("CARGO_PKG_VERSION"),
+        url: redacted_url.to_string(),
+        input_options: args.input_options.clone(),
+        rtsp_transport: args.rtsp_transport.as_str(),
+        duration_sec: elapsed.as_secs_f64(),
+        recordings: rows.len(),
+        video_samples: rows.iter().map(|r| r.video_samples as i64).sum(),
+        video_sync_samples: rows.iter().map(|r| r.video_sync_samples as i64).sum(),
+        sample_file_bytes: rows.iter().map(|r| r.sample_file_bytes as i64).sum(),
+        media_duration_sec: media_duration_90k as f64 / recording::TIME_UNITS_PER_SEC as f64,
+        video_sample_entries,
+        tests: Vec::new(),
+    };
+
+    // Ingest tests.
+    report.tests.push(TestResult {
+        name: "ingest",
+        passed: !rows.is_empty(),
+        detail: format!(
+            "{} recordings of {} frames",
+            rows.len(),
+            report.video_samples
+        ),
+    });
+    let runs = rows.iter().filter(|r| r.run_offset == 0).count();
+    report.tests.push(TestResult {
+        name: "stability",
+        passed: runs == 1,
+        detail: format!("{} reconnects; see log for errors", runs.saturating_sub(1)),
+    });
+    let coverage = report.media_duration_sec / report.duration_sec;
+    report.tests.push(TestResult {
+        name: "coverage",
+        passed: coverage >= MIN_COVERAGE,
+        detail: format!("recordings cover {:.1}% of the test", 100. * coverage),
+    });
+    report.tests.push(TestResult {
+        name: "video sample entry",
+        passed: vse_ids.len() == 1,
+        detail: format!(
+            "{} distinct video sample entries",
+            report.video_sample_entries.len()
+        ),
+    });
+    let max_key_frame_interval_90k = rows
+        .iter()
+        .map(|r| r.duration_90k as i64 / cmp::max(r.video_sync_samples as i64, 1))
+        .max()
+        .unwrap_or(0);
+    report.tests.push(TestResult {
+        name: "key frame interval",
+        passed: !rows.is_empty() && max_key_frame_interval_90k <= MAX_KEY_FRAME_INTERVAL_90K,
+        detail: format!(
+            "max average key frame interval {}",
+            recording::Duration(max_key_frame_interval_90k)
+        ),
+    });
+
+    // Clock test.
+    let mut max_delta_90k = 0;
+    {
+        let l = db.lock();
+        for r in &rows {
+            if let Some(d) = l.get_local_time_delta(r.id)? {
+                max_delta_90k = cmp::max(max_delta_90k, d.0.abs());
+            }
+        }
+    }
+    report.tests.push(TestResult {
+        name: "clock",
+        passed: max_delta_90k <= MAX_LOCAL_TIME_DELTA_90K,
+        detail: format!(
+            "max local time delta {}",
+            recording::Duration(max_delta_90k)
+        ),
+    });
+
+    // Playback tests.
+    report
+        .tests
+        .push(match test_mp4(&db, &dirs_by_stream_id, stream_id, &rows) {
+            Ok(d) => TestResult {
+                name: "mp4 playback",
+                passed: true,
+                detail: d,
+            },
+            Err(e) => TestResult {
+                name: "mp4 playback",
+                passed: false,
+                detail: e.to_string(),
+            },
+        });
+    report
+        .tests
+        .push(match test_m4s(&db, &dirs_by_stream_id, stream_id, &rows) {
+            Ok(d) => TestResult {
+                name: "m4s playback",
+                passed: true,
+                detail: d,
+            },
+            Err(e) => TestResult {
+                name: "m4s playback",
+                passed: false,
+                detail: e.to_string(),
+            },
+        });
+
+    drop(syncer_channel);
+    syncer_join
+        .join()
+        .map_err(|_| format_err!("syncer panicked"))?;
+
+    match args.report {
+        None => serde_json::to_writer_pretty(std::io::stdout(), &report)?,
+        Some(ref p) => serde_json::to_writer_pretty(std::fs::File::create(p)?, &report)?,
+    }
+    let failed: Vec<&str> = report
+        .tests
+        .iter()
+        .filter(|t| !t.passed)
+        .map(|t| t.name)
+        .collect();
+    if !failed.is_empty() {
+        warn!("failed tests: {}", failed.join(", "));
+        bail!("{} of {} tests failed", failed.len(), report.tests.len());
+    }
+    info!("All {} tests passed.", report.tests.len());
+    Ok(())
+}
+
+/// Builds one `.mp4` per run and checks it can be read both whole and in two ranges.
+fn test_mp4(
+    db: &Arc<db::Database>,
+    dirs_by_stream_id: &Arc<FnvHashMap<i32, dir::StreamDirs>>,
+    stream_id: i32,
+    rows: &[Row],
+) -> Result<String, Error> {
+    let mut files = 0;
+    let mut total_len = 0;
+    let mut i = 0;
+    while i < rows.len() {
+        let run_start = i;
+        i += 1;
+        while i < rows.len() && rows[i].run_offset != 0 {
+            i += 1;
+        }
+        let ids = rows[run_start].id.recording()..rows[i - 1].id.recording() + 1;
+        let mut builder = mp4::FileBuilder::new(mp4::Type::Normal);
+        {
+            let l = db.lock();
+            l.list_recordings_by_id(stream_id, ids, &mut |r| {
+                let d = r.duration_90k;
+                builder.append(&l, r, 0..d)?;
+                Ok(())
+            })?;
+        }
+        let f = builder.build(db.clone(), dirs_by_stream_id.clone())?;
+        check_reads(&f)?;
+        files += 1;
+        total_len += http_serve::Entity::len(&f);
+    }
+    Ok(format!("{} .mp4 files totaling {} bytes", files, total_len))
+}
+
+/// Builds an init segment for each video sample entry and a media segment for each recording.
+fn test_m4s(
+    db: &Arc<db::Database>,
+    dirs_by_stream_id: &Arc<FnvHashMap<i32, dir::StreamDirs>>,
+    stream_id: i32,
+    rows: &[Row],
+) -> Result<String, Error> {
+    let entries: Vec<_> = db
+        .lock()
+        .video_sample_entries_by_id()
+        .values()
+        .cloned()
+        .collect();
+    for e in entries {
+        let mut builder = mp4::FileBuilder::new(mp4::Type::InitSegment);
+        builder.append_video_sample_entry(e);
+        check_reads(&builder.build(db.clone(), dirs_by_stream_id.clone())?)?;
+    }
+    for r in rows {
+        let mut builder = mp4::FileBuilder::new(mp4::Type::MediaSegment);
+        {
+            let l = db.lock();
+            let ids = r.id.recording()..r.id.recording() + 1;
+            l.list_recordings_by_id(stream_id, ids, &mut |r| {
+                let d = r.duration_90k;
+                builder.append(&l, r, 0..d)?;
+                Ok(())
+            })?;
+        }
+        check_reads(&builder.build(db.clone(), dirs_by_stream_id.clone())?)?;
+    }
+    Ok(format!("{} media segments", rows.len()))
+}
+
+/// Checks that reading `f` whole matches reading it as two ranges split in the middle.
+fn check_reads(f: &mp4::File) -> Result<(), Error> {
+    let len = http_serve::Entity::len(f);
+    let mut whole = Sha1::new();
+    hash_range(f, 0..len, &mut whole)?;
+    let mut split = Sha1::new();
+    hash_range(f, 0..len / 2, &mut split)?;
+    hash_range(f, len / 2..len, &mut split)?;
+    if whole.finish() != split.finish() {
+        bail!(
+            "reading {}-byte file in two ranges produced different bytes",
+            len
+        );
+    }
+    Ok(())
+}
+
+fn hash_range(f: &mp4::File, r: Range<u64>, h: &mut Sha1) -> Result<(), Error> {
+    use bytes::Buf;
+    use futures::stream::StreamExt;
+    let want = r.end - r.start;
+    let mut s = std::pin::Pin::from(http_serve::Entity::get_range(f, r));
+    let mut got = 0;
+    futures::executor::block_on(async {
+        while let Some(c) = s.next().await {
+            let c = c.map_err(failure::Error::from_boxed_compat)?;
+            h.update(c.bytes());
+            got += c.bytes().len() as u64;
+        }
+        Ok::<_, Error>(())
+    })?;
+    if got != want {
+        bail!("read {} bytes; expected {}", got, want);
+    }
+    Ok(())
+}
@@ -0,0 +1,339 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Subcommand to import recordings from a video file, such as when migrating from another NVR.
+//!
+//! Imported recordings mustn't overlap existing ones in the stream; if the file runs into the
+//! stream's next recording, the import stops there. Note retention deletes a stream's recordings
+//! in the order they were added, so imported recordings outlast those already in the stream even
+//! if they're older.
+
+use crate::h264;
+use crate::stream::{self, Opener, Stream};
+use base::clock::RealClocks;
+use db::{dir, recording, writer};
+use failure::{bail, format_err, Error};
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::StructOpt;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Imported recordings are split at the first key frame after this long, so they're comparable in
+/// length to those recorded live.
+const RECORDING_DURATION_90K: i64 = 60 * recording::TIME_UNITS_PER_SEC;
+
+#[derive(StructOpt)]
+pub struct Args {
+    /// Directory holding the SQLite3 index database.
+    #[structopt(
+        long,
+        default_value = "/var/lib/moonfire-nvr/db",
+        value_name = "path",
+        parse(from_os_str)
+    )]
+    db_dir: PathBuf,
+
+    /// UUID of the camera to import into.
+    #[structopt(long)]
+    camera: Uuid,
+
+    /// The camera's stream to import into: `main` or `sub`.
+    #[structopt(long, default_value = "main")]
+    stream: String,
+
+    /// The wall time of the file's first frame, as accepted by `moonfire-nvr ts`, such as
+    /// `2020-04-26T12:00:00-07:00`.
+    #[structopt(long)]
+    start: String,
+
+    /// As in `moonfire-nvr run --sample-file-key`. If set, imported sample files are encrypted.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    sample_file_key: Option<PathBuf>,
+
+    /// The file to import. It may be in any container ffmpeg can read, such as `.mp4` or
+    /// `.mkv`, and must hold H.264 video without B-frames. Other tracks are ignored.
+    #[structopt(parse(from_os_str))]
+    file: PathBuf,
+}
+
+pub fn run(args: &Args) -> Result<(), Error> {
+    let type_ = db::StreamType::parse(&args.stream)
+        .ok_or_else(|| format_err!("bad --stream {:?}; expected main or sub", args.stream))?;
+    let start = recording::Time::parse(&args.start)?;
+    let path = args
+        .file
+        .to_str()
+        .ok_or_else(|| format_err!("{} isn't UTF-8", args.file.display()))?;
+
+    let (_db_dir, conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadWrite)?;
+    db::upgrade::recover(&args.db_dir, &conn, false)?;
+    let db = Arc::new(db::Database::new(RealClocks {}, conn, true)?);
+    if let Some(ref p) = args.sample_file_key {
+        let key = dir::SampleFileKey::read(p)?;
+        info!("Using sample file key {:?}", &key);
+        db.lock().set_sample_file_key(Some(Arc::new(key)));
+    }
+    let (stream_id, dir_ids, limit) = {
+        let mut l = db.lock();
+        let camera = l
+            .get_camera(args.camera)
+            .ok_or_else(|| format_err!("no such camera {}", args.camera))?;
+        let stream_id = camera.streams[type_.index()]
+            .ok_or_else(|| format_err!("camera {} has no {} stream", args.camera, type_))?;
+        let dir_ids = l.streams_by_id().get(&stream_id).unwrap().dir_ids();
+        if dir_ids.is_empty() {
+            bail!(
+                "stream {}/{} has no sample file directory",
+                args.camera,
+                type_
+            );
+        }
+        let limit = next_recording(&l, stream_id, start)?;
+        l.open_sample_file_dirs(&dir_ids)?;
+        (stream_id, dir_ids, limit)
+    };
+
+    let mut input = stream::FFMPEG.open(stream::Source::File(path))?;
+    let mut syncers = Vec::with_capacity(dir_ids.len());
+    for &id in &dir_ids {
+        let dir = db.lock().sample_file_dirs_by_id().get(&id).unwrap().get()?;
+        let (channel, join) = writer::start_syncer(db.clone(), id, || {})?;
+        syncers.push((dir, channel, join));
+    }
+    let summary = import(
+        &db,
+        syncers.iter().map(|(d, c, _)| (d, c)).collect(),
+        stream_id,
+        &mut input,
+        start,
+        limit,
+    )?;
+    for (_, channel, join) in syncers {
+        drop(channel);
+        join.join().map_err(|_| format_err!("syncer panicked"))?;
+    }
+    db.lock().flush("import")?;
+    println!(
+        "Imported {} frames in {} recordings, from {} to {}.",
+        summary.frames, summary.recordings, start, summary.end
+    );
+    Ok(())
+}
+
+/// Returns the start of the stream's first recording after `start`, or an error if a recording
+/// already covers `start`.
+fn next_recording(
+    l: &db::LockedDatabase,
+    stream_id: i32,
+    start: recording::Time,
+) -> Result<Option<recording::Time>, Error> {
+    let mut next: Option<recording::Time> = None;
+    l.list_recordings_by_time(stream_id, start..recording::Time::max_value(), &mut |r| {
+        let end = r.start + recording::Duration(i64::from(r.duration_90k));
+        if r.start <= start && start < end {
+            bail!("recording {} already covers {}", r.id, start);
+        }
+        if r.start > start && next.map(|n| r.start < n).unwrap_or(true) {
+            next = Some(r.start);
+        }
+        Ok(())
+    })?;
+    Ok(next)
+}
+
+/// What `import` wrote.
+struct Summary {
+    frames: u64,
+    recordings: u32,
+
+    /// The end of the last frame imported.
+    end: recording::Time,
+}
+
+/// Writes `input`'s video to the given stream as recordings starting at `start`. Stops early
+/// (with a warning) rather than write any frame at or after `limit`.
+fn import<S: Stream>(
+    db: &db::Database,
+    stripes: Vec<(
+        &Arc<dir::SampleFileDir>,
+        &writer::SyncerChannel<dir::SampleFileWriter>,
+    )>,
+    stream_id: i32,
+    input: &mut S,
+    start: recording::Time,
+    limit: Option<recording::Time>,
+) -> Result<Summary, Error> {
+    let extra_data = input.get_extra_data()?;
+    let video_sample_entry_id = db.lock().insert_video_sample_entry(
+        extra_data.width,
+        extra_data.height,
+        extra_data.sample_entry.clone(),
+        extra_data.rfc6381_codec.clone(),
+    )?;
+    let mut w = writer::Writer::new_striped(stripes, db, stream_id, video_sample_entry_id);
+    let mut transformed = Vec::new();
+    let mut first_pts = None;
+    let mut recording_start_pts = 0;
+    let mut summary = Summary {
+        frames: 0,
+        recordings: 0,
+        end: start,
+    };
+
+    // As in `mp4::tests::copy_mp4_to_db`, the writer calculates each frame's duration from the
+    // next frame's pts, so the final frame's duration comes from ffmpeg instead.
+    let mut end_pts = None;
+    loop {
+        let pkt = match input.get_next() {
+            Ok(p) => p,
+            Err(e) if e.is_eof() => break,
+            Err(e) => return Err(e.into()),
+        };
+        let pts = pkt.pts().ok_or_else(|| format_err!("packet with no pts"))?;
+        let first = match first_pts {
+            Some(p) => p,
+            None if !pkt.is_key() => continue, // recordings must start with a key frame.
+            None => {
+                first_pts = Some(pts);
+                recording_start_pts = pts;
+                pts
+            }
+        };
+        let frame_time = start + recording::Duration(pts - first);
+        if let Some(l) = limit {
+            if frame_time >= l {
+                // Shorten the previous frame (if any) so it ends where the next recording starts.
+                warn!(
+                    "Stopping at {}, where the stream's next recording starts",
+                    l
+                );
+                end_pts = end_pts.map(|_| first + (l - start).0);
+                break;
+            }
+        }
+        if pkt.is_key() && pts - recording_start_pts >= RECORDING_DURATION_90K {
+            w.close(Some(pts))?;
+            recording_start_pts = pts;
+        }
+        if recording_start_pts == pts {
+            summary.recordings += 1;
+        }
+        let data = pkt
+            .data()
+            .ok_or_else(|| format_err!("packet has no data"))?;
+        let data = if extra_data.need_transform {
+            h264::transform_sample_data(data, &mut transformed)?;
+            transformed.as_slice()
+        } else {
+            data
+        };
+        w.write(data, frame_time, pts, pkt.is_key())?;
+        summary.frames += 1;
+        end_pts = Some(pts + i64::from(pkt.duration()));
+    }
+    w.close(end_pts)?;
+    if let (Some(first), Some(end)) = (first_pts, end_pts) {
+        summary.end = start + recording::Duration(end - first);
+    }
+    if summary.frames == 0 {
+        bail!("no key frame to start from");
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::testutil::{self, TestDb, TEST_STREAM_ID};
+
+    /// 2015-04-26 00:00:00 UTC.
+    const START: recording::Time = recording::Time(1430006400i64 * recording::TIME_UNITS_PER_SEC);
+
+    fn import_clip(db: &TestDb<RealClocks>, limit: Option<recording::Time>) -> Summary {
+        let dir = &db.dirs_by_stream_id.get(&TEST_STREAM_ID).unwrap().stripes[0];
+        let mut input = stream::FFMPEG
+            .open(stream::Source::File("src/testdata/clip.mp4"))
+            .unwrap();
+        let summary = import(
+            &db.db,
+            vec![(dir, &db.syncer_channel)],
+            TEST_STREAM_ID,
+            &mut input,
+            START,
+            limit,
+        )
+        .unwrap();
+        db.syncer_channel.flush();
+        summary
+    }
+
+    #[test]
+    fn whole_file() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let summary = import_clip(&db, None);
+        assert_eq!(summary.recordings, 1);
+        let mut rows = Vec::new();
+        db.db
+            .lock()
+            .list_recordings_by_time(TEST_STREAM_ID, START..summary.end, &mut |r| {
+                rows.push(r);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].start, START);
+        assert_eq!(rows[0].video_samples as u64, summary.frames);
+        assert_eq!(
+            START + recording::Duration(i64::from(rows[0].duration_90k)),
+            summary.end
+        );
+
+        // A second import at the same time is rejected; one just before it stops short.
+        let l = db.db.lock();
+        next_recording(&l, TEST_STREAM_ID, START).unwrap_err();
+        let before = START - recording::Duration(recording::TIME_UNITS_PER_SEC);
+        assert_eq!(
+            next_recording(&l, TEST_STREAM_ID, before).unwrap(),
+            Some(START)
+        );
+    }
+
+    #[test]
+    fn limit() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let limit = START + recording::Duration(recording::TIME_UNITS_PER_SEC);
+        let summary = import_clip(&db, Some(limit));
+        assert!(summary.frames > 0);
+        assert_eq!(summary.end, limit);
+    }
+}
@@ -29,9 +29,9 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use failure::Error;
-use log::info;
 use std::path::PathBuf;
 use structopt::StructOpt;
+use tracing::info;
 
 #[derive(StructOpt)]
 pub struct Args {
@@ -29,17 +29,27 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use db::dir;
-use failure::{Error, Fail};
+use failure::{bail, Error, Fail};
 use nix::fcntl::FlockArg;
 use rusqlite;
 use std::path::Path;
 
+pub mod backup;
 pub mod check;
 pub mod config;
+pub mod demo;
+pub mod downgrade;
+#[cfg(feature = "hil-test")]
+pub mod hil_test;
+pub mod import;
 pub mod init;
 pub mod login;
+pub mod retention;
 pub mod run;
+pub mod sample_files;
 pub mod sql;
+pub mod support_bundle;
+pub mod token;
 pub mod ts;
 pub mod upgrade;
 
@@ -74,8 +84,16 @@ fn open_dir(db_dir: &Path, mode: OpenMode) -> Result<dir::Fd, Error> {
 /// The returned `dir::Fd` holds the lock and should be kept open as long as the `Connection` is.
 fn open_conn(db_dir: &Path, mode: OpenMode) -> Result<(dir::Fd, rusqlite::Connection), Error> {
     let dir = open_dir(db_dir, mode)?;
+    let db_path = db_dir.join("db");
+    if mode != OpenMode::Create && !db_path.exists() {
+        bail!(
+            "no database in {}. If it's on a separate disk, is that disk mounted? Otherwise, use \
+             `moonfire-nvr init` to create one.",
+            db_dir.display()
+        );
+    }
     let conn = rusqlite::Connection::open_with_flags(
-        db_dir.join("db"),
+        db_path,
         match mode {
             OpenMode::ReadOnly => rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
             OpenMode::ReadWrite => rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE,
@@ -0,0 +1,142 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Subcommand to predict how much footage streams would keep under different retention limits.
+
+use crate::json;
+use base::clock::{self, Clocks};
+use base::strutil::decode_size;
+use db::recording;
+use db::retention::{self, Limit};
+use failure::{bail, format_err, Error};
+use fnv::FnvHashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Args {
+    /// Directory holding the SQLite3 index database.
+    #[structopt(
+        long,
+        default_value = "/var/lib/moonfire-nvr/db",
+        value_name = "path",
+        parse(from_os_str)
+    )]
+    db_dir: PathBuf,
+
+    /// The number of recent days over which to measure each stream's disk usage.
+    #[structopt(long, default_value = "7", value_name = "days")]
+    window_days: i64,
+
+    /// Hypothetical limits, as `<camera short name>/<main|sub>=<limit>`. The limit is either a
+    /// size, such as `500G`, or a number of days, such as `30d`.
+    ///
+    /// Other streams are shown with their current limits.
+    limits: Vec<String>,
+}
+
+/// Parses a limit such as `500G` or `30d`.
+fn parse_limit(s: &str) -> Option<Limit> {
+    if s.ends_with('d') {
+        return f64::from_str(&s[..s.len() - 1])
+            .ok()
+            .filter(|d| *d >= 0. && d.is_finite())
+            .map(Limit::Days);
+    }
+    decode_size(s).ok().map(Limit::Bytes)
+}
+
+pub fn run(args: &Args) -> Result<(), Error> {
+    if !(1..=retention::MAX_WINDOW_DAYS).contains(&args.window_days) {
+        bail!(
+            "--window-days must be between 1 and {}",
+            retention::MAX_WINDOW_DAYS
+        );
+    }
+    let (_db_dir, conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadOnly)?;
+    let clocks = clock::RealClocks {};
+    let db = db::Database::new(clocks, conn, false)?;
+    let window = retention::window(recording::Time::new(clocks.realtime()), args.window_days);
+    let db = db.lock();
+    let mut limits = FnvHashMap::default();
+    for l in &args.limits {
+        let eq = l
+            .find('=')
+            .ok_or_else(|| format_err!("limit {:?} isn't camera/stream=limit", l))?;
+        let (s, limit) = (&l[..eq], &l[eq + 1..]);
+        let slash = s
+            .rfind('/')
+            .ok_or_else(|| format_err!("limit {:?} isn't camera/stream=limit", l))?;
+        let type_ = db::StreamType::parse(&s[slash + 1..])
+            .ok_or_else(|| format_err!("limit {:?} has bad stream type", l))?;
+        let camera = db
+            .cameras_by_id()
+            .values()
+            .find(|c| c.short_name == s[..slash])
+            .ok_or_else(|| format_err!("no camera named {:?}", &s[..slash]))?;
+        let stream_id = camera.streams[type_.index()]
+            .ok_or_else(|| format_err!("camera {:?} has no {} stream", &s[..slash], type_))?;
+        let limit = parse_limit(limit).ok_or_else(|| format_err!("bad limit {:?}", limit))?;
+        limits.insert(stream_id, limit);
+    }
+    let mut out = json::RetentionSimulation {
+        streams: Vec::new(),
+    };
+    for (&id, s) in db.streams_by_id() {
+        if s.sample_file_dir_id.is_none() {
+            continue;
+        }
+        let usage = db.stream_usage(id, window.clone())?;
+        out.streams.extend(json::RetentionSimulationStream::new(
+            &db,
+            id,
+            &usage,
+            limits.get(&id).copied(),
+        ));
+    }
+    println!("{}", serde_json::to_string_pretty(&out)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_limit() {
+        assert_eq!(super::parse_limit("500G"), Some(Limit::Bytes(500 << 30)));
+        assert_eq!(super::parse_limit("30d"), Some(Limit::Days(30.)));
+        assert_eq!(super::parse_limit("1.5d"), Some(Limit::Days(1.5)));
+        assert_eq!(super::parse_limit("-1d"), None);
+        assert_eq!(super::parse_limit("d"), None);
+        assert_eq!(super::parse_limit("lots"), None);
+    }
+}
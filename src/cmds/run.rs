@@ -28,23 +28,39 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::stream;
+use crate::admission;
+use crate::archive;
+use crate::events;
+use crate::export;
+use crate::health;
+use crate::listen;
+use crate::logs;
+use crate::mqtt;
+use crate::onvif;
+use crate::pipelines;
+use crate::replication;
+use crate::rtmp;
+use crate::sched;
+use crate::sendfile;
 use crate::streamer;
+use crate::tls;
 use crate::web;
+use crate::webhook;
 use base::clock;
-use db::{dir, writer};
+use base::strutil::encode_size;
+use db::dir;
 use failure::{bail, Error};
 use fnv::FnvHashMap;
 use futures::future::FutureExt;
 use hyper::service::{make_service_fn, service_fn};
-use log::{info, warn};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use structopt::StructOpt;
 use tokio;
 use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
 
 #[derive(StructOpt)]
 pub struct Args {
@@ -58,18 +74,50 @@ pub struct Args {
     db_dir: PathBuf,
 
     /// Directory holding user interface files (.html, .js, etc).
-    #[structopt(
-        long,
-        default_value = "/usr/local/lib/moonfire-nvr/ui",
-        value_name = "path",
-        parse(from_os_str)
-    )]
-    ui_dir: std::path::PathBuf,
+    ///
+    /// Defaults to the files bundled into the binary if built with the bundled-ui feature, or
+    /// /usr/local/lib/moonfire-nvr/ui otherwise.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    ui_dir: Option<std::path::PathBuf>,
 
     /// Bind address for unencrypted HTTP server.
     #[structopt(long, default_value = "0.0.0.0:8080", parse(try_from_str))]
     http_addr: std::net::SocketAddr,
 
+    /// Path of a Unix domain socket on which to also serve HTTP, such as
+    /// /run/moonfire-nvr/http.sock.
+    ///
+    /// A stale socket at this path is replaced. Access is controlled by the permissions of the
+    /// socket's directory. If systemd passes sockets via socket activation, they're used instead
+    /// of this and --http-addr.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    http_unix_socket: Option<PathBuf>,
+
+    /// Grant requests over a Unix socket from processes running as this user id the
+    /// --unix-peer-permissions without other authentication. May be repeated.
+    ///
+    /// The user id is checked via the socket's peer credentials (SO_PEERCRED), so this suits
+    /// local tooling such as backup scripts.
+    #[structopt(long = "unix-peer-uid", value_name = "uid")]
+    unix_peer_uids: Vec<u32>,
+
+    /// As with --unix-peer-uid, but for processes running with this primary group id.
+    #[structopt(long = "unix-peer-gid", value_name = "gid")]
+    unix_peer_gids: Vec<u32>,
+
+    /// Permissions for requests allowed by --unix-peer-uid or --unix-peer-gid, as a text
+    /// Permissions protobuf such as "view_video: true".
+    #[structopt(long, parse(try_from_str = protobuf::text_format::parse_from_str))]
+    unix_peer_permissions: Option<db::Permissions>,
+
+    /// Bind address for the unencrypted gRPC control-plane API, if any.
+    ///
+    /// See proto/nvr.proto. Requests are authenticated with the same session cookies as the
+    /// HTTP API, sent as `cookie` metadata.
+    #[cfg(feature = "grpc")]
+    #[structopt(long, value_name = "addr", parse(try_from_str))]
+    grpc_addr: Option<std::net::SocketAddr>,
+
     /// Open the database in read-only mode and disables recording.
     ///
     /// Note this is incompatible with authentication, so you'll likely want to specify
@@ -77,6 +125,46 @@ pub struct Args {
     #[structopt(long)]
     read_only: bool,
 
+    /// Don't count playback and export requests for the playback heatmap.
+    ///
+    /// The counts are aggregated by stream and hour of recorded time and record nothing about
+    /// who made the requests.
+    #[structopt(long)]
+    no_playback_heat: bool,
+
+    /// Serve recordings by reading sample files in chunks of this many bytes, prefetching the
+    /// next chunk, rather than by memory-mapping them.
+    ///
+    /// This reduces page cache churn and page fault stalls during concurrent playback on
+    /// low-memory devices. 262144 (256 KiB) is a reasonable value.
+    #[structopt(long, value_name = "bytes")]
+    read_ahead_bytes: Option<usize>,
+
+    /// Send video data of `.mp4` and `.m4s` responses with sendfile(2) rather than copying it
+    /// through userspace, reducing CPU usage for large exports.
+    ///
+    /// The generated mp4 headers are still written normally. This has no effect on encrypted
+    /// sample files or when --read-ahead-bytes is set.
+    #[structopt(long)]
+    sendfile: bool,
+
+    /// Don't delay or shed timeline queries and exports when recording is under pressure.
+    ///
+    /// By default, while the average wait for the database lock exceeds --shed-lock-wait-ms or
+    /// a sample file directory's write queue is more than --shed-queue-fraction full, exports
+    /// are delayed and timeline queries may be too. At twice either threshold, exports are
+    /// refused with HTTP 503. Live viewing is never held back.
+    #[structopt(long)]
+    no_load_shedding: bool,
+
+    /// The average database lock wait at which to start holding back web requests.
+    #[structopt(long, value_name = "ms", default_value = "250")]
+    shed_lock_wait_ms: u64,
+
+    /// The fraction of a syncer's queue capacity at which to start holding back web requests.
+    #[structopt(long, value_name = "fraction", default_value = "0.5")]
+    shed_queue_fraction: f64,
+
     /// Allow unauthenticated access to the web interface, with the given permissions (may be
     /// empty). Should be a text Permissions protobuf such as "view_videos: true".
     ///
@@ -92,8 +180,222 @@ pub struct Args {
     /// --http-addr=127.0.0.1:8080.
     #[structopt(long)]
     trust_forward_hdrs: bool,
+
+    /// SameSite policy of session cookies set on password login: "strict" or "lax".
+    ///
+    /// "lax" lets links from other sites open the UI logged in, at the cost of sending the
+    /// cookie on top-level cross-site navigations. State-changing requests require a CSRF
+    /// token either way.
+    #[structopt(
+        long,
+        value_name = "policy",
+        default_value = "strict",
+        parse(try_from_str)
+    )]
+    cookie_same_site: web::SameSite,
+
+    /// Always mark session cookies Secure, rather than only when the request arrived with
+    /// X-Forwarded-Proto: https under --trust-forward-hdrs.
+    #[structopt(long)]
+    secure_cookies: bool,
+
+    /// An origin (such as "https://nvr.example.com") other than the server's own which may
+    /// make state-changing requests. May be repeated.
+    ///
+    /// By default, requests with an Origin header not matching the Host header are rejected.
+    #[structopt(long = "allowed-origin", value_name = "origin")]
+    allowed_origins: Vec<String>,
+
+    /// Scheduling priority ("nice" value, -20 to 19) of the ingest threads, which receive RTSP
+    /// streams and write recordings to disk.
+    ///
+    /// Lower values are higher priority. Negative values typically require CAP_SYS_NICE. This
+    /// and the other scheduling options are supported only on Linux.
+    #[structopt(long, value_name = "nice", allow_hyphen_values = true)]
+    ingest_nice: Option<i32>,
+
+    /// CPUs to which the ingest threads are pinned, such as "0-1,3".
+    #[structopt(long, value_name = "cpus")]
+    ingest_cpus: Option<sched::CpuList>,
+
+    /// Scheduling priority ("nice" value, -20 to 19) of the threads which serve HTTP requests.
+    #[structopt(long, value_name = "nice", allow_hyphen_values = true)]
+    serve_nice: Option<i32>,
+
+    /// CPUs to which the HTTP serving threads are pinned, such as "2-3".
+    #[structopt(long, value_name = "cpus")]
+    serve_cpus: Option<sched::CpuList>,
+
+    /// Scheduling priority ("nice" value, -20 to 19) of the threads and ffmpeg subprocesses which
+    /// generate thumbnails, export recordings, and transcode virtual, V4L2, and MJPEG streams.
+    #[structopt(long, value_name = "nice", allow_hyphen_values = true)]
+    analytics_nice: Option<i32>,
+
+    /// CPUs to which the thumbnail, export, and transcoding threads and subprocesses are pinned.
+    #[structopt(long, value_name = "cpus")]
+    analytics_cpus: Option<sched::CpuList>,
+
+    /// Soft limit on the number of recordings awaiting a database flush, across all streams.
+    ///
+    /// If the database can't keep up (such as when it's on a failing SD card), new runs of sub
+    /// streams are refused above this limit, and all new recordings above twice this limit.
+    #[structopt(long, value_name = "recordings")]
+    max_uncommitted_recordings: Option<usize>,
+
+    /// Soft limit on the total sample file bytes of recordings awaiting a database flush,
+    /// across all streams. Enforced as with --max-uncommitted-recordings.
+    #[structopt(long, value_name = "bytes")]
+    max_uncommitted_bytes: Option<i64>,
+
+    /// Flush the database as soon as this many recordings, across all streams, have been
+    /// written to disk but not yet to the database, regardless of each stream's flush_if_sec.
+    ///
+    /// This bounds how many recordings a crash or power loss can lose.
+    #[structopt(long, value_name = "recordings")]
+    max_unflushed_recordings: Option<usize>,
+
+    /// Keep each camera's sub stream recordings only as far back as its main stream's (with
+    /// 1), or a fixed multiple of the main stream's retained history (such as 2 for twice as
+    /// long). The sub stream's own retention limit still applies.
+    ///
+    /// Whenever the main stream's old recordings are deleted, the sub stream's are deleted in
+    /// lock-step, so that low-resolution context exists for all remaining high-resolution video.
+    #[structopt(long, value_name = "multiple")]
+    sub_retention_multiple: Option<f64>,
+
+    /// Failed password logins allowed per username before lockouts start.
+    ///
+    /// After this many failures, each further failure locks the username out of password login
+    /// for exponentially longer (1 second, 2, 4, ...) up to --login-max-lockout-sec. Failures are
+    /// forgotten a day after the last one or, per username, on successful login.
+    #[structopt(long, value_name = "n", default_value = "5")]
+    login_attempts_per_user: u32,
+
+    /// Failed password logins allowed per client address before lockouts start.
+    ///
+    /// Client addresses are only known with --trust-forward-hdrs.
+    #[structopt(long, value_name = "n", default_value = "20")]
+    login_attempts_per_addr: u32,
+
+    /// The longest single login lockout, in seconds. 0 disables login throttling.
+    #[structopt(long, value_name = "secs", default_value = "900")]
+    login_max_lockout_sec: i64,
+
+    /// Limit on the total size of recordings' video indexes kept in memory, so that timeline
+    /// scrubbing and repeated `.mp4` builds don't read them from the database each time.
+    #[structopt(long, value_name = "bytes", default_value = "8388608")]
+    video_index_cache_bytes: usize,
+
+    /// Width in pixels of the JPEG thumbnail saved with each recording, or 0 to save none.
+    ///
+    /// Thumbnails are made from each recording's first frame by running `ffmpeg` (which must be
+    /// on the `PATH`) about once a minute per stream.
+    #[structopt(long, value_name = "pixels", default_value = "320")]
+    thumbnail_width: u32,
+
+    /// Reserve disk space for each new sample file up front, reducing fragmentation of long
+    /// recordings on ext4 and XFS. The unused space is released when the recording ends.
+    ///
+    /// May be a size (such as 64M) or "previous" to reserve a little more than the size of the
+    /// stream's previous recording.
+    #[structopt(long, default_value = "off", value_name = "off|previous|bytes")]
+    preallocate: db::Preallocation,
+
+    /// Write sample files with O_DIRECT, bypassing the page cache.
+    ///
+    /// Recording then doesn't evict cached data used by playback and the database. Takes
+    /// precedence over io_uring writes. Falls back to buffered writes on filesystems which don't
+    /// support it.
+    #[structopt(long)]
+    direct_io: bool,
+
+    /// On running out of space, delete each stream's oldest recordings beyond its retention
+    /// limit, leaving it with no less than its limit minus this many bytes.
+    ///
+    /// By default, a stream whose sample file directory is full is paused until space is freed.
+    #[structopt(long, value_name = "bytes")]
+    emergency_delete_bytes: Option<i64>,
+
+    /// Continually re-read committed recordings in the background at up to this rate, checking
+    /// for corruption as with `moonfire-nvr check --scrub`.
+    ///
+    /// Each full pass is followed by a day's pause. By default, there's no background scrubbing.
+    #[structopt(long, value_name = "bytes")]
+    scrub_bytes_per_sec: Option<u64>,
+
+    /// Keep a grab-and-go copy of recent recordings in this directory, such as on a removable
+    /// disk, as standalone .mp4 files with an index.json. See src/archive.rs.
+    ///
+    /// The directory must already exist; use a subdirectory of the disk's mount point so that
+    /// nothing is written while the disk is unplugged.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    archive_dir: Option<PathBuf>,
+
+    /// The number of days of recordings to keep in --archive-dir.
+    #[structopt(long, value_name = "days", default_value = "7")]
+    archive_days: u32,
+
+    /// A stream to copy to --archive-dir, as "<camera short name>/<main|sub>". May be repeated.
+    /// Defaults to every camera's main stream.
+    #[structopt(long = "archive-stream", value_name = "camera/stream")]
+    archive_streams: Vec<String>,
+
+    /// Build clips requested via `POST /api/exports` in the background into this directory,
+    /// from which they can be downloaded. See src/export.rs.
+    ///
+    /// The directory must already exist and should hold nothing else. By default, exports can't
+    /// be requested.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    export_dir: Option<PathBuf>,
+
+    /// Also write logs to files in this directory, and retain recent lines for the
+    /// `/api/logs` endpoint.
+    ///
+    /// The current file is moonfire-nvr.log; older files are rotated aside and gzipped. Logs
+    /// are still written to standard error as well.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    log_dir: Option<PathBuf>,
+
+    /// Rotate the current log file when it exceeds this size.
+    #[structopt(long, value_name = "bytes", default_value = "10485760")]
+    log_max_bytes: u64,
+
+    /// Rotate the current log file after this long, even if it's below --log-max-bytes.
+    #[structopt(long, value_name = "secs", default_value = "86400")]
+    log_max_age_sec: i64,
+
+    /// The number of rotated log files to keep.
+    #[structopt(long, value_name = "files", default_value = "10")]
+    log_keep: usize,
+
+    /// On SIGHUP, replace the log filter with the contents of this file, in MOONFIRE_LOG syntax.
+    ///
+    /// If this is unset or the file is empty or missing, SIGHUP restores the filter the server
+    /// started with. The filter can also be changed via the /api/logs/filter endpoint.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    log_filter_file: Option<PathBuf>,
+
+    /// On SIGINT or SIGTERM, exit anyway if shutdown hasn't finished within this many seconds,
+    /// or 0 to wait indefinitely.
+    ///
+    /// Shutdown stops streamers, closes their recordings, waits for sample files to be synced,
+    /// and commits the database. A failing disk can stall the syncing; after the deadline, the
+    /// recordings not yet committed are lost, but the database stays consistent.
+    #[structopt(long, value_name = "secs", default_value = "60")]
+    shutdown_deadline_sec: u64,
+
+    /// Encrypt new sample files with a key derived from the master key in this file, which
+    /// should hold 64 hex digits, such as from `openssl rand -hex 32`.
+    ///
+    /// Keep the key file outside the database and sample file directories, and back it up:
+    /// recordings encrypted with a lost key can't be played. Existing encrypted recordings need
+    /// the same key to be played; unencrypted recordings are unaffected.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    sample_file_key: Option<PathBuf>,
 }
 
+const DEFAULT_UI_DIR: &'static str = "/usr/local/lib/moonfire-nvr/ui";
+
 // These are used in a hack to get the name of the current time zone (e.g. America/Los_Angeles).
 // They seem to be correct for Linux and macOS at least.
 const LOCALTIME_PATH: &'static str = "/etc/localtime";
@@ -177,16 +479,96 @@ fn resolve_zone() -> Result<String, Error> {
     }
 }
 
-struct Syncer {
-    dir: Arc<dir::SampleFileDir>,
-    channel: writer::SyncerChannel<::std::fs::File>,
-    join: thread::JoinHandle<()>,
+/// Tracks the progress of shutdown, forcing the process to exit if it misses its deadline.
+struct ShutdownDeadline {
+    phase: Arc<parking_lot::Mutex<&'static str>>,
+}
+
+impl ShutdownDeadline {
+    /// Starts the deadline, if any. `deadline_sec` of 0 means to wait indefinitely.
+    fn start(deadline_sec: u64) -> Self {
+        let phase = Arc::new(parking_lot::Mutex::new("starting shutdown"));
+        if deadline_sec > 0 {
+            let phase = phase.clone();
+            thread::Builder::new()
+                .name("shutdown-deadline".to_owned())
+                .spawn(move || {
+                    thread::sleep(std::time::Duration::from_secs(deadline_sec));
+                    error!(
+                        "Shutdown didn't finish within {} sec; exiting while {}.",
+                        deadline_sec,
+                        *phase.lock()
+                    );
+                    std::process::exit(1);
+                })
+                .expect("can't create thread");
+        }
+        ShutdownDeadline { phase }
+    }
+
+    /// Logs and records the current phase, to be reported if the deadline is missed.
+    fn enter(&self, phase: &'static str) {
+        info!("Shutdown: {}.", phase);
+        *self.phase.lock() = phase;
+    }
 }
 
-#[tokio::main]
-pub async fn run(args: &Args) -> Result<(), Error> {
+/// Handles SIGHUP by replacing the log filter with the contents of `--log-filter-file` or, if
+/// that's unset, empty, or missing, restoring the filter the server started with.
+fn reload_log_filter(path: Option<&Path>) {
+    let spec = match path.map(std::fs::read_to_string) {
+        None => None,
+        Some(Ok(s)) => Some(s.trim().to_owned()).filter(|s| !s.is_empty()),
+        Some(Err(ref e)) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Some(Err(e)) => {
+            warn!("Unable to read log filter file: {}", e);
+            return;
+        }
+    };
+    match logs::set_filter(spec.as_deref()) {
+        Ok(f) => info!("Log filter is now {:?}", f),
+        Err(e) => warn!("Unable to set log filter: {}", e),
+    }
+}
+
+pub fn run(args: &Args) -> Result<(), Error> {
+    let logs = match args.log_dir {
+        None => None,
+        Some(ref dir) => Some(logs::Capture::start(logs::Options {
+            dir: dir.clone(),
+            max_bytes: args.log_max_bytes,
+            max_age_sec: args.log_max_age_sec,
+            keep: args.log_keep,
+        })?),
+    };
+    let serve_sched = sched::Params {
+        nice: args.serve_nice,
+        cpus: args.serve_cpus.clone(),
+    };
+    let mut rt = tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .on_thread_start(move || serve_sched.apply_or_warn())
+        .build()?;
+    let result = rt.block_on(async_run(args, logs.as_ref().map(|l| l.recent().clone())));
+    drop(rt);
+    if let Some(l) = logs {
+        l.stop();
+    }
+    result
+}
+
+async fn async_run(args: &Args, logs: Option<Arc<logs::Recent>>) -> Result<(), Error> {
+    let ingest_sched = sched::Params {
+        nice: args.ingest_nice,
+        cpus: args.ingest_cpus.clone(),
+    };
+    let analytics_sched = sched::Params {
+        nice: args.analytics_nice,
+        cpus: args.analytics_cpus.clone(),
+    };
     let clocks = clock::RealClocks {};
-    let (_db_dir, conn) = super::open_conn(
+    let (db_dir, conn) = super::open_conn(
         &args.db_dir,
         if args.read_only {
             super::OpenMode::ReadOnly
@@ -194,7 +576,45 @@ pub async fn run(args: &Args) -> Result<(), Error> {
             super::OpenMode::ReadWrite
         },
     )?;
+    if !args.read_only {
+        db::upgrade::recover(&args.db_dir, &conn, false)?;
+    }
     let db = Arc::new(db::Database::new(clocks.clone(), conn, !args.read_only).unwrap());
+    db.lock().set_uncommitted_limits(db::UncommittedLimits {
+        max_recordings: args.max_uncommitted_recordings,
+        max_bytes: args.max_uncommitted_bytes,
+    });
+    db.lock()
+        .set_max_unflushed_recordings(args.max_unflushed_recordings);
+    if let Some(m) = args.sub_retention_multiple {
+        if !(m > 0.) {
+            bail!("--sub-retention-multiple must be positive");
+        }
+    }
+    db.lock()
+        .set_sub_retention_multiple(args.sub_retention_multiple);
+    db.lock()
+        .set_login_throttle(if args.login_max_lockout_sec > 0 {
+            Some(db::auth::LoginThrottle {
+                free_attempts_per_user: args.login_attempts_per_user,
+                free_attempts_per_addr: args.login_attempts_per_addr,
+                max_lockout_sec: args.login_max_lockout_sec,
+            })
+        } else {
+            None
+        });
+    db.lock().set_preallocation(args.preallocate.clone());
+    db.lock()
+        .set_video_index_cache_bytes(args.video_index_cache_bytes);
+    if let Some(max_bytes) = args.emergency_delete_bytes {
+        db.lock()
+            .set_disk_full_policy(db::DiskFullPolicy::EmergencyDelete { max_bytes });
+    }
+    if let Some(ref p) = args.sample_file_key {
+        let key = dir::SampleFileKey::read(p)?;
+        info!("Using sample file key {:?}", &key);
+        db.lock().set_sample_file_key(Some(Arc::new(key)));
+    }
     info!("Database is loaded.");
 
     {
@@ -202,148 +622,522 @@ pub async fn run(args: &Args) -> Result<(), Error> {
         let dirs_to_open: Vec<_> = l
             .streams_by_id()
             .values()
-            .filter_map(|s| s.sample_file_dir_id)
+            .flat_map(|s| s.dir_ids().into_iter().chain(s.failover_sample_file_dir_id))
             .collect();
         l.open_sample_file_dirs(&dirs_to_open)?;
+        if l.get_config("require_separate_mounts")?.as_deref() == Some("true") {
+            let mut dirs = Vec::with_capacity(dirs_to_open.len());
+            for id in &dirs_to_open {
+                let d = l.sample_file_dirs_by_id().get(id).unwrap();
+                dirs.push((d.path.as_str(), d.get()?));
+            }
+            let dirs: Vec<_> = dirs.iter().map(|(p, d)| (*p, &**d)).collect();
+            dir::check_separate_mounts(&db_dir, &dirs)?;
+        }
     }
     info!("Directories are opened.");
 
     let time_zone_name = resolve_zone()?;
     info!("Resolved timezone: {}", &time_zone_name);
-    let svc = Arc::new(web::Service::new(web::Config {
-        db: db.clone(),
-        ui_dir: Some(&args.ui_dir),
-        allow_unauthenticated_permissions: args.allow_unauthenticated_permissions.clone(),
-        trust_forward_hdrs: args.trust_forward_hdrs,
-        time_zone_name,
-    })?);
-
     // Start a streamer for each stream.
     let shutdown_streamers = Arc::new(AtomicBool::new(false));
+    let health = health::Monitor::new(&db.lock())?;
+    let events = events::Bus::default();
+    events.watch_db(&mut db.lock());
     let mut streamers = Vec::new();
-    let syncers = if !args.read_only {
-        let l = db.lock();
-        let mut dirs = FnvHashMap::with_capacity_and_hasher(
-            l.sample_file_dirs_by_id().len(),
-            Default::default(),
+    let mut reconciler = None;
+    let pipelines = if !args.read_only {
+        let p = pipelines::Pipelines::new(
+            db.clone(),
+            health.clone(),
+            events.clone(),
+            match args.thumbnail_width {
+                0 => None,
+                w => Some(w),
+            },
+            ingest_sched.clone(),
+            analytics_sched.clone(),
+            args.direct_io,
         );
-        let streams = l.streams_by_id().len();
-        let env = streamer::Environment {
-            db: &db,
-            opener: &*stream::FFMPEG,
-            shutdown: &shutdown_streamers,
-        };
+        let p = Arc::new(parking_lot::Mutex::new(p));
 
-        // Get the directories that need syncers.
-        for stream in l.streams_by_id().values() {
-            if let (Some(id), true) = (stream.sample_file_dir_id, stream.record) {
-                dirs.entry(id).or_insert_with(|| {
-                    let d = l.sample_file_dirs_by_id().get(&id).unwrap();
-                    info!("Starting syncer for path {}", d.path);
-                    d.get().unwrap()
-                });
-            }
-        }
-
-        // Then, with the lock dropped, create syncers.
-        drop(l);
-        let mut syncers = FnvHashMap::with_capacity_and_hasher(dirs.len(), Default::default());
-        for (id, dir) in dirs.drain() {
-            let (channel, join) = writer::start_syncer(db.clone(), id)?;
-            syncers.insert(id, Syncer { dir, channel, join });
-        }
+        // Start syncers for the directories of all recorded streams, then a streamer for each
+        // stream which needs its own.
+        let dir_ids: Vec<i32> = db
+            .lock()
+            .streams_by_id()
+            .values()
+            .filter(|s| s.record)
+            .flat_map(|s| s.dir_ids().into_iter().chain(s.failover_sample_file_dir_id))
+            .collect();
+        p.lock().start_syncers(&dir_ids)?;
+        pipelines::reconcile(&p);
 
-        // Then start up streams.
+        // Those pushed by RTMP are received by one shared server instead, and those replicated
+        // from another instance are pulled by one shared thread.
+        let pl = p.lock();
         let l = db.lock();
+        let streams = l.streams_by_id().len();
+        let mut rtmp_streams = Vec::new();
+        let mut replica_streams = Vec::new();
         for (i, (id, stream)) in l.streams_by_id().iter().enumerate() {
-            if !stream.record {
+            if !stream.record
+                || stream.sample_file_dir_id.is_none()
+                || !pipelines::is_shared(stream)
+            {
                 continue;
             }
             let camera = l.cameras_by_id().get(&stream.camera_id).unwrap();
-            let sample_file_dir_id = match stream.sample_file_dir_id {
-                Some(s) => s,
-                None => {
-                    warn!(
-                        "Can't record stream {} ({}/{}) because it has no sample file dir",
-                        id,
-                        camera.short_name,
-                        stream.type_.as_str()
-                    );
-                    continue;
-                }
-            };
             let rotate_offset_sec = streamer::ROTATE_INTERVAL_SEC * i as i64 / streams as i64;
-            let syncer = syncers.get(&sample_file_dir_id).unwrap();
-            let mut streamer = streamer::Streamer::new(
-                &env,
-                syncer.dir.clone(),
-                syncer.channel.clone(),
-                *id,
-                camera,
-                stream,
-                rotate_offset_sec,
-                streamer::ROTATE_INTERVAL_SEC,
-            )?;
-            info!("Starting streamer for {}", streamer.short_name());
-            let name = format!("s-{}", streamer.short_name());
-            streamers.push(
-                thread::Builder::new()
-                    .name(name)
-                    .spawn(move || {
-                        streamer.run();
-                    })
-                    .expect("can't create thread"),
-            );
+            let stripes = stream
+                .dir_ids()
+                .iter()
+                .map(|&id| pl.dir_and_syncer(id))
+                .collect();
+            let failover = stream
+                .failover_sample_file_dir_id
+                .map(|id| pl.dir_and_syncer(id));
+            if let Some(path) = rtmp::publish_path(&stream.rtsp_url) {
+                rtmp_streams.push(rtmp::IngestStream {
+                    stream_id: *id,
+                    short_name: format!("{}-{}", camera.short_name, stream.type_.as_str()),
+                    path,
+                    stripes,
+                    failover,
+                    rotate_offset_sec,
+                    session_timeout: std::time::Duration::from_secs(
+                        stream.rtsp.session_timeout_sec as u64,
+                    ),
+                });
+            } else if let Some(url) = replication::remote_url(&stream.rtsp_url) {
+                replica_streams.push(replication::ReplicaStream {
+                    stream_id: *id,
+                    short_name: format!("{}-{}", camera.short_name, stream.type_.as_str()),
+                    url,
+                    token: Some(camera.password.clone()).filter(|p| !p.is_empty()),
+                    stripes,
+                    failover,
+                });
+            }
         }
+        let rtmp_addr = rtmp::addr(&l)?;
         drop(l);
-        Some(syncers)
+        drop(pl);
+        if !rtmp_streams.is_empty() {
+            match rtmp_addr {
+                None => warn!(
+                    "{} stream(s) have rtmp:// URLs, but rtmp_addr isn't set; not receiving them",
+                    rtmp_streams.len()
+                ),
+                Some(addr) => {
+                    let server = rtmp::Server::new(
+                        db.clone(),
+                        &health,
+                        shutdown_streamers.clone(),
+                        rtmp_streams,
+                    );
+                    streamers.push(server.start(addr)?);
+                }
+            }
+        }
+        if !replica_streams.is_empty() {
+            let puller =
+                replication::Puller::new(db.clone(), shutdown_streamers.clone(), replica_streams)?;
+            streamers.push(puller.start()?);
+        }
+
+        // Apply later configuration changes as they're made.
+        reconciler = Some(pipelines::watch(p.clone(), &mut db.lock()));
+        Some(p)
     } else {
         None
     };
 
+    let syncer_queues = match pipelines {
+        None => FnvHashMap::default(),
+        Some(ref p) => p.lock().queue_monitors(),
+    };
+    let tls = match tls::Config::new(&db.lock())? {
+        None => None,
+        Some(c) => Some(c.start(&db_dir)?),
+    };
+    let unix_peer_auth = if args.unix_peer_uids.is_empty() && args.unix_peer_gids.is_empty() {
+        None
+    } else {
+        let permissions = match args.unix_peer_permissions.as_ref() {
+            Some(p) => p.clone(),
+            None => bail!("--unix-peer-uid and --unix-peer-gid require --unix-peer-permissions"),
+        };
+        Some(web::UnixPeerAuth {
+            uids: args.unix_peer_uids.clone(),
+            gids: args.unix_peer_gids.clone(),
+            permissions,
+        })
+    };
+    let svc = Arc::new(web::Service::new(web::Config {
+        db: db.clone(),
+        ui_dir: match args.ui_dir.as_ref() {
+            Some(d) => Some(d.as_path()),
+            None if cfg!(feature = "bundled-ui") => None,
+            None => Some(std::path::Path::new(DEFAULT_UI_DIR)),
+        },
+        allow_unauthenticated_permissions: args.allow_unauthenticated_permissions.clone(),
+        trust_forward_hdrs: args.trust_forward_hdrs,
+        cookie_same_site: args.cookie_same_site,
+        secure_cookies: args.secure_cookies,
+        allowed_origins: args.allowed_origins.clone(),
+        time_zone_name,
+        syncer_queues,
+        health: health.clone(),
+        events: events.clone(),
+        logs,
+        record_playback_heat: !args.no_playback_heat,
+        read_ahead_bytes: args.read_ahead_bytes,
+        load_thresholds: if args.no_load_shedding {
+            None
+        } else {
+            Some(admission::Thresholds {
+                lock_wait: std::time::Duration::from_millis(args.shed_lock_wait_ms),
+                queue_fraction: args.shed_queue_fraction,
+            })
+        },
+        unix_peer_auth,
+        export_dir: args.export_dir.clone(),
+        acme_challenges: tls
+            .as_ref()
+            .and_then(|t| t.acme.as_ref())
+            .map(|a| a.challenges()),
+    })?);
+
+    // Start background tasks: an ONVIF event subscriber for each camera with signals to drive,
+    // and the MQTT client and webhook dispatcher if configured.
+    let (shutdown_tasks_tx, shutdown_tasks_rx) = futures::channel::oneshot::channel::<()>();
+    let shutdown_tasks_rx = shutdown_tasks_rx.shared();
+    let mut tasks = Vec::new();
+    let mut webhook = None;
+    let mut scrubber = None;
+    let mut archiver = None;
+    let mut exporter = None;
+    let mut maintainer = None;
+    let mut acme_thread = None;
+    if !args.read_only {
+        let l = db.lock();
+        for camera in l.cameras_by_id().values() {
+            if let Some(s) = onvif::Subscriber::new(&db, &l, camera) {
+                info!("Starting ONVIF event subscriber for {}", s.short_name());
+                let shutdown = shutdown_tasks_rx.clone();
+                tasks.push(tokio::spawn(async move {
+                    futures::future::select(Box::pin(s.run()), shutdown).await;
+                }));
+            }
+        }
+        drop(l);
+        if let Some(c) = mqtt::Client::new(&db)? {
+            info!("Starting MQTT client for {}", c.broker());
+            let shutdown = shutdown_tasks_rx.clone();
+            tasks.push(tokio::spawn(async move {
+                futures::future::select(Box::pin(c.run()), shutdown).await;
+            }));
+        }
+        if let Some(d) = webhook::Dispatcher::new(&db, &health)? {
+            info!("Starting webhook dispatcher for {}", d.urls());
+            let (tx, rx) = std::sync::mpsc::channel();
+            let join = thread::Builder::new()
+                .name("webhook".to_owned())
+                .spawn(move || d.run(rx))
+                .expect("can't create thread");
+            webhook = Some((tx, join));
+        }
+        if let Some(bytes_per_sec) = args.scrub_bytes_per_sec.filter(|&b| b > 0) {
+            info!(
+                "Starting scrubber at {}/sec",
+                encode_size(bytes_per_sec as i64)
+            );
+            let s = db::scrub::Scrubber::new(db.clone(), bytes_per_sec);
+            let (tx, rx) = std::sync::mpsc::channel();
+            let ingest_sched = ingest_sched.clone();
+            let join = thread::Builder::new()
+                .name("scrub".to_owned())
+                .spawn(move || {
+                    ingest_sched.apply_or_warn();
+                    s.run(rx)
+                })
+                .expect("can't create thread");
+            scrubber = Some((tx, join));
+        }
+        if let Some(ref d) = args.archive_dir {
+            let a = archive::Archiver::new(
+                db.clone(),
+                d.clone(),
+                args.archive_days,
+                &args.archive_streams,
+            )?;
+            info!("Starting archiver for {}", a.dir().display());
+            let (tx, rx) = std::sync::mpsc::channel();
+            let join = thread::Builder::new()
+                .name("archive".to_owned())
+                .spawn(move || a.run(rx))
+                .expect("can't create thread");
+            archiver = Some((tx, join));
+        }
+        if let Some(ref d) = args.export_dir {
+            let e = export::Exporter::new(db.clone(), d.clone())?;
+            info!("Starting exporter for {}", e.dir().display());
+            let (tx, rx) = std::sync::mpsc::channel();
+            let analytics_sched = analytics_sched.clone();
+            let join = thread::Builder::new()
+                .name("export".to_owned())
+                .spawn(move || {
+                    analytics_sched.apply_or_warn();
+                    e.run(rx)
+                })
+                .expect("can't create thread");
+            exporter = Some((tx, join));
+        }
+        let m = db::maintenance::Maintainer::new(db.clone());
+        let (tx, rx) = std::sync::mpsc::channel();
+        let join = thread::Builder::new()
+            .name("maintenance".to_owned())
+            .spawn(move || m.run(rx))
+            .expect("can't create thread");
+        maintainer = Some((tx, join));
+    }
+
+    #[cfg(feature = "grpc")]
+    {
+        if let Some(addr) = args.grpc_addr {
+            let grpc = crate::grpc::Service::new(db.clone(), svc.clone());
+            let shutdown = shutdown_tasks_rx.clone();
+            info!("Starting gRPC server on {}", addr);
+            tasks.push(tokio::spawn(async move {
+                if let Err(e) = tonic::transport::Server::builder()
+                    .add_service(crate::grpc::NvrServer::new(grpc))
+                    .serve_with_shutdown(addr, shutdown.map(|_| ()))
+                    .await
+                {
+                    warn!("gRPC server failed: {}", e);
+                }
+            }));
+        }
+    }
+
     // Start the web interface.
-    let make_svc = make_service_fn(move |_conn| {
-        futures::future::ok::<_, std::convert::Infallible>(service_fn({
-            let svc = Arc::clone(&svc);
-            move |req| Arc::clone(&svc).serve(req)
-        }))
-    });
-    let server = ::hyper::server::Server::bind(&args.http_addr)
-        .tcp_nodelay(true)
-        .serve(make_svc);
+    if args.sendfile {
+        sendfile::enable();
+    }
+    // Each server is stopped gracefully by sending on its channel.
+    let mut servers = Vec::new();
+    if let Some(t) = tls {
+        if let Some(acme) = t.acme {
+            let (tx, rx) = std::sync::mpsc::channel();
+            let join = thread::Builder::new()
+                .name("acme".to_owned())
+                .spawn(move || acme.run(rx))
+                .expect("can't create thread");
+            acme_thread = Some((tx, join));
+        }
+        let svc = Arc::clone(&svc);
+        let make_svc = make_service_fn(
+            move |_conn: &tokio_rustls::server::TlsStream<tokio::net::TcpStream>| {
+                futures::future::ok::<_, std::convert::Infallible>(service_fn({
+                    let svc = Arc::clone(&svc);
+                    move |mut req: ::hyper::Request<::hyper::Body>| {
+                        req.extensions_mut().insert(web::ViaTls);
+                        Arc::clone(&svc).serve(req)
+                    }
+                }))
+            },
+        );
+        let listener = tokio::net::TcpListener::bind(t.addr).await?;
+        let (tx, rx) = futures::channel::oneshot::channel();
+        let server = ::hyper::server::Server::builder(tls::incoming(listener, t.acceptor))
+            .serve(make_svc)
+            .with_graceful_shutdown(rx.map(|_| ()));
+        info!("Serving HTTPS on {}", t.addr);
+        servers.push((tx, tokio::spawn(server)));
+    }
+
+    let mut listeners = listen::from_systemd()?;
+    if listeners.is_empty() {
+        listeners.push(listen::Listener::Tcp(
+            tokio::net::TcpListener::bind(args.http_addr).await?,
+        ));
+        if let Some(p) = args.http_unix_socket.as_ref() {
+            listeners.push(listen::Listener::Unix(listen::bind_unix(p)?));
+        }
+    } else {
+        info!(
+            "Using {} socket(s) from systemd rather than --http-addr or --http-unix-socket",
+            listeners.len()
+        );
+    }
+    for l in listeners {
+        let svc = Arc::clone(&svc);
+        let (tx, rx) = futures::channel::oneshot::channel();
+        let server = match l {
+            listen::Listener::Tcp(l) => {
+                info!("Serving HTTP on {}", l.local_addr()?);
+                let make_svc = make_service_fn(move |_conn: &sendfile::TcpStream| {
+                    futures::future::ok::<_, std::convert::Infallible>(service_fn({
+                        let svc = Arc::clone(&svc);
+                        move |req| Arc::clone(&svc).serve(req)
+                    }))
+                });
+                tokio::spawn(
+                    ::hyper::server::Server::builder(sendfile::incoming(l))
+                        // Queue body chunks rather than flattening them into hyper's buffer, so
+                        // that sendfile can recognize mapped sample data.
+                        .http1_writev(true)
+                        .serve(make_svc)
+                        .with_graceful_shutdown(rx.map(|_| ())),
+                )
+            }
+            listen::Listener::Unix(l) => {
+                match l.local_addr()?.as_pathname() {
+                    Some(p) => info!("Serving HTTP on Unix socket {}", p.display()),
+                    None => info!("Serving HTTP on unnamed Unix socket"),
+                }
+                let make_svc = make_service_fn(move |conn: &tokio::net::UnixStream| {
+                    let peer = match conn.peer_cred() {
+                        Ok(c) => Some(web::UnixPeer {
+                            uid: c.uid,
+                            gid: c.gid,
+                        }),
+                        Err(e) => {
+                            warn!("Unable to get Unix socket peer credentials: {}", e);
+                            None
+                        }
+                    };
+                    futures::future::ok::<_, std::convert::Infallible>(service_fn({
+                        let svc = Arc::clone(&svc);
+                        move |mut req: ::hyper::Request<::hyper::Body>| {
+                            if let Some(p) = peer {
+                                req.extensions_mut().insert(p);
+                            }
+                            Arc::clone(&svc).serve(req)
+                        }
+                    }))
+                });
+                tokio::spawn(
+                    ::hyper::server::Server::builder(listen::unix_incoming(l))
+                        .serve(make_svc)
+                        .with_graceful_shutdown(rx.map(|_| ())),
+                )
+            }
+        };
+        servers.push((tx, server));
+    }
+    drop(svc);
+
+    // SIGHUP reloads the log filter; see --log-filter-file.
+    let mut hup = signal(SignalKind::hangup())?;
+    let log_filter_file = args.log_filter_file.clone();
+    let shutdown = shutdown_tasks_rx.clone();
+    tasks.push(tokio::spawn(async move {
+        let reload = async move {
+            while let Some(()) = hup.recv().await {
+                reload_log_filter(log_filter_file.as_deref());
+            }
+        };
+        futures::future::select(Box::pin(reload), shutdown).await;
+    }));
+
+    // SIGUSR1 protects recent recordings, as POST /api/protect does; see web::protect_recent.
+    if !args.read_only {
+        let mut usr1 = signal(SignalKind::user_defined1())?;
+        let db = db.clone();
+        let shutdown = shutdown_tasks_rx.clone();
+        tasks.push(tokio::spawn(async move {
+            let protect = async move {
+                while let Some(()) = usr1.recv().await {
+                    if let Err(e) = web::protect_recent(&db) {
+                        warn!("unable to protect recordings on SIGUSR1: {}", e);
+                    }
+                }
+            };
+            futures::future::select(Box::pin(protect), shutdown).await;
+        }));
+    }
 
     let mut int = signal(SignalKind::interrupt())?;
     let mut term = signal(SignalKind::terminate())?;
     let shutdown = futures::future::select(Box::pin(int.recv()), Box::pin(term.recv()));
 
-    let (shutdown_tx, shutdown_rx) = futures::channel::oneshot::channel();
-    let server = server.with_graceful_shutdown(shutdown_rx.map(|_| ()));
-    let server_handle = tokio::spawn(server);
-
     info!("Ready to serve HTTP requests");
     shutdown.await;
-    shutdown_tx.send(()).unwrap();
+    let deadline = ShutdownDeadline::start(args.shutdown_deadline_sec);
 
-    info!("Shutting down streamers.");
+    // Stop accepting frames first; the streamers finish their recordings while the rest of the
+    // server shuts down.
     shutdown_streamers.store(true, Ordering::SeqCst);
-    for streamer in streamers.drain(..) {
+    let pipeline_streamers = match pipelines {
+        None => Vec::new(),
+        Some(ref p) => p.lock().close(),
+    };
+    let server_handles: Vec<_> = servers
+        .into_iter()
+        .map(|(tx, handle)| {
+            tx.send(()).unwrap();
+            handle
+        })
+        .collect();
+
+    deadline.enter("shutting down background tasks");
+    drop(shutdown_tasks_tx);
+    for s in tasks.drain(..) {
+        s.await?;
+    }
+    if let Some((tx, join)) = webhook {
+        drop(tx);
+        join.join().unwrap();
+    }
+    if let Some((tx, join)) = scrubber {
+        drop(tx);
+        join.join().unwrap();
+    }
+    if let Some((tx, join)) = archiver {
+        drop(tx);
+        join.join().unwrap();
+    }
+    if let Some((tx, join)) = exporter {
+        drop(tx);
+        join.join().unwrap();
+    }
+    if let Some((tx, join)) = maintainer {
+        drop(tx);
+        join.join().unwrap();
+    }
+    if let Some((tx, join)) = acme_thread {
+        drop(tx);
+        join.join().unwrap();
+    }
+
+    deadline.enter("waiting for streamers to close their recordings");
+    for streamer in streamers.drain(..).chain(pipeline_streamers) {
         streamer.join().unwrap();
     }
 
-    if let Some(mut ss) = syncers {
-        // The syncers shut down when all channels to them have been dropped.
-        // The database maintains one; and `ss` holds one. Drop both.
-        db.lock().clear_on_flush();
-        for (_, s) in ss.drain() {
-            drop(s.channel);
-            s.join.join().unwrap();
+    if let Some(ref p) = pipelines {
+        deadline.enter("waiting for sample files to be synced");
+        pipelines::stop_syncers(p);
+
+        // Commit everything the syncers saved in one transaction.
+        deadline.enter("committing the database");
+        if let Err(e) = db.lock().flush("shutdown") {
+            error!("Unable to commit the database at shutdown: {}", e);
         }
     }
 
     db.lock().clear_watches();
+    events.close();
+    if let Some(r) = reconciler {
+        r.join().unwrap();
+    }
 
-    info!("Waiting for HTTP requests to finish.");
-    server_handle.await??;
+    deadline.enter("waiting for HTTP requests to finish");
+    for h in server_handles {
+        h.await??;
+    }
     info!("Exiting.");
     Ok(())
 }
@@ -0,0 +1,98 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2016-2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Subcommand to map sample file names to the recordings they hold.
+
+use crate::json;
+use base::clock;
+use failure::{bail, Error};
+use std::path::PathBuf;
+use structopt::StructOpt;
+use tracing::warn;
+
+#[derive(StructOpt)]
+pub struct Args {
+    /// Directory holding the SQLite3 index database.
+    #[structopt(
+        long,
+        default_value = "/var/lib/moonfire-nvr/db",
+        value_name = "path",
+        parse(from_os_str)
+    )]
+    db_dir: PathBuf,
+
+    /// Sample file names (or paths) to describe, such as `0000000100000002`.
+    ///
+    /// If none are given, describes every committed recording's sample file.
+    names: Vec<String>,
+}
+
+pub fn run(args: &Args) -> Result<(), Error> {
+    let (_db_dir, conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadOnly)?;
+    let db = db::Database::new(clock::RealClocks {}, conn, false)?;
+    let db = db.lock();
+    let mut ranges = Vec::with_capacity(args.names.len());
+    for n in &args.names {
+        let base = n.rsplit('/').next().unwrap_or(n);
+        match db::dir::parse_id(base.as_bytes()) {
+            Ok(id) => ranges.push((n, id..db::CompositeId(id.0 + 1))),
+            Err(()) => bail!("bad sample file name {:?}", n),
+        }
+    }
+    let mut unknown = 0;
+    let mut print = |row: db::SampleFileRow| -> Result<(), Error> {
+        match json::SampleFile::new(&db, &row) {
+            Some(f) => println!("{}", serde_json::to_string(&f)?),
+            None => warn!("recording {} belongs to a missing stream", row.id),
+        }
+        Ok(())
+    };
+    if ranges.is_empty() {
+        db.list_sample_files(
+            db::CompositeId(0)..db::CompositeId(i64::max_value()),
+            &mut print,
+        )?;
+    }
+    for (n, r) in ranges {
+        let mut found = false;
+        db.list_sample_files(r, &mut |row| {
+            found = true;
+            print(row)
+        })?;
+        if !found {
+            warn!("{:?} is not a committed recording", n);
+            unknown += 1;
+        }
+    }
+    if unknown > 0 {
+        bail!("{} of {} sample files not found", unknown, args.names.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,176 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Subcommand to write a support bundle for attaching to bug reports; see `diagnostics.rs`.
+
+use crate::diagnostics;
+use base::clock::{self, Clocks};
+use db::recording;
+use failure::{bail, format_err, Error};
+use std::path::PathBuf;
+use structopt::StructOpt;
+use tracing::{info, warn};
+
+#[derive(StructOpt)]
+pub struct Args {
+    /// Directory holding the SQLite3 index database.
+    #[structopt(
+        long,
+        default_value = "/var/lib/moonfire-nvr/db",
+        value_name = "path",
+        parse(from_os_str)
+    )]
+    db_dir: PathBuf,
+
+    /// Path to write the bundle (a .tar.gz file) to. It must not already exist.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    out: PathBuf,
+
+    /// The server's --log-dir, from which to include the end of the current log file.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    log_dir: Option<PathBuf>,
+
+    /// The most log bytes to include.
+    #[structopt(long, value_name = "bytes", default_value = "1048576")]
+    log_bytes: u64,
+
+    /// The running server's base URL, such as "http://127.0.0.1:8080", from which to include
+    /// the `/api/health/ready` report.
+    #[structopt(long, value_name = "url")]
+    server_url: Option<String>,
+
+    /// A stream, such as "driveway/main", from which to include the most recent video as a raw
+    /// H.264 file.
+    ///
+    /// Recorded video may show people and places; review it before sharing.
+    #[structopt(long, value_name = "camera/stream")]
+    media_stream: Option<String>,
+
+    /// How much of --media-stream's video to include.
+    #[structopt(long, value_name = "minutes", default_value = "1")]
+    media_minutes: i64,
+}
+
+pub fn run(args: &Args) -> Result<(), Error> {
+    // Like `backup`, this opens the database read-only without locking the directory so that it
+    // can run alongside the server, which is when bug reports are most useful.
+    let db_path = args.db_dir.join("db");
+    if !db_path.exists() {
+        bail!("no database in {}", args.db_dir.display());
+    }
+    if args.out.exists() {
+        bail!("{} already exists", args.out.display());
+    }
+    let conn = rusqlite::Connection::open_with_flags(
+        &db_path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    let schema_version = db::get_schema_version(&conn)?;
+    let clocks = clock::RealClocks {};
+    let now = clocks.realtime();
+    let f = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&args.out)?;
+    let mut bundle = diagnostics::Bundle::new(f, now.sec);
+
+    if schema_version == Some(db::EXPECTED_VERSION) {
+        let db = db::Database::new(clocks, conn, false)?;
+        let l = db.lock();
+        bundle.add_json("config.json", &diagnostics::config(&l, schema_version)?)?;
+        if let Some(ref s) = args.media_stream {
+            let stream_id = find_stream(&l, s)?;
+            let end = match l.streams_by_id().get(&stream_id).unwrap().range {
+                None => bail!("stream {} has no recordings", s),
+                Some(ref r) => r.end,
+            };
+            let start =
+                end - recording::Duration(args.media_minutes * 60 * recording::TIME_UNITS_PER_SEC);
+            let media = diagnostics::media_sample(&l, stream_id, start..end)?;
+            info!("Including {} bytes of video from {}", media.len(), s);
+            bundle.add("media.h264", &media)?;
+        }
+    } else {
+        // The in-memory database requires the expected schema; still report the version, which
+        // may well be the problem.
+        warn!(
+            "database schema version {:?} isn't the expected {}; omitting configuration",
+            schema_version,
+            db::EXPECTED_VERSION
+        );
+        bundle.add(
+            "schema-version.txt",
+            format!("{:?}\n", schema_version).as_bytes(),
+        )?;
+    }
+
+    if let Some(ref dir) = args.log_dir {
+        bundle.add(
+            crate::logs::CURRENT_NAME,
+            &diagnostics::log_tail(dir, args.log_bytes)?,
+        )?;
+    }
+
+    if let Some(ref url) = args.server_url {
+        let url = format!("{}/api/health/ready", url.trim_end_matches('/'));
+        let health = reqwest::blocking::Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()?
+            .get(&url)
+            .send()
+            .and_then(|r| r.text());
+        match health {
+            Ok(h) => bundle.add("health.json", h.as_bytes())?,
+            Err(e) => {
+                warn!("unable to fetch {}: {}", url, e);
+                bundle.add("health-error.txt", format!("{}: {}\n", url, e).as_bytes())?;
+            }
+        }
+    }
+
+    bundle.finish()?.sync_all()?;
+    info!("Wrote support bundle to {}", args.out.display());
+    Ok(())
+}
+
+/// Finds a stream by camera short name and stream type, as in `driveway/main`.
+fn find_stream(db: &db::LockedDatabase, name: &str) -> Result<i32, Error> {
+    let slash = name
+        .rfind('/')
+        .ok_or_else(|| format_err!("stream {:?} should be camera/type", name))?;
+    let type_ = db::StreamType::parse(&name[slash + 1..])
+        .ok_or_else(|| format_err!("unknown stream type in {:?}", name))?;
+    let camera = db
+        .cameras_by_id()
+        .values()
+        .find(|c| c.short_name == name[..slash])
+        .ok_or_else(|| format_err!("no camera {:?}", &name[..slash]))?;
+    camera.streams[type_.index()].ok_or_else(|| format_err!("camera has no {} stream", type_))
+}
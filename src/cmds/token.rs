@@ -0,0 +1,138 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Subcommand to manage long-lived API tokens.
+
+use base::clock::{self, Clocks};
+use db::recording::{self, TIME_UNITS_PER_SEC};
+use failure::{format_err, Error};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct Args {
+    /// Directory holding the SQLite3 index database.
+    #[structopt(
+        long,
+        default_value = "/var/lib/moonfire-nvr/db",
+        value_name = "path",
+        parse(from_os_str)
+    )]
+    db_dir: PathBuf,
+
+    #[structopt(subcommand)]
+    action: Action,
+}
+
+#[derive(Debug, StructOpt)]
+enum Action {
+    /// Creates a token, printing it once. It's sent as `Authorization: Bearer <token>`.
+    Create {
+        /// Create the token with the given permissions.
+        ///
+        /// If unspecified, uses user's default permissions.
+        #[structopt(long, value_name="perms",
+                    parse(try_from_str = protobuf::text_format::parse_from_str))]
+        permissions: Option<db::Permissions>,
+
+        /// Describe the token's purpose, such as "Home Assistant".
+        #[structopt(long)]
+        description: Option<String>,
+
+        /// Create the token for this username.
+        username: String,
+    },
+
+    /// Lists tokens, including revoked ones.
+    List,
+
+    /// Revokes the token with the given id, as shown by `list`.
+    Revoke { id: i32 },
+}
+
+fn format_sec(sec: i64) -> String {
+    recording::Time(sec * TIME_UNITS_PER_SEC).to_string()
+}
+
+pub fn run(args: &Args) -> Result<(), Error> {
+    let clocks = clock::RealClocks {};
+    let (_db_dir, conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadWrite)?;
+    let db = db::Database::new(clocks.clone(), conn, true)?;
+    let mut l = db.lock();
+    match args.action {
+        Action::Create {
+            ref permissions,
+            ref description,
+            ref username,
+        } => {
+            let u = l
+                .get_user(username)
+                .ok_or_else(|| format_err!("no such user {:?}", username))?;
+            let permissions = permissions.as_ref().unwrap_or(&u.permissions).clone();
+            let uid = u.id;
+            let (raw, t) =
+                l.create_token(uid, description.clone(), permissions, clocks.realtime().sec)?;
+            let mut encoded = [0u8; 64];
+            base64::encode_config_slice(&raw, base64::STANDARD_NO_PAD, &mut encoded);
+            eprintln!("Created token {}. It won't be shown again.", t.id);
+            println!(
+                "{}",
+                std::str::from_utf8(&encoded[..]).expect("base64 is valid UTF-8")
+            );
+        }
+        Action::List => {
+            for (id, t) in l.tokens_by_id() {
+                let username = l
+                    .users_by_id()
+                    .get(&t.user_id)
+                    .map(|u| u.username.as_str())
+                    .unwrap_or("?");
+                println!(
+                    "{}\t{}\t{}\tcreated {}\tlast used {}\t{}",
+                    id,
+                    username,
+                    t.description.as_ref().map(String::as_str).unwrap_or(""),
+                    format_sec(t.creation_time_sec),
+                    t.last_use_time_sec
+                        .map(format_sec)
+                        .unwrap_or_else(|| "never".to_owned()),
+                    t.revocation_time_sec
+                        .map(|s| format!("revoked {}", format_sec(s)))
+                        .unwrap_or_else(|| "active".to_owned()),
+                );
+            }
+        }
+        Action::Revoke { id } => {
+            l.revoke_token(id, clocks.realtime().sec)?;
+            eprintln!("Revoked token {}.", id);
+        }
+    }
+    Ok(())
+}
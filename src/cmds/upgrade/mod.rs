@@ -31,8 +31,9 @@
 /// Upgrades the database schema.
 ///
 /// See `guide/schema.md` for more information.
-use failure::Error;
+use failure::{bail, Error};
 use structopt::StructOpt;
+use tracing::{info, warn};
 
 #[derive(StructOpt)]
 pub struct Args {
@@ -64,20 +65,51 @@ pub struct Args {
 
     #[structopt(help = "Skips the normal post-upgrade vacuum operation.", long)]
     no_vacuum: bool,
+
+    #[structopt(
+        help = "Finishes an upgrade which was interrupted after changing sample files, by \
+                        redoing the changes recorded in its journal then retrying the step.",
+        long
+    )]
+    resume: bool,
+
+    #[structopt(
+        help = "Upgrades even if the estimated free space needed for the upgrade isn't \
+                        available.",
+        long
+    )]
+    force: bool,
 }
 
 pub fn run(args: &Args) -> Result<(), Error> {
     let (_db_dir, mut conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadWrite)?;
+    db::upgrade::recover(&args.db_dir, &conn, args.resume)?;
+
+    let upgrade_args = db::upgrade::Args {
+        db_dir: &args.db_dir,
+        sample_file_dir: args
+            .sample_file_dir
+            .as_ref()
+            .map(std::path::PathBuf::as_path),
+        preset_journal: &args.preset_journal,
+        no_vacuum: args.no_vacuum,
+    };
+    if let Some(e) = db::upgrade::estimate::estimate(&upgrade_args, &conn, db::EXPECTED_VERSION)? {
+        info!("{}", e);
+        if !e.sufficient() {
+            if !args.force {
+                bail!(
+                    "Not enough free space in {} to upgrade safely: need {} but only {} is \
+                     available. Free some space, or see guide/schema.md for options. Pass \
+                     --force to upgrade anyway.",
+                    args.db_dir.display(),
+                    base::strutil::encode_size(e.bytes_needed as i64),
+                    base::strutil::encode_size(e.bytes_free as i64)
+                );
+            }
+            warn!("Upgrading despite insufficient free space because of --force.");
+        }
+    }
 
-    db::upgrade::run(
-        &db::upgrade::Args {
-            sample_file_dir: args
-                .sample_file_dir
-                .as_ref()
-                .map(std::path::PathBuf::as_path),
-            preset_journal: &args.preset_journal,
-            no_vacuum: args.no_vacuum,
-        },
-        &mut conn,
-    )
+    db::upgrade::run(&upgrade_args, &mut conn)
 }
@@ -0,0 +1,330 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Server-sent events of state changes, served at `/api/events`.
+//!
+//! Events come from two sources: the database (see `db::LockedDatabase::watch_events`) reports
+//! committed recordings, signal changes, and disk warnings; each streamer reports its stream
+//! going online and offline. Each event is serialized once and broadcast to every subscriber;
+//! `web.rs` filters them by the caller's camera access.
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tracing::warn;
+use uuid::Uuid;
+
+/// The number of events buffered for each subscriber. A subscriber which falls further behind
+/// skips ahead and is told how many events it missed.
+const CAPACITY: usize = 256;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Event {
+    #[serde(rename_all = "camelCase")]
+    CameraOnline { camera: Uuid, stream: &'static str },
+
+    #[serde(rename_all = "camelCase")]
+    CameraOffline { camera: Uuid, stream: &'static str },
+
+    #[serde(rename_all = "camelCase")]
+    RecordingCommitted {
+        camera: Uuid,
+        stream: &'static str,
+        id: i32,
+        start_time_90k: i64,
+        end_time_90k: i64,
+        sample_file_bytes: i32,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    SignalChanged {
+        signal: u32,
+        short_name: String,
+        state: u16,
+        start_time_90k: i64,
+        end_time_90k: i64,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    DiskFull {
+        camera: Uuid,
+        stream: &'static str,
+        full: bool,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    DirUnhealthy { path: String, unhealthy: bool },
+
+    #[serde(rename_all = "camelCase")]
+    StreamerFailed {
+        camera: Uuid,
+        stream: &'static str,
+        message: String,
+        restart_in_sec: i64,
+    },
+}
+
+impl Event {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Event::CameraOnline { .. } => "cameraOnline",
+            Event::CameraOffline { .. } => "cameraOffline",
+            Event::RecordingCommitted { .. } => "recordingCommitted",
+            Event::SignalChanged { .. } => "signalChanged",
+            Event::DiskFull { .. } => "diskFull",
+            Event::DirUnhealthy { .. } => "dirUnhealthy",
+            Event::StreamerFailed { .. } => "streamerFailed",
+        }
+    }
+}
+
+/// A serialized event, as sent to subscribers.
+#[derive(Debug)]
+pub struct Message {
+    /// The camera this event concerns, or `None` if it's visible to all callers.
+    pub camera_id: Option<i32>,
+
+    pub type_name: &'static str,
+    pub json: String,
+}
+
+/// The broadcast channel of events. Cheap to clone; clones share the channel.
+#[derive(Clone)]
+pub struct Bus(Arc<Mutex<Option<broadcast::Sender<Arc<Message>>>>>);
+
+impl Default for Bus {
+    fn default() -> Self {
+        Bus(Arc::new(Mutex::new(Some(broadcast::channel(CAPACITY).0))))
+    }
+}
+
+impl Bus {
+    /// Sends an event to all current subscribers, if any.
+    pub fn send(&self, camera_id: Option<i32>, e: Event) {
+        let json = match serde_json::to_string(&e) {
+            Ok(j) => j,
+            Err(err) => {
+                warn!("Unable to serialize event {:?}: {}", &e, err);
+                return;
+            }
+        };
+        if let Some(ref s) = *self.0.lock() {
+            // An error means there are no subscribers, which is fine.
+            let _ = s.send(Arc::new(Message {
+                camera_id,
+                type_name: e.type_name(),
+                json,
+            }));
+        }
+    }
+
+    /// Subscribes to all events sent from now on. After `close`, the receiver is immediately
+    /// closed.
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Message>> {
+        match *self.0.lock() {
+            Some(ref s) => s.subscribe(),
+            None => broadcast::channel(1).1,
+        }
+    }
+
+    /// Closes the channel, ending all subscriptions. Used on shutdown, so that `/api/events`
+    /// responses finish.
+    pub fn close(&self) {
+        self.0.lock().take();
+    }
+
+    /// Notes that the given stream has started or stopped receiving video from its camera.
+    pub fn stream_status(&self, db: &db::LockedDatabase, stream_id: i32, online: bool) {
+        let (camera_id, camera, stream) = match stream_names(db, stream_id) {
+            None => return,
+            Some(n) => n,
+        };
+        self.send(
+            Some(camera_id),
+            if online {
+                Event::CameraOnline { camera, stream }
+            } else {
+                Event::CameraOffline { camera, stream }
+            },
+        );
+    }
+
+    /// Notes that the given stream's streamer panicked or failed to start and will be restarted;
+    /// see `crate::supervisor`.
+    pub fn streamer_failed(
+        &self,
+        db: &db::LockedDatabase,
+        stream_id: i32,
+        message: String,
+        restart_in_sec: i64,
+    ) {
+        if let Some((camera_id, camera, stream)) = stream_names(db, stream_id) {
+            self.send(
+                Some(camera_id),
+                Event::StreamerFailed {
+                    camera,
+                    stream,
+                    message,
+                    restart_in_sec,
+                },
+            );
+        }
+    }
+
+    /// Relays the database's events to this bus.
+    pub fn watch_db(&self, db: &mut db::LockedDatabase) {
+        let bus = self.clone();
+        db.watch_events(Box::new(move |db, e| {
+            bus.send_db_event(db, e);
+            true
+        }));
+    }
+
+    fn send_db_event(&self, db: &db::LockedDatabase, e: &db::Event) {
+        match *e {
+            db::Event::RecordingCommitted {
+                id,
+                ref time,
+                sample_file_bytes,
+            } => {
+                if let Some((camera_id, camera, stream)) = stream_names(db, id.stream()) {
+                    self.send(
+                        Some(camera_id),
+                        Event::RecordingCommitted {
+                            camera,
+                            stream,
+                            id: id.recording(),
+                            start_time_90k: time.start.0,
+                            end_time_90k: time.end.0,
+                            sample_file_bytes,
+                        },
+                    );
+                }
+            }
+            db::Event::SignalsChanged {
+                ref time,
+                ref signals,
+                ref states,
+            } => {
+                for (&signal, &state) in signals.iter().zip(states) {
+                    let short_name = match db.signals_by_id().get(&signal) {
+                        None => continue,
+                        Some(s) => s.short_name.clone(),
+                    };
+                    self.send(
+                        None,
+                        Event::SignalChanged {
+                            signal,
+                            short_name,
+                            state,
+                            start_time_90k: time.start.0,
+                            end_time_90k: time.end.0,
+                        },
+                    );
+                }
+            }
+            db::Event::DiskFull { stream_id, full } => {
+                if let Some((camera_id, camera, stream)) = stream_names(db, stream_id) {
+                    self.send(
+                        Some(camera_id),
+                        Event::DiskFull {
+                            camera,
+                            stream,
+                            full,
+                        },
+                    );
+                }
+            }
+            db::Event::DirUnhealthy { dir_id, unhealthy } => {
+                if let Some(d) = db.sample_file_dirs_by_id().get(&dir_id) {
+                    self.send(
+                        None,
+                        Event::DirUnhealthy {
+                            path: d.path.clone(),
+                            unhealthy,
+                        },
+                    );
+                }
+            }
+            db::Event::StreamsChanged => {} // handled by pipelines::Pipelines.
+        }
+    }
+}
+
+/// Returns the camera id, camera uuid, and stream type name of the given stream.
+fn stream_names(db: &db::LockedDatabase, stream_id: i32) -> Option<(i32, Uuid, &'static str)> {
+    let s = db.streams_by_id().get(&stream_id)?;
+    let c = db.cameras_by_id().get(&s.camera_id)?;
+    Some((c.id, c.uuid, s.type_.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::testutil;
+
+    #[test]
+    fn db_events() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(base::clock::RealClocks {});
+        let bus = Bus::default();
+        let mut rx = bus.subscribe();
+        bus.watch_db(&mut tdb.db.lock());
+        tdb.db
+            .lock()
+            .set_disk_full(testutil::TEST_STREAM_ID, true)
+            .unwrap();
+
+        // Setting the same value again is not an event.
+        tdb.db
+            .lock()
+            .set_disk_full(testutil::TEST_STREAM_ID, true)
+            .unwrap();
+        bus.stream_status(&tdb.db.lock(), testutil::TEST_STREAM_ID, true);
+        let m = rx.try_recv().unwrap();
+        assert_eq!(m.type_name, "diskFull");
+        assert_eq!(m.camera_id, Some(testutil::TEST_CAMERA_ID));
+        let j: serde_json::Value = serde_json::from_str(&m.json).unwrap();
+        assert_eq!(j["stream"], "main");
+        assert_eq!(j["full"], true);
+        let m = rx.try_recv().unwrap();
+        assert_eq!(m.type_name, "cameraOnline");
+        assert!(rx.try_recv().is_err());
+
+        bus.close();
+        assert!(match rx.try_recv() {
+            Err(broadcast::TryRecvError::Closed) => true,
+            _ => false,
+        });
+        assert!(bus.subscribe().try_recv().is_err());
+    }
+}
@@ -0,0 +1,144 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Signatures of export manifests, so that footage handed over as evidence can later be shown to
+//! be unaltered.
+//!
+//! The server holds a single Ed25519 key, created on first use and kept in the `config` table as
+//! `export_signing_key`. A signed export's `.zip` holds `manifest.json` (`json::ExportManifest`),
+//! which records who requested the export and when, the recordings it covers, and the SHA-256 of
+//! each file, and `manifest.json.sig` (`json::ExportSignature`), a signature over the exact bytes
+//! of `manifest.json`. Anyone with the public key can check the signature offline; `POST
+//! /api/exports/verify` also reports whether it was made by this server's key.
+
+use crate::json;
+use failure::{format_err, Error};
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use tracing::info;
+
+/// The `algorithm` of `json::ExportSignature`.
+pub const ALGORITHM: &str = "Ed25519";
+
+/// The key in the `config` table which holds the base64-encoded PKCS #8 document.
+const KEY_CONFIG: &str = "export_signing_key";
+
+pub struct Signer {
+    key_pair: Ed25519KeyPair,
+}
+
+impl Signer {
+    /// Loads the server's signing key, if one has been created.
+    pub fn load(l: &db::LockedDatabase) -> Result<Option<Self>, Error> {
+        let k = match l.get_config(KEY_CONFIG)? {
+            None => return Ok(None),
+            Some(k) => k,
+        };
+        let pkcs8 =
+            base64::decode(&k).map_err(|e| format_err!("unparseable {}: {}", KEY_CONFIG, e))?;
+        Ok(Some(Signer::from_pkcs8(&pkcs8)?))
+    }
+
+    /// Loads the server's signing key, creating and saving one if there's none yet.
+    pub fn load_or_create(l: &mut db::LockedDatabase) -> Result<Self, Error> {
+        if let Some(s) = Signer::load(l)? {
+            return Ok(s);
+        }
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
+            .map_err(|_| format_err!("unable to generate export signing key"))?;
+        let s = Signer::from_pkcs8(pkcs8.as_ref())?;
+        l.set_config(KEY_CONFIG, Some(&base64::encode(pkcs8.as_ref())))?;
+        info!(
+            "created export signing key {}",
+            base64::encode(s.public_key())
+        );
+        Ok(s)
+    }
+
+    fn from_pkcs8(pkcs8: &[u8]) -> Result<Self, Error> {
+        let key_pair =
+            Ed25519KeyPair::from_pkcs8(pkcs8).map_err(|_| format_err!("invalid {}", KEY_CONFIG))?;
+        Ok(Signer { key_pair })
+    }
+
+    /// Returns the raw 32-byte public key.
+    pub fn public_key(&self) -> &[u8] {
+        self.key_pair.public_key().as_ref()
+    }
+
+    pub fn sign(&self, msg: &[u8]) -> json::ExportSignature {
+        json::ExportSignature {
+            algorithm: ALGORITHM.to_owned(),
+            public_key: base64::encode(self.public_key()),
+            signature: base64::encode(self.key_pair.sign(msg).as_ref()),
+        }
+    }
+}
+
+/// Returns whether `sig` is a valid signature of `msg`, under the public key it names.
+pub fn verify(sig: &json::ExportSignature, msg: &[u8]) -> bool {
+    if sig.algorithm != ALGORITHM {
+        return false;
+    }
+    let (public_key, signature) = match (
+        base64::decode(&sig.public_key),
+        base64::decode(&sig.signature),
+    ) {
+        (Ok(k), Ok(s)) => (k, s),
+        _ => return false,
+    };
+    signature::UnparsedPublicKey::new(&signature::ED25519, &public_key)
+        .verify(msg, &signature)
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let s = Signer::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let sig = s.sign(b"manifest");
+        assert_eq!(sig.algorithm, ALGORITHM);
+        assert_eq!(base64::decode(&sig.public_key).unwrap(), s.public_key());
+        assert!(verify(&sig, b"manifest"));
+        assert!(!verify(&sig, b"manifest!"));
+
+        let mut bad = sig.clone();
+        bad.algorithm = "RSA".to_owned();
+        assert!(!verify(&bad, b"manifest"));
+        let mut bad = sig;
+        bad.public_key = "not base64!".to_owned();
+        assert!(!verify(&bad, b"manifest"));
+    }
+}
@@ -0,0 +1,516 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Background clip exports, as requested via `POST /api/exports`.
+//!
+//! With `moonfire-nvr run --export-dir`, a background thread claims queued jobs from the
+//! database's `export_job` table one at a time and builds each into that directory: a `.mp4`
+//! for a single stream, or a `.zip` holding one `.mp4` per stream and a `manifest.json`
+//! (`json::ExportManifest`) describing the time range and recordings of each, with SHA-256
+//! checksums, so the bundle can be handed over and checked as a unit. Signed exports are always
+//! `.zip`s, with a `manifest.json.sig` as described in `evidence.rs`. Progress is saved to the
+//! job's row about once a second, which is also when a cancellation is noticed. Jobs which ask
+//! for masking transcode each stream with a privacy mask through `ffmpeg` to black it out, via
+//! temporary files. The files are named `<job id>-...`; the directory shouldn't hold anything
+//! else.
+
+use crate::archive::sanitize;
+use crate::evidence;
+use crate::json;
+use crate::mp4;
+use crate::thumbnail;
+use base::clock::Clocks;
+use base::{crypto, strutil};
+use bytes::Buf;
+use db::dir::StreamDirs;
+use db::export::ExportJob;
+use db::recording;
+use failure::{bail, format_err, Error};
+use fnv::FnvHashMap;
+use futures::stream::StreamExt;
+use std::cmp;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration as StdDuration, Instant};
+use tracing::{info, warn};
+
+/// How often to look for newly queued jobs.
+const POLL_INTERVAL: StdDuration = StdDuration::from_secs(2);
+
+/// How often to save a running job's progress (and check whether it's been canceled).
+const PROGRESS_INTERVAL: StdDuration = StdDuration::from_secs(1);
+
+/// One stream's part of an export.
+struct Part {
+    /// The name of the `.mp4` file, or of the entry within the `.zip`.
+    name: String,
+    mp4: mp4::File,
+
+    /// The ffmpeg video filter which blacks out the stream's privacy mask, if the job asked
+    /// for masking and the stream has one.
+    mask: Option<String>,
+
+    /// The part's entry in the manifest, lacking `bytes` and `sha256` until it's written.
+    manifest: json::ExportManifestFile,
+}
+
+/// Why a job stopped before finishing.
+enum Interrupted {
+    Canceled,
+    Shutdown,
+}
+
+pub struct Exporter {
+    db: Arc<db::Database>,
+    dirs_by_stream_id: Arc<FnvHashMap<i32, StreamDirs>>,
+    dir: PathBuf,
+}
+
+impl Exporter {
+    /// Creates an exporter writing into `dir`, which must already exist, and queues again any
+    /// jobs left running by the last server.
+    pub fn new(db: Arc<db::Database>, dir: PathBuf) -> Result<Self, Error> {
+        if !dir.is_dir() {
+            bail!("export directory {} doesn't exist", dir.display());
+        }
+        let dirs_by_stream_id = {
+            let mut l = db.lock();
+            let n = l.requeue_export_jobs()?;
+            if n > 0 {
+                info!("export: resuming {} interrupted job(s)", n);
+            }
+            StreamDirs::all(&l)?
+        };
+        Ok(Exporter {
+            db,
+            dirs_by_stream_id: Arc::new(dirs_by_stream_id),
+            dir,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Runs until `shutdown_rx` is signalled or dropped.
+    pub fn run(self, shutdown_rx: mpsc::Receiver<()>) {
+        let clocks = self.db.clocks();
+        loop {
+            loop {
+                let j = match self.db.lock().start_export_job() {
+                    Ok(Some(j)) => j,
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("export: unable to start next job: {}", e);
+                        break;
+                    }
+                };
+                if !self.run_job(j, &shutdown_rx) {
+                    return;
+                }
+            }
+            match clocks.recv_timeout(&shutdown_rx, POLL_INTERVAL) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Ok(()) | Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    /// Runs a single job. Returns false if interrupted by shutdown, in which case the job is
+    /// left running, to be queued again on the next start.
+    fn run_job(&self, j: ExportJob, shutdown_rx: &mpsc::Receiver<()>) -> bool {
+        info!("export: starting job {}", j.id);
+        let result = self.build(&j, shutdown_rx);
+        let now = self.db.clocks().realtime().sec;
+        let finished = match result {
+            Ok(Ok(ref name)) => {
+                let finished = self
+                    .db
+                    .lock()
+                    .finish_export_job(j.id, Ok(name.as_str()), now);
+                if let Ok(false) = finished {
+                    // Canceled just as it finished.
+                    remove_file(&self.dir.join(name));
+                }
+                finished
+            }
+            Ok(Err(Interrupted::Canceled)) => {
+                info!("export: job {} canceled", j.id);
+                return true;
+            }
+            Ok(Err(Interrupted::Shutdown)) => return false,
+            Err(e) => {
+                warn!("export: job {} failed: {}", j.id, e);
+                self.db
+                    .lock()
+                    .finish_export_job(j.id, Err(e.to_string().as_str()), now)
+            }
+        };
+        if let Err(e) = finished {
+            warn!("export: unable to finish job {}: {}", j.id, e);
+        }
+        true
+    }
+
+    /// Builds the job's file, returning its name within the export directory.
+    fn build(
+        &self,
+        j: &ExportJob,
+        shutdown_rx: &mpsc::Receiver<()>,
+    ) -> Result<Result<String, Interrupted>, Error> {
+        let parts = self.parts(j)?;
+        let total: u64 = parts.iter().map(|p| http_serve::Entity::len(&p.mp4)).sum();
+        let signer = if j.sign {
+            Some(evidence::Signer::load_or_create(&mut self.db.lock())?)
+        } else {
+            None
+        };
+        let bundle = parts.len() > 1 || signer.is_some();
+        let name = if bundle {
+            format!("{}-export.zip", j.id)
+        } else {
+            format!("{}-{}", j.id, parts[0].name)
+        };
+        let path = self.dir.join(&name);
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        let mut progress = Progress {
+            db: &self.db,
+            shutdown_rx,
+            id: j.id,
+            done: 0,
+            total,
+            last_update: Instant::now(),
+        };
+        let result = (|| -> Result<Result<(), Interrupted>, Error> {
+            let mut f = fs::File::create(&tmp)?;
+            if !bundle {
+                if let Err(i) = self.write_part(j.id, &parts[0], &mut f, &mut progress)? {
+                    return Ok(Err(i));
+                }
+            } else {
+                let mut z = zip::ZipWriter::new(f);
+                let options = zip::write::FileOptions::default()
+                    .compression_method(zip::CompressionMethod::Stored)
+                    .large_file(true);
+                let mut files = Vec::with_capacity(parts.len());
+                for p in parts {
+                    z.start_file(p.name.as_str(), options)?;
+                    let (sha256, len) = match self.write_part(j.id, &p, &mut z, &mut progress)? {
+                        Ok(d) => d,
+                        Err(i) => return Ok(Err(i)),
+                    };
+                    let mut m = p.manifest;
+                    m.bytes = len;
+                    m.sha256 = strutil::hex(&sha256);
+                    files.push(m);
+                }
+                let manifest = json::ExportManifest {
+                    export_id: j.id,
+                    generator: concat!("moonfire-nvr ", env!("CARGO_PKG_VERSION")).to_owned(),
+                    creation_time_sec: j.creation_time_sec,
+                    author: j.author.clone(),
+                    generated_time_sec: self.db.clocks().realtime().sec,
+                    start_time_90k: j.time.start.0,
+                    end_time_90k: j.time.end.0,
+                    start_time: rfc3339(j.time.start),
+                    end_time: rfc3339(j.time.end),
+                    files,
+                };
+                let manifest = serde_json::to_vec_pretty(&manifest)?;
+                z.start_file("manifest.json", options)?;
+                z.write_all(&manifest)?;
+                if let Some(ref s) = signer {
+                    z.start_file("manifest.json.sig", options)?;
+                    serde_json::to_writer_pretty(&mut z, &s.sign(&manifest))?;
+                }
+                f = z.finish()?;
+            }
+            f.sync_all()?;
+            fs::rename(&tmp, &path)?;
+            Ok(Ok(()))
+        })();
+        match result {
+            Ok(Ok(())) => Ok(Ok(name)),
+            Ok(Err(i)) => {
+                remove_file(&tmp);
+                Ok(Err(i))
+            }
+            Err(e) => {
+                remove_file(&tmp);
+                Err(e)
+            }
+        }
+    }
+
+    /// Writes `p` to `w`, first transcoding it if it has a mask. Returns the SHA-256 and length
+    /// of what was written.
+    fn write_part<W: Write>(
+        &self,
+        id: i64,
+        p: &Part,
+        w: &mut W,
+        progress: &mut Progress,
+    ) -> Result<Result<([u8; 32], u64), Interrupted>, Error> {
+        let filter = match p.mask {
+            None => {
+                let len = http_serve::Entity::len(&p.mp4);
+                return Ok(copy(&p.mp4, w, progress)?.map(|d| (d, len)));
+            }
+            Some(ref f) => f,
+        };
+        let unmasked = self.dir.join(format!("{}-{}.unmasked.tmp", id, p.name));
+        let masked = self.dir.join(format!("{}-{}.masked.tmp", id, p.name));
+        let result = (|| -> Result<Result<([u8; 32], u64), Interrupted>, Error> {
+            {
+                let mut f = fs::File::create(&unmasked)?;
+                if let Err(i) = copy(&p.mp4, &mut f, progress)? {
+                    return Ok(Err(i));
+                }
+            }
+            transcode(&unmasked, &masked, filter)?;
+            let mut f = fs::File::open(&masked)?;
+            let mut sha256 = crypto::Sha256::new();
+            let mut buf = vec![0; 1 << 16];
+            let mut len = 0;
+            loop {
+                let n = f.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                w.write_all(&buf[..n])?;
+                sha256.update(&buf[..n]);
+                len += n as u64;
+            }
+            Ok(Ok((sha256.finish(), len)))
+        })();
+        remove_file(&unmasked);
+        remove_file(&masked);
+        result
+    }
+
+    /// Prepares a `.mp4` of each stream of `j` which has committed recordings in its range.
+    fn parts(&self, j: &ExportJob) -> Result<Vec<Part>, Error> {
+        let start = time::at(time::Timespec::new(j.time.start.unix_seconds(), 0));
+        let start = start.strftime("%Y%m%dT%H%M%S")?;
+        let mut builders = Vec::new();
+        {
+            let l = self.db.lock();
+            for &stream_id in &j.stream_ids {
+                let s = l
+                    .streams_by_id()
+                    .get(&stream_id)
+                    .ok_or_else(|| format_err!("stream {} no longer exists", stream_id))?;
+                let c = l.cameras_by_id().get(&s.camera_id).unwrap();
+                let mut rows = Vec::new();
+                l.list_recordings_by_time(stream_id, j.time.clone(), &mut |r| {
+                    if (r.flags & db::RecordingFlags::Uncommitted as i32) == 0 {
+                        rows.push(r);
+                    }
+                    Ok(())
+                })?;
+                if rows.is_empty() {
+                    continue;
+                }
+                rows.sort_by_key(|r| r.start);
+                let mut builder = mp4::FileBuilder::new(mp4::Type::Normal);
+                builder.reserve(rows.len());
+                let mut recordings = Vec::with_capacity(rows.len());
+                for r in rows {
+                    let end = r.start + recording::Duration(i64::from(r.duration_90k));
+                    let included = cmp::max(j.time.start, r.start)..cmp::min(j.time.end, end);
+                    recordings.push(json::ExportManifestRecording {
+                        id: r.id.recording(),
+                        start_time_90k: included.start.0,
+                        end_time_90k: included.end.0,
+                    });
+                    let rel =
+                        (included.start - r.start).0 as i32..(included.end - r.start).0 as i32;
+                    builder.append(&l, r, rel)?;
+                }
+                let name = format!(
+                    "{}-{}-{}.mp4",
+                    sanitize(&c.short_name),
+                    s.type_.as_str(),
+                    start
+                );
+                let covered = recording::Time(recordings[0].start_time_90k)
+                    ..recording::Time(recordings[recordings.len() - 1].end_time_90k);
+                let manifest = json::ExportManifestFile {
+                    name: name.clone(),
+                    camera_uuid: c.uuid,
+                    camera_short_name: c.short_name.clone(),
+                    stream: s.type_.as_str(),
+                    start_time_90k: covered.start.0,
+                    end_time_90k: covered.end.0,
+                    start_time: rfc3339(covered.start),
+                    end_time: rfc3339(covered.end),
+                    bytes: 0,
+                    sha256: String::new(),
+                    masked: false,
+                    recordings,
+                };
+                let mask = if j.mask {
+                    thumbnail::mask_filter(&s.privacy_mask)
+                } else {
+                    None
+                };
+                builders.push((name, builder, mask, manifest));
+            }
+        }
+        if builders.is_empty() {
+            bail!("no recordings in the requested range");
+        }
+        builders
+            .into_iter()
+            .map(|(name, b, mask, mut manifest)| {
+                manifest.masked = mask.is_some();
+                Ok(Part {
+                    name,
+                    mp4: b.build(self.db.clone(), self.dirs_by_stream_id.clone())?,
+                    mask,
+                    manifest,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Tracks a running job's progress, saving it periodically.
+struct Progress<'a> {
+    db: &'a db::Database,
+    shutdown_rx: &'a mpsc::Receiver<()>,
+    id: i64,
+    done: u64,
+    total: u64,
+    last_update: Instant,
+}
+
+impl<'a> Progress<'a> {
+    fn add(&mut self, bytes: u64) -> Result<Result<(), Interrupted>, Error> {
+        self.done += bytes;
+        match self.shutdown_rx.try_recv() {
+            Err(mpsc::TryRecvError::Empty) => {}
+            Ok(()) | Err(mpsc::TryRecvError::Disconnected) => {
+                return Ok(Err(Interrupted::Shutdown))
+            }
+        }
+        let now = Instant::now();
+        if now.duration_since(self.last_update) < PROGRESS_INTERVAL {
+            return Ok(Ok(()));
+        }
+        self.last_update = now;
+        if !self
+            .db
+            .lock()
+            .set_export_progress(self.id, self.done as i64, self.total as i64)?
+        {
+            return Ok(Err(Interrupted::Canceled));
+        }
+        Ok(Ok(()))
+    }
+}
+
+/// Writes `mp4` to `w`, noting progress after each chunk. Returns the SHA-256 of what was
+/// written.
+fn copy<W: Write>(
+    mp4: &mp4::File,
+    w: &mut W,
+    progress: &mut Progress,
+) -> Result<Result<[u8; 32], Interrupted>, Error> {
+    use http_serve::Entity;
+    let mut body = std::pin::Pin::from(mp4.get_range(0..mp4.len()));
+    let mut sha256 = crypto::Sha256::new();
+    let result: Result<Result<(), Interrupted>, Error> = futures::executor::block_on(async {
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(failure::Error::from_boxed_compat)?;
+            w.write_all(chunk.bytes())?;
+            sha256.update(chunk.bytes());
+            if let Err(i) = progress.add(chunk.bytes().len() as u64)? {
+                return Ok(Err(i));
+            }
+        }
+        Ok(Ok(()))
+    });
+    Ok(result?.map(|()| sha256.finish()))
+}
+
+/// Transcodes the `.mp4` at `input` into a new one at `output` through the ffmpeg video filter
+/// `filter`. Blocks until the `ffmpeg` subprocess exits.
+fn transcode(input: &Path, output: &Path, filter: &str) -> Result<(), Error> {
+    let out = Command::new("ffmpeg")
+        .args(&["-nostdin", "-hide_banner", "-loglevel", "error", "-y", "-i"])
+        .arg(input)
+        .args(&[
+            "-vf",
+            filter,
+            "-c:v",
+            "libx264",
+            "-preset",
+            "veryfast",
+            "-pix_fmt",
+            "yuv420p",
+            "-movflags",
+            "+faststart",
+            "-f",
+            "mp4",
+        ])
+        .arg(output)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| format_err!("unable to run ffmpeg: {}", e))?;
+    if !out.status.success() {
+        bail!(
+            "ffmpeg failed with {}: {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Formats `t` as an RFC 3339 UTC timestamp, to the second.
+fn rfc3339(t: recording::Time) -> String {
+    time::at_utc(time::Timespec::new(t.unix_seconds(), 0))
+        .rfc3339()
+        .to_string()
+}
+
+fn remove_file(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            warn!("export: unable to remove {}: {}", path.display(), e);
+        }
+    }
+}
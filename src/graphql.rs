@@ -0,0 +1,422 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Optional GraphQL query API at `/api/graphql`, when built with the `graphql` feature.
+//!
+//! This lets a dashboard fetch cameras, streams, recordings, detections, and signal changes in
+//! one request rather than one per camera and stream. It's read-only and follows the same
+//! access rules as the corresponding JSON endpoints: callers see only the cameras they may
+//! access, and camera configuration needs `read_camera_configs`.
+//!
+//! GraphQL's `Int` is 32 bits, so 64-bit quantities (times in 90 kHz units and byte counts)
+//! are `Float`s, which represent them exactly up to 2^53.
+
+use crate::web;
+use db::recording;
+use juniper::{EmptyMutation, FieldResult, GraphQLObject, RootNode};
+use std::sync::Arc;
+use uuid::Uuid;
+
+pub type Schema = RootNode<'static, Query, EmptyMutation<Context>>;
+pub type Request = juniper::http::GraphQLRequest;
+
+pub fn schema() -> Schema {
+    Schema::new(Query, EmptyMutation::new())
+}
+
+pub struct Context {
+    db: Arc<db::Database>,
+    caller: web::Caller,
+}
+
+impl juniper::Context for Context {}
+
+impl Context {
+    pub(crate) fn new(db: Arc<db::Database>, caller: web::Caller) -> Self {
+        Context { db, caller }
+    }
+}
+
+/// Executes `req`, returning whether it succeeded and the JSON response.
+pub fn execute(schema: &Schema, ctx: &Context, req: &Request) -> (bool, Vec<u8>) {
+    let resp = req.execute(schema, ctx);
+    let body = serde_json::to_vec(&resp).expect("GraphQL responses are serializable");
+    (resp.is_ok(), body)
+}
+
+/// Returns the time range given by optional `startTime90k` and `endTime90k` arguments.
+fn time_range(start_90k: Option<f64>, end_90k: Option<f64>) -> std::ops::Range<recording::Time> {
+    start_90k.map_or(recording::Time::min_value(), |t| recording::Time(t as i64))
+        ..end_90k.map_or(recording::Time::max_value(), |t| recording::Time(t as i64))
+}
+
+pub struct Query;
+
+#[juniper::object(Context = Context)]
+impl Query {
+    /// The cameras the caller may access, or just the one with the given uuid.
+    fn cameras(context: &Context, uuid: Option<String>) -> FieldResult<Vec<Camera>> {
+        let uuid = match uuid {
+            None => None,
+            Some(u) => Some(Uuid::parse_str(&u).map_err(|_| format!("bad uuid {:?}", u))?),
+        };
+        let db = context.db.lock();
+        Ok(db
+            .cameras_by_id()
+            .values()
+            .filter(|c| context.caller.may_access_camera(c.id))
+            .filter(|c| uuid.map(|u| u == c.uuid).unwrap_or(true))
+            .map(Camera::new)
+            .collect())
+    }
+
+    /// All signals, as in `GET /api/`.
+    fn signals(context: &Context) -> Vec<Signal> {
+        let db = context.db.lock();
+        db.signals_by_id()
+            .values()
+            .map(|s| Signal::new(&db, s))
+            .collect()
+    }
+}
+
+pub struct Camera {
+    id: i32,
+    uuid: Uuid,
+    short_name: String,
+    description: String,
+    streams: [Option<i32>; 2],
+}
+
+impl Camera {
+    fn new(c: &db::Camera) -> Self {
+        Camera {
+            id: c.id,
+            uuid: c.uuid,
+            short_name: c.short_name.clone(),
+            description: c.description.clone(),
+            streams: c.streams,
+        }
+    }
+}
+
+#[juniper::object(Context = Context)]
+impl Camera {
+    fn uuid(&self) -> String {
+        self.uuid.to_string()
+    }
+
+    fn short_name(&self) -> String {
+        self.short_name.clone()
+    }
+
+    fn description(&self) -> String {
+        self.description.clone()
+    }
+
+    /// The camera's ONVIF host, if the caller has the `read_camera_configs` permission.
+    fn onvif_host(&self, context: &Context) -> Option<String> {
+        if !context.caller.permissions.read_camera_configs {
+            return None;
+        }
+        let db = context.db.lock();
+        db.cameras_by_id()
+            .get(&self.id)
+            .map(|c| c.onvif_host.clone())
+    }
+
+    /// The camera's streams, `main` before `sub`.
+    fn streams(&self, context: &Context) -> Vec<Stream> {
+        let db = context.db.lock();
+        self.streams
+            .iter()
+            .filter_map(|id| id.and_then(|id| db.streams_by_id().get(&id)))
+            .map(|s| Stream::new(&context.caller, s))
+            .collect()
+    }
+
+    /// The signals associated with this camera.
+    fn signals(&self, context: &Context) -> Vec<Signal> {
+        let db = context.db.lock();
+        db.signals_by_id()
+            .values()
+            .filter(|s| s.cameras.iter().any(|c| c.camera_id == self.id))
+            .map(|s| Signal::new(&db, s))
+            .collect()
+    }
+}
+
+pub struct Stream {
+    id: i32,
+    type_: db::StreamType,
+    record: bool,
+    retain_bytes: i64,
+    range: Option<std::ops::Range<recording::Time>>,
+    duration: recording::Duration,
+    sample_file_bytes: i64,
+    rtsp_url: Option<String>,
+}
+
+impl Stream {
+    fn new(caller: &web::Caller, s: &db::Stream) -> Self {
+        Stream {
+            id: s.id,
+            type_: s.type_,
+            record: s.record,
+            retain_bytes: s.retain_bytes,
+            range: s.range.clone(),
+            duration: s.duration,
+            sample_file_bytes: s.sample_file_bytes,
+            rtsp_url: if caller.permissions.read_camera_configs {
+                Some(s.rtsp_url.clone())
+            } else {
+                None
+            },
+        }
+    }
+}
+
+#[juniper::object(Context = Context)]
+impl Stream {
+    /// `main` or `sub`.
+    fn stream_type(&self) -> String {
+        self.type_.as_str().to_owned()
+    }
+
+    fn record(&self) -> bool {
+        self.record
+    }
+
+    fn retain_bytes(&self) -> f64 {
+        self.retain_bytes as f64
+    }
+
+    /// The start of the first recording, if any.
+    fn min_start_time_90k(&self) -> Option<f64> {
+        self.range.as_ref().map(|r| r.start.0 as f64)
+    }
+
+    /// The end of the last recording, if any.
+    fn max_end_time_90k(&self) -> Option<f64> {
+        self.range.as_ref().map(|r| r.end.0 as f64)
+    }
+
+    fn total_duration_90k(&self) -> f64 {
+        self.duration.0 as f64
+    }
+
+    fn total_sample_file_bytes(&self) -> f64 {
+        self.sample_file_bytes as f64
+    }
+
+    /// The stream's url, if the caller has the `read_camera_configs` permission.
+    fn rtsp_url(&self) -> Option<String> {
+        self.rtsp_url.clone()
+    }
+
+    /// Recordings overlapping the given range, aggregated as in
+    /// `GET /api/cameras/<uuid>/<stream>/recordings`.
+    fn recordings(
+        &self,
+        context: &Context,
+        start_time_90k: Option<f64>,
+        end_time_90k: Option<f64>,
+        split_90k: Option<f64>,
+    ) -> FieldResult<Vec<Recording>> {
+        let split = recording::Duration(split_90k.map_or(i64::max_value(), |s| s as i64));
+        if split.0 <= 0 {
+            return Err("split90k must be positive".into());
+        }
+        let db = context.db.lock();
+        let mut out = Vec::new();
+        db.list_aggregated_recordings(
+            self.id,
+            time_range(start_time_90k, end_time_90k),
+            split,
+            &mut |row| {
+                let vse = db
+                    .video_sample_entries_by_id()
+                    .get(&row.video_sample_entry_id)
+                    .unwrap();
+                out.push(Recording {
+                    start_id: row.ids.start,
+                    end_id: row.ids.end - 1, // inclusive, as in the JSON API.
+                    first_uncommitted: row.first_uncommitted,
+                    growing: row.growing,
+                    start_time_90k: row.time.start.0 as f64,
+                    end_time_90k: row.time.end.0 as f64,
+                    sample_file_bytes: row.sample_file_bytes as f64,
+                    video_samples: row.video_samples as f64,
+                    video_sample_entry_id: row.video_sample_entry_id,
+                    width: i32::from(vse.width),
+                    height: i32::from(vse.height),
+                });
+                Ok(())
+            },
+        )?;
+        Ok(out)
+    }
+
+    /// Detections overlapping the given range, optionally with the given label, as in
+    /// `GET /api/cameras/<uuid>/<stream>/detections`.
+    fn detections(
+        &self,
+        context: &Context,
+        start_time_90k: Option<f64>,
+        end_time_90k: Option<f64>,
+        label: Option<String>,
+    ) -> FieldResult<Vec<Detection>> {
+        let db = context.db.lock();
+        let mut out = Vec::new();
+        db.list_detections(
+            self.id,
+            time_range(start_time_90k, end_time_90k),
+            label.as_deref(),
+            &mut |d| {
+                out.push(Detection {
+                    id: d.id as f64,
+                    recording_id: d.recording_id.recording(),
+                    start_time_90k: d.time.start.0 as f64,
+                    end_time_90k: d.time.end.0 as f64,
+                    label: d.label,
+                    confidence: d.confidence,
+                    source: d.source,
+                });
+                Ok(())
+            },
+        )?;
+        Ok(out)
+    }
+}
+
+#[derive(GraphQLObject)]
+pub struct Recording {
+    start_id: i32,
+
+    /// The last recording id included, inclusive.
+    end_id: i32,
+    first_uncommitted: Option<i32>,
+    growing: bool,
+    start_time_90k: f64,
+    end_time_90k: f64,
+    sample_file_bytes: f64,
+    video_samples: f64,
+    video_sample_entry_id: i32,
+    width: i32,
+    height: i32,
+}
+
+#[derive(GraphQLObject)]
+pub struct Detection {
+    id: f64,
+    recording_id: i32,
+    start_time_90k: f64,
+    end_time_90k: f64,
+    label: String,
+    confidence: Option<f64>,
+    source: Option<String>,
+}
+
+pub struct Signal {
+    id: u32,
+    source: Uuid,
+    type_: Uuid,
+    short_name: String,
+    camera_uuids: Vec<String>,
+}
+
+impl Signal {
+    fn new(db: &db::LockedDatabase, s: &db::Signal) -> Self {
+        Signal {
+            id: s.id,
+            source: s.source,
+            type_: s.type_,
+            short_name: s.short_name.clone(),
+            camera_uuids: s
+                .cameras
+                .iter()
+                .filter_map(|c| db.cameras_by_id().get(&c.camera_id))
+                .map(|c| c.uuid.to_string())
+                .collect(),
+        }
+    }
+}
+
+#[juniper::object(Context = Context)]
+impl Signal {
+    fn id(&self) -> i32 {
+        self.id as i32
+    }
+
+    fn source(&self) -> String {
+        self.source.to_string()
+    }
+
+    fn type_uuid(&self) -> String {
+        self.type_.to_string()
+    }
+
+    fn short_name(&self) -> String {
+        self.short_name.clone()
+    }
+
+    fn camera_uuids(&self) -> Vec<String> {
+        self.camera_uuids.clone()
+    }
+
+    /// The signal's state changes within the given range, as in `GET /api/signals`. The first
+    /// is the state in effect at the start of the range, if known.
+    fn changes(
+        &self,
+        context: &Context,
+        start_time_90k: Option<f64>,
+        end_time_90k: Option<f64>,
+    ) -> Vec<SignalChange> {
+        let mut out = Vec::new();
+        context.db.lock().list_changes_by_time(
+            time_range(start_time_90k, end_time_90k),
+            &mut |c: &db::signal::ListStateChangesRow| {
+                if c.signal == self.id {
+                    out.push(SignalChange {
+                        time_90k: c.when.0 as f64,
+                        state: i32::from(c.state),
+                    });
+                }
+            },
+        );
+        out
+    }
+}
+
+#[derive(GraphQLObject)]
+pub struct SignalChange {
+    time_90k: f64,
+    state: i32,
+}
@@ -0,0 +1,694 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Optional gRPC control-plane API, as described in `proto/nvr.proto`.
+//!
+//! This mirrors parts of the JSON API in `web.rs`, sharing its authentication and live segment
+//! building.
+
+use crate::web;
+use base::ErrorKind;
+use db::recording;
+use futures::sink::SinkExt;
+use futures::stream::{Stream, StreamExt};
+use std::pin::Pin;
+use std::sync::Arc;
+use tonic::{Code, Request, Response, Status};
+use uuid::Uuid;
+
+pub mod proto {
+    tonic::include_proto!("moonfire_nvr.v1");
+}
+
+use proto::nvr_server::Nvr;
+pub use proto::nvr_server::NvrServer;
+
+pub struct Service {
+    db: Arc<db::Database>,
+    web: Arc<web::Service>,
+}
+
+impl Service {
+    pub fn new(db: Arc<db::Database>, web: Arc<web::Service>) -> Self {
+        Service { db, web }
+    }
+
+    /// Authenticates a request via its `cookie` or `authorization` metadata, as `web::Service`
+    /// does for HTTP.
+    fn caller<T>(&self, req: &Request<T>) -> Result<web::Caller, Status> {
+        self.web
+            .authenticate(&req.metadata().clone().into_headers(), None, false)
+            .map_err(from_base_error)
+    }
+}
+
+fn from_base_error(err: base::Error) -> Status {
+    let code = match err.kind() {
+        ErrorKind::Cancelled => Code::Cancelled,
+        ErrorKind::InvalidArgument => Code::InvalidArgument,
+        ErrorKind::DeadlineExceeded => Code::DeadlineExceeded,
+        ErrorKind::NotFound => Code::NotFound,
+        ErrorKind::AlreadyExists => Code::AlreadyExists,
+        ErrorKind::PermissionDenied => Code::PermissionDenied,
+        ErrorKind::Unauthenticated => Code::Unauthenticated,
+        ErrorKind::ResourceExhausted => Code::ResourceExhausted,
+        ErrorKind::FailedPrecondition => Code::FailedPrecondition,
+        ErrorKind::Aborted => Code::Aborted,
+        ErrorKind::OutOfRange => Code::OutOfRange,
+        ErrorKind::Unimplemented => Code::Unimplemented,
+        ErrorKind::Internal => Code::Internal,
+        ErrorKind::Unavailable => Code::Unavailable,
+        ErrorKind::DataLoss => Code::DataLoss,
+        _ => Code::Unknown,
+    };
+    Status::new(code, err.to_string())
+}
+
+/// How long `DeleteStream` waits for the stream's recording in progress to be saved.
+const DELETE_STREAM_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+fn internal(err: failure::Error) -> Status {
+    Status::internal(err.to_string())
+}
+
+fn parse_uuid(uuid: &str) -> Result<Uuid, Status> {
+    Uuid::parse_str(uuid).map_err(|_| Status::invalid_argument(format!("bad uuid {:?}", uuid)))
+}
+
+fn parse_stream_type(type_: i32) -> Result<db::StreamType, Status> {
+    match proto::StreamType::from_i32(type_) {
+        Some(proto::StreamType::Main) => Ok(db::StreamType::MAIN),
+        Some(proto::StreamType::Sub) => Ok(db::StreamType::SUB),
+        None => Err(Status::invalid_argument(format!(
+            "bad stream type {}",
+            type_
+        ))),
+    }
+}
+
+/// Looks up the given camera, treating one the caller may not access as missing.
+fn get_camera<'db>(
+    db: &'db db::LockedDatabase,
+    caller: &web::Caller,
+    uuid: Uuid,
+) -> Result<&'db db::Camera, Status> {
+    db.get_camera(uuid)
+        .filter(|c| caller.may_access_camera(c.id))
+        .ok_or_else(|| Status::not_found(format!("no such camera {}", uuid)))
+}
+
+/// Looks up the stream id of the given camera and stream type.
+fn stream_id(
+    db: &db::LockedDatabase,
+    caller: &web::Caller,
+    uuid: &str,
+    type_: i32,
+) -> Result<i32, Status> {
+    let uuid = parse_uuid(uuid)?;
+    let type_ = parse_stream_type(type_)?;
+    let camera = get_camera(db, caller, uuid)?;
+    camera.streams[type_.index()]
+        .ok_or_else(|| Status::not_found(format!("no such stream {}/{}", uuid, type_)))
+}
+
+fn stream(s: &db::Stream) -> proto::Stream {
+    proto::Stream {
+        retain_bytes: s.retain_bytes,
+        record: s.record,
+        min_start_time_90k: s.range.as_ref().map(|r| r.start.0),
+        max_end_time_90k: s.range.as_ref().map(|r| r.end.0),
+        total_duration_90k: s.duration.0,
+        total_sample_file_bytes: s.sample_file_bytes,
+        fs_bytes: s.fs_bytes,
+    }
+}
+
+/// Describes `c`, including its configuration if `include_config`.
+fn camera(db: &db::LockedDatabase, c: &db::Camera, include_config: bool) -> proto::Camera {
+    let stream_by_id = |id: Option<i32>| id.and_then(|id| db.streams_by_id().get(&id)).map(stream);
+    let stream_config = |id: Option<i32>| {
+        let s = id.and_then(|id| db.streams_by_id().get(&id))?;
+        Some(proto::StreamConfig {
+            rtsp_url: s.rtsp_url.clone(),
+            record: s.record,
+            sample_file_dir_id: s.sample_file_dir_id.unwrap_or(0),
+            stripe_dir_ids: s.stripe_dir_ids.clone(),
+            failover_sample_file_dir_id: s.failover_sample_file_dir_id.unwrap_or(0),
+            flush_if_sec: s.flush_if_sec,
+            flush_if_bytes: s.flush_if_bytes,
+            input_options: s.input_options.clone(),
+            rtsp_transport: s.rtsp.transport.as_str().to_owned(),
+            reconnect_min_sec: s.rtsp.reconnect_min_sec,
+            reconnect_max_sec: s.rtsp.reconnect_max_sec,
+            session_timeout_sec: s.rtsp.session_timeout_sec,
+        })
+    };
+    proto::Camera {
+        uuid: c.uuid.to_string(),
+        short_name: c.short_name.clone(),
+        description: c.description.clone(),
+        ptz: c.ptz,
+        config: if include_config {
+            Some(proto::CameraConfig {
+                short_name: c.short_name.clone(),
+                description: c.description.clone(),
+                onvif_host: c.onvif_host.clone(),
+                username: c.username.clone(),
+                password: c.password.clone(),
+                ptz: c.ptz,
+                main: stream_config(c.streams[0]),
+                sub: stream_config(c.streams[1]),
+            })
+        } else {
+            None
+        },
+        main: stream_by_id(c.streams[0]),
+        sub: stream_by_id(c.streams[1]),
+    }
+}
+
+/// Builds a `CameraChange` from `config`. Stream settings not exposed via gRPC (currently just
+/// the virtual source) are carried over from `existing`.
+fn camera_change(
+    db: &db::LockedDatabase,
+    config: Option<proto::CameraConfig>,
+    existing: Option<&db::Camera>,
+) -> Result<db::CameraChange, Status> {
+    let config = config.ok_or_else(|| Status::invalid_argument("config required"))?;
+    if config.short_name.is_empty() {
+        return Err(Status::invalid_argument("short_name required"));
+    }
+    let mut c = db::CameraChange {
+        short_name: config.short_name,
+        description: config.description,
+        onvif_host: config.onvif_host,
+        username: config.username,
+        password: config.password,
+        ptz: config.ptz,
+        streams: Default::default(),
+    };
+    for (&t, s) in db::ALL_STREAM_TYPES
+        .iter()
+        .zip(vec![config.main, config.sub])
+    {
+        let s = match s {
+            None => continue,
+            Some(s) => s,
+        };
+        let default_rtsp = db::RtspPolicy::default();
+        let or_default = |v: i64, d: i64| if v == 0 { d } else { v };
+        let reconnect_min_sec = or_default(s.reconnect_min_sec, default_rtsp.reconnect_min_sec);
+        let rtsp = db::RtspPolicy {
+            transport: match s.rtsp_transport.as_str() {
+                "" => default_rtsp.transport,
+                t => db::RtspTransport::parse(t).ok_or_else(|| {
+                    Status::invalid_argument(format!("bad rtsp_transport {:?}", t))
+                })?,
+            },
+            reconnect_min_sec,
+
+            // With only a minimum given, don't back off beyond it.
+            reconnect_max_sec: or_default(
+                s.reconnect_max_sec,
+                reconnect_min_sec.max(default_rtsp.reconnect_max_sec),
+            ),
+            session_timeout_sec: or_default(
+                s.session_timeout_sec,
+                default_rtsp.session_timeout_sec,
+            ),
+        };
+        let virtual_source = existing
+            .and_then(|c| c.streams[t.index()])
+            .and_then(|id| db.streams_by_id().get(&id))
+            .and_then(|s| s.virtual_source);
+        c.streams[t.index()] = db::StreamChange {
+            rtsp_url: s.rtsp_url,
+            sample_file_dir_id: Some(s.sample_file_dir_id).filter(|&id| id != 0),
+            stripe_dir_ids: s.stripe_dir_ids,
+            failover_sample_file_dir_id: Some(s.failover_sample_file_dir_id).filter(|&id| id != 0),
+            virtual_source,
+            record: s.record,
+            flush_if_sec: s.flush_if_sec,
+            flush_if_bytes: s.flush_if_bytes,
+            input_options: s.input_options,
+            rtsp,
+        };
+    }
+    Ok(c)
+}
+
+/// Describes `u`. Its password (hash) is never included.
+fn user(db: &db::LockedDatabase, u: &db::User) -> proto::User {
+    proto::User {
+        id: u.id,
+        config: Some(proto::UserConfig {
+            username: u.username.clone(),
+            permissions: db::auth::permission_names(&u.permissions)
+                .into_iter()
+                .map(str::to_owned)
+                .collect(),
+            camera_restricted: u.cameras.is_some(),
+            camera_uuids: u
+                .cameras
+                .iter()
+                .flatten()
+                .filter_map(|id| db.cameras_by_id().get(id))
+                .map(|c| c.uuid.to_string())
+                .collect(),
+            unix_uid: u.unix_uid,
+            disabled: u.disabled(),
+            password: None,
+        }),
+        has_password: u.password_hash().is_some(),
+        expiration_time_sec: u.expiration_time_sec,
+    }
+}
+
+/// Applies `config` to `change`, which is either a new user or the `User::change` of the user
+/// with id `existing_id`.
+fn user_change(
+    db: &db::LockedDatabase,
+    config: Option<proto::UserConfig>,
+    mut change: db::UserChange,
+    existing_id: Option<i32>,
+) -> Result<db::UserChange, Status> {
+    let config = config.ok_or_else(|| Status::invalid_argument("config required"))?;
+    if config.username.is_empty() {
+        return Err(Status::invalid_argument("username required"));
+    }
+    if let Some(u) = db.get_user(&config.username) {
+        if Some(u.id) != existing_id {
+            return Err(Status::already_exists(format!(
+                "user {:?} already exists",
+                config.username
+            )));
+        }
+    }
+    change.username = config.username;
+    let mut permissions = db::Permissions::default();
+    for n in &config.permissions {
+        *db::auth::permission_mut(&mut permissions, n)
+            .ok_or_else(|| Status::invalid_argument(format!("unknown permission {:?}", n)))? = true;
+    }
+    change.permissions = permissions;
+    change.cameras = if config.camera_restricted {
+        let mut ids = std::collections::BTreeSet::new();
+        for u in &config.camera_uuids {
+            let uuid = parse_uuid(u)?;
+            let c = db
+                .get_camera(uuid)
+                .ok_or_else(|| Status::invalid_argument(format!("no such camera {}", uuid)))?;
+            ids.insert(c.id);
+        }
+        Some(ids)
+    } else {
+        None
+    };
+    change.unix_uid = config.unix_uid;
+    if config.disabled {
+        change.disable();
+    } else {
+        change.enable();
+    }
+    match config.password {
+        None => {}
+        Some(ref p) if p.is_empty() => change.clear_password(),
+        Some(p) => change.set_password(p),
+    }
+    Ok(change)
+}
+
+fn require_admin_users(caller: &web::Caller) -> Result<(), Status> {
+    if !caller.permissions.admin_users {
+        return Err(Status::permission_denied("admin_users required"));
+    }
+    Ok(())
+}
+
+fn require_update_camera_configs(caller: &web::Caller) -> Result<(), Status> {
+    if !caller.permissions.update_camera_configs {
+        return Err(Status::permission_denied("update_camera_configs required"));
+    }
+    Ok(())
+}
+
+fn require_view_video(caller: &web::Caller) -> Result<(), Status> {
+    if !caller.permissions.view_video {
+        return Err(Status::permission_denied("view_video required"));
+    }
+    Ok(())
+}
+
+#[tonic::async_trait]
+impl Nvr for Service {
+    async fn list_cameras(
+        &self,
+        req: Request<proto::ListCamerasRequest>,
+    ) -> Result<Response<proto::ListCamerasResponse>, Status> {
+        let caller = self.caller(&req)?;
+        let db = self.db.lock();
+        let cameras = db
+            .cameras_by_id()
+            .values()
+            .filter(|c| caller.may_access_camera(c.id))
+            .map(|c| camera(&db, c, caller.permissions.read_camera_configs))
+            .collect();
+        Ok(Response::new(proto::ListCamerasResponse { cameras }))
+    }
+
+    async fn get_camera(
+        &self,
+        req: Request<proto::GetCameraRequest>,
+    ) -> Result<Response<proto::Camera>, Status> {
+        let caller = self.caller(&req)?;
+        let uuid = parse_uuid(&req.get_ref().uuid)?;
+        let db = self.db.lock();
+        let c = get_camera(&db, &caller, uuid)?;
+        Ok(Response::new(camera(
+            &db,
+            c,
+            caller.permissions.read_camera_configs,
+        )))
+    }
+
+    async fn create_camera(
+        &self,
+        req: Request<proto::CreateCameraRequest>,
+    ) -> Result<Response<proto::Camera>, Status> {
+        let caller = self.caller(&req)?;
+        require_update_camera_configs(&caller)?;
+        let mut db = self.db.lock();
+        let change = camera_change(&db, req.into_inner().config, None)?;
+        let id = db
+            .add_camera(change)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        let c = db.cameras_by_id().get(&id).expect("camera just added");
+        Ok(Response::new(camera(
+            &db,
+            c,
+            caller.permissions.read_camera_configs,
+        )))
+    }
+
+    async fn update_camera(
+        &self,
+        req: Request<proto::UpdateCameraRequest>,
+    ) -> Result<Response<proto::Camera>, Status> {
+        let caller = self.caller(&req)?;
+        require_update_camera_configs(&caller)?;
+        let req = req.into_inner();
+        let uuid = parse_uuid(&req.uuid)?;
+        let mut db = self.db.lock();
+        let (id, change) = {
+            let c = get_camera(&db, &caller, uuid)?;
+            (c.id, camera_change(&db, req.config, Some(c))?)
+        };
+        db.update_camera(id, change)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        let c = db.cameras_by_id().get(&id).expect("camera just updated");
+        Ok(Response::new(camera(
+            &db,
+            c,
+            caller.permissions.read_camera_configs,
+        )))
+    }
+
+    async fn delete_camera(
+        &self,
+        req: Request<proto::DeleteCameraRequest>,
+    ) -> Result<Response<proto::DeleteCameraResponse>, Status> {
+        let caller = self.caller(&req)?;
+        require_update_camera_configs(&caller)?;
+        let uuid = parse_uuid(&req.get_ref().uuid)?;
+        let mut db = self.db.lock();
+        let id = get_camera(&db, &caller, uuid)?.id;
+        db.delete_camera(id)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        Ok(Response::new(proto::DeleteCameraResponse {}))
+    }
+
+    async fn update_stream_retention(
+        &self,
+        req: Request<proto::UpdateStreamRetentionRequest>,
+    ) -> Result<Response<proto::Stream>, Status> {
+        let caller = self.caller(&req)?;
+        require_update_camera_configs(&caller)?;
+        let req = req.into_inner();
+        if req.retain_bytes < 0 {
+            return Err(Status::invalid_argument(
+                "retain_bytes must be non-negative",
+            ));
+        }
+        let mut db = self.db.lock();
+        let stream_id = stream_id(&db, &caller, &req.camera_uuid, req.stream)?;
+        db.update_retention(&[db::RetentionChange {
+            stream_id,
+            new_record: req.record,
+            new_limit: req.retain_bytes,
+        }])
+        .map_err(internal)?;
+        Ok(Response::new(stream(&db.streams_by_id()[&stream_id])))
+    }
+
+    async fn delete_stream(
+        &self,
+        req: Request<proto::DeleteStreamRequest>,
+    ) -> Result<Response<proto::DeleteStreamResponse>, Status> {
+        let caller = self.caller(&req)?;
+        require_update_camera_configs(&caller)?;
+        let req = req.into_inner();
+        let stream_id = {
+            let mut db = self.db.lock();
+            let stream_id = stream_id(&db, &caller, &req.camera_uuid, req.stream)?;
+
+            // Stop recording, so the stream's streamer closes its writer.
+            let s = &db.streams_by_id()[&stream_id];
+            if s.record {
+                let new_limit = s.retain_bytes;
+                db.update_retention(&[db::RetentionChange {
+                    stream_id,
+                    new_record: false,
+                    new_limit,
+                }])
+                .map_err(internal)?;
+            }
+            stream_id
+        };
+
+        // Then delete it once its last recording has been synced.
+        let deadline = std::time::Instant::now() + DELETE_STREAM_TIMEOUT;
+        loop {
+            match self.db.lock().delete_stream(stream_id) {
+                Ok(()) => return Ok(Response::new(proto::DeleteStreamResponse {})),
+                Err(e)
+                    if e.kind() == ErrorKind::Unavailable
+                        && std::time::Instant::now() < deadline => {}
+                Err(e) => return Err(from_base_error(e)),
+            }
+            tokio::time::delay_for(std::time::Duration::from_millis(500)).await;
+        }
+    }
+
+    async fn list_recordings(
+        &self,
+        req: Request<proto::ListRecordingsRequest>,
+    ) -> Result<Response<proto::ListRecordingsResponse>, Status> {
+        let caller = self.caller(&req)?;
+        require_view_video(&caller)?;
+        let req = req.into_inner();
+        let mut time = recording::Time::min_value()..recording::Time::max_value();
+        if req.start_time_90k != 0 {
+            time.start = recording::Time(req.start_time_90k);
+        }
+        if req.end_time_90k != 0 {
+            time.end = recording::Time(req.end_time_90k);
+        }
+        let split = recording::Duration(if req.split_90k != 0 {
+            req.split_90k
+        } else {
+            i64::max_value()
+        });
+        let db = self.db.lock();
+        let stream_id = stream_id(&db, &caller, &req.camera_uuid, req.stream)?;
+        let mut out = proto::ListRecordingsResponse {
+            recordings: Vec::new(),
+        };
+        db.list_aggregated_recordings(stream_id, time, split, &mut |row| {
+            let vse = db
+                .video_sample_entries_by_id()
+                .get(&row.video_sample_entry_id)
+                .unwrap();
+            out.recordings.push(proto::Recording {
+                start_id: row.ids.start,
+                end_id: row.ids.end - 1, // inclusive, as in the JSON API.
+                open_id: row.open_id,
+                first_uncommitted: row.first_uncommitted,
+                growing: row.growing,
+                start_time_90k: row.time.start.0,
+                end_time_90k: row.time.end.0,
+                sample_file_bytes: row.sample_file_bytes,
+                video_samples: row.video_samples,
+                video_sample_entry_id: row.video_sample_entry_id,
+                width: u32::from(vse.width),
+                height: u32::from(vse.height),
+            });
+            Ok(())
+        })
+        .map_err(internal)?;
+        Ok(Response::new(out))
+    }
+
+    type SubscribeLiveStream =
+        Pin<Box<dyn Stream<Item = Result<proto::LiveSegment, Status>> + Send + Sync + 'static>>;
+
+    async fn subscribe_live(
+        &self,
+        req: Request<proto::SubscribeLiveRequest>,
+    ) -> Result<Response<Self::SubscribeLiveStream>, Status> {
+        let caller = self.caller(&req)?;
+        require_view_video(&caller)?;
+        let req = req.into_inner();
+        let (sub_tx, mut sub_rx) = futures::channel::mpsc::unbounded();
+        let (stream_id, open_id) = {
+            let mut db = self.db.lock();
+            let open_id = match db.open {
+                None => {
+                    return Err(Status::failed_precondition(
+                        "database is read-only; there are no live streams",
+                    ))
+                }
+                Some(o) => o.id,
+            };
+            let stream_id = stream_id(&db, &caller, &req.camera_uuid, req.stream)?;
+            db.watch_live(
+                stream_id,
+                Box::new(move |l| sub_tx.unbounded_send(l).is_ok()),
+            )
+            .expect("stream_id refed by camera");
+            (stream_id, open_id)
+        };
+
+        // Build each segment as it arrives. The channel's small bound keeps a slow client from
+        // causing segments to pile up in memory; when it disconnects, the send fails and
+        // dropping `sub_rx` ends the database's subscription.
+        let (mut tx, rx) = futures::channel::mpsc::channel(1);
+        let web = self.web.clone();
+        tokio::spawn(async move {
+            while let Some(live) = sub_rx.next().await {
+                let seg = async {
+                    let c = web.live_chunk(stream_id, &live)?;
+                    let mut data = Vec::new();
+                    c.mp4.append_into_vec(&mut data).await?;
+                    Ok::<_, failure::Error>(proto::LiveSegment {
+                        open_id,
+                        recording_id: live.recording,
+                        recording_start_90k: c.start.0,
+                        start_90k: live.off_90k.start,
+                        end_90k: live.off_90k.end,
+                        video_sample_entry_sha1: c.vse_id,
+                        data,
+                    })
+                }
+                .await
+                .map_err(internal);
+                let failed = seg.is_err();
+                if tx.send(seg).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(rx)))
+    }
+
+    async fn list_users(
+        &self,
+        req: Request<proto::ListUsersRequest>,
+    ) -> Result<Response<proto::ListUsersResponse>, Status> {
+        let caller = self.caller(&req)?;
+        require_admin_users(&caller)?;
+        let db = self.db.lock();
+        let users = db.users_by_id().values().map(|u| user(&db, u)).collect();
+        Ok(Response::new(proto::ListUsersResponse { users }))
+    }
+
+    async fn create_user(
+        &self,
+        req: Request<proto::CreateUserRequest>,
+    ) -> Result<Response<proto::User>, Status> {
+        let caller = self.caller(&req)?;
+        require_admin_users(&caller)?;
+        let mut db = self.db.lock();
+        let change = user_change(
+            &db,
+            req.into_inner().config,
+            db::UserChange::add_user(String::new()),
+            None,
+        )?;
+        let id = db
+            .apply_user_change(change)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?
+            .id;
+        Ok(Response::new(user(&db, &db.users_by_id()[&id])))
+    }
+
+    async fn update_user(
+        &self,
+        req: Request<proto::UpdateUserRequest>,
+    ) -> Result<Response<proto::User>, Status> {
+        let caller = self.caller(&req)?;
+        require_admin_users(&caller)?;
+        let req = req.into_inner();
+        let mut db = self.db.lock();
+        let existing = db
+            .users_by_id()
+            .get(&req.id)
+            .ok_or_else(|| Status::not_found(format!("no such user {}", req.id)))?
+            .change();
+        let change = user_change(&db, req.config, existing, Some(req.id))?;
+        db.apply_user_change(change)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        Ok(Response::new(user(&db, &db.users_by_id()[&req.id])))
+    }
+
+    async fn delete_user(
+        &self,
+        req: Request<proto::DeleteUserRequest>,
+    ) -> Result<Response<proto::DeleteUserResponse>, Status> {
+        let caller = self.caller(&req)?;
+        require_admin_users(&caller)?;
+        let id = req.get_ref().id;
+        let mut db = self.db.lock();
+        if !db.users_by_id().contains_key(&id) {
+            return Err(Status::not_found(format!("no such user {}", id)));
+        }
+        db.delete_user(id).map_err(internal)?;
+        Ok(Response::new(proto::DeleteUserResponse {}))
+    }
+}
@@ -39,8 +39,14 @@
 //! ffmpeg of course has logic to do the same thing, but unfortunately it is not exposed except
 //! through ffmpeg's own generated `.mp4` file. Extracting just this part of their `.mp4` files
 //! would be more trouble than it's worth.
+//!
+//! The reverse conversion (`to_annex_b`) is needed only to hand individual frames to an external
+//! `ffmpeg` process, as when generating thumbnails.
+//!
+//! Sources which don't go through ffmpeg (such as RTMP ingest) don't supply the dimensions, so
+//! `sps_dimensions` reads them from the sequence parameter set.
 
-use byteorder::{BigEndian, WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use failure::{bail, Error};
 
 // See ISO/IEC 14496-10 table 7-1 - NAL unit type codes, syntax element categories, and NAL unit
@@ -107,6 +113,154 @@ fn parse_annex_b_extra_data(data: &[u8]) -> Result<(&[u8], &[u8]), Error> {
     }
 }
 
+/// Reads the bits of an H.264 RBSP, as in ISO/IEC 14496-10 section 7.2.
+struct BitReader {
+    rbsp: Vec<u8>,
+    pos: usize,
+}
+
+impl BitReader {
+    /// Creates a reader of the given NAL unit's payload, removing emulation prevention bytes.
+    fn new(nal: &[u8]) -> Self {
+        let mut rbsp = Vec::with_capacity(nal.len());
+        let mut zeros = 0;
+        for &b in nal {
+            if zeros >= 2 && b == 3 {
+                zeros = 0;
+                continue;
+            }
+            zeros = if b == 0 { zeros + 1 } else { 0 };
+            rbsp.push(b);
+        }
+        BitReader { rbsp, pos: 0 }
+    }
+
+    fn bit(&mut self) -> Result<u32, Error> {
+        let b = match self.rbsp.get(self.pos / 8) {
+            None => bail!("truncated parameter set"),
+            Some(b) => b,
+        };
+        let bit = (b >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Ok(u32::from(bit))
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32, Error> {
+        let mut v = 0;
+        for _ in 0..n {
+            v = (v << 1) | self.bit()?;
+        }
+        Ok(v)
+    }
+
+    /// Reads an unsigned Exp-Golomb-coded value, as in section 9.1.
+    fn ue(&mut self) -> Result<u32, Error> {
+        let mut leading_zeros = 0;
+        while self.bit()? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                bail!("bad Exp-Golomb code");
+            }
+        }
+        Ok((1 << leading_zeros) - 1 + self.bits(leading_zeros)?)
+    }
+
+    /// Reads a signed Exp-Golomb-coded value, as in section 9.1.1.
+    fn se(&mut self) -> Result<i32, Error> {
+        let k = self.ue()?;
+        let magnitude = ((k + 1) / 2) as i32;
+        Ok(if k % 2 == 1 { magnitude } else { -magnitude })
+    }
+}
+
+/// Returns the cropped width and height described by a sequence parameter set NAL unit, as in
+/// ISO/IEC 14496-10 section 7.3.2.1.1.
+pub fn sps_dimensions(sps: &[u8]) -> Result<(u16, u16), Error> {
+    if sps.first().map(|b| b & NAL_UNIT_TYPE_MASK) != Some(NAL_UNIT_SEQ_PARAMETER_SET) {
+        bail!("not a sequence parameter set");
+    }
+    let mut r = BitReader::new(&sps[1..]);
+    let profile_idc = r.bits(8)?;
+    r.bits(16)?; // constraint flags, level_idc
+    r.ue()?; // seq_parameter_set_id
+    let mut chroma_format_idc = 1;
+    let mut separate_colour_plane = false;
+    if [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135].contains(&profile_idc) {
+        chroma_format_idc = r.ue()?;
+        if chroma_format_idc == 3 {
+            separate_colour_plane = r.bit()? == 1;
+        }
+        r.ue()?; // bit_depth_luma_minus8
+        r.ue()?; // bit_depth_chroma_minus8
+        r.bit()?; // qpprime_y_zero_transform_bypass_flag
+        if r.bit()? == 1 {
+            // seq_scaling_matrix_present_flag; skip the scaling lists, as in section 7.3.2.1.1.1.
+            for i in 0..if chroma_format_idc == 3 { 12 } else { 8 } {
+                if r.bit()? == 0 {
+                    continue;
+                }
+                let size = if i < 6 { 16 } else { 64 };
+                let (mut last, mut next) = (8, 8);
+                for _ in 0..size {
+                    if next != 0 {
+                        next = (last + r.se()? + 256) % 256;
+                    }
+                    if next != 0 {
+                        last = next;
+                    }
+                }
+            }
+        }
+    }
+    r.ue()?; // log2_max_frame_num_minus4
+    match r.ue()? {
+        0 => {
+            r.ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            r.bit()?; // delta_pic_order_always_zero_flag
+            r.se()?; // offset_for_non_ref_pic
+            r.se()?; // offset_for_top_to_bottom_field
+            for _ in 0..r.ue()? {
+                r.se()?; // offset_for_ref_frame[i]
+            }
+        }
+        _ => {}
+    }
+    r.ue()?; // max_num_ref_frames
+    r.bit()?; // gaps_in_frame_num_value_allowed_flag
+    let width_in_mbs = r.ue()? + 1;
+    let height_in_map_units = r.ue()? + 1;
+    let frame_mbs_only = r.bit()?;
+    if frame_mbs_only == 0 {
+        r.bit()?; // mb_adaptive_frame_field_flag
+    }
+    r.bit()?; // direct_8x8_inference_flag
+    let (mut crop_left, mut crop_right, mut crop_top, mut crop_bottom) = (0, 0, 0, 0);
+    if r.bit()? == 1 {
+        crop_left = r.ue()?;
+        crop_right = r.ue()?;
+        crop_top = r.ue()?;
+        crop_bottom = r.ue()?;
+    }
+
+    // Equations 7-19 through 7-22 and table 6-1.
+    let (crop_unit_x, crop_unit_y) = match (separate_colour_plane, chroma_format_idc) {
+        (true, _) | (false, 0) => (1, 2 - frame_mbs_only),
+        (false, 1) => (2, 2 * (2 - frame_mbs_only)),
+        (false, 2) => (2, 2 - frame_mbs_only),
+        _ => (1, 2 - frame_mbs_only),
+    };
+    let width = u64::from(width_in_mbs) * 16;
+    let height = u64::from(2 - frame_mbs_only) * u64::from(height_in_map_units) * 16;
+    let crop_x = u64::from(crop_unit_x) * (u64::from(crop_left) + u64::from(crop_right));
+    let crop_y = u64::from(crop_unit_y) * (u64::from(crop_top) + u64::from(crop_bottom));
+    if crop_x >= width || crop_y >= height || width > 0xffff || height > 0xffff {
+        bail!("bad dimensions in sequence parameter set");
+    }
+    Ok(((width - crop_x) as u16, (height - crop_y) as u16))
+}
+
 /// Parsed representation of ffmpeg's "extradata".
 #[derive(Debug, PartialEq, Eq)]
 pub struct ExtraData {
@@ -122,6 +276,21 @@ pub struct ExtraData {
 }
 
 impl ExtraData {
+    /// Parses an `AVCDecoderConfigurationRecord`, taking the dimensions from its first sequence
+    /// parameter set.
+    pub fn from_avc_decoder_config(config: &[u8]) -> Result<ExtraData, Error> {
+        if config.len() < 8 || config[5] & 0x1f == 0 {
+            bail!("AVCDecoderConfiguration has no sequence parameter set");
+        }
+        let sps_len = BigEndian::read_u16(&config[6..8]) as usize;
+        let sps = match config.get(8..8 + sps_len) {
+            None => bail!("truncated AVCDecoderConfiguration"),
+            Some(s) => s,
+        };
+        let (width, height) = sps_dimensions(sps)?;
+        ExtraData::parse(config, width, height)
+    }
+
     /// Parses "extradata" from ffmpeg. This data may be in either Annex B format or AVC format.
     pub fn parse(extradata: &[u8], width: u16, height: u16) -> Result<ExtraData, Error> {
         let mut sps_and_pps = None;
@@ -270,6 +439,61 @@ pub fn transform_sample_data(annexb_sample: &[u8], avc_sample: &mut Vec<u8>) ->
     Ok(())
 }
 
+/// Transforms a sample from AVC format to Annex B format, prefixed with the SPS and PPS from
+/// `sample_entry` (as returned by `ExtraData::parse`) so that it's decodable on its own if it's a
+/// key frame.
+pub fn to_annex_b(sample_entry: &[u8], avc_sample: &[u8], out: &mut Vec<u8>) -> Result<(), Error> {
+    // The AVCDecoderConfiguration follows the 86-byte VisualSampleEntry and the avcC box header;
+    // see `ExtraData::parse`.
+    if sample_entry.len() < 94 + 6 || &sample_entry[90..94] != b"avcC" {
+        bail!("sample entry has no avcC box");
+    }
+    let config = &sample_entry[94..];
+    let length_size = usize::from(config[4] & 0x3) + 1;
+    out.clear();
+    out.reserve(avc_sample.len() + config.len());
+    let mut p = &config[5..];
+    for &count_mask in &[0x1f, 0xff] {
+        // The # of SPSs, then the # of PPSs, each followed by the length-prefixed NAL units.
+        if p.is_empty() {
+            bail!("truncated AVCDecoderConfiguration");
+        }
+        let count = p[0] & count_mask;
+        p = &p[1..];
+        for _ in 0..count {
+            let len = match p.len() {
+                l if l >= 2 => usize::from(BigEndian::read_u16(p)),
+                _ => bail!("truncated AVCDecoderConfiguration"),
+            };
+            if p.len() < 2 + len {
+                bail!("truncated AVCDecoderConfiguration");
+            }
+            out.extend_from_slice(b"\x00\x00\x00\x01");
+            out.extend_from_slice(&p[2..2 + len]);
+            p = &p[2 + len..];
+        }
+    }
+    let mut s = avc_sample;
+    while !s.is_empty() {
+        if s.len() < length_size {
+            bail!("truncated NAL unit length");
+        }
+        let len = BigEndian::read_uint(s, length_size) as usize;
+        s = &s[length_size..];
+        if s.len() < len {
+            bail!(
+                "NAL unit length {} exceeds remaining {} bytes",
+                len,
+                s.len()
+            );
+        }
+        out.extend_from_slice(b"\x00\x00\x00\x01");
+        out.extend_from_slice(&s[..len]);
+        s = &s[len..];
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use db::testutil;
@@ -322,6 +546,18 @@ mod tests {
         assert_eq!(e.rfc6381_codec, "avc1.4d001f");
     }
 
+    #[test]
+    fn test_sps_dimensions() {
+        testutil::init();
+        assert_eq!(
+            super::sps_dimensions(&ANNEX_B_TEST_INPUT[4..27]).unwrap(),
+            (1280, 720)
+        );
+        super::sps_dimensions(&ANNEX_B_TEST_INPUT[31..]).unwrap_err(); // a PPS
+        let e = super::ExtraData::from_avc_decoder_config(&AVC_DECODER_CONFIG_TEST_INPUT).unwrap();
+        assert_eq!(&e.sample_entry[..], &TEST_OUTPUT[..]);
+    }
+
     #[test]
     fn test_sample_entry_from_annex_b() {
         testutil::init();
@@ -353,5 +589,10 @@ mod tests {
         let mut out = Vec::new();
         super::transform_sample_data(&INPUT, &mut out).unwrap();
         assert_eq!(&out[..], &EXPECTED_OUTPUT[..]);
+
+        // Transforming back should prepend the SPS and PPS from the sample entry.
+        super::to_annex_b(&TEST_OUTPUT, &EXPECTED_OUTPUT, &mut out).unwrap();
+        assert_eq!(&out[..35], &ANNEX_B_TEST_INPUT[..]);
+        assert_eq!(&out[35..], &INPUT[..]);
     }
 }
@@ -0,0 +1,259 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Per-stream liveness tracking.
+//!
+//! Each streamer reports its connection state and every frame it receives to a `Tracker`, which
+//! keeps the stream's `Liveness` (last frame time, reconnect count, recent bitrate and frame rate)
+//! in a `Monitor` shared with the web server and the webhook dispatcher. `/api/health` reports it,
+//! and the dispatcher raises `streamSilent` and `streamResumed` notifications when a stream goes
+//! without frames for longer than the `health_silence_sec` config key (default 10) and recovers.
+//!
+//! Unlike `Stream::is_recording`, this notices a camera which holds its session open but stops
+//! sending frames, which otherwise goes unnoticed until the RTSP session times out.
+
+use db::recording;
+use failure::{format_err, Error};
+use fnv::FnvHashMap;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::Arc;
+use time::{Duration, Timespec};
+
+const DEFAULT_SILENCE_SEC: i64 = 10;
+
+/// The span of recent frames from which the bitrate and frame rate are calculated.
+const RATE_WINDOW_SEC: i64 = 5;
+
+/// The liveness of a single stream, as last reported by its streamer.
+#[derive(Clone, Debug)]
+pub struct Liveness {
+    /// When the streamer started, on the monotonic clock.
+    pub since: Timespec,
+
+    /// True iff the streamer has an open session with the camera.
+    pub connected: bool,
+
+    /// When the last frame arrived, on the monotonic clock and as a wall time.
+    pub last_frame: Option<(Timespec, recording::Time)>,
+
+    /// The number of sessions opened after the first.
+    pub reconnects: u64,
+
+    /// The bitrate and frame rate over the last `RATE_WINDOW_SEC`, or zero when disconnected.
+    pub bitrate_bps: u64,
+    pub fps: f32,
+}
+
+impl Liveness {
+    /// Returns how long the stream has gone without a frame as of `now` (on the monotonic clock).
+    /// A stream which has never had a frame is silent since its streamer started.
+    pub fn silent_for(&self, now: Timespec) -> Duration {
+        now - self.last_frame.map(|(t, _)| t).unwrap_or(self.since)
+    }
+}
+
+/// The liveness of all streams with a streamer, shared between the streamers and observers.
+#[derive(Clone)]
+pub struct Monitor {
+    streams: Arc<Mutex<FnvHashMap<i32, Liveness>>>,
+    silence_after: Duration,
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Monitor {
+            streams: Arc::default(),
+            silence_after: Duration::seconds(DEFAULT_SILENCE_SEC),
+        }
+    }
+}
+
+impl Monitor {
+    /// Returns a monitor with the silence threshold configured in the database.
+    pub fn new(l: &db::LockedDatabase) -> Result<Self, Error> {
+        let mut m = Monitor::default();
+        if let Some(s) = l.get_config("health_silence_sec")? {
+            let sec = i64::from_str(&s)
+                .ok()
+                .filter(|&s| s > 0)
+                .ok_or_else(|| format_err!("bad health_silence_sec {:?}", s))?;
+            m.silence_after = Duration::seconds(sec);
+        }
+        Ok(m)
+    }
+
+    /// How long a stream must go without frames to be considered silent.
+    pub fn silence_after(&self) -> Duration {
+        self.silence_after
+    }
+
+    /// Returns a tracker for the given stream, which starts being monitored at `now`.
+    pub fn tracker(&self, stream_id: i32, now: Timespec) -> Tracker {
+        self.streams.lock().insert(
+            stream_id,
+            Liveness {
+                since: now,
+                connected: false,
+                last_frame: None,
+                reconnects: 0,
+                bitrate_bps: 0,
+                fps: 0.,
+            },
+        );
+        Tracker {
+            monitor: self.clone(),
+            stream_id,
+            sessions: 0,
+            window: VecDeque::new(),
+            window_bytes: 0,
+        }
+    }
+
+    /// Returns a snapshot of all monitored streams, by stream id.
+    pub fn streams(&self) -> FnvHashMap<i32, Liveness> {
+        self.streams.lock().clone()
+    }
+}
+
+/// Updates a single stream's `Liveness`. Owned by the stream's streamer.
+pub struct Tracker {
+    monitor: Monitor,
+    stream_id: i32,
+    sessions: u64,
+
+    /// Arrival times (on the monotonic clock) and sizes of frames within `RATE_WINDOW_SEC` of
+    /// the latest one.
+    window: VecDeque<(Timespec, usize)>,
+    window_bytes: u64,
+}
+
+impl Tracker {
+    fn update<F: FnOnce(&mut Liveness)>(&self, f: F) {
+        if let Some(l) = self.monitor.streams.lock().get_mut(&self.stream_id) {
+            f(l);
+        }
+    }
+
+    /// Notes that a session with the camera was opened.
+    pub fn connected(&mut self) {
+        self.sessions += 1;
+        self.window.clear();
+        self.window_bytes = 0;
+        let reconnects = self.sessions - 1;
+        self.update(|l| {
+            l.connected = true;
+            l.reconnects = reconnects;
+        });
+    }
+
+    /// Notes that the session with the camera ended or couldn't be opened.
+    pub fn disconnected(&mut self) {
+        self.window.clear();
+        self.window_bytes = 0;
+        self.update(|l| {
+            l.connected = false;
+            l.bitrate_bps = 0;
+            l.fps = 0.;
+        });
+    }
+
+    /// Notes a frame of `bytes` bytes which arrived at `now` (on the monotonic clock), `time`.
+    pub fn frame(&mut self, now: Timespec, time: recording::Time, bytes: usize) {
+        self.window.push_back((now, bytes));
+        self.window_bytes += bytes as u64;
+        let horizon = now - Duration::seconds(RATE_WINDOW_SEC);
+        while self.window.front().map(|&(t, _)| t < horizon) == Some(true) {
+            let (_, b) = self.window.pop_front().unwrap();
+            self.window_bytes -= b as u64;
+        }
+
+        // Rates are over the span between the first and last frames in the window, so they
+        // exclude the first frame, which arrived at the start of the span.
+        let (first_time, first_bytes) = *self.window.front().unwrap();
+        let span_nanos = (now - first_time).num_nanoseconds().unwrap_or(0);
+        let (bitrate_bps, fps) = if span_nanos > 0 {
+            let bits = (self.window_bytes - first_bytes as u64) * 8;
+            (
+                (u128::from(bits) * 1_000_000_000 / span_nanos as u128) as u64,
+                ((self.window.len() - 1) as f64 * 1e9 / span_nanos as f64) as f32,
+            )
+        } else {
+            (0, 0.)
+        };
+        self.update(|l| {
+            l.last_frame = Some((now, time));
+            l.bitrate_bps = bitrate_bps;
+            l.fps = fps;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracker() {
+        let m = Monitor::default();
+        let start = Timespec::new(1_000, 0);
+        let mut t = m.tracker(1, start);
+        assert_eq!(
+            m.streams()[&1].silent_for(start + Duration::seconds(3)),
+            Duration::seconds(3)
+        );
+
+        // 10 frames per second of 1,000 bytes each for 10 seconds.
+        t.connected();
+        let mut now = start;
+        for _ in 0..100 {
+            now = now + Duration::milliseconds(100);
+            t.frame(now, recording::Time::new(now), 1_000);
+        }
+        let l = m.streams()[&1].clone();
+        assert!(l.connected);
+        assert_eq!(l.reconnects, 0);
+        assert_eq!(l.bitrate_bps, 80_000);
+        assert!((l.fps - 10.).abs() < 0.01, "fps={}", l.fps);
+        assert_eq!(l.last_frame.map(|(t, _)| t), Some(now));
+        assert_eq!(
+            l.silent_for(now + Duration::seconds(2)),
+            Duration::seconds(2)
+        );
+
+        t.disconnected();
+        t.connected();
+        let l = m.streams()[&1].clone();
+        assert_eq!(l.reconnects, 1);
+        assert_eq!(l.bitrate_bps, 0);
+        assert_eq!(l.last_frame.map(|(t, _)| t), Some(now));
+    }
+}
@@ -0,0 +1,270 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! HTTP Live Streaming (HLS) relay of live streams, so that any number of viewers share the one
+//! upstream connection each streamer already holds to its camera, rather than overloading
+//! cameras which allow few clients.
+//!
+//! `GET /api/cameras/<uuid>/<type>/live.m3u8` returns a playlist of the stream's most recent
+//! live segments (each running from one key frame to the next), which are served as fragmented
+//! `.m4s` media segments from `live/<seq>.m4s` with `/api/init/<sha1>.mp4` initialization
+//! segments. The relay subscribes to a stream's live segments when its playlist is first
+//! requested and unsubscribes once it goes unrequested for `IDLE`, so unwatched streams cost
+//! nothing. The segments' video is read back from the sample files being recorded.
+
+use failure::Error;
+use fnv::FnvHashMap;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How many recent segments a playlist lists. With the typical one key frame per second or
+/// two, this is several seconds of video, enough for players to buffer against network jitter.
+const SEGMENTS: usize = 6;
+
+/// How long after its playlist was last requested to stop following a stream.
+const IDLE: Duration = Duration::from_secs(30);
+
+/// The target duration to advertise before a stream has sent any segments.
+const DEFAULT_TARGET_DURATION_SEC: i32 = 2;
+
+/// A live segment as listed in a playlist.
+#[derive(Clone, Debug)]
+pub struct Segment {
+    /// The media sequence number, unique within the stream for the life of the process.
+    pub seq: u64,
+    pub live: db::LiveSegment,
+
+    /// True if this segment starts a new recording, whose timestamps and possibly video sample
+    /// entry don't continue from the previous segment's.
+    pub discontinuity: bool,
+}
+
+/// The current contents of a stream's playlist.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    /// The number of discontinuities in segments which have already left the playlist.
+    pub discontinuity_seq: u64,
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Default)]
+struct Playlist {
+    /// True iff a `watch_live` callback is registered for this stream.
+    subscribed: bool,
+    last_request: Option<Instant>,
+    next_seq: u64,
+    discontinuity_seq: u64,
+    segments: VecDeque<Segment>,
+}
+
+impl Playlist {
+    fn push(&mut self, live: db::LiveSegment) {
+        let discontinuity = match self.segments.back() {
+            Some(prev) => prev.live.recording != live.recording,
+            None => true,
+        };
+        self.segments.push_back(Segment {
+            seq: self.next_seq,
+            live,
+            discontinuity,
+        });
+        self.next_seq += 1;
+        while self.segments.len() > SEGMENTS {
+            if self.segments.pop_front().unwrap().discontinuity {
+                self.discontinuity_seq += 1;
+            }
+        }
+    }
+
+    /// Forgets the segments, noting a discontinuity before whatever comes next.
+    fn clear(&mut self) {
+        for s in self.segments.drain(..) {
+            if s.discontinuity {
+                self.discontinuity_seq += 1;
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            discontinuity_seq: self.discontinuity_seq,
+            segments: self.segments.iter().cloned().collect(),
+        }
+    }
+}
+
+/// Follows the live segments of streams whose playlists are being requested.
+#[derive(Clone, Default)]
+pub struct Relay(Arc<Mutex<FnvHashMap<i32, Playlist>>>);
+
+impl Relay {
+    /// Returns the stream's current playlist, starting to follow the stream if necessary.
+    /// `db` must not be locked.
+    pub fn playlist(&self, db: &db::Database, stream_id: i32) -> Result<Snapshot, Error> {
+        let (snapshot, subscribe) = {
+            let mut l = self.0.lock();
+            let p = l.entry(stream_id).or_default();
+            p.last_request = Some(Instant::now());
+            let subscribe = !p.subscribed;
+            p.subscribed = true;
+            (p.snapshot(), subscribe)
+        };
+        if subscribe {
+            // The callback runs with the database locked, so this mutex must never be held
+            // while locking the database.
+            let relay = self.0.clone();
+            let r = db.lock().watch_live(
+                stream_id,
+                Box::new(move |live| {
+                    let mut l = relay.lock();
+                    let p = l.get_mut(&stream_id).unwrap();
+                    let idle = p.last_request.map(|t| t.elapsed() > IDLE).unwrap_or(true);
+                    if idle {
+                        p.subscribed = false;
+                        p.clear();
+                        return false;
+                    }
+                    p.push(live);
+                    true
+                }),
+            );
+            if let Err(e) = r {
+                self.0.lock().get_mut(&stream_id).unwrap().subscribed = false;
+                return Err(e);
+            }
+        }
+        Ok(snapshot)
+    }
+
+    /// Returns the given segment of the stream, if it's still in the playlist.
+    pub fn segment(&self, stream_id: i32, seq: u64) -> Option<db::LiveSegment> {
+        let l = self.0.lock();
+        let p = l.get(&stream_id)?;
+        p.segments
+            .iter()
+            .find(|s| s.seq == seq)
+            .map(|s| s.live.clone())
+    }
+}
+
+/// Renders a playlist as an `application/vnd.apple.mpegurl` document. `init_sha1s` maps the
+/// snapshot's recording ids to the hex SHA-1s of their video sample entries.
+pub fn render(s: &Snapshot, init_sha1s: &FnvHashMap<i32, String>) -> String {
+    let target_duration = s
+        .segments
+        .iter()
+        .map(|s| (s.live.off_90k.end - s.live.off_90k.start + 89_999) / 90_000)
+        .max()
+        .unwrap_or(DEFAULT_TARGET_DURATION_SEC);
+    let mut out = String::new();
+    writeln!(
+        &mut out,
+        "#EXTM3U\n\
+         #EXT-X-VERSION:7\n\
+         #EXT-X-TARGETDURATION:{}\n\
+         #EXT-X-MEDIA-SEQUENCE:{}\n\
+         #EXT-X-DISCONTINUITY-SEQUENCE:{}",
+        target_duration,
+        s.segments.first().map(|s| s.seq).unwrap_or(0),
+        s.discontinuity_seq
+    )
+    .unwrap();
+    for (i, seg) in s.segments.iter().enumerate() {
+        if seg.discontinuity {
+            if i > 0 {
+                out.push_str("#EXT-X-DISCONTINUITY\n");
+            }
+            if let Some(sha1) = init_sha1s.get(&seg.live.recording) {
+                // Relative to .../<uuid>/<type>/live.m3u8, this is /api/init/<sha1>.mp4.
+                writeln!(&mut out, "#EXT-X-MAP:URI=\"../../../init/{}.mp4\"", sha1).unwrap();
+            }
+        }
+        writeln!(
+            &mut out,
+            "#EXTINF:{:.3},\nlive/{}.m4s",
+            f64::from(seg.live.off_90k.end - seg.live.off_90k.start) / 90_000.,
+            seg.seq
+        )
+        .unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn live(recording: i32, start: i32, end: i32) -> db::LiveSegment {
+        db::LiveSegment {
+            recording,
+            off_90k: start..end,
+        }
+    }
+
+    #[test]
+    fn playlist() {
+        let mut p = Playlist::default();
+        p.push(live(1, 0, 90_000));
+        for i in 0..SEGMENTS as i32 {
+            p.push(live(2, i * 90_000, (i + 1) * 90_000));
+        }
+
+        // The first segment of recording 1 has been dropped, so only recording 2 remains; its
+        // first segment is a discontinuity.
+        let s = p.snapshot();
+        assert_eq!(s.discontinuity_seq, 1);
+        assert_eq!(s.segments.len(), SEGMENTS);
+        assert_eq!(s.segments[0].seq, 1);
+        assert!(s.segments[0].discontinuity);
+        assert!(!s.segments[1].discontinuity);
+
+        let mut sha1s = FnvHashMap::default();
+        sha1s.insert(2, "00ff".to_owned());
+        let rendered = render(&s, &sha1s);
+        assert!(rendered.starts_with(
+            "#EXTM3U\n\
+             #EXT-X-VERSION:7\n\
+             #EXT-X-TARGETDURATION:1\n\
+             #EXT-X-MEDIA-SEQUENCE:1\n\
+             #EXT-X-DISCONTINUITY-SEQUENCE:1\n\
+             #EXT-X-MAP:URI=\"../../../init/00ff.mp4\"\n\
+             #EXTINF:1.000,\n\
+             live/1.m4s\n"
+        ));
+
+        p.clear();
+        assert_eq!(p.snapshot().discontinuity_seq, 2);
+        p.push(live(3, 0, 90_000));
+        assert_eq!(p.snapshot().segments[0].seq, SEGMENTS as u64 + 1);
+    }
+}
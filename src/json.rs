@@ -32,8 +32,9 @@ use db::auth::SessionHash;
 use failure::{format_err, Error};
 use serde::ser::{Error as _, SerializeMap, SerializeSeq, Serializer};
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ops::Not;
+use std::str::FromStr;
 use uuid::Uuid;
 
 #[derive(Serialize)]
@@ -43,8 +44,14 @@ pub struct TopLevel<'a> {
 
     // Use a custom serializer which presents the map's values as a sequence and includes the
     // "days" and "camera_configs" attributes or not, according to the respective bools.
+    // If the set is present, only the cameras it names are included.
     #[serde(serialize_with = "TopLevel::serialize_cameras")]
-    pub cameras: (&'a db::LockedDatabase, bool, bool),
+    pub cameras: (
+        &'a db::LockedDatabase,
+        bool,
+        bool,
+        Option<&'a BTreeSet<i32>>,
+    ),
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub session: Option<Session>,
@@ -84,6 +91,7 @@ pub struct Camera<'a> {
     pub uuid: Uuid,
     pub short_name: &'a str,
     pub description: &'a str,
+    pub ptz: bool,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub config: Option<CameraConfig<'a>>,
@@ -110,6 +118,9 @@ pub struct Stream {
     pub total_sample_file_bytes: i64,
     pub fs_bytes: i64,
 
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub privacy_mask: Vec<Vec<[f32; 2]>>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(serialize_with = "Stream::serialize_days")]
     pub days: Option<BTreeMap<db::StreamDayKey, db::StreamDayValue>>,
@@ -156,12 +167,1028 @@ pub struct PostSignalsRequest {
     pub rel_end_time_90k: Option<i64>,
 }
 
+/// A PTZ (pan-tilt-zoom) command, as in `POST /api/cameras/<uuid>/ptz`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum PostPtzRequest {
+    ContinuousMove {
+        #[serde(default)]
+        pan: f32,
+        #[serde(default)]
+        tilt: f32,
+        #[serde(default)]
+        zoom: f32,
+    },
+    Stop,
+    GotoPreset {
+        preset: String,
+    },
+}
+
+/// A request to replace a stream's privacy mask, as in
+/// `POST /api/cameras/<uuid>/<stream>/privacyMask`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostPrivacyMaskRequest {
+    /// Polygons in normalized coordinates; see `db::mask::Mask`.
+    pub polygons: Vec<Vec<[f32; 2]>>,
+}
+
+/// A request to change a camera's privacy mode, as in `POST /api/cameras/<uuid>/privacy`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostPrivacyRequest {
+    pub enabled: bool,
+
+    /// For how long to stay in the requested mode before reverting; indefinitely if absent.
+    pub duration_sec: Option<i64>,
+}
+
+/// A camera's privacy mode, as in `GET /api/cameras/<uuid>/privacy`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Privacy {
+    pub enabled: bool,
+
+    /// When the mode is next scheduled to change, if ever.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until_90k: Option<i64>,
+}
+
+/// A request to protect recent recordings against deletion, as in `POST /api/protect`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostProtectRequest {
+    /// The cameras to protect; all cameras if absent.
+    pub cameras: Option<Vec<Uuid>>,
+
+    pub start_time_90k: Option<i64>,
+    pub duration_90k: Option<i64>,
+
+    #[serde(default)]
+    pub unprotect: bool,
+
+    #[serde(default)]
+    pub upload: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostProtectResponse {
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub recordings: usize,
+}
+
+/// The response to `DELETE /api/cameras/<uuid>/<stream>/recordings`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteRecordingsResponse {
+    pub recordings: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostNoteRequest {
+    /// The camera this note is about, if any.
+    pub camera_uuid: Option<Uuid>,
+
+    pub start_time_90k: Option<i64>,
+    pub end_time_90k: Option<i64>,
+    pub text: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostNoteResponse {
+    pub id: i64,
+}
+
+/// A request to add a bookmark, as in `POST /api/bookmarks`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostBookmarkRequest {
+    /// The camera whose recordings to protect; all cameras if absent.
+    pub camera_uuid: Option<Uuid>,
+
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub label: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostBookmarkResponse {
+    pub id: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bookmarks {
+    pub bookmarks: Vec<Bookmark>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bookmark {
+    pub id: i64,
+    pub camera_uuid: Option<Uuid>,
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub label: String,
+    pub creation_time_sec: i64,
+    pub author: Option<String>,
+}
+
+/// A request to export clips, as in `POST /api/exports`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostExportRequest {
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+
+    /// The streams to export, each as `<camera uuid>/<main|sub>`.
+    pub streams: Vec<String>,
+
+    /// Whether to sign the export's manifest; see `evidence.rs`.
+    #[serde(default)]
+    pub sign: bool,
+
+    /// Whether to black out streams' privacy masks by transcoding.
+    #[serde(default)]
+    pub mask: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostExportResponse {
+    pub id: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Exports {
+    pub exports: Vec<Export>,
+}
+
+/// An export job, as in `GET /api/exports/<id>`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Export {
+    pub id: i64,
+    pub state: &'static str,
+    pub creation_time_sec: i64,
+    pub author: Option<String>,
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub streams: Vec<String>,
+    pub sign: bool,
+    pub mask: bool,
+    pub progress_bytes: i64,
+    pub total_bytes: Option<i64>,
+    pub file_name: Option<String>,
+    pub error: Option<String>,
+    pub finish_time_sec: Option<i64>,
+}
+
+/// The `manifest.json` within a multi-stream export's `.zip`, as written by `export.rs`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportManifest {
+    pub export_id: i64,
+
+    /// The server software which wrote the export, as `moonfire-nvr <version>`.
+    pub generator: String,
+    pub creation_time_sec: i64,
+    pub author: Option<String>,
+
+    /// When the export finished, in seconds since epoch.
+    pub generated_time_sec: i64,
+
+    /// The requested range, in 90 kHz units and as RFC 3339 UTC timestamps.
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub start_time: String,
+    pub end_time: String,
+
+    pub files: Vec<ExportManifestFile>,
+}
+
+/// A `.mp4` file within a multi-stream export.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportManifestFile {
+    pub name: String,
+    pub camera_uuid: Uuid,
+    pub camera_short_name: String,
+    pub stream: &'static str,
+
+    /// The range the file actually covers, which may be less than requested.
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub start_time: String,
+    pub end_time: String,
+
+    pub bytes: u64,
+
+    /// The hex-encoded SHA-256 hash of the file.
+    pub sha256: String,
+
+    /// True if the file was transcoded to black out the stream's privacy mask, so its video
+    /// isn't exactly as recorded.
+    #[serde(skip_serializing_if = "Not::not")]
+    pub masked: bool,
+
+    pub recordings: Vec<ExportManifestRecording>,
+}
+
+/// The portion of a recording included in an export.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportManifestRecording {
+    pub id: i32,
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+}
+
+/// The `manifest.json.sig` within a signed export's `.zip`, as written by `evidence::Signer`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportSignature {
+    /// Currently always `Ed25519`.
+    pub algorithm: String,
+
+    /// The base64-encoded public key.
+    pub public_key: String,
+
+    /// The base64-encoded signature of the exact bytes of `manifest.json`.
+    pub signature: String,
+}
+
+/// A request to check a signed export, as in `POST /api/exports/verify`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyExportRequest {
+    /// The contents of `manifest.json`.
+    pub manifest: String,
+    pub signature: ExportSignature,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyExportResponse {
+    /// Whether the signature is valid under the public key it names.
+    pub valid: bool,
+
+    /// Whether that public key is this server's.
+    pub signed_by_server: bool,
+}
+
+/// A user's notification policy, as in `/api/user/notifications`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationPolicy {
+    pub webhook_url: Option<String>,
+    pub quiet_hours: Option<QuietHours>,
+    pub offline_after_sec: Option<i64>,
+    pub escalation: Option<Escalation>,
+}
+
+/// Quiet hours, as `HH:MM` strings in the server's local time zone.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuietHours {
+    pub start: String,
+    pub end: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Escalation {
+    pub after_sec: i64,
+    pub email: String,
+}
+
+impl From<db::notify::NotificationPolicy> for NotificationPolicy {
+    fn from(p: db::notify::NotificationPolicy) -> Self {
+        let fmt = |m: u16| format!("{:02}:{:02}", m / 60, m % 60);
+        NotificationPolicy {
+            webhook_url: p.webhook_url,
+            quiet_hours: p.quiet_hours.map(|q| QuietHours {
+                start: fmt(q.start_min),
+                end: fmt(q.end_min),
+            }),
+            offline_after_sec: p.offline_after_sec,
+            escalation: p.escalation.map(|e| Escalation {
+                after_sec: e.after_sec,
+                email: e.email,
+            }),
+        }
+    }
+}
+
+/// A user's sessions, as in `/api/user/sessions`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserSessions {
+    pub sessions: Vec<UserSession>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserSession {
+    pub id: i32,
+    pub description: Option<String>,
+    pub creation_time_sec: Option<i64>,
+    pub creation_user_agent: Option<String>,
+    pub creation_peer_addr: Option<String>,
+    pub last_use_time_sec: Option<i64>,
+    pub last_use_user_agent: Option<String>,
+    pub last_use_peer_addr: Option<String>,
+    pub use_count: i32,
+
+    /// True iff this is the session which made the request.
+    pub current: bool,
+}
+
+impl UserSession {
+    pub fn new(s: db::auth::ListedSession, current: Option<i32>) -> Self {
+        let ua = |r: &db::auth::Request| {
+            r.user_agent
+                .as_ref()
+                .map(|u| String::from_utf8_lossy(u).into_owned())
+        };
+        UserSession {
+            id: s.id,
+            current: current == Some(s.id),
+            creation_time_sec: s.creation.when_sec,
+            creation_user_agent: ua(&s.creation),
+            creation_peer_addr: s.creation.addr.map(|a| a.to_string()),
+            last_use_time_sec: s.last_use.when_sec,
+            last_use_user_agent: ua(&s.last_use),
+            last_use_peer_addr: s.last_use.addr.map(|a| a.to_string()),
+            use_count: s.use_count,
+            description: s.description,
+        }
+    }
+}
+
+impl NotificationPolicy {
+    /// Converts to the database representation, returning an error message on invalid input.
+    pub fn into_db(self) -> Result<db::notify::NotificationPolicy, String> {
+        fn parse(hhmm: &str) -> Result<u16, String> {
+            let mut parts = hhmm.splitn(2, ':');
+            let h = parts.next().and_then(|h| u16::from_str(h).ok());
+            let m = parts.next().and_then(|m| u16::from_str(m).ok());
+            match (h, m) {
+                (Some(h), Some(m)) if h < 24 && m < 60 => Ok(h * 60 + m),
+                _ => Err(format!("bad time {:?}; expected HH:MM", hhmm)),
+            }
+        }
+        if let Some(ref u) = self.webhook_url {
+            url::Url::parse(u).map_err(|e| format!("bad webhookUrl {:?}: {}", u, e))?;
+        }
+        Ok(db::notify::NotificationPolicy {
+            webhook_url: self.webhook_url,
+            quiet_hours: match self.quiet_hours {
+                None => None,
+                Some(q) => Some(db::notify::QuietHours {
+                    start_min: parse(&q.start)?,
+                    end_min: parse(&q.end)?,
+                }),
+            },
+            offline_after_sec: self.offline_after_sec,
+            escalation: self.escalation.map(|e| db::notify::Escalation {
+                after_sec: e.after_sec,
+                email: e.email,
+            }),
+        })
+    }
+}
+
+/// The response to `GET /api/heatmap`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Heatmap {
+    pub bucket_duration_90k: i64,
+    pub buckets: Vec<HeatmapBucket>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeatmapBucket {
+    pub camera_uuid: Uuid,
+    pub stream: &'static str,
+    pub start_time_90k: i64,
+    pub views: i64,
+}
+
+/// The response to `GET /api/timeline`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Timeline {
+    pub bucket_duration_90k: i64,
+    pub buckets: Vec<TimelineBucket>,
+    pub signal_changes: Vec<TimelineSignalChange>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelineBucket {
+    pub camera_uuid: Uuid,
+    pub stream: &'static str,
+    pub start_time_90k: i64,
+    pub recordings: i64,
+    pub duration_90k: i64,
+    pub sample_file_bytes: i64,
+    pub detections: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelineSignalChange {
+    pub signal_id: u32,
+    pub time_90k: i64,
+    pub state: u16,
+}
+
+/// The response to `GET /api/playback`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Playback {
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub streams: Vec<PlaybackStream>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaybackStream {
+    pub camera_uuid: Uuid,
+    pub stream: &'static str,
+    pub segments: Vec<PlaybackSegment>,
+}
+
+/// The response to `GET /api/cameras/<uuid>/<stream>/gaps`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Gaps {
+    pub total_duration_90k: i64,
+    pub gaps: Vec<Gap>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Gap {
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub reason: &'static str,
+}
+
+/// The response to `GET /api/cameras/<uuid>/coverage`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Coverage {
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub segments: Vec<PlaybackSegment>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaybackSegment {
+    /// The stream, `main` or `sub`, when segments of both are listed together.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<&'static str>,
+
+    /// The start and end of the segment on the local clock.
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub local_time_delta_90k: i64,
+
+    /// The `s` parameter of `view.mp4` or `view.m4s` for this segment.
+    pub s: String,
+    pub video_sample_entry_sha1: String,
+    pub width: u16,
+    pub height: u16,
+    pub bitrate_bps: i64,
+}
+
+/// The response to `GET /api/sampleFiles`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SampleFiles<'a> {
+    pub sample_files: Vec<SampleFile<'a>>,
+}
+
+/// A committed recording's sample file, as listed by `GET /api/sampleFiles` and
+/// `moonfire-nvr sample-files`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SampleFile<'a> {
+    /// The file's name within its sample file directory.
+    pub name: String,
+    pub camera_uuid: Uuid,
+    pub camera_short_name: &'a str,
+    pub stream: &'static str,
+    pub recording_id: i32,
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub sample_file_bytes: i32,
+
+    #[serde(skip_serializing_if = "Not::not")]
+    pub encrypted: bool,
+
+    /// The hex-encoded BLAKE3 hash of the file as written, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blake3: Option<String>,
+
+    /// The hex-encoded SHA-1 hash of the file, for recordings written by older versions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_time_delta_90k: Option<i64>,
+
+    /// The camera's RTP timestamp of the first frame, in units of `rtp_clock_rate`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtp_timestamp: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtp_clock_rate: Option<i32>,
+}
+
+impl<'a> SampleFile<'a> {
+    /// Describes `row`, or returns `None` if its stream no longer exists.
+    pub fn new(db: &'a db::LockedDatabase, row: &db::SampleFileRow) -> Option<Self> {
+        let s = db.streams_by_id().get(&row.id.stream())?;
+        let c = db.cameras_by_id().get(&s.camera_id)?;
+        let (blake3, sha1) = match row.digest {
+            Some(db::scrub::Digest::Blake3(ref d)) => (Some(base::strutil::hex(d)), None),
+            Some(db::scrub::Digest::Sha1(ref d)) => (None, Some(base::strutil::hex(d))),
+            None => (None, None),
+        };
+        Some(SampleFile {
+            name: format!("{:016x}", row.id.0),
+            camera_uuid: c.uuid,
+            camera_short_name: &c.short_name,
+            stream: s.type_.as_str(),
+            recording_id: row.id.recording(),
+            start_time_90k: row.start.0,
+            end_time_90k: row.start.0 + row.duration_90k as i64,
+            sample_file_bytes: row.sample_file_bytes,
+            encrypted: (row.flags & db::RecordingFlags::Encrypted as i32) != 0,
+            blake3,
+            sha1,
+            local_time_delta_90k: row.local_time_delta_90k,
+            rtp_timestamp: row.rtp_timestamp.map(|(ts, _)| ts),
+            rtp_clock_rate: row.rtp_timestamp.map(|(_, rate)| rate),
+        })
+    }
+}
+
+/// The response to `GET /api/cameras/<uuid>/<stream>/replica`, as read by another instance's
+/// `replication::Puller`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Replica {
+    pub recordings: Vec<ReplicaRecording>,
+
+    /// The video sample entries used by `recordings`, keyed by id.
+    pub video_sample_entries: BTreeMap<i32, ReplicaVideoSampleEntry>,
+}
+
+/// A committed recording's database row, enough to write it again elsewhere.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplicaRecording {
+    pub id: i32,
+    pub start_time_90k: i64,
+    pub duration_90k: i32,
+    pub sample_file_bytes: i32,
+    pub video_sample_entry_id: i32,
+
+    /// The base64-encoded video index, as in `recording_playback.video_index` in `schema.sql`.
+    pub video_index: String,
+
+    /// The hex-encoded BLAKE3 hash of the (decrypted) sample file, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blake3: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplicaVideoSampleEntry {
+    pub width: u16,
+    pub height: u16,
+    pub rfc6381_codec: String,
+
+    /// The base64-encoded ISO/IEC 14496-12 `VisualSampleEntry` box.
+    pub data: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoder: Option<String>,
+}
+
+/// The response to `GET /api/cameras/<uuid>/<stream>/retentionPreview`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionPreview {
+    pub streams: Vec<RetentionPreviewStream>,
+}
+
+/// A stream's recordings which a retention change would delete.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionPreviewStream {
+    pub camera_uuid: Uuid,
+    pub stream: &'static str,
+    pub recordings: Vec<RetentionPreviewRecording>,
+    pub sample_file_bytes: i64,
+    pub fs_bytes: i64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oldest_remaining_time_90k: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionPreviewRecording {
+    pub recording_id: i32,
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub sample_file_bytes: i32,
+}
+
+impl RetentionPreviewStream {
+    /// Describes `p`, or returns `None` if its stream no longer exists.
+    pub fn new(
+        db: &db::LockedDatabase,
+        stream_id: i32,
+        p: &db::StreamDeletionPreview,
+    ) -> Option<Self> {
+        let s = db.streams_by_id().get(&stream_id)?;
+        let c = db.cameras_by_id().get(&s.camera_id)?;
+        Some(RetentionPreviewStream {
+            camera_uuid: c.uuid,
+            stream: s.type_.as_str(),
+            recordings: p
+                .recordings
+                .iter()
+                .map(|r| RetentionPreviewRecording {
+                    recording_id: r.id.recording(),
+                    start_time_90k: r.time.start.0,
+                    end_time_90k: r.time.end.0,
+                    sample_file_bytes: r.sample_file_bytes,
+                })
+                .collect(),
+            sample_file_bytes: p.sample_file_bytes,
+            fs_bytes: p.fs_bytes,
+            oldest_remaining_time_90k: p.oldest_remaining.map(|t| t.0),
+        })
+    }
+}
+
+/// A request to predict the effect of retention changes, as in `POST /api/retentionSimulation`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionSimulationRequest {
+    /// The number of recent days over which to measure each stream's disk usage.
+    pub window_days: Option<i64>,
+
+    /// Hypothetical limits, keyed by `<camera uuid>/<main|sub>`. Other streams keep their
+    /// current limits.
+    #[serde(default)]
+    pub streams: BTreeMap<String, RetentionSimulationLimit>,
+}
+
+/// A hypothetical retention limit. Exactly one attribute should be set.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionSimulationLimit {
+    pub retain_bytes: Option<i64>,
+    pub retain_days: Option<f64>,
+}
+
+impl RetentionSimulationLimit {
+    pub fn to_limit(&self) -> Result<db::retention::Limit, Error> {
+        match (self.retain_bytes, self.retain_days) {
+            (Some(b), None) if b >= 0 => Ok(db::retention::Limit::Bytes(b)),
+            (None, Some(d)) if d >= 0. && d.is_finite() => Ok(db::retention::Limit::Days(d)),
+            _ => Err(format_err!(
+                "exactly one of non-negative retainBytes or retainDays required"
+            )),
+        }
+    }
+}
+
+/// The response to `POST /api/retentionSimulation` and the output of `moonfire-nvr retention`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionSimulation {
+    pub streams: Vec<RetentionSimulationStream>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionSimulationStream {
+    pub camera_uuid: Uuid,
+    pub camera_short_name: String,
+    pub stream: &'static str,
+
+    /// The span over which disk usage was measured.
+    pub window_start_time_90k: i64,
+    pub window_end_time_90k: i64,
+    pub recorded_duration_90k: i64,
+
+    /// The average filesystem bytes used per day over the window, if anything was recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fs_bytes_per_day: Option<i64>,
+
+    pub current_retain_bytes: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_days: Option<f64>,
+
+    /// The simulated `retainBytes`, which is the current limit unless one was given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retain_bytes: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub predicted_days: Option<f64>,
+}
+
+impl RetentionSimulationStream {
+    /// Describes the stream with the given usage under `limit` (or its current limit), or
+    /// returns `None` if it no longer exists.
+    pub fn new(
+        db: &db::LockedDatabase,
+        stream_id: i32,
+        usage: &db::retention::Usage,
+        limit: Option<db::retention::Limit>,
+    ) -> Option<Self> {
+        let s = db.streams_by_id().get(&stream_id)?;
+        let c = db.cameras_by_id().get(&s.camera_id)?;
+        let current = db::retention::Limit::Bytes(s.retain_bytes);
+        let (_, current_days) = usage.simulate(current);
+        let (retain_bytes, predicted_days) = usage.simulate(limit.unwrap_or(current));
+        Some(RetentionSimulationStream {
+            camera_uuid: c.uuid,
+            camera_short_name: c.short_name.clone(),
+            stream: s.type_.as_str(),
+            window_start_time_90k: usage.time.start.0,
+            window_end_time_90k: usage.time.end.0,
+            recorded_duration_90k: usage.recorded.0,
+            fs_bytes_per_day: usage.fs_bytes_per_day(),
+            current_retain_bytes: s.retain_bytes,
+            current_days,
+            retain_bytes,
+            predicted_days,
+        })
+    }
+}
+
+/// The state of the server or one of its subsystems, as returned by `/api/health/...`.
+/// Ordered from best to worst.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HealthStatus {
+    Ok,
+    Degraded,
+    Failed,
+}
+
+/// The request and response of `/api/logs/filter`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogFilter {
+    /// The filter, in `MOONFIRE_LOG` syntax. In a request, `None` restores the filter the
+    /// server was started with.
+    pub filter: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Health {
+    pub status: HealthStatus,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub db: Option<DbHealth>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dirs: Vec<DirHealth>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub streams: Vec<StreamHealth>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DbHealth {
+    pub status: HealthStatus,
+    pub flush_failures: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_flush_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirHealth {
+    pub id: i32,
+    pub status: HealthStatus,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unhealthy_since_sec: Option<i64>,
+
+    /// The syncer's command queue, if this server is running a syncer for the directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub syncer_queue_depth: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub syncer_queue_capacity: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub syncer_stalls: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamHealth {
+    pub camera_uuid: Uuid,
+    pub stream: &'static str,
+    pub status: HealthStatus,
+    pub recording: bool,
+    pub disk_full: bool,
+
+    /// Symptoms suggesting another client is also connected to the camera, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub multi_homed: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Liveness {
+    pub silence_threshold_sec: i64,
+    pub streams: Vec<StreamLiveness>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamLiveness {
+    pub camera_uuid: Uuid,
+    pub stream: &'static str,
+    pub connected: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_frame_90k: Option<i64>,
+
+    /// How long since the last frame, or since the streamer started if there hasn't been one.
+    pub silent_for_ms: i64,
+    pub silent: bool,
+    pub reconnects: u64,
+    pub bitrate_bps: u64,
+    pub fps: f32,
+}
+
+#[derive(Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResults {
+    pub results: Vec<SearchResult>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResult {
+    /// One of `camera`, `note`, or `detection`.
+    #[serde(rename = "type")]
+    pub type_: &'static str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera_uuid: Option<Uuid>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<&'static str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recording_id: Option<i32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_time_90k: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_time_90k: Option<i64>,
+
+    pub score: f64,
+    pub snippet: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostDetectionsRequest {
+    pub detections: Vec<PostDetection>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostDetection {
+    pub recording_id: i32,
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub label: String,
+    pub confidence: Option<f64>,
+    pub source: Option<String>,
+}
+
+#[derive(Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Detections {
+    pub detections: Vec<Detection>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Detection {
+    pub id: i64,
+    pub recording_id: i32,
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub label: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PostSignalsResponse {
     pub time_90k: i64,
 }
 
+/// How a signal relates to a camera; see the `signal_camera` table.
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SignalCameraType {
+    Direct,
+    Indirect,
+}
+
+impl SignalCameraType {
+    pub fn to_db(self) -> db::signal::SignalCameraType {
+        match self {
+            SignalCameraType::Direct => db::signal::SignalCameraType::Direct,
+            SignalCameraType::Indirect => db::signal::SignalCameraType::Indirect,
+        }
+    }
+}
+
+/// A request to create a signal, as in `POST /api/signals/new`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostSignalRequest {
+    /// The originating object, such as an alarm panel's zone; a new uuid if absent.
+    pub source: Option<Uuid>,
+    #[serde(rename = "type")]
+    pub type_: Uuid,
+    pub short_name: String,
+    #[serde(default)]
+    pub cameras: BTreeMap<Uuid, SignalCameraType>,
+
+    /// The type's states, used only if the type has none yet.
+    #[serde(default)]
+    pub states: Vec<PostSignalTypeState>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostSignalTypeState {
+    pub value: u16,
+    pub name: String,
+    #[serde(default)]
+    pub motion: bool,
+    #[serde(default)]
+    pub color: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostSignalResponse {
+    pub id: u32,
+}
+
+/// A request to change a signal, as in `POST /api/signals/<id>`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateSignalRequest {
+    pub short_name: String,
+    #[serde(default)]
+    pub cameras: BTreeMap<Uuid, SignalCameraType>,
+}
+
 #[derive(Default, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Signals {
@@ -201,6 +1228,7 @@ impl<'a> Camera<'a> {
             uuid: c.uuid,
             short_name: &c.short_name,
             description: &c.description,
+            ptz: c.ptz,
             config: match include_config {
                 false => None,
                 true => Some(CameraConfig {
@@ -256,6 +1284,7 @@ impl Stream {
             total_duration_90k: s.duration.0,
             total_sample_file_bytes: s.sample_file_bytes,
             fs_bytes: s.fs_bytes,
+            privacy_mask: s.privacy_mask.polygons().to_vec(),
             days: if include_days { Some(s.days()) } else { None },
         }))
     }
@@ -362,16 +1391,20 @@ impl<'a> TopLevel<'a> {
     /// Serializes cameras as a list (rather than a map), optionally including the `days` and
     /// `cameras` fields.
     fn serialize_cameras<S>(
-        cameras: &(&db::LockedDatabase, bool, bool),
+        cameras: &(&db::LockedDatabase, bool, bool, Option<&BTreeSet<i32>>),
         serializer: S,
     ) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let (db, include_days, include_config) = *cameras;
-        let cs = db.cameras_by_id();
+        let (db, include_days, include_config, allowed) = *cameras;
+        let cs: Vec<_> = db
+            .cameras_by_id()
+            .values()
+            .filter(|c| allowed.map(|a| a.contains(&c.id)).unwrap_or(true))
+            .collect();
         let mut seq = serializer.serialize_seq(Some(cs.len()))?;
-        for (_, c) in cs {
+        for c in cs {
             seq.serialize_element(
                 &Camera::wrap(c, db, include_days, include_config)
                     .map_err(|e| S::Error::custom(e))?,
@@ -411,32 +1444,19 @@ impl<'a> TopLevel<'a> {
     }
 }
 
-#[derive(Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct ListRecordings<'a> {
-    pub recordings: Vec<Recording>,
-
-    // There are likely very few video sample entries for a given stream in a given day, so
-    // representing with an unordered Vec (and having O(n) insert-if-absent) is probably better
-    // than dealing with a HashSet's code bloat.
-    #[serde(serialize_with = "ListRecordings::serialize_video_sample_entries")]
-    pub video_sample_entries: (&'a db::LockedDatabase, Vec<i32>),
-}
+/// The `videoSampleEntries` map of a recordings list, for the given ids.
+pub struct VideoSampleEntries<'a>(pub &'a db::LockedDatabase, pub &'a [i32]);
 
-impl<'a> ListRecordings<'a> {
-    fn serialize_video_sample_entries<S>(
-        video_sample_entries: &(&db::LockedDatabase, Vec<i32>),
-        serializer: S,
-    ) -> Result<S::Ok, S::Error>
+impl<'a> Serialize for VideoSampleEntries<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let (db, ref v) = *video_sample_entries;
-        let mut map = serializer.serialize_map(Some(v.len()))?;
-        for id in v {
+        let mut map = serializer.serialize_map(Some(self.1.len()))?;
+        for id in self.1 {
             map.serialize_entry(
                 id,
-                &VideoSampleEntry::from(&db.video_sample_entries_by_id().get(id).unwrap()),
+                &VideoSampleEntry::from(&self.0.video_sample_entries_by_id().get(id).unwrap()),
             )?;
         }
         map.end()
@@ -454,6 +1474,9 @@ pub struct Recording {
     pub start_id: i32,
     pub open_id: u32,
 
+    /// The average bitrate in bits per second.
+    pub bitrate_bps: i64,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub first_uncommitted: Option<i32>,
 
@@ -470,6 +1493,9 @@ pub struct VideoSampleEntry {
     pub sha1: String,
     pub width: u16,
     pub height: u16,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoder: Option<String>,
 }
 
 impl VideoSampleEntry {
@@ -478,6 +1504,7 @@ impl VideoSampleEntry {
             sha1: base::strutil::hex(&e.sha1),
             width: e.width,
             height: e.height,
+            encoder: e.encoder.clone(),
         }
     }
 }
@@ -0,0 +1,140 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Listening sockets for the HTTP server: TCP, Unix domain sockets, and sockets passed by
+//! systemd socket activation (see `sd_listen_fds(3)`).
+
+use failure::{bail, format_err, Error};
+use futures::stream;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::socket::{getsockname, SockAddr};
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::Path;
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+use tracing::warn;
+
+/// The first file descriptor passed by systemd.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Takes the listening sockets passed via systemd socket activation, if any.
+///
+/// Unsets the activation environment variables so that they can't be taken twice or passed on
+/// to child processes.
+pub fn from_systemd() -> Result<Vec<Listener>, Error> {
+    let pid = std::env::var("LISTEN_PID");
+    let fds = std::env::var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    let (pid, fds) = match (pid, fds) {
+        (Ok(p), Ok(f)) => (p, f),
+        _ => return Ok(Vec::new()),
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(Vec::new()); // intended for another process.
+    }
+    let n: RawFd = fds
+        .parse()
+        .map_err(|_| format_err!("bad LISTEN_FDS {:?}", fds))?;
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + n)
+        .map(from_fd)
+        .collect()
+}
+
+fn from_fd(fd: RawFd) -> Result<Listener, Error> {
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    match getsockname(fd)? {
+        SockAddr::Inet(_) => {
+            // SAFETY: systemd passes ownership of the fd, and from_systemd takes it only once.
+            let l = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            l.set_nonblocking(true)?;
+            Ok(Listener::Tcp(TcpListener::from_std(l)?))
+        }
+        SockAddr::Unix(_) => {
+            // SAFETY: as above.
+            let l = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            l.set_nonblocking(true)?;
+            Ok(Listener::Unix(UnixListener::from_std(l)?))
+        }
+        a => bail!("systemd passed fd {} with unsupported address {}", fd, a),
+    }
+}
+
+/// Binds a Unix domain socket, replacing any stale socket left at `path` by a previous run.
+pub fn bind_unix(path: &Path) -> Result<UnixListener, Error> {
+    match std::fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => bail!("{} exists and isn't a socket", path.display()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    UnixListener::bind(path)
+        .map_err(|e| format_err!("unable to bind Unix socket {}: {}", path.display(), e))
+}
+
+/// Accepts connections on a Unix domain socket, as `sendfile::incoming` does for TCP.
+pub fn unix_incoming(
+    listener: UnixListener,
+) -> impl hyper::server::accept::Accept<Conn = UnixStream, Error = io::Error> {
+    hyper::server::accept::from_stream(stream::unfold(listener, |mut l| async move {
+        loop {
+            match l.accept().await {
+                Ok((s, _)) => return Some((Ok::<_, io::Error>(s), l)),
+                Err(e) => {
+                    warn!("Accept error on Unix socket: {}; pausing", e);
+                    tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn bind_unix_replaces_stale_socket() {
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let path = tmpdir.path().join("http.sock");
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async { super::bind_unix(&path).unwrap() });
+
+        let file = tmpdir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        rt.block_on(async { super::bind_unix(&file).unwrap_err() });
+    }
+}
@@ -0,0 +1,558 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Log setup, log file management, and retention of recent log lines for the web API.
+//!
+//! `install` sets up `tracing` to write to standard error. The `MOONFIRE_LOG` environment
+//! variable filters events (as in `tracing_subscriber::EnvFilter`; the default is `info`), and
+//! `MOONFIRE_FORMAT` selects the format: `google` (the default), `google-systemd` (which replaces
+//! the timestamp with a syslog priority prefix for journald), or `json` (one object per line).
+//! Each line includes the spans the event happened within, such as `request{id=...}` in the web
+//! layer, `stream{id=...}` in streamers, and `syncer{dir_id=...}` and `recording{id=...}` in
+//! syncers, so one request or recording can be followed through the logs.
+//!
+//! The filter can be changed while running with `set_filter`, as via `/api/logs/filter` or
+//! `SIGHUP`, without restarting and so interrupting recordings.
+//!
+//! `moonfire-nvr run --log-dir=...` redirects the process's standard error through a pipe to a
+//! thread which copies each line to the original standard error, to a log file, and to an
+//! in-memory buffer of recent lines. Capturing at the file descriptor level means the log format
+//! (`MOONFIRE_FORMAT`) and filtering (`MOONFIRE_LOG`) are unchanged, and stray output such as
+//! panic messages is captured as well.
+//!
+//! The current file is `moonfire-nvr.log`. When it grows past the size limit or its age limit,
+//! it's renamed with a timestamp suffix and compressed with gzip in the background; only the
+//! most recent rotated files are kept.
+
+use failure::{format_err, Error, ResultExt};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use tracing::{warn, Level, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{reload, EnvFilter};
+
+pub(crate) const CURRENT_NAME: &str = "moonfire-nvr.log";
+
+/// The number of lines retained in memory for `Recent::query`.
+const RECENT_LINES: usize = 10_000;
+
+lazy_static! {
+    static ref FILTER: Mutex<Option<ReloadableFilter>> = Mutex::new(None);
+}
+
+/// The installed subscriber's filter, which `set_filter` replaces.
+struct ReloadableFilter {
+    initial: String,
+    current: String,
+    reload: Box<dyn Fn(EnvFilter) -> Result<(), Error> + Send>,
+}
+
+/// Installs the global `tracing` subscriber as described in the module documentation. Records
+/// from dependencies which use the `log` crate are included.
+pub fn install() {
+    let spec = std::env::var("MOONFIRE_LOG").unwrap_or_else(|_| "info".to_owned());
+    let b = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(&spec))
+        .with_writer(std::io::stderr);
+    let format = std::env::var("MOONFIRE_FORMAT").unwrap_or_default();
+    match format.as_str() {
+        "json" => {
+            let b = b.json().with_thread_names(true).with_filter_reloading();
+            set_reload_handle(spec, b.reload_handle());
+            b.init()
+        }
+        "google-systemd" => {
+            let b = b
+                .event_format(GoogleFormat { systemd: true })
+                .with_filter_reloading();
+            set_reload_handle(spec, b.reload_handle());
+            b.init()
+        }
+        _ => {
+            let b = b
+                .event_format(GoogleFormat { systemd: false })
+                .with_filter_reloading();
+            set_reload_handle(spec, b.reload_handle());
+            b.init()
+        }
+    }
+}
+
+fn set_reload_handle<S>(spec: String, h: reload::Handle<EnvFilter, S>)
+where
+    S: Subscriber + Send + Sync + 'static,
+{
+    *FILTER.lock() = Some(ReloadableFilter {
+        initial: spec.clone(),
+        current: spec,
+        reload: Box::new(move |f| h.reload(f).map_err(|e| format_err!("{}", e))),
+    });
+}
+
+/// Returns the current log filter, in `MOONFIRE_LOG` syntax, or `None` if `install` hasn't been
+/// called.
+pub fn filter() -> Option<String> {
+    FILTER.lock().as_ref().map(|f| f.current.clone())
+}
+
+/// Replaces the log filter with `spec`, in `MOONFIRE_LOG` syntax, or (with `None`) restores the
+/// filter the process started with. Returns the new filter.
+pub fn set_filter(spec: Option<&str>) -> Result<String, Error> {
+    let mut l = FILTER.lock();
+    let f = l
+        .as_mut()
+        .ok_or_else(|| format_err!("logging isn't installed"))?;
+    let spec = spec.unwrap_or(&f.initial).to_owned();
+    let filter =
+        EnvFilter::try_new(&spec).map_err(|e| format_err!("bad filter {:?}: {}", &spec, e))?;
+    (f.reload)(filter)?;
+    f.current = spec.clone();
+    Ok(spec)
+}
+
+/// The glog-style format, as in `I20200101 00:00:00.000 main moonfire_nvr::cmds::run] message`.
+/// `Recent::query` relies on the thread name and target preceding the `] `.
+struct GoogleFormat {
+    /// If true, replace the level letter and timestamp with a `<n>` syslog priority prefix.
+    systemd: bool,
+}
+
+impl<S, N> FormatEvent<S, N> for GoogleFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        w: &mut dyn fmt::Write,
+        event: &tracing::Event<'_>,
+    ) -> fmt::Result {
+        let normalized = event.normalized_metadata();
+        let meta = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        let (letter, priority) = match *meta.level() {
+            Level::ERROR => ('E', 3),
+            Level::WARN => ('W', 4),
+            Level::INFO => ('I', 6),
+            Level::DEBUG => ('D', 7),
+            Level::TRACE => ('T', 7),
+        };
+        if self.systemd {
+            write!(w, "<{}>", priority)?;
+        } else {
+            let now = time::now();
+            let ts = time::strftime("%Y%m%d %H:%M:%S", &now).map_err(|_| fmt::Error)?;
+            write!(w, "{}{}.{:03} ", letter, ts, now.tm_nsec / 1_000_000)?;
+        }
+        let t = thread::current();
+        write!(w, "{} {}] ", t.name().unwrap_or("unnamed"), meta.target())?;
+        ctx.visit_spans(|span| {
+            w.write_str(span.name())?;
+            let ext = span.extensions();
+            if let Some(f) = ext.get::<FormattedFields<N>>() {
+                if !f.fields.is_empty() {
+                    write!(w, "{{{}}}", &f.fields)?;
+                }
+            }
+            w.write_str(": ")
+        })?;
+        ctx.format_fields(w, event)?;
+        writeln!(w)
+    }
+}
+
+pub struct Options {
+    pub dir: PathBuf,
+
+    /// Rotate the current file when it exceeds this many bytes.
+    pub max_bytes: u64,
+
+    /// Rotate the current file when it's been open for this long.
+    pub max_age_sec: i64,
+
+    /// The number of rotated (compressed) files to keep.
+    pub keep: usize,
+}
+
+/// Recently logged lines, oldest first.
+pub struct Recent(Mutex<VecDeque<String>>);
+
+/// Selects log lines by the logging module and/or the stream which produced them.
+#[derive(Default)]
+pub struct Filter<'a> {
+    /// A module path such as `moonfire_nvr::streamer`; matches it and its submodules.
+    pub module: Option<&'a str>,
+
+    /// A stream as named by its streamer thread, such as `driveway-main`.
+    pub stream: Option<&'a str>,
+}
+
+impl<'a> Filter<'a> {
+    fn matches(&self, thread: &str, target: &str) -> bool {
+        if let Some(m) = self.module {
+            if target != m && !(target.starts_with(m) && target[m.len()..].starts_with("::")) {
+                return false;
+            }
+        }
+        if let Some(s) = self.stream {
+            if !thread.starts_with("s-") || &thread[2..] != s {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Splits the thread name and module path from the start of a formatted log line, such as
+/// `I20200101 00:00:00.000 s-driveway-main moonfire_nvr::streamer] ...` or (with
+/// `MOONFIRE_FORMAT=google-systemd`) `<6>s-driveway-main moonfire_nvr::streamer] ...`. Returns
+/// `None` for
+/// continuation lines of multi-line messages, JSON lines, and other unformatted output.
+fn split_prefix(line: &str) -> Option<(&str, &str)> {
+    match line.as_bytes().first() {
+        Some(b'E') | Some(b'W') | Some(b'I') | Some(b'D') | Some(b'T') | Some(b'<') => {}
+        _ => return None,
+    }
+    let end = line.find("] ").or_else(|| {
+        if line.ends_with(']') {
+            Some(line.len() - 1)
+        } else {
+            None
+        }
+    })?;
+    let mut words = line[..end].rsplit(' ');
+    let target = words.next()?;
+    let mut thread = words.next()?;
+    if thread.starts_with('<') {
+        thread = &thread[thread.find('>')? + 1..];
+    }
+    Some((thread, target))
+}
+
+/// Returns the thread name and module path of a line logged with `MOONFIRE_FORMAT=json`.
+fn split_json(line: &str) -> Option<(String, String)> {
+    if !line.starts_with('{') {
+        return None;
+    }
+    let v: serde_json::Value = serde_json::from_str(line).ok()?;
+    let thread = v.get("threadName")?.as_str()?;
+    let target = v.get("target")?.as_str()?;
+    Some((thread.to_owned(), target.to_owned()))
+}
+
+impl Recent {
+    fn new() -> Self {
+        Recent(Mutex::new(VecDeque::with_capacity(RECENT_LINES)))
+    }
+
+    fn push(&self, line: String) {
+        let mut l = self.0.lock();
+        if l.len() == RECENT_LINES {
+            l.pop_front();
+        }
+        l.push_back(line);
+    }
+
+    /// Returns up to `limit` of the most recent lines matching `filter`, oldest first, each
+    /// followed by a newline. Continuation lines match iff the line they continue matches.
+    pub fn query(&self, filter: &Filter, limit: usize) -> String {
+        let l = self.0.lock();
+        let mut matching = Vec::new();
+        let mut matched = false;
+        for line in l.iter() {
+            if let Some((thread, target)) = split_prefix(line) {
+                matched = filter.matches(thread, target);
+            } else if let Some((thread, target)) = split_json(line) {
+                matched = filter.matches(&thread, &target);
+            }
+            if matched {
+                matching.push(&line[..]);
+            }
+        }
+        let skip = matching.len().saturating_sub(limit);
+        let mut out = String::new();
+        for line in &matching[skip..] {
+            out.push_str(line);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// The currently open log file.
+struct Current {
+    dir: PathBuf,
+    file: File,
+    len: u64,
+    opened_sec: i64,
+}
+
+impl Current {
+    fn open(dir: &Path) -> Result<Self, Error> {
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(dir.join(CURRENT_NAME))?;
+        let len = file.metadata()?.len();
+        Ok(Current {
+            dir: dir.to_owned(),
+            file,
+            len,
+            opened_sec: time::get_time().sec,
+        })
+    }
+
+    /// Renames the current file aside and opens a fresh one, returning the renamed path.
+    fn rotate(&mut self) -> Result<PathBuf, Error> {
+        let stamp = time::strftime("%Y%m%dT%H%M%S", &time::now_utc())?;
+        let mut rotated = self.dir.join(format!("{}.{}", CURRENT_NAME, stamp));
+        let mut n = 1;
+        while rotated.exists() || gz_path(&rotated).exists() {
+            rotated = self.dir.join(format!("{}.{}.{}", CURRENT_NAME, stamp, n));
+            n += 1;
+        }
+        fs::rename(self.dir.join(CURRENT_NAME), &rotated)?;
+        *self = Current::open(&self.dir)?;
+        Ok(rotated)
+    }
+}
+
+fn gz_path(p: &Path) -> PathBuf {
+    let mut gz = p.as_os_str().to_owned();
+    gz.push(".gz");
+    gz.into()
+}
+
+/// Compresses a rotated file, then deletes all but the newest `keep` rotated files.
+fn compress_and_prune(dir: &Path, rotated: &Path, keep: usize) -> Result<(), Error> {
+    let mut r = File::open(rotated)?;
+    let mut w = flate2::write::GzEncoder::new(
+        File::create(gz_path(rotated))?,
+        flate2::Compression::default(),
+    );
+    std::io::copy(&mut r, &mut w)?;
+    w.finish()?.sync_all()?;
+    fs::remove_file(rotated)?;
+
+    let prefix = format!("{}.", CURRENT_NAME);
+    let mut names = Vec::new();
+    for e in fs::read_dir(dir)? {
+        let name = e?.file_name();
+        if let Some(n) = name.to_str() {
+            if n.starts_with(&prefix) && n.ends_with(".gz") {
+                names.push(n.to_owned());
+            }
+        }
+    }
+    names.sort(); // timestamp suffixes sort chronologically.
+    let excess = names.len().saturating_sub(keep);
+    for n in &names[..excess] {
+        fs::remove_file(dir.join(n))?;
+    }
+    Ok(())
+}
+
+/// Captures standard error as described in the module documentation until `stop` is called.
+pub struct Capture {
+    recent: Arc<Recent>,
+    orig_stderr: RawFd,
+    join: thread::JoinHandle<()>,
+}
+
+impl Capture {
+    pub fn start(opts: Options) -> Result<Self, Error> {
+        fs::create_dir_all(&opts.dir)
+            .with_context(|_| format!("unable to create log dir {}", opts.dir.display()))?;
+        let mut current = Current::open(&opts.dir)
+            .with_context(|_| format!("unable to open log file in {}", opts.dir.display()))?;
+        let recent = Arc::new(Recent::new());
+        let (pipe_r, pipe_w) = nix::unistd::pipe()?;
+        let orig_stderr = nix::unistd::dup(libc::STDERR_FILENO)?;
+        let echo_fd = nix::unistd::dup(orig_stderr)?;
+        nix::unistd::dup2(pipe_w, libc::STDERR_FILENO)?;
+        nix::unistd::close(pipe_w)?;
+
+        // Errors within the thread go straight to the original standard error; logging them
+        // could block on the pipe this thread is responsible for draining.
+        let mut echo = unsafe { File::from_raw_fd(echo_fd) };
+        let r = BufReader::new(unsafe { File::from_raw_fd(pipe_r) });
+        let thread_recent = recent.clone();
+        let join = thread::Builder::new()
+            .name("logs".to_owned())
+            .spawn(move || {
+                let mut r = r;
+                let mut buf = Vec::new();
+                loop {
+                    buf.clear();
+                    match r.read_until(b'\n', &mut buf) {
+                        Ok(0) => return,
+                        Ok(_) => {}
+                        Err(e) => {
+                            let _ = writeln!(&mut echo, "log capture read failed: {}", e);
+                            return;
+                        }
+                    }
+                    let _ = echo.write_all(&buf);
+                    let now = time::get_time().sec;
+                    if current.len > 0
+                        && (current.len + buf.len() as u64 > opts.max_bytes
+                            || now - current.opened_sec >= opts.max_age_sec)
+                    {
+                        match current.rotate() {
+                            Ok(rotated) => {
+                                let dir = opts.dir.clone();
+                                let keep = opts.keep;
+                                // Unlike this thread, the compressing thread may log: it doesn't
+                                // drain the pipe, so it can't block itself.
+                                thread::Builder::new()
+                                    .name("logs-gzip".to_owned())
+                                    .spawn(move || {
+                                        if let Err(e) = compress_and_prune(&dir, &rotated, keep) {
+                                            warn!(
+                                                "unable to compress {}: {}",
+                                                rotated.display(),
+                                                e
+                                            );
+                                        }
+                                    })
+                                    .expect("can't create thread");
+                            }
+                            Err(e) => {
+                                let _ = writeln!(&mut echo, "unable to rotate log file: {}", e);
+                            }
+                        }
+                    }
+                    match current.file.write_all(&buf) {
+                        Ok(()) => current.len += buf.len() as u64,
+                        Err(e) => {
+                            let _ = writeln!(&mut echo, "unable to write log file: {}", e);
+                        }
+                    }
+                    let line = String::from_utf8_lossy(&buf);
+                    thread_recent.push(line.trim_end_matches('\n').to_owned());
+                }
+            })
+            .expect("can't create thread");
+        Ok(Capture {
+            recent,
+            orig_stderr,
+            join,
+        })
+    }
+
+    pub fn recent(&self) -> &Arc<Recent> {
+        &self.recent
+    }
+
+    /// Restores the original standard error and waits for all captured lines to be written.
+    pub fn stop(self) {
+        nix::unistd::dup2(self.orig_stderr, libc::STDERR_FILENO).expect("can't restore stderr");
+        let _ = nix::unistd::close(self.orig_stderr);
+        self.join.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query() {
+        let r = Recent::new();
+        for l in &[
+            "I20200101 00:00:00.000 main moonfire_nvr::cmds::run] Database is loaded.",
+            "W20200101 00:00:01.000 s-driveway-main moonfire_nvr::streamer] driveway-main: sleeping",
+            "  caused by: connection refused",
+            "I20200101 00:00:02.000 s-back-sub moonfire_nvr::streamer] back-sub: opening input",
+            "I20200101 00:00:03.000 sync-/media/nvr moonfire_db::writer] flushing",
+            "<4>s-driveway-main moonfire_nvr::streamer] driveway-main: sleeping",
+        ] {
+            r.push((*l).to_owned());
+        }
+        let stream = Filter {
+            stream: Some("driveway-main"),
+            ..Default::default()
+        };
+        assert_eq!(
+            r.query(&stream, 100),
+            "W20200101 00:00:01.000 s-driveway-main moonfire_nvr::streamer] driveway-main: \
+             sleeping\n  caused by: connection refused\n\
+             <4>s-driveway-main moonfire_nvr::streamer] driveway-main: sleeping\n"
+        );
+        let module = Filter {
+            module: Some("moonfire_nvr"),
+            ..Default::default()
+        };
+        assert_eq!(r.query(&module, 100).lines().count(), 5);
+        assert_eq!(
+            r.query(&module, 1),
+            "<4>s-driveway-main moonfire_nvr::streamer] driveway-main: sleeping\n"
+        );
+        let prefix_only = Filter {
+            module: Some("moonfire_nvr::stream"),
+            ..Default::default()
+        };
+        assert_eq!(r.query(&prefix_only, 100), "");
+        assert_eq!(r.query(&Filter::default(), 100).lines().count(), 6);
+    }
+
+    #[test]
+    fn query_json() {
+        let r = Recent::new();
+        for l in &[
+            r#"{"timestamp":"Jan 01 00:00:00.000","level":"INFO","fields":{"message":"Database is loaded."},"target":"moonfire_nvr::cmds::run","threadName":"main"}"#,
+            r#"{"timestamp":"Jan 01 00:00:01.000","level":"WARN","fields":{"message":"sleeping"},"target":"moonfire_nvr::streamer","spans":[{"id":1,"name":"stream"}],"threadName":"s-driveway-main"}"#,
+            "I20200101 00:00:02.000 s-driveway-main moonfire_nvr::streamer] stream{id=1}: opening",
+        ] {
+            r.push((*l).to_owned());
+        }
+        let stream = Filter {
+            stream: Some("driveway-main"),
+            ..Default::default()
+        };
+        assert_eq!(r.query(&stream, 100).lines().count(), 2);
+        let module = Filter {
+            module: Some("moonfire_nvr::cmds"),
+            ..Default::default()
+        };
+        assert!(r.query(&module, 100).contains("Database is loaded."));
+        assert_eq!(r.query(&module, 100).lines().count(), 1);
+    }
+}
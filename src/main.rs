@@ -30,19 +30,49 @@
 
 #![cfg_attr(all(feature = "nightly", test), feature(test))]
 
-use log::{error, info};
-use std::str::FromStr;
 use structopt::StructOpt;
+use tracing::{error, info};
 
+mod admission;
+mod archive;
 mod body;
+mod bufpool;
+#[cfg(feature = "bundled-ui")]
+mod bundled_ui;
 mod cmds;
+mod diagnostics;
+mod events;
+mod evidence;
+mod export;
+#[cfg(feature = "graphql")]
+mod graphql;
+#[cfg(feature = "grpc")]
+mod grpc;
 mod h264;
+mod health;
+mod hls;
 mod json;
+mod listen;
+mod logs;
 mod mp4;
+mod mqtt;
+mod oidc;
+mod onvif;
+mod pipelines;
+mod replication;
+mod rtmp;
+mod saml;
+mod sched;
+mod sendfile;
 mod slices;
 mod stream;
 mod streamer;
+mod supervisor;
+mod talkback;
+mod thumbnail;
+mod tls;
 mod web;
+mod webhook;
 
 #[derive(StructOpt)]
 #[structopt(
@@ -50,12 +80,35 @@ mod web;
     about = "security camera network video recorder"
 )]
 enum Args {
+    /// Copies the database while the server runs.
+    Backup(cmds::backup::Args),
+
     /// Checks database integrity (like fsck).
     Check(cmds::check::Args),
 
-    /// Interactively edits configuration.
+    /// Edits configuration, interactively or from a file.
     Config(cmds::config::Args),
 
+    /// Generates synthetic data for demos and UI development.
+    Demo(cmds::demo::Args),
+
+    /// Downgrades to an earlier database schema.
+    Downgrade(cmds::downgrade::Args),
+
+    /// Tests compatibility with a camera by recording from it into a scratch database.
+    ///
+    /// This records the given RTSP stream for a while, checks the recordings can be played back,
+    /// and writes a JSON report suitable for attaching to camera compatibility reports.
+    #[cfg(feature = "hil-test")]
+    HilTest(cmds::hil_test::Args),
+
+    /// Imports recordings from a video file, such as when migrating from another NVR.
+    ///
+    /// The file's video is copied as is (not re-encoded) into a stream's sample file directories
+    /// and indexed as if recorded live, starting at the given time. It locks the database, so the
+    /// server must be stopped first.
+    Import(cmds::import::Args),
+
     /// Initializes a database.
     Init(cmds::init::Args),
 
@@ -66,15 +119,37 @@ enum Args {
     /// have.
     Login(cmds::login::Args),
 
+    /// Predicts how many days of footage each stream would keep under different retention limits.
+    ///
+    /// This measures each stream's recent disk usage. It locks the database; while the server is
+    /// running, use `POST /api/retentionSimulation` instead.
+    Retention(cmds::retention::Args),
+
     /// Runs the server, saving recordings and allowing web access.
     Run(cmds::run::Args),
 
+    /// Maps sample file names to the cameras, times, and hashes of their recordings.
+    ///
+    /// This is intended for external backup tools which copy sample files directly. It locks
+    /// the database; while the server is running, use `GET /api/sampleFiles` instead.
+    SampleFiles(cmds::sample_files::Args),
+
     /// Runs a SQLite3 shell on Moonfire NVR's index database.
     ///
     /// Note this locks the database to prevent simultaneous access with a running server. The
     /// server maintains cached state which could be invalidated otherwise.
     Sql(cmds::sql::Args),
 
+    /// Writes a sanitized archive of configuration, state, and logs to attach to bug reports.
+    ///
+    /// Like `backup`, this can run alongside the server. Credentials are omitted.
+    SupportBundle(cmds::support_bundle::Args),
+
+    /// Creates, lists, and revokes long-lived API tokens.
+    ///
+    /// Like `login`, this is a privileged command that directly accesses the database.
+    Token(cmds::token::Args),
+
     /// Translates between integer and human-readable timestamps.
     Ts(cmds::ts::Args),
 
@@ -85,12 +160,22 @@ enum Args {
 impl Args {
     fn run(&self) -> Result<(), failure::Error> {
         match self {
+            Args::Backup(ref a) => cmds::backup::run(a),
             Args::Check(ref a) => cmds::check::run(a),
             Args::Config(ref a) => cmds::config::run(a),
+            Args::Demo(ref a) => cmds::demo::run(a),
+            Args::Downgrade(ref a) => cmds::downgrade::run(a),
+            #[cfg(feature = "hil-test")]
+            Args::HilTest(ref a) => cmds::hil_test::run(a),
+            Args::Import(ref a) => cmds::import::run(a),
             Args::Init(ref a) => cmds::init::run(a),
             Args::Login(ref a) => cmds::login::run(a),
+            Args::Retention(ref a) => cmds::retention::run(a),
             Args::Run(ref a) => cmds::run::run(a),
+            Args::SampleFiles(ref a) => cmds::sample_files::run(a),
             Args::Sql(ref a) => cmds::sql::run(a),
+            Args::SupportBundle(ref a) => cmds::support_bundle::run(a),
+            Args::Token(ref a) => cmds::token::run(a),
             Args::Ts(ref a) => cmds::ts::run(a),
             Args::Upgrade(ref a) => cmds::upgrade::run(a),
         }
@@ -99,21 +184,9 @@ impl Args {
 
 fn main() {
     let args = Args::from_args();
-    let mut h = mylog::Builder::new()
-        .set_format(
-            ::std::env::var("MOONFIRE_FORMAT")
-                .map_err(|_| ())
-                .and_then(|s| mylog::Format::from_str(&s))
-                .unwrap_or(mylog::Format::Google),
-        )
-        .set_spec(&::std::env::var("MOONFIRE_LOG").unwrap_or("info".to_owned()))
-        .build();
-    h.clone().install().unwrap();
-
-    if let Err(e) = {
-        let _a = h.async_scope();
-        args.run()
-    } {
+    logs::install();
+
+    if let Err(e) = args.run() {
         error!("{:?}", e);
         ::std::process::exit(1);
     }
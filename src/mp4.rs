@@ -77,6 +77,8 @@
 //! ```
 
 use crate::body::{wrap_error, BoxedError, Chunk};
+use crate::bufpool;
+use crate::sendfile;
 use crate::slices::{self, Slices};
 use base::{bail_t, format_err_t, strutil, Error, ErrorKind, ResultExt};
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
@@ -88,9 +90,6 @@ use futures::Stream;
 use http;
 use http::header::HeaderValue;
 use http_serve;
-use log::{debug, error, trace, warn};
-use memmap;
-use openssl::hash;
 use parking_lot::Once;
 use reffers::ARefss;
 use smallvec::SmallVec;
@@ -103,11 +102,12 @@ use std::mem;
 use std::ops::Range;
 use std::sync::Arc;
 use std::time::SystemTime;
+use tracing::{debug, error, trace, warn};
 
 /// This value should be incremented any time a change is made to this file that causes different
 /// bytes to be output for a particular set of `FileBuilder` options. Incrementing this value will
 /// cause the etag to change as well.
-const FORMAT_VERSION: [u8; 1] = [0x06];
+const FORMAT_VERSION: [u8; 1] = [0x07];
 
 /// An `ftyp` (ISO/IEC 14496-12 section 4.3 `FileType`) box.
 const NORMAL_FTYP_BOX: &'static [u8] = &[
@@ -339,10 +339,18 @@ struct Segment {
     ///    3. stss: `slice[stss_start ..]`
     index: UnsafeCell<Result<Box<[u8]>, ()>>,
 
+    /// The recording's flags, used to locate its sample file.
+    recording_flags: i32,
+
     /// The 1-indexed frame number in the `File` of the first frame in this segment.
     first_frame_num: u32,
     num_subtitle_samples: u16,
 
+    /// The offset to apply to `s.start` to produce the wall-clock time for subtitles, as
+    /// described at `recording_integrity.local_time_delta_90k` in `db/schema.sql`. Only
+    /// filled in when building a subtitle track; zero if unknown.
+    local_time_delta: recording::Duration,
+
     index_once: Once,
 }
 
@@ -353,6 +361,7 @@ impl fmt::Debug for Segment {
             .field("s", &self.s)
             .field("first_frame_num", &self.first_frame_num)
             .field("num_subtitle_samples", &self.num_subtitle_samples)
+            .field("local_time_delta", &self.local_time_delta)
             .finish()
     }
 }
@@ -370,11 +379,24 @@ impl Segment {
             s: recording::Segment::new(db, row, rel_range_90k).err_kind(ErrorKind::Unknown)?,
             index: UnsafeCell::new(Err(())),
             index_once: Once::new(),
+            recording_flags: row.flags,
             first_frame_num,
             num_subtitle_samples: 0,
+            local_time_delta: recording::Duration(0),
         })
     }
 
+    fn is_encrypted(&self) -> bool {
+        (self.recording_flags & db::RecordingFlags::Encrypted as i32) != 0
+    }
+
+    /// Returns the wall-clock time range to describe in this segment's subtitles.
+    fn subtitle_range(&self) -> Range<recording::Time> {
+        let d = &self.s.desired_range_90k;
+        let start = self.s.start + self.local_time_delta;
+        start + recording::Duration(d.start as i64)..start + recording::Duration(d.end as i64)
+    }
+
     fn get_index<'a, F>(&'a self, db: &db::Database, f: F) -> Result<&'a [u8], Error>
     where
         F: FnOnce(&[u8], SegmentLengths) -> &[u8],
@@ -581,6 +603,7 @@ pub struct FileBuilder {
     type_: Type,
     include_timestamp_subtitle_track: bool,
     content_disposition: Option<HeaderValue>,
+    read_pool: Option<Arc<bufpool::Pool>>,
 }
 
 /// The portion of `FileBuilder` which is mutated while building the body of the file.
@@ -663,7 +686,8 @@ impl Slice {
 
     fn wrap_truns(&self, mp4: &File, r: Range<u64>, len: usize) -> Result<Chunk, Error> {
         let s = &mp4.0.segments[self.p()];
-        let mut pos = mp4.0.initial_sample_byte_pos;
+        // trun data offsets are relative to the start of the moof (tfhd default-base-is-moof).
+        let mut pos = mp4.0.initial_sample_byte_pos - mp4.0.moof_pos;
         for ps in &mp4.0.segments[0..self.p()] {
             let r = ps.s.sample_file_range();
             pos += r.end - r.start;
@@ -718,7 +742,12 @@ impl slices::Slice for Slice {
             SliceType::Stsz => self.wrap_index(f, range.clone(), &Segment::stsz),
             SliceType::Stss => self.wrap_index(f, range.clone(), &Segment::stss),
             SliceType::Co64 => f.0.get_co64(range.clone(), len),
-            SliceType::VideoSampleData => f.0.get_video_sample_data(p, range.clone()),
+            SliceType::VideoSampleData => match f.0.read_pool.as_ref() {
+                Some(pool) if !f.0.segments[p].is_encrypted() => {
+                    return f.0.read_video_sample_data(p, range, pool.clone());
+                }
+                _ => f.0.get_video_sample_data(p, range.clone()),
+            },
             SliceType::SubtitleSampleData => f.0.get_subtitle_sample_data(p, range.clone(), len),
             SliceType::Truns => self.wrap_truns(f, range.clone(), len as usize),
         };
@@ -801,15 +830,23 @@ impl FileBuilder {
             type_: type_,
             include_timestamp_subtitle_track: false,
             content_disposition: None,
+            read_pool: None,
         }
     }
 
     /// Sets if the generated `.mp4` should include a subtitle track with second-level timestamps.
-    /// Default is false.
+    /// These are wall-clock times: each recording's start time corrected by its
+    /// `local_time_delta`, when known. Default is false.
     pub fn include_timestamp_subtitle_track(&mut self, b: bool) {
         self.include_timestamp_subtitle_track = b;
     }
 
+    /// Sets a pool for reading unencrypted video sample data sequentially, one buffer at a time,
+    /// with read-ahead of the following buffer. Default is to `mmap()` each range instead.
+    pub fn read_via(&mut self, pool: Arc<bufpool::Pool>) {
+        self.read_pool = Some(pool);
+    }
+
     /// Reserves space for the given number of additional segments.
     pub fn reserve(&mut self, additional: usize) {
         self.segments.reserve(additional);
@@ -866,24 +903,27 @@ impl FileBuilder {
     pub fn build(
         mut self,
         db: Arc<db::Database>,
-        dirs_by_stream_id: Arc<::fnv::FnvHashMap<i32, Arc<dir::SampleFileDir>>>,
+        dirs_by_stream_id: Arc<::fnv::FnvHashMap<i32, dir::StreamDirs>>,
     ) -> Result<File, Error> {
         let mut max_end = None;
-        let mut etag =
-            hash::Hasher::new(hash::MessageDigest::sha1()).err_kind(ErrorKind::Internal)?;
-        etag.update(&FORMAT_VERSION[..])
-            .err_kind(ErrorKind::Internal)?;
+        let mut etag = base::crypto::Sha1::new();
+        etag.update(&FORMAT_VERSION[..]);
         if self.include_timestamp_subtitle_track {
-            etag.update(b":ts:").err_kind(ErrorKind::Internal)?;
+            etag.update(b":ts:");
         }
         if let Some(cd) = self.content_disposition.as_ref() {
-            etag.update(b":cd:").err_kind(ErrorKind::Internal)?;
-            etag.update(cd.as_bytes()).err_kind(ErrorKind::Internal)?;
+            etag.update(b":cd:");
+            etag.update(cd.as_bytes());
         }
         match self.type_ {
             Type::Normal => {}
-            Type::InitSegment => etag.update(b":init:").err_kind(ErrorKind::Internal)?,
-            Type::MediaSegment => etag.update(b":media:").err_kind(ErrorKind::Internal)?,
+            Type::InitSegment => etag.update(b":init:"),
+            Type::MediaSegment => etag.update(b":media:"),
+        };
+        let l = if self.include_timestamp_subtitle_track {
+            Some(db.lock())
+        } else {
+            None
         };
         for s in &mut self.segments {
             let d = &s.s.desired_range_90k;
@@ -894,12 +934,27 @@ impl FileBuilder {
                 Some(v) => Some(cmp::max(v, end)),
             };
 
-            if self.include_timestamp_subtitle_track {
+            if let Some(ref l) = l {
+                // Correct the subtitles to the local clock where possible. A zero delta yields
+                // exactly the same output as before, so it's only reflected in the etag when
+                // non-zero.
+                if let Some(delta) = l
+                    .get_local_time_delta(s.s.id)
+                    .err_kind(ErrorKind::Unknown)?
+                {
+                    s.local_time_delta = delta;
+                }
+                if s.local_time_delta.0 != 0 {
+                    etag.update(b":ltd:");
+                    let mut buf = [0_u8; 8];
+                    BigEndian::write_i64(&mut buf[..], s.local_time_delta.0);
+                    etag.update(&buf[..]);
+                }
+
                 // Calculate the number of subtitle samples: starting to ending time (rounding up).
-                let start_sec = (s.s.start + recording::Duration(d.start as i64)).unix_seconds();
-                let end_sec = (s.s.start
-                    + recording::Duration(d.end as i64 + TIME_UNITS_PER_SEC - 1))
-                .unix_seconds();
+                let r = s.subtitle_range();
+                let start_sec = r.start.unix_seconds();
+                let end_sec = (r.end + recording::Duration(TIME_UNITS_PER_SEC - 1)).unix_seconds();
                 s.num_subtitle_samples = (end_sec - start_sec) as u16;
                 self.num_subtitle_samples += s.num_subtitle_samples as u32;
             }
@@ -922,9 +977,9 @@ impl FileBuilder {
             cursor
                 .write_i32::<BigEndian>(d.end)
                 .err_kind(ErrorKind::Internal)?;
-            etag.update(cursor.into_inner())
-                .err_kind(ErrorKind::Internal)?;
+            etag.update(cursor.into_inner());
         }
+        drop(l);
         let max_end = match max_end {
             None => 0,
             Some(v) => v.unix_seconds(),
@@ -937,10 +992,15 @@ impl FileBuilder {
         self.body.slices.reserve(est_slices);
         const EST_BUF_LEN: usize = 2048;
         self.body.buf.reserve(EST_BUF_LEN);
+        let mut moof_pos = 0;
         let initial_sample_byte_pos = match self.type_ {
             Type::MediaSegment => {
+                let referenced_size_pos = self.append_sidx()?;
+                self.body.flush_buf()?;
+                moof_pos = self.body.slices.len();
                 self.append_moof()?;
                 let p = self.append_mdat()?;
+                let referenced_size = self.body.slices.len() - moof_pos;
 
                 // If the segment is > 4 GiB, the 32-bit trun data offsets are untrustworthy.
                 // We'd need multiple moof+mdat sequences to support large media segments properly.
@@ -952,6 +1012,12 @@ impl FileBuilder {
                     );
                 }
 
+                // Fill in the sidx's referenced_size left as a placeholder above. The top bit is
+                // reference_type, which is 0 (the reference is to media, not to another sidx).
+                BigEndian::write_u32(
+                    &mut self.body.buf[referenced_size_pos..referenced_size_pos + 4],
+                    referenced_size as u32,
+                );
                 p
             }
             Type::InitSegment => {
@@ -998,7 +1064,7 @@ impl FileBuilder {
         debug!("slices: {:?}", self.body.slices);
         let last_modified =
             ::std::time::UNIX_EPOCH + ::std::time::Duration::from_secs(max_end as u64);
-        let etag = etag.finish().err_kind(ErrorKind::Internal)?;
+        let etag = etag.finish();
         Ok(File(Arc::new(FileInner {
             db,
             dirs_by_stream_id,
@@ -1007,10 +1073,12 @@ impl FileBuilder {
             buf: self.body.buf,
             video_sample_entries: self.video_sample_entries,
             initial_sample_byte_pos,
+            moof_pos,
             last_modified,
             etag: HeaderValue::try_from(format!("\"{}\"", &strutil::hex(&etag)))
                 .expect("hex string should be valid UTF-8"),
             content_disposition: self.content_disposition,
+            read_pool: self.read_pool,
         })))
     }
 
@@ -1091,6 +1159,46 @@ impl FileBuilder {
         })
     }
 
+    /// Appends a `SegmentIndexBox` version 1 (ISO/IEC 14496-12 section 8.16.3) describing the
+    /// single `moof`+`mdat` pair which follows it. This lets players such as the MSE-based ones
+    /// seek precisely within the media segment without parsing the `moof`.
+    ///
+    /// Returns the position within `buf` of the `referenced_size` field, which the caller must
+    /// fill in once the `mdat` length is known.
+    fn append_sidx(&mut self) -> Result<usize, Error> {
+        // The subsegment spans from the first key frame of each segment (which may precede the
+        // desired start) to the desired end.
+        let subsegment_duration: u64 = self
+            .segments
+            .iter()
+            .map(|s| (s.s.desired_range_90k.end - s.s.actual_start_90k()) as u64)
+            .sum();
+        if subsegment_duration > u64::from(u32::max_value()) {
+            bail_t!(
+                InvalidArgument,
+                "media segment has duration {}, greater than allowed by sidx",
+                subsegment_duration
+            );
+        }
+        let referenced_size_pos;
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"sidx\x01\x00\x00\x00");
+            self.body.append_u32(1); // reference_ID = track_id 1
+            self.body.append_u32(TIME_UNITS_PER_SEC as u32); // timescale
+            self.body.append_u64(0); // earliest_presentation_time; matches tfdt.
+            self.body.append_u64(0); // first_offset: the moof immediately follows.
+            self.body.append_u32(1); // reserved = 0, reference_count = 1
+            referenced_size_pos = self.body.buf.len();
+            self.body.append_u32(0); // placeholder for reference_type + referenced_size
+            self.body.append_u32(subsegment_duration as u32);
+
+            // starts_with_SAP = 1, SAP_type = 1, SAP_delta_time = 0.
+            // Each segment starts at a key frame.
+            self.body.append_u32(0x9000_0000);
+        })?;
+        Ok(referenced_size_pos)
+    }
+
     /// Appends a `MovieFragmentBox` (ISO/IEC 14496-12 section 8.8.4).
     fn append_moof(&mut self) -> Result<(), Error> {
         write_length!(self, {
@@ -1395,9 +1503,8 @@ impl FileBuilder {
 
             let mut entry_count = 0;
             for s in &self.segments {
-                let r = &s.s.desired_range_90k;
-                let start = s.s.start + recording::Duration(r.start as i64);
-                let end = s.s.start + recording::Duration(r.end as i64);
+                let r = s.subtitle_range();
+                let (start, end) = (r.start, r.end);
                 let start_next_sec =
                     recording::Time(start.0 + TIME_UNITS_PER_SEC - (start.0 % TIME_UNITS_PER_SEC));
                 if end <= start_next_sec {
@@ -1591,15 +1698,19 @@ impl BodyState {
 
 struct FileInner {
     db: Arc<db::Database>,
-    dirs_by_stream_id: Arc<::fnv::FnvHashMap<i32, Arc<dir::SampleFileDir>>>,
+    dirs_by_stream_id: Arc<::fnv::FnvHashMap<i32, dir::StreamDirs>>,
     segments: Vec<Segment>,
     slices: Slices<Slice>,
     buf: Vec<u8>,
     video_sample_entries: SmallVec<[Arc<db::VideoSampleEntry>; 1]>,
     initial_sample_byte_pos: u64,
+
+    /// The byte position of the `moof` within a `Type::MediaSegment`; 0 otherwise.
+    moof_pos: u64,
     last_modified: SystemTime,
     etag: HeaderValue,
     content_disposition: Option<HeaderValue>,
+    read_pool: Option<Arc<bufpool::Pool>>,
 }
 
 impl FileInner {
@@ -1618,7 +1729,8 @@ impl FileInner {
     }
 
     /// Gets a `Chunk` of video sample data from disk.
-    /// This works by `mmap()`ing in the data. There are a couple caveats:
+    /// This works by `mmap()`ing in the data, registered with `sendfile` so that connections which
+    /// support it can send it without touching the mapping. There are a couple caveats:
     ///
     ///    * The thread which reads the resulting slice is likely to experience major page faults.
     ///      Eventually this will likely be rewritten to `mmap()` the memory in another thread, and
@@ -1626,32 +1738,91 @@ impl FileInner {
     ///
     ///    * If the backing file is truncated, the program will crash with `SIGBUS`. This shouldn't
     ///      happen because nothing should be touching Moonfire NVR's files but itself.
+    ///
+    /// Encrypted sample files are instead read and decrypted into memory.
     fn get_video_sample_data(&self, i: usize, r: Range<u64>) -> Result<Chunk, Error> {
         let s = &self.segments[i];
-        let f = self
+        let dir = self
             .dirs_by_stream_id
             .get(&s.s.id.stream())
-            .ok_or_else(|| format_err_t!(NotFound, "{}: stream not found", s.s.id))?
-            .open_file(s.s.id)
-            .err_kind(ErrorKind::Unknown)?;
+            .and_then(|d| d.get(s.s.id, s.recording_flags))
+            .ok_or_else(|| format_err_t!(NotFound, "{}: stream not found", s.s.id))?;
         let start = s.s.sample_file_range().start + r.start;
-        let mmap = Box::new(unsafe {
-            memmap::MmapOptions::new()
-                .offset(start)
-                .len((r.end - r.start) as usize)
-                .map(&f)
-                .err_kind(ErrorKind::Internal)?
-        });
+        if s.is_encrypted() {
+            let v = dir
+                .read_encrypted(s.s.id, start..start + (r.end - r.start))
+                .err_kind(ErrorKind::Unknown)?;
+            return Ok(ARefss::new(v).map(|v| &v[..]).into());
+        }
+        let f = dir.open_file(s.s.id).err_kind(ErrorKind::Unknown)?;
+        let mmap = Box::new(
+            sendfile::Mapping::new(f, start, (r.end - r.start) as usize)
+                .err_kind(ErrorKind::Internal)?,
+        );
         use core::ops::Deref;
         Ok(ARefss::new(mmap).map(|m| m.deref()).into())
     }
 
+    /// Returns a stream of video sample data read into buffers from `pool`.
+    /// Before returning each buffer, advises the kernel to read ahead the next one, so that
+    /// sequential playback rarely blocks on disk.
+    fn read_video_sample_data(
+        &self,
+        i: usize,
+        r: Range<u64>,
+        pool: Arc<bufpool::Pool>,
+    ) -> Box<dyn Stream<Item = Result<Chunk, BoxedError>> + Send + Sync> {
+        let s = &self.segments[i];
+        let f = self
+            .dirs_by_stream_id
+            .get(&s.s.id.stream())
+            .and_then(|d| d.get(s.s.id, s.recording_flags))
+            .ok_or_else(|| format_err_t!(NotFound, "{}: stream not found", s.s.id))
+            .and_then(|d| d.open_file(s.s.id).err_kind(ErrorKind::Unknown));
+        let f = match f {
+            Ok(f) => f,
+            Err(e) => return Box::new(stream::once(futures::future::ready(Err(wrap_error(e))))),
+        };
+        let start = s.s.sample_file_range().start + r.start;
+        let end = start + (r.end - r.start);
+        let id = s.s.id;
+        Box::new(stream::unfold(
+            (f, start),
+            move |(f, pos)| -> futures::future::Ready<Option<_>> {
+                if pos == end {
+                    return futures::future::ready(None);
+                }
+                let len = cmp::min(end - pos, pool.buf_len() as u64) as usize;
+                if pos + (len as u64) < end {
+                    use std::os::unix::io::AsRawFd;
+                    let next_len = cmp::min(end - pos - len as u64, pool.buf_len() as u64);
+                    unsafe {
+                        libc::posix_fadvise(
+                            f.as_raw_fd(),
+                            (pos + len as u64) as libc::off_t,
+                            next_len as libc::off_t,
+                            libc::POSIX_FADV_WILLNEED,
+                        )
+                    };
+                }
+                let mut buf = pool.get();
+                buf.resize(len, 0);
+                use std::os::unix::fs::FileExt;
+                if let Err(e) = f.read_exact_at(&mut buf[..], pos) {
+                    let e = format_err_t!(Unknown, "{}: read at {}: {}", id, pos, e);
+                    return futures::future::ready(Some((Err(wrap_error(e)), (f, end))));
+                }
+                let c = ARefss::new(Box::new(buf)).map(|b| &b[..]).into();
+                futures::future::ready(Some((Ok(c), (f, pos + len as u64))))
+            },
+        ))
+    }
+
     fn get_subtitle_sample_data(&self, i: usize, r: Range<u64>, l: u64) -> Result<Chunk, Error> {
         let s = &self.segments[i];
-        let d = &s.s.desired_range_90k;
-        let start_sec = (s.s.start + recording::Duration(d.start as i64)).unix_seconds();
-        let end_sec =
-            (s.s.start + recording::Duration(d.end as i64 + TIME_UNITS_PER_SEC - 1)).unix_seconds();
+        let r = s.subtitle_range();
+        let start_sec = r.start.unix_seconds();
+        let end_sec = (r.end + recording::Duration(TIME_UNITS_PER_SEC - 1)).unix_seconds();
         let mut v = Vec::with_capacity(l as usize);
         for ts in start_sec..end_sec {
             v.write_u16::<BigEndian>(SUBTITLE_LENGTH as u16)
@@ -1777,13 +1948,12 @@ mod tests {
     use db::writer;
     use futures::stream::TryStreamExt;
     use http_serve::{self, Entity};
-    use log::info;
-    use openssl::hash;
     use std::fs;
     use std::ops::Range;
     use std::path::Path;
     use std::pin::Pin;
     use std::str;
+    use tracing::info;
 
     async fn fill_slice<E: http_serve::Entity>(slice: &mut [u8], e: &E, start: u64)
     where
@@ -1802,23 +1972,19 @@ mod tests {
     }
 
     /// Returns the SHA-1 digest of the given `Entity`.
-    async fn digest<E: http_serve::Entity>(e: &E) -> hash::DigestBytes
+    async fn digest<E: http_serve::Entity>(e: &E) -> [u8; 20]
     where
         E::Error: ::std::fmt::Debug,
     {
         Pin::from(e.get_range(0..e.len()))
-            .try_fold(
-                hash::Hasher::new(hash::MessageDigest::sha1()).unwrap(),
-                |mut sha1, chunk| {
-                    let c: &[u8] = chunk.bytes();
-                    sha1.update(c).unwrap();
-                    futures::future::ok::<_, E::Error>(sha1)
-                },
-            )
+            .try_fold(base::crypto::Sha1::new(), |mut sha1, chunk| {
+                let c: &[u8] = chunk.bytes();
+                sha1.update(c);
+                futures::future::ok::<_, E::Error>(sha1)
+            })
             .await
             .unwrap()
             .finish()
-            .unwrap()
     }
 
     /// Information used within `BoxCursor` to describe a box on the stack.
@@ -2057,7 +2223,7 @@ mod tests {
                 extra_data.rfc6381_codec,
             )
             .unwrap();
-        let dir = db.dirs_by_stream_id.get(&TEST_STREAM_ID).unwrap();
+        let dir = &db.dirs_by_stream_id.get(&TEST_STREAM_ID).unwrap().stripes[0];
         let mut output = writer::Writer::new(
             dir,
             &db.db,
@@ -2462,11 +2628,35 @@ mod tests {
             2 + 4 + 6..2 + 4 + 6 + 8 + 1,
         )
         .unwrap();
+
+        // As in test_round_trip, the etag must change whenever the output does.
+        const EXPECTED_ETAG: &'static str = "\"02c2dce3b9631cb45a4f004ef03b7747e92afae8\"";
+        assert_eq!(
+            Some(HeaderValue::from_str(EXPECTED_ETAG).unwrap()),
+            mp4.etag()
+        );
         let mut cursor = BoxCursor::new(mp4);
         cursor.down().await;
 
         let mut mdat = cursor.clone();
         assert!(mdat.find(b"mdat").await);
+        let mut moof = cursor.clone();
+        assert!(moof.find(b"moof").await);
+        let moof_start = moof.interior().start - 8;
+
+        assert_eq!(cursor.name(), "sidx");
+        assert_eq!(cursor.get_u32(0).await, 0x0100_0000); // version + flags
+        assert_eq!(cursor.get_u32(4).await, 1); // reference_ID
+        assert_eq!(cursor.get_u32(8).await, 90_000); // timescale
+        assert_eq!(cursor.get_u64(12).await, 0); // earliest_presentation_time
+        assert_eq!(cursor.get_u64(20).await, 0); // first_offset
+        assert_eq!(cursor.get_u32(28).await, 1); // reserved + reference_count
+        assert_eq!(
+            cursor.get_u32(32).await as u64, // reference_type + referenced_size
+            mdat.interior().end - moof_start
+        );
+        assert_eq!(cursor.get_u32(36).await, 6 + 8 + 1); // subsegment_duration
+        assert_eq!(cursor.get_u32(40).await, 0x9000_0000); // SAP
 
         assert!(cursor.find(b"moof").await);
         cursor.down().await;
@@ -2474,7 +2664,10 @@ mod tests {
         cursor.down().await;
         assert!(cursor.find(b"trun").await);
         assert_eq!(cursor.get_u32(4).await, 2);
-        assert_eq!(cursor.get_u32(8).await as u64, mdat.interior().start);
+        assert_eq!(
+            cursor.get_u32(8).await as u64,
+            mdat.interior().start - moof_start
+        );
         assert_eq!(cursor.get_u32(12).await, 174063616); // first_sample_flags
         assert_eq!(cursor.get_u32(16).await, 6); // sample duration
         assert_eq!(cursor.get_u32(20).await, 9); // sample size
@@ -2485,7 +2678,7 @@ mod tests {
         assert_eq!(cursor.get_u32(4).await, 1);
         assert_eq!(
             cursor.get_u32(8).await as u64,
-            mdat.interior().start + 9 + 12
+            mdat.interior().start - moof_start + 9 + 12
         );
         assert_eq!(cursor.get_u32(12).await, 174063616); // first_sample_flags
         assert_eq!(cursor.get_u32(16).await, 1); // sample duration
@@ -2509,7 +2702,7 @@ mod tests {
             "17376879bcf872dd4ad1197225a32d5473fb0dc6",
             strutil::hex(&sha1[..])
         );
-        const EXPECTED_ETAG: &'static str = "\"953dcf1a61debe785d5dec3ae2d3992a819b68ae\"";
+        const EXPECTED_ETAG: &'static str = "\"7b55d0bd4370712bf1a7549f6383ca51b1eb97e9\"";
         assert_eq!(
             Some(HeaderValue::from_str(EXPECTED_ETAG).unwrap()),
             mp4.etag()
@@ -2519,6 +2712,38 @@ mod tests {
         db.syncer_join.join().unwrap();
     }
 
+    /// Tests that reading through a (deliberately tiny) buffer pool produces the same bytes as
+    /// `mmap()`ing.
+    #[tokio::test]
+    async fn test_round_trip_with_read_pool() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        copy_mp4_to_db(&db);
+        let mut builder = FileBuilder::new(Type::Normal);
+        builder.read_via(crate::bufpool::Pool::new(4096));
+        let all_time = recording::Time(i64::min_value())..recording::Time(i64::max_value());
+        {
+            let l = db.db.lock();
+            l.list_recordings_by_time(TEST_STREAM_ID, all_time, &mut |r| {
+                let d = r.duration_90k;
+                builder.append(&*l, r, 0..d).unwrap();
+                Ok(())
+            })
+            .unwrap();
+        }
+        let mp4 = builder
+            .build(db.db.clone(), db.dirs_by_stream_id.clone())
+            .unwrap();
+        let sha1 = digest(&mp4).await;
+        assert_eq!(
+            "17376879bcf872dd4ad1197225a32d5473fb0dc6",
+            strutil::hex(&sha1[..])
+        );
+        drop(db.syncer_channel);
+        db.db.lock().clear_on_flush();
+        db.syncer_join.join().unwrap();
+    }
+
     #[tokio::test]
     async fn test_round_trip_with_subtitles() {
         testutil::init();
@@ -2536,7 +2761,7 @@ mod tests {
             "1cd90e0b49747cc54c953153d6709f2fb5df6b14",
             strutil::hex(&sha1[..])
         );
-        const EXPECTED_ETAG: &'static str = "\"736655313f10747528a663190517620cdffea6d0\"";
+        const EXPECTED_ETAG: &'static str = "\"f17085373bbee7d2ffc99046575a1ef28f8134e0\"";
         assert_eq!(
             Some(HeaderValue::from_str(EXPECTED_ETAG).unwrap()),
             mp4.etag()
@@ -2563,7 +2788,7 @@ mod tests {
             "49893e3997da6bc625a04b09abf4b1ddbe0bc85d",
             strutil::hex(&sha1[..])
         );
-        const EXPECTED_ETAG: &'static str = "\"e87ed99dea31b7c4d1e9186045abaf5ac3c2d2f8\"";
+        const EXPECTED_ETAG: &'static str = "\"c48b2819f74b090d89c27fa615ab34e445a4b322\"";
         assert_eq!(
             Some(HeaderValue::from_str(EXPECTED_ETAG).unwrap()),
             mp4.etag()
@@ -2590,7 +2815,7 @@ mod tests {
             "0615feaa3c50a7889fb0e6842de3bd3d3143bc78",
             strutil::hex(&sha1[..])
         );
-        const EXPECTED_ETAG: &'static str = "\"6f0d21a6027b0e444f404a68527dbf5c9a5c1a26\"";
+        const EXPECTED_ETAG: &'static str = "\"48da7c8f9c15c318ef91ae00148356b3247b671f\"";
         assert_eq!(
             Some(HeaderValue::from_str(EXPECTED_ETAG).unwrap()),
             mp4.etag()
@@ -0,0 +1,439 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Optional MQTT integration, for home automation systems such as Home Assistant or Node-RED.
+//!
+//! When the `mqtt_url` key is set in the `config` table, `moonfire-nvr run` connects to that
+//! MQTT 3.1.1 broker and publishes retained messages under the topic prefix (the
+//! `mqtt_topic_prefix` key, defaulting to `moonfire-nvr`):
+//!
+//! * `<prefix>/status`: `online` while connected, `offline` (via the last will) otherwise.
+//! * `<prefix>/cameras/<camera>/<stream>/recording`: `true` while the stream is being recorded,
+//!   `false` otherwise. A recording stream is connected to the camera, so this also indicates
+//!   the camera's connectivity.
+//! * `<prefix>/cameras/<camera>/<stream>/fsBytes` and `.../retainBytes`: the stream's disk usage
+//!   and limit, as in the JSON API.
+//! * `<prefix>/signals/<id>`: the current state of each signal, such as ONVIF motion.
+//!
+//! It also subscribes to `<prefix>/signals/<id>/set`. A message with payload `<state>` sets the
+//! signal's state from now on; `<state> <seconds>` sets it for the given duration.
+//!
+//! This is a minimal client: it supports only QoS 0 and unencrypted connections.
+
+use base::clock::Clocks;
+use byteorder::{BigEndian, ByteOrder};
+use db::recording;
+use failure::{bail, format_err, Error};
+use futures::channel::mpsc;
+use futures::future::Either;
+use futures::stream::StreamExt;
+use std::collections::BTreeMap;
+use std::str::{self, FromStr};
+use std::sync::Arc;
+use std::time::{Duration as StdDuration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{info, trace, warn};
+
+const DEFAULT_PORT: u16 = 1883;
+const DEFAULT_TOPIC_PREFIX: &'static str = "moonfire-nvr";
+
+/// The keep alive interval sent to the broker. Pings are sent at half this interval.
+const KEEP_ALIVE_SEC: u16 = 60;
+
+/// How often to check for changes to publish.
+const POLL_INTERVAL: StdDuration = StdDuration::from_secs(5);
+
+// Control packet types (MQTT 3.1.1 section 2.2.1).
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+
+/// A connection to a MQTT broker, as configured in the database.
+pub struct Client<C: Clocks + Clone> {
+    db: Arc<db::Database<C>>,
+    host: String,
+    port: u16,
+    username: Option<String>,
+    password: Option<String>,
+    topic_prefix: String,
+}
+
+impl<C: Clocks + Clone> Client<C> {
+    /// Returns a client for the broker configured in the database, or `None` if MQTT isn't
+    /// configured.
+    pub fn new(db: &Arc<db::Database<C>>) -> Result<Option<Self>, Error> {
+        let l = db.lock();
+        let url = match l.get_config("mqtt_url")? {
+            None => return Ok(None),
+            Some(u) => u,
+        };
+        let topic_prefix = l
+            .get_config("mqtt_topic_prefix")?
+            .unwrap_or_else(|| DEFAULT_TOPIC_PREFIX.to_owned());
+        let url =
+            url::Url::parse(&url).map_err(|e| format_err!("bad mqtt_url {:?}: {}", url, e))?;
+        if url.scheme() != "mqtt" {
+            bail!("mqtt_url {} must have scheme mqtt", url);
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| format_err!("mqtt_url {} has no host", url))?
+            .to_owned();
+        Ok(Some(Client {
+            db: db.clone(),
+            host,
+            port: url.port().unwrap_or(DEFAULT_PORT),
+            username: Some(url.username())
+                .filter(|u| !u.is_empty())
+                .map(str::to_owned),
+            password: url.password().map(str::to_owned),
+            topic_prefix,
+        }))
+    }
+
+    /// Returns the broker's `host:port`, for logging.
+    pub fn broker(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    /// Runs until the returned future is dropped, reconnecting on error.
+    pub async fn run(self) {
+        loop {
+            if let Err(e) = self.run_once().await {
+                let sleep_time = StdDuration::from_secs(10);
+                warn!("MQTT: sleeping for {:?} after error: {:?}", sleep_time, e);
+                tokio::time::delay_for(sleep_time).await;
+            }
+        }
+    }
+
+    async fn run_once(&self) -> Result<(), Error> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let (mut r, mut w) = tokio::io::split(stream);
+        w.write_all(&self.connect_packet()).await?;
+        let (first, body) = read_packet(&mut r).await?;
+        if first >> 4 != CONNACK || body.len() != 2 {
+            bail!("expected CONNACK; got packet type {}", first >> 4);
+        }
+        if body[1] != 0 {
+            bail!("broker refused connection with return code {}", body[1]);
+        }
+        info!("MQTT: connected to {}", self.broker());
+        w.write_all(&subscribe_packet(1, &self.topic("signals/+/set")))
+            .await?;
+        w.write_all(&publish_packet(&self.topic("status"), b"online", true))
+            .await?;
+
+        // Read and write concurrently within this task. When either fails, the other is dropped
+        // along with the connection.
+        let (tx, rx) = mpsc::unbounded();
+        let reader = Box::pin(read_loop(r, tx));
+        let writer = Box::pin(self.write_loop(w, rx));
+        match futures::future::select(reader, writer).await {
+            Either::Left((r, _)) => r,
+            Either::Right((r, _)) => r,
+        }
+    }
+
+    async fn write_loop<W: AsyncWrite + Unpin>(
+        &self,
+        mut w: W,
+        mut incoming: mpsc::UnboundedReceiver<(u8, Vec<u8>)>,
+    ) -> Result<(), Error> {
+        let mut published: BTreeMap<String, String> = BTreeMap::new();
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        let mut last_ping = Instant::now();
+        loop {
+            for (topic, payload) in self.snapshot() {
+                if published.get(&topic) == Some(&payload) {
+                    continue;
+                }
+                trace!("MQTT: publishing {}={}", topic, payload);
+                w.write_all(&publish_packet(&topic, payload.as_bytes(), true))
+                    .await?;
+                published.insert(topic, payload);
+            }
+            if last_ping.elapsed() >= StdDuration::from_secs(u64::from(KEEP_ALIVE_SEC / 2)) {
+                w.write_all(&[PINGREQ << 4, 0]).await?;
+                last_ping = Instant::now();
+            }
+            match futures::future::select(Box::pin(interval.tick()), incoming.next()).await {
+                Either::Left(_) => {}
+                Either::Right((None, _)) => bail!("reader stopped"),
+                Either::Right((Some((first, body)), _)) => {
+                    if let Err(e) = self.handle_publish(first, &body) {
+                        warn!("MQTT: ignoring message: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handles an incoming `PUBLISH` to `<prefix>/signals/<id>/set`.
+    fn handle_publish(&self, first: u8, body: &[u8]) -> Result<(), Error> {
+        let (topic, payload) = parse_publish(first, body)?;
+        let payload = str::from_utf8(payload)?;
+        let prefix = self.topic("signals/");
+        let signal = if topic.starts_with(&prefix) && topic.ends_with("/set") {
+            u32::from_str(&topic[prefix.len()..topic.len() - "/set".len()]).ok()
+        } else {
+            None
+        };
+        let signal = signal.ok_or_else(|| format_err!("unexpected topic {:?}", topic))?;
+        let mut parts = payload.split_whitespace();
+        let state = parts
+            .next()
+            .and_then(|s| u16::from_str(s).ok())
+            .ok_or_else(|| format_err!("bad payload {:?} on {}", payload, topic))?;
+        let now = recording::Time::new(self.db.clocks().realtime());
+        let end = match parts.next() {
+            None => recording::Time(i64::max_value()),
+            Some(s) => {
+                let secs = i64::from_str(s)
+                    .map_err(|_| format_err!("bad payload {:?} on {}", payload, topic))?;
+                now + recording::Duration(secs * recording::TIME_UNITS_PER_SEC)
+            }
+        };
+        info!("MQTT: setting signal {} to state {}", signal, state);
+        self.db
+            .lock()
+            .update_signals(now..end, &[signal], &[state])
+            .map_err(|e| format_err!("unable to update signal {}: {}", signal, e))
+    }
+
+    /// Returns all topics to publish with their current values.
+    fn snapshot(&self) -> Vec<(String, String)> {
+        let l = self.db.lock();
+        let mut out = Vec::new();
+        for s in l.streams_by_id().values() {
+            let camera = match l.cameras_by_id().get(&s.camera_id) {
+                None => continue,
+                Some(c) => c,
+            };
+            let prefix = self.topic(&format!(
+                "cameras/{}/{}/",
+                topic_level(&camera.short_name),
+                s.type_.as_str()
+            ));
+            out.push((prefix.clone() + "recording", s.is_recording().to_string()));
+            out.push((prefix.clone() + "fsBytes", s.fs_bytes.to_string()));
+            out.push((prefix + "retainBytes", s.retain_bytes.to_string()));
+        }
+        let mut states: BTreeMap<u32, u16> = l.signals_by_id().keys().map(|&id| (id, 0)).collect();
+        let now = recording::Time::new(self.db.clocks().realtime());
+        l.list_changes_by_time(now..now, &mut |c| {
+            states.insert(c.signal, c.state);
+        });
+        for (id, state) in states {
+            out.push((self.topic(&format!("signals/{}", id)), state.to_string()));
+        }
+        out
+    }
+
+    fn topic(&self, suffix: &str) -> String {
+        format!("{}/{}", self.topic_prefix, suffix)
+    }
+
+    /// Returns a `CONNECT` packet (MQTT 3.1.1 section 3.1) with a last will marking the server
+    /// offline.
+    fn connect_packet(&self) -> Vec<u8> {
+        let mut b = Vec::new();
+        put_bytes(&mut b, b"MQTT");
+        b.push(4); // protocol level: 3.1.1.
+
+        // Connect flags: clean session, will flag, will retain.
+        let mut flags = 0x02 | 0x04 | 0x20;
+        if self.username.is_some() {
+            flags |= 0x80;
+        }
+        if self.password.is_some() {
+            flags |= 0x40;
+        }
+        b.push(flags);
+        b.extend_from_slice(&KEEP_ALIVE_SEC.to_be_bytes());
+        put_bytes(&mut b, self.topic_prefix.as_bytes()); // client identifier
+        put_bytes(&mut b, self.topic("status").as_bytes()); // will topic
+        put_bytes(&mut b, b"offline"); // will message
+        if let Some(ref u) = self.username {
+            put_bytes(&mut b, u.as_bytes());
+        }
+        if let Some(ref p) = self.password {
+            put_bytes(&mut b, p.as_bytes());
+        }
+        packet(CONNECT << 4, &b)
+    }
+}
+
+/// Reads packets, sending `PUBLISH`es to `tx`.
+async fn read_loop<R: AsyncRead + Unpin>(
+    mut r: R,
+    tx: mpsc::UnboundedSender<(u8, Vec<u8>)>,
+) -> Result<(), Error> {
+    loop {
+        let (first, body) = read_packet(&mut r).await?;
+        match first >> 4 {
+            PUBLISH => tx
+                .unbounded_send((first, body))
+                .map_err(|_| format_err!("writer stopped"))?,
+            SUBACK => {
+                if body.get(2) == Some(&0x80) {
+                    bail!("broker refused subscription");
+                }
+            }
+            PINGRESP => {}
+            t => bail!("unexpected packet type {}", t),
+        }
+    }
+}
+
+/// Reads a packet, returning the first byte (type and flags) and the remainder after the length.
+async fn read_packet<R: AsyncRead + Unpin>(r: &mut R) -> Result<(u8, Vec<u8>), Error> {
+    let first = r.read_u8().await?;
+    let mut len = 0usize;
+    for i in 0.. {
+        if i == 4 {
+            bail!("malformed remaining length");
+        }
+        let b = r.read_u8().await?;
+        len |= usize::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut body = vec![0u8; len];
+    r.read_exact(&mut body).await?;
+    Ok((first, body))
+}
+
+/// Parses a `PUBLISH` packet (MQTT 3.1.1 section 3.3) into its topic and payload.
+fn parse_publish(first: u8, body: &[u8]) -> Result<(&str, &[u8]), Error> {
+    if body.len() < 2 {
+        bail!("truncated PUBLISH");
+    }
+    let topic_end = 2 + usize::from(BigEndian::read_u16(&body[..2]));
+    let packet_id_len = if (first >> 1) & 0x3 != 0 { 2 } else { 0 };
+    if body.len() < topic_end + packet_id_len {
+        bail!("truncated PUBLISH");
+    }
+    let topic = str::from_utf8(&body[2..topic_end])?;
+    Ok((topic, &body[topic_end + packet_id_len..]))
+}
+
+/// Returns a QoS 0 `PUBLISH` packet.
+fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut b = Vec::with_capacity(2 + topic.len() + payload.len());
+    put_bytes(&mut b, topic.as_bytes());
+    b.extend_from_slice(payload);
+    packet(PUBLISH << 4 | (retain as u8), &b)
+}
+
+/// Returns a `SUBSCRIBE` packet (MQTT 3.1.1 section 3.8) for a single filter at QoS 0.
+fn subscribe_packet(packet_id: u16, filter: &str) -> Vec<u8> {
+    let mut b = Vec::new();
+    b.extend_from_slice(&packet_id.to_be_bytes());
+    put_bytes(&mut b, filter.as_bytes());
+    b.push(0); // requested QoS
+    packet(SUBSCRIBE << 4 | 0x2, &b)
+}
+
+/// Returns a packet with the given first byte and body, adding the remaining length.
+fn packet(first: u8, body: &[u8]) -> Vec<u8> {
+    let mut p = Vec::with_capacity(5 + body.len());
+    p.push(first);
+    let mut len = body.len();
+    loop {
+        let mut b = (len & 0x7f) as u8;
+        len >>= 7;
+        if len > 0 {
+            b |= 0x80;
+        }
+        p.push(b);
+        if len == 0 {
+            break;
+        }
+    }
+    p.extend_from_slice(body);
+    p
+}
+
+/// Appends a length-prefixed string or binary field.
+fn put_bytes(b: &mut Vec<u8>, s: &[u8]) {
+    b.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    b.extend_from_slice(s);
+}
+
+/// Returns a topic level for the given name, replacing characters reserved by MQTT.
+fn topic_level(name: &str) -> String {
+    name.replace(|c| c == '/' || c == '+' || c == '#', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remaining_length() {
+        assert_eq!(packet(0xc0, b""), b"\xc0\x00");
+        let p = packet(0x30, &[0u8; 321]);
+        assert_eq!(&p[..3], b"\x30\xc1\x02");
+        assert_eq!(p.len(), 3 + 321);
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let p = publish_packet("moonfire-nvr/signals/1/set", b"2 30", false);
+        let (first, body) = read_packet(&mut &p[..]).await.unwrap();
+        assert_eq!(first >> 4, PUBLISH);
+        let (topic, payload) = parse_publish(first, &body).unwrap();
+        assert_eq!(topic, "moonfire-nvr/signals/1/set");
+        assert_eq!(payload, b"2 30");
+    }
+
+    #[test]
+    fn parse_qos1_publish() {
+        // Topic "a/b", packet id 0x0102, payload "x".
+        let body = b"\x00\x03a/b\x01\x02x";
+        let (topic, payload) = parse_publish(0x32, &body[..]).unwrap();
+        assert_eq!(topic, "a/b");
+        assert_eq!(payload, b"x");
+        parse_publish(0x32, &body[..5]).unwrap_err();
+    }
+
+    #[test]
+    fn topic_levels() {
+        assert_eq!(topic_level("back yard"), "back yard");
+        assert_eq!(topic_level("a/b+c#"), "a_b_c_");
+    }
+}
@@ -0,0 +1,447 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! OpenID Connect single sign-on, as a relying party.
+//!
+//! This uses the authorization code flow with PKCE: `GET /api/login/oidc` redirects the browser
+//! to the provider's authorization endpoint, and the provider redirects back to
+//! `GET /api/login/oidc/callback` with a code, which the server exchanges for an ID token at the
+//! provider's token endpoint.
+//!
+//! The provider is configured through the `config` table:
+//!
+//! * `oidc_issuer_url`: the provider's issuer identifier, such as `https://accounts.google.com`.
+//!   OIDC is enabled iff this is set. The provider's endpoints are found through
+//!   `<issuer>/.well-known/openid-configuration` on first use.
+//! * `oidc_client_id` and `oidc_client_secret`: Moonfire NVR's client credentials, as
+//!   registered with the provider. The secret is sent with HTTP basic authentication
+//!   (`client_secret_basic`).
+//! * `oidc_redirect_url`: the full external URL of `/api/login/oidc/callback`.
+//! * `oidc_scopes` (optional): space-separated scopes to request in addition to `openid`.
+//!   Defaults to `profile email`.
+//! * `oidc_username_claim` (optional): the ID token claim holding the Moonfire NVR username.
+//!   Defaults to `preferred_username`.
+//! * `oidc_permissions_claim` and `oidc_permissions_map` (optional): if set, the session's
+//!   permissions are derived from the values of the given claim (a string or array of strings,
+//!   such as `groups`) rather than copied from the user. The map has the same form as
+//!   `saml_permissions_map`; see `saml.rs`.
+//!
+//! The ID token comes straight from the token endpoint over TLS, so as OpenID Connect Core 1.0
+//! section 3.1.3.7 allows, the TLS server certificate stands in for checking the token's
+//! signature. The token endpoint must therefore use `https`. The `iss`, `aud`, `exp`, and
+//! `nonce` claims are still checked.
+
+use crate::saml;
+use base::strutil;
+use failure::{bail, format_err, Error};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use url::form_urlencoded;
+
+/// How long a login attempt remains valid.
+const REQUEST_LIFETIME_SEC: i64 = 600;
+
+/// The maximum number of outstanding login attempts; the oldest are forgotten beyond this.
+const MAX_PENDING_REQUESTS: usize = 1000;
+
+/// Allowed clock skew between Moonfire NVR and the provider.
+const CLOCK_SKEW_SEC: i64 = 60;
+
+struct Config {
+    issuer_url: String,
+    client_id: String,
+    client_secret: String,
+    redirect_url: String,
+    scopes: String,
+    username_claim: String,
+    permissions_claim: Option<String>,
+    permissions_map: Vec<(String, db::Permissions)>,
+}
+
+/// The subset of the provider's metadata used here.
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// An outstanding login attempt.
+struct Pending {
+    state: String,
+    nonce: String,
+    code_verifier: String,
+    redirect: String,
+    expires_sec: i64,
+}
+
+/// An OpenID Connect relying party, as configured in the database.
+pub struct RelyingParty {
+    config: Config,
+    client: reqwest::Client,
+
+    /// The provider's metadata, once fetched.
+    discovery: Mutex<Option<Arc<Discovery>>>,
+
+    /// Outstanding login attempts, oldest first.
+    pending: Mutex<VecDeque<Pending>>,
+}
+
+/// A user authenticated by the provider.
+#[derive(Debug)]
+pub struct Login {
+    pub username: String,
+
+    /// Permissions derived from the permissions claim, or `None` if no such claim is configured
+    /// and the user's own permissions apply.
+    pub permissions: Option<db::Permissions>,
+
+    /// The local path to return to, as passed to `RelyingParty::login_url`.
+    pub redirect: String,
+}
+
+impl RelyingParty {
+    /// Returns the configured relying party, or `None` if OIDC isn't configured.
+    pub fn new(l: &db::LockedDatabase) -> Result<Option<Self>, Error> {
+        let issuer_url = match l.get_config("oidc_issuer_url")? {
+            None => return Ok(None),
+            Some(u) => u.trim_end_matches('/').to_owned(),
+        };
+        let required = |key| {
+            l.get_config(key)?
+                .ok_or_else(|| format_err!("{} must be set when oidc_issuer_url is", key))
+        };
+        let client_id = required("oidc_client_id")?;
+        let client_secret = required("oidc_client_secret")?;
+        let redirect_url = required("oidc_redirect_url")?;
+        let permissions_claim = l.get_config("oidc_permissions_claim")?;
+        let permissions_map = match permissions_claim {
+            None => Vec::new(),
+            Some(_) => saml::parse_permissions_map(&required("oidc_permissions_map")?)?,
+        };
+        Ok(Some(RelyingParty {
+            config: Config {
+                issuer_url,
+                client_id,
+                client_secret,
+                redirect_url,
+                scopes: l
+                    .get_config("oidc_scopes")?
+                    .unwrap_or_else(|| "profile email".to_owned()),
+                username_claim: l
+                    .get_config("oidc_username_claim")?
+                    .unwrap_or_else(|| "preferred_username".to_owned()),
+                permissions_claim,
+                permissions_map,
+            },
+            client: reqwest::Client::new(),
+            discovery: Mutex::new(None),
+            pending: Mutex::new(VecDeque::new()),
+        }))
+    }
+
+    /// Returns the provider's metadata, fetching it if necessary.
+    async fn discovery(&self) -> Result<Arc<Discovery>, Error> {
+        let cached = self.discovery.lock().clone();
+        if let Some(d) = cached {
+            return Ok(d);
+        }
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            self.config.issuer_url
+        );
+        let resp = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .map_err(|e| format_err!("unable to fetch {}: {}", url, e))?;
+        if !resp.status().is_success() {
+            bail!("unable to fetch {}: status {}", url, resp.status());
+        }
+        let d: Discovery = resp
+            .json()
+            .await
+            .map_err(|e| format_err!("unable to parse {}: {}", url, e))?;
+        if d.issuer.trim_end_matches('/') != self.config.issuer_url {
+            bail!(
+                "provider metadata has issuer {:?}; expected {:?}",
+                d.issuer,
+                self.config.issuer_url
+            );
+        }
+        if !d.token_endpoint.starts_with("https://") {
+            bail!("token endpoint {:?} doesn't use https", d.token_endpoint);
+        }
+        let d = Arc::new(d);
+        *self.discovery.lock() = Some(d.clone());
+        Ok(d)
+    }
+
+    /// Returns the authorization endpoint URL to redirect to, recording the new attempt as
+    /// pending. `redirect` is the local path to return to afterward.
+    pub async fn login_url(&self, redirect: &str, now_sec: i64) -> Result<String, Error> {
+        let d = self.discovery().await?;
+        let state = random_token()?;
+        let nonce = random_token()?;
+        let code_verifier = random_token()?;
+        let c = &self.config;
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &c.client_id)
+            .append_pair("redirect_uri", &c.redirect_url)
+            .append_pair("scope", &format!("openid {}", c.scopes))
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &code_challenge(&code_verifier))
+            .append_pair("code_challenge_method", "S256")
+            .finish();
+        let sep = if d.authorization_endpoint.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+
+        let mut pending = self.pending.lock();
+        while pending.len() >= MAX_PENDING_REQUESTS
+            || pending
+                .front()
+                .map(|p| p.expires_sec <= now_sec)
+                .unwrap_or(false)
+        {
+            pending.pop_front();
+        }
+        pending.push_back(Pending {
+            state,
+            nonce,
+            code_verifier,
+            redirect: redirect.to_owned(),
+            expires_sec: now_sec + REQUEST_LIFETIME_SEC,
+        });
+        Ok(format!("{}{}{}", d.authorization_endpoint, sep, query))
+    }
+
+    /// Completes a login attempt given the `code` and `state` the provider passed to the
+    /// callback at `now_sec`.
+    pub async fn process_callback(
+        &self,
+        code: &str,
+        state: &str,
+        now_sec: i64,
+    ) -> Result<Login, Error> {
+        // Consume the attempt first, so a code can't be replayed.
+        let p = {
+            let mut pending = self.pending.lock();
+            let i = pending
+                .iter()
+                .position(|p| p.state == state && p.expires_sec > now_sec)
+                .ok_or_else(|| format_err!("callback is for unknown or expired login attempt"))?;
+            pending.remove(i).unwrap()
+        };
+        let d = self.discovery().await?;
+        let c = &self.config;
+        let resp = self
+            .client
+            .post(&d.token_endpoint)
+            .basic_auth(&c.client_id, Some(&c.client_secret))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", c.redirect_url.as_str()),
+                ("code_verifier", p.code_verifier.as_str()),
+            ])
+            .send()
+            .await
+            .map_err(|e| format_err!("unable to reach token endpoint: {}", e))?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            bail!("token endpoint returned status {}: {}", status, body);
+        }
+        let t: TokenResponse = resp
+            .json()
+            .await
+            .map_err(|e| format_err!("unable to parse token response: {}", e))?;
+        let claims = decode_claims(&t.id_token)?;
+        let (username, permissions) = check_claims(c, &d.issuer, &p.nonce, &claims, now_sec)?;
+        Ok(Login {
+            username,
+            permissions,
+            redirect: p.redirect,
+        })
+    }
+}
+
+/// Returns a random URL-safe token with 160 bits of entropy.
+fn random_token() -> Result<String, Error> {
+    let mut raw = [0u8; 20];
+    base::crypto::rand_bytes(&mut raw)?;
+    Ok(strutil::hex(&raw))
+}
+
+/// Returns the PKCE `S256` code challenge for the given verifier.
+fn code_challenge(verifier: &str) -> String {
+    let digest = base::crypto::sha256(verifier.as_bytes());
+    base64::encode_config(&digest[..], base64::URL_SAFE_NO_PAD)
+}
+
+/// Decodes the claims of a JWT without checking its signature.
+fn decode_claims(jwt: &str) -> Result<serde_json::Value, Error> {
+    let mut parts = jwt.split('.');
+    let payload = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(_), Some(p), Some(_), None) => p,
+        _ => bail!("ID token isn't a JWS compact serialization"),
+    };
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .map_err(|e| format_err!("ID token payload isn't base64url: {}", e))?;
+    let claims: serde_json::Value = serde_json::from_slice(&payload)?;
+    if !claims.is_object() {
+        bail!("ID token payload isn't a JSON object");
+    }
+    Ok(claims)
+}
+
+/// Checks the ID token's claims, returning the username and (if configured) mapped permissions.
+fn check_claims(
+    c: &Config,
+    issuer: &str,
+    nonce: &str,
+    claims: &serde_json::Value,
+    now_sec: i64,
+) -> Result<(String, Option<db::Permissions>), Error> {
+    if claims["iss"].as_str() != Some(issuer) {
+        bail!(
+            "ID token has issuer {}; expected {:?}",
+            claims["iss"],
+            issuer
+        );
+    }
+    let aud_ok = match claims["aud"] {
+        serde_json::Value::String(ref a) => *a == c.client_id,
+        serde_json::Value::Array(ref a) => {
+            a.iter().any(|a| a.as_str() == Some(c.client_id.as_str()))
+        }
+        _ => false,
+    };
+    if !aud_ok {
+        bail!("ID token is not intended for {:?}", c.client_id);
+    }
+    match claims["exp"].as_i64() {
+        Some(exp) if exp > now_sec - CLOCK_SKEW_SEC => {}
+        Some(exp) => bail!("ID token expired at {}", exp),
+        None => bail!("ID token has no exp"),
+    }
+    if claims["nonce"].as_str() != Some(nonce) {
+        bail!("ID token has the wrong nonce");
+    }
+    let username = claims[c.username_claim.as_str()]
+        .as_str()
+        .ok_or_else(|| format_err!("ID token has no {} claim", c.username_claim))?
+        .to_owned();
+    let permissions = c.permissions_claim.as_ref().map(|claim| {
+        let values: Vec<String> = match claims[claim.as_str()] {
+            serde_json::Value::String(ref v) => vec![v.clone()],
+            serde_json::Value::Array(ref a) => a
+                .iter()
+                .filter_map(|v| v.as_str().map(str::to_owned))
+                .collect(),
+            _ => Vec::new(),
+        };
+        saml::mapped_permissions(&c.permissions_map, &values)
+    });
+    Ok((username, permissions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            issuer_url: "https://idp.example".to_owned(),
+            client_id: "nvr".to_owned(),
+            client_secret: "secret".to_owned(),
+            redirect_url: "https://nvr.example/api/login/oidc/callback".to_owned(),
+            scopes: "profile email".to_owned(),
+            username_claim: "preferred_username".to_owned(),
+            permissions_claim: Some("groups".to_owned()),
+            permissions_map: saml::parse_permissions_map("viewers=view_video").unwrap(),
+        }
+    }
+
+    #[test]
+    fn pkce_challenge() {
+        // From RFC 7636 appendix B.
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuJWtPh9Nts"
+        );
+    }
+
+    #[test]
+    fn decode() {
+        let payload = base64::encode_config(b"{\"sub\":\"1\"}", base64::URL_SAFE_NO_PAD);
+        let claims = decode_claims(&format!("e30.{}.c2ln", payload)).unwrap();
+        assert_eq!(claims["sub"], "1");
+        decode_claims("e30.e30").unwrap_err();
+        decode_claims("e30.!!!.c2ln").unwrap_err();
+    }
+
+    #[test]
+    fn claims() {
+        let c = config();
+        let good = serde_json::json!({
+            "iss": "https://idp.example",
+            "aud": ["other", "nvr"],
+            "exp": 2000,
+            "nonce": "n",
+            "preferred_username": "slamb",
+            "groups": ["viewers"],
+        });
+        let (username, permissions) =
+            check_claims(&c, "https://idp.example", "n", &good, 1000).unwrap();
+        assert_eq!(username, "slamb");
+        assert!(permissions.unwrap().view_video);
+
+        let mut bad = good.clone();
+        bad["aud"] = "other".into();
+        check_claims(&c, "https://idp.example", "n", &bad, 1000).unwrap_err();
+        check_claims(&c, "https://idp.example", "m", &good, 1000).unwrap_err();
+        check_claims(&c, "https://evil.example", "n", &good, 1000).unwrap_err();
+        check_claims(&c, "https://idp.example", "n", &good, 3000).unwrap_err();
+    }
+}
@@ -0,0 +1,568 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Minimal ONVIF client for ingesting camera-side events and sending PTZ commands.
+//!
+//! This speaks just enough SOAP to find a camera's event service, create a PullPoint
+//! subscription (ONVIF Core Specification section 9.1.2), and pull notifications from it.
+//! Motion and tamper notifications are translated into state changes of the signals with the
+//! well-known types `db::signal::ONVIF_MOTION_TYPE_UUID` and `db::signal::ONVIF_TAMPER_TYPE_UUID`
+//! whose source is the camera's uuid.
+//!
+//! It also can send a few PTZ Service Specification commands (`ContinuousMove`, `Stop`, and
+//! `GotoPreset`) on behalf of the web API.
+//!
+//! There's no XML parser here, just a forgiving scanner for the few elements of interest.
+
+use base::clock::Clocks;
+use db::recording;
+use failure::{bail, format_err, Error};
+use hyper::client::HttpConnector;
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+use tracing::{debug, info, warn};
+
+/// How far into the future to predict the current state of a signal. Each pull refreshes this
+/// prediction, so it should be comfortably longer than `PULL_TIMEOUT`.
+const PREDICTION_90K: i64 = 60 * recording::TIME_UNITS_PER_SEC;
+
+/// The `Timeout` of each `PullMessages` request, as an ISO 8601 duration.
+const PULL_TIMEOUT: &'static str = "PT30S";
+
+/// The time until the subscription expires if not renewed, as an ISO 8601 duration.
+const TERMINATION_TIME: &'static str = "PT120S";
+
+const STATE_INACTIVE: u16 = 1;
+const STATE_ACTIVE: u16 = 2;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Kind {
+    Motion,
+    Tamper,
+}
+
+impl Kind {
+    /// Classifies a notification topic such as `tns1:RuleEngine/CellMotionDetector/Motion`.
+    fn from_topic(topic: &str) -> Option<Self> {
+        let t = topic.to_ascii_lowercase();
+        if t.contains("tamper") || t.contains("globalscenechange") || t.contains("imagetooblurry") {
+            Some(Kind::Tamper)
+        } else if t.contains("motion") {
+            Some(Kind::Motion)
+        } else {
+            None
+        }
+    }
+}
+
+/// A SOAP client for a single camera's ONVIF services.
+pub struct Client {
+    client: hyper::Client<HttpConnector>,
+    device_url: String,
+    username: String,
+    password: String,
+}
+
+/// Subscribes to the events of a single camera.
+pub struct Subscriber<C: Clocks + Clone> {
+    db: Arc<db::Database<C>>,
+    client: Client,
+    short_name: String,
+    motion_signal: Option<u32>,
+    tamper_signal: Option<u32>,
+}
+
+impl<C: Clocks + Clone> Subscriber<C> {
+    /// Returns a subscriber for the given camera, or `None` if it has no ONVIF host or no
+    /// signals to drive.
+    pub fn new(
+        db: &Arc<db::Database<C>>,
+        l: &db::LockedDatabase,
+        camera: &db::Camera,
+    ) -> Option<Self> {
+        let client = Client::new(camera)?;
+        let find = |type_: uuid::Uuid| {
+            l.signals_by_id()
+                .values()
+                .find(|s| s.source == camera.uuid && s.type_ == type_)
+                .map(|s| s.id)
+        };
+        let motion_signal = find(db::signal::ONVIF_MOTION_TYPE_UUID);
+        let tamper_signal = find(db::signal::ONVIF_TAMPER_TYPE_UUID);
+        if motion_signal.is_none() && tamper_signal.is_none() {
+            return None;
+        }
+        Some(Subscriber {
+            db: db.clone(),
+            client,
+            short_name: camera.short_name.clone(),
+            motion_signal,
+            tamper_signal,
+        })
+    }
+
+    pub fn short_name(&self) -> &str {
+        &self.short_name
+    }
+
+    /// Runs until the returned future is dropped, retrying on error.
+    pub async fn run(self) {
+        loop {
+            if let Err(e) = self.run_once().await {
+                let sleep_time = StdDuration::from_secs(10);
+                warn!(
+                    "{}: ONVIF events: sleeping for {:?} after error: {:?}",
+                    self.short_name, sleep_time, e
+                );
+                tokio::time::delay_for(sleep_time).await;
+            }
+        }
+    }
+
+    async fn run_once(&self) -> Result<(), Error> {
+        let events_url = self.client.service_url("Events").await?;
+        debug!("{}: ONVIF event service at {}", self.short_name, events_url);
+        let resp = self
+            .client
+            .call(
+                &events_url,
+                "http://www.onvif.org/ver10/events/wsdl/EventPortType/\
+                 CreatePullPointSubscriptionRequest",
+                &format!(
+                    "<CreatePullPointSubscription xmlns=\"http://www.onvif.org/ver10/events/wsdl\">\
+                     <InitialTerminationTime>{}</InitialTerminationTime>\
+                     </CreatePullPointSubscription>",
+                    TERMINATION_TIME
+                ),
+            )
+            .await?;
+        let sub_url = first_element(&resp, "SubscriptionReference")
+            .and_then(|e| first_element(e.content, "Address"))
+            .map(|e| unescape(e.content.trim()))
+            .ok_or_else(|| format_err!("no SubscriptionReference in response: {}", resp))?;
+        info!("{}: subscribed to ONVIF events", self.short_name);
+        let mut motion = false;
+        let mut tamper = false;
+        loop {
+            let resp = self
+                .client
+                .call(
+                    &sub_url,
+                    "http://www.onvif.org/ver10/events/wsdl/PullPointSubscription/\
+                     PullMessagesRequest",
+                    &format!(
+                        "<PullMessages xmlns=\"http://www.onvif.org/ver10/events/wsdl\">\
+                         <Timeout>{}</Timeout><MessageLimit>100</MessageLimit></PullMessages>",
+                        PULL_TIMEOUT
+                    ),
+                )
+                .await?;
+            for (kind, active) in parse_notifications(&resp) {
+                debug!("{}: ONVIF {:?} active={}", self.short_name, kind, active);
+                match kind {
+                    Kind::Motion => motion = active,
+                    Kind::Tamper => tamper = active,
+                }
+            }
+            self.update(self.motion_signal, motion)?;
+            self.update(self.tamper_signal, tamper)?;
+            self.client
+                .call(
+                    &sub_url,
+                    "http://docs.oasis-open.org/wsn/bw-2/SubscriptionManager/RenewRequest",
+                    &format!(
+                        "<Renew xmlns=\"http://docs.oasis-open.org/wsn/b-2\">\
+                         <TerminationTime>{}</TerminationTime></Renew>",
+                        TERMINATION_TIME
+                    ),
+                )
+                .await?;
+        }
+    }
+
+    /// Sets the given signal (if any) to the given state from now through the prediction window.
+    fn update(&self, signal: Option<u32>, active: bool) -> Result<(), Error> {
+        let signal = match signal {
+            None => return Ok(()),
+            Some(s) => s,
+        };
+        let state = if active { STATE_ACTIVE } else { STATE_INACTIVE };
+        let now = recording::Time::new(self.db.clocks().realtime());
+        self.db
+            .lock()
+            .update_signals(
+                now..now + recording::Duration(PREDICTION_90K),
+                &[signal],
+                &[state],
+            )
+            .map_err(|e| format_err!("unable to update signal {}: {}", signal, e))
+    }
+}
+
+impl Client {
+    /// Returns a client for the given camera, or `None` if it has no ONVIF host.
+    pub fn new(camera: &db::Camera) -> Option<Self> {
+        if camera.onvif_host.is_empty() {
+            return None;
+        }
+        Some(Client {
+            client: hyper::Client::new(),
+            device_url: format!("http://{}/onvif/device_service", camera.onvif_host),
+            username: camera.username.clone(),
+            password: camera.password.clone(),
+        })
+    }
+
+    /// Starts moving at the given velocities, each in [-1.0, 1.0]. The camera keeps moving until
+    /// `stop` is called or it reaches its limits.
+    pub async fn continuous_move(&self, pan: f32, tilt: f32, zoom: f32) -> Result<(), Error> {
+        self.ptz_call("ContinuousMove", |profile| {
+            format!(
+                "<ContinuousMove xmlns=\"http://www.onvif.org/ver20/ptz/wsdl\">\
+                 <ProfileToken>{}</ProfileToken><Velocity>\
+                 <PanTilt xmlns=\"http://www.onvif.org/ver10/schema\" x=\"{}\" y=\"{}\"/>\
+                 <Zoom xmlns=\"http://www.onvif.org/ver10/schema\" x=\"{}\"/>\
+                 </Velocity></ContinuousMove>",
+                escape(profile),
+                pan,
+                tilt,
+                zoom
+            )
+        })
+        .await
+    }
+
+    /// Stops any pan, tilt, or zoom movement.
+    pub async fn stop(&self) -> Result<(), Error> {
+        self.ptz_call("Stop", |profile| {
+            format!(
+                "<Stop xmlns=\"http://www.onvif.org/ver20/ptz/wsdl\">\
+                 <ProfileToken>{}</ProfileToken><PanTilt>true</PanTilt><Zoom>true</Zoom></Stop>",
+                escape(profile)
+            )
+        })
+        .await
+    }
+
+    /// Moves to the preset with the given token.
+    pub async fn goto_preset(&self, preset: &str) -> Result<(), Error> {
+        self.ptz_call("GotoPreset", |profile| {
+            format!(
+                "<GotoPreset xmlns=\"http://www.onvif.org/ver20/ptz/wsdl\">\
+                 <ProfileToken>{}</ProfileToken><PresetToken>{}</PresetToken></GotoPreset>",
+                escape(profile),
+                escape(preset)
+            )
+        })
+        .await
+    }
+
+    /// Sends a PTZ command, using the first media profile.
+    async fn ptz_call<F>(&self, op: &str, body: F) -> Result<(), Error>
+    where
+        F: FnOnce(&str) -> String,
+    {
+        let media_url = self.service_url("Media").await?;
+        let resp = self
+            .call(
+                &media_url,
+                "http://www.onvif.org/ver10/media/wsdl/GetProfiles",
+                "<GetProfiles xmlns=\"http://www.onvif.org/ver10/media/wsdl\"/>",
+            )
+            .await?;
+        let profile = first_element(&resp, "Profiles")
+            .and_then(|e| e.attr("token").map(unescape))
+            .ok_or_else(|| format_err!("no media profile: {}", resp))?;
+        let ptz_url = self.service_url("PTZ").await?;
+        self.call(
+            &ptz_url,
+            &format!("http://www.onvif.org/ver20/ptz/wsdl/{}", op),
+            &body(&profile),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the URL of the given service, as named by a `GetCapabilities` category such as
+    /// `Events`, `Media`, or `PTZ`.
+    async fn service_url(&self, category: &str) -> Result<String, Error> {
+        let resp = self
+            .call(
+                &self.device_url,
+                "http://www.onvif.org/ver10/device/wsdl/GetCapabilities",
+                &format!(
+                    "<GetCapabilities xmlns=\"http://www.onvif.org/ver10/device/wsdl\">\
+                     <Category>{}</Category></GetCapabilities>",
+                    category
+                ),
+            )
+            .await?;
+        first_element(&resp, category)
+            .and_then(|e| first_element(e.content, "XAddr"))
+            .map(|e| unescape(e.content.trim()))
+            .ok_or_else(|| format_err!("no {} XAddr in capabilities: {}", category, resp))
+    }
+
+    /// Makes a SOAP call, returning the response body.
+    async fn call(&self, url: &str, action: &str, body: &str) -> Result<String, Error> {
+        let req = hyper::Request::post(url)
+            .header(
+                hyper::header::CONTENT_TYPE,
+                format!("application/soap+xml; charset=utf-8; action=\"{}\"", action),
+            )
+            .body(hyper::Body::from(self.envelope(body)?))?;
+        let resp = self.client.request(req).await?;
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await?;
+        let body = String::from_utf8(body.to_vec())?;
+        if !status.is_success() {
+            bail!("{} for {}: {}", status, action, body);
+        }
+        Ok(body)
+    }
+
+    /// Wraps `body` in a SOAP envelope with a WS-Security `UsernameToken` header.
+    fn envelope(&self, body: &str) -> Result<String, Error> {
+        let mut nonce = [0u8; 16];
+        base::crypto::rand_bytes(&mut nonce)?;
+        let created = time::now_utc()
+            .strftime("%Y-%m-%dT%H:%M:%SZ")
+            .expect("valid template")
+            .to_string();
+        let mut h = base::crypto::Sha1::new();
+        h.update(&nonce);
+        h.update(created.as_bytes());
+        h.update(self.password.as_bytes());
+        let digest = h.finish();
+        Ok(format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+             <s:Envelope xmlns:s=\"http://www.w3.org/2003/05/soap-envelope\">\
+             <s:Header><Security s:mustUnderstand=\"1\" xmlns=\"http://docs.oasis-open.org/wss/\
+             2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd\"><UsernameToken>\
+             <Username>{}</Username>\
+             <Password Type=\"http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-username-\
+             token-profile-1.0#PasswordDigest\">{}</Password>\
+             <Nonce EncodingType=\"http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-soap-\
+             message-security-1.0#Base64Binary\">{}</Nonce>\
+             <Created xmlns=\"http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-\
+             utility-1.0.xsd\">{}</Created>\
+             </UsernameToken></Security></s:Header>\
+             <s:Body>{}</s:Body></s:Envelope>",
+            escape(&self.username),
+            base64::encode(&digest),
+            base64::encode(&nonce),
+            created,
+            body
+        ))
+    }
+}
+
+/// An element found by `first_element` or `elements`.
+#[derive(Debug)]
+struct Element<'a> {
+    /// The attributes portion of the start tag, e.g. ` Name="State" Value="true"`.
+    attrs: &'a str,
+
+    /// The content between the start and end tags; empty for a self-closing tag.
+    content: &'a str,
+}
+
+impl<'a> Element<'a> {
+    /// Returns the value of the given attribute, if present.
+    fn attr(&self, name: &str) -> Option<&'a str> {
+        let mut rest = self.attrs;
+        while let Some(eq) = rest.find('=') {
+            let key = rest[..eq].trim();
+            let after = rest[eq + 1..].trim_start();
+            let quote = after.chars().next()?;
+            if quote != '"' && quote != '\'' {
+                return None;
+            }
+            let end = after[1..].find(quote)? + 1;
+            let key = key.rsplit(':').next().unwrap_or(key);
+            if key == name {
+                return Some(&after[1..end]);
+            }
+            rest = &after[end + 1..];
+        }
+        None
+    }
+}
+
+/// Returns all elements with the given local name (ignoring any namespace prefix) in order.
+/// Nested elements of the same name aren't supported.
+fn elements<'a>(xml: &'a str, local_name: &str) -> Vec<Element<'a>> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while let Some(lt) = xml[pos..].find('<') {
+        let start = pos + lt + 1;
+        let tag_end = match xml[start..].find('>') {
+            None => break,
+            Some(e) => start + e,
+        };
+        pos = tag_end + 1;
+        let tag = &xml[start..tag_end];
+        if tag.starts_with('/') || tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+        let name_end = tag
+            .find(|c: char| c.is_ascii_whitespace() || c == '/')
+            .unwrap_or(tag.len());
+        let qname = &tag[..name_end];
+        if qname.rsplit(':').next() != Some(local_name) {
+            continue;
+        }
+        if tag.ends_with('/') {
+            out.push(Element {
+                attrs: &tag[name_end..tag.len() - 1],
+                content: "",
+            });
+            continue;
+        }
+        let close = format!("</{}>", qname);
+        let content_end = match xml[pos..].find(&close) {
+            None => break,
+            Some(e) => pos + e,
+        };
+        out.push(Element {
+            attrs: &tag[name_end..],
+            content: &xml[pos..content_end],
+        });
+        pos = content_end + close.len();
+    }
+    out
+}
+
+fn first_element<'a>(xml: &'a str, local_name: &str) -> Option<Element<'a>> {
+    elements(xml, local_name).into_iter().next()
+}
+
+/// Parses a `PullMessagesResponse`, returning each recognized notification in order.
+fn parse_notifications(xml: &str) -> Vec<(Kind, bool)> {
+    let mut out = Vec::new();
+    for msg in elements(xml, "NotificationMessage") {
+        let kind =
+            match first_element(msg.content, "Topic").and_then(|t| Kind::from_topic(t.content)) {
+                None => continue,
+                Some(k) => k,
+            };
+        let data = match first_element(msg.content, "Data") {
+            None => continue,
+            Some(d) => d,
+        };
+        for item in elements(data.content, "SimpleItem") {
+            match item.attr("Value") {
+                Some("true") | Some("1") => out.push((kind, true)),
+                Some("false") | Some("0") => out.push((kind, false)),
+                _ => continue,
+            }
+            break;
+        }
+    }
+    out
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notifications() {
+        let resp = r#"<?xml version="1.0" encoding="UTF-8"?>
+<env:Envelope xmlns:env="http://www.w3.org/2003/05/soap-envelope"
+              xmlns:tt="http://www.onvif.org/ver10/schema"
+              xmlns:wsnt="http://docs.oasis-open.org/wsn/b-2"
+              xmlns:tev="http://www.onvif.org/ver10/events/wsdl">
+<env:Body><tev:PullMessagesResponse>
+<tev:CurrentTime>2020-03-01T00:00:00Z</tev:CurrentTime>
+<wsnt:NotificationMessage>
+<wsnt:Topic Dialect="http://www.onvif.org/ver10/tev/topicExpression/ConcreteSet">tns1:RuleEngine/CellMotionDetector/Motion</wsnt:Topic>
+<wsnt:Message><tt:Message UtcTime="2020-03-01T00:00:00Z" PropertyOperation="Changed">
+<tt:Source><tt:SimpleItem Name="VideoSourceConfigurationToken" Value="VideoSourceToken"/></tt:Source>
+<tt:Data><tt:SimpleItem Name="IsMotion" Value="true"/></tt:Data>
+</tt:Message></wsnt:Message>
+</wsnt:NotificationMessage>
+<wsnt:NotificationMessage>
+<wsnt:Topic Dialect="http://www.onvif.org/ver10/tev/topicExpression/ConcreteSet">tns1:RuleEngine/TamperDetector/Tamper</wsnt:Topic>
+<wsnt:Message><tt:Message UtcTime="2020-03-01T00:00:01Z" PropertyOperation="Changed">
+<tt:Data><tt:SimpleItem Name='IsTamper' Value='false'/></tt:Data>
+</tt:Message></wsnt:Message>
+</wsnt:NotificationMessage>
+<wsnt:NotificationMessage>
+<wsnt:Topic>tns1:Device/Trigger/DigitalInput</wsnt:Topic>
+<wsnt:Message><tt:Message><tt:Data><tt:SimpleItem Name="LogicalState" Value="true"/></tt:Data></tt:Message></wsnt:Message>
+</wsnt:NotificationMessage>
+</tev:PullMessagesResponse></env:Body></env:Envelope>"#;
+        assert_eq!(
+            parse_notifications(resp),
+            vec![(Kind::Motion, true), (Kind::Tamper, false)]
+        );
+    }
+
+    #[test]
+    fn subscription_address() {
+        let resp = r#"<tev:CreatePullPointSubscriptionResponse>
+<tev:SubscriptionReference><wsa5:Address>http://192.168.1.100/onvif/Events/PullSubManager?Idx=0&amp;x=1</wsa5:Address></tev:SubscriptionReference>
+</tev:CreatePullPointSubscriptionResponse>"#;
+        let addr = first_element(resp, "SubscriptionReference")
+            .and_then(|e| first_element(e.content, "Address"))
+            .map(|e| unescape(e.content.trim()));
+        assert_eq!(
+            addr.as_deref(),
+            Some("http://192.168.1.100/onvif/Events/PullSubManager?Idx=0&x=1")
+        );
+    }
+
+    #[test]
+    fn profile_token() {
+        let resp = r#"<trt:GetProfilesResponse>
+<trt:Profiles fixed="true" token="Profile&amp;1"><tt:Name>mainStream</tt:Name></trt:Profiles>
+<trt:Profiles fixed="true" token="Profile_2"><tt:Name>subStream</tt:Name></trt:Profiles>
+</trt:GetProfilesResponse>"#;
+        let token = first_element(resp, "Profiles").and_then(|e| e.attr("token").map(unescape));
+        assert_eq!(token.as_deref(), Some("Profile&1"));
+    }
+}
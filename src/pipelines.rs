@@ -0,0 +1,497 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Runs a streamer for each recorded stream, starting and stopping them as the configuration
+//! changes.
+//!
+//! At startup and whenever the database reports `db::Event::StreamsChanged` (as when a camera is
+//! added, edited, or removed through the API), `reconcile` compares each stream's configuration to
+//! the one its running streamer was created with. The streamers of removed, no-longer-recorded,
+//! and changed streams are stopped; each finishes its recording in progress and closes its
+//! `db::writer::Writer` as on shutdown. Then streamers are started for new and changed streams,
+//! along with syncers for any sample file directories not yet in use. A stream whose directory
+//! can't be opened is skipped with a warning and retried on the next change.
+//!
+//! Streams pushed by RTMP or replicated from another instance are received by shared servers
+//! configured at startup, so changes to them take effect on restart.
+
+use crate::events;
+use crate::health;
+use crate::replication;
+use crate::rtmp;
+use crate::sched;
+use crate::stream;
+use crate::streamer;
+use crate::supervisor;
+use base::clock;
+use db::{dir, writer};
+use failure::{format_err, Error};
+use fnv::FnvHashMap;
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use tracing::{info, warn};
+
+/// A sample file directory and the syncer thread for it.
+pub struct Syncer {
+    pub dir: Arc<dir::SampleFileDir>,
+    pub channel: writer::SyncerChannel<dir::SampleFileWriter>,
+    pub join: thread::JoinHandle<()>,
+}
+
+/// The configuration a streamer is created from. A change to any of it restarts the streamer.
+#[derive(Debug, PartialEq)]
+struct StreamConfig {
+    short_name: String,
+
+    /// The credentials and URL to connect with. For a virtual stream, these are the source's.
+    username: String,
+    password: String,
+    url: String,
+
+    virtual_source: Option<db::VirtualSource>,
+    dir_ids: Vec<i32>,
+    failover_sample_file_dir_id: Option<i32>,
+    input_options: String,
+    rtsp: db::RtspPolicy,
+}
+
+impl StreamConfig {
+    fn new(l: &db::LockedDatabase, c: &db::Camera, s: &db::Stream) -> Self {
+        let source = s.virtual_source.and_then(|v| {
+            let src_s = l.streams_by_id().get(&v.source_stream_id)?;
+            Some((l.cameras_by_id().get(&src_s.camera_id)?, src_s))
+        });
+        let (src_c, src_s) = source.unwrap_or((c, s));
+        StreamConfig {
+            short_name: format!("{}-{}", c.short_name, s.type_.as_str()),
+            username: src_c.username.clone(),
+            password: src_c.password.clone(),
+            url: src_s.rtsp_url.clone(),
+            virtual_source: s.virtual_source,
+            dir_ids: s.dir_ids(),
+            failover_sample_file_dir_id: s.failover_sample_file_dir_id,
+            input_options: s.input_options.clone(),
+            rtsp: s.rtsp,
+        }
+    }
+
+    /// Returns the ids of all sample file directories the streamer writes to.
+    fn all_dir_ids<'a>(&'a self) -> impl Iterator<Item = i32> + 'a {
+        self.dir_ids
+            .iter()
+            .copied()
+            .chain(self.failover_sample_file_dir_id)
+    }
+}
+
+/// A streamer thread started by `Pipelines`.
+struct Running {
+    config: StreamConfig,
+    shutdown: Arc<AtomicBool>,
+    join: thread::JoinHandle<()>,
+}
+
+/// Returns true if the given stream is received by the shared RTMP server or replication puller
+/// rather than by a streamer of its own.
+pub fn is_shared(s: &db::Stream) -> bool {
+    s.virtual_source.is_none()
+        && (rtmp::publish_path(&s.rtsp_url).is_some()
+            || replication::remote_url(&s.rtsp_url).is_some())
+}
+
+/// Returns true if the given stream should have a streamer of its own.
+fn wants_streamer(s: &db::Stream) -> bool {
+    s.record && s.sample_file_dir_id.is_some() && !is_shared(s)
+}
+
+/// The streamers and syncers of a read-write server.
+pub struct Pipelines {
+    db: Arc<db::Database>,
+    health: health::Monitor,
+    events: events::Bus,
+    thumbnail_width: Option<u32>,
+    ingest_sched: sched::Params,
+    analytics_sched: sched::Params,
+    direct_io: bool,
+    syncers: FnvHashMap<i32, Syncer>,
+    running: FnvHashMap<i32, Running>,
+
+    /// True once shutdown has begun; no more streamers are started.
+    closed: bool,
+}
+
+impl Pipelines {
+    pub fn new(
+        db: Arc<db::Database>,
+        health: health::Monitor,
+        events: events::Bus,
+        thumbnail_width: Option<u32>,
+        ingest_sched: sched::Params,
+        analytics_sched: sched::Params,
+        direct_io: bool,
+    ) -> Self {
+        Pipelines {
+            db,
+            health,
+            events,
+            thumbnail_width,
+            ingest_sched,
+            analytics_sched,
+            direct_io,
+            syncers: FnvHashMap::default(),
+            running: FnvHashMap::default(),
+            closed: false,
+        }
+    }
+
+    /// Starts syncers for any of the given sample file directories which lack them, opening the
+    /// directories if necessary. The database must not be locked.
+    pub fn start_syncers(&mut self, ids: &[i32]) -> Result<(), Error> {
+        for &id in ids {
+            self.start_syncer(id)?;
+        }
+        Ok(())
+    }
+
+    /// Starts a syncer for the given sample file directory if it lacks one.
+    /// The database must not be locked.
+    fn start_syncer(&mut self, id: i32) -> Result<(), Error> {
+        if self.syncers.contains_key(&id) {
+            return Ok(());
+        }
+        let dir = {
+            let mut l = self.db.lock();
+            l.open_sample_file_dirs(&[id])?;
+            let d = l.sample_file_dirs_by_id().get(&id).unwrap();
+            info!("Starting syncer for path {}", d.path);
+            d.get()?
+        };
+
+        // Then, with the lock dropped, create the syncer.
+        dir.set_direct_io(self.direct_io);
+        let ingest_sched = self.ingest_sched.clone();
+        let (channel, join) =
+            writer::start_syncer(self.db.clone(), id, move || ingest_sched.apply_or_warn())?;
+        self.syncers.insert(id, Syncer { dir, channel, join });
+        Ok(())
+    }
+
+    /// Returns the given directory and a channel to its syncer, which must have been started.
+    pub fn dir_and_syncer(&self, id: i32) -> streamer::DirAndSyncer {
+        let syncer = self.syncers.get(&id).unwrap();
+        (syncer.dir.clone(), syncer.channel.clone())
+    }
+
+    pub fn queue_monitors(&self) -> FnvHashMap<i32, writer::QueueMonitor> {
+        self.syncers
+            .iter()
+            .map(|(&id, s)| (id, s.channel.queue_monitor()))
+            .collect()
+    }
+
+    /// Returns the streams which should have streamers of their own, with the configuration and
+    /// rotation offset of each. The database must not be locked.
+    fn wanted(&self) -> FnvHashMap<i32, (StreamConfig, i64)> {
+        let mut wanted = FnvHashMap::default();
+        let l = self.db.lock();
+        let streams = l.streams_by_id().len();
+        for (i, (&id, s)) in l.streams_by_id().iter().enumerate() {
+            let c = l.cameras_by_id().get(&s.camera_id).unwrap();
+            if s.record && s.sample_file_dir_id.is_none() {
+                warn!(
+                    "Can't record stream {} ({}/{}) because it has no sample file dir",
+                    id,
+                    c.short_name,
+                    s.type_.as_str()
+                );
+            }
+            if !wants_streamer(s) {
+                continue;
+            }
+            let rotate_offset_sec = streamer::ROTATE_INTERVAL_SEC * i as i64 / streams as i64;
+            wanted.insert(id, (StreamConfig::new(&l, c, s), rotate_offset_sec));
+        }
+        wanted
+    }
+
+    /// Signals the streamers of removed and changed streams to stop, returning their threads.
+    /// These should be joined after the lock on `self` is dropped, then `start_wanted` called.
+    /// The database must not be locked.
+    fn stop_unwanted(&mut self) -> Vec<thread::JoinHandle<()>> {
+        let stopping: Vec<i32> = {
+            let l = self.db.lock();
+            self.running
+                .iter()
+                .filter(|&(id, r)| match l.streams_by_id().get(id) {
+                    Some(s) if wants_streamer(s) => {
+                        let c = l.cameras_by_id().get(&s.camera_id).unwrap();
+                        StreamConfig::new(&l, c, s) != r.config
+                    }
+                    _ => true,
+                })
+                .map(|(&id, _)| id)
+                .collect()
+        };
+
+        // Signal all of them before waiting for any, as each may take a while to notice.
+        stopping
+            .into_iter()
+            .map(|id| {
+                let r = self.running.remove(&id).unwrap();
+                info!("Stopping streamer for {}", r.config.short_name);
+                r.shutdown.store(true, Ordering::SeqCst);
+                r.join
+            })
+            .collect()
+    }
+
+    /// Starts streamers for new and changed streams, along with any syncers they need. A stream
+    /// is skipped (with a warning) if one of its sample file directories can't be opened.
+    /// The database must not be locked.
+    fn start_wanted(&mut self) {
+        if self.closed {
+            return;
+        }
+        let wanted = self.wanted();
+        for (config, _) in wanted.values() {
+            for id in config.all_dir_ids() {
+                if let Err(e) = self.start_syncer(id) {
+                    warn!("Unable to start syncer for sample file dir {}: {}", id, e);
+                }
+            }
+        }
+        let db = self.db.clone();
+        let l = db.lock();
+        for (id, (config, rotate_offset_sec)) in wanted {
+            if self.running.contains_key(&id) {
+                continue;
+            }
+            let missing = config.all_dir_ids().find(|d| !self.syncers.contains_key(d));
+            if let Some(d) = missing {
+                warn!(
+                    "Not starting streamer for {}: sample file dir {} is unavailable",
+                    config.short_name, d
+                );
+                continue;
+            }
+            if let Err(e) = self.start(&l, id, config, rotate_offset_sec) {
+                warn!("Unable to start streamer for stream {}: {}", id, e);
+            }
+        }
+    }
+
+    fn start(
+        &mut self,
+        l: &db::LockedDatabase,
+        stream_id: i32,
+        config: StreamConfig,
+        rotate_offset_sec: i64,
+    ) -> Result<(), Error> {
+        let stripes: Vec<_> = config
+            .dir_ids
+            .iter()
+            .map(|&id| self.dir_and_syncer(id))
+            .collect();
+        let failover = config
+            .failover_sample_file_dir_id
+            .map(|id| self.dir_and_syncer(id));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let env = streamer::Environment {
+            db: &self.db,
+            opener: &*stream::FFMPEG,
+            shutdown: &shutdown,
+            health: &self.health,
+            events: &self.events,
+            thumbnail_width: self.thumbnail_width,
+            analytics_sched: &self.analytics_sched,
+        };
+        let streamer = new_streamer(
+            &env,
+            l,
+            stripes.clone(),
+            failover.clone(),
+            stream_id,
+            rotate_offset_sec,
+        )?;
+        info!("Starting streamer for {}", streamer.short_name());
+        let name = format!("s-{}", streamer.short_name());
+        let supervisor = supervisor::Supervisor {
+            db: self.db.clone(),
+            events: self.events.clone(),
+            shutdown: shutdown.clone(),
+            stream_id,
+            short_name: streamer.short_name().to_owned(),
+        };
+
+        // After a panic, the streamer is recreated from the current configuration.
+        let mut first = Some(streamer);
+        let (health, events) = (self.health.clone(), self.events.clone());
+        let thumbnail_width = self.thumbnail_width;
+        let ingest_sched = self.ingest_sched.clone();
+        let analytics_sched = self.analytics_sched.clone();
+        let join = thread::Builder::new()
+            .name(name)
+            .spawn(move || {
+                ingest_sched.apply_or_warn();
+                supervisor.run(|| {
+                    let mut streamer = match first.take() {
+                        Some(s) => s,
+                        None => {
+                            let env = streamer::Environment {
+                                db: &supervisor.db,
+                                opener: &*stream::FFMPEG,
+                                shutdown: &supervisor.shutdown,
+                                health: &health,
+                                events: &events,
+                                thumbnail_width,
+                                analytics_sched: &analytics_sched,
+                            };
+                            new_streamer(
+                                &env,
+                                &supervisor.db.lock(),
+                                stripes.clone(),
+                                failover.clone(),
+                                stream_id,
+                                rotate_offset_sec,
+                            )?
+                        }
+                    };
+                    streamer.run();
+                    Ok(())
+                });
+            })
+            .expect("can't create thread");
+        self.running.insert(
+            stream_id,
+            Running {
+                config,
+                shutdown,
+                join,
+            },
+        );
+        Ok(())
+    }
+
+    /// Begins shutdown: signals all streamers to stop and starts no more. Returns the streamers'
+    /// threads, to be joined after the lock on `self` is dropped.
+    pub fn close(&mut self) -> Vec<thread::JoinHandle<()>> {
+        self.closed = true;
+        self.running
+            .drain()
+            .map(|(_, r)| {
+                r.shutdown.store(true, Ordering::SeqCst);
+                r.join
+            })
+            .collect()
+    }
+}
+
+/// Brings the running streamers in line with the database's configuration, waiting for stopped
+/// streamers without holding the lock on `pipelines`. The database must not be locked.
+pub fn reconcile(pipelines: &Mutex<Pipelines>) {
+    let stopping = pipelines.lock().stop_unwanted();
+    for join in stopping {
+        join.join().unwrap();
+    }
+    pipelines.lock().start_wanted();
+}
+
+/// Stops the syncers, after all streamers and other users of their channels have stopped.
+pub fn stop_syncers(pipelines: &Mutex<Pipelines>) {
+    let syncers: Vec<Syncer> = {
+        let mut p = pipelines.lock();
+
+        // The syncers shut down when all channels to them have been dropped.
+        // The database maintains one; and `syncers` holds one. Drop both.
+        p.db.lock().clear_on_flush();
+        p.syncers.drain().map(|(_, s)| s).collect()
+    };
+    for s in syncers {
+        drop(s.channel);
+        s.join.join().unwrap();
+    }
+}
+
+/// Reconciles `pipelines` on each `db::Event::StreamsChanged`, in a thread which exits when the
+/// database's watches are cleared.
+pub fn watch(
+    pipelines: Arc<Mutex<Pipelines>>,
+    db: &mut db::LockedDatabase,
+) -> thread::JoinHandle<()> {
+    let (tx, rx) = mpsc::channel();
+    db.watch_events(Box::new(move |_, e| match e {
+        db::Event::StreamsChanged => tx.send(()).is_ok(),
+        _ => true,
+    }));
+    thread::Builder::new()
+        .name("reconciler".to_owned())
+        .spawn(move || {
+            while rx.recv().is_ok() {
+                // Handle a burst of changes at once.
+                while rx.try_recv().is_ok() {}
+                reconcile(&pipelines);
+            }
+        })
+        .expect("can't create thread")
+}
+
+/// Creates the streamer for the given stream, as when starting it and when restarting it after a
+/// panic.
+fn new_streamer(
+    env: &streamer::Environment<'static, '_, clock::RealClocks, stream::FfmpegStream>,
+    l: &db::LockedDatabase,
+    stripes: Vec<streamer::DirAndSyncer>,
+    failover: Option<streamer::DirAndSyncer>,
+    stream_id: i32,
+    rotate_offset_sec: i64,
+) -> Result<streamer::Streamer<'static, clock::RealClocks, stream::FfmpegStream>, Error> {
+    let stream = l
+        .streams_by_id()
+        .get(&stream_id)
+        .ok_or_else(|| format_err!("no such stream {}", stream_id))?;
+    let camera = l.cameras_by_id().get(&stream.camera_id).unwrap();
+    let source = stream.virtual_source.map(|v| {
+        let s = l.streams_by_id().get(&v.source_stream_id).unwrap();
+        (l.cameras_by_id().get(&s.camera_id).unwrap(), s)
+    });
+    streamer::Streamer::new(
+        env,
+        stripes,
+        failover,
+        stream_id,
+        camera,
+        stream,
+        source,
+        rotate_offset_sec,
+        streamer::ROTATE_INTERVAL_SEC,
+    )
+}
@@ -0,0 +1,384 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Pull replication of recordings from another Moonfire NVR instance, such as for keeping an
+//! offsite copy of a home system's footage.
+//!
+//! A stream whose URL is `nvr+https://<host>[:<port>]/api/cameras/<uuid>/<type>/` (or
+//! `nvr+http://...`) isn't pulled from a camera by a streamer. Instead, `Puller` periodically
+//! asks that remote ("edge") stream for committed recordings newer than the local stream's
+//! latest, via `GET .../replica`, downloads each one's sample data via `GET .../replica/<id>.bin`,
+//! and writes it through the same `db::writer::Writer` pipeline the streamers use. The camera's
+//! password, if any, is sent as a bearer token, so it should be an API token of a user with the
+//! `view_video` permission on the edge instance.
+//!
+//! Recordings get fresh ids in the local stream, so they never conflict with local recordings or
+//! those replicated from elsewhere, and keep their original start times and frame durations.
+//! Progress isn't stored separately: on startup, replication resumes after the end of the local
+//! stream's latest recording. A download interrupted partway is resumed from the last byte
+//! received, and is checked against the edge's BLAKE3 digest (when known) before being written.
+
+use crate::json;
+use crate::streamer::DirAndSyncer;
+use base::crypto;
+use base::strutil;
+use db::{recording, writer};
+use failure::{bail, format_err, Error};
+use fnv::FnvHashMap;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration as StdDuration;
+use tracing::{info, warn};
+use url::Url;
+
+/// How long to wait between polls of each remote stream after catching up.
+const POLL_INTERVAL: StdDuration = StdDuration::from_secs(60);
+
+/// How long a single HTTP read or write may stall before the request fails.
+const REQUEST_TIMEOUT: StdDuration = StdDuration::from_secs(60);
+
+/// The number of recordings to request per `GET .../replica`.
+const PAGE_LIMIT: usize = 10;
+
+/// The number of consecutive failed requests which make no progress downloading a recording
+/// before giving up on it until the next poll.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// How long to wait before resuming an interrupted download.
+const RETRY_INTERVAL: StdDuration = StdDuration::from_secs(5);
+
+/// Returns the remote stream's API URL (ending in a slash) if the given stream URL names a
+/// stream on another Moonfire NVR instance, or `None` otherwise.
+pub fn remote_url(url: &str) -> Option<Url> {
+    if !url.starts_with("nvr+http://") && !url.starts_with("nvr+https://") {
+        return None;
+    }
+    let mut url = Url::parse(&url["nvr+".len()..]).ok()?;
+    url.set_query(None);
+    url.set_fragment(None);
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    Some(url)
+}
+
+/// A stream which receives recordings by replication, with the directories it records to.
+pub struct ReplicaStream {
+    pub stream_id: i32,
+    pub short_name: String,
+
+    /// The remote stream's API URL, as returned by `remote_url`.
+    pub url: Url,
+
+    /// The bearer token to authenticate with, if any.
+    pub token: Option<String>,
+
+    /// As in `streamer::Streamer::new`.
+    pub stripes: Vec<DirAndSyncer>,
+    pub failover: Option<DirAndSyncer>,
+}
+
+/// Per-stream replication state, kept in memory only.
+#[derive(Default)]
+struct Progress {
+    /// Remote recordings starting before this time have already been copied.
+    next_start: Option<recording::Time>,
+
+    /// Local video sample entry ids, by remote id.
+    sample_entries: FnvHashMap<i32, i32>,
+}
+
+pub struct Puller {
+    db: Arc<db::Database>,
+    shutdown: Arc<AtomicBool>,
+    streams: Vec<ReplicaStream>,
+    client: reqwest::blocking::Client,
+}
+
+impl Puller {
+    pub fn new(
+        db: Arc<db::Database>,
+        shutdown: Arc<AtomicBool>,
+        streams: Vec<ReplicaStream>,
+    ) -> Result<Self, Error> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        Ok(Puller {
+            db,
+            shutdown,
+            streams,
+            client,
+        })
+    }
+
+    /// Replicates until shutdown on a new thread.
+    pub fn start(self) -> Result<thread::JoinHandle<()>, Error> {
+        info!("replication: pulling {} stream(s)", self.streams.len());
+        Ok(thread::Builder::new()
+            .name("replication".to_owned())
+            .spawn(move || self.run())?)
+    }
+
+    fn run(&self) {
+        let mut progress: Vec<Progress> =
+            self.streams.iter().map(|_| Progress::default()).collect();
+        while !self.shutdown.load(Ordering::SeqCst) {
+            for (s, p) in self.streams.iter().zip(progress.iter_mut()) {
+                loop {
+                    if self.shutdown.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    match self.pull(s, p) {
+                        Ok(true) => continue,
+                        Ok(false) => break,
+                        Err(e) => {
+                            warn!("replication: {}: {}", s.short_name, e);
+                            break;
+                        }
+                    }
+                }
+            }
+            self.sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Sleeps for the given duration or until shutdown, whichever comes first.
+    fn sleep(&self, d: StdDuration) {
+        let step = StdDuration::from_secs(1);
+        let mut remaining = d;
+        while remaining > StdDuration::from_secs(0) && !self.shutdown.load(Ordering::SeqCst) {
+            let s = std::cmp::min(step, remaining);
+            thread::sleep(s);
+            remaining -= s;
+        }
+    }
+
+    fn get(&self, s: &ReplicaStream, url: Url) -> reqwest::blocking::RequestBuilder {
+        let req = self.client.get(url);
+        match s.token {
+            Some(ref t) => req.bearer_auth(t),
+            None => req,
+        }
+    }
+
+    /// Copies the next page of remote recordings. Returns true if there may be more.
+    fn pull(&self, s: &ReplicaStream, p: &mut Progress) -> Result<bool, Error> {
+        let next_start = match p.next_start {
+            Some(t) => t,
+            None => {
+                let l = self.db.lock();
+                let stream = l
+                    .streams_by_id()
+                    .get(&s.stream_id)
+                    .ok_or_else(|| format_err!("no such stream {}", s.stream_id))?;
+                stream
+                    .range
+                    .as_ref()
+                    .map(|r| r.end)
+                    .unwrap_or(recording::Time(0))
+            }
+        };
+        let replica: json::Replica = self
+            .get(s, s.url.join("replica")?)
+            .query(&[
+                ("startTime90k", next_start.0.to_string()),
+                ("limit", PAGE_LIMIT.to_string()),
+            ])
+            .send()?
+            .error_for_status()?
+            .json()?;
+        for r in &replica.recordings {
+            if self.shutdown.load(Ordering::SeqCst) {
+                return Ok(false);
+            }
+            let video_sample_entry_id = match p.sample_entries.get(&r.video_sample_entry_id) {
+                Some(&id) => id,
+                None => {
+                    let e = replica
+                        .video_sample_entries
+                        .get(&r.video_sample_entry_id)
+                        .ok_or_else(|| {
+                            format_err!(
+                                "recording {} has unlisted video sample entry {}",
+                                r.id,
+                                r.video_sample_entry_id
+                            )
+                        })?;
+                    let id = self.db.lock().insert_encoded_video_sample_entry(
+                        e.width,
+                        e.height,
+                        base64::decode(&e.data)?,
+                        e.rfc6381_codec.clone(),
+                        e.encoder.clone(),
+                    )?;
+                    p.sample_entries.insert(r.video_sample_entry_id, id);
+                    id
+                }
+            };
+            let data = self.fetch(s, r)?;
+            self.write(s, r, video_sample_entry_id, &data)?;
+            p.next_start = Some(recording::Time(
+                r.start_time_90k + std::cmp::max(r.duration_90k, 1) as i64,
+            ));
+        }
+        Ok(replica.recordings.len() == PAGE_LIMIT)
+    }
+
+    /// Downloads a remote recording's sample data, resuming after interruptions.
+    fn fetch(&self, s: &ReplicaStream, r: &json::ReplicaRecording) -> Result<Vec<u8>, Error> {
+        let len = r.sample_file_bytes as usize;
+        let url = s.url.join(&format!("replica/{}.bin", r.id))?;
+        let mut data = Vec::with_capacity(len);
+        let mut failures = 0;
+        while data.len() < len {
+            let before = data.len();
+            let result = self
+                .get(s, url.clone())
+                .query(&[("offset", before)])
+                .send()
+                .and_then(|resp| resp.error_for_status())
+                .map_err(Error::from)
+                .and_then(|mut resp| {
+                    // On error, read_to_end keeps the bytes read so far, so they needn't be
+                    // fetched again.
+                    resp.by_ref()
+                        .take((len - before) as u64)
+                        .read_to_end(&mut data)?;
+                    Ok(())
+                });
+            match result {
+                Ok(()) if data.len() == before => {
+                    bail!("recording {} ended after {} of {} bytes", r.id, before, len)
+                }
+                Ok(()) => {}
+                Err(e) => {
+                    failures = if data.len() == before {
+                        failures + 1
+                    } else {
+                        1
+                    };
+                    if failures >= MAX_ATTEMPTS {
+                        return Err(e);
+                    }
+                    warn!(
+                        "replication: {}: recording {} interrupted at byte {} of {}: {}; resuming",
+                        s.short_name,
+                        r.id,
+                        data.len(),
+                        len,
+                        e
+                    );
+                    self.sleep(RETRY_INTERVAL);
+                    if self.shutdown.load(Ordering::SeqCst) {
+                        bail!("shutting down");
+                    }
+                }
+            }
+        }
+        if let Some(ref want) = r.blake3 {
+            let got = strutil::hex(&crypto::blake3(&data));
+            if &got != want {
+                bail!("recording {} has BLAKE3 {}; expected {}", r.id, got, want);
+            }
+        }
+        Ok(data)
+    }
+
+    /// Writes a downloaded recording to the local stream as a new recording.
+    fn write(
+        &self,
+        s: &ReplicaStream,
+        r: &json::ReplicaRecording,
+        video_sample_entry_id: i32,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let index = base64::decode(&r.video_index)?;
+        let w = writer::Writer::new_striped(
+            s.stripes.iter().map(|(d, c)| (d, c)).collect(),
+            &*self.db,
+            s.stream_id,
+            video_sample_entry_id,
+        );
+        let mut w = match s.failover {
+            Some((ref d, ref c)) => w.with_failover(d, c),
+            None => w,
+        };
+        let start = recording::Time(r.start_time_90k);
+        let mut it = recording::SampleIndexIterator::new();
+        while it.next(&index)? {
+            let pos = it.pos as usize;
+            let end = pos + it.bytes as usize;
+            if end > data.len() {
+                bail!(
+                    "recording {}'s index extends past its {} bytes",
+                    r.id,
+                    data.len()
+                );
+            }
+
+            // The writer takes each recording's start time to be the earliest of its samples'
+            // local times minus the duration through them, so pass each sample's end time.
+            let local_time = start + recording::Duration((it.start_90k + it.duration_90k) as i64);
+            w.write(
+                &data[pos..end],
+                local_time,
+                it.start_90k as i64,
+                it.is_key(),
+            )?;
+        }
+        w.close(Some(r.duration_90k as i64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn remote_url() {
+        assert_eq!(super::remote_url("rtsp://cam/main"), None);
+        assert_eq!(
+            super::remote_url("nvr+https://edge.example:8443/api/cameras/x/main")
+                .unwrap()
+                .as_str(),
+            "https://edge.example:8443/api/cameras/x/main/"
+        );
+        assert_eq!(
+            super::remote_url("nvr+http://edge/api/cameras/x/sub/")
+                .unwrap()
+                .join("replica/3.bin")
+                .unwrap()
+                .as_str(),
+            "http://edge/api/cameras/x/sub/replica/3.bin"
+        );
+    }
+}
@@ -0,0 +1,1039 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! RTMP ingest, for devices (such as doorbells) and software (such as OBS) which can push a stream
+//! but not serve RTSP.
+//!
+//! A stream whose URL is `rtmp://<host>[:<port>]/<app>/<key>` isn't pulled by a streamer. Instead,
+//! when the `rtmp_addr` config key is set (such as to `0.0.0.0:1935`), `moonfire-nvr run` listens
+//! there, and a client which connects to application `<app>` and publishes stream name `<key>`
+//! records into that stream through the same `db::writer::Writer` pipeline the streamers use. The
+//! host and port in the stream's URL are just what to configure the device with; the key acts
+//! as the device's credential, so it should be long and random.
+//!
+//! Only H.264 video is accepted; audio and metadata are discarded. Each stream accepts one
+//! publisher at a time, and its liveness is reported to `crate::health` just as a pulled stream's.
+//!
+//! This implements just enough of the RTMP specification (version 1.0) to accept a publisher:
+//! the unauthenticated "simple" handshake, the chunk stream, and the AMF0 commands `connect`,
+//! `createStream`, `publish`, and `deleteStream`.
+
+use crate::h264;
+use crate::health;
+use crate::streamer::{self, DirAndSyncer};
+use base::clock::{Clocks, RealClocks};
+use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use db::{dir, recording, writer};
+use failure::{bail, format_err, Error};
+use parking_lot::{Mutex, MutexGuard};
+use std::collections::HashMap;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration as StdDuration, Instant};
+use tracing::{debug, info, warn};
+
+/// The length of the C1/C2/S1/S2 handshake messages.
+const HANDSHAKE_LEN: usize = 1536;
+
+/// How often blocked reads and accepts check for shutdown.
+const POLL_INTERVAL: StdDuration = StdDuration::from_secs(1);
+
+/// How long a client may take to publish, and be silent before then.
+const UNPUBLISHED_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+
+/// The most connections to serve at once; further ones are refused.
+const MAX_CONNECTIONS: usize = 32;
+
+/// The most chunk streams a connection may use at once, bounding partially received messages.
+const MAX_CHUNK_STREAMS: usize = 32;
+
+/// The chunk size this server sends with.
+const OUT_CHUNK_SIZE: usize = 4096;
+
+/// The window acknowledgement size and peer bandwidth this server requests.
+const WINDOW_SIZE: u32 = 2_500_000;
+
+// Message type ids, as in the RTMP specification sections 5.4 and 7.1.
+const MSG_SET_CHUNK_SIZE: u8 = 1;
+const MSG_ABORT: u8 = 2;
+const MSG_ACK: u8 = 3;
+const MSG_USER_CONTROL: u8 = 4;
+const MSG_WINDOW_ACK_SIZE: u8 = 5;
+const MSG_SET_PEER_BANDWIDTH: u8 = 6;
+const MSG_VIDEO: u8 = 9;
+const MSG_COMMAND_AMF3: u8 = 17;
+const MSG_COMMAND_AMF0: u8 = 20;
+
+// Chunk stream ids used for sending.
+const CSID_CONTROL: u8 = 2;
+const CSID_COMMAND: u8 = 3;
+const CSID_STATUS: u8 = 5;
+
+/// The message stream id given to the client's one stream by `createStream`.
+const PUBLISH_STREAM_ID: u32 = 1;
+
+/// The FLV video codec id of H.264, as in the FLV specification's `VIDEODATA`.
+const CODEC_AVC: u8 = 7;
+
+/// Returns the `rtmp_addr` config key: the address to accept RTMP pushes on, if any.
+pub fn addr(l: &db::LockedDatabase) -> Result<Option<SocketAddr>, Error> {
+    match l.get_config("rtmp_addr")? {
+        None => Ok(None),
+        Some(a) => Ok(Some(
+            SocketAddr::from_str(&a).map_err(|_| format_err!("bad rtmp_addr {:?}", a))?,
+        )),
+    }
+}
+
+/// Returns the `<app>/<key>` a stream with the given URL is published to, or `None` if it isn't
+/// an RTMP URL.
+pub fn publish_path(url: &str) -> Option<String> {
+    let url = url::Url::parse(url).ok()?;
+    if url.scheme() != "rtmp" {
+        return None;
+    }
+    Some(url.path().trim_matches('/').to_owned())
+}
+
+/// A stream which receives video by RTMP push, with the directories it records to.
+pub struct IngestStream {
+    pub stream_id: i32,
+    pub short_name: String,
+
+    /// The path to publish to, as returned by `publish_path`.
+    pub path: String,
+
+    /// As in `streamer::Streamer::new`.
+    pub stripes: Vec<DirAndSyncer>,
+    pub failover: Option<DirAndSyncer>,
+    pub rotate_offset_sec: i64,
+
+    /// How long the publisher may be silent before the connection is dropped.
+    pub session_timeout: StdDuration,
+}
+
+pub struct Server {
+    db: Arc<db::Database>,
+    shutdown: Arc<AtomicBool>,
+    streams: Vec<IngestStream>,
+
+    /// A liveness tracker for each of `streams`. Held by the stream's publisher, if any, so that
+    /// there's at most one at a time.
+    publishers: Vec<Mutex<health::Tracker>>,
+
+    connections: AtomicUsize,
+}
+
+impl Server {
+    pub fn new(
+        db: Arc<db::Database>,
+        health: &health::Monitor,
+        shutdown: Arc<AtomicBool>,
+        streams: Vec<IngestStream>,
+    ) -> Self {
+        let now = db.clocks().monotonic();
+        let publishers = streams
+            .iter()
+            .map(|s| Mutex::new(health.tracker(s.stream_id, now)))
+            .collect();
+        Server {
+            db,
+            shutdown,
+            streams,
+            publishers,
+            connections: AtomicUsize::new(0),
+        }
+    }
+
+    /// Listens on `addr` until shutdown, serving each connection on its own thread. The returned
+    /// thread finishes after all connections have closed their recordings.
+    pub fn start(self, addr: SocketAddr) -> Result<thread::JoinHandle<()>, Error> {
+        let listener = TcpListener::bind(addr)
+            .map_err(|e| format_err!("unable to bind RTMP address {}: {}", addr, e))?;
+        listener.set_nonblocking(true)?;
+        info!(
+            "rtmp: listening on {} for {} stream(s)",
+            addr,
+            self.streams.len()
+        );
+        let server = Arc::new(self);
+        Ok(thread::Builder::new()
+            .name("rtmp".to_owned())
+            .spawn(move || server.accept_loop(listener))?)
+    }
+
+    fn accept_loop(self: Arc<Self>, listener: TcpListener) {
+        // Each connection's thread holds a sender; when all are dropped, they've all finished.
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        while !self.shutdown.load(Ordering::SeqCst) {
+            let (conn, peer) = match listener.accept() {
+                Ok(c) => c,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("rtmp: accept failed: {}", e);
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            };
+            if self.connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                self.connections.fetch_sub(1, Ordering::SeqCst);
+                warn!("rtmp: refusing {}; too many connections", peer);
+                continue;
+            }
+            let server = self.clone();
+            let done_tx = done_tx.clone();
+            let r = thread::Builder::new()
+                .name(format!("rtmp-{}", peer))
+                .spawn(move || {
+                    let _done_tx = done_tx;
+                    if let Err(e) = server.serve(conn, peer) {
+                        info!("rtmp: {}: {}", peer, e);
+                    }
+                    server.connections.fetch_sub(1, Ordering::SeqCst);
+                });
+            if let Err(e) = r {
+                warn!("rtmp: unable to create thread for {}: {}", peer, e);
+                self.connections.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+        drop(done_tx);
+        let _ = done_rx.recv();
+        info!("rtmp: shutting down");
+    }
+
+    fn serve(&self, conn: TcpStream, peer: SocketAddr) -> Result<(), Error> {
+        debug!("rtmp: connection from {}", peer);
+        conn.set_nonblocking(false)?;
+        conn.set_read_timeout(Some(POLL_INTERVAL))?;
+        conn.set_nodelay(true)?;
+        let w = conn.try_clone()?;
+        let mut s = Session {
+            server: self,
+            peer,
+            r: BufReader::new(Conn {
+                stream: conn,
+                shutdown: &self.shutdown,
+                timeout: UNPUBLISHED_TIMEOUT,
+                bytes_read: 0,
+            }),
+            w,
+            chunks: ChunkReader::default(),
+            out_chunk_size: 128,
+            ack_window: None,
+            acked: 0,
+            app: None,
+            publication: None,
+        };
+        let r = s.run();
+        if let Some(p) = s.publication.take() {
+            p.finish();
+        }
+        r
+    }
+}
+
+/// A connection's socket, which gives up on reads when the server is shutting down or the peer
+/// has been silent for too long.
+struct Conn<'a> {
+    stream: TcpStream,
+    shutdown: &'a AtomicBool,
+    timeout: StdDuration,
+    bytes_read: u64,
+}
+
+impl<'a> Read for Conn<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        loop {
+            match self.stream.read(buf) {
+                Ok(n) => {
+                    self.bytes_read += n as u64;
+                    return Ok(n);
+                }
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    if self.shutdown.load(Ordering::SeqCst) {
+                        return Err(io::Error::new(io::ErrorKind::Other, "shutting down"));
+                    }
+                    if start.elapsed() >= self.timeout {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "client is silent"));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// A complete message, reassembled from chunks.
+#[derive(Debug, PartialEq)]
+struct Message {
+    type_id: u8,
+    stream_id: u32,
+    timestamp: u32,
+    payload: Vec<u8>,
+}
+
+/// The state of a chunk stream: the previous message header and any partial message.
+#[derive(Default)]
+struct ChunkStream {
+    timestamp: u32,
+    delta: u32,
+    len: usize,
+    type_id: u8,
+    stream_id: u32,
+    extended: bool,
+    partial: Vec<u8>,
+}
+
+/// Reassembles messages from chunks, as in the RTMP specification section 5.3.
+struct ChunkReader {
+    chunk_size: usize,
+    streams: HashMap<u32, ChunkStream>,
+}
+
+impl Default for ChunkReader {
+    fn default() -> Self {
+        ChunkReader {
+            chunk_size: 128,
+            streams: HashMap::new(),
+        }
+    }
+}
+
+impl ChunkReader {
+    fn read_message<R: Read>(&mut self, r: &mut R) -> Result<Message, Error> {
+        loop {
+            let b = r.read_u8()?;
+            let fmt = b >> 6;
+            let csid = match b & 0x3f {
+                0 => 64 + u32::from(r.read_u8()?),
+                1 => 64 + u32::from(r.read_u16::<LittleEndian>()?),
+                c => u32::from(c),
+            };
+            if !self.streams.contains_key(&csid) {
+                if fmt != 0 {
+                    bail!("chunk stream {} starts without a full header", csid);
+                }
+                if self.streams.len() >= MAX_CHUNK_STREAMS {
+                    bail!("too many chunk streams");
+                }
+            }
+            let s = self.streams.entry(csid).or_default();
+            if fmt != 3 && !s.partial.is_empty() {
+                bail!(
+                    "chunk stream {} has a new message before the last finished",
+                    csid
+                );
+            }
+            let starting = s.partial.is_empty();
+            if fmt < 3 {
+                let ts = r.read_u24::<BigEndian>()?;
+                if fmt < 2 {
+                    s.len = r.read_u24::<BigEndian>()? as usize;
+                    s.type_id = r.read_u8()?;
+                }
+                if fmt == 0 {
+                    s.stream_id = r.read_u32::<LittleEndian>()?;
+                }
+                s.extended = ts == 0xff_ffff;
+                let ts = if s.extended {
+                    r.read_u32::<BigEndian>()?
+                } else {
+                    ts
+                };
+                if fmt == 0 {
+                    s.timestamp = ts;
+                    s.delta = 0;
+                } else {
+                    s.delta = ts;
+                    s.timestamp = s.timestamp.wrapping_add(ts);
+                }
+            } else {
+                if s.extended {
+                    r.read_u32::<BigEndian>()?;
+                }
+                if starting {
+                    s.timestamp = s.timestamp.wrapping_add(s.delta);
+                }
+            }
+            let n = std::cmp::min(self.chunk_size, s.len - s.partial.len());
+            let start = s.partial.len();
+            s.partial.resize(start + n, 0);
+            r.read_exact(&mut s.partial[start..])?;
+            if s.partial.len() == s.len {
+                return Ok(Message {
+                    type_id: s.type_id,
+                    stream_id: s.stream_id,
+                    timestamp: s.timestamp,
+                    payload: std::mem::replace(&mut s.partial, Vec::new()),
+                });
+            }
+        }
+    }
+
+    /// Discards the partial message on the given chunk stream, as requested by an abort message.
+    fn abort(&mut self, csid: u32) {
+        if let Some(s) = self.streams.get_mut(&csid) {
+            s.partial.clear();
+        }
+    }
+}
+
+/// Writes a message with a type 0 chunk header followed by type 3 continuation chunks.
+fn write_message<W: Write>(
+    w: &mut W,
+    chunk_size: usize,
+    csid: u8,
+    type_id: u8,
+    stream_id: u32,
+    payload: &[u8],
+) -> Result<(), Error> {
+    let mut buf = Vec::with_capacity(12 + payload.len() + payload.len() / chunk_size);
+    buf.push(csid);
+    buf.write_u24::<BigEndian>(0)?; // timestamp
+    buf.write_u24::<BigEndian>(payload.len() as u32)?;
+    buf.push(type_id);
+    buf.write_u32::<LittleEndian>(stream_id)?;
+    for (i, chunk) in payload.chunks(chunk_size).enumerate() {
+        if i > 0 {
+            buf.push(0xc0 | csid);
+        }
+        buf.extend_from_slice(chunk);
+    }
+    w.write_all(&buf)?;
+    Ok(())
+}
+
+/// An AMF0 value, as in the Action Message Format AMF0 specification.
+#[derive(Clone, Debug, PartialEq)]
+enum Amf {
+    Number(f64),
+    Boolean(bool),
+    String(String),
+    Object(Vec<(String, Amf)>),
+    Null,
+    Undefined,
+    Array(Vec<Amf>),
+}
+
+impl Amf {
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Amf::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&Amf> {
+        match self {
+            Amf::Object(props) => props.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn decode(r: &mut &[u8]) -> Result<Amf, Error> {
+        fn string(r: &mut &[u8], len: usize) -> Result<String, Error> {
+            if r.len() < len {
+                bail!("truncated AMF0 string");
+            }
+            let s = String::from_utf8(r[..len].to_vec())?;
+            *r = &r[len..];
+            Ok(s)
+        }
+        fn props(r: &mut &[u8]) -> Result<Vec<(String, Amf)>, Error> {
+            let mut props = Vec::new();
+            loop {
+                let len = r.read_u16::<BigEndian>()? as usize;
+                if len == 0 && r.first() == Some(&9) {
+                    *r = &r[1..];
+                    return Ok(props);
+                }
+                let key = string(r, len)?;
+                props.push((key, Amf::decode(r)?));
+            }
+        }
+        Ok(match r.read_u8()? {
+            0 => Amf::Number(r.read_f64::<BigEndian>()?),
+            1 => Amf::Boolean(r.read_u8()? != 0),
+            2 => {
+                let len = r.read_u16::<BigEndian>()? as usize;
+                Amf::String(string(r, len)?)
+            }
+            3 => Amf::Object(props(r)?),
+            5 => Amf::Null,
+            6 => Amf::Undefined,
+            8 => {
+                r.read_u32::<BigEndian>()?; // approximate count
+                Amf::Object(props(r)?)
+            }
+            10 => {
+                let n = r.read_u32::<BigEndian>()?;
+                let mut values = Vec::new();
+                for _ in 0..n {
+                    values.push(Amf::decode(r)?);
+                }
+                Amf::Array(values)
+            }
+            12 => {
+                let len = r.read_u32::<BigEndian>()? as usize;
+                Amf::String(string(r, len)?)
+            }
+            m => bail!("unsupported AMF0 marker {}", m),
+        })
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        fn string(s: &str, out: &mut Vec<u8>) {
+            out.write_u16::<BigEndian>(s.len() as u16).unwrap();
+            out.extend_from_slice(s.as_bytes());
+        }
+        match self {
+            Amf::Number(n) => {
+                out.push(0);
+                out.write_f64::<BigEndian>(*n).unwrap();
+            }
+            Amf::Boolean(b) => out.extend_from_slice(&[1, *b as u8]),
+            Amf::String(s) => {
+                out.push(2);
+                string(s, out);
+            }
+            Amf::Object(props) => {
+                out.push(3);
+                for (k, v) in props {
+                    string(k, out);
+                    v.encode(out);
+                }
+                out.extend_from_slice(&[0, 0, 9]);
+            }
+            Amf::Null => out.push(5),
+            Amf::Undefined => out.push(6),
+            Amf::Array(values) => {
+                out.push(10);
+                out.write_u32::<BigEndian>(values.len() as u32).unwrap();
+                for v in values {
+                    v.encode(out);
+                }
+            }
+        }
+    }
+}
+
+/// Returns an AMF0 object with the given string properties.
+fn status_object(props: &[(&str, &str)]) -> Amf {
+    Amf::Object(
+        props
+            .iter()
+            .map(|&(k, v)| (k.to_owned(), Amf::String(v.to_owned())))
+            .collect(),
+    )
+}
+
+struct Session<'a> {
+    server: &'a Server,
+    peer: SocketAddr,
+    r: BufReader<Conn<'a>>,
+    w: TcpStream,
+    chunks: ChunkReader,
+    out_chunk_size: usize,
+
+    /// The client's window acknowledgement size, and the byte count last acknowledged.
+    ack_window: Option<u32>,
+    acked: u64,
+
+    /// The application given by `connect`.
+    app: Option<String>,
+    publication: Option<Publication<'a>>,
+}
+
+impl<'a> Session<'a> {
+    fn run(&mut self) -> Result<(), Error> {
+        self.handshake()?;
+        loop {
+            let msg = self.chunks.read_message(&mut self.r)?;
+            self.acknowledge()?;
+            match msg.type_id {
+                MSG_SET_CHUNK_SIZE => {
+                    let size = payload_u32(&msg.payload)? & 0x7fff_ffff;
+                    if size == 0 || size > 0xff_ffff {
+                        bail!("bad chunk size {}", size);
+                    }
+                    self.chunks.chunk_size = size as usize;
+                }
+                MSG_ABORT => self.chunks.abort(payload_u32(&msg.payload)?),
+                MSG_WINDOW_ACK_SIZE => self.ack_window = Some(payload_u32(&msg.payload)?),
+                MSG_COMMAND_AMF0 | MSG_COMMAND_AMF3 => {
+                    // An AMF3 command message starts with a format byte, then is AMF0 anyway.
+                    let skip = if msg.type_id == MSG_COMMAND_AMF3 {
+                        1
+                    } else {
+                        0
+                    };
+                    let mut p = msg.payload.get(skip..).unwrap_or(&[]);
+                    let mut values = Vec::new();
+                    while !p.is_empty() {
+                        values.push(Amf::decode(&mut p)?);
+                    }
+                    if !self.command(&values)? {
+                        return Ok(());
+                    }
+                }
+                MSG_VIDEO => {
+                    if let Some(ref mut p) = self.publication {
+                        p.video(msg.timestamp, &msg.payload)?;
+                    }
+                }
+                _ => {} // audio, metadata, user control events, acknowledgements.
+            }
+        }
+    }
+
+    /// Performs the simple handshake, as in the RTMP specification section 5.2.
+    fn handshake(&mut self) -> Result<(), Error> {
+        let mut c0c1 = vec![0; 1 + HANDSHAKE_LEN];
+        self.r.read_exact(&mut c0c1)?;
+        if c0c1[0] != 3 {
+            bail!("unsupported RTMP version {}", c0c1[0]);
+        }
+        let mut s0s1s2 = Vec::with_capacity(1 + 2 * HANDSHAKE_LEN);
+        s0s1s2.push(3);
+        s0s1s2.resize(1 + HANDSHAKE_LEN, 0); // S1: zero time, zero, zero "random" data.
+        s0s1s2.extend_from_slice(&c0c1[1..]); // S2 echoes C1.
+        self.w.write_all(&s0s1s2)?;
+        let mut c2 = vec![0; HANDSHAKE_LEN];
+        self.r.read_exact(&mut c2)?;
+        Ok(())
+    }
+
+    fn acknowledge(&mut self) -> Result<(), Error> {
+        let window = match self.ack_window {
+            Some(w) if w > 0 => u64::from(w),
+            _ => return Ok(()),
+        };
+        let read = self.r.get_ref().bytes_read;
+        if read - self.acked >= window {
+            self.acked = read;
+            let mut p = Vec::new();
+            p.write_u32::<BigEndian>(read as u32)?;
+            self.send(CSID_CONTROL, MSG_ACK, 0, &p)?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, csid: u8, type_id: u8, stream_id: u32, payload: &[u8]) -> Result<(), Error> {
+        write_message(
+            &mut self.w,
+            self.out_chunk_size,
+            csid,
+            type_id,
+            stream_id,
+            payload,
+        )
+    }
+
+    fn send_command(&mut self, csid: u8, stream_id: u32, values: &[Amf]) -> Result<(), Error> {
+        let mut p = Vec::new();
+        for v in values {
+            v.encode(&mut p);
+        }
+        self.send(csid, MSG_COMMAND_AMF0, stream_id, &p)
+    }
+
+    fn send_status(&mut self, level: &str, code: &str, description: &str) -> Result<(), Error> {
+        self.send_command(
+            CSID_STATUS,
+            PUBLISH_STREAM_ID,
+            &[
+                Amf::String("onStatus".to_owned()),
+                Amf::Number(0.),
+                Amf::Null,
+                status_object(&[
+                    ("level", level),
+                    ("code", code),
+                    ("description", description),
+                ]),
+            ],
+        )
+    }
+
+    /// Handles a command. Returns false if the session should end.
+    fn command(&mut self, values: &[Amf]) -> Result<bool, Error> {
+        let name = values.get(0).and_then(Amf::as_str).unwrap_or("");
+        let txid = match values.get(1) {
+            Some(Amf::Number(n)) => *n,
+            _ => 0.,
+        };
+        debug!("rtmp: {}: command {}", self.peer, name);
+        match name {
+            "connect" => {
+                let app = values
+                    .get(2)
+                    .and_then(|o| o.get("app"))
+                    .and_then(Amf::as_str)
+                    .ok_or_else(|| format_err!("connect without app"))?;
+                self.app = Some(app.trim_matches('/').to_owned());
+                let mut p = Vec::new();
+                p.write_u32::<BigEndian>(WINDOW_SIZE)?;
+                self.send(CSID_CONTROL, MSG_WINDOW_ACK_SIZE, 0, &p)?;
+                p.push(2); // dynamic limit type
+                self.send(CSID_CONTROL, MSG_SET_PEER_BANDWIDTH, 0, &p)?;
+                p.clear();
+                p.write_u32::<BigEndian>(OUT_CHUNK_SIZE as u32)?;
+                self.send(CSID_CONTROL, MSG_SET_CHUNK_SIZE, 0, &p)?;
+                self.out_chunk_size = OUT_CHUNK_SIZE;
+                self.send_command(
+                    CSID_COMMAND,
+                    0,
+                    &[
+                        Amf::String("_result".to_owned()),
+                        Amf::Number(txid),
+                        Amf::Object(vec![
+                            ("fmsVer".to_owned(), Amf::String("FMS/3,0,1,123".to_owned())),
+                            ("capabilities".to_owned(), Amf::Number(31.)),
+                        ]),
+                        Amf::Object(vec![
+                            ("level".to_owned(), Amf::String("status".to_owned())),
+                            (
+                                "code".to_owned(),
+                                Amf::String("NetConnection.Connect.Success".to_owned()),
+                            ),
+                            (
+                                "description".to_owned(),
+                                Amf::String("Connection succeeded.".to_owned()),
+                            ),
+                            ("objectEncoding".to_owned(), Amf::Number(0.)),
+                        ]),
+                    ],
+                )?;
+            }
+            "createStream" => {
+                self.send_command(
+                    CSID_COMMAND,
+                    0,
+                    &[
+                        Amf::String("_result".to_owned()),
+                        Amf::Number(txid),
+                        Amf::Null,
+                        Amf::Number(f64::from(PUBLISH_STREAM_ID)),
+                    ],
+                )?;
+            }
+            "publish" => {
+                let name = values.get(3).and_then(Amf::as_str).unwrap_or("");
+                self.publish(name)?;
+            }
+            "deleteStream" | "closeStream" | "FCUnpublish" => {
+                if let Some(p) = self.publication.take() {
+                    info!("rtmp: {}: stopped publishing", self.peer);
+                    p.finish();
+                }
+                return Ok(false);
+            }
+            _ => {} // releaseStream, FCPublish, and others need no response.
+        }
+        Ok(true)
+    }
+
+    fn publish(&mut self, name: &str) -> Result<(), Error> {
+        if self.publication.is_some() {
+            bail!("publish while already publishing");
+        }
+        let app = self
+            .app
+            .clone()
+            .ok_or_else(|| format_err!("publish before connect"))?;
+
+        // Clients may append query parameters to the stream name; they're not part of the key.
+        let name = name.split('?').next().unwrap();
+        let path = format!("{}/{}", app, name);
+        let server = self.server;
+        let i = server.streams.iter().position(|s| {
+            ring::constant_time::verify_slices_are_equal(s.path.as_bytes(), path.as_bytes()).is_ok()
+        });
+        let i = match i {
+            Some(i) => i,
+            None => {
+                self.send_status("error", "NetStream.Publish.BadName", "no such stream")?;
+                bail!("no stream with this key in application {:?}", app);
+            }
+        };
+        let stream = &server.streams[i];
+        let mut tracker = match server.publishers[i].try_lock() {
+            Some(t) => t,
+            None => {
+                self.send_status("error", "NetStream.Publish.BadName", "already publishing")?;
+                bail!("{} already has a publisher", stream.short_name);
+            }
+        };
+        let mut p = Vec::new();
+        p.write_u16::<BigEndian>(0)?; // StreamBegin
+        p.write_u32::<BigEndian>(PUBLISH_STREAM_ID)?;
+        self.send(CSID_CONTROL, MSG_USER_CONTROL, 0, &p)?;
+        self.send_status("status", "NetStream.Publish.Start", "Start publishing")?;
+        info!("rtmp: {}: publishing to {}", self.peer, stream.short_name);
+        tracker.connected();
+        self.r.get_mut().timeout = stream.session_timeout;
+        let clocks = server.db.clocks();
+        self.publication = Some(Publication {
+            server,
+            stream,
+            tracker,
+            realtime_offset: clocks.realtime() - clocks.monotonic(),
+            config: None,
+            writer: None,
+            rotate: None,
+        });
+        Ok(())
+    }
+}
+
+fn payload_u32(payload: &[u8]) -> Result<u32, Error> {
+    if payload.len() < 4 {
+        bail!("short control message");
+    }
+    Ok(BigEndian::read_u32(payload))
+}
+
+/// A stream being published, and its recording.
+struct Publication<'a> {
+    server: &'a Server,
+    stream: &'a IngestStream,
+    tracker: MutexGuard<'a, health::Tracker>,
+    realtime_offset: time::Duration,
+
+    /// The current `AVCDecoderConfigurationRecord` and its video sample entry id.
+    config: Option<(Vec<u8>, i32)>,
+    writer: Option<writer::Writer<'a, RealClocks, Arc<dir::SampleFileDir>>>,
+
+    /// Seconds since epoch at which to next rotate, once the writer has a recording open.
+    rotate: Option<i64>,
+}
+
+impl<'a> Publication<'a> {
+    /// Handles a video message, as in the FLV specification's `VIDEODATA` and `AVCVIDEOPACKET`.
+    fn video(&mut self, timestamp: u32, payload: &[u8]) -> Result<(), Error> {
+        if payload.len() < 5 {
+            bail!("short video message");
+        }
+        let codec = payload[0] & 0x0f;
+        if codec != CODEC_AVC {
+            bail!(
+                "unsupported video codec id {}; only H.264 is supported",
+                codec
+            );
+        }
+        let is_key = payload[0] >> 4 == 1;
+        let composition_time = (BigEndian::read_i32(&payload[1..5]) << 8) >> 8;
+        let data = &payload[5..];
+        match payload[1] {
+            0 => self.sequence_header(data),
+            1 => self.frame(timestamp, composition_time, is_key, data),
+            _ => Ok(()), // end of sequence
+        }
+    }
+
+    fn sequence_header(&mut self, config: &[u8]) -> Result<(), Error> {
+        if self.config.as_ref().map(|(c, _)| &c[..]) == Some(config) {
+            return Ok(());
+        }
+        let extra_data = h264::ExtraData::from_avc_decoder_config(config)?;
+        let id = self.server.db.lock().insert_video_sample_entry(
+            extra_data.width,
+            extra_data.height,
+            extra_data.sample_entry,
+            extra_data.rfc6381_codec,
+        )?;
+        debug!(
+            "rtmp: {}: video_sample_entry_id={}",
+            self.stream.short_name, id
+        );
+
+        // The parameters changed, so the next recording must use the new sample entry.
+        self.close()?;
+        self.config = Some((config.to_owned(), id));
+        Ok(())
+    }
+
+    fn frame(
+        &mut self,
+        timestamp: u32,
+        composition_time: i32,
+        is_key: bool,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let now = self.server.db.clocks().monotonic();
+        let frame_realtime = now + self.realtime_offset;
+        self.tracker
+            .frame(now, recording::Time::new(frame_realtime), data.len());
+        let video_sample_entry_id = match self.config {
+            None => return Ok(()), // no sequence header yet.
+            Some((_, id)) => id,
+        };
+        if self.writer.is_none() && !is_key {
+            return Ok(());
+        }
+        let pts = (i64::from(timestamp) + i64::from(composition_time)) * 90;
+        let local_time = recording::Time::new(frame_realtime);
+        let stream = self.stream;
+        let server = self.server;
+        let db = &*server.db;
+        let w = self.writer.get_or_insert_with(|| {
+            let w = writer::Writer::new_striped(
+                stream.stripes.iter().map(|(d, c)| (d, c)).collect(),
+                db,
+                stream.stream_id,
+                video_sample_entry_id,
+            );
+            match stream.failover {
+                Some((ref d, ref c)) => w.with_failover(d, c),
+                None => w,
+            }
+        });
+        if let Some(r) = self.rotate {
+            if frame_realtime.sec > r && is_key {
+                w.close(Some(pts))?;
+                self.rotate = None;
+            }
+        }
+        if self.rotate.is_none() {
+            self.rotate = Some(streamer::rotation_time(
+                frame_realtime.sec,
+                stream.rotate_offset_sec,
+                streamer::ROTATE_INTERVAL_SEC,
+                w.previously_opened()?,
+            ));
+        }
+        w.write(data, local_time, pts, is_key)
+    }
+
+    /// Closes the current recording, if any, and the writer.
+    fn close(&mut self) -> Result<(), Error> {
+        if let Some(mut w) = self.writer.take() {
+            if self.rotate.take().is_some() {
+                w.close(None)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) {
+        if let Err(e) = self.close() {
+            warn!(
+                "rtmp: {}: unable to close recording: {}",
+                self.stream.short_name, e
+            );
+        }
+        self.tracker.disconnected();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publish_path() {
+        assert_eq!(
+            super::publish_path("rtmp://nvr.example.com:1935/live/s3cr3t").as_deref(),
+            Some("live/s3cr3t")
+        );
+        assert_eq!(super::publish_path("rtsp://camera/main"), None);
+    }
+
+    #[test]
+    fn amf_round_trip() {
+        let values = vec![
+            Amf::String("connect".to_owned()),
+            Amf::Number(1.),
+            Amf::Object(vec![
+                ("app".to_owned(), Amf::String("live".to_owned())),
+                ("fpad".to_owned(), Amf::Boolean(false)),
+            ]),
+            Amf::Null,
+            Amf::Undefined,
+            Amf::Array(vec![Amf::Number(2.)]),
+        ];
+        let mut buf = Vec::new();
+        for v in &values {
+            v.encode(&mut buf);
+        }
+        let mut p = &buf[..];
+        let mut decoded = Vec::new();
+        while !p.is_empty() {
+            decoded.push(Amf::decode(&mut p).unwrap());
+        }
+        assert_eq!(decoded, values);
+        assert_eq!(decoded[2].get("app").and_then(Amf::as_str), Some("live"));
+    }
+
+    #[test]
+    fn chunks() {
+        // A 200-byte video message split at the default chunk size of 128, then a message
+        // using a type 2 header (timestamp delta only), then one using a type 3 header.
+        let payload: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let mut buf = Vec::new();
+        write_message(&mut buf, 128, 6, MSG_VIDEO, 1, &payload).unwrap();
+        buf.extend_from_slice(&[0x86, 0, 0, 40]);
+        buf.extend_from_slice(&payload[..128]);
+        buf.push(0xc6);
+        buf.extend_from_slice(&payload[128..]);
+        buf.push(0xc6);
+        buf.extend_from_slice(&payload[..128]);
+        buf.push(0xc6);
+        buf.extend_from_slice(&payload[128..]);
+        let mut r = ChunkReader::default();
+        let mut p = &buf[..];
+        let timestamps: Vec<u32> = (0..3)
+            .map(|_| {
+                let m = r.read_message(&mut p).unwrap();
+                assert_eq!(m.type_id, MSG_VIDEO);
+                assert_eq!(m.stream_id, 1);
+                assert_eq!(m.payload, payload);
+                m.timestamp
+            })
+            .collect();
+        assert_eq!(timestamps, vec![0, 40, 80]);
+        assert!(p.is_empty());
+
+        // A continuation without a full header first is an error.
+        let mut r = ChunkReader::default();
+        r.read_message(&mut &[0xc7, 0][..]).unwrap_err();
+    }
+}
@@ -0,0 +1,1017 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! SAML 2.0 single sign-on, as a service provider.
+//!
+//! This supports SP-initiated login only: `GET /api/login/saml` redirects the browser to the
+//! identity provider with an `AuthnRequest` (HTTP-Redirect binding), and the identity provider
+//! posts the signed `Response` back to `POST /api/login/saml/acs` (HTTP-POST binding).
+//!
+//! The identity provider is configured through the `config` table:
+//!
+//! * `saml_idp_sso_url`: the IdP's single sign-on service URL. SAML is enabled iff this is set.
+//! * `saml_idp_certificate`: the IdP's signing certificate, as PEM or the base64 DER found in
+//!   its metadata.
+//! * `saml_sp_entity_id`: Moonfire NVR's entity id, as registered with the IdP.
+//! * `saml_acs_url`: the full external URL of `/api/login/saml/acs`.
+//! * `saml_username_attribute` (optional): the attribute holding the Moonfire NVR username.
+//!   If unset, the subject's `NameID` is used.
+//! * `saml_permissions_attribute` and `saml_permissions_map` (optional): if set, the session's
+//!   permissions are derived from the values of the given attribute rather than copied from the
+//!   user. The map is a `;`-separated list of `<attribute value>=<permission>,...`, such as
+//!   `nvr-viewers=view_video;nvr-admins=view_video,read_camera_configs,update_signals`.
+//!
+//! Either the `Response` or its single `Assertion` must carry a valid enveloped RSA-SHA256 or
+//! RSA-SHA512 signature, from a 2048- to 8192-bit key, with exclusive canonicalization. Encrypted assertions aren't supported.
+//! The XML parser here is deliberately minimal: it rejects DTDs and processing instructions
+//! rather than trying to canonicalize them.
+
+use base::strutil;
+use db::recording;
+use failure::{bail, format_err, Error};
+use parking_lot::Mutex;
+use rustls::internal::pemfile;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::Write;
+use url::form_urlencoded;
+
+const NS_SAMLP: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+const NS_SAML: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+const NS_DS: &str = "http://www.w3.org/2000/09/xmldsig#";
+const NS_XML: &str = "http://www.w3.org/XML/1998/namespace";
+const EXC_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+const ENVELOPED_SIGNATURE: &str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+const STATUS_SUCCESS: &str = "urn:oasis:names:tc:SAML:2.0:status:Success";
+const BEARER: &str = "urn:oasis:names:tc:SAML:2.0:cm:bearer";
+
+/// How long an `AuthnRequest` remains valid.
+const REQUEST_LIFETIME_SEC: i64 = 600;
+
+/// The maximum number of outstanding `AuthnRequest`s; the oldest are forgotten beyond this.
+const MAX_PENDING_REQUESTS: usize = 1000;
+
+/// Allowed clock skew between Moonfire NVR and the identity provider.
+const CLOCK_SKEW_SEC: i64 = 60;
+
+/// The maximum element nesting depth accepted by the XML parser.
+const MAX_DEPTH: usize = 64;
+
+struct Config {
+    idp_sso_url: String,
+    /// The DER-encoded certificate, as checked by `parse_certificate`.
+    idp_cert: Vec<u8>,
+    sp_entity_id: String,
+    acs_url: String,
+    username_attribute: Option<String>,
+    permissions_attribute: Option<String>,
+    permissions_map: Vec<(String, db::Permissions)>,
+}
+
+/// A SAML service provider, as configured in the database.
+pub struct ServiceProvider {
+    config: Config,
+
+    /// Outstanding `AuthnRequest` ids and their expiration times, oldest first.
+    pending: Mutex<VecDeque<(String, i64)>>,
+}
+
+/// A user authenticated by the identity provider.
+#[derive(Debug)]
+pub struct Login {
+    pub username: String,
+
+    /// Permissions derived from the permissions attribute, or `None` if no such attribute is
+    /// configured and the user's own permissions apply.
+    pub permissions: Option<db::Permissions>,
+}
+
+impl ServiceProvider {
+    /// Returns the configured service provider, or `None` if SAML isn't configured.
+    pub fn new(l: &db::LockedDatabase) -> Result<Option<Self>, Error> {
+        let idp_sso_url = match l.get_config("saml_idp_sso_url")? {
+            None => return Ok(None),
+            Some(u) => u,
+        };
+        let required = |key| {
+            l.get_config(key)?
+                .ok_or_else(|| format_err!("{} must be set when saml_idp_sso_url is", key))
+        };
+        let idp_cert = parse_certificate(&required("saml_idp_certificate")?)?;
+        let sp_entity_id = required("saml_sp_entity_id")?;
+        let acs_url = required("saml_acs_url")?;
+        let permissions_attribute = l.get_config("saml_permissions_attribute")?;
+        let permissions_map = match permissions_attribute {
+            None => Vec::new(),
+            Some(_) => parse_permissions_map(&required("saml_permissions_map")?)?,
+        };
+        Ok(Some(ServiceProvider {
+            config: Config {
+                idp_sso_url,
+                idp_cert,
+                sp_entity_id,
+                acs_url,
+                username_attribute: l.get_config("saml_username_attribute")?,
+                permissions_attribute,
+                permissions_map,
+            },
+            pending: Mutex::new(VecDeque::new()),
+        }))
+    }
+
+    /// Returns the identity provider URL to redirect to, recording the new request as pending.
+    /// `relay_state` is returned as-is with the response.
+    pub fn login_url(&self, relay_state: &str, now: time::Timespec) -> Result<String, Error> {
+        let mut raw_id = [0u8; 20];
+        base::crypto::rand_bytes(&mut raw_id)?;
+        let id = format!("_{}", strutil::hex(&raw_id));
+        let issue_instant = time::at_utc(now)
+            .strftime("%Y-%m-%dT%H:%M:%SZ")?
+            .to_string();
+        let request = format!(
+            "<samlp:AuthnRequest xmlns:samlp=\"{}\" xmlns:saml=\"{}\" ID=\"{}\" Version=\"2.0\" \
+             IssueInstant=\"{}\" Destination=\"{}\" AssertionConsumerServiceURL=\"{}\" \
+             ProtocolBinding=\"urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST\">\
+             <saml:Issuer>{}</saml:Issuer>\
+             </samlp:AuthnRequest>",
+            NS_SAMLP,
+            NS_SAML,
+            id,
+            issue_instant,
+            escape_attr(&self.config.idp_sso_url),
+            escape_attr(&self.config.acs_url),
+            escape_text(&self.config.sp_entity_id)
+        );
+        let mut e = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        e.write_all(request.as_bytes())?;
+        let request = base64::encode(&e.finish()?);
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("SAMLRequest", &request)
+            .append_pair("RelayState", relay_state)
+            .finish();
+        let sep = if self.config.idp_sso_url.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+
+        let mut pending = self.pending.lock();
+        while pending.len() >= MAX_PENDING_REQUESTS
+            || pending.front().map(|p| p.1 <= now.sec).unwrap_or(false)
+        {
+            pending.pop_front();
+        }
+        pending.push_back((id, now.sec + REQUEST_LIFETIME_SEC));
+        Ok(format!("{}{}{}", self.config.idp_sso_url, sep, query))
+    }
+
+    /// Verifies a base64-encoded `SAMLResponse` received at `now_sec`.
+    pub fn process_response(&self, encoded: &str, now_sec: i64) -> Result<Login, Error> {
+        let xml = base64::decode(&strip_whitespace(encoded))?;
+        let response = parse(&String::from_utf8(xml)?)?;
+        if !response.is(NS_SAMLP, "Response") {
+            bail!("expected samlp:Response; got {}", response.name);
+        }
+        let c = &self.config;
+        if let Some(d) = response.attr("Destination") {
+            if d != c.acs_url {
+                bail!("response has Destination {:?}; expected {:?}", d, c.acs_url);
+            }
+        }
+        let status = response
+            .child(NS_SAMLP, "Status")
+            .and_then(|s| s.child(NS_SAMLP, "StatusCode"))
+            .and_then(|s| s.attr("Value"));
+        if status != Some(STATUS_SUCCESS) {
+            bail!("identity provider returned status {:?}", status);
+        }
+        if response.child(NS_SAML, "EncryptedAssertion").is_some() {
+            bail!("encrypted assertions aren't supported");
+        }
+        let mut assertions = response.children().filter(|e| e.is(NS_SAML, "Assertion"));
+        let assertion = match (assertions.next(), assertions.next()) {
+            (Some(a), None) => a,
+            _ => bail!("expected exactly one assertion"),
+        };
+
+        // Check both signatures (if present) so an invalid one is never ignored.
+        let response_signed = verify_signature(&response, &c.idp_cert)?;
+        let assertion_signed = verify_signature(assertion, &c.idp_cert)?;
+        if !response_signed && !assertion_signed {
+            bail!("neither the response nor the assertion is signed");
+        }
+
+        if let Some(conditions) = assertion.child(NS_SAML, "Conditions") {
+            if let Some(t) = conditions.attr("NotBefore") {
+                if parse_instant(t)? > now_sec + CLOCK_SKEW_SEC {
+                    bail!("assertion is not valid until {}", t);
+                }
+            }
+            if let Some(t) = conditions.attr("NotOnOrAfter") {
+                if parse_instant(t)? <= now_sec - CLOCK_SKEW_SEC {
+                    bail!("assertion expired at {}", t);
+                }
+            }
+            for r in conditions
+                .children()
+                .filter(|e| e.is(NS_SAML, "AudienceRestriction"))
+            {
+                if !r
+                    .children()
+                    .any(|a| a.is(NS_SAML, "Audience") && a.text() == c.sp_entity_id)
+                {
+                    bail!("assertion is not intended for {:?}", c.sp_entity_id);
+                }
+            }
+        }
+
+        let subject = assertion
+            .child(NS_SAML, "Subject")
+            .ok_or_else(|| format_err!("assertion has no subject"))?;
+        let mut in_response_to = None;
+        for confirmation in subject
+            .children()
+            .filter(|e| e.is(NS_SAML, "SubjectConfirmation"))
+        {
+            if confirmation.attr("Method") != Some(BEARER) {
+                continue;
+            }
+            let data = match confirmation.child(NS_SAML, "SubjectConfirmationData") {
+                None => continue,
+                Some(d) => d,
+            };
+            if data.attr("Recipient") != Some(c.acs_url.as_str()) {
+                continue;
+            }
+            match data.attr("NotOnOrAfter") {
+                Some(t) if parse_instant(t)? > now_sec - CLOCK_SKEW_SEC => {}
+                _ => continue,
+            }
+            in_response_to = data.attr("InResponseTo");
+            if in_response_to.is_some() {
+                break;
+            }
+        }
+        let in_response_to = in_response_to
+            .ok_or_else(|| format_err!("assertion has no valid bearer subject confirmation"))?;
+        if let Some(r) = response.attr("InResponseTo") {
+            if r != in_response_to {
+                bail!("response and assertion have different InResponseTo");
+            }
+        }
+
+        let mut attributes: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for statement in assertion
+            .children()
+            .filter(|e| e.is(NS_SAML, "AttributeStatement"))
+        {
+            for a in statement.children().filter(|e| e.is(NS_SAML, "Attribute")) {
+                let name = a
+                    .attr("Name")
+                    .ok_or_else(|| format_err!("attribute has no Name"))?;
+                attributes.entry(name).or_default().extend(
+                    a.children()
+                        .filter(|e| e.is(NS_SAML, "AttributeValue"))
+                        .map(Element::text),
+                );
+            }
+        }
+        let username = match c.username_attribute {
+            None => subject
+                .child(NS_SAML, "NameID")
+                .map(Element::text)
+                .ok_or_else(|| format_err!("subject has no NameID"))?,
+            Some(ref a) => attributes
+                .get(a.as_str())
+                .and_then(|v| v.first())
+                .cloned()
+                .ok_or_else(|| format_err!("assertion has no {} attribute", a))?,
+        };
+        let permissions = c.permissions_attribute.as_ref().map(|a| {
+            let values = attributes.get(a.as_str()).map(|v| &v[..]).unwrap_or(&[]);
+            mapped_permissions(&c.permissions_map, values)
+        });
+
+        // Consume the request last, so that a response which fails the checks above can't
+        // prevent a legitimate response to the same request.
+        let mut pending = self.pending.lock();
+        let i = pending
+            .iter()
+            .position(|p| p.0 == in_response_to && p.1 > now_sec)
+            .ok_or_else(|| format_err!("response is to unknown or expired request"))?;
+        pending.remove(i);
+        Ok(Login {
+            username,
+            permissions,
+        })
+    }
+}
+
+fn parse_certificate(s: &str) -> Result<Vec<u8>, Error> {
+    let der = if s.contains("-----BEGIN") {
+        pemfile::certs(&mut s.as_bytes())
+            .map_err(|()| format_err!("unparseable saml_idp_certificate"))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| format_err!("no certificate in saml_idp_certificate"))?
+            .0
+    } else {
+        base64::decode(&strip_whitespace(s))?
+    };
+
+    // Check it now rather than failing on each login.
+    webpki::EndEntityCert::from(&der)
+        .map_err(|e| format_err!("bad saml_idp_certificate: {:?}", e))?;
+    Ok(der)
+}
+
+/// Parses `saml_permissions_map` (or `oidc_permissions_map`), as described in the module
+/// documentation.
+pub(crate) fn parse_permissions_map(s: &str) -> Result<Vec<(String, db::Permissions)>, Error> {
+    let mut out = Vec::new();
+    for entry in s.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let eq = entry
+            .rfind('=')
+            .ok_or_else(|| format_err!("permissions map entry {:?} has no =", entry))?;
+        let mut p = db::Permissions::new();
+        for name in entry[eq + 1..]
+            .split(',')
+            .map(str::trim)
+            .filter(|n| !n.is_empty())
+        {
+            match db::auth::permission_mut(&mut p, name) {
+                Some(b) => *b = true,
+                None => bail!("unknown permission {:?} in permissions map", name),
+            }
+        }
+        out.push((entry[..eq].trim().to_owned(), p));
+    }
+    Ok(out)
+}
+
+/// Returns the union of the permissions mapped from each of the given attribute values.
+pub(crate) fn mapped_permissions(
+    map: &[(String, db::Permissions)],
+    values: &[String],
+) -> db::Permissions {
+    let mut p = db::Permissions::new();
+    for (value, mapped) in map {
+        if values.contains(value) {
+            p.view_video |= mapped.view_video;
+            p.read_camera_configs |= mapped.read_camera_configs;
+            p.update_signals |= mapped.update_signals;
+            p.update_detections |= mapped.update_detections;
+            p.control_ptz |= mapped.control_ptz;
+            p.protect_recordings |= mapped.protect_recordings;
+            p.write_notes |= mapped.write_notes;
+            p.read_logs |= mapped.read_logs;
+            p.read_playback_heat |= mapped.read_playback_heat;
+            p.update_camera_configs |= mapped.update_camera_configs;
+            p.delete_recordings |= mapped.delete_recordings;
+            p.talkback |= mapped.talkback;
+            p.admin_users |= mapped.admin_users;
+            p.update_log_filter |= mapped.update_log_filter;
+        }
+    }
+    p
+}
+
+/// Parses an `xs:dateTime` in UTC, as used throughout SAML, into seconds since epoch.
+fn parse_instant(s: &str) -> Result<i64, Error> {
+    // recording::Time::parse doesn't understand decimal fractions of a second; drop them.
+    let trimmed = match (s.find('.'), s.rfind('Z')) {
+        (Some(dot), Some(z)) if dot < z => format!("{}Z", &s[..dot]),
+        _ => s.to_owned(),
+    };
+    if !trimmed.ends_with('Z') {
+        bail!("instant {:?} isn't in UTC", s);
+    }
+    Ok(recording::Time::parse(&trimmed)?.0 / recording::TIME_UNITS_PER_SEC)
+}
+
+fn strip_whitespace(s: &str) -> String {
+    s.chars().filter(|c| !c.is_ascii_whitespace()).collect()
+}
+
+/// A hash algorithm for a signature or reference digest.
+#[derive(Copy, Clone, Debug)]
+enum Algorithm {
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    fn for_uri(uri: Option<&str>) -> Result<Self, Error> {
+        Ok(match uri {
+            Some("http://www.w3.org/2001/04/xmlenc#sha256")
+            | Some("http://www.w3.org/2001/04/xmldsig-more#rsa-sha256") => Algorithm::Sha256,
+            Some("http://www.w3.org/2001/04/xmlenc#sha512")
+            | Some("http://www.w3.org/2001/04/xmldsig-more#rsa-sha512") => Algorithm::Sha512,
+            a => bail!("unsupported signature or digest algorithm {:?}", a),
+        })
+    }
+
+    fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Algorithm::Sha256 => base::crypto::sha256(data).to_vec(),
+            Algorithm::Sha512 => base::crypto::sha512(data).to_vec(),
+        }
+    }
+
+    /// Returns the RSA PKCS#1 v1.5 signature algorithm using this hash.
+    fn rsa(self) -> &'static webpki::SignatureAlgorithm {
+        match self {
+            Algorithm::Sha256 => &webpki::RSA_PKCS1_2048_8192_SHA256,
+            Algorithm::Sha512 => &webpki::RSA_PKCS1_2048_8192_SHA512,
+        }
+    }
+}
+
+/// Returns the `PrefixList` of an exclusive canonicalization's `InclusiveNamespaces`, if any.
+fn inclusive_prefixes(method: &Element) -> Vec<&str> {
+    method
+        .child(EXC_C14N, "InclusiveNamespaces")
+        .and_then(|n| n.attr("PrefixList"))
+        .map(|l| {
+            l.split_whitespace()
+                .map(|p| if p == "#default" { "" } else { p })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Verifies the enveloped signature of `e`, if any.
+///
+/// Returns `Ok(true)` if `e` has a valid signature, `Ok(false)` if it's unsigned, and an error
+/// if it has an invalid or unsupported signature. The signature must reference `e` itself (by
+/// its `ID`), so content elsewhere in the document can't be passed off as signed.
+fn verify_signature(e: &Element, cert: &[u8]) -> Result<bool, Error> {
+    let sig = match e.child(NS_DS, "Signature") {
+        None => return Ok(false),
+        Some(s) => s,
+    };
+    let id = e
+        .attr("ID")
+        .ok_or_else(|| format_err!("signed {} has no ID", e.name))?;
+    let signed_info = sig
+        .child(NS_DS, "SignedInfo")
+        .ok_or_else(|| format_err!("signature has no SignedInfo"))?;
+    let c14n_method = signed_info
+        .child(NS_DS, "CanonicalizationMethod")
+        .ok_or_else(|| format_err!("signature has no CanonicalizationMethod"))?;
+    if c14n_method.attr("Algorithm") != Some(EXC_C14N) {
+        bail!(
+            "unsupported canonicalization method {:?}",
+            c14n_method.attr("Algorithm")
+        );
+    }
+    let signature_alg = Algorithm::for_uri(
+        signed_info
+            .child(NS_DS, "SignatureMethod")
+            .and_then(|m| m.attr("Algorithm")),
+    )?;
+    let mut references = signed_info.children().filter(|c| c.is(NS_DS, "Reference"));
+    let reference = match (references.next(), references.next()) {
+        (Some(r), None) => r,
+        _ => bail!("expected exactly one signature reference"),
+    };
+    if reference.attr("URI") != Some(format!("#{}", id).as_str()) {
+        bail!("signature doesn't reference the signed element");
+    }
+    let mut inclusive = None;
+    for t in reference
+        .child(NS_DS, "Transforms")
+        .into_iter()
+        .flat_map(|t| t.children())
+    {
+        match t.attr("Algorithm") {
+            Some(ENVELOPED_SIGNATURE) => {}
+            Some(EXC_C14N) => inclusive = Some(inclusive_prefixes(t)),
+            a => bail!("unsupported transform {:?}", a),
+        }
+    }
+    let inclusive = inclusive.ok_or_else(|| format_err!("reference isn't canonicalized"))?;
+    let digest_alg = Algorithm::for_uri(
+        reference
+            .child(NS_DS, "DigestMethod")
+            .and_then(|m| m.attr("Algorithm")),
+    )?;
+    let expected_digest = base64::decode(&strip_whitespace(
+        &reference
+            .child(NS_DS, "DigestValue")
+            .ok_or_else(|| format_err!("reference has no DigestValue"))?
+            .text(),
+    ))?;
+    let mut canonical = String::new();
+    c14n(e, &BTreeMap::new(), &inclusive, Some(sig), &mut canonical);
+    let digest = digest_alg.digest(canonical.as_bytes());
+    if ring::constant_time::verify_slices_are_equal(&digest, &expected_digest).is_err() {
+        bail!("digest mismatch on {}", e.name);
+    }
+
+    let signature = base64::decode(&strip_whitespace(
+        &sig.child(NS_DS, "SignatureValue")
+            .ok_or_else(|| format_err!("signature has no SignatureValue"))?
+            .text(),
+    ))?;
+    let mut canonical = String::new();
+    c14n(
+        signed_info,
+        &BTreeMap::new(),
+        &inclusive_prefixes(c14n_method),
+        None,
+        &mut canonical,
+    );
+    let cert = webpki::EndEntityCert::from(cert)
+        .map_err(|e| format_err!("bad IdP certificate: {:?}", e))?;
+    if cert
+        .verify_signature(signature_alg.rsa(), canonical.as_bytes(), &signature)
+        .is_err()
+    {
+        bail!("bad signature on {}", e.name);
+    }
+    Ok(true)
+}
+
+/// A parsed XML element, retaining what's needed for canonicalization.
+#[derive(Debug)]
+struct Element {
+    /// The qualified name as written, such as `saml:Assertion`.
+    name: String,
+
+    /// Attributes other than namespace declarations, as (qualified name, normalized value).
+    attrs: Vec<(String, String)>,
+
+    /// All in-scope namespace bindings by prefix, with `""` for the default namespace.
+    namespaces: BTreeMap<String, String>,
+
+    children: Vec<Node>,
+}
+
+#[derive(Debug)]
+enum Node {
+    Element(Element),
+    Text(String),
+}
+
+fn split_qname(qname: &str) -> (&str, &str) {
+    match qname.find(':') {
+        Some(colon) => (&qname[..colon], &qname[colon + 1..]),
+        None => ("", qname),
+    }
+}
+
+impl Element {
+    /// Returns true iff this element has the given namespace and local name.
+    fn is(&self, ns: &str, local_name: &str) -> bool {
+        let (prefix, local) = split_qname(&self.name);
+        local == local_name
+            && self
+                .namespaces
+                .get(prefix)
+                .map(String::as_str)
+                .unwrap_or("")
+                == ns
+    }
+
+    /// Returns the value of the given unprefixed attribute.
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|a| a.0 == name)
+            .map(|a| a.1.as_str())
+    }
+
+    fn children(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|c| match c {
+            Node::Element(e) => Some(e),
+            Node::Text(_) => None,
+        })
+    }
+
+    /// Returns the first child element with the given namespace and local name.
+    fn child(&self, ns: &str, local_name: &str) -> Option<&Element> {
+        self.children().find(|e| e.is(ns, local_name))
+    }
+
+    /// Returns the concatenated text of this element's immediate children.
+    fn text(&self) -> String {
+        let mut out = String::new();
+        for c in &self.children {
+            if let Node::Text(t) = c {
+                out.push_str(t);
+            }
+        }
+        out
+    }
+}
+
+/// Parses a document, returning its root element.
+fn parse(xml: &str) -> Result<Element, Error> {
+    let xml = xml.replace("\r\n", "\n").replace('\r', "\n");
+    let mut p = Parser {
+        input: &xml,
+        pos: 0,
+    };
+    p.skip_misc()?;
+    let root = p.element(&BTreeMap::new(), 0)?;
+    p.skip_misc()?;
+    if p.pos != p.input.len() {
+        bail!("unexpected content after root element");
+    }
+    Ok(root)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        if self.rest().starts_with(s) {
+            self.pos += s.len();
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), Error> {
+        if !self.eat(s) {
+            bail!("expected {:?} at offset {}", s, self.pos);
+        }
+        Ok(())
+    }
+
+    /// Skips past the next occurrence of `end`, returning the text before it.
+    fn until(&mut self, end: &str) -> Result<&'a str, Error> {
+        let rest = self.rest();
+        let i = rest
+            .find(end)
+            .ok_or_else(|| format_err!("unterminated construct; expected {:?}", end))?;
+        self.pos += i + end.len();
+        Ok(&rest[..i])
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Skips whitespace, comments, and the XML declaration outside the root element.
+    fn skip_misc(&mut self) -> Result<(), Error> {
+        loop {
+            self.skip_whitespace();
+            if self.eat("<?") {
+                self.until("?>")?;
+            } else if self.eat("<!--") {
+                self.until("-->")?;
+            } else if self.rest().starts_with("<!") {
+                bail!("DTDs aren't supported");
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<&'a str, Error> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| c.is_ascii_whitespace() || c == '/' || c == '>' || c == '=')
+            .unwrap_or(rest.len());
+        if len == 0 {
+            bail!("expected name at offset {}", self.pos);
+        }
+        self.pos += len;
+        Ok(&rest[..len])
+    }
+
+    fn element(
+        &mut self,
+        scope: &BTreeMap<String, String>,
+        depth: usize,
+    ) -> Result<Element, Error> {
+        if depth > MAX_DEPTH {
+            bail!("elements nested too deeply");
+        }
+        self.expect("<")?;
+        let name = self.name()?;
+        let mut e = Element {
+            name: name.to_owned(),
+            attrs: Vec::new(),
+            namespaces: scope.clone(),
+            children: Vec::new(),
+        };
+        loop {
+            self.skip_whitespace();
+            if self.eat("/>") {
+                return Ok(e);
+            }
+            if self.eat(">") {
+                break;
+            }
+            let attr = self.name()?;
+            self.skip_whitespace();
+            self.expect("=")?;
+            self.skip_whitespace();
+            let quote = if self.eat("\"") {
+                "\""
+            } else {
+                self.expect("'")?;
+                "'"
+            };
+            let raw = self.until(quote)?;
+            if raw.contains('<') {
+                bail!("attribute {} contains <", attr);
+            }
+            let value = unescape(raw, true)?;
+            if attr == "xmlns" {
+                e.namespaces.insert(String::new(), value);
+            } else if attr.starts_with("xmlns:") {
+                e.namespaces
+                    .insert(attr["xmlns:".len()..].to_owned(), value);
+            } else if e.attrs.iter().any(|a| a.0 == attr) {
+                bail!("duplicate attribute {} on {}", attr, name);
+            } else {
+                e.attrs.push((attr.to_owned(), value));
+            }
+        }
+        loop {
+            if self.eat("</") {
+                let end = self.name()?;
+                if end != name {
+                    bail!("mismatched end tag {}; expected {}", end, name);
+                }
+                self.skip_whitespace();
+                self.expect(">")?;
+                return Ok(e);
+            } else if self.eat("<!--") {
+                self.until("-->")?;
+            } else if self.eat("<![CDATA[") {
+                let text = self.until("]]>")?;
+                e.children.push(Node::Text(text.to_owned()));
+            } else if self.rest().starts_with("<?") || self.rest().starts_with("<!") {
+                bail!("unsupported markup at offset {}", self.pos);
+            } else if self.rest().starts_with('<') {
+                let child = self.element(&e.namespaces, depth + 1)?;
+                e.children.push(Node::Element(child));
+            } else if self.rest().is_empty() {
+                bail!("unterminated element {}", name);
+            } else {
+                let rest = self.rest();
+                let len = rest.find('<').unwrap_or(rest.len());
+                self.pos += len;
+                e.children.push(Node::Text(unescape(&rest[..len], false)?));
+            }
+        }
+    }
+}
+
+/// Replaces entity and character references; in attributes, also normalizes whitespace.
+fn unescape(raw: &str, attr: bool) -> Result<String, Error> {
+    let mut out = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(i) = rest.find(|c: char| c == '&' || (attr && (c == '\t' || c == '\n'))) {
+        out.push_str(&rest[..i]);
+        if rest.as_bytes()[i] != b'&' {
+            out.push(' ');
+            rest = &rest[i + 1..];
+            continue;
+        }
+        let semi = rest[i..]
+            .find(';')
+            .ok_or_else(|| format_err!("unterminated reference"))?;
+        let name = &rest[i + 1..i + semi];
+        out.push(match name {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = if name.starts_with("#x") {
+                    u32::from_str_radix(&name[2..], 16).ok()
+                } else if name.starts_with('#') {
+                    name[1..].parse().ok()
+                } else {
+                    None
+                };
+                code.and_then(std::char::from_u32)
+                    .ok_or_else(|| format_err!("bad reference &{};", name))?
+            }
+        });
+        rest = &rest[i + semi + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn escape_text(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn escape_attr(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '"' => out.push_str("&quot;"),
+            '\t' => out.push_str("&#x9;"),
+            '\n' => out.push_str("&#xA;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Appends the [exclusive canonicalization](https://www.w3.org/TR/xml-exc-c14n/) (without
+/// comments) of `e` to `out`, omitting the descendant `skip`.
+///
+/// `rendered` holds the namespace declarations already output by ancestors; `inclusive` is the
+/// `InclusiveNamespaces` prefix list.
+fn c14n<'a>(
+    e: &'a Element,
+    rendered: &BTreeMap<&'a str, &'a str>,
+    inclusive: &[&str],
+    skip: Option<&Element>,
+    out: &mut String,
+) {
+    let (prefix, _) = split_qname(&e.name);
+    let mut utilized: BTreeSet<&str> = BTreeSet::new();
+    utilized.insert(prefix);
+    for (name, _) in &e.attrs {
+        let (p, _) = split_qname(name);
+        if !p.is_empty() && p != "xml" {
+            utilized.insert(p);
+        }
+    }
+    for &p in inclusive {
+        if let Some((p, _)) = e.namespaces.get_key_value(p) {
+            utilized.insert(p.as_str());
+        }
+    }
+
+    out.push('<');
+    out.push_str(&e.name);
+    let mut now_rendered = rendered.clone();
+    for p in utilized {
+        let uri = e.namespaces.get(p).map(String::as_str).unwrap_or("");
+        if rendered.get(p).cloned().unwrap_or("") == uri {
+            continue;
+        }
+        out.push_str(" xmlns");
+        if !p.is_empty() {
+            out.push(':');
+            out.push_str(p);
+        }
+        out.push_str("=\"");
+        out.push_str(&escape_attr(uri));
+        out.push('"');
+        now_rendered.insert(p, uri);
+    }
+
+    let mut attrs: Vec<(&str, &str, &str, &str)> = e
+        .attrs
+        .iter()
+        .map(|(name, value)| {
+            let (p, local) = split_qname(name);
+            let uri = match p {
+                "" => "",
+                "xml" => NS_XML,
+                p => e.namespaces.get(p).map(String::as_str).unwrap_or(""),
+            };
+            (uri, local, name.as_str(), value.as_str())
+        })
+        .collect();
+    attrs.sort();
+    for (_, _, name, value) in attrs {
+        out.push(' ');
+        out.push_str(name);
+        out.push_str("=\"");
+        out.push_str(&escape_attr(value));
+        out.push('"');
+    }
+    out.push('>');
+    for c in &e.children {
+        match c {
+            Node::Text(t) => out.push_str(&escape_text(t)),
+            Node::Element(c) if skip.map(|s| std::ptr::eq(s, c)).unwrap_or(false) => {}
+            Node::Element(c) => c14n(c, &now_rendered, inclusive, skip, out),
+        }
+    }
+    out.push_str("</");
+    out.push_str(&e.name);
+    out.push('>');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canonicalize(xml: &str, inclusive: &[&str]) -> String {
+        let e = parse(xml).unwrap();
+        let mut out = String::new();
+        c14n(&e, &BTreeMap::new(), inclusive, None, &mut out);
+        out
+    }
+
+    #[test]
+    fn c14n_basics() {
+        // Unused namespace declarations are dropped, attributes are sorted by namespace then
+        // local name, empty elements are expanded, and references are normalized.
+        assert_eq!(
+            canonicalize(
+                "<?xml version=\"1.0\"?>\r\n\
+                 <a:root xmlns:a=\"urn:a\" xmlns:b=\"urn:b\" xmlns:unused=\"urn:u\" z='1' \
+                 b:y=\"&#x41;\" x=\"&quot;\"><!-- comment --><a:child/>\
+                 <c xmlns=\"urn:c\">&gt;<![CDATA[<&]]></c></a:root>",
+                &[]
+            ),
+            "<a:root xmlns:a=\"urn:a\" xmlns:b=\"urn:b\" x=\"&quot;\" z=\"1\" b:y=\"A\">\
+             <a:child></a:child><c xmlns=\"urn:c\">&gt;&lt;&amp;</c></a:root>"
+        );
+    }
+
+    #[test]
+    fn c14n_inclusive_namespaces() {
+        let xml = "<r xmlns:xs=\"urn:xs\" xmlns:q=\"urn:q\"><v>xs:string</v></r>";
+        assert_eq!(canonicalize(xml, &[]), "<r><v>xs:string</v></r>");
+        assert_eq!(
+            canonicalize(xml, &["xs"]),
+            "<r xmlns:xs=\"urn:xs\"><v>xs:string</v></r>"
+        );
+    }
+
+    #[test]
+    fn rejects_dtd() {
+        parse("<!DOCTYPE r [<!ENTITY e \"x\">]><r>&e;</r>").unwrap_err();
+        parse("<r>&e;</r>").unwrap_err();
+        parse("<r><s></r>").unwrap_err();
+    }
+
+    #[test]
+    fn permissions_map() {
+        let map = parse_permissions_map(
+            "viewers=view_video; admins = view_video,read_camera_configs,update_signals",
+        )
+        .unwrap();
+        let p = mapped_permissions(&map, &["admins".to_owned()]);
+        assert!(p.view_video && p.read_camera_configs && p.update_signals);
+        assert!(!p.control_ptz);
+        let p = mapped_permissions(&map, &["other".to_owned()]);
+        assert!(!p.view_video);
+        parse_permissions_map("viewers=fly").unwrap_err();
+    }
+
+    #[test]
+    fn instants() {
+        assert_eq!(parse_instant("2020-05-01T00:00:00Z").unwrap(), 1588291200);
+        assert_eq!(
+            parse_instant("2020-05-01T00:00:01.123Z").unwrap(),
+            1588291201
+        );
+        parse_instant("2020-05-01T00:00:00").unwrap_err();
+    }
+}
@@ -0,0 +1,201 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Per-thread scheduling parameters (priority and CPU affinity).
+//!
+//! On small machines, the threads which receive and write video can compete for CPU with those
+//! which serve HTTP requests and those which analyze or transcode video. These parameters let the
+//! operator favor one over the others. They're applied by each thread to itself as it starts, and
+//! child processes inherit them from the thread which spawns them. Only Linux supports per-thread
+//! settings; elsewhere, `Params::apply` returns an error if any parameter is set.
+
+use failure::{bail, format_err, Error};
+use std::process::Command;
+use std::str::FromStr;
+use tracing::warn;
+
+/// A set of CPUs, as specified on the command line in a form such as `0-1,3`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CpuList(Vec<usize>);
+
+impl FromStr for CpuList {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut cpus = Vec::new();
+        for part in s.split(',') {
+            let part = part.trim();
+            let parse = |n: &str| {
+                usize::from_str(n).map_err(|_| format_err!("bad CPU number {:?} in {:?}", n, s))
+            };
+            match part.find('-') {
+                None => cpus.push(parse(part)?),
+                Some(dash) => {
+                    let (start, end) = (parse(&part[..dash])?, parse(&part[dash + 1..])?);
+                    if start > end {
+                        bail!("bad CPU range {:?} in {:?}", part, s);
+                    }
+                    cpus.extend(start..=end);
+                }
+            }
+        }
+        cpus.sort();
+        cpus.dedup();
+        Ok(CpuList(cpus))
+    }
+}
+
+/// Scheduling parameters for a class of threads.
+#[derive(Clone, Debug, Default)]
+pub struct Params {
+    /// The "nice" value, from -20 (highest priority) to 19 (lowest priority).
+    pub nice: Option<i32>,
+
+    /// The CPUs the thread may run on.
+    pub cpus: Option<CpuList>,
+}
+
+impl Params {
+    fn is_default(&self) -> bool {
+        self.nice.is_none() && self.cpus.is_none()
+    }
+
+    /// Applies these parameters to the calling thread.
+    #[cfg(target_os = "linux")]
+    pub fn apply(&self) -> Result<(), Error> {
+        if let Some(nice) = self.nice {
+            // On Linux, the "process" id given to setpriority may be a thread id, and the nice
+            // value applies only to that thread.
+            let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, nice) } != 0 {
+                bail!(
+                    "unable to set nice value {}: {}",
+                    nice,
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+        if let Some(ref cpus) = self.cpus {
+            let mut set = nix::sched::CpuSet::new();
+            for &cpu in &cpus.0 {
+                set.set(cpu)
+                    .map_err(|e| format_err!("unable to use CPU {}: {}", cpu, e))?;
+            }
+            nix::sched::sched_setaffinity(nix::unistd::Pid::from_raw(0), &set)
+                .map_err(|e| format_err!("unable to set CPU affinity {:?}: {}", cpus.0, e))?;
+        }
+        Ok(())
+    }
+
+    /// Applies these parameters to the calling thread.
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply(&self) -> Result<(), Error> {
+        if !self.is_default() {
+            bail!("thread scheduling parameters are only supported on Linux");
+        }
+        Ok(())
+    }
+
+    /// Applies these parameters to the calling thread, logging rather than returning any error.
+    /// Intended for use as the first thing in a newly spawned thread.
+    pub fn apply_or_warn(&self) {
+        if self.is_default() {
+            return;
+        }
+        if let Err(e) = self.apply() {
+            warn!("unable to apply scheduling parameters {:?}: {}", self, e);
+        }
+    }
+
+    /// Arranges for the process spawned by `cmd` to start with these parameters, rather than
+    /// those it would inherit from the calling thread. As with `apply_or_warn`, problems don't
+    /// prevent the spawn: bad CPUs are logged now, and failures in the child are ignored, as it
+    /// can't safely log between `fork` and `exec`.
+    #[cfg(target_os = "linux")]
+    pub fn apply_to_command(&self, cmd: &mut Command) {
+        use std::os::unix::process::CommandExt;
+        if self.is_default() {
+            return;
+        }
+        let nice = self.nice;
+        let mut cpus = None;
+        if let Some(ref c) = self.cpus {
+            let mut set = nix::sched::CpuSet::new();
+            for &cpu in &c.0 {
+                if let Err(e) = set.set(cpu) {
+                    warn!("unable to use CPU {}: {}", cpu, e);
+                }
+            }
+            cpus = Some(set);
+        }
+
+        // Only async-signal-safe calls are allowed in the child, so everything which might
+        // allocate happens above.
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(nice) = nice {
+                    libc::setpriority(libc::PRIO_PROCESS, 0, nice);
+                }
+                if let Some(ref set) = cpus {
+                    let _ = nix::sched::sched_setaffinity(nix::unistd::Pid::from_raw(0), set);
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Arranges for the process spawned by `cmd` to start with these parameters.
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply_to_command(&self, _cmd: &mut Command) {
+        if !self.is_default() {
+            warn!("thread scheduling parameters are only supported on Linux");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuList;
+    use std::str::FromStr;
+
+    #[test]
+    fn parse_cpu_list() {
+        assert_eq!(CpuList::from_str("0").unwrap(), CpuList(vec![0]));
+        assert_eq!(CpuList::from_str("3,0-1").unwrap(), CpuList(vec![0, 1, 3]));
+        assert_eq!(
+            CpuList::from_str("1-2, 2-3").unwrap(),
+            CpuList(vec![1, 2, 3])
+        );
+        CpuList::from_str("").unwrap_err();
+        CpuList::from_str("2-1").unwrap_err();
+        CpuList::from_str("a").unwrap_err();
+        CpuList::from_str("0-").unwrap_err();
+    }
+}
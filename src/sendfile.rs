@@ -0,0 +1,279 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Zero-copy serving of sample file data via `sendfile(2)`.
+//!
+//! hyper writes response bodies only through `AsyncWrite`, so it can't be asked to send a range
+//! of a file. Instead, video sample data is `mmap()`ed as before and each mapping is registered
+//! here along with the file it maps. `TcpStream` wraps accepted connections; when hyper asks it
+//! to write a buffer which lies within a registered mapping, it `sendfile()`s the equivalent
+//! range of the file instead. The mapped pages are never touched in userspace, so they're
+//! neither copied nor faulted in by the serving threads.
+//!
+//! As the mapping holds the same bytes as the file, anything not recognized here (the
+//! dynamically generated mp4 boxes, encrypted or pool-read sample data, or a socket which isn't
+//! currently writable) falls back to an ordinary write with the same result.
+
+use bytes::Buf;
+use futures::stream;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::io::{self, IoSlice};
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::warn;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// Registered mappings by start address.
+    static ref MAPPINGS: Mutex<BTreeMap<usize, Region>> = Mutex::new(BTreeMap::new());
+}
+
+#[derive(Copy, Clone)]
+struct Region {
+    end: usize,
+    fd: RawFd,
+    offset: u64,
+}
+
+/// Enables registration of new `Mapping`s, for use by connections accepted via `incoming`.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// A read-only `mmap()` of part of a file, registered for `sendfile()` if enabled.
+pub struct Mapping {
+    mmap: memmap::Mmap,
+    registered: bool,
+
+    /// Keeps the fd in `MAPPINGS` open; dropped after `drop` unregisters it.
+    _file: std::fs::File,
+}
+
+impl Mapping {
+    pub fn new(file: std::fs::File, offset: u64, len: usize) -> Result<Self, io::Error> {
+        let mmap = unsafe {
+            memmap::MmapOptions::new()
+                .offset(offset)
+                .len(len)
+                .map(&file)?
+        };
+        let registered = ENABLED.load(Ordering::Relaxed);
+        if registered {
+            let start = mmap.as_ptr() as usize;
+            MAPPINGS.lock().insert(
+                start,
+                Region {
+                    end: start + len,
+                    fd: file.as_raw_fd(),
+                    offset,
+                },
+            );
+        }
+        Ok(Mapping {
+            mmap,
+            registered,
+            _file: file,
+        })
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.registered {
+            MAPPINGS.lock().remove(&(self.mmap.as_ptr() as usize));
+        }
+    }
+}
+
+impl Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.mmap[..]
+    }
+}
+
+/// Returns the file range backing `s`, if it lies within a registered mapping: the fd, the
+/// offset, and the length, which may be shorter than `s` if it runs past the mapping.
+fn lookup(s: &[u8]) -> Option<(RawFd, u64, usize)> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let addr = s.as_ptr() as usize;
+    let l = MAPPINGS.lock();
+    let (&start, r) = l.range(..=addr).next_back()?;
+    if addr >= r.end {
+        return None;
+    }
+    Some((
+        r.fd,
+        r.offset + (addr - start) as u64,
+        std::cmp::min(s.len(), r.end - addr),
+    ))
+}
+
+/// A TCP connection which writes registered mappings with `sendfile()`.
+///
+/// The HTTP server always accepts connections as these; until `enable` is called, they behave
+/// as plain `tokio::net::TcpStream`s.
+pub struct TcpStream(tokio::net::TcpStream);
+
+impl AsyncRead for TcpStream {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [MaybeUninit<u8>]) -> bool {
+        self.0.prepare_uninitialized_buffer(buf)
+    }
+
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+
+    fn poll_write_buf<B: Buf>(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut B,
+    ) -> Poll<io::Result<usize>> {
+        // Find the length of ordinary memory before the first file-backed slice, or that slice.
+        let mut plain_len = 0;
+        let mut file = None;
+        {
+            let mut slices = [IoSlice::new(&[]); 64];
+            let n = buf.bytes_vectored(&mut slices);
+            for s in &slices[..n] {
+                if let Some(f) = lookup(s) {
+                    if plain_len == 0 {
+                        file = Some(f);
+                    }
+                    break;
+                }
+                plain_len += s.len();
+            }
+        }
+        if let Some((fd, offset, len)) = file {
+            let mut off = offset as libc::off_t;
+            let ret = unsafe { libc::sendfile(self.0.as_raw_fd(), fd, &mut off, len) };
+            if ret >= 0 {
+                buf.advance(ret as usize);
+                return Poll::Ready(Ok(ret as usize));
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::WouldBlock && e.kind() != io::ErrorKind::Interrupted {
+                return Poll::Ready(Err(e));
+            }
+
+            // The socket is full (or the call was interrupted). Write the mapped bytes instead, which registers for a wakeup
+            // when it becomes writable (or writes them, if it has drained in the meantime).
+            plain_len = len;
+        }
+        if plain_len == 0 {
+            return Pin::new(&mut self.0).poll_write_buf(cx, buf);
+        }
+        Pin::new(&mut self.0).poll_write_buf(cx, &mut (&mut *buf).take(plain_len))
+    }
+}
+
+/// Accepts connections from `listener` as `TcpStream`s, for use with `hyper::Server::builder`.
+///
+/// Like hyper's own `AddrIncoming`, this logs and retries accept errors (such as running out of
+/// file descriptors) after a pause rather than stopping the server.
+pub fn incoming(
+    listener: tokio::net::TcpListener,
+) -> impl hyper::server::accept::Accept<Conn = TcpStream, Error = io::Error> {
+    hyper::server::accept::from_stream(stream::unfold(listener, |mut l| async move {
+        loop {
+            match l.accept().await {
+                Ok((s, _)) => {
+                    if let Err(e) = s.set_nodelay(true) {
+                        warn!("Unable to set TCP_NODELAY: {}", e);
+                    }
+                    return Some((Ok::<_, io::Error>(TcpStream(s)), l));
+                }
+                Err(e) => {
+                    warn!("Accept error: {}; pausing", e);
+                    tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn lookup_mapping() {
+        enable();
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let p = tmpdir.path().join("f");
+        std::fs::File::create(&p)
+            .unwrap()
+            .write_all(&[0u8; 8192][..])
+            .unwrap();
+        let m = Mapping::new(std::fs::File::open(&p).unwrap(), 4096, 4096).unwrap();
+        let fd = m._file.as_raw_fd();
+        assert_eq!(lookup(&m[..]), Some((fd, 4096, 4096)));
+        assert_eq!(lookup(&m[100..200]), Some((fd, 4196, 100)));
+        assert_eq!(lookup(&[0u8; 16][..]), None);
+        let addr = m.as_ptr() as usize;
+        drop(m);
+        assert!(MAPPINGS.lock().get(&addr).is_none());
+    }
+}
@@ -29,13 +29,17 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::h264;
+use crate::sched;
 use cstr::*;
-use failure::{bail, Error};
+use failure::{bail, format_err, Error};
 use ffmpeg;
 use lazy_static::lazy_static;
-use log::{debug, info, warn};
 use std::ffi::CString;
+use std::os::unix::io::AsRawFd;
+use std::process::{Child, Command, Stdio};
 use std::result::Result;
+use tracing::{debug, info, warn};
+use url::Url;
 
 static START: parking_lot::Once = parking_lot::Once::new();
 
@@ -44,12 +48,213 @@ lazy_static! {
 }
 
 pub enum Source<'a> {
-    /// A filename, for testing.
-    #[cfg(test)]
+    /// A local file, for `moonfire-nvr import` and testing. Its timestamps are rescaled to
+    /// 90 kHz if necessary.
     File(&'a str),
 
     /// An RTSP stream, for production use.
-    Rtsp { url: &'a str, redacted_url: &'a str },
+    /// `input_options` are extra ffmpeg options, as accepted by `db::parse_input_options`.
+    /// `rtsp` supplies the transport and session timeout.
+    Rtsp {
+        url: &'a str,
+        redacted_url: &'a str,
+        input_options: &'a str,
+        rtsp: db::RtspPolicy,
+    },
+
+    /// An RTSP stream transcoded through the given ffmpeg video filter, for virtual streams.
+    /// `input_options` and `rtsp` apply to the transcoder's input; `sched` to the transcoder.
+    Transcode {
+        url: &'a str,
+        redacted_url: &'a str,
+        filter: &'a str,
+        input_options: &'a str,
+        rtsp: db::RtspPolicy,
+        sched: &'a sched::Params,
+    },
+
+    /// A local V4L2 capture device, encoded to H.264 as the device specifies.
+    /// `input_options` apply to the capture device, such as to select its `video_size`; `sched`
+    /// to the transcoder.
+    V4l2 {
+        device: &'a V4l2Device,
+        input_options: &'a str,
+        sched: &'a sched::Params,
+    },
+
+    /// An SRT (Secure Reliable Transport) stream carrying MPEG-TS, either from a remote caller
+    /// or listener depending on the URL's `mode`. `passphrase` decrypts it, if set.
+    /// `input_options` are as for `Rtsp`; `rtsp` supplies the timeouts.
+    Srt {
+        url: &'a str,
+        redacted_url: &'a str,
+        passphrase: Option<&'a str>,
+        input_options: &'a str,
+        rtsp: db::RtspPolicy,
+    },
+
+    /// An HTTP MJPEG camera, transcoded to H.264 with the given encoder.
+    /// `input_options` apply to the transcoder's input; `sched` to the transcoder.
+    Mjpeg {
+        url: &'a str,
+        redacted_url: &'a str,
+        encode: Encode,
+        input_options: &'a str,
+        sched: &'a sched::Params,
+    },
+}
+
+/// How to get H.264 video from a source which isn't already recorded as received.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Encode {
+    /// The source produces H.264 itself (as many USB cameras do with `input_format=h264`); record
+    /// it as is.
+    Copy,
+
+    /// Encode with the hardware encoder exposed through V4L2's memory-to-memory interface, as on
+    /// the Raspberry Pi.
+    Hardware,
+
+    /// Encode with Cisco's openh264, for ffmpeg builds without libx264.
+    OpenH264,
+
+    /// Encode with libx264. This is the default, as it works with any source, but it's CPU
+    /// intensive.
+    Software,
+}
+
+impl Encode {
+    fn parse(s: &str) -> Result<Self, Error> {
+        Ok(match s {
+            "copy" => Encode::Copy,
+            "hardware" => Encode::Hardware,
+            "openh264" => Encode::OpenH264,
+            "software" => Encode::Software,
+            _ => bail!(
+                "bad encode {:?}; expected copy, hardware, openh264, or software",
+                s
+            ),
+        })
+    }
+
+    /// Returns the `ffmpeg` output arguments which produce H.264 this way.
+    fn args(self) -> Vec<&'static str> {
+        let mut args = Vec::new();
+        match self {
+            // The source decides when to send key frames.
+            Encode::Copy => return vec!["-c:v", "copy"],
+            Encode::Hardware => args.extend_from_slice(&["-c:v", "h264_v4l2m2m"]),
+            Encode::OpenH264 => args.extend_from_slice(&["-c:v", "libopenh264"]),
+            Encode::Software => args.extend_from_slice(&[
+                "-c:v",
+                "libx264",
+                "-preset",
+                "veryfast",
+                "-tune",
+                "zerolatency",
+            ]),
+        }
+        args.extend_from_slice(&["-pix_fmt", "yuv420p"]);
+
+        // A key frame every two seconds, so recordings can rotate on time.
+        args.extend_from_slice(&["-force_key_frames", "expr:gte(t,n_forced*2)"]);
+        args
+    }
+}
+
+/// A local V4L2 capture device, named by a stream URL such as
+/// `v4l2:///dev/video0?encode=hardware`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct V4l2Device {
+    pub path: String,
+    pub encode: Encode,
+}
+
+impl V4l2Device {
+    /// Parses a `v4l2:` URL, returning `None` if the URL has another scheme.
+    pub fn parse(url: &Url) -> Result<Option<Self>, Error> {
+        if url.scheme() != "v4l2" {
+            return Ok(None);
+        }
+        let path = url.path();
+        if !path.starts_with("/dev/") || path.contains("..") {
+            bail!("V4L2 URL {} doesn't name a device under /dev", url);
+        }
+        let mut encode = Encode::Software;
+        for (k, v) in url.query_pairs() {
+            match &*k {
+                "encode" => encode = Encode::parse(&v)?,
+                _ => bail!("unknown V4L2 URL parameter {:?}", k),
+            }
+        }
+        Ok(Some(V4l2Device {
+            path: path.to_owned(),
+            encode,
+        }))
+    }
+}
+
+/// An SRT stream, named by a stream URL such as `srt://0.0.0.0:9000?mode=listener`. The camera's
+/// password, if any, is the passphrase; its username is unused.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Srt {
+    pub passphrase: Option<String>,
+}
+
+impl Srt {
+    /// Parses an `srt:` URL, returning `None` if the URL has another scheme.
+    pub fn parse(url: &Url, password: &str) -> Result<Option<Self>, Error> {
+        if url.scheme() != "srt" {
+            return Ok(None);
+        }
+        if url.password().is_some() || url.query_pairs().any(|(k, _)| k == "passphrase") {
+            bail!(
+                "SRT URL {} shouldn't have a passphrase; set the camera's password",
+                url
+            );
+        }
+        if password.is_empty() {
+            return Ok(Some(Srt { passphrase: None }));
+        }
+
+        // The protocol requires this length.
+        if password.len() < 10 || password.len() > 79 {
+            bail!(
+                "SRT passphrase must be 10 to 79 characters, not {}",
+                password.len()
+            );
+        }
+        Ok(Some(Srt {
+            passphrase: Some(password.to_owned()),
+        }))
+    }
+}
+
+/// An HTTP MJPEG camera, named by a stream URL such as `http://camera/video.mjpg`. The URL's
+/// fragment, which isn't sent to the camera, may choose the encoder, as in `#encode=openh264`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mjpeg {
+    pub encode: Encode,
+}
+
+impl Mjpeg {
+    /// Parses an `http:` or `https:` URL, returning `None` if the URL has another scheme.
+    pub fn parse(url: &Url) -> Result<Option<Self>, Error> {
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Ok(None);
+        }
+        let mut encode = Encode::Software;
+        for (k, v) in url::form_urlencoded::parse(url.fragment().unwrap_or("").as_bytes()) {
+            match &*k {
+                "encode" => encode = Encode::parse(&v)?,
+                _ => bail!("unknown MJPEG URL parameter {:?}", k),
+            }
+        }
+        if encode == Encode::Copy {
+            bail!("MJPEG video must be encoded to H.264; encode=copy isn't possible");
+        }
+        Ok(Some(Mjpeg { encode }))
+    }
 }
 
 pub trait Opener<S: Stream>: Sync {
@@ -59,6 +264,18 @@ pub trait Opener<S: Stream>: Sync {
 pub trait Stream {
     fn get_extra_data(&self) -> Result<h264::ExtraData, Error>;
     fn get_next<'p>(&'p mut self) -> Result<ffmpeg::Packet<'p>, ffmpeg::Error>;
+
+    /// Returns the clock rate of the packets' pts if they're the camera's RTP timestamps, as
+    /// opposed to ones assigned by a transcoder or file.
+    fn rtp_clock_rate(&self) -> Option<i32> {
+        None
+    }
+
+    /// Returns the ffmpeg arguments of the encoder which produced this stream's video, if it
+    /// was encoded by Moonfire NVR rather than the camera.
+    fn encoder(&self) -> Option<&str> {
+        None
+    }
 }
 
 pub struct Ffmpeg {}
@@ -77,8 +294,17 @@ impl Ffmpeg {
 impl Opener<FfmpegStream> for Ffmpeg {
     fn open(&self, src: Source) -> Result<FfmpegStream, Error> {
         use ffmpeg::InputFormatContext;
-        let (mut input, discard_first) = match src {
-            #[cfg(test)]
+        let rtp = if let Source::Rtsp { .. } = src {
+            true
+        } else {
+            false
+        };
+        let file = if let Source::File(_) = src {
+            true
+        } else {
+            false
+        };
+        let (mut input, discard_first, transcoder) = match src {
             Source::File(filename) => {
                 let mut open_options = ffmpeg::Dictionary::new();
 
@@ -97,19 +323,26 @@ impl Opener<FfmpegStream> for Ffmpeg {
                         url, open_options
                     );
                 }
-                (i, false)
+                (i, false, None)
             }
-            Source::Rtsp { url, redacted_url } => {
+            Source::Rtsp {
+                url,
+                redacted_url,
+                input_options,
+                rtsp,
+            } => {
                 let mut open_options = ffmpeg::Dictionary::new();
                 open_options
-                    .set(cstr!("rtsp_transport"), cstr!("tcp"))
+                    .set(
+                        cstr!("rtsp_transport"),
+                        &CString::new(rtsp.transport.as_str()).unwrap(),
+                    )
                     .unwrap();
                 open_options
                     .set(cstr!("user-agent"), cstr!("moonfire-nvr"))
                     .unwrap();
-                // 10-second socket timeout, in microseconds.
                 open_options
-                    .set(cstr!("stimeout"), cstr!("10000000"))
+                    .set(cstr!("stimeout"), &CString::new(stimeout(&rtsp)).unwrap())
                     .unwrap();
 
                 // Moonfire NVR currently only supports video, so receiving audio is wasteful.
@@ -118,6 +351,13 @@ impl Opener<FfmpegStream> for Ffmpeg {
                     .set(cstr!("allowed_media_types"), cstr!("video"))
                     .unwrap();
 
+                // The stream's own options override the defaults above.
+                for (name, value) in db::parse_input_options(input_options)? {
+                    open_options
+                        .set(&CString::new(name).unwrap(), &CString::new(value).unwrap())
+                        .unwrap();
+                }
+
                 let i = InputFormatContext::open(&CString::new(url).unwrap(), &mut open_options)?;
                 if !open_options.empty() {
                     warn!(
@@ -125,7 +365,89 @@ impl Opener<FfmpegStream> for Ffmpeg {
                         redacted_url, open_options
                     );
                 }
-                (i, true)
+                (i, true, None)
+            }
+            Source::Srt {
+                url,
+                redacted_url,
+                passphrase,
+                input_options,
+                rtsp,
+            } => {
+                let mut open_options = ffmpeg::Dictionary::new();
+
+                // Give up on a silent peer, and when listening, wake up periodically so the
+                // streamer can notice shutdown.
+                let timeout = CString::new(stimeout(&rtsp)).unwrap();
+                open_options.set(cstr!("timeout"), &timeout).unwrap();
+                open_options.set(cstr!("listen_timeout"), &timeout).unwrap();
+                if let Some(p) = passphrase {
+                    open_options
+                        .set(cstr!("passphrase"), &CString::new(p).unwrap())
+                        .unwrap();
+                }
+
+                // The stream's own options (such as `latency`) override the defaults above.
+                for (name, value) in db::parse_input_options(input_options)? {
+                    open_options
+                        .set(&CString::new(name).unwrap(), &CString::new(value).unwrap())
+                        .unwrap();
+                }
+
+                let i = InputFormatContext::open(&CString::new(url).unwrap(), &mut open_options)?;
+                if !open_options.empty() {
+                    warn!(
+                        "While opening URL {}, some options were not understood: {}",
+                        redacted_url, open_options
+                    );
+                }
+                (i, false, None)
+            }
+            Source::Transcode {
+                url,
+                redacted_url,
+                filter,
+                input_options,
+                rtsp,
+                sched,
+            } => {
+                info!("Transcoding {} with filter {}", redacted_url, filter);
+                let t = Transcoder::rtsp(
+                    url,
+                    filter,
+                    &rtsp,
+                    &db::parse_input_options(input_options)?,
+                    sched,
+                )?;
+                (t.open()?, false, Some(t))
+            }
+            Source::V4l2 {
+                device,
+                input_options,
+                sched,
+            } => {
+                info!("Capturing {} with encode {:?}", device.path, device.encode);
+                let t = Transcoder::v4l2(device, &db::parse_input_options(input_options)?, sched)?;
+                (t.open()?, false, Some(t))
+            }
+            Source::Mjpeg {
+                url,
+                redacted_url,
+                encode,
+                input_options,
+                sched,
+            } => {
+                info!(
+                    "Transcoding MJPEG {} with encode {:?}",
+                    redacted_url, encode
+                );
+                let t = Transcoder::mjpeg(
+                    url,
+                    encode,
+                    &db::parse_input_options(input_options)?,
+                    sched,
+                )?;
+                (t.open()?, false, Some(t))
             }
         };
 
@@ -147,8 +469,21 @@ impl Opener<FfmpegStream> for Ffmpeg {
             Some(i) => i,
             None => bail!("no video stream"),
         };
+        let tb = input.streams().get(video_i).time_base();
+        let rescale = if file && (tb.num != 1 || tb.den != 90000) {
+            debug!("Rescaling timestamps from time base {}/{}", tb.num, tb.den);
+            Some((tb.num, tb.den))
+        } else {
+            None
+        };
 
-        let mut stream = FfmpegStream { input, video_i };
+        let mut stream = FfmpegStream {
+            input,
+            video_i,
+            rtp,
+            rescale,
+            transcoder,
+        };
 
         if discard_first {
             info!("Discarding the first packet to work around https://trac.ffmpeg.org/ticket/5018");
@@ -159,16 +494,158 @@ impl Opener<FfmpegStream> for Ffmpeg {
     }
 }
 
+/// Returns ffmpeg's `stimeout` option value for the policy's session timeout, in microseconds.
+fn stimeout(rtsp: &db::RtspPolicy) -> String {
+    (rtsp.session_timeout_sec * 1_000_000).to_string()
+}
+
 pub struct FfmpegStream {
     input: ffmpeg::InputFormatContext,
     video_i: usize,
+
+    /// True iff `input` is an RTSP session, whose pts are RTP timestamps.
+    rtp: bool,
+
+    /// The video stream's time base as `(num, den)`, if it's a file whose pts and durations
+    /// `get_next` rescales to 90 kHz.
+    rescale: Option<(i32, i32)>,
+
+    /// The subprocess feeding `input`, if any. Declared after `input` so it's dropped after.
+    transcoder: Option<Transcoder>,
+}
+
+/// An `ffmpeg` subprocess which produces H.264 video, writing it as MPEG-TS to its standard
+/// output. It crops and scales an RTSP stream (for virtual streams), captures from a V4L2
+/// device, or transcodes an MJPEG camera. This is a separate process (rather than more of the
+/// ffmpeg library in this one) so that a crashing or stuck encoder only affects its own stream.
+/// It's killed when dropped.
+///
+/// Note a URL, including any credentials, is visible in the subprocess's command line.
+struct Transcoder {
+    child: Child,
+
+    /// The encoder's arguments, or `None` if the video is copied as is.
+    encoder: Option<String>,
+}
+
+impl Transcoder {
+    fn rtsp(
+        url: &str,
+        filter: &str,
+        rtsp: &db::RtspPolicy,
+        input_options: &[(&str, &str)],
+        sched: &sched::Params,
+    ) -> Result<Self, Error> {
+        let stimeout = stimeout(rtsp);
+        Transcoder::spawn(
+            &[
+                "-rtsp_transport",
+                rtsp.transport.as_str(),
+                "-stimeout",
+                stimeout.as_str(),
+                "-allowed_media_types",
+                "video",
+            ],
+            input_options,
+            url,
+            Some(filter),
+            Encode::Software,
+            sched,
+        )
+    }
+
+    fn v4l2(
+        device: &V4l2Device,
+        input_options: &[(&str, &str)],
+        sched: &sched::Params,
+    ) -> Result<Self, Error> {
+        Transcoder::spawn(
+            &["-f", "v4l2"],
+            input_options,
+            &device.path,
+            None,
+            device.encode,
+            sched,
+        )
+    }
+
+    fn mjpeg(
+        url: &str,
+        encode: Encode,
+        input_options: &[(&str, &str)],
+        sched: &sched::Params,
+    ) -> Result<Self, Error> {
+        // MJPEG has no timestamps of its own.
+        Transcoder::spawn(
+            &["-use_wallclock_as_timestamps", "1"],
+            input_options,
+            url,
+            None,
+            encode,
+            sched,
+        )
+    }
+
+    fn spawn(
+        input_args: &[&str],
+        input_options: &[(&str, &str)],
+        input: &str,
+        filter: Option<&str>,
+        encode: Encode,
+        sched: &sched::Params,
+    ) -> Result<Self, Error> {
+        let mut cmd = Command::new("ffmpeg");
+        sched.apply_to_command(&mut cmd);
+        cmd.args(&["-nostdin", "-hide_banner", "-loglevel", "error"])
+            .args(input_args);
+
+        // As with ffmpeg's command line parsing in general, later options override earlier ones.
+        for &(name, value) in input_options {
+            cmd.arg(format!("-{}", name)).arg(value);
+        }
+        cmd.args(&["-i", input, "-an"]);
+        if let Some(f) = filter {
+            cmd.args(&["-vf", f]);
+        }
+        let encode_args = encode.args();
+        let child = cmd
+            .args(&encode_args)
+            .args(&["-f", "mpegts", "pipe:1"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format_err!("unable to run ffmpeg: {}", e))?;
+        let encoder = match encode {
+            Encode::Copy => None,
+            _ => Some(encode_args.join(" ")),
+        };
+        Ok(Transcoder { child, encoder })
+    }
+
+    /// Opens the subprocess's output.
+    fn open(&self) -> Result<ffmpeg::InputFormatContext, Error> {
+        let fd = self.child.stdout.as_ref().unwrap().as_raw_fd();
+        let pipe = format!("pipe:{}", fd);
+        let mut open_options = ffmpeg::Dictionary::new();
+        Ok(ffmpeg::InputFormatContext::open(
+            &CString::new(pipe).unwrap(),
+            &mut open_options,
+        )?)
+    }
+}
+
+impl Drop for Transcoder {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
 }
 
 impl Stream for FfmpegStream {
     fn get_extra_data(&self) -> Result<h264::ExtraData, Error> {
         let video = self.input.streams().get(self.video_i);
         let tb = video.time_base();
-        if tb.num != 1 || tb.den != 90000 {
+        if self.rescale.is_none() && (tb.num != 1 || tb.den != 90000) {
             bail!(
                 "video stream has timebase {}/{}; expected 1/90000",
                 tb.num,
@@ -187,12 +664,32 @@ impl Stream for FfmpegStream {
         )
     }
 
+    /// `get_extra_data` ensures the time base is 1/90000, matching the RTP clock rate of H.264.
+    fn encoder(&self) -> Option<&str> {
+        self.transcoder.as_ref().and_then(|t| t.encoder.as_deref())
+    }
+
+    fn rtp_clock_rate(&self) -> Option<i32> {
+        if self.rtp {
+            Some(90000)
+        } else {
+            None
+        }
+    }
+
     fn get_next<'i>(&'i mut self) -> Result<ffmpeg::Packet<'i>, ffmpeg::Error> {
         loop {
-            let p = self.input.read_frame()?;
-            if p.stream_index() == self.video_i {
-                return Ok(p);
+            let mut p = self.input.read_frame()?;
+            if p.stream_index() != self.video_i {
+                continue;
+            }
+            if let Some((num, den)) = self.rescale {
+                let to_90k =
+                    |v: i64| (i128::from(v) * 90000 * i128::from(num) / i128::from(den)) as i64;
+                p.set_pts(p.pts().map(to_90k));
+                p.set_duration(to_90k(i64::from(p.duration())) as i32);
             }
+            return Ok(p);
         }
     }
 }
@@ -28,20 +28,54 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use crate::events;
 use crate::h264;
+use crate::health;
+use crate::sched;
 use crate::stream;
+use crate::thumbnail;
 use base::clock::{Clocks, TimerGuard};
-use db::{dir, recording, writer, Camera, Database, Stream};
+use db::{dir, recording, writer, Camera, CompositeId, Database, Stream};
 use failure::{bail, format_err, Error};
-use log::{debug, info, trace, warn};
+use std::collections::VecDeque;
 use std::result::Result;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
+use std::thread;
 use time;
+use tracing::{debug, info, trace, warn};
 use url::Url;
 
 pub static ROTATE_INTERVAL_SEC: i64 = 60;
 
+/// How long to pause a stream after its sample file directory filled.
+const DISK_FULL_RETRY_SEC: i64 = 60;
+
+/// How long symptoms of another client connected to the camera count toward a warning.
+const MULTI_HOMED_WINDOW_SEC: i64 = 3600;
+
+/// The number of one kind of symptom within `MULTI_HOMED_WINDOW_SEC` which triggers a warning.
+const MULTI_HOMED_THRESHOLD: usize = 3;
+
+/// The number of recent key frame intervals from which to learn the camera's usual interval.
+const KEY_FRAME_INTERVALS: usize = 16;
+
+/// Substrings of errors (as reported by ffmpeg) indicating the camera ended the RTSP session.
+const SESSION_ENDED_ERRORS: &[&str] = &[
+    // Such as 453 Not Enough Bandwidth or 454 Session Not Found.
+    "4XX Client Error",
+    // Such as 503 Service Unavailable, which some cameras return at their client limit.
+    "5XX Server Error",
+    // The camera closed the connection.
+    "End of file",
+];
+
+/// A sample file directory and the syncer for it.
+pub type DirAndSyncer = (
+    Arc<dir::SampleFileDir>,
+    writer::SyncerChannel<dir::SampleFileWriter>,
+);
+
 /// Common state that can be used by multiple `Streamer` instances.
 pub struct Environment<'a, 'b, C, S>
 where
@@ -51,6 +85,18 @@ where
     pub opener: &'a dyn stream::Opener<S>,
     pub db: &'b Arc<Database<C>>,
     pub shutdown: &'b Arc<AtomicBool>,
+
+    /// Where each streamer reports its stream's liveness.
+    pub health: &'b health::Monitor,
+
+    /// Where each streamer announces its stream going online and offline.
+    pub events: &'b events::Bus,
+
+    /// The width of recordings' thumbnails, or `None` not to generate them.
+    pub thumbnail_width: Option<u32>,
+
+    /// Scheduling parameters for thumbnail threads and transcoder subprocesses.
+    pub analytics_sched: &'b sched::Params,
 }
 
 pub struct Streamer<'a, C, S>
@@ -64,13 +110,151 @@ where
     rotate_offset_sec: i64,
     rotate_interval_sec: i64,
     db: Arc<Database<C>>,
-    dir: Arc<dir::SampleFileDir>,
-    syncer_channel: writer::SyncerChannel<::std::fs::File>,
+
+    /// The stream's sample file directories and their syncers, as in `Stream::dir_ids`.
+    stripes: Vec<DirAndSyncer>,
+
+    /// The stream's failover directory and its syncer, as in
+    /// `Stream::failover_sample_file_dir_id`.
+    failover: Option<DirAndSyncer>,
     opener: &'a dyn stream::Opener<S>,
     stream_id: i32,
+    camera_id: i32,
     short_name: String,
     url: Url,
     redacted_url: Url,
+
+    /// For a virtual stream, the ffmpeg video filter which produces it from `url`.
+    filter: Option<String>,
+
+    /// For a local capture device, the device named by `url`.
+    v4l2: Option<stream::V4l2Device>,
+
+    /// For an HTTP MJPEG camera, how to encode its video.
+    mjpeg: Option<stream::Mjpeg>,
+
+    /// For an SRT stream, its passphrase.
+    srt: Option<stream::Srt>,
+
+    /// The stream's extra ffmpeg input options, as in `Stream::input_options`.
+    input_options: String,
+
+    /// How to open and reopen the session, as in `Stream::rtsp`.
+    rtsp: db::RtspPolicy,
+
+    /// The number of consecutive sessions which failed before receiving a key frame.
+    failures: u32,
+
+    thumbnail_width: Option<u32>,
+    analytics_sched: sched::Params,
+    multi_homed: MultiHomedDetector,
+    health: health::Tracker,
+    events: events::Bus,
+
+    /// True iff the last `events` sent for this stream was `cameraOnline`.
+    online: bool,
+}
+
+/// Watches for symptoms of another client (such as a second NVR) connected to the same camera:
+/// key frames well ahead of the camera's usual interval, as when another client's connection
+/// makes the encoder restart, and the camera ending sessions.
+#[derive(Default)]
+struct MultiHomedDetector {
+    last_key_pts: Option<i64>,
+    key_intervals: VecDeque<i64>,
+
+    /// Times (in seconds since epoch) of early key frames and ended sessions within the window.
+    early_key_frames: VecDeque<i64>,
+    ended_sessions: VecDeque<i64>,
+
+    warning: Option<String>,
+}
+
+impl MultiHomedDetector {
+    /// Notes the start of a session, which restarts the pts sequence.
+    fn new_session(&mut self) {
+        self.last_key_pts = None;
+    }
+
+    /// Notes a key frame. Returns the new warning if it changed.
+    fn key_frame(&mut self, pts: i64, now_sec: i64) -> Option<Option<String>> {
+        if let Some(prev) = self.last_key_pts.replace(pts) {
+            let interval = pts - prev;
+            if interval > 0 {
+                if self.key_intervals.len() >= KEY_FRAME_INTERVALS / 2 {
+                    let mut sorted: Vec<i64> = self.key_intervals.iter().cloned().collect();
+                    sorted.sort_unstable();
+                    if interval < sorted[sorted.len() / 2] / 2 {
+                        self.early_key_frames.push_back(now_sec);
+                    }
+                }
+                if self.key_intervals.len() == KEY_FRAME_INTERVALS {
+                    self.key_intervals.pop_front();
+                }
+                self.key_intervals.push_back(interval);
+            }
+        }
+        self.update(now_sec)
+    }
+
+    /// Notes a session which ended with the given error. Returns the new warning if it changed.
+    fn session_error(&mut self, e: &str, now_sec: i64) -> Option<Option<String>> {
+        if SESSION_ENDED_ERRORS.iter().any(|s| e.contains(s)) {
+            self.ended_sessions.push_back(now_sec);
+        }
+        self.update(now_sec)
+    }
+
+    fn update(&mut self, now_sec: i64) -> Option<Option<String>> {
+        for q in [&mut self.early_key_frames, &mut self.ended_sessions].iter_mut() {
+            while q.front().map(|&t| t <= now_sec - MULTI_HOMED_WINDOW_SEC) == Some(true) {
+                q.pop_front();
+            }
+        }
+        let mut symptoms = Vec::new();
+        if self.early_key_frames.len() >= MULTI_HOMED_THRESHOLD {
+            symptoms.push(format!(
+                "{} unexpected key frames",
+                self.early_key_frames.len()
+            ));
+        }
+        if self.ended_sessions.len() >= MULTI_HOMED_THRESHOLD {
+            symptoms.push(format!(
+                "{} sessions ended by the camera",
+                self.ended_sessions.len()
+            ));
+        }
+        let warning = if symptoms.is_empty() {
+            None
+        } else {
+            Some(format!("{} in the last hour", symptoms.join(" and ")))
+        };
+        if warning == self.warning {
+            return None;
+        }
+        self.warning = warning.clone();
+        Some(warning)
+    }
+}
+
+/// Returns when (in seconds since epoch) a recording started at `sec` should end.
+pub fn rotation_time(
+    sec: i64,
+    rotate_offset_sec: i64,
+    rotate_interval_sec: i64,
+    previously_opened: bool,
+) -> i64 {
+    let r = sec - (sec % rotate_interval_sec) + rotate_offset_sec;
+    let r = r + if r <= sec { rotate_interval_sec } else { 0 };
+
+    // On the first recording, set rotate time to not the next rotate offset, but the one after,
+    // so that it's longer than usual rather than shorter than usual.  This ensures there's
+    // plenty of frame times to use when calculating the start time.
+    r + if previously_opened {
+        0
+    } else {
+        rotate_interval_sec
+    }
 }
 
 impl<'a, C, S> Streamer<'a, C, S>
@@ -78,23 +262,56 @@ where
     C: 'a + Clocks + Clone,
     S: 'a + stream::Stream,
 {
+    /// Creates a streamer for stream `s` of camera `c`. If `s` is a virtual stream, `source`
+    /// should be the camera and stream it's transcoded from.
     pub fn new<'b>(
         env: &Environment<'a, 'b, C, S>,
-        dir: Arc<dir::SampleFileDir>,
-        syncer_channel: writer::SyncerChannel<::std::fs::File>,
+        stripes: Vec<DirAndSyncer>,
+        failover: Option<DirAndSyncer>,
         stream_id: i32,
         c: &Camera,
         s: &Stream,
+        source: Option<(&Camera, &Stream)>,
         rotate_offset_sec: i64,
         rotate_interval_sec: i64,
     ) -> Result<Self, Error> {
-        let mut url = Url::parse(&s.rtsp_url)?;
+        let (src_c, src_s, filter) = match s.virtual_source {
+            None => (c, s, None),
+            Some(v) => match source {
+                Some((src_c, src_s)) if src_s.id == v.source_stream_id => (
+                    src_c,
+                    src_s,
+                    Some(format!(
+                        "crop={}:{}:{}:{},scale={}:{}",
+                        v.crop_width, v.crop_height, v.crop_x, v.crop_y, v.width, v.height
+                    )),
+                ),
+                _ => bail!(
+                    "missing source stream {} of virtual stream {}",
+                    v.source_stream_id,
+                    stream_id
+                ),
+            },
+        };
+        let mut url = Url::parse(&src_s.rtsp_url)?;
+        let v4l2 = stream::V4l2Device::parse(&url)?;
+        let mjpeg = stream::Mjpeg::parse(&url)?;
+        let srt = stream::Srt::parse(&url, &src_c.password)?;
+        if (v4l2.is_some() || mjpeg.is_some() || srt.is_some()) && filter.is_some() {
+            bail!(
+                "virtual stream {} can only be transcoded from an RTSP stream",
+                stream_id
+            );
+        }
+
+        // An MJPEG URL's fragment is for Moonfire NVR, not the camera.
+        url.set_fragment(None);
         let mut redacted_url = url.clone();
-        if !c.username.is_empty() {
-            url.set_username(&c.username)
+        if v4l2.is_none() && srt.is_none() && !src_c.username.is_empty() {
+            url.set_username(&src_c.username)
                 .map_err(|_| format_err!("can't set username"))?;
-            redacted_url.set_username(&c.username).unwrap();
-            url.set_password(Some(&c.password)).unwrap();
+            redacted_url.set_username(&src_c.username).unwrap();
+            url.set_password(Some(&src_c.password)).unwrap();
             redacted_url.set_password(Some("redacted")).unwrap();
         }
         Ok(Streamer {
@@ -102,13 +319,27 @@ where
             rotate_offset_sec: rotate_offset_sec,
             rotate_interval_sec: rotate_interval_sec,
             db: env.db.clone(),
-            dir,
-            syncer_channel: syncer_channel,
+            stripes,
+            failover,
             opener: env.opener,
             stream_id: stream_id,
+            camera_id: c.id,
             short_name: format!("{}-{}", c.short_name, s.type_.as_str()),
             url,
             redacted_url,
+            filter,
+            v4l2,
+            mjpeg,
+            srt,
+            input_options: s.input_options.clone(),
+            rtsp: s.rtsp,
+            failures: 0,
+            thumbnail_width: env.thumbnail_width,
+            analytics_sched: env.analytics_sched.clone(),
+            multi_homed: MultiHomedDetector::default(),
+            health: env.health.tracker(stream_id, env.db.clocks().monotonic()),
+            events: env.events.clone(),
+            online: false,
         })
     }
 
@@ -116,41 +347,160 @@ where
         &self.short_name
     }
 
+    fn save_thumbnail(&self, id: CompositeId, result: Result<Vec<u8>, Error>) {
+        let r = result.and_then(|jpeg| self.db.lock().set_thumbnail(id, jpeg));
+        if let Err(e) = r {
+            warn!(
+                "{}: unable to save thumbnail of recording {}: {}",
+                self.short_name, id, e
+            );
+        }
+    }
+
+    /// Raises or clears the stream's `multi_homed` warning.
+    fn set_multi_homed(&self, warning: Option<String>) {
+        match warning {
+            Some(ref w) => warn!(
+                "{}: camera may also be connected to another client, such as a second NVR: {}",
+                self.short_name, w
+            ),
+            None => info!(
+                "{}: no recent symptoms of another client connected to the camera",
+                self.short_name
+            ),
+        }
+        if let Err(e) = self.db.lock().set_multi_homed(self.stream_id, warning) {
+            warn!(
+                "{}: unable to set multi-homed warning: {}",
+                self.short_name, e
+            );
+        }
+    }
+
+    /// Announces the stream going online or offline, if it's a change.
+    fn set_online(&mut self, online: bool) {
+        if self.online != online {
+            self.online = online;
+            self.events
+                .stream_status(&self.db.lock(), self.stream_id, online);
+        }
+    }
+
     pub fn run(&mut self) {
+        let span = tracing::info_span!("stream", id = self.stream_id);
+        let _enter = span.enter();
         while !self.shutdown.load(Ordering::SeqCst) {
-            if let Err(e) = self.run_once() {
-                let sleep_time = time::Duration::seconds(1);
+            let r = self.run_once();
+            self.health.disconnected();
+            self.set_online(false);
+            if let Err(e) = r {
+                let now_sec = self.db.clocks().realtime().sec;
+                if let Some(w) = self.multi_homed.session_error(&e.to_string(), now_sec) {
+                    self.set_multi_homed(w);
+                }
+                // When paused for lack of disk space, there's no point in retrying quickly.
+                let disk_full = self
+                    .db
+                    .lock()
+                    .streams_by_id()
+                    .get(&self.stream_id)
+                    .map(|s| s.disk_full)
+                    .unwrap_or(false);
+                self.failures += 1;
+                let sleep_time = time::Duration::seconds(if disk_full {
+                    DISK_FULL_RETRY_SEC
+                } else {
+                    self.rtsp.reconnect_delay_sec(self.failures)
+                });
                 warn!(
                     "{}: sleeping for {:?} after error: {:?}",
                     self.short_name, sleep_time, e
                 );
-                self.db.clocks().sleep(sleep_time);
+                self.sleep_unless_shutdown(sleep_time);
             }
         }
         info!("{}: shutting down", self.short_name);
     }
 
+    /// Sleeps for `d` in one-second steps, returning early if shutdown is requested, so that a
+    /// long retry delay doesn't hold up shutdown.
+    fn sleep_unless_shutdown(&self, d: time::Duration) {
+        let step = time::Duration::seconds(1);
+        let mut remaining = d;
+        while remaining > time::Duration::zero() && !self.shutdown.load(Ordering::SeqCst) {
+            let s = std::cmp::min(step, remaining);
+            self.db.clocks().sleep(s);
+            remaining = remaining - s;
+        }
+    }
+
+    /// Returns the source to open, according to the stream's URL and virtual source.
+    fn source(&self) -> stream::Source {
+        let url = self.url.as_str();
+        let redacted_url = self.redacted_url.as_str();
+        let input_options = &self.input_options;
+        if let Some(ref device) = self.v4l2 {
+            return stream::Source::V4l2 {
+                device,
+                input_options,
+                sched: &self.analytics_sched,
+            };
+        }
+        if let Some(ref mjpeg) = self.mjpeg {
+            return stream::Source::Mjpeg {
+                url,
+                redacted_url,
+                encode: mjpeg.encode,
+                input_options,
+                sched: &self.analytics_sched,
+            };
+        }
+        if let Some(ref srt) = self.srt {
+            return stream::Source::Srt {
+                url,
+                redacted_url,
+                passphrase: srt.passphrase.as_deref(),
+                input_options,
+                rtsp: self.rtsp,
+            };
+        }
+        match self.filter {
+            None => stream::Source::Rtsp {
+                url,
+                redacted_url,
+                input_options,
+                rtsp: self.rtsp,
+            },
+            Some(ref filter) => stream::Source::Transcode {
+                url,
+                redacted_url,
+                filter,
+                input_options,
+                rtsp: self.rtsp,
+                sched: &self.analytics_sched,
+            },
+        }
+    }
+
     fn run_once(&mut self) -> Result<(), Error> {
         info!("{}: Opening input: {}", self.short_name, self.redacted_url);
         let clocks = self.db.clocks();
 
         let mut stream = {
             let _t = TimerGuard::new(&clocks, || format!("opening {}", self.redacted_url));
-            self.opener.open(stream::Source::Rtsp {
-                url: self.url.as_str(),
-                redacted_url: self.redacted_url.as_str(),
-            })?
+            self.opener.open(self.source())?
         };
         let realtime_offset = self.db.clocks().realtime() - clocks.monotonic();
         // TODO: verify width/height.
         let extra_data = stream.get_extra_data()?;
         let video_sample_entry_id = {
             let _t = TimerGuard::new(&clocks, || "inserting video sample entry");
-            self.db.lock().insert_video_sample_entry(
+            self.db.lock().insert_encoded_video_sample_entry(
                 extra_data.width,
                 extra_data.height,
-                extra_data.sample_entry,
-                extra_data.rfc6381_codec,
+                extra_data.sample_entry.clone(),
+                extra_data.rfc6381_codec.clone(),
+                stream.encoder().map(str::to_owned),
             )?
         };
         debug!(
@@ -158,31 +508,88 @@ where
             self.short_name, video_sample_entry_id
         );
         let mut seen_key_frame = false;
+        let mut private = false;
+        let mut privacy_checked_sec = None;
+        self.multi_homed.new_session();
+        self.health.connected();
+        self.set_online(true);
+
+        // Thumbnails are generated on another thread, which sends them back to be saved. At most
+        // one is in progress at a time, so a stuck ffmpeg doesn't pile up threads.
+        let (thumbnail_snd, thumbnail_rcv) = mpsc::channel();
+        let mut thumbnail_in_progress = false;
+        let mut thumbnailed_id = None;
 
         // Seconds since epoch at which to next rotate.
         let mut rotate: Option<i64> = None;
         let mut transformed = Vec::new();
-        let mut w = writer::Writer::new(
-            &self.dir,
+        let mut w = writer::Writer::new_striped(
+            self.stripes.iter().map(|(d, c)| (d, c)).collect(),
             &self.db,
-            &self.syncer_channel,
             self.stream_id,
             video_sample_entry_id,
-        );
+        )
+        .with_rtp_clock_rate(stream.rtp_clock_rate());
+        if let Some((d, c)) = &self.failover {
+            w = w.with_failover(d, c);
+        }
         while !self.shutdown.load(Ordering::SeqCst) {
             let pkt = {
                 let _t = TimerGuard::new(&clocks, || "getting next packet");
                 stream.get_next()?
             };
+            while let Ok((id, result)) = thumbnail_rcv.try_recv() {
+                thumbnail_in_progress = false;
+                self.save_thumbnail(id, result);
+            }
             let pts = pkt.pts().ok_or_else(|| format_err!("packet with no pts"))?;
+            let arrival = clocks.monotonic();
+            self.health.frame(
+                arrival,
+                recording::Time::new(arrival + realtime_offset),
+                pkt.data().map(|d| d.len()).unwrap_or(0),
+            );
+
+            // Check privacy mode at most once a second. While private, end any recording in
+            // progress and discard frames; recording resumes at the next key frame afterward.
+            let arrival_realtime = arrival + realtime_offset;
+            if privacy_checked_sec != Some(arrival_realtime.sec) {
+                privacy_checked_sec = Some(arrival_realtime.sec);
+                let p = self
+                    .db
+                    .lock()
+                    .privacy_at(self.camera_id, recording::Time::new(arrival_realtime));
+                if p != private {
+                    info!(
+                        "{}: privacy mode {}",
+                        self.short_name,
+                        if p { "on" } else { "off" }
+                    );
+                    private = p;
+                }
+            }
+            if private {
+                if rotate.take().is_some() {
+                    let _t = TimerGuard::new(&clocks, || "closing writer");
+                    w.close(Some(pts))?;
+                }
+                seen_key_frame = false;
+                continue;
+            }
             if !seen_key_frame && !pkt.is_key() {
                 continue;
             } else if !seen_key_frame {
                 debug!("{}: have first key frame", self.short_name);
                 seen_key_frame = true;
+                self.failures = 0;
             }
             let frame_realtime = clocks.monotonic() + realtime_offset;
             let local_time = recording::Time::new(frame_realtime);
+            if pkt.is_key() {
+                if let Some(w) = self.multi_homed.key_frame(pts, frame_realtime.sec) {
+                    self.set_multi_homed(w);
+                }
+            }
             rotate = if let Some(r) = rotate {
                 if frame_realtime.sec > r && pkt.is_key() {
                     trace!("{}: write on normal rotation", self.short_name);
@@ -198,23 +605,12 @@ where
             let r = match rotate {
                 Some(r) => r,
                 None => {
-                    let sec = frame_realtime.sec;
-                    let r = sec - (sec % self.rotate_interval_sec) + self.rotate_offset_sec;
-                    let r = r + if r <= sec {
-                        self.rotate_interval_sec
-                    } else {
-                        0
-                    };
-
-                    // On the first recording, set rotate time to not the next rotate offset, but
-                    // the one after, so that it's longer than usual rather than shorter than
-                    // usual.  This ensures there's plenty of frame times to use when calculating
-                    // the start time.
-                    let r = r + if w.previously_opened()? {
-                        0
-                    } else {
-                        self.rotate_interval_sec
-                    };
+                    let r = rotation_time(
+                        frame_realtime.sec,
+                        self.rotate_offset_sec,
+                        self.rotate_interval_sec,
+                        w.previously_opened()?,
+                    );
                     let _t = TimerGuard::new(&clocks, || "creating writer");
                     r
                 }
@@ -234,6 +630,31 @@ where
             });
             w.write(transformed_data, local_time, pts, pkt.is_key())?;
             rotate = Some(r);
+            if let (Some(width), Some(id)) = (self.thumbnail_width, w.recording_id()) {
+                if pkt.is_key() && thumbnailed_id != Some(id) && !thumbnail_in_progress {
+                    thumbnailed_id = Some(id);
+                    thumbnail_in_progress = true;
+                    let snd = thumbnail_snd.clone();
+                    let sample_entry = extra_data.sample_entry.clone();
+                    let key_frame = transformed_data.to_vec();
+                    let sched = self.analytics_sched.clone();
+                    let mask = self
+                        .db
+                        .lock()
+                        .streams_by_id()
+                        .get(&self.stream_id)
+                        .map(|s| s.privacy_mask.clone())
+                        .unwrap_or_default();
+                    thread::Builder::new()
+                        .name(format!("t-{}", self.short_name))
+                        .spawn(move || {
+                            sched.apply_or_warn();
+                            let result =
+                                thumbnail::generate(&sample_entry, &key_frame, width, &mask);
+                            let _ = snd.send((id, result));
+                        })?;
+                }
+            }
         }
         if rotate.is_some() {
             let _t = TimerGuard::new(&clocks, || "closing writer");
@@ -250,12 +671,12 @@ mod tests {
     use base::clock::{self, Clocks};
     use db::{recording, testutil, CompositeId};
     use failure::{bail, Error};
-    use log::trace;
     use parking_lot::Mutex;
     use std::cmp;
     use std::sync::atomic::{AtomicBool, Ordering};
     use std::sync::Arc;
     use time;
+    use tracing::trace;
 
     struct ProxyingStream<'a> {
         clocks: &'a clock::SimulatedClocks,
@@ -340,7 +761,13 @@ mod tests {
         fn open(&self, src: stream::Source) -> Result<ProxyingStream<'a>, Error> {
             match src {
                 stream::Source::Rtsp { url, .. } => assert_eq!(url, &self.expected_url),
-                stream::Source::File(_) => panic!("expected rtsp url"),
+                stream::Source::File(_)
+                | stream::Source::Transcode { .. }
+                | stream::Source::V4l2 { .. }
+                | stream::Source::Mjpeg { .. }
+                | stream::Source::Srt { .. } => {
+                    panic!("expected rtsp url")
+                }
             };
             let mut l = self.streams.lock();
             match l.pop() {
@@ -380,6 +807,64 @@ mod tests {
         .unwrap()
     }
 
+    #[test]
+    fn multi_homed_detector() {
+        let mut d = super::MultiHomedDetector::default();
+        let mut pts = 0;
+        let mut sec = 1_600_000_000;
+
+        // Learn the usual interval of 2 seconds.
+        for _ in 0..super::KEY_FRAME_INTERVALS {
+            pts += 180_000;
+            sec += 2;
+            assert_eq!(d.key_frame(pts, sec), None);
+        }
+
+        // Occasional early key frames and ended sessions aren't enough for a warning.
+        pts += 9_000;
+        assert_eq!(d.key_frame(pts, sec), None);
+        assert_eq!(d.session_error("End of file", sec), None);
+        assert_eq!(d.session_error("Connection refused", sec), None);
+        assert_eq!(d.session_error("End of file", sec), None);
+        d.new_session();
+        assert_eq!(d.key_frame(0, sec), None); // no interval across sessions.
+        pts = 0;
+
+        // But repeated ones are.
+        pts += 180_000;
+        sec += 2;
+        assert_eq!(d.key_frame(pts, sec), None);
+        pts += 9_000;
+        sec += 1;
+        assert_eq!(d.key_frame(pts, sec), None);
+        assert_eq!(
+            d.session_error("Server returned 4XX Client Error", sec),
+            Some(Some(
+                "3 sessions ended by the camera in the last hour".to_owned()
+            ))
+        );
+        d.new_session();
+        pts = 0;
+        assert_eq!(d.key_frame(pts, sec), None);
+        pts += 180_000;
+        sec += 2;
+        assert_eq!(d.key_frame(pts, sec), None);
+        pts += 9_000;
+        sec += 1;
+        assert_eq!(
+            d.key_frame(pts, sec),
+            Some(Some(
+                "3 unexpected key frames and 3 sessions ended by the camera in the last hour"
+                    .to_owned()
+            ))
+        );
+
+        // The warning clears once the symptoms are an hour old.
+        sec += super::MULTI_HOMED_WINDOW_SEC;
+        pts += 180_000;
+        assert_eq!(d.key_frame(pts, sec), Some(None));
+    }
+
     #[test]
     fn basic() {
         testutil::init();
@@ -404,6 +889,10 @@ mod tests {
             opener: &opener,
             db: &db.db,
             shutdown: &opener.shutdown,
+            health: &Default::default(),
+            events: &Default::default(),
+            thumbnail_width: None,
+            analytics_sched: &Default::default(),
         };
         let mut stream;
         {
@@ -414,14 +903,16 @@ mod tests {
                 .dirs_by_stream_id
                 .get(&testutil::TEST_STREAM_ID)
                 .unwrap()
+                .stripes[0]
                 .clone();
             stream = super::Streamer::new(
                 &env,
-                dir,
-                db.syncer_channel.clone(),
+                vec![(dir, db.syncer_channel.clone())],
+                None,
                 testutil::TEST_STREAM_ID,
                 camera,
                 s,
+                None,
                 0,
                 3,
             )
@@ -0,0 +1,150 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Restarts streamers after panics, so a bug triggered by one stream doesn't stop the others
+//! recording.
+//!
+//! Each streamer runs in its own thread under a `Supervisor`. When the streamer panics, its
+//! in-progress recording is abandoned (see `db::writer::Writer`'s `Drop` impl), the failure is
+//! logged and sent as a `streamerFailed` event, and after a delay the stream's pipeline is
+//! recreated from the database's current configuration. The delay starts at `MIN_BACKOFF_SEC`
+//! and doubles with each consecutive failure up to `MAX_BACKOFF_SEC`; a run lasting
+//! `HEALTHY_RUN_SEC` resets it.
+
+use crate::events;
+use base::clock::Clocks;
+use db::Database;
+use failure::Error;
+use std::any::Any;
+use std::cmp;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use time::Duration;
+use tracing::error;
+
+const MIN_BACKOFF_SEC: i64 = 1;
+const MAX_BACKOFF_SEC: i64 = 300;
+const HEALTHY_RUN_SEC: i64 = 600;
+
+pub struct Supervisor<C: Clocks + Clone> {
+    pub db: Arc<Database<C>>,
+    pub events: events::Bus,
+    pub shutdown: Arc<AtomicBool>,
+    pub stream_id: i32,
+    pub short_name: String,
+}
+
+impl<C: Clocks + Clone> Supervisor<C> {
+    /// Calls `f` until it returns `Ok` or shutdown is requested, restarting it after it panics or
+    /// returns `Err` (as when the restarted streamer can't be created).
+    pub fn run<F: FnMut() -> Result<(), Error>>(&self, mut f: F) {
+        let clocks = self.db.clocks();
+        let mut backoff_sec = MIN_BACKOFF_SEC;
+        while !self.shutdown.load(Ordering::SeqCst) {
+            let start = clocks.monotonic();
+            let message = match panic::catch_unwind(AssertUnwindSafe(&mut f)) {
+                Ok(Ok(())) => return,
+                Ok(Err(e)) => format!("unable to start: {}", e),
+                Err(p) => format!("panicked: {}", panic_message(&*p)),
+            };
+            if clocks.monotonic() - start >= Duration::seconds(HEALTHY_RUN_SEC) {
+                backoff_sec = MIN_BACKOFF_SEC;
+            }
+            error!(
+                "{}: streamer {}; restarting in {} s",
+                &self.short_name, &message, backoff_sec
+            );
+            self.events
+                .streamer_failed(&self.db.lock(), self.stream_id, message, backoff_sec);
+
+            // Sleep in short steps so shutdown isn't delayed.
+            let deadline = clocks.monotonic() + Duration::seconds(backoff_sec);
+            while !self.shutdown.load(Ordering::SeqCst) && clocks.monotonic() < deadline {
+                clocks.sleep(Duration::seconds(1));
+            }
+            backoff_sec = cmp::min(backoff_sec * 2, MAX_BACKOFF_SEC);
+        }
+    }
+}
+
+fn panic_message(p: &(dyn Any + Send)) -> &str {
+    if let Some(s) = p.downcast_ref::<&'static str>() {
+        s
+    } else if let Some(s) = p.downcast_ref::<String>() {
+        s
+    } else {
+        "(non-string payload)"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::clock::SimulatedClocks;
+    use db::testutil;
+
+    #[test]
+    fn restarts_with_backoff() {
+        testutil::init();
+        let clocks = SimulatedClocks::new(time::Timespec::new(1429920000, 0));
+        let tdb = testutil::TestDb::new(clocks.clone());
+        let events = events::Bus::default();
+        let mut rx = events.subscribe();
+        let s = Supervisor {
+            db: tdb.db.clone(),
+            events,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            stream_id: testutil::TEST_STREAM_ID,
+            short_name: "test-camera-main".to_owned(),
+        };
+        let mut starts = Vec::new();
+        s.run(|| {
+            starts.push(clocks.monotonic().sec);
+            match starts.len() {
+                1 | 2 => panic!("oops {}", starts.len()),
+                3 => failure::bail!("no such stream"),
+                _ => Ok(()),
+            }
+        });
+        assert_eq!(starts, vec![0, 1, 3, 7]);
+        let m = rx.try_recv().unwrap();
+        assert_eq!(m.type_name, "streamerFailed");
+        let j: serde_json::Value = serde_json::from_str(&m.json).unwrap();
+        assert_eq!(j["message"], "panicked: oops 1");
+        assert_eq!(j["restartInSec"], 1);
+        rx.try_recv().unwrap();
+        let m = rx.try_recv().unwrap();
+        let j: serde_json::Value = serde_json::from_str(&m.json).unwrap();
+        assert_eq!(j["message"], "unable to start: no such stream");
+        assert_eq!(j["restartInSec"], 4);
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,1012 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Two-way audio ("talkback") through a camera's ONVIF RTSP backchannel.
+//!
+//! The ONVIF Streaming Specification (section 5.3) extends RTSP so a client can send audio to
+//! the camera: the client includes `Require: www.onvif.org/ver20/backchannel` in its requests,
+//! and the camera's session description then has an extra audio media section marked
+//! `a=sendonly`. After `SETUP` and `PLAY` of that section, the client sends RTP packets
+//! interleaved on the RTSP connection as in RFC 2326 section 10.12.
+//!
+//! This is a minimal client for just that. It authenticates with Basic or Digest authentication,
+//! sets up only the backchannel (no video), and sends G.711 audio (µ-law or A-law at 8 kHz), which
+//! is what cameras commonly accept. The caller supplies 16-bit linear PCM; this module encodes and
+//! packetizes it. Each camera allows one talkback session at a time; see `Sessions`.
+
+use failure::{bail, format_err, Error};
+use futures::future::AbortHandle;
+use parking_lot::Mutex;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration as StdDuration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, WriteHalf};
+use tokio::net::TcpStream;
+use tracing::debug;
+use url::Url;
+use uuid::Uuid;
+
+/// The RTSP option tag which requests the backchannel.
+const REQUIRE_BACKCHANNEL: &str = "www.onvif.org/ver20/backchannel";
+
+/// The sample rate of G.711 audio, which is also its RTP clock rate.
+const SAMPLE_RATE: usize = 8_000;
+
+/// The samples in each RTP packet: 20 ms, the usual G.711 packetization interval.
+const SAMPLES_PER_PACKET: usize = 160;
+
+/// The most PCM bytes accepted in one call to `Backchannel::send`: one second of audio.
+pub const MAX_SEND_BYTES: usize = 2 * SAMPLE_RATE;
+
+/// How long to wait to connect and for each response while setting up.
+const SETUP_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+
+/// The largest response header section or body accepted from the camera.
+const MAX_RESPONSE_LEN: usize = 65_536;
+
+/// The session timeout to assume if the camera doesn't specify one, as in RFC 2326 section 12.37.
+const DEFAULT_SESSION_TIMEOUT_SEC: u64 = 60;
+
+/// How often the caller should check for idleness and send keepalives while no audio arrives.
+pub const POLL_INTERVAL: StdDuration = StdDuration::from_secs(5);
+
+/// How long a session may go without audio before the caller should end it.
+pub const IDLE_TIMEOUT: StdDuration = StdDuration::from_secs(60);
+
+/// The cameras which have a talkback session in progress.
+#[derive(Default)]
+pub struct Sessions(Mutex<HashSet<Uuid>>);
+
+impl Sessions {
+    /// Claims `camera` for a new session, or returns `None` if it already has one.
+    pub fn try_claim(self: &Arc<Self>, camera: Uuid) -> Option<Claim> {
+        if !self.0.lock().insert(camera) {
+            return None;
+        }
+        Some(Claim {
+            sessions: self.clone(),
+            camera,
+        })
+    }
+}
+
+/// A camera's claim on talkback, released when dropped.
+pub struct Claim {
+    sessions: Arc<Sessions>,
+    camera: Uuid,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.sessions.0.lock().remove(&self.camera);
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Codec {
+    /// G.711 µ-law.
+    Pcmu,
+
+    /// G.711 A-law.
+    Pcma,
+}
+
+impl Codec {
+    /// Returns the codec with the given `a=rtpmap` encoding name, if supported.
+    fn from_encoding_name(name: &str) -> Option<Self> {
+        if name.eq_ignore_ascii_case("PCMU") {
+            Some(Codec::Pcmu)
+        } else if name.eq_ignore_ascii_case("PCMA") {
+            Some(Codec::Pcma)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the codec with the given static RTP payload type (RFC 3551 section 6), if
+    /// supported.
+    fn from_static_payload_type(pt: u8) -> Option<Self> {
+        match pt {
+            0 => Some(Codec::Pcmu),
+            8 => Some(Codec::Pcma),
+            _ => None,
+        }
+    }
+
+    fn encode(self, sample: i16) -> u8 {
+        match self {
+            Codec::Pcmu => linear_to_ulaw(sample),
+            Codec::Pcma => linear_to_alaw(sample),
+        }
+    }
+}
+
+/// Encodes a sample as G.711 µ-law.
+fn linear_to_ulaw(sample: i16) -> u8 {
+    let mut v = i32::from(sample) >> 2; // µ-law operates on 14-bit samples.
+    let mask = if v < 0 {
+        v = -v;
+        0x7f
+    } else {
+        0xff
+    };
+    v = v.min(8159) + 33; // clip, then add the bias.
+    let segment = (32 - (v as u32).leading_zeros() as i32 - 6).max(0);
+    if segment >= 8 {
+        return (0x7f ^ mask) as u8;
+    }
+    (((segment << 4) | ((v >> (segment + 1)) & 0x0f)) ^ mask) as u8
+}
+
+/// Encodes a sample as G.711 A-law.
+fn linear_to_alaw(sample: i16) -> u8 {
+    let mut v = i32::from(sample) >> 3; // A-law operates on 13-bit samples.
+    let mask = if v >= 0 {
+        0xd5
+    } else {
+        v = -v - 1;
+        0x55
+    };
+    let segment = (32 - (v as u32).leading_zeros() as i32 - 5).max(0);
+    if segment >= 8 {
+        return (0x7f ^ mask) as u8;
+    }
+    let shift = if segment < 2 { 1 } else { segment };
+    (((segment << 4) | ((v >> shift) & 0x0f)) ^ mask) as u8
+}
+
+/// An RTSP response.
+struct Response {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Reads a response, skipping any interleaved data (such as RTCP reports) which precedes it.
+async fn read_response<R: AsyncBufRead + Unpin>(r: &mut R) -> Result<Response, Error> {
+    let mut first = r.read_u8().await?;
+    while first == b'$' {
+        let mut hdr = [0u8; 3];
+        r.read_exact(&mut hdr).await?;
+        let mut skip = vec![0u8; usize::from(u16::from_be_bytes([hdr[1], hdr[2]]))];
+        r.read_exact(&mut skip).await?;
+        first = r.read_u8().await?;
+    }
+    let mut status_line = vec![first];
+    r.read_until(b'\n', &mut status_line).await?;
+    let status_line = String::from_utf8(status_line)
+        .map_err(|_| format_err!("non-UTF-8 status line from camera"))?;
+    let mut parts = status_line.trim_end().splitn(3, ' ');
+    if parts.next() != Some("RTSP/1.0") {
+        bail!("bad status line {:?} from camera", status_line);
+    }
+    let status = parts
+        .next()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| format_err!("bad status line {:?} from camera", status_line))?;
+    let reason = parts.next().unwrap_or("").to_owned();
+    let mut headers = Vec::new();
+    let mut len = status_line.len();
+    loop {
+        let mut line = String::new();
+        if r.read_line(&mut line).await? == 0 {
+            bail!("camera closed connection mid-response");
+        }
+        len += line.len();
+        if len > MAX_RESPONSE_LEN {
+            bail!("camera's response headers are too long");
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let colon = line
+            .find(':')
+            .ok_or_else(|| format_err!("bad header line {:?} from camera", line))?;
+        headers.push((
+            line[..colon].trim().to_owned(),
+            line[colon + 1..].trim().to_owned(),
+        ));
+    }
+    let mut resp = Response {
+        status,
+        reason,
+        headers,
+        body: Vec::new(),
+    };
+    if let Some(l) = resp.header("Content-Length") {
+        let l: usize = l
+            .parse()
+            .map_err(|_| format_err!("bad Content-Length {:?} from camera", l))?;
+        if l > MAX_RESPONSE_LEN {
+            bail!("camera's response body is too long");
+        }
+        resp.body = vec![0u8; l];
+        r.read_exact(&mut resp.body).await?;
+    }
+    Ok(resp)
+}
+
+/// A camera's authentication challenge, from a `WWW-Authenticate` header.
+#[derive(Debug, Eq, PartialEq)]
+enum Challenge {
+    Basic,
+    Digest {
+        realm: String,
+        nonce: String,
+        opaque: Option<String>,
+        qop_auth: bool,
+    },
+}
+
+/// Parses a `WWW-Authenticate` header value, returning `None` for unsupported schemes.
+fn parse_challenge(v: &str) -> Option<Challenge> {
+    let v = v.trim();
+    let (scheme, rest) = match v.find(' ') {
+        Some(i) => (&v[..i], &v[i + 1..]),
+        None => (v, ""),
+    };
+    if scheme.eq_ignore_ascii_case("Basic") {
+        return Some(Challenge::Basic);
+    }
+    if !scheme.eq_ignore_ascii_case("Digest") {
+        return None;
+    }
+    let mut realm = None;
+    let mut nonce = None;
+    let mut opaque = None;
+    let mut qop_auth = false;
+    for (k, v) in parse_params(rest) {
+        match k.to_ascii_lowercase().as_str() {
+            "realm" => realm = Some(v),
+            "nonce" => nonce = Some(v),
+            "opaque" => opaque = Some(v),
+            "qop" => qop_auth = v.split(',').any(|q| q.trim() == "auth"),
+            "algorithm" if !v.eq_ignore_ascii_case("MD5") => return None,
+            _ => {}
+        }
+    }
+    Some(Challenge::Digest {
+        realm: realm?,
+        nonce: nonce?,
+        opaque,
+        qop_auth,
+    })
+}
+
+/// Parses comma-separated `key=value` or `key="quoted value"` parameters, as in a challenge.
+fn parse_params(mut s: &str) -> Vec<(String, String)> {
+    let mut out = Vec::new();
+    loop {
+        s = s.trim_start_matches(|c| c == ',' || c == ' ' || c == '\t');
+        let eq = match s.find('=') {
+            None => break,
+            Some(i) => i,
+        };
+        let key = s[..eq].trim().to_owned();
+        s = &s[eq + 1..];
+        let mut value = String::new();
+        if s.starts_with('"') {
+            let mut end = s.len();
+            let mut chars = s[1..].char_indices();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                    '"' => {
+                        end = i + 2;
+                        break;
+                    }
+                    c => value.push(c),
+                }
+            }
+            s = &s[end..];
+        } else {
+            let end = s.find(',').unwrap_or_else(|| s.len());
+            value.push_str(s[..end].trim());
+            s = &s[end..];
+        }
+        out.push((key, value));
+    }
+    out
+}
+
+fn md5_hex(s: &str) -> String {
+    base::strutil::hex(&base::crypto::md5(s.as_bytes()))
+}
+
+/// Computes a Digest `response` as in RFC 2617 section 3.2.2.1.
+/// `qop` is the `nc` and `cnonce`, if the challenge offered `qop=auth`.
+fn digest_response(
+    username: &str,
+    password: &str,
+    realm: &str,
+    nonce: &str,
+    method: &str,
+    uri: &str,
+    qop: Option<(&str, &str)>,
+) -> String {
+    let ha1 = md5_hex(&format!("{}:{}:{}", username, realm, password));
+    let ha2 = md5_hex(&format!("{}:{}", method, uri));
+    match qop {
+        None => md5_hex(&format!("{}:{}:{}", ha1, nonce, ha2)),
+        Some((nc, cnonce)) => md5_hex(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2)),
+    }
+}
+
+/// Credentials and the camera's most recent challenge.
+struct Auth {
+    username: String,
+    password: String,
+    challenge: Option<Challenge>,
+    nc: u32,
+}
+
+impl Auth {
+    /// Returns the `Authorization` header value for a request, if the camera has challenged.
+    fn authorization(&mut self, method: &str, uri: &str) -> Result<Option<String>, Error> {
+        let (realm, nonce, opaque, qop_auth) = match self.challenge {
+            None => return Ok(None),
+            Some(Challenge::Basic) => {
+                return Ok(Some(format!(
+                    "Basic {}",
+                    base64::encode(&format!("{}:{}", self.username, self.password))
+                )))
+            }
+            Some(Challenge::Digest {
+                ref realm,
+                ref nonce,
+                ref opaque,
+                qop_auth,
+            }) => (realm, nonce, opaque, qop_auth),
+        };
+        let mut v = format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\"",
+            self.username, realm, nonce, uri
+        );
+        if qop_auth {
+            self.nc += 1;
+            let nc = format!("{:08x}", self.nc);
+            let mut cnonce = [0u8; 8];
+            base::crypto::rand_bytes(&mut cnonce)?;
+            let cnonce = base::strutil::hex(&cnonce);
+            let response = digest_response(
+                &self.username,
+                &self.password,
+                realm,
+                nonce,
+                method,
+                uri,
+                Some((&nc, &cnonce)),
+            );
+            v.push_str(&format!(
+                ", qop=auth, nc={}, cnonce=\"{}\", response=\"{}\"",
+                nc, cnonce, response
+            ));
+        } else {
+            let response = digest_response(
+                &self.username,
+                &self.password,
+                realm,
+                nonce,
+                method,
+                uri,
+                None,
+            );
+            v.push_str(&format!(", response=\"{}\"", response));
+        }
+        if let Some(o) = opaque {
+            v.push_str(&format!(", opaque=\"{}\"", o));
+        }
+        Ok(Some(v))
+    }
+}
+
+/// Formats an RTSP request.
+fn format_request(
+    method: &str,
+    url: &str,
+    cseq: u32,
+    auth: &mut Auth,
+    session: Option<&str>,
+    headers: &[(&str, &str)],
+) -> Result<String, Error> {
+    let mut r = format!(
+        "{} {} RTSP/1.0\r\nCSeq: {}\r\nUser-Agent: moonfire-nvr\r\n",
+        method, url, cseq
+    );
+    if let Some(a) = auth.authorization(method, url)? {
+        r.push_str(&format!("Authorization: {}\r\n", a));
+    }
+    if let Some(s) = session {
+        r.push_str(&format!("Session: {}\r\n", s));
+    }
+    for (k, v) in headers {
+        r.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    r.push_str("\r\n");
+    Ok(r)
+}
+
+/// The backchannel media section of a session description.
+#[derive(Debug, Eq, PartialEq)]
+struct Media {
+    control: String,
+    codec: Codec,
+    payload_type: u8,
+}
+
+/// Finds the first backchannel audio media section with a supported encoding.
+fn parse_sdp(sdp: &str, base: &str) -> Result<Media, Error> {
+    #[derive(Default)]
+    struct Section<'a> {
+        audio: bool,
+        payload_types: Vec<u8>,
+        sendonly: bool,
+        control: Option<&'a str>,
+        rtpmap: Vec<(u8, &'a str)>,
+    }
+    let mut sections: Vec<Section> = Vec::new();
+    for line in sdp.lines() {
+        let line = line.trim_end();
+        if line.starts_with("m=") {
+            let mut fields = line[2..].split(' ');
+            sections.push(Section {
+                audio: fields.next() == Some("audio"),
+                payload_types: fields.skip(2).filter_map(|p| p.parse().ok()).collect(),
+                ..Default::default()
+            });
+            continue;
+        }
+        let s = match sections.last_mut() {
+            None => continue, // session-level attributes aren't of interest.
+            Some(s) => s,
+        };
+        if line == "a=sendonly" {
+            s.sendonly = true;
+        } else if line.starts_with("a=control:") {
+            s.control = Some(&line["a=control:".len()..]);
+        } else if line.starts_with("a=rtpmap:") {
+            let v = &line["a=rtpmap:".len()..];
+            if let Some(sp) = v.find(' ') {
+                if let Ok(pt) = v[..sp].parse() {
+                    s.rtpmap.push((pt, &v[sp + 1..]));
+                }
+            }
+        }
+    }
+    let mut found_backchannel = false;
+    for s in sections.iter().filter(|s| s.audio && s.sendonly) {
+        found_backchannel = true;
+        for &pt in &s.payload_types {
+            let codec = match s.rtpmap.iter().find(|&&(p, _)| p == pt) {
+                None => Codec::from_static_payload_type(pt),
+                Some(&(_, encoding)) => {
+                    let mut parts = encoding.split('/');
+                    let name = parts.next().unwrap();
+                    if parts.next() != Some("8000") {
+                        continue;
+                    }
+                    Codec::from_encoding_name(name)
+                }
+            };
+            if let Some(codec) = codec {
+                let control = s
+                    .control
+                    .ok_or_else(|| format_err!("backchannel media section has no control URL"))?;
+                return Ok(Media {
+                    control: join_control(base, control),
+                    codec,
+                    payload_type: pt,
+                });
+            }
+        }
+    }
+    if found_backchannel {
+        bail!("camera's backchannel doesn't accept G.711 audio at 8 kHz");
+    }
+    bail!("camera's session description has no backchannel; it may not support two-way audio");
+}
+
+/// Resolves a media section's `a=control` attribute against the base URL, as in RFC 2326
+/// appendix C.1.1.
+fn join_control(base: &str, control: &str) -> String {
+    if control == "*" {
+        return base.to_owned();
+    }
+    if control.starts_with("rtsp://") || control.starts_with("rtsps://") {
+        return control.to_owned();
+    }
+    if base.ends_with('/') {
+        format!("{}{}", base, control)
+    } else {
+        format!("{}/{}", base, control)
+    }
+}
+
+/// Parses a `Session` header into the session id and timeout in seconds.
+fn parse_session(v: &str) -> Result<(String, u64), Error> {
+    let mut parts = v.split(';');
+    let id = parts.next().unwrap().trim();
+    if id.is_empty() {
+        bail!("empty Session header from camera");
+    }
+    let timeout = parts
+        .filter_map(|p| {
+            let p = p.trim();
+            if p.starts_with("timeout=") {
+                p["timeout=".len()..].parse().ok()
+            } else {
+                None
+            }
+        })
+        .next()
+        .unwrap_or(DEFAULT_SESSION_TIMEOUT_SEC);
+    Ok((id.to_owned(), timeout))
+}
+
+/// Returns the RTP channel from a `Transport` header's `interleaved=<rtp>-<rtcp>` parameter.
+fn parse_interleaved_channel(transport: &str) -> Option<u8> {
+    transport.split(';').find_map(|p| {
+        let p = p.trim();
+        if !p.starts_with("interleaved=") {
+            return None;
+        }
+        p["interleaved=".len()..].split('-').next()?.parse().ok()
+    })
+}
+
+/// An RTSP connection while setting up the backchannel.
+struct Conn {
+    stream: BufReader<TcpStream>,
+    cseq: u32,
+    auth: Auth,
+}
+
+impl Conn {
+    /// Sends a request and returns its successful response, retrying once with credentials if
+    /// the camera asks for them.
+    async fn request(
+        &mut self,
+        method: &str,
+        url: &str,
+        session: Option<&str>,
+        headers: &[(&str, &str)],
+    ) -> Result<Response, Error> {
+        let mut retried = false;
+        loop {
+            self.cseq += 1;
+            let req = format_request(method, url, self.cseq, &mut self.auth, session, headers)?;
+            self.stream.write_all(req.as_bytes()).await?;
+            let resp = tokio::time::timeout(SETUP_TIMEOUT, read_response(&mut self.stream))
+                .await
+                .map_err(|_| format_err!("timed out waiting for {} response", method))??;
+            if resp.status == 401 && !retried {
+                self.auth.challenge = resp
+                    .headers
+                    .iter()
+                    .filter(|(n, _)| n.eq_ignore_ascii_case("WWW-Authenticate"))
+                    .filter_map(|(_, v)| parse_challenge(v))
+                    .min_by_key(|c| match c {
+                        Challenge::Digest { .. } => 0,
+                        Challenge::Basic => 1,
+                    });
+                if self.auth.challenge.is_none() {
+                    bail!("camera requested unsupported authentication for {}", method);
+                }
+                retried = true;
+                continue;
+            }
+            if resp.status == 551 {
+                bail!(
+                    "camera doesn't support the ONVIF backchannel ({} returned 551 {})",
+                    method,
+                    resp.reason
+                );
+            }
+            if resp.status != 200 {
+                bail!("{} failed: {} {}", method, resp.status, resp.reason);
+            }
+            return Ok(resp);
+        }
+    }
+}
+
+/// A camera's playing backchannel, ready for audio.
+pub struct Backchannel {
+    w: WriteHalf<BufReader<TcpStream>>,
+    url: String,
+    cseq: u32,
+    auth: Auth,
+    session: String,
+    media: Media,
+    channel: u8,
+    ssrc: u32,
+    seq: u16,
+    timestamp: u32,
+    sent_packet: bool,
+
+    /// Encoded samples not yet sent, fewer than `SAMPLES_PER_PACKET`.
+    pending: Vec<u8>,
+
+    keepalive_interval: StdDuration,
+    last_request: Instant,
+    last_audio: Instant,
+
+    /// Stops the task which reads (and discards) the camera's RTCP reports and responses.
+    drain: AbortHandle,
+}
+
+impl Backchannel {
+    /// Connects to the camera at the given `rtsp://` URL and sets up its backchannel.
+    pub async fn open(url: &str, username: &str, password: &str) -> Result<Self, Error> {
+        let mut url = Url::parse(url)?;
+        if url.scheme() != "rtsp" {
+            bail!("talkback requires an rtsp:// URL, not {}", url.scheme());
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| format_err!("URL has no host"))?
+            .to_owned();
+        let port = url.port().unwrap_or(554);
+
+        // Credentials go in Authorization headers, never in the request URL.
+        url.set_username("").unwrap();
+        url.set_password(None).unwrap();
+        let url = url.as_str().to_owned();
+
+        let stream = tokio::time::timeout(SETUP_TIMEOUT, TcpStream::connect((host.as_str(), port)))
+            .await
+            .map_err(|_| format_err!("timed out connecting to {}:{}", host, port))??;
+        let mut conn = Conn {
+            stream: BufReader::new(stream),
+            cseq: 0,
+            auth: Auth {
+                username: username.to_owned(),
+                password: password.to_owned(),
+                challenge: None,
+                nc: 0,
+            },
+        };
+        let resp = conn
+            .request(
+                "DESCRIBE",
+                &url,
+                None,
+                &[
+                    ("Accept", "application/sdp"),
+                    ("Require", REQUIRE_BACKCHANNEL),
+                ],
+            )
+            .await?;
+        let base = resp
+            .header("Content-Base")
+            .or_else(|| resp.header("Content-Location"))
+            .unwrap_or(&url)
+            .to_owned();
+        let sdp = std::str::from_utf8(&resp.body)
+            .map_err(|_| format_err!("camera's session description isn't UTF-8"))?;
+        let media = parse_sdp(sdp, &base)?;
+        debug!("talkback: {:?} at {}", media, url);
+        let resp = conn
+            .request(
+                "SETUP",
+                &media.control,
+                None,
+                &[
+                    ("Transport", "RTP/AVP/TCP;unicast;interleaved=0-1"),
+                    ("Require", REQUIRE_BACKCHANNEL),
+                ],
+            )
+            .await?;
+        let (session, timeout_sec) = parse_session(
+            resp.header("Session")
+                .ok_or_else(|| format_err!("SETUP response has no Session header"))?,
+        )?;
+        let channel = resp
+            .header("Transport")
+            .and_then(parse_interleaved_channel)
+            .unwrap_or(0);
+        conn.request(
+            "PLAY",
+            &base,
+            Some(&session),
+            &[("Range", "npt=0.000-"), ("Require", REQUIRE_BACKCHANNEL)],
+        )
+        .await?;
+
+        let (mut r, w) = tokio::io::split(conn.stream);
+        let (drain, drain_handle) = futures::future::abortable(async move {
+            let mut buf = [0u8; 4096];
+            while let Ok(n) = r.read(&mut buf).await {
+                if n == 0 {
+                    break;
+                }
+            }
+        });
+        tokio::spawn(drain);
+        let mut ssrc = [0u8; 4];
+        base::crypto::rand_bytes(&mut ssrc)?;
+        let now = Instant::now();
+        Ok(Backchannel {
+            w,
+            url: base,
+            cseq: conn.cseq,
+            auth: conn.auth,
+            session,
+            media,
+            channel,
+            ssrc: u32::from_be_bytes(ssrc),
+            seq: 0,
+            timestamp: 0,
+            sent_packet: false,
+            pending: Vec::with_capacity(SAMPLES_PER_PACKET),
+            keepalive_interval: StdDuration::from_secs(timeout_sec.max(2) / 2),
+            last_request: now,
+            last_audio: now,
+            drain: drain_handle,
+        })
+    }
+
+    /// Sends audio, given as 16-bit little-endian linear PCM, mono at 8 kHz.
+    /// Samples which don't fill a packet are held until the next call.
+    pub async fn send(&mut self, pcm: &[u8]) -> Result<(), Error> {
+        if pcm.len() % 2 != 0 {
+            bail!("PCM audio must be whole 16-bit samples");
+        }
+        if pcm.len() > MAX_SEND_BYTES {
+            bail!(
+                "{} bytes of audio at once; the limit is {}",
+                pcm.len(),
+                MAX_SEND_BYTES
+            );
+        }
+        self.last_audio = Instant::now();
+        let codec = self.media.codec;
+        self.pending.extend(
+            pcm.chunks_exact(2)
+                .map(|s| codec.encode(i16::from_le_bytes([s[0], s[1]]))),
+        );
+        let full = self.pending.len() / SAMPLES_PER_PACKET * SAMPLES_PER_PACKET;
+        let samples: Vec<u8> = self.pending.drain(..full).collect();
+        let mut packets = Vec::with_capacity(full / SAMPLES_PER_PACKET * (16 + SAMPLES_PER_PACKET));
+        for payload in samples.chunks(SAMPLES_PER_PACKET) {
+            self.append_packet(payload, &mut packets);
+        }
+        self.w.write_all(&packets).await?;
+        self.keepalive().await
+    }
+
+    /// Appends an interleaved RTP packet (RFC 3550 section 5.1) holding `payload`.
+    fn append_packet(&mut self, payload: &[u8], out: &mut Vec<u8>) {
+        out.push(b'$');
+        out.push(self.channel);
+        out.extend_from_slice(&((12 + payload.len()) as u16).to_be_bytes());
+        out.push(0x80); // version 2; no padding, extension, or CSRCs.
+
+        // The marker bit flags the start of a talkspurt, as in RFC 3551 section 4.1.
+        let marker = if self.sent_packet { 0 } else { 0x80 };
+        out.push(marker | self.media.payload_type);
+        out.extend_from_slice(&self.seq.to_be_bytes());
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out.extend_from_slice(&self.ssrc.to_be_bytes());
+        out.extend_from_slice(payload);
+        self.sent_packet = true;
+        self.seq = self.seq.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(payload.len() as u32);
+    }
+
+    /// Returns how long it's been since audio was last sent (or the session was opened).
+    pub fn idle(&self) -> StdDuration {
+        self.last_audio.elapsed()
+    }
+
+    /// Sends a keepalive request if one is due, so the camera doesn't time out the session while
+    /// the user isn't speaking.
+    pub async fn keepalive(&mut self) -> Result<(), Error> {
+        if self.last_request.elapsed() < self.keepalive_interval {
+            return Ok(());
+        }
+        self.send_request("GET_PARAMETER").await
+    }
+
+    /// Sends a request without waiting for the response, which the drain task discards.
+    async fn send_request(&mut self, method: &str) -> Result<(), Error> {
+        self.cseq += 1;
+        let req = format_request(
+            method,
+            &self.url,
+            self.cseq,
+            &mut self.auth,
+            Some(&self.session),
+            &[],
+        )?;
+        self.w.write_all(req.as_bytes()).await?;
+        self.last_request = Instant::now();
+        Ok(())
+    }
+
+    /// Ends the session, discarding any samples which don't fill a packet.
+    pub async fn close(mut self) {
+        if let Err(e) = self.send_request("TEARDOWN").await {
+            debug!("talkback: TEARDOWN failed: {}", e);
+        }
+    }
+}
+
+impl Drop for Backchannel {
+    fn drop(&mut self) {
+        self.drain.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn g711() {
+        for &(sample, ulaw, alaw) in &[
+            (0, 0xff, 0xd5),
+            (-1, 0x7e, 0x55),
+            (8, 0xfe, 0xd5),
+            (1000, 0xce, 0xfa),
+            (-1000, 0x4e, 0x7a),
+            (32767, 0x80, 0xaa),
+            (-32768, 0x00, 0x2a),
+        ] {
+            assert_eq!(linear_to_ulaw(sample), ulaw, "ulaw of {}", sample);
+            assert_eq!(linear_to_alaw(sample), alaw, "alaw of {}", sample);
+        }
+    }
+
+    #[test]
+    fn challenge() {
+        assert_eq!(
+            parse_challenge(
+                "Digest realm=\"IP Camera(21388)\", nonce=\"3a1b, \\\"x\\\"\", stale=\"FALSE\""
+            ),
+            Some(Challenge::Digest {
+                realm: "IP Camera(21388)".to_owned(),
+                nonce: "3a1b, \"x\"".to_owned(),
+                opaque: None,
+                qop_auth: false,
+            })
+        );
+        assert_eq!(
+            parse_challenge("Digest realm=r, nonce=n, qop=\"auth,auth-int\", opaque=\"o\""),
+            Some(Challenge::Digest {
+                realm: "r".to_owned(),
+                nonce: "n".to_owned(),
+                opaque: Some("o".to_owned()),
+                qop_auth: true,
+            })
+        );
+        assert_eq!(
+            parse_challenge("Basic realm=\"camera\""),
+            Some(Challenge::Basic)
+        );
+        assert_eq!(
+            parse_challenge("Digest realm=r, nonce=n, algorithm=SHA-256"),
+            None
+        );
+        assert_eq!(parse_challenge("Negotiate"), None);
+    }
+
+    #[test]
+    fn digest() {
+        // The example of RFC 2617 section 3.5.
+        assert_eq!(
+            digest_response(
+                "Mufasa",
+                "Circle Of Life",
+                "testrealm@host.com",
+                "dcd98b7102dd2f0e8b11d0f600bfb0c093",
+                "GET",
+                "/dir/index.html",
+                Some(("00000001", "0a4f113b")),
+            ),
+            "6629fae49393a05397450978507c4ef1"
+        );
+    }
+
+    #[test]
+    fn sdp() {
+        let sdp = "v=0\r\n\
+                   o=- 2251938202 2251938202 IN IP4 0.0.0.0\r\n\
+                   s=Media Server\r\n\
+                   a=control:*\r\n\
+                   m=video 0 RTP/AVP 96\r\n\
+                   a=control:trackID=0\r\n\
+                   a=rtpmap:96 H264/90000\r\n\
+                   a=recvonly\r\n\
+                   m=audio 0 RTP/AVP 8\r\n\
+                   a=control:trackID=1\r\n\
+                   a=rtpmap:8 PCMA/8000\r\n\
+                   a=recvonly\r\n\
+                   m=audio 0 RTP/AVP 97 0\r\n\
+                   a=control:trackID=2\r\n\
+                   a=rtpmap:97 MPEG4-GENERIC/16000/1\r\n\
+                   a=sendonly\r\n";
+        assert_eq!(
+            parse_sdp(sdp, "rtsp://192.168.5.104/Streaming/Channels/101/").unwrap(),
+            Media {
+                control: "rtsp://192.168.5.104/Streaming/Channels/101/trackID=2".to_owned(),
+                codec: Codec::Pcmu,
+                payload_type: 0,
+            }
+        );
+        let no_backchannel = sdp.replace("a=sendonly", "a=recvonly");
+        parse_sdp(&no_backchannel, "rtsp://192.168.5.104/").unwrap_err();
+    }
+
+    #[test]
+    fn headers() {
+        assert_eq!(
+            parse_session("12345678;timeout=30").unwrap(),
+            ("12345678".to_owned(), 30)
+        );
+        assert_eq!(
+            parse_session("abc").unwrap(),
+            ("abc".to_owned(), DEFAULT_SESSION_TIMEOUT_SEC)
+        );
+        assert_eq!(
+            parse_interleaved_channel("RTP/AVP/TCP;unicast;interleaved=2-3"),
+            Some(2)
+        );
+        assert_eq!(parse_interleaved_channel("RTP/AVP;unicast"), None);
+    }
+
+    #[tokio::test]
+    async fn response() {
+        let raw = b"$\x01\x00\x02ab\
+                    RTSP/1.0 401 Unauthorized\r\n\
+                    CSeq: 1\r\n\
+                    WWW-Authenticate: Basic realm=\"x\"\r\n\
+                    Content-Length: 3\r\n\
+                    \r\n\
+                    abc";
+        let mut r = BufReader::new(&raw[..]);
+        let resp = read_response(&mut r).await.unwrap();
+        assert_eq!(resp.status, 401);
+        assert_eq!(resp.reason, "Unauthorized");
+        assert_eq!(resp.header("www-authenticate"), Some("Basic realm=\"x\""));
+        assert_eq!(resp.body, b"abc");
+    }
+}
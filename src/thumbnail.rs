@@ -0,0 +1,151 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! JPEG thumbnails of recordings, for visual scrubbing previews in the web UI.
+//!
+//! Each recording's thumbnail is made from its first key frame. As with virtual streams'
+//! transcoding (see `stream::Transcoder`), decoding and encoding happen in an `ffmpeg`
+//! subprocess, so a crashing or stuck decoder can't take down recording. The same goes for
+//! the live JPEGs of `GET /api/cameras/<uuid>/<stream>/mjpeg`. Both black out the stream's
+//! privacy mask.
+
+use crate::h264;
+use db::mask::Mask;
+use failure::{bail, format_err, Error};
+use std::fmt::Write as _;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Returns an ffmpeg video filter which blacks out `mask`, or `None` if it's empty.
+///
+/// Each pixel is tested against each polygon by counting the edges crossed by a ray to its
+/// left (the even-odd rule), using `geq` on planar RGB so that all planes share coordinates.
+pub fn mask_filter(mask: &Mask) -> Option<String> {
+    if mask.is_empty() {
+        return None;
+    }
+    let mut inside = String::new();
+    for (i, p) in mask.polygons().iter().enumerate() {
+        if i > 0 {
+            inside.push('+');
+        }
+        inside.push_str("mod(0");
+        for (j, &[xi, yi]) in p.iter().enumerate() {
+            let [xj, yj] = p[(j + 1) % p.len()];
+            if yi == yj {
+                continue; // a horizontal edge is never crossed.
+            }
+            write!(
+                &mut inside,
+                "+not(eq(gt({yi},Y/H),gt({yj},Y/H)))*lt(X/W,{xi}+({xj}-{xi})*(Y/H-{yi})/({yj}-{yi}))",
+                xi = xi,
+                yi = yi,
+                xj = xj,
+                yj = yj
+            )
+            .unwrap();
+        }
+        inside.push_str(",2)");
+    }
+    let plane = |c: &str| {
+        format!(
+            "{c}='if(gt({inside},0),0,{c}(X,Y))'",
+            c = c,
+            inside = inside
+        )
+    };
+    Some(format!(
+        "format=gbrp,geq={}:{}:{}",
+        plane("r"),
+        plane("g"),
+        plane("b")
+    ))
+}
+
+/// Generates a thumbnail no wider than `width` pixels from `key_frame`, a sample in AVC format
+/// described by the AVC sample entry `sample_entry`, with `mask` blacked out. Blocks until the
+/// `ffmpeg` subprocess exits.
+pub fn generate(
+    sample_entry: &[u8],
+    key_frame: &[u8],
+    width: u32,
+    mask: &Mask,
+) -> Result<Vec<u8>, Error> {
+    let mut annex_b = Vec::new();
+    h264::to_annex_b(sample_entry, key_frame, &mut annex_b)?;
+    let mut filter = format!("scale='min({},iw)':-2", width);
+    if let Some(m) = mask_filter(mask) {
+        filter.push(',');
+        filter.push_str(&m);
+    }
+    let mut child = Command::new("ffmpeg")
+        .args(&[
+            "-nostdin",
+            "-hide_banner",
+            "-loglevel",
+            "error",
+            "-f",
+            "h264",
+            "-i",
+            "pipe:0",
+            "-frames:v",
+            "1",
+            "-vf",
+            &filter,
+            "-q:v",
+            "5",
+            "-f",
+            "image2",
+            "-c:v",
+            "mjpeg",
+            "pipe:1",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format_err!("unable to run ffmpeg: {}", e))?;
+
+    // Write the whole frame before reading anything. This can't deadlock: ffmpeg doesn't write
+    // its (small) output until it's read its input to EOF.
+    {
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(&annex_b)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() || output.stdout.is_empty() {
+        bail!(
+            "ffmpeg failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
@@ -0,0 +1,444 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Built-in TLS termination.
+//!
+//! If the `tls_addr` config key is set, `moonfire-nvr run` serves HTTPS on that address in
+//! addition to plain HTTP on `--http-addr`. The certificate comes from one of two places:
+//!
+//! *   PEM files named by `tls_cert_path` and `tls_key_path`. These are read at startup.
+//! *   an ACME certificate authority such as Let's Encrypt, for the domain `tls_acme_domain`
+//!     with account email `tls_acme_email`. This uses the `HTTP-01` challenge, so the plain
+//!     HTTP server must be reachable as `http://<domain>/` (port 80). Issued certificates are
+//!     kept in the `acme` subdirectory of the database directory and renewed in the background;
+//!     renewals take effect without a restart. This requires the `acme` feature, which is on
+//!     by default.
+
+use failure::{bail, format_err, Error};
+use fnv::FnvHashMap;
+use futures::sink::SinkExt;
+use parking_lot::{Mutex, RwLock};
+use rustls::internal::pemfile;
+use rustls::sign::CertifiedKey;
+use rustls::{ClientHello, ResolvesServerCert};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+
+const DEFAULT_ACME_DIRECTORY_URL: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Renew ACME certificates with fewer than this many days of validity left.
+const RENEW_DAYS: i64 = 30;
+
+/// How often to check if the ACME certificate needs renewal.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// How long to wait after a failed ACME check or order before trying again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How long a client may take to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait between polls while the certificate authority validates a challenge or
+/// issues a certificate.
+const ACME_POLL_MILLIS: u64 = 5000;
+
+#[derive(Debug, Eq, PartialEq)]
+enum Source {
+    Files {
+        cert: PathBuf,
+        key: PathBuf,
+    },
+    Acme {
+        domain: String,
+        email: String,
+        directory_url: String,
+    },
+}
+
+/// TLS configuration, as read from the `config` table.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Config {
+    pub addr: SocketAddr,
+    source: Source,
+}
+
+impl Config {
+    /// Reads the configuration, returning `None` if TLS is disabled (`tls_addr` is unset).
+    pub fn new(db: &db::LockedDatabase) -> Result<Option<Self>, Error> {
+        Self::from_keys(|k| db.get_config(k))
+    }
+
+    fn from_keys<F>(get: F) -> Result<Option<Self>, Error>
+    where
+        F: Fn(&str) -> Result<Option<String>, Error>,
+    {
+        let addr = match get("tls_addr")? {
+            None => return Ok(None),
+            Some(a) => {
+                SocketAddr::from_str(&a).map_err(|e| format_err!("bad tls_addr {:?}: {}", a, e))?
+            }
+        };
+        let source = match (
+            get("tls_cert_path")?,
+            get("tls_key_path")?,
+            get("tls_acme_domain")?,
+        ) {
+            (Some(cert), Some(key), None) => Source::Files {
+                cert: cert.into(),
+                key: key.into(),
+            },
+            (None, None, Some(_)) if !cfg!(feature = "acme") => {
+                bail!("tls_acme_domain requires building with the acme feature")
+            }
+            (None, None, Some(domain)) => Source::Acme {
+                domain,
+                email: get("tls_acme_email")?
+                    .ok_or_else(|| format_err!("tls_acme_domain requires tls_acme_email"))?,
+                directory_url: get("tls_acme_directory_url")?
+                    .unwrap_or_else(|| DEFAULT_ACME_DIRECTORY_URL.to_owned()),
+            },
+            _ => {
+                bail!("tls_addr requires either tls_cert_path and tls_key_path or tls_acme_domain")
+            }
+        };
+        Ok(Some(Config { addr, source }))
+    }
+
+    /// Prepares to serve TLS.
+    ///
+    /// With certificate files, fails if they can't be loaded. With ACME, the certificate is
+    /// loaded or obtained later by `Acme::run`; until then, handshakes fail.
+    pub fn start(self, db_dir: &Path) -> Result<Started, Error> {
+        let resolver = Arc::new(CertResolver::default());
+        let acme = match self.source {
+            Source::Files { cert, key } => {
+                let k =
+                    certified_key(&std::fs::read(&cert)?, &std::fs::read(&key)?).map_err(|e| {
+                        format_err!(
+                            "unable to load TLS certificate {} and key {}: {}",
+                            cert.display(),
+                            key.display(),
+                            e
+                        )
+                    })?;
+                *resolver.0.write() = Some(k);
+                None
+            }
+            Source::Acme {
+                domain,
+                email,
+                directory_url,
+            } => {
+                let persist_dir = db_dir.join("acme");
+                std::fs::create_dir_all(&persist_dir)?;
+                Some(Acme {
+                    domain,
+                    email,
+                    directory_url,
+                    persist_dir,
+                    resolver: resolver.clone(),
+                    challenges: Arc::new(Challenges::default()),
+                })
+            }
+        };
+        let mut c = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+        c.cert_resolver = resolver;
+        c.set_protocols(&[b"http/1.1".to_vec()]);
+        Ok(Started {
+            addr: self.addr,
+            acceptor: TlsAcceptor::from(Arc::new(c)),
+            acme,
+        })
+    }
+}
+
+pub struct Started {
+    pub addr: SocketAddr,
+    pub acceptor: TlsAcceptor,
+
+    /// The ACME certificate manager, to be run on its own thread, if configured.
+    pub acme: Option<Acme>,
+}
+
+/// Supplies the current certificate to handshakes, allowing it to be replaced on renewal.
+#[derive(Default)]
+struct CertResolver(RwLock<Option<CertifiedKey>>);
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        self.0.read().clone()
+    }
+}
+
+/// Outstanding ACME `HTTP-01` challenges, for `web.rs` to answer.
+#[derive(Default)]
+pub struct Challenges(Mutex<FnvHashMap<String, String>>);
+
+impl Challenges {
+    /// Returns the key authorization to serve at `/.well-known/acme-challenge/<token>`.
+    pub fn get(&self, token: &str) -> Option<String> {
+        self.0.lock().get(token).cloned()
+    }
+}
+
+/// Parses a PEM-encoded certificate chain and PKCS#8 or RSA private key.
+fn certified_key(cert_pem: &[u8], key_pem: &[u8]) -> Result<CertifiedKey, Error> {
+    let certs =
+        pemfile::certs(&mut &cert_pem[..]).map_err(|()| format_err!("unparseable certificate"))?;
+    if certs.is_empty() {
+        bail!("no certificates found");
+    }
+    let mut keys = pemfile::pkcs8_private_keys(&mut &key_pem[..])
+        .map_err(|()| format_err!("unparseable private key"))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut &key_pem[..])
+            .map_err(|()| format_err!("unparseable private key"))?;
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| format_err!("no private key found"))?;
+    let key = rustls::sign::any_supported_type(&key)
+        .map_err(|()| format_err!("unsupported private key type"))?;
+    Ok(CertifiedKey::new(certs, Arc::new(key)))
+}
+
+#[cfg(feature = "acme")]
+fn acme_err(e: acme_lib::Error) -> Error {
+    format_err!("{}", e)
+}
+
+/// Obtains and renews a certificate from an ACME certificate authority.
+#[cfg_attr(not(feature = "acme"), allow(dead_code))]
+pub struct Acme {
+    domain: String,
+    email: String,
+    directory_url: String,
+    persist_dir: PathBuf,
+    resolver: Arc<CertResolver>,
+    challenges: Arc<Challenges>,
+}
+
+impl Acme {
+    pub fn challenges(&self) -> Arc<Challenges> {
+        self.challenges.clone()
+    }
+
+    /// Runs until `shutdown_rx` is signalled or dropped, checking the certificate periodically.
+    pub fn run(self, shutdown_rx: mpsc::Receiver<()>) {
+        loop {
+            let interval = match self.pass() {
+                Ok(()) => CHECK_INTERVAL,
+                Err(e) => {
+                    warn!("tls: unable to get certificate for {}: {}", self.domain, e);
+                    RETRY_INTERVAL
+                }
+            };
+            match shutdown_rx.recv_timeout(interval) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                _ => return,
+            }
+        }
+    }
+
+    #[cfg(not(feature = "acme"))]
+    fn pass(&self) -> Result<(), Error> {
+        bail!("built without the acme feature")
+    }
+
+    /// Loads the saved certificate, first ordering a new one if it's missing or expiring soon.
+    #[cfg(feature = "acme")]
+    fn pass(&self) -> Result<(), Error> {
+        let persist = acme_lib::persist::FilePersist::new(&self.persist_dir);
+        let dir = acme_lib::Directory::from_url(
+            persist,
+            acme_lib::DirectoryUrl::Other(&self.directory_url),
+        )
+        .map_err(acme_err)?;
+        let acc = dir.account(&self.email).map_err(acme_err)?;
+        if let Some(c) = acc.certificate(&self.domain).map_err(acme_err)? {
+            let days_left = c.valid_days_left();
+            if days_left > RENEW_DAYS {
+                debug!(
+                    "tls: certificate for {} valid for {} more days",
+                    self.domain, days_left
+                );
+                return self.install(&c);
+            }
+            info!(
+                "tls: certificate for {} has {} days left; renewing",
+                self.domain, days_left
+            );
+        } else {
+            info!("tls: requesting certificate for {}", self.domain);
+        }
+        let mut ord_new = acc.new_order(&self.domain, &[]).map_err(acme_err)?;
+        let ord_csr = loop {
+            if let Some(o) = ord_new.confirm_validations() {
+                break o;
+            }
+            for a in ord_new.authorizations().map_err(acme_err)? {
+                if !a.need_challenge() {
+                    continue;
+                }
+                let chall = a.http_challenge();
+                let token = chall.http_token().to_owned();
+                self.challenges
+                    .0
+                    .lock()
+                    .insert(token.clone(), chall.http_proof());
+                let r = chall.validate(ACME_POLL_MILLIS);
+                self.challenges.0.lock().remove(&token);
+                r.map_err(acme_err)?;
+            }
+            ord_new.refresh().map_err(acme_err)?;
+        };
+        let pkey = acme_lib::create_p384_key();
+        let ord_cert = ord_csr
+            .finalize_pkey(pkey, ACME_POLL_MILLIS)
+            .map_err(acme_err)?;
+        let c = ord_cert.download_and_save_cert().map_err(acme_err)?;
+        info!(
+            "tls: obtained certificate for {}, valid for {} days",
+            self.domain,
+            c.valid_days_left()
+        );
+        self.install(&c)
+    }
+
+    #[cfg(feature = "acme")]
+    fn install(&self, c: &acme_lib::Certificate) -> Result<(), Error> {
+        let k = certified_key(c.certificate().as_bytes(), c.private_key().as_bytes())?;
+        *self.resolver.0.write() = Some(k);
+        Ok(())
+    }
+}
+
+/// Accepts TLS connections for a hyper server.
+///
+/// Handshakes run on their own tasks so that a slow client doesn't hold up others.
+pub fn incoming(
+    mut listener: TcpListener,
+    acceptor: TlsAcceptor,
+) -> impl hyper::server::accept::Accept<Conn = TlsStream<TcpStream>, Error = std::io::Error> {
+    let (tx, rx) = futures::channel::mpsc::channel(16);
+    tokio::spawn(async move {
+        loop {
+            let (s, peer) = match listener.accept().await {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("tls: accept failed: {}", e);
+                    tokio::time::delay_for(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            if tx.is_closed() {
+                return;
+            }
+            if let Err(e) = s.set_nodelay(true) {
+                debug!("tls: unable to set TCP_NODELAY for {}: {}", peer, e);
+            }
+            let acceptor = acceptor.clone();
+            let mut tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(s)).await {
+                    Ok(Ok(s)) => {
+                        let _ = tx.send(Ok(s)).await;
+                    }
+                    Ok(Err(e)) => debug!("tls: handshake with {} failed: {}", peer, e),
+                    Err(_) => debug!("tls: handshake with {} timed out", peer),
+                }
+            });
+        }
+    });
+    hyper::server::accept::from_stream(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, Source};
+    use std::collections::HashMap;
+
+    fn config(keys: &[(&str, &str)]) -> Result<Option<Config>, failure::Error> {
+        let keys: HashMap<&str, &str> = keys.iter().cloned().collect();
+        Config::from_keys(|k| Ok(keys.get(k).map(|v| (*v).to_owned())))
+    }
+
+    #[test]
+    fn parse_config() {
+        assert_eq!(config(&[]).unwrap(), None);
+        assert_eq!(
+            config(&[
+                ("tls_addr", "0.0.0.0:443"),
+                ("tls_cert_path", "/etc/nvr/cert.pem"),
+                ("tls_key_path", "/etc/nvr/key.pem"),
+            ])
+            .unwrap()
+            .unwrap()
+            .source,
+            Source::Files {
+                cert: "/etc/nvr/cert.pem".into(),
+                key: "/etc/nvr/key.pem".into(),
+            }
+        );
+        assert_eq!(
+            config(&[
+                ("tls_addr", "[::]:443"),
+                ("tls_acme_domain", "nvr.example.com"),
+                ("tls_acme_email", "admin@example.com"),
+            ])
+            .unwrap()
+            .unwrap()
+            .source,
+            Source::Acme {
+                domain: "nvr.example.com".to_owned(),
+                email: "admin@example.com".to_owned(),
+                directory_url: super::DEFAULT_ACME_DIRECTORY_URL.to_owned(),
+            }
+        );
+        config(&[("tls_addr", "0.0.0.0:443")]).unwrap_err();
+        config(&[("tls_addr", "nope"), ("tls_acme_domain", "a")]).unwrap_err();
+        config(&[("tls_addr", "0.0.0.0:443"), ("tls_acme_domain", "a")]).unwrap_err();
+        config(&[
+            ("tls_addr", "0.0.0.0:443"),
+            ("tls_cert_path", "c"),
+            ("tls_key_path", "k"),
+            ("tls_acme_domain", "a"),
+        ])
+        .unwrap_err();
+    }
+}
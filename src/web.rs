@@ -28,17 +28,32 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::body::Body;
+use crate::admission;
+use crate::body::{Body, BodyStream, BoxedError, Chunk};
+use crate::bufpool;
+use crate::events;
+use crate::evidence;
+#[cfg(feature = "graphql")]
+use crate::graphql;
+use crate::health;
+use crate::hls;
 use crate::json;
+use crate::logs;
 use crate::mp4;
+use crate::oidc;
+use crate::onvif;
+use crate::saml;
+use crate::talkback;
+use crate::thumbnail;
+use crate::tls;
 use base::clock::Clocks;
 use base::{bail_t, strutil, ErrorKind};
 use bytes::Bytes;
 use bytes::{BufMut, BytesMut};
 use core::borrow::Borrow;
 use core::str::FromStr;
-use db::dir::SampleFileDir;
-use db::{auth, recording};
+use db::dir::StreamDirs;
+use db::{auth, recording, writer};
 use failure::{bail, format_err, Error};
 use fnv::FnvHashMap;
 use futures::sink::SinkExt;
@@ -46,17 +61,20 @@ use futures::stream::StreamExt;
 use http::header::{self, HeaderValue};
 use http::{status::StatusCode, Request, Response};
 use http_serve::dir::FsDir;
-use log::{debug, info, warn};
 use memchr::memchr;
 use nom::bytes::complete::{tag, take_while1};
 use nom::combinator::{all_consuming, map, map_res, opt};
 use nom::sequence::{preceded, tuple};
 use nom::IResult;
 use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
 use std::net::IpAddr;
 use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio_tungstenite::tungstenite;
+use tracing::{debug, info, warn};
+use tracing_futures::Instrument;
 use url::form_urlencoded;
 use uuid::Uuid;
 
@@ -66,19 +84,69 @@ enum Path {
     Request,                                          // "/api/request"
     InitSegment([u8; 20], bool),                      // "/api/init/<sha1>.mp4{.txt}"
     Camera(Uuid),                                     // "/api/cameras/<uuid>/"
+    CameraPtz(Uuid),                                  // "/api/cameras/<uuid>/ptz"
+    CameraCoverage(Uuid),                             // "/api/cameras/<uuid>/coverage"
+    CameraPrivacy(Uuid),                              // "/api/cameras/<uuid>/privacy"
+    CameraTalkback(Uuid),                             // "/api/cameras/<uuid>/talkback"
     Signals,                                          // "/api/signals"
+    NewSignal,                                        // "/api/signals/new"
+    Signal(u32),                                      // "/api/signals/<id>"
+    Protect,                                          // "/api/protect"
+    Notes,                                            // "/api/notes"
+    Note(i64),                                        // "/api/notes/<id>"
+    Bookmarks,                                        // "/api/bookmarks"
+    Bookmark(i64),                                    // "/api/bookmarks/<id>"
+    Exports,                                          // "/api/exports"
+    ExportVerify,                                     // "/api/exports/verify"
+    Export(i64),                                      // "/api/exports/<id>"
+    ExportDownload(i64),                              // "/api/exports/<id>/download"
+    Search,                                           // "/api/search"
+    RetentionSimulation,                              // "/api/retentionSimulation"
+    Graphql,                                          // "/api/graphql"
+    Events,                                           // "/api/events"
+    Health,                                           // "/api/health"
+    HealthLive,                                       // "/api/health/live"
+    HealthReady,                                      // "/api/health/ready"
+    Logs,                                             // "/api/logs"
+    LogFilter,                                        // "/api/logs/filter"
+    UserNotifications,                                // "/api/user/notifications"
+    UserSessions,                                     // "/api/user/sessions"
+    UserSession(i32),                                 // "/api/user/sessions/<id>"
+    Heatmap,                                          // "/api/heatmap"
+    Timeline,                                         // "/api/timeline"
+    Playback,                                         // "/api/playback"
+    SampleFiles,                                      // "/api/sampleFiles"
     StreamRecordings(Uuid, db::StreamType),           // "/api/cameras/<uuid>/<type>/recordings"
+    StreamThumbnail(Uuid, db::StreamType, i32),       // ".../<type>/recordings/<id>/thumbnail"
     StreamViewMp4(Uuid, db::StreamType, bool),        // "/api/cameras/<uuid>/<type>/view.mp4{.txt}"
     StreamViewMp4Segment(Uuid, db::StreamType, bool), // "/api/cameras/<uuid>/<type>/view.m4s{.txt}"
     StreamLiveMp4Segments(Uuid, db::StreamType),      // "/api/cameras/<uuid>/<type>/live.m4s"
+    StreamHlsPlaylist(Uuid, db::StreamType),          // "/api/cameras/<uuid>/<type>/live.m3u8"
+    StreamHlsSegment(Uuid, db::StreamType, u64),      // ".../<type>/live/<seq>.m4s"
+    StreamMjpeg(Uuid, db::StreamType),                // "/api/cameras/<uuid>/<type>/mjpeg"
+    StreamRetentionPreview(Uuid, db::StreamType),     // ".../<type>/retentionPreview"
+    StreamDetections(Uuid, db::StreamType),           // "/api/cameras/<uuid>/<type>/detections"
+    StreamGaps(Uuid, db::StreamType),                 // "/api/cameras/<uuid>/<type>/gaps"
+    StreamPrivacyMask(Uuid, db::StreamType),          // ".../<type>/privacyMask"
+    StreamReplica(Uuid, db::StreamType),              // "/api/cameras/<uuid>/<type>/replica"
+    StreamReplicaData(Uuid, db::StreamType, i32),     // ".../<type>/replica/<id>.bin"
     Login,                                            // "/api/login"
+    SamlLogin,                                        // "/api/login/saml"
+    SamlAcs,                                          // "/api/login/saml/acs"
+    OidcLogin,                                        // "/api/login/oidc"
+    OidcCallback,                                     // "/api/login/oidc/callback"
     Logout,                                           // "/api/logout"
+    AcmeChallenge(String),                            // "/.well-known/acme-challenge/<token>"
     Static,                                           // (anything that doesn't start with "/api/")
     NotFound,
 }
 
 impl Path {
     fn decode(path: &str) -> Self {
+        const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+        if path.starts_with(ACME_CHALLENGE_PREFIX) {
+            return Path::AcmeChallenge(path[ACME_CHALLENGE_PREFIX.len()..].to_owned());
+        }
         if !path.starts_with("/api/") {
             return Path::Static;
         }
@@ -88,11 +156,73 @@ impl Path {
         }
         match path {
             "/login" => return Path::Login,
+            "/login/saml" => return Path::SamlLogin,
+            "/login/saml/acs" => return Path::SamlAcs,
+            "/login/oidc" => return Path::OidcLogin,
+            "/login/oidc/callback" => return Path::OidcCallback,
             "/logout" => return Path::Logout,
             "/request" => return Path::Request,
             "/signals" => return Path::Signals,
+            "/signals/new" => return Path::NewSignal,
+            "/protect" => return Path::Protect,
+            "/notes" => return Path::Notes,
+            "/bookmarks" => return Path::Bookmarks,
+            "/exports" => return Path::Exports,
+            "/exports/verify" => return Path::ExportVerify,
+            "/search" => return Path::Search,
+            "/retentionSimulation" => return Path::RetentionSimulation,
+            "/graphql" => return Path::Graphql,
+            "/events" => return Path::Events,
+            "/health" => return Path::Health,
+            "/health/live" => return Path::HealthLive,
+            "/health/ready" => return Path::HealthReady,
+            "/logs" => return Path::Logs,
+            "/logs/filter" => return Path::LogFilter,
+            "/user/notifications" => return Path::UserNotifications,
+            "/user/sessions" => return Path::UserSessions,
+            "/heatmap" => return Path::Heatmap,
+            "/timeline" => return Path::Timeline,
+            "/playback" => return Path::Playback,
+            "/sampleFiles" => return Path::SampleFiles,
             _ => {}
         };
+        if path.starts_with("/signals/") {
+            return match u32::from_str(&path["/signals/".len()..]) {
+                Ok(id) => Path::Signal(id),
+                Err(_) => Path::NotFound,
+            };
+        }
+        if path.starts_with("/notes/") {
+            return match i64::from_str(&path["/notes/".len()..]) {
+                Ok(id) => Path::Note(id),
+                Err(_) => Path::NotFound,
+            };
+        }
+        if path.starts_with("/bookmarks/") {
+            return match i64::from_str(&path["/bookmarks/".len()..]) {
+                Ok(id) => Path::Bookmark(id),
+                Err(_) => Path::NotFound,
+            };
+        }
+        if path.starts_with("/exports/") {
+            let rest = &path["/exports/".len()..];
+            let (id, download) = match rest.find('/') {
+                None => (rest, false),
+                Some(i) if &rest[i..] == "/download" => (&rest[..i], true),
+                Some(_) => return Path::NotFound,
+            };
+            return match (i64::from_str(id), download) {
+                (Ok(id), false) => Path::Export(id),
+                (Ok(id), true) => Path::ExportDownload(id),
+                (Err(_), _) => Path::NotFound,
+            };
+        }
+        if path.starts_with("/user/sessions/") {
+            return match i32::from_str(&path["/user/sessions/".len()..]) {
+                Ok(id) => Path::UserSession(id),
+                Err(_) => Path::NotFound,
+            };
+        }
         if path.starts_with("/init/") {
             let (debug, path) = if path.ends_with(".txt") {
                 (true, &path[0..path.len() - 4])
@@ -129,6 +259,18 @@ impl Path {
         if path.is_empty() {
             return Path::Camera(uuid);
         }
+        if path == "ptz" {
+            return Path::CameraPtz(uuid);
+        }
+        if path == "coverage" {
+            return Path::CameraCoverage(uuid);
+        }
+        if path == "privacy" {
+            return Path::CameraPrivacy(uuid);
+        }
+        if path == "talkback" {
+            return Path::CameraTalkback(uuid);
+        }
 
         let slash = match path.find('/') {
             None => {
@@ -151,9 +293,104 @@ impl Path {
             "/view.m4s" => Path::StreamViewMp4Segment(uuid, type_, false),
             "/view.m4s.txt" => Path::StreamViewMp4Segment(uuid, type_, true),
             "/live.m4s" => Path::StreamLiveMp4Segments(uuid, type_),
+            "/live.m3u8" => Path::StreamHlsPlaylist(uuid, type_),
+            "/mjpeg" => Path::StreamMjpeg(uuid, type_),
+            "/retentionPreview" => Path::StreamRetentionPreview(uuid, type_),
+            "/detections" => Path::StreamDetections(uuid, type_),
+            "/gaps" => Path::StreamGaps(uuid, type_),
+            "/privacyMask" => Path::StreamPrivacyMask(uuid, type_),
+            "/replica" => Path::StreamReplica(uuid, type_),
+            _ if path.starts_with("/live/") && path.ends_with(".m4s") => {
+                let seq = &path["/live/".len()..path.len() - ".m4s".len()];
+                match u64::from_str(seq) {
+                    Ok(seq) => Path::StreamHlsSegment(uuid, type_, seq),
+                    _ => Path::NotFound,
+                }
+            }
+            _ if path.starts_with("/replica/") && path.ends_with(".bin") => {
+                let id = &path["/replica/".len()..path.len() - ".bin".len()];
+                match i32::from_str(id) {
+                    Ok(id) if id >= 0 => Path::StreamReplicaData(uuid, type_, id),
+                    _ => Path::NotFound,
+                }
+            }
+            _ if path.starts_with("/recordings/") && path.ends_with("/thumbnail") => {
+                let id = &path["/recordings/".len()..path.len() - "/thumbnail".len()];
+                match i32::from_str(id) {
+                    Ok(id) if id >= 0 => Path::StreamThumbnail(uuid, type_, id),
+                    _ => Path::NotFound,
+                }
+            }
             _ => Path::NotFound,
         }
     }
+
+    /// Returns the scheduling priority of a request, or `None` for requests which should never
+    /// be delayed or shed, such as login, configuration changes, and health checks.
+    fn priority(&self, method: &http::Method) -> Option<admission::Priority> {
+        match *method {
+            http::Method::GET | http::Method::HEAD => {}
+            _ => return None,
+        }
+        match *self {
+            Path::StreamLiveMp4Segments(..)
+            | Path::StreamHlsPlaylist(..)
+            | Path::StreamHlsSegment(..)
+            | Path::StreamMjpeg(..) => Some(admission::Priority::Live),
+            Path::StreamRecordings(..)
+            | Path::StreamThumbnail(..)
+            | Path::StreamViewMp4Segment(..)
+            | Path::StreamRetentionPreview(..)
+            | Path::StreamDetections(..)
+            | Path::StreamGaps(..)
+            | Path::Search
+            | Path::Heatmap
+            | Path::Timeline
+            | Path::Playback
+            | Path::CameraCoverage(..)
+            | Path::RetentionSimulation
+            | Path::Graphql
+            | Path::SampleFiles => Some(admission::Priority::Query),
+            Path::StreamViewMp4(..) | Path::StreamReplica(..) | Path::StreamReplicaData(..) => {
+                Some(admission::Priority::Export)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// How often `/api/events` sends a comment when there are no events, so proxies don't time out
+/// the connection and the server notices disconnected clients.
+const EVENTS_KEEPALIVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Relays events to an `/api/events` client in `text/event-stream` format until it disconnects.
+async fn relay_events(
+    caller: Caller,
+    mut rx: tokio::sync::broadcast::Receiver<Arc<events::Message>>,
+    mut tx: futures::channel::mpsc::Sender<Result<Chunk, BoxedError>>,
+) {
+    use tokio::sync::broadcast::RecvError;
+    loop {
+        let chunk = match tokio::time::timeout(EVENTS_KEEPALIVE_INTERVAL, rx.recv()).await {
+            Err(_) => ": keepalive\n\n".to_owned(),
+            Ok(Ok(m)) => {
+                if let Some(id) = m.camera_id {
+                    if !caller.may_access_camera(id) {
+                        continue;
+                    }
+                }
+                format!("event: {}\ndata: {}\n\n", m.type_name, &m.json)
+            }
+            Ok(Err(RecvError::Lagged(n))) => format!(
+                "event: lagged\ndata: {{\"type\":\"lagged\",\"missed\":{}}}\n\n",
+                n
+            ),
+            Ok(Err(RecvError::Closed)) => return,
+        };
+        if tx.send(Ok(chunk.into())).await.is_err() {
+            return; // client disconnected.
+        }
+    }
 }
 
 fn plain_response<B: Into<Body>>(status: http::StatusCode, body: B) -> Response<Body> {
@@ -181,6 +418,7 @@ fn from_base_error(err: base::Error) -> Response<Body> {
         ErrorKind::PermissionDenied | ErrorKind::Unauthenticated => StatusCode::UNAUTHORIZED,
         ErrorKind::InvalidArgument => StatusCode::BAD_REQUEST,
         ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        ErrorKind::FailedPrecondition => StatusCode::CONFLICT,
         _ => StatusCode::INTERNAL_SERVER_ERROR,
     };
     plain_response(status_code, err.to_string())
@@ -250,9 +488,78 @@ impl FromStr for Segments {
     }
 }
 
-struct Caller {
-    permissions: db::Permissions,
+/// A live segment's `.m4s` media segment and the metadata needed to play it.
+pub(crate) struct LiveChunk {
+    /// The start of the recording holding this segment.
+    pub(crate) start: recording::Time,
+
+    /// The hex-encoded SHA-1 of the segment's video sample entry.
+    pub(crate) vse_id: String,
+    pub(crate) vse: Arc<db::VideoSampleEntry>,
+    pub(crate) mp4: mp4::File,
+}
+
+/// Builds a `live.m4s` WebSocket message holding a live segment's media segment.
+async fn live_chunk_message(
+    open_id: u32,
+    live: &db::LiveSegment,
+    c: LiveChunk,
+) -> Result<Vec<u8>, Error> {
+    use http_serve::Entity;
+    let mut hdrs = header::HeaderMap::new();
+    c.mp4.add_headers(&mut hdrs);
+    let mime_type = hdrs.get(header::CONTENT_TYPE).unwrap();
+    let hdr = format!(
+        "Content-Type: {}\r\n\
+        X-Recording-Start: {}\r\n\
+        X-Recording-Id: {}.{}\r\n\
+        X-Time-Range: {}-{}\r\n\
+        X-Video-Sample-Entry-Sha1: {}\r\n\r\n",
+        mime_type.to_str().unwrap(),
+        c.start.0,
+        open_id,
+        live.recording,
+        live.off_90k.start,
+        live.off_90k.end,
+        &c.vse_id
+    );
+    let mut v = hdr.into_bytes();
+    c.mp4.append_into_vec(&mut v).await?;
+    Ok(v)
+}
+
+pub(crate) struct Caller {
+    pub(crate) permissions: db::Permissions,
     session: Option<json::Session>,
+
+    /// The id of the authenticating session, if any.
+    session_id: Option<i32>,
+
+    /// The authenticated user, if any.
+    user_id: Option<i32>,
+
+    /// If set, the ids of the only cameras the caller may access.
+    cameras: Option<BTreeSet<i32>>,
+}
+
+impl Caller {
+    pub(crate) fn may_access_camera(&self, camera_id: i32) -> bool {
+        self.cameras
+            .as_ref()
+            .map(|c| c.contains(&camera_id))
+            .unwrap_or(true)
+    }
+
+    /// Rejects requests which would affect cameras beyond the ones the caller may access.
+    fn require_all_cameras(&self) -> Result<(), Response<Body>> {
+        if self.cameras.is_some() {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "access to all cameras required",
+            ));
+        }
+        Ok(())
+    }
 }
 
 type ResponseResult = Result<Response<Body>, Response<Body>>;
@@ -269,6 +576,413 @@ fn serve_json<T: serde::ser::Serialize>(req: &Request<hyper::Body>, out: &T) ->
     Ok(resp)
 }
 
+/// Writes the `GET /api/cameras/<uuid>/<stream>/recordings` response as each recording is
+/// listed, rather than building the whole list in memory first. With a `limit`, lists one page
+/// starting at `min_id` and includes a `continue` token for the next page, if any.
+fn write_recordings<W: std::io::Write>(
+    w: &mut W,
+    db: &db::LockedDatabase,
+    stream_id: i32,
+    time: Range<recording::Time>,
+    split: recording::Duration,
+    limit: Option<usize>,
+    min_id: i32,
+) -> Result<(), Error> {
+    // There are likely very few video sample entries for a given stream in a given day, so
+    // representing with an unordered Vec (and having O(n) insert-if-absent) is probably better
+    // than dealing with a HashSet's code bloat.
+    let mut video_sample_entries = Vec::new();
+    w.write_all(b"{\"recordings\":[")?;
+    let next = {
+        let mut first = true;
+        let mut f = |row: &db::ListAggregatedRecordingsRow| -> Result<(), Error> {
+            if !first {
+                w.write_all(b",")?;
+            }
+            first = false;
+            let end = row.ids.end - 1; // in api, ids are inclusive.
+            serde_json::to_writer(
+                &mut *w,
+                &json::Recording {
+                    start_id: row.ids.start,
+                    end_id: if end == row.ids.start {
+                        None
+                    } else {
+                        Some(end)
+                    },
+                    start_time_90k: row.time.start.0,
+                    end_time_90k: row.time.end.0,
+                    sample_file_bytes: row.sample_file_bytes,
+                    bitrate_bps: bitrate_bps(
+                        row.sample_file_bytes,
+                        (row.time.end - row.time.start).0,
+                    ),
+                    open_id: row.open_id,
+                    first_uncommitted: row.first_uncommitted,
+                    video_samples: row.video_samples,
+                    video_sample_entry_id: row.video_sample_entry_id.to_string(),
+                    growing: row.growing,
+                },
+            )?;
+            if !video_sample_entries.contains(&row.video_sample_entry_id) {
+                video_sample_entries.push(row.video_sample_entry_id);
+            }
+            Ok(())
+        };
+        match limit {
+            None => {
+                db.list_aggregated_recordings(stream_id, time, split, &mut f)?;
+                None
+            }
+            Some(l) => {
+                db.list_aggregated_recordings_page(stream_id, time, split, min_id, l, &mut f)?
+            }
+        }
+    };
+    w.write_all(b"],\"videoSampleEntries\":")?;
+    serde_json::to_writer(
+        &mut *w,
+        &json::VideoSampleEntries(db, &video_sample_entries),
+    )?;
+    if let Some(next) = next {
+        w.write_all(b",\"continue\":")?;
+        serde_json::to_writer(&mut *w, &encode_continue(next))?;
+    }
+    w.write_all(b"}")?;
+    Ok(())
+}
+
+/// Encodes the recording id at which the next page of `GET .../recordings` starts as an opaque
+/// `continue` token.
+fn encode_continue(min_id: i32) -> String {
+    base64::encode_config(format!("r{}", min_id), base64::URL_SAFE_NO_PAD)
+}
+
+/// Decodes a token from `encode_continue`.
+fn decode_continue(token: &str) -> Option<i32> {
+    let raw = base64::decode_config(token, base64::URL_SAFE_NO_PAD).ok()?;
+    let raw = std::str::from_utf8(&raw).ok()?;
+    if !raw.starts_with('r') {
+        return None;
+    }
+    i32::from_str(&raw[1..]).ok().filter(|&id| id >= 0)
+}
+
+/// The default `duration90k` of `POST /api/protect`: 24 hours.
+const DEFAULT_PROTECT_DURATION_90K: i64 = 24 * 60 * 60 * recording::TIME_UNITS_PER_SEC;
+
+/// The default and maximum `limit` of `GET /api/search`.
+const DEFAULT_SEARCH_LIMIT: u32 = 50;
+const MAX_SEARCH_LIMIT: u32 = 1000;
+
+/// The maximum `limit` of `GET /api/cameras/<uuid>/<stream>/recordings`.
+const MAX_RECORDINGS_LIMIT: usize = 100_000;
+
+/// The maximum number of buckets in a `GET /api/timeline` range.
+const MAX_TIMELINE_BUCKETS: i64 = 10_000;
+
+/// The maximum number of streams and length of range of a `GET /api/playback` request.
+const MAX_PLAYBACK_STREAMS: usize = 16;
+const MAX_PLAYBACK_DURATION: recording::Duration = recording::Duration(24 * 60 * 60 * 90_000);
+
+/// Parses a `GET /api/playback` `stream` parameter of the form `<uuid>/<type>`.
+fn parse_playback_stream(s: &str) -> Option<(Uuid, db::StreamType)> {
+    let slash = s.find('/')?;
+    let uuid = Uuid::parse_str(&s[..slash]).ok()?;
+    let type_ = db::StreamType::parse(&s[slash + 1..])?;
+    Some((uuid, type_))
+}
+
+/// The default `minGap90k` and maximum range of `GET /api/cameras/<uuid>/<stream>/gaps`.
+const DEFAULT_MIN_GAP: recording::Duration = recording::Duration(90_000);
+const MAX_GAPS_DURATION: recording::Duration = recording::Duration(31 * 24 * 60 * 60 * 90_000);
+
+/// The default and maximum `limit` of `GET /api/cameras/<uuid>/<stream>/replica`.
+const DEFAULT_REPLICA_LIMIT: usize = 100;
+const MAX_REPLICA_LIMIT: usize = 1000;
+
+/// Sub stream gaps shorter than this aren't used to fill gaps in the main stream for
+/// `GET /api/cameras/<uuid>/coverage`, as switching streams so briefly would be jarring.
+const MIN_COVERAGE_FILL: recording::Duration = recording::Duration(90_000);
+
+/// A recording trimmed to a requested range, for `GET /api/playback` and
+/// `GET /api/cameras/<uuid>/coverage`.
+struct RangeSegment {
+    id: db::CompositeId,
+    open_id: u32,
+    video_sample_entry_id: i32,
+
+    /// The recording's start on the local clock.
+    local_start: recording::Time,
+    local_time_delta: recording::Duration,
+
+    /// The part of the recording within the range, relative to its start.
+    rel: Range<i32>,
+    bitrate_bps: i64,
+}
+
+impl RangeSegment {
+    /// Lists the given stream's recordings within `range` on the local clock, ordered by time.
+    fn list(
+        db: &db::LockedDatabase,
+        stream_id: i32,
+        range: &Range<recording::Time>,
+    ) -> Result<Vec<Self>, Error> {
+        let mut out = Vec::new();
+        db.list_recordings_by_time(stream_id, range.clone(), &mut |r| {
+            let delta = db
+                .get_local_time_delta(r.id)?
+                .unwrap_or(recording::Duration(0));
+            let local_start = r.start + delta;
+            if let Some(rel) = align_to_range(local_start, r.duration_90k, range) {
+                out.push(RangeSegment {
+                    id: r.id,
+                    open_id: r.open_id,
+                    video_sample_entry_id: r.video_sample_entry_id,
+                    local_start,
+                    local_time_delta: delta,
+                    rel,
+                    bitrate_bps: bitrate_bps(r.sample_file_bytes.into(), r.duration_90k.into()),
+                });
+            }
+            Ok(())
+        })?;
+        out.sort_by_key(|s| s.local_start);
+        Ok(out)
+    }
+
+    /// Returns the local clock times of the given part of the recording.
+    fn time(&self, rel: &Range<i32>) -> Range<recording::Time> {
+        self.local_start + recording::Duration(rel.start.into())
+            ..self.local_start + recording::Duration(rel.end.into())
+    }
+
+    fn to_json(
+        &self,
+        db: &db::LockedDatabase,
+        stream: Option<&'static str>,
+        rel: Range<i32>,
+    ) -> json::PlaybackSegment {
+        let vse = db
+            .video_sample_entries_by_id()
+            .get(&self.video_sample_entry_id)
+            .unwrap();
+        let time = self.time(&rel);
+        json::PlaybackSegment {
+            stream,
+            start_time_90k: time.start.0,
+            end_time_90k: time.end.0,
+            local_time_delta_90k: self.local_time_delta.0,
+            s: format!(
+                "{}@{}.{}-{}",
+                self.id.recording(),
+                self.open_id,
+                rel.start,
+                rel.end
+            ),
+            video_sample_entry_sha1: strutil::hex(&vse.sha1),
+            width: vse.width,
+            height: vse.height,
+            bitrate_bps: self.bitrate_bps,
+        }
+    }
+}
+
+/// Returns the average bitrate of a recording, in bits per second.
+fn bitrate_bps(sample_file_bytes: i64, duration_90k: i64) -> i64 {
+    if duration_90k <= 0 {
+        return 0;
+    }
+    sample_file_bytes * 8 * recording::TIME_UNITS_PER_SEC / duration_90k
+}
+
+/// Returns the parts of `r` not within `covered` (which must be sorted and non-overlapping),
+/// omitting parts shorter than `min`.
+fn uncovered(
+    r: Range<recording::Time>,
+    covered: &[Range<recording::Time>],
+    min: recording::Duration,
+) -> Vec<Range<recording::Time>> {
+    let mut out = Vec::new();
+    let mut start = r.start;
+    for c in covered {
+        if c.end <= start {
+            continue;
+        }
+        if c.start >= r.end {
+            break;
+        }
+        if c.start > start && c.start - start >= min {
+            out.push(start..c.start);
+        }
+        start = cmp::max(start, c.end);
+        if start >= r.end {
+            return out;
+        }
+    }
+    if r.end - start >= min {
+        out.push(start..r.end);
+    }
+    out
+}
+
+/// Returns the part of a recording, as a range relative to its start, which lies within
+/// `range`. `start` is the recording's start on the local clock.
+fn align_to_range(
+    start: recording::Time,
+    duration_90k: i32,
+    range: &Range<recording::Time>,
+) -> Option<Range<i32>> {
+    let rel_start = cmp::max(0, (range.start - start).0);
+    let rel_end = cmp::min(i64::from(duration_90k), (range.end - start).0);
+    if rel_start >= rel_end {
+        return None;
+    }
+    Some(rel_start as i32..rel_end as i32)
+}
+
+/// How many live segments may be queued for a `live.m4s` subscriber before further segments
+/// are dropped. With the typical one key frame per second, this is a few seconds of video.
+const LIVE_SEGMENT_QUEUE: usize = 4;
+
+/// The default and maximum `fps` of `GET /api/cameras/<uuid>/<stream>/mjpeg`. Each frame is a
+/// key frame decoded by an `ffmpeg` subprocess, so this is kept low.
+const DEFAULT_MJPEG_FPS: u32 = 1;
+const MAX_MJPEG_FPS: u32 = 2;
+
+/// The default and maximum `width` of `GET /api/cameras/<uuid>/<stream>/mjpeg`.
+const DEFAULT_MJPEG_WIDTH: u32 = 640;
+const MAX_MJPEG_WIDTH: u32 = 3840;
+
+/// The default and maximum `limit` of `GET /api/logs`.
+const DEFAULT_LOG_LIMIT: usize = 1000;
+const MAX_LOG_LIMIT: usize = 10_000;
+
+/// Runs the configured `protect_upload_command` in the background, passing it the protected
+/// time range and cameras via environment variables. The command is expected to fetch the
+/// recordings through this API and copy them off-site.
+fn spawn_upload(
+    command: String,
+    time: Range<recording::Time>,
+    cameras: &[Uuid],
+) -> Result<(), Error> {
+    let cameras: Vec<_> = cameras.iter().map(|c| c.to_string()).collect();
+    let mut child = std::process::Command::new(&command)
+        .env("MOONFIRE_START_TIME_90K", time.start.0.to_string())
+        .env("MOONFIRE_END_TIME_90K", time.end.0.to_string())
+        .env("MOONFIRE_CAMERAS", cameras.join(" "))
+        .stdin(std::process::Stdio::null())
+        .spawn()
+        .map_err(|e| format_err!("unable to run {:?}: {}", &command, e))?;
+    std::thread::Builder::new()
+        .name("protect-upload".to_owned())
+        .spawn(move || match child.wait() {
+            Ok(s) if s.success() => info!("{:?} succeeded", &command),
+            Ok(s) => warn!("{:?} failed: {}", &command, s),
+            Err(e) => warn!("unable to wait for {:?}: {}", &command, e),
+        })?;
+    Ok(())
+}
+
+/// Protects or unprotects the given streams' recordings which overlap `time`, then starts the
+/// upload command, if any, for the given cameras. Returns the number of recordings changed.
+/// This is the common path of `POST /api/protect` and `protect_recent`.
+fn protect_streams(
+    mut l: db::DatabaseGuard<base::clock::RealClocks>,
+    cameras: &[Uuid],
+    stream_ids: &[i32],
+    time: Range<recording::Time>,
+    protect: bool,
+    upload_command: Option<String>,
+) -> Result<usize, Error> {
+    let mut recordings = 0;
+    for &stream_id in stream_ids {
+        recordings += l.protect_recordings(stream_id, time.clone(), protect)?;
+    }
+    drop(l);
+    info!(
+        "{} {} recordings from {} to {} on cameras {:?}",
+        if protect { "protected" } else { "unprotected" },
+        recordings,
+        time.start,
+        time.end,
+        cameras
+    );
+    if let Some(c) = upload_command {
+        spawn_upload(c, time, cameras)?;
+    }
+    Ok(recordings)
+}
+
+/// Protects all cameras' recordings from the default `duration90k` of `POST /api/protect`, as
+/// that endpoint does when called with no parameters by a user who may access every camera, and
+/// runs the upload command if one is configured. This is the server's `SIGUSR1` handler, for
+/// triggers such as a GPIO input or panic button.
+pub fn protect_recent(db: &db::Database) -> Result<(), Error> {
+    let l = db.lock();
+    let now = recording::Time::new(db.clocks().realtime());
+    let upload_command = l.get_config("protect_upload_command")?;
+    let mut cameras = Vec::new();
+    let mut stream_ids = Vec::new();
+    for c in l.cameras_by_id().values() {
+        cameras.push(c.uuid);
+        stream_ids.extend(c.streams.iter().filter_map(|&s| s));
+    }
+    let start = now - recording::Duration(DEFAULT_PROTECT_DURATION_90K);
+    protect_streams(l, &cameras, &stream_ids, start..now, true, upload_command)?;
+    Ok(())
+}
+
+/// The `SameSite` attribute of session cookies set by `POST /api/login`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+}
+
+impl SameSite {
+    fn as_str(self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+        }
+    }
+}
+
+impl FromStr for SameSite {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(SameSite::Strict),
+            "lax" => Ok(SameSite::Lax),
+            _ => bail!("SameSite policy {:?} should be strict or lax", s),
+        }
+    }
+}
+
+/// Returns true if `method` may change server state, so the request needs cross-site request
+/// forgery protection.
+fn is_unsafe_method(method: &http::Method) -> bool {
+    match *method {
+        http::Method::GET | http::Method::HEAD | http::Method::OPTIONS => false,
+        _ => true,
+    }
+}
+
+/// Returns true iff `origin` (an `Origin` header value, such as `https://nvr.example.com`)
+/// names the same host and port as `host` (a `Host` header value).
+fn origin_matches_host(origin: &[u8], host: &[u8]) -> bool {
+    let origin = if origin.starts_with(b"https://") {
+        &origin["https://".len()..]
+    } else if origin.starts_with(b"http://") {
+        &origin["http://".len()..]
+    } else {
+        return false;
+    };
+    origin.eq_ignore_ascii_case(host)
+}
+
 fn csrf_matches(csrf: &str, session: auth::SessionHash) -> bool {
     let mut b64 = [0u8; 32];
     session.encode_base64(&mut b64);
@@ -276,8 +990,8 @@ fn csrf_matches(csrf: &str, session: auth::SessionHash) -> bool {
 }
 
 /// Extracts `s` cookie from the HTTP request. Does not authenticate.
-fn extract_sid(req: &Request<hyper::Body>) -> Option<auth::RawSessionId> {
-    let hdr = match req.headers().get(header::COOKIE) {
+fn extract_sid(hdrs: &header::HeaderMap) -> Option<auth::RawSessionId> {
+    let hdr = match hdrs.get(header::COOKIE) {
         None => return None,
         Some(c) => c,
     };
@@ -295,11 +1009,55 @@ fn extract_sid(req: &Request<hyper::Body>) -> Option<auth::RawSessionId> {
     None
 }
 
+/// Reads the given byte range of a recording's sample data, decrypting it if the recording's
+/// `flags` say it's encrypted.
+fn read_sample_data(
+    dir: &db::dir::SampleFileDir,
+    id: db::CompositeId,
+    flags: i32,
+    range: Range<u64>,
+) -> Result<Vec<u8>, Error> {
+    if (flags & db::RecordingFlags::Encrypted as i32) != 0 {
+        return dir.read_encrypted(id, range);
+    }
+    use std::os::unix::fs::FileExt;
+    let mut buf = vec![0u8; (range.end - range.start) as usize];
+    dir.open_file(id)?.read_exact_at(&mut buf, range.start)?;
+    Ok(buf)
+}
+
+/// Extracts an access token from the HTTP request's `Authorization: Bearer` header.
+/// Returns `Some(None)` if the header is present but malformed. Does not authenticate.
+fn extract_bearer(hdrs: &header::HeaderMap) -> Option<Option<auth::RawSessionId>> {
+    let hdr = hdrs.get(header::AUTHORIZATION)?;
+    let hdr = hdr.as_bytes();
+    if !hdr.starts_with(b"Bearer ") {
+        return None;
+    }
+    Some(auth::RawSessionId::decode_base64(&hdr[7..]).ok())
+}
+
 /// Extracts an `application/json` POST body from a request.
 ///
 /// This returns the request body as bytes rather than performing
 /// deserialization. Keeping the bytes allows the caller to use a `Deserialize`
 /// that borrows from the bytes.
+/// Returns true if this is a `GET` request for the start of the entity, rather than a `HEAD`
+/// request or a later `Range` request while playing the same file. Used to count each playback
+/// once.
+fn is_first_fetch(req: &Request<hyper::Body>) -> bool {
+    if *req.method() != http::method::Method::GET {
+        return false;
+    }
+    match req.headers().get(header::RANGE) {
+        None => true,
+        Some(r) => r
+            .to_str()
+            .map(|r| r.trim().starts_with("bytes=0-"))
+            .unwrap_or(false),
+    }
+}
+
 async fn extract_json_body(req: &mut Request<hyper::Body>) -> Result<Bytes, Response<Body>> {
     if *req.method() != http::method::Method::POST {
         return Err(plain_response(
@@ -321,21 +1079,151 @@ async fn extract_json_body(req: &mut Request<hyper::Body>) -> Result<Bytes, Resp
         .map_err(|e| internal_server_err(format_err!("unable to read request body: {}", e)))
 }
 
+/// Returns the domain (the `Host` header without port) to record with a new session.
+fn domain(req: &Request<::hyper::Body>) -> Result<Vec<u8>, Response<Body>> {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .ok_or_else(|| bad_req("missing Host header!"))?;
+    let host = host.as_bytes();
+    Ok(match memchr(b':', host) {
+        Some(colon) => &host[0..colon],
+        None => host,
+    }
+    .to_owned())
+}
+
+/// Returns a `Set-Cookie` header value for the given session.
+fn session_cookie(sid: &auth::RawSessionId, is_secure: bool, same_site: &str) -> HeaderValue {
+    let mut encoded = [0u8; 64];
+    base64::encode_config_slice(&sid, base64::STANDARD_NO_PAD, &mut encoded);
+    let suffix = format!(
+        "; HttpOnly;{} SameSite={}; Max-Age=2147483648; Path=/",
+        if is_secure { " Secure;" } else { "" },
+        same_site
+    );
+    let mut cookie = BytesMut::with_capacity("s=".len() + encoded.len() + suffix.len());
+    cookie.put(&b"s="[..]);
+    cookie.put(&encoded[..]);
+    cookie.put(suffix.as_bytes());
+    HeaderValue::from_maybe_shared(cookie.freeze()).expect("cookie can't have invalid bytes")
+}
+
+/// Returns true iff `path` is a path on this server, safe to redirect to after login.
+fn is_local_path(path: &str) -> bool {
+    path.starts_with('/') && !path.starts_with("//") && !path.contains('\\')
+}
+
 pub struct Config<'a> {
     pub db: Arc<db::Database>,
+
+    /// The directory from which to serve UI files. If absent, serves the files bundled into the
+    /// binary (with the `bundled-ui` feature) or none at all.
     pub ui_dir: Option<&'a std::path::Path>,
     pub trust_forward_hdrs: bool,
     pub time_zone_name: String,
     pub allow_unauthenticated_permissions: Option<db::Permissions>,
+
+    /// The `SameSite` policy of session cookies set on password login. SAML logins always use
+    /// `Lax`, as the cookie is set on a cross-site request.
+    pub cookie_same_site: SameSite,
+
+    /// Mark session cookies `Secure` even when the request isn't known to be over https.
+    pub secure_cookies: bool,
+
+    /// Origins (such as `https://nvr.example.com`) besides the server's own `Host` which may
+    /// make state-changing requests.
+    pub allowed_origins: Vec<String>,
+
+    /// Monitors of the syncers' command queues, by sample file directory id, for health checks.
+    pub syncer_queues: FnvHashMap<i32, db::writer::QueueMonitor>,
+
+    /// The streams' liveness, as reported by their streamers, for `/api/health`.
+    pub health: health::Monitor,
+
+    /// State changes reported by the database and streamers, for `/api/events`.
+    pub events: events::Bus,
+
+    /// Recently logged lines, if log capture is enabled (`--log-dir`).
+    pub logs: Option<Arc<logs::Recent>>,
+
+    /// Whether to count playback and export requests for `/api/heatmap`.
+    pub record_playback_heat: bool,
+
+    /// If set, read sample files for `.mp4` and `.m4s` responses in chunks of this many bytes,
+    /// with read-ahead, rather than `mmap()`ing them. See `bufpool.rs`.
+    pub read_ahead_bytes: Option<usize>,
+
+    /// If set, delay or shed timeline queries and exports while the database lock or syncer
+    /// queues are this busy. See `admission.rs`.
+    pub load_thresholds: Option<admission::Thresholds>,
+
+    /// Outstanding ACME challenges to answer, if TLS certificates are obtained via ACME.
+    pub acme_challenges: Option<Arc<tls::Challenges>>,
+
+    /// Which local processes connecting over a Unix socket may skip authentication.
+    pub unix_peer_auth: Option<UnixPeerAuth>,
+
+    /// The directory into which `export.rs` writes clip exports (`--export-dir`). If absent,
+    /// `/api/exports` is unavailable.
+    pub export_dir: Option<std::path::PathBuf>,
+}
+
+/// Grants requests over a Unix socket from the given users or groups the given permissions.
+pub struct UnixPeerAuth {
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+    pub permissions: db::Permissions,
+}
+
+/// A request extension holding the peer credentials of a Unix socket connection.
+#[derive(Copy, Clone, Debug)]
+pub struct UnixPeer {
+    pub uid: u32,
+    pub gid: u32,
 }
 
+/// A request extension marking requests received over built-in TLS (see `tls.rs`).
+pub struct ViaTls;
+
 pub struct Service {
     db: Arc<db::Database>,
-    ui_dir: Option<Arc<FsDir>>,
-    dirs_by_stream_id: Arc<FnvHashMap<i32, Arc<SampleFileDir>>>,
+    ui: Option<UiFiles>,
+    dirs_by_stream_id: Arc<FnvHashMap<i32, StreamDirs>>,
     time_zone_name: String,
     allow_unauthenticated_permissions: Option<db::Permissions>,
     trust_forward_hdrs: bool,
+    cookie_same_site: SameSite,
+    secure_cookies: bool,
+    allowed_origins: Vec<String>,
+    saml: Option<saml::ServiceProvider>,
+    oidc: Option<oidc::RelyingParty>,
+    syncer_queues: FnvHashMap<i32, db::writer::QueueMonitor>,
+    health: health::Monitor,
+    events: events::Bus,
+    hls: hls::Relay,
+    logs: Option<Arc<logs::Recent>>,
+    record_playback_heat: bool,
+    read_pool: Option<Arc<bufpool::Pool>>,
+    scheduler: Option<admission::Scheduler>,
+    acme_challenges: Option<Arc<tls::Challenges>>,
+    unix_peer_auth: Option<UnixPeerAuth>,
+    export_dir: Option<std::path::PathBuf>,
+    talkback_sessions: Arc<talkback::Sessions>,
+
+    /// The id of the next request, as logged in its `request` span and returned in its
+    /// `X-Request-Id` header.
+    next_request_id: AtomicU64,
+    #[cfg(feature = "graphql")]
+    graphql_schema: graphql::Schema,
+}
+
+/// The source of static user interface files.
+enum UiFiles {
+    Dir(Arc<FsDir>),
+
+    #[cfg(feature = "bundled-ui")]
+    Bundled,
 }
 
 /// Useful HTTP `Cache-Control` values to set on successful (HTTP 200) API responses.
@@ -354,7 +1242,7 @@ enum CacheControl {
 
 impl Service {
     pub fn new(config: Config) -> Result<Self, Error> {
-        let mut ui_dir = None;
+        let mut ui = None;
         if let Some(d) = config.ui_dir {
             match FsDir::builder().for_path(&d) {
                 Err(e) => {
@@ -364,30 +1252,57 @@ impl Service {
                         e
                     );
                 }
-                Ok(d) => ui_dir = Some(d),
+                Ok(d) => ui = Some(UiFiles::Dir(d)),
             };
-        }
-        let dirs_by_stream_id = {
-            let l = config.db.lock();
-            let mut d =
-                FnvHashMap::with_capacity_and_hasher(l.streams_by_id().len(), Default::default());
-            for (&id, s) in l.streams_by_id().iter() {
-                let dir_id = match s.sample_file_dir_id {
-                    Some(d) => d,
-                    None => continue,
-                };
-                d.insert(id, l.sample_file_dirs_by_id().get(&dir_id).unwrap().get()?);
+        } else {
+            #[cfg(feature = "bundled-ui")]
+            {
+                ui = Some(UiFiles::Bundled);
             }
-            Arc::new(d)
+        }
+        let dirs_by_stream_id = Arc::new(StreamDirs::all(&config.db.lock())?);
+        let saml = saml::ServiceProvider::new(&config.db.lock())?;
+        let oidc = oidc::RelyingParty::new(&config.db.lock())?;
+        if config.read_ahead_bytes == Some(0) {
+            bail!("read_ahead_bytes must be positive");
+        }
+
+        let scheduler = match config.load_thresholds {
+            None => None,
+            Some(t) => Some(admission::Scheduler::new(
+                config.db.clone(),
+                config.syncer_queues.values().cloned().collect(),
+                t,
+            )),
         };
 
         Ok(Service {
             db: config.db,
             dirs_by_stream_id,
-            ui_dir,
+            ui,
             allow_unauthenticated_permissions: config.allow_unauthenticated_permissions,
             trust_forward_hdrs: config.trust_forward_hdrs,
+            cookie_same_site: config.cookie_same_site,
+            secure_cookies: config.secure_cookies,
+            allowed_origins: config.allowed_origins,
             time_zone_name: config.time_zone_name,
+            saml,
+            oidc,
+            syncer_queues: config.syncer_queues,
+            health: config.health,
+            events: config.events,
+            hls: hls::Relay::default(),
+            logs: config.logs,
+            record_playback_heat: config.record_playback_heat,
+            read_pool: config.read_ahead_bytes.map(bufpool::Pool::new),
+            scheduler,
+            acme_challenges: config.acme_challenges,
+            unix_peer_auth: config.unix_peer_auth,
+            export_dir: config.export_dir,
+            talkback_sessions: Arc::new(talkback::Sessions::default()),
+            next_request_id: AtomicU64::new(1),
+            #[cfg(feature = "graphql")]
+            graphql_schema: graphql::schema(),
         })
     }
 
@@ -405,9 +1320,18 @@ impl Service {
             ));
         }
 
+        let mut send_init = false;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                if key == "init" {
+                    send_init = value == "true";
+                }
+            }
+        }
+
         let stream_id;
         let open_id;
-        let (sub_tx, sub_rx) = futures::channel::mpsc::unbounded();
+        let (mut sub_tx, sub_rx) = futures::channel::mpsc::channel(LIVE_SEGMENT_QUEUE);
         {
             let mut db = self.db.lock();
             open_id = match db.open {
@@ -428,9 +1352,15 @@ impl Service {
                     format!("no such stream {}/{}", uuid, stream_type),
                 )
             })?;
+
+            // If the subscriber falls behind, drop segments rather than buffering without
+            // bound. Each segment starts with a key frame, so the next one is still playable.
             db.watch_live(
                 stream_id,
-                Box::new(move |l| sub_tx.unbounded_send(l).is_ok()),
+                Box::new(move |l| match sub_tx.try_send(l) {
+                    Ok(()) => true,
+                    Err(e) => !e.is_disconnected(),
+                }),
             )
             .expect("stream_id refed by camera");
         }
@@ -441,7 +1371,7 @@ impl Service {
             .map_err(|e| bad_req(e.to_string()))?;
         let (parts, ()) = response.into_parts();
 
-        tokio::spawn(self.stream_live_m4s_ws(stream_id, open_id, body, sub_rx));
+        tokio::spawn(self.stream_live_m4s_ws(stream_id, open_id, send_init, body, sub_rx));
 
         Ok(Response::from_parts(parts, Body::from("")))
     }
@@ -450,8 +1380,9 @@ impl Service {
         self: Arc<Self>,
         stream_id: i32,
         open_id: u32,
+        send_init: bool,
         body: hyper::Body,
-        mut sub_rx: futures::channel::mpsc::UnboundedReceiver<db::LiveSegment>,
+        mut sub_rx: futures::channel::mpsc::Receiver<db::LiveSegment>,
     ) {
         let upgraded = match body.on_upgrade().await {
             Ok(u) => u,
@@ -460,47 +1391,98 @@ impl Service {
                 return;
             }
         };
-        let mut ws = tokio_tungstenite::WebSocketStream::from_raw_socket(
+        let (mut ws_tx, mut ws_rx) = tokio_tungstenite::WebSocketStream::from_raw_socket(
             upgraded,
             tungstenite::protocol::Role::Server,
             None,
         )
-        .await;
-        loop {
-            let live = match sub_rx.next().await {
-                Some(l) => l,
-                None => return,
-            };
-            if let Err(e) = self
-                .stream_live_m4s_chunk(open_id, stream_id, &mut ws, live)
-                .await
-            {
-                info!("Dropping WebSocket after error: {}", e);
-                return;
+        .await
+        .split();
+
+        // Read (and discard) client messages, so that pings are answered and a close is noticed
+        // even while the stream is idle.
+        let reader = async {
+            while let Some(m) = ws_rx.next().await {
+                match m {
+                    Ok(tungstenite::Message::Close(_)) => return,
+                    Ok(_) => {}
+                    Err(e) => {
+                        debug!("WebSocket read error: {}", e);
+                        return;
+                    }
+                }
+            }
+        };
+        let writer = async {
+            let mut last_vse_id = None;
+            while let Some(live) = sub_rx.next().await {
+                let c = self.live_chunk(stream_id, &live)?;
+                if send_init && last_vse_id != Some(c.vse.id) {
+                    ws_tx
+                        .send(tungstenite::Message::Binary(
+                            self.live_init_message(&c.vse).await?,
+                        ))
+                        .await?;
+                    last_vse_id = Some(c.vse.id);
+                }
+                ws_tx
+                    .send(tungstenite::Message::Binary(
+                        live_chunk_message(open_id, &live, c).await?,
+                    ))
+                    .await?;
             }
+            Ok::<_, Error>(())
+        };
+        if let futures::future::Either::Right((Err(e), _)) =
+            futures::future::select(Box::pin(reader), Box::pin(writer)).await
+        {
+            info!("Dropping WebSocket after error: {}", e);
         }
     }
 
-    async fn stream_live_m4s_chunk(
+    /// Builds a WebSocket message holding the initialization segment for `vse`.
+    async fn live_init_message(&self, vse: &Arc<db::VideoSampleEntry>) -> Result<Vec<u8>, Error> {
+        let mut builder = mp4::FileBuilder::new(mp4::Type::InitSegment);
+        builder.append_video_sample_entry(vse.clone());
+        let mp4 = builder.build(self.db.clone(), self.dirs_by_stream_id.clone())?;
+        use http_serve::Entity;
+        let mut hdrs = header::HeaderMap::new();
+        mp4.add_headers(&mut hdrs);
+        let mime_type = hdrs.get(header::CONTENT_TYPE).unwrap();
+        let hdr = format!(
+            "Content-Type: {}\r\n\
+            X-Video-Sample-Entry-Sha1: {}\r\n\r\n",
+            mime_type.to_str().unwrap(),
+            strutil::hex(&vse.sha1)
+        );
+        let mut v = hdr.into_bytes();
+        mp4.append_into_vec(&mut v).await?;
+        Ok(v)
+    }
+
+    /// Builds the `.m4s` media segment for a live segment, for WebSocket and gRPC subscribers.
+    pub(crate) fn live_chunk(
         &self,
-        open_id: u32,
         stream_id: i32,
-        ws: &mut tokio_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
-        live: db::LiveSegment,
-    ) -> Result<(), Error> {
+        live: &db::LiveSegment,
+    ) -> Result<LiveChunk, Error> {
         let mut builder = mp4::FileBuilder::new(mp4::Type::MediaSegment);
-        let mut vse_id = None;
+        if let Some(p) = self.read_pool.as_ref() {
+            builder.read_via(p.clone());
+        }
+        let mut vse = None;
         let mut start = None;
         {
             let db = self.db.lock();
             let mut rows = 0;
             db.list_recordings_by_id(stream_id, live.recording..live.recording + 1, &mut |r| {
                 rows += 1;
-                let vse = db
-                    .video_sample_entries_by_id()
-                    .get(&r.video_sample_entry_id)
-                    .unwrap();
-                vse_id = Some(strutil::hex(&vse.sha1));
+                vse = Some(
+                    db.video_sample_entries_by_id()
+                        .get(&r.video_sample_entry_id)
+                        .unwrap()
+                        .clone(),
+                );
                 start = Some(r.start);
                 builder.append(&db, r, live.off_90k.clone())?;
                 Ok(())
@@ -509,32 +1491,294 @@ impl Service {
                 bail_t!(Internal, "unable to find {:?}", live);
             }
         }
-        let vse_id = vse_id.unwrap();
+        let vse = vse.unwrap();
         let start = start.unwrap();
-        use http_serve::Entity;
         let mp4 = builder.build(self.db.clone(), self.dirs_by_stream_id.clone())?;
-        let mut hdrs = header::HeaderMap::new();
-        mp4.add_headers(&mut hdrs);
-        let mime_type = hdrs.get(header::CONTENT_TYPE).unwrap();
-        let hdr = format!(
-            "Content-Type: {}\r\n\
-            X-Recording-Start: {}\r\n\
-            X-Recording-Id: {}.{}\r\n\
-            X-Time-Range: {}-{}\r\n\
-            X-Video-Sample-Entry-Sha1: {}\r\n\r\n",
-            mime_type.to_str().unwrap(),
-            start.0,
-            open_id,
-            live.recording,
-            live.off_90k.start,
-            live.off_90k.end,
-            &vse_id
-        );
-        let mut v = /*Pin::from(*/hdr.into_bytes()/*)*/;
-        mp4.append_into_vec(&mut v).await?;
-        //let v = Pin::into_inner();
-        ws.send(tungstenite::Message::Binary(v)).await?;
-        Ok(())
+        Ok(LiveChunk {
+            start,
+            vse_id: strutil::hex(&vse.sha1),
+            vse,
+            mp4,
+        })
+    }
+
+    /// Returns the id of a stream to relay by HLS, checking that the caller may view it and that
+    /// it's live.
+    fn hls_stream_id(
+        &self,
+        caller: &Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> Result<i32, Response<Body>> {
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        let db = self.db.lock();
+        if db.open.is_none() {
+            return Err(plain_response(
+                StatusCode::PRECONDITION_FAILED,
+                "database is read-only; there are no live streams",
+            ));
+        }
+        let camera = db
+            .get_camera(uuid)
+            .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?;
+        camera.streams[stream_type.index()]
+            .ok_or_else(|| not_found(format!("no such stream {}/{}", uuid, stream_type)))
+    }
+
+    /// Serves the stream's HLS playlist of recent live segments. See `hls`.
+    fn stream_hls_playlist(
+        &self,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> ResponseResult {
+        let stream_id = self.hls_stream_id(&caller, uuid, stream_type)?;
+        let snapshot = self
+            .hls
+            .playlist(&self.db, stream_id)
+            .map_err(internal_server_err)?;
+        let mut init_sha1s = FnvHashMap::default();
+        {
+            let db = self.db.lock();
+            for s in snapshot.segments.iter().filter(|s| s.discontinuity) {
+                let id = s.live.recording;
+                db.list_recordings_by_id(stream_id, id..id + 1, &mut |r| {
+                    if let Some(e) = db
+                        .video_sample_entries_by_id()
+                        .get(&r.video_sample_entry_id)
+                    {
+                        init_sha1s.insert(id, strutil::hex(&e.sha1));
+                    }
+                    Ok(())
+                })
+                .map_err(internal_server_err)?;
+            }
+        }
+        Ok(Response::builder()
+            .header(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/vnd.apple.mpegurl"),
+            )
+            .body(hls::render(&snapshot, &init_sha1s).into())
+            .unwrap())
+    }
+
+    /// Serves a media segment listed in the stream's HLS playlist.
+    fn stream_hls_segment(
+        &self,
+        req: &Request<::hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+        seq: u64,
+    ) -> ResponseResult {
+        let stream_id = self.hls_stream_id(&caller, uuid, stream_type)?;
+        let live = self
+            .hls
+            .segment(stream_id, seq)
+            .ok_or_else(|| not_found(format!("no live segment {}", seq)))?;
+        let c = self
+            .live_chunk(stream_id, &live)
+            .map_err(internal_server_err)?;
+        Ok(http_serve::serve(c.mp4, req))
+    }
+
+    /// Serves the stream's live key frames as `multipart/x-mixed-replace` JPEGs, for consumers
+    /// which understand neither `.m4s` segments nor H.264.
+    fn stream_mjpeg(
+        self: Arc<Self>,
+        req: &Request<::hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> ResponseResult {
+        if *req.method() != http::method::Method::GET {
+            return Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET expected",
+            ));
+        }
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        let mut fps = DEFAULT_MJPEG_FPS;
+        let mut width = DEFAULT_MJPEG_WIDTH;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "fps" => {
+                        fps = u32::from_str(value)
+                            .ok()
+                            .filter(|&f| f > 0 && f <= MAX_MJPEG_FPS)
+                            .ok_or_else(|| {
+                                bad_req(format!("fps must be between 1 and {}", MAX_MJPEG_FPS))
+                            })?
+                    }
+                    "width" => {
+                        width = u32::from_str(value)
+                            .ok()
+                            .filter(|&w| w > 0 && w <= MAX_MJPEG_WIDTH)
+                            .ok_or_else(|| {
+                                bad_req(format!("width must be between 1 and {}", MAX_MJPEG_WIDTH))
+                            })?
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let stream_id;
+        let (sub_tx, sub_rx) = futures::channel::mpsc::unbounded();
+        {
+            let mut db = self.db.lock();
+            if db.open.is_none() {
+                return Err(plain_response(
+                    StatusCode::PRECONDITION_FAILED,
+                    "database is read-only; there are no live streams",
+                ));
+            }
+            let camera = db.get_camera(uuid).ok_or_else(|| {
+                plain_response(StatusCode::NOT_FOUND, format!("no such camera {}", uuid))
+            })?;
+            stream_id = camera.streams[stream_type.index()].ok_or_else(|| {
+                plain_response(
+                    StatusCode::NOT_FOUND,
+                    format!("no such stream {}/{}", uuid, stream_type),
+                )
+            })?;
+            db.watch_live(
+                stream_id,
+                Box::new(move |l| sub_tx.unbounded_send(l).is_ok()),
+            )
+            .expect("stream_id refed by camera");
+        }
+
+        // A single-slot channel, so a slow client holds up decoding rather than buffering frames.
+        let (frame_tx, frame_rx) = futures::channel::mpsc::channel(1);
+        let min_interval = time::Duration::milliseconds(1000 / i64::from(fps));
+        tokio::spawn(self.stream_mjpeg_frames(stream_id, min_interval, width, sub_rx, frame_tx));
+        let body: BodyStream = Box::new(frame_rx);
+        Ok(Response::builder()
+            .header(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("multipart/x-mixed-replace; boundary=frame"),
+            )
+            .body(body.into())
+            .unwrap())
+    }
+
+    async fn stream_mjpeg_frames(
+        self: Arc<Self>,
+        stream_id: i32,
+        min_interval: time::Duration,
+        width: u32,
+        mut sub_rx: futures::channel::mpsc::UnboundedReceiver<db::LiveSegment>,
+        mut frame_tx: futures::channel::mpsc::Sender<Result<Chunk, BoxedError>>,
+    ) {
+        let mut last_frame: Option<time::Timespec> = None;
+        while let Some(mut live) = sub_rx.next().await {
+            // Skip to the newest segment; older ones queued while the last frame was decoding.
+            while let Ok(Some(l)) = sub_rx.try_next() {
+                live = l;
+            }
+            let now = self.db.clocks().monotonic();
+            if let Some(l) = last_frame {
+                if now - l < min_interval {
+                    continue;
+                }
+            }
+            last_frame = Some(now);
+            let s = self.clone();
+            let jpeg = match tokio::task::spawn_blocking(move || {
+                s.live_key_frame_jpeg(stream_id, &live, width)
+            })
+            .await
+            {
+                Ok(Ok(j)) => j,
+                Ok(Err(e)) => {
+                    warn!(
+                        "Unable to decode live key frame of stream {}: {}",
+                        stream_id, e
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    warn!(
+                        "Live key frame decoding for stream {} panicked: {}",
+                        stream_id, e
+                    );
+                    return;
+                }
+            };
+            let mut part = format!(
+                "--frame\r\n\
+                Content-Type: image/jpeg\r\n\
+                Content-Length: {}\r\n\r\n",
+                jpeg.len()
+            )
+            .into_bytes();
+            part.extend_from_slice(&jpeg);
+            part.extend_from_slice(b"\r\n");
+            if frame_tx.send(Ok(part.into())).await.is_err() {
+                return; // the client went away.
+            }
+        }
+    }
+
+    /// Reads the key frame which starts a live segment and decodes it to a JPEG no wider than
+    /// `width`. Blocks on disk I/O and an `ffmpeg` subprocess.
+    fn live_key_frame_jpeg(
+        &self,
+        stream_id: i32,
+        live: &db::LiveSegment,
+        width: u32,
+    ) -> Result<Vec<u8>, Error> {
+        let id = db::CompositeId::new(stream_id, live.recording);
+        let (flags, sample_entry, frame, mask) = {
+            let db = self.db.lock();
+            let mut row = None;
+            db.list_recordings_by_id(stream_id, live.recording..live.recording + 1, &mut |r| {
+                let vse = db
+                    .video_sample_entries_by_id()
+                    .get(&r.video_sample_entry_id)
+                    .unwrap();
+                row = Some((r.flags, vse.data.clone()));
+                Ok(())
+            })?;
+            let (flags, sample_entry) =
+                row.ok_or_else(|| format_err!("unable to find {:?}", live))?;
+            let frame = db.with_recording_playback(id, &mut |p| {
+                let mut it = recording::SampleIndexIterator::new();
+                while it.next(p.video_index)? {
+                    if it.start_90k >= live.off_90k.start && it.is_key() {
+                        return Ok(it.pos as u64..(it.pos + it.bytes) as u64);
+                    }
+                }
+                bail!("no key frame in {:?}", live)
+            })?;
+            let mask = db
+                .streams_by_id()
+                .get(&stream_id)
+                .map(|s| s.privacy_mask.clone())
+                .unwrap_or_default();
+            (flags, sample_entry, frame, mask)
+        };
+        let dir = self
+            .dirs_by_stream_id
+            .get(&stream_id)
+            .and_then(|d| d.get(id, flags))
+            .ok_or_else(|| format_err!("{}: stream not found", id))?;
+        let key_frame = read_sample_data(dir, id, flags, frame)?;
+        thumbnail::generate(&sample_entry, &key_frame, width, &mask)
     }
 
     async fn signals(&self, req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
@@ -549,6 +1793,24 @@ impl Service {
         }
     }
 
+    async fn stream_detections(
+        &self,
+        req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        type_: db::StreamType,
+    ) -> ResponseResult {
+        use http::method::Method;
+        match *req.method() {
+            Method::POST => self.post_detections(req, caller, uuid, type_).await,
+            Method::GET | Method::HEAD => self.get_detections(&req, uuid, type_),
+            _ => Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST, GET, or HEAD expected",
+            )),
+        }
+    }
+
     async fn serve_inner(
         self: Arc<Self>,
         req: Request<::hyper::Body>,
@@ -563,10 +1825,34 @@ impl Service {
             Path::TopLevel => (CacheControl::PrivateDynamic, self.top_level(&req, caller)?),
             Path::Request => (CacheControl::PrivateDynamic, self.request(&req)?),
             Path::Camera(uuid) => (CacheControl::PrivateDynamic, self.camera(&req, uuid)?),
+            Path::CameraPtz(uuid) => (
+                CacheControl::PrivateDynamic,
+                self.camera_ptz(req, caller, uuid).await?,
+            ),
+            Path::CameraPrivacy(uuid) => (
+                CacheControl::PrivateDynamic,
+                self.camera_privacy(req, caller, uuid).await?,
+            ),
+            Path::CameraTalkback(uuid) => (
+                CacheControl::PrivateDynamic,
+                self.camera_talkback(req, caller, uuid).await?,
+            ),
+            Path::StreamRecordings(uuid, type_)
+                if *req.method() == http::method::Method::DELETE =>
+            {
+                (
+                    CacheControl::PrivateDynamic,
+                    self.delete_stream_recordings(&req, caller, uuid, type_)?,
+                )
+            }
             Path::StreamRecordings(uuid, type_) => (
                 CacheControl::PrivateDynamic,
                 self.stream_recordings(&req, uuid, type_)?,
             ),
+            Path::StreamThumbnail(uuid, type_, id) => (
+                CacheControl::PrivateStatic,
+                self.stream_thumbnail(caller, uuid, type_, id)?,
+            ),
             Path::StreamViewMp4(uuid, type_, debug) => (
                 CacheControl::PrivateStatic,
                 self.stream_view_mp4(&req, caller, uuid, type_, mp4::Type::Normal, debug)?,
@@ -579,14 +1865,138 @@ impl Service {
                 CacheControl::PrivateDynamic,
                 self.stream_live_m4s(req, caller, uuid, type_)?,
             ),
+            Path::StreamHlsPlaylist(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_hls_playlist(caller, uuid, type_)?,
+            ),
+            Path::StreamHlsSegment(uuid, type_, seq) => (
+                CacheControl::PrivateDynamic,
+                self.stream_hls_segment(&req, caller, uuid, type_, seq)?,
+            ),
+            Path::StreamMjpeg(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_mjpeg(&req, caller, uuid, type_)?,
+            ),
+            Path::StreamRetentionPreview(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_retention_preview(&req, caller, uuid, type_)?,
+            ),
+            Path::StreamDetections(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_detections(req, caller, uuid, type_).await?,
+            ),
+            Path::StreamGaps(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_gaps(&req, caller, uuid, type_)?,
+            ),
+            Path::StreamPrivacyMask(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_privacy_mask(req, caller, uuid, type_).await?,
+            ),
+            Path::StreamReplica(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_replica(&req, caller, uuid, type_)?,
+            ),
+            Path::StreamReplicaData(uuid, type_, id) => (
+                CacheControl::PrivateStatic,
+                self.stream_replica_data(&req, caller, uuid, type_, id)
+                    .await?,
+            ),
             Path::NotFound => return Err(not_found("path not understood")),
             Path::Login => (CacheControl::PrivateDynamic, self.login(req).await?),
+            Path::SamlLogin => (CacheControl::PrivateDynamic, self.saml_login(&req)?),
+            Path::SamlAcs => (CacheControl::PrivateDynamic, self.saml_acs(req).await?),
+            Path::OidcLogin => (CacheControl::PrivateDynamic, self.oidc_login(req).await?),
+            Path::OidcCallback => (CacheControl::PrivateDynamic, self.oidc_callback(req).await?),
             Path::Logout => (CacheControl::PrivateDynamic, self.logout(req).await?),
             Path::Signals => (
                 CacheControl::PrivateDynamic,
                 self.signals(req, caller).await?,
             ),
+            Path::NewSignal => (
+                CacheControl::PrivateDynamic,
+                self.new_signal(req, caller).await?,
+            ),
+            Path::Signal(id) => (
+                CacheControl::PrivateDynamic,
+                self.signal(req, caller, id).await?,
+            ),
+            Path::Protect => (
+                CacheControl::PrivateDynamic,
+                self.protect(req, caller).await?,
+            ),
+            Path::Notes => (
+                CacheControl::PrivateDynamic,
+                self.post_note(req, caller).await?,
+            ),
+            Path::Note(id) => (
+                CacheControl::PrivateDynamic,
+                self.delete_note(&req, caller, id)?,
+            ),
+            Path::Bookmarks => (
+                CacheControl::PrivateDynamic,
+                self.bookmarks(req, caller).await?,
+            ),
+            Path::Bookmark(id) => (
+                CacheControl::PrivateDynamic,
+                self.delete_bookmark(&req, caller, id)?,
+            ),
+            Path::Exports => (
+                CacheControl::PrivateDynamic,
+                self.exports(req, caller).await?,
+            ),
+            Path::ExportVerify => (
+                CacheControl::PrivateDynamic,
+                self.verify_export(req, caller).await?,
+            ),
+            Path::Export(id) => (CacheControl::PrivateDynamic, self.export(&req, caller, id)?),
+            Path::ExportDownload(id) => (
+                CacheControl::PrivateDynamic,
+                self.export_download(&req, caller, id).await?,
+            ),
+            Path::Search => (CacheControl::PrivateDynamic, self.search(&req, caller)?),
+            Path::RetentionSimulation => (
+                CacheControl::PrivateDynamic,
+                self.retention_simulation(req, caller).await?,
+            ),
+            Path::Graphql => (
+                CacheControl::PrivateDynamic,
+                self.graphql(req, caller).await?,
+            ),
+            Path::Events => (CacheControl::PrivateDynamic, self.events(&req, caller)?),
+            Path::Health => (CacheControl::PrivateDynamic, self.health(&req, &caller)?),
+            Path::HealthLive => (CacheControl::PrivateDynamic, self.health_live(&req)?),
+            Path::HealthReady => (CacheControl::PrivateDynamic, self.health_ready(&req)?),
+            Path::Logs => (CacheControl::PrivateDynamic, self.logs(&req, caller)?),
+            Path::LogFilter => (
+                CacheControl::PrivateDynamic,
+                self.log_filter(req, caller).await?,
+            ),
+            Path::UserNotifications => (
+                CacheControl::PrivateDynamic,
+                self.user_notifications(req, caller).await?,
+            ),
+            Path::UserSessions => (
+                CacheControl::PrivateDynamic,
+                self.user_sessions(&req, caller)?,
+            ),
+            Path::UserSession(id) => (
+                CacheControl::PrivateDynamic,
+                self.user_session(&req, caller, id)?,
+            ),
+            Path::Heatmap => (CacheControl::PrivateDynamic, self.heatmap(&req, caller)?),
+            Path::Timeline => (CacheControl::PrivateDynamic, self.timeline(&req, caller)?),
+            Path::Playback => (CacheControl::PrivateDynamic, self.playback(&req, caller)?),
+            Path::CameraCoverage(uuid) => (
+                CacheControl::PrivateDynamic,
+                self.camera_coverage(&req, caller, uuid)?,
+            ),
+            Path::SampleFiles => (
+                CacheControl::PrivateDynamic,
+                self.sample_files(&req, caller)?,
+            ),
             Path::Static => (CacheControl::None, self.static_file(req).await?),
+            Path::AcmeChallenge(token) => (CacheControl::None, self.acme_challenge(&token)?),
         };
         match cache {
             CacheControl::PrivateStatic => {
@@ -609,38 +2019,213 @@ impl Service {
     pub async fn serve(
         self: Arc<Self>,
         req: Request<::hyper::Body>,
+    ) -> Result<Response<Body>, std::convert::Infallible> {
+        let id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let span = tracing::info_span!(
+            "request",
+            id,
+            method = %req.method(),
+            path = %req.uri().path()
+        );
+        let mut resp = self.serve_request(req).instrument(span).await?;
+        resp.headers_mut()
+            .insert("X-Request-Id", HeaderValue::from(id));
+        Ok(resp)
+    }
+
+    async fn serve_request(
+        self: Arc<Self>,
+        req: Request<::hyper::Body>,
     ) -> Result<Response<Body>, std::convert::Infallible> {
         let p = Path::decode(req.uri().path());
         let always_allow_unauthenticated = match p {
-            Path::NotFound | Path::Request | Path::Login | Path::Logout | Path::Static => true,
+            Path::NotFound
+            | Path::Request
+            | Path::Login
+            | Path::SamlLogin
+            | Path::SamlAcs
+            | Path::OidcLogin
+            | Path::OidcCallback
+            | Path::Logout
+            | Path::HealthLive
+            | Path::HealthReady
+            | Path::AcmeChallenge(_)
+            | Path::Static => true,
             _ => false,
         };
         debug!("request on: {}: {:?}", req.uri(), p);
-        let caller = match self.authenticate(&req, always_allow_unauthenticated) {
+        let caller = match self.authenticate(
+            req.headers(),
+            req.extensions().get::<UnixPeer>(),
+            always_allow_unauthenticated,
+        ) {
             Ok(c) => c,
             Err(e) => return Ok(from_base_error(e)),
         };
+        if let Err(e) = self.check_csrf(&req, &p, &caller) {
+            return Ok(e);
+        }
+        if let Err(e) = self.check_camera_access(&p, &caller) {
+            return Ok(e);
+        }
+        if let Err(e) = self.check_privacy(&p) {
+            return Ok(e);
+        }
+        if let (Some(s), Some(priority)) = (self.scheduler.as_ref(), p.priority(req.method())) {
+            if !s.admit(priority).await {
+                debug!("shedding {:?} request {}", priority, req.uri());
+                let mut resp = plain_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "server is busy recording; try again later",
+                );
+                resp.headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from_static("10"));
+                return Ok(resp);
+            }
+        }
         Ok(self.serve_inner(req, p, caller).await.unwrap_or_else(|e| e))
     }
 
-    fn top_level(&self, req: &Request<::hyper::Body>, caller: Caller) -> ResponseResult {
-        let mut days = false;
-        let mut camera_configs = false;
-        if let Some(q) = req.uri().query() {
-            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
-                let (key, value): (_, &str) = (key.borrow(), value.borrow());
-                match key {
-                    "days" => days = value == "true",
-                    "cameraConfigs" => camera_configs = value == "true",
-                    _ => {}
-                };
+    /// Rejects requests for a camera the caller may not access as if the camera didn't exist.
+    /// Handlers which span cameras filter or check their own results.
+    fn check_camera_access(&self, p: &Path, caller: &Caller) -> Result<(), Response<Body>> {
+        if caller.cameras.is_none() {
+            return Ok(());
+        }
+        let uuid = match *p {
+            Path::Camera(uuid)
+            | Path::CameraPtz(uuid)
+            | Path::CameraCoverage(uuid)
+            | Path::CameraPrivacy(uuid)
+            | Path::CameraTalkback(uuid)
+            | Path::StreamRecordings(uuid, _)
+            | Path::StreamThumbnail(uuid, _, _)
+            | Path::StreamViewMp4(uuid, _, _)
+            | Path::StreamViewMp4Segment(uuid, _, _)
+            | Path::StreamLiveMp4Segments(uuid, _)
+            | Path::StreamHlsPlaylist(uuid, _)
+            | Path::StreamHlsSegment(uuid, _, _)
+            | Path::StreamMjpeg(uuid, _)
+            | Path::StreamRetentionPreview(uuid, _)
+            | Path::StreamDetections(uuid, _)
+            | Path::StreamGaps(uuid, _)
+            | Path::StreamPrivacyMask(uuid, _)
+            | Path::StreamReplica(uuid, _)
+            | Path::StreamReplicaData(uuid, _, _) => uuid,
+            _ => return Ok(()),
+        };
+        match self.db.lock().get_camera(uuid) {
+            Some(c) if !caller.may_access_camera(c.id) => {
+                Err(not_found(format!("no such camera {}", uuid)))
             }
+            _ => Ok(()),
         }
+    }
 
-        if camera_configs {
-            if !caller.permissions.read_camera_configs {
-                return Err(plain_response(
-                    StatusCode::UNAUTHORIZED,
+    /// Rejects live view of a camera in privacy mode. Recorded video from before or after
+    /// the privacy interval remains available.
+    fn check_privacy(&self, p: &Path) -> Result<(), Response<Body>> {
+        let uuid = match *p {
+            Path::StreamLiveMp4Segments(uuid, _)
+            | Path::StreamHlsPlaylist(uuid, _)
+            | Path::StreamHlsSegment(uuid, _, _)
+            | Path::StreamMjpeg(uuid, _) => uuid,
+            _ => return Ok(()),
+        };
+        let now = recording::Time::new(self.db.clocks().realtime());
+        let db = self.db.lock();
+        match db.get_camera(uuid) {
+            Some(c) if db.privacy_at(c.id, now) => Err(plain_response(
+                StatusCode::FORBIDDEN,
+                format!("camera {} is in privacy mode", uuid),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Rejects likely cross-site request forgeries: state-changing requests from a foreign
+    /// `Origin` and, when authenticated by session cookie, ones without the session's
+    /// `X-CSRF-Token` header.
+    fn check_csrf(
+        &self,
+        req: &Request<::hyper::Body>,
+        p: &Path,
+        caller: &Caller,
+    ) -> Result<(), Response<Body>> {
+        if !is_unsafe_method(req.method()) {
+            return Ok(());
+        }
+        match *p {
+            // The identity provider's login page posts here from its own origin.
+            Path::SamlAcs => return Ok(()),
+
+            // Login has no session yet; logout checks the token in its body.
+            Path::Login | Path::Logout => {}
+            _ => {
+                if let Some(s) = caller.session.as_ref() {
+                    let token = req
+                        .headers()
+                        .get("X-CSRF-Token")
+                        .and_then(|t| t.to_str().ok());
+                    if !token.map(|t| csrf_matches(t, s.csrf)).unwrap_or(false) {
+                        warn!(
+                            "{} {} with missing/incorrect csrf token",
+                            req.method(),
+                            req.uri()
+                        );
+                        return Err(plain_response(
+                            StatusCode::FORBIDDEN,
+                            "missing or incorrect X-CSRF-Token header",
+                        ));
+                    }
+                }
+            }
+        }
+
+        // Browsers always send Origin with cross-origin unsafe requests; its absence means a
+        // same-origin request from an older browser or a non-browser client.
+        let origin = match req.headers().get(header::ORIGIN) {
+            None => return Ok(()),
+            Some(o) => o.as_bytes(),
+        };
+        let same_host = req
+            .headers()
+            .get(header::HOST)
+            .map(|h| origin_matches_host(origin, h.as_bytes()))
+            .unwrap_or(false);
+        if same_host || self.allowed_origins.iter().any(|o| o.as_bytes() == origin) {
+            return Ok(());
+        }
+        warn!(
+            "rejecting {} {} from origin {:?}",
+            req.method(),
+            req.uri(),
+            String::from_utf8_lossy(origin)
+        );
+        Err(plain_response(
+            StatusCode::FORBIDDEN,
+            "cross-origin request not allowed",
+        ))
+    }
+
+    fn top_level(&self, req: &Request<::hyper::Body>, caller: Caller) -> ResponseResult {
+        let mut days = false;
+        let mut camera_configs = false;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value): (_, &str) = (key.borrow(), value.borrow());
+                match key {
+                    "days" => days = value == "true",
+                    "cameraConfigs" => camera_configs = value == "true",
+                    _ => {}
+                };
+            }
+        }
+
+        if camera_configs {
+            if !caller.permissions.read_camera_configs {
+                return Err(plain_response(
+                    StatusCode::UNAUTHORIZED,
                     "read_camera_configs required",
                 ));
             }
@@ -651,7 +2236,7 @@ impl Service {
             req,
             &json::TopLevel {
                 time_zone_name: &self.time_zone_name,
-                cameras: (&db, days, camera_configs),
+                cameras: (&db, days, camera_configs, caller.cameras.as_ref()),
                 session: caller.session,
                 signals: (&db, days),
                 signal_types: &db,
@@ -670,116 +2255,253 @@ impl Service {
         )
     }
 
-    fn stream_recordings(
+    async fn camera_ptz(
         &self,
-        req: &Request<::hyper::Body>,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
         uuid: Uuid,
-        type_: db::StreamType,
     ) -> ResponseResult {
-        let (r, split) = {
-            let mut time = recording::Time::min_value()..recording::Time::max_value();
-            let mut split = recording::Duration(i64::max_value());
-            if let Some(q) = req.uri().query() {
-                for (key, value) in form_urlencoded::parse(q.as_bytes()) {
-                    let (key, value) = (key.borrow(), value.borrow());
-                    match key {
-                        "startTime90k" => {
-                            time.start = recording::Time::parse(value)
-                                .map_err(|_| bad_req("unparseable startTime90k"))?
-                        }
-                        "endTime90k" => {
-                            time.end = recording::Time::parse(value)
-                                .map_err(|_| bad_req("unparseable endTime90k"))?
-                        }
-                        "split90k" => {
-                            split = recording::Duration(
-                                i64::from_str(value)
-                                    .map_err(|_| bad_req("unparseable split90k"))?,
-                            )
-                        }
-                        _ => {}
+        if *req.method() != http::method::Method::POST {
+            return Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST expected",
+            ));
+        }
+        if !caller.permissions.control_ptz {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "control_ptz required",
+            ));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostPtzRequest =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let client = {
+            let db = self.db.lock();
+            let camera = db
+                .get_camera(uuid)
+                .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?;
+            if !camera.ptz {
+                return Err(bad_req(format!("camera {} doesn't support PTZ", uuid)));
+            }
+            onvif::Client::new(camera)
+                .ok_or_else(|| bad_req(format!("camera {} has no ONVIF host", uuid)))?
+        };
+        match r {
+            json::PostPtzRequest::ContinuousMove { pan, tilt, zoom } => {
+                for &v in &[pan, tilt, zoom] {
+                    if !(-1.0..=1.0).contains(&v) {
+                        return Err(bad_req("velocities must be in [-1, 1]"));
                     }
                 }
+                client.continuous_move(pan, tilt, zoom).await
+            }
+            json::PostPtzRequest::Stop => client.stop().await,
+            json::PostPtzRequest::GotoPreset { preset } => client.goto_preset(&preset).await,
+        }
+        .map_err(internal_server_err)?;
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(b""[..].into())
+            .unwrap())
+    }
+
+    async fn camera_privacy(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        let now = recording::Time::new(self.db.clocks().realtime());
+        if *req.method() == http::method::Method::POST {
+            if !caller.permissions.update_signals {
+                return Err(plain_response(
+                    StatusCode::UNAUTHORIZED,
+                    "update_signals required",
+                ));
+            }
+            let r = extract_json_body(&mut req).await?;
+            let r: json::PostPrivacyRequest =
+                serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+            let end = match r.duration_sec {
+                None => recording::Time::max_value(),
+                Some(d) if d > 0 && d <= i64::max_value() / recording::TIME_UNITS_PER_SEC => {
+                    now + recording::Duration(d * recording::TIME_UNITS_PER_SEC)
+                }
+                Some(_) => return Err(bad_req("durationSec must be positive")),
+            };
+            let mut l = self.db.lock();
+            let id = l
+                .get_camera(uuid)
+                .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?
+                .id;
+            l.set_privacy(id, now..end, r.enabled)
+                .map_err(internal_server_err)?;
+            return Ok(Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(b""[..].into())
+                .unwrap());
+        }
+        if *req.method() != http::method::Method::GET && *req.method() != http::method::Method::HEAD
+        {
+            return Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, or POST expected",
+            ));
+        }
+        let out = {
+            let l = self.db.lock();
+            let id = l
+                .get_camera(uuid)
+                .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?
+                .id;
+            json::Privacy {
+                enabled: l.privacy_at(id, now),
+                until_90k: l
+                    .privacy_changes_after(id, now)
+                    .filter(|&t| t != recording::Time::max_value())
+                    .map(|t| t.0),
             }
-            (time, split)
         };
-        let db = self.db.lock();
-        let mut out = json::ListRecordings {
-            recordings: Vec::new(),
-            video_sample_entries: (&db, Vec::new()),
+        serve_json(&req, &out)
+    }
+
+    async fn camera_talkback(
+        &self,
+        req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        if !caller.permissions.talkback {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "talkback required",
+            ));
+        }
+        let (url, username, password) = {
+            let db = self.db.lock();
+            let camera = db
+                .get_camera(uuid)
+                .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?;
+            let stream = camera.streams[db::StreamType::MAIN.index()]
+                .and_then(|id| db.streams_by_id().get(&id))
+                .filter(|s| !s.rtsp_url.is_empty())
+                .ok_or_else(|| bad_req(format!("camera {} has no main RTSP stream", uuid)))?;
+            (
+                stream.rtsp_url.clone(),
+                camera.username.clone(),
+                camera.password.clone(),
+            )
         };
-        let camera = db.get_camera(uuid).ok_or_else(|| {
-            plain_response(StatusCode::NOT_FOUND, format!("no such camera {}", uuid))
-        })?;
-        let stream_id = camera.streams[type_.index()].ok_or_else(|| {
+
+        let (parts, body) = req.into_parts();
+        let req = Request::from_parts(parts, ());
+        let response = tungstenite::handshake::server::create_response(&req)
+            .map_err(|e| bad_req(e.to_string()))?;
+        let (parts, ()) = response.into_parts();
+
+        let claim = self.talkback_sessions.try_claim(uuid).ok_or_else(|| {
             plain_response(
-                StatusCode::NOT_FOUND,
-                format!("no such stream {}/{}", uuid, type_),
+                StatusCode::CONFLICT,
+                format!("camera {} already has a talkback session", uuid),
             )
         })?;
-        db.list_aggregated_recordings(stream_id, r, split, &mut |row| {
-            let end = row.ids.end - 1; // in api, ids are inclusive.
-            out.recordings.push(json::Recording {
-                start_id: row.ids.start,
-                end_id: if end == row.ids.start {
-                    None
-                } else {
-                    Some(end)
-                },
-                start_time_90k: row.time.start.0,
-                end_time_90k: row.time.end.0,
-                sample_file_bytes: row.sample_file_bytes,
-                open_id: row.open_id,
-                first_uncommitted: row.first_uncommitted,
-                video_samples: row.video_samples,
-                video_sample_entry_id: row.video_sample_entry_id.to_string(),
-                growing: row.growing,
-            });
-            if !out
-                .video_sample_entries
-                .1
-                .contains(&row.video_sample_entry_id)
-            {
-                out.video_sample_entries.1.push(row.video_sample_entry_id);
+        let backchannel = talkback::Backchannel::open(&url, &username, &password)
+            .await
+            .map_err(internal_server_err)?;
+        tokio::spawn(Self::camera_talkback_ws(claim, backchannel, body));
+
+        Ok(Response::from_parts(parts, Body::from("")))
+    }
+
+    /// Relays audio from a talkback WebSocket until either side closes or the session idles.
+    async fn camera_talkback_ws(
+        _claim: talkback::Claim,
+        mut backchannel: talkback::Backchannel,
+        body: hyper::Body,
+    ) {
+        let upgraded = match body.on_upgrade().await {
+            Ok(u) => u,
+            Err(e) => {
+                warn!("Unable to upgrade talkback to websocket: {}", e);
+                return;
             }
-            Ok(())
-        })
-        .map_err(internal_server_err)?;
-        serve_json(req, &out)
+        };
+        let mut ws = tokio_tungstenite::WebSocketStream::from_raw_socket(
+            upgraded,
+            tungstenite::protocol::Role::Server,
+            None,
+        )
+        .await;
+        let relay = async {
+            loop {
+                let m = match tokio::time::timeout(talkback::POLL_INTERVAL, ws.next()).await {
+                    Err(_) => {
+                        if backchannel.idle() >= talkback::IDLE_TIMEOUT {
+                            info!("Ending idle talkback session");
+                            return Ok::<_, Error>(());
+                        }
+                        backchannel.keepalive().await?;
+                        continue;
+                    }
+                    Ok(None) => return Ok(()),
+                    Ok(Some(m)) => m?,
+                };
+                match m {
+                    tungstenite::Message::Binary(pcm) => backchannel.send(&pcm).await?,
+                    tungstenite::Message::Close(_) => return Ok(()),
+                    _ => {}
+                }
+            }
+        };
+        if let Err(e) = relay.await {
+            info!("Ending talkback session after error: {}", e);
+        }
+        backchannel.close().await;
     }
 
-    fn init_segment(
+    async fn stream_privacy_mask(
         &self,
-        sha1: [u8; 20],
-        debug: bool,
-        req: &Request<::hyper::Body>,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        type_: db::StreamType,
     ) -> ResponseResult {
-        let mut builder = mp4::FileBuilder::new(mp4::Type::InitSegment);
-        let db = self.db.lock();
-        for ent in db.video_sample_entries_by_id().values() {
-            if ent.sha1 == sha1 {
-                builder.append_video_sample_entry(ent.clone());
-                let mp4 = builder
-                    .build(self.db.clone(), self.dirs_by_stream_id.clone())
-                    .map_err(from_base_error)?;
-                if debug {
-                    return Ok(plain_response(StatusCode::OK, format!("{:#?}", mp4)));
-                } else {
-                    return Ok(http_serve::serve(mp4, req));
-                }
-            }
+        if *req.method() != http::method::Method::POST {
+            return Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST expected",
+            ));
         }
-        Err(not_found("no such init segment"))
+        if !caller.permissions.update_camera_configs {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "update_camera_configs required",
+            ));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostPrivacyMaskRequest =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let mask = db::mask::Mask::new(r.polygons).map_err(|e| bad_req(e.to_string()))?;
+        let mut l = self.db.lock();
+        let stream_id = l
+            .get_camera(uuid)
+            .and_then(|c| c.streams[type_.index()])
+            .ok_or_else(|| not_found(format!("no such stream {}/{}", uuid, type_.as_str())))?;
+        l.set_privacy_mask(stream_id, mask)
+            .map_err(internal_server_err)?;
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(b""[..].into())
+            .unwrap())
     }
 
-    fn stream_view_mp4(
+    fn stream_thumbnail(
         &self,
-        req: &Request<::hyper::Body>,
         caller: Caller,
         uuid: Uuid,
-        stream_type: db::StreamType,
-        mp4_type: mp4::Type,
-        debug: bool,
+        type_: db::StreamType,
+        id: i32,
     ) -> ResponseResult {
         if !caller.permissions.view_video {
             return Err(plain_response(
@@ -787,47 +2509,291 @@ impl Service {
                 "view_video required",
             ));
         }
-        let (stream_id, camera_name);
-        {
+        let jpeg = {
             let db = self.db.lock();
             let camera = db.get_camera(uuid).ok_or_else(|| {
                 plain_response(StatusCode::NOT_FOUND, format!("no such camera {}", uuid))
             })?;
-            camera_name = camera.short_name.clone();
-            stream_id = camera.streams[stream_type.index()].ok_or_else(|| {
+            let stream_id = camera.streams[type_.index()].ok_or_else(|| {
                 plain_response(
                     StatusCode::NOT_FOUND,
-                    format!("no such stream {}/{}", uuid, stream_type),
+                    format!("no such stream {}/{}", uuid, type_),
                 )
             })?;
+            db.get_thumbnail(db::CompositeId::new(stream_id, id))
+                .map_err(|e| not_found(e.to_string()))?
+                .ok_or_else(|| not_found(format!("recording {} has no thumbnail", id)))?
         };
-        let mut start_time_for_filename = None;
-        let mut builder = mp4::FileBuilder::new(mp4_type);
+        Ok(Response::builder()
+            .header(header::CONTENT_TYPE, HeaderValue::from_static("image/jpeg"))
+            .body(jpeg.into())
+            .unwrap())
+    }
+
+    /// Deletes the recordings overlapping the requested time range immediately, moving their
+    /// sample files to garbage for the syncer to unlink.
+    fn delete_stream_recordings(
+        &self,
+        req: &Request<::hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        type_: db::StreamType,
+    ) -> ResponseResult {
+        if !caller.permissions.delete_recordings {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "delete_recordings required",
+            ));
+        }
+        let (mut start, mut end) = (None, None);
         if let Some(q) = req.uri().query() {
             for (key, value) in form_urlencoded::parse(q.as_bytes()) {
                 let (key, value) = (key.borrow(), value.borrow());
                 match key {
-                    "s" => {
-                        let s = Segments::from_str(value).map_err(|()| {
-                            plain_response(
-                                StatusCode::BAD_REQUEST,
-                                format!("invalid s parameter: {}", value),
-                            )
-                        })?;
-                        debug!("stream_view_mp4: appending s={:?}", s);
-                        let mut est_segments = (s.ids.end - s.ids.start) as usize;
-                        if let Some(end) = s.end_time {
-                            // There should be roughly ceil((end - start) /
-                            // desired_recording_duration) recordings in the desired timespan if
-                            // there are no gaps or overlap, possibly another for misalignment of
-                            // the requested timespan with the rotate offset and another because
-                            // rotation only happens at key frames.
-                            let ceil_durations =
-                                (end - s.start_time + recording::DESIRED_RECORDING_DURATION - 1)
-                                    / recording::DESIRED_RECORDING_DURATION;
-                            est_segments = cmp::min(est_segments, (ceil_durations + 2) as usize);
-                        }
-                        builder.reserve(est_segments);
+                    "startTime90k" => {
+                        start = Some(
+                            recording::Time::parse(value)
+                                .map_err(|_| bad_req("unparseable startTime90k"))?,
+                        )
+                    }
+                    "endTime90k" => {
+                        end = Some(
+                            recording::Time::parse(value)
+                                .map_err(|_| bad_req("unparseable endTime90k"))?,
+                        )
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let time = match (start, end) {
+            (Some(s), Some(e)) if s < e => s..e,
+            (Some(_), Some(_)) => return Err(bad_req("startTime90k must be before endTime90k")),
+            _ => return Err(bad_req("startTime90k and endTime90k are required")),
+        };
+        let mut db = self.db.lock();
+        let camera = db
+            .get_camera(uuid)
+            .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?;
+        let stream_id = camera.streams[type_.index()]
+            .ok_or_else(|| not_found(format!("no such stream {}/{}", uuid, type_)))?;
+        let n = db
+            .delete_recordings(stream_id, time.clone())
+            .map_err(from_base_error)?;
+        db.flush("manual deletion").map_err(internal_server_err)?;
+        drop(db);
+        info!(
+            "deleted {} recordings of {}/{} from {} to {}",
+            n, uuid, type_, time.start, time.end
+        );
+        serve_json(req, &json::DeleteRecordingsResponse { recordings: n })
+    }
+
+    /// Reports what lowering the stream's retention limit to `retainBytes` would delete, without
+    /// deleting anything or changing the limit.
+    fn stream_retention_preview(
+        &self,
+        req: &Request<::hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        type_: db::StreamType,
+    ) -> ResponseResult {
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        let mut limit = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                if key == "retainBytes" {
+                    limit = Some(
+                        i64::from_str(value)
+                            .ok()
+                            .filter(|&b| b >= 0)
+                            .ok_or_else(|| bad_req("unparseable retainBytes"))?,
+                    );
+                }
+            }
+        }
+        let limit = limit.ok_or_else(|| bad_req("retainBytes is required"))?;
+        let mut db = self.db.lock();
+        let camera = db
+            .get_camera(uuid)
+            .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?;
+        let stream_id = camera.streams[type_.index()]
+            .ok_or_else(|| not_found(format!("no such stream {}/{}", uuid, type_)))?;
+        let previews =
+            writer::preview_lower_retention(&mut db, &[writer::NewLimit { stream_id, limit }])
+                .map_err(internal_server_err)?;
+        let streams = previews
+            .iter()
+            .filter_map(|(&id, p)| json::RetentionPreviewStream::new(&db, id, p))
+            .collect();
+        drop(db);
+        serve_json(req, &json::RetentionPreview { streams })
+    }
+
+    fn stream_recordings(
+        &self,
+        req: &Request<::hyper::Body>,
+        uuid: Uuid,
+        type_: db::StreamType,
+    ) -> ResponseResult {
+        let (r, split, limit, min_id) = {
+            let mut time = recording::Time::min_value()..recording::Time::max_value();
+            let mut split = recording::Duration(i64::max_value());
+            let mut limit = None;
+            let mut min_id = None;
+            if let Some(q) = req.uri().query() {
+                for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                    let (key, value) = (key.borrow(), value.borrow());
+                    match key {
+                        "startTime90k" => {
+                            time.start = recording::Time::parse(value)
+                                .map_err(|_| bad_req("unparseable startTime90k"))?
+                        }
+                        "endTime90k" => {
+                            time.end = recording::Time::parse(value)
+                                .map_err(|_| bad_req("unparseable endTime90k"))?
+                        }
+                        "split90k" => {
+                            split = recording::Duration(
+                                i64::from_str(value)
+                                    .map_err(|_| bad_req("unparseable split90k"))?,
+                            )
+                        }
+                        "limit" => {
+                            limit = Some(
+                                usize::from_str(value)
+                                    .ok()
+                                    .filter(|&l| l > 0 && l <= MAX_RECORDINGS_LIMIT)
+                                    .ok_or_else(|| bad_req("bad limit"))?,
+                            )
+                        }
+                        "continue" => {
+                            min_id = Some(
+                                decode_continue(value)
+                                    .ok_or_else(|| bad_req("unparseable continue"))?,
+                            )
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            if min_id.is_some() && limit.is_none() {
+                return Err(bad_req("continue requires limit"));
+            }
+            (time, split, limit, min_id.unwrap_or(0))
+        };
+        let db = self.db.lock();
+        let camera = db.get_camera(uuid).ok_or_else(|| {
+            plain_response(StatusCode::NOT_FOUND, format!("no such camera {}", uuid))
+        })?;
+        let stream_id = camera.streams[type_.index()].ok_or_else(|| {
+            plain_response(
+                StatusCode::NOT_FOUND,
+                format!("no such stream {}/{}", uuid, type_),
+            )
+        })?;
+        let (mut resp, writer) = http_serve::streaming_body(&req).build();
+        resp.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        if let Some(mut w) = writer {
+            write_recordings(&mut w, &db, stream_id, r, split, limit, min_id)
+                .map_err(internal_server_err)?;
+        }
+        Ok(resp)
+    }
+
+    fn init_segment(
+        &self,
+        sha1: [u8; 20],
+        debug: bool,
+        req: &Request<::hyper::Body>,
+    ) -> ResponseResult {
+        let mut builder = mp4::FileBuilder::new(mp4::Type::InitSegment);
+        let db = self.db.lock();
+        for ent in db.video_sample_entries_by_id().values() {
+            if ent.sha1 == sha1 {
+                builder.append_video_sample_entry(ent.clone());
+                let mp4 = builder
+                    .build(self.db.clone(), self.dirs_by_stream_id.clone())
+                    .map_err(from_base_error)?;
+                if debug {
+                    return Ok(plain_response(StatusCode::OK, format!("{:#?}", mp4)));
+                } else {
+                    return Ok(http_serve::serve(mp4, req));
+                }
+            }
+        }
+        Err(not_found("no such init segment"))
+    }
+
+    fn stream_view_mp4(
+        &self,
+        req: &Request<::hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+        mp4_type: mp4::Type,
+        debug: bool,
+    ) -> ResponseResult {
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        let (stream_id, camera_name);
+        {
+            let db = self.db.lock();
+            let camera = db.get_camera(uuid).ok_or_else(|| {
+                plain_response(StatusCode::NOT_FOUND, format!("no such camera {}", uuid))
+            })?;
+            camera_name = camera.short_name.clone();
+            stream_id = camera.streams[stream_type.index()].ok_or_else(|| {
+                plain_response(
+                    StatusCode::NOT_FOUND,
+                    format!("no such stream {}/{}", uuid, stream_type),
+                )
+            })?;
+        };
+        let mut start_time_for_filename = None;
+        let mut viewed = Vec::new();
+        let mut builder = mp4::FileBuilder::new(mp4_type);
+        if let Some(p) = self.read_pool.as_ref() {
+            builder.read_via(p.clone());
+        }
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "s" => {
+                        let s = Segments::from_str(value).map_err(|()| {
+                            plain_response(
+                                StatusCode::BAD_REQUEST,
+                                format!("invalid s parameter: {}", value),
+                            )
+                        })?;
+                        debug!("stream_view_mp4: appending s={:?}", s);
+                        let mut est_segments = (s.ids.end - s.ids.start) as usize;
+                        if let Some(end) = s.end_time {
+                            // There should be roughly ceil((end - start) /
+                            // desired_recording_duration) recordings in the desired timespan if
+                            // there are no gaps or overlap, possibly another for misalignment of
+                            // the requested timespan with the rotate offset and another because
+                            // rotation only happens at key frames.
+                            let ceil_durations =
+                                (end - s.start_time + recording::DESIRED_RECORDING_DURATION - 1)
+                                    / recording::DESIRED_RECORDING_DURATION;
+                            est_segments = cmp::min(est_segments, (ceil_durations + 2) as usize);
+                        }
+                        builder.reserve(est_segments);
                         let db = self.db.lock();
                         let mut prev = None;
                         let mut cur_off = 0;
@@ -872,6 +2838,10 @@ impl Service {
                                     start_time_for_filename =
                                         Some(r.start + recording::Duration(start));
                                 }
+                                viewed.push(
+                                    r.start + recording::Duration(start)
+                                        ..r.start + recording::Duration(end),
+                                );
                                 builder.append(&db, r, start as i32..end as i32)?;
                             } else {
                                 debug!("...skipping recording {} dur {}", r.id, d);
@@ -943,18 +2913,34 @@ impl Service {
         if debug {
             return Ok(plain_response(StatusCode::OK, format!("{:#?}", mp4)));
         }
+        if self.record_playback_heat && is_first_fetch(req) {
+            self.db.lock().record_playback(stream_id, &viewed);
+        }
         Ok(http_serve::serve(mp4, req))
     }
 
     async fn static_file(&self, req: Request<hyper::Body>) -> ResponseResult {
-        let dir = self
-            .ui_dir
-            .clone()
+        let ui = self
+            .ui
+            .as_ref()
             .ok_or_else(|| not_found("--ui-dir not configured; no static files available."))?;
         let static_req = match StaticFileRequest::parse(req.uri().path()) {
             None => return Err(not_found("static file not found")),
             Some(r) => r,
         };
+        let dir = match ui {
+            UiFiles::Dir(d) => d.clone(),
+
+            #[cfg(feature = "bundled-ui")]
+            UiFiles::Bundled => {
+                let asset = crate::bundled_ui::Asset::get(static_req.path, req.headers())
+                    .ok_or_else(|| not_found("no such static file"))?;
+                let mut hdrs = http::HeaderMap::new();
+                asset.add_encoding_headers(&mut hdrs);
+                static_req.add_headers(&mut hdrs);
+                return Ok(http_serve::serve(asset.into_entity(hdrs), &req));
+            }
+        };
         let f = dir.get(static_req.path, req.headers());
         let node = f.await.map_err(|e| {
             if e.kind() == std::io::ErrorKind::NotFound {
@@ -965,43 +2951,29 @@ impl Service {
         })?;
         let mut hdrs = http::HeaderMap::new();
         node.add_encoding_headers(&mut hdrs);
-        hdrs.insert(
-            header::CACHE_CONTROL,
-            HeaderValue::from_static(if static_req.immutable {
-                // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Cache-Control#Caching_static_assets
-                "public, max-age=604800, immutable"
-            } else {
-                "public"
-            }),
-        );
-        hdrs.insert(
-            header::CONTENT_TYPE,
-            HeaderValue::from_static(static_req.mime),
-        );
+        static_req.add_headers(&mut hdrs);
         let e = node.into_file_entity(hdrs).map_err(internal_server_err)?;
         Ok(http_serve::serve(e, &req))
     }
 
-    fn authreq(&self, req: &Request<::hyper::Body>) -> auth::Request {
+    fn authreq(&self, hdrs: &header::HeaderMap) -> auth::Request {
         auth::Request {
             when_sec: Some(self.db.clocks().realtime().sec),
             addr: if self.trust_forward_hdrs {
-                req.headers()
-                    .get("X-Real-IP")
+                hdrs.get("X-Real-IP")
                     .and_then(|v| v.to_str().ok())
                     .and_then(|v| IpAddr::from_str(v).ok())
             } else {
                 None
             },
-            user_agent: req
-                .headers()
+            user_agent: hdrs
                 .get(header::USER_AGENT)
                 .map(|ua| ua.as_bytes().to_vec()),
         }
     }
 
     fn request(&self, req: &Request<::hyper::Body>) -> ResponseResult {
-        let authreq = self.authreq(req);
+        let authreq = self.authreq(req.headers());
         let host = req
             .headers()
             .get(header::HOST)
@@ -1033,7 +3005,15 @@ impl Service {
         ))
     }
 
+    /// Returns true if session cookies set in response to `req` should be marked `Secure`.
+    fn cookie_is_secure(&self, req: &Request<::hyper::Body>) -> bool {
+        self.secure_cookies || self.is_secure(req)
+    }
+
     fn is_secure(&self, req: &Request<::hyper::Body>) -> bool {
+        if req.extensions().get::<ViaTls>().is_some() {
+            return true;
+        }
         self.trust_forward_hdrs
             && req
                 .headers()
@@ -1046,22 +3026,17 @@ impl Service {
         let r = extract_json_body(&mut req).await?;
         let r: json::LoginRequest =
             serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
-        let authreq = self.authreq(&req);
-        let host = req
-            .headers()
-            .get(header::HOST)
-            .ok_or_else(|| bad_req("missing Host header!"))?;
-        let host = host.as_bytes();
-        let domain = match memchr(b':', host) {
-            Some(colon) => &host[0..colon],
-            None => host,
-        }
-        .to_owned();
+        let authreq = self.authreq(req.headers());
+        let domain = domain(&req)?;
         let mut l = self.db.lock();
-        let is_secure = self.is_secure(&req);
+        let is_secure = self.cookie_is_secure(&req);
         let flags = (auth::SessionFlag::HttpOnly as i32)
             | (auth::SessionFlag::SameSite as i32)
-            | (auth::SessionFlag::SameSiteStrict as i32)
+            | if self.cookie_same_site == SameSite::Strict {
+                auth::SessionFlag::SameSiteStrict as i32
+            } else {
+                0
+            }
             | if is_secure {
                 auth::SessionFlag::Secure as i32
             } else {
@@ -1069,82 +3044,257 @@ impl Service {
             };
         let (sid, _) = l
             .login_by_password(authreq, &r.username, r.password, Some(domain), flags)
-            .map_err(|e| plain_response(StatusCode::UNAUTHORIZED, e.to_string()))?;
-        let s_suffix = if is_secure {
-            &b"; HttpOnly; Secure; SameSite=Strict; Max-Age=2147483648; Path=/"[..]
-        } else {
-            &b"; HttpOnly; SameSite=Strict; Max-Age=2147483648; Path=/"[..]
-        };
-        let mut encoded = [0u8; 64];
-        base64::encode_config_slice(&sid, base64::STANDARD_NO_PAD, &mut encoded);
-        let mut cookie = BytesMut::with_capacity("s=".len() + encoded.len() + s_suffix.len());
-        cookie.put(&b"s="[..]);
-        cookie.put(&encoded[..]);
-        cookie.put(s_suffix);
+            .map_err(|e| match e.downcast_ref::<auth::LoginThrottled>() {
+                Some(t) => {
+                    let mut resp = plain_response(StatusCode::TOO_MANY_REQUESTS, e.to_string());
+                    resp.headers_mut().insert(
+                        header::RETRY_AFTER,
+                        HeaderValue::from_str(&t.retry_after_sec.to_string()).unwrap(),
+                    );
+                    resp
+                }
+                None => plain_response(StatusCode::UNAUTHORIZED, e.to_string()),
+            })?;
         Ok(Response::builder()
             .header(
                 header::SET_COOKIE,
-                HeaderValue::from_maybe_shared(cookie.freeze())
-                    .expect("cookie can't have invalid bytes"),
+                session_cookie(&sid, is_secure, self.cookie_same_site.as_str()),
             )
             .status(StatusCode::NO_CONTENT)
             .body(b""[..].into())
             .unwrap())
     }
 
-    async fn logout(&self, mut req: Request<hyper::Body>) -> ResponseResult {
-        let r = extract_json_body(&mut req).await?;
-        let r: json::LogoutRequest =
-            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
-
-        let mut res = Response::new(b""[..].into());
-        if let Some(sid) = extract_sid(&req) {
-            let authreq = self.authreq(&req);
-            let mut l = self.db.lock();
-            let hash = sid.hash();
-            let need_revoke = match l.authenticate_session(authreq.clone(), &hash) {
-                Ok((s, _)) => {
-                    if !csrf_matches(r.csrf, s.csrf()) {
-                        warn!("logout request with missing/incorrect csrf");
-                        return Err(bad_req("logout with incorrect csrf token"));
-                    }
-                    info!("revoking session");
-                    true
-                }
-                Err(e) => {
-                    // TODO: distinguish "no such session", "session is no longer valid", and
-                    // "user ... is disabled" (which are all client error / bad state) from database
-                    // errors.
-                    warn!("logout failed: {}", e);
-                    false
+    /// Starts SAML login by redirecting to the identity provider.
+    ///
+    /// The optional `redirect` query parameter is a local path to return to afterward.
+    fn saml_login(&self, req: &Request<::hyper::Body>) -> ResponseResult {
+        let sp = self
+            .saml
+            .as_ref()
+            .ok_or_else(|| not_found("SAML is not configured"))?;
+        let mut redirect = "/".to_owned();
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                if key == "redirect" && is_local_path(&value) {
+                    redirect = value.into_owned();
                 }
-            };
-            if need_revoke {
-                // TODO: inline this above with non-lexical lifetimes.
-                l.revoke_session(auth::RevocationReason::LoggedOut, None, authreq, &hash)
-                    .map_err(internal_server_err)?;
             }
-
-            // By now the session is invalid (whether it was valid to start with or not).
-            // Clear useless cookie.
-            res.headers_mut().append(
-                header::SET_COOKIE,
-                HeaderValue::from_str("s=; Max-Age=0; Path=/").unwrap(),
-            );
         }
-        *res.status_mut() = StatusCode::NO_CONTENT;
-        Ok(res)
+        let url = sp
+            .login_url(&redirect, self.db.clocks().realtime())
+            .map_err(internal_server_err)?;
+        Ok(Response::builder()
+            .header(header::LOCATION, url)
+            .status(StatusCode::SEE_OTHER)
+            .body(b""[..].into())
+            .unwrap())
     }
 
-    async fn post_signals(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
-        if !caller.permissions.update_signals {
+    /// Completes SAML login: verifies the identity provider's response and starts a session.
+    async fn saml_acs(&self, mut req: Request<::hyper::Body>) -> ResponseResult {
+        let sp = self
+            .saml
+            .as_ref()
+            .ok_or_else(|| not_found("SAML is not configured"))?;
+        if *req.method() != http::method::Method::POST {
             return Err(plain_response(
-                StatusCode::UNAUTHORIZED,
-                "update_signals required",
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST expected",
             ));
         }
-        let r = extract_json_body(&mut req).await?;
-        let r: json::PostSignalsRequest =
+        let b = ::std::mem::replace(req.body_mut(), hyper::Body::empty());
+        let b = hyper::body::to_bytes(b)
+            .await
+            .map_err(|e| internal_server_err(format_err!("unable to read request body: {}", e)))?;
+        let mut saml_response = None;
+        let mut redirect = "/".to_owned();
+        for (key, value) in form_urlencoded::parse(&b) {
+            match &*key {
+                "SAMLResponse" => saml_response = Some(value.into_owned()),
+                "RelayState" if is_local_path(&value) => redirect = value.into_owned(),
+                _ => {}
+            }
+        }
+        let saml_response = saml_response.ok_or_else(|| bad_req("missing SAMLResponse"))?;
+        let login = sp
+            .process_response(&saml_response, self.db.clocks().realtime().sec)
+            .map_err(|e| {
+                warn!("SAML login failed: {}", e);
+                plain_response(StatusCode::UNAUTHORIZED, e.to_string())
+            })?;
+        self.sso_session(&req, "SAML", &login.username, login.permissions, redirect)
+    }
+
+    /// Starts OpenID Connect login by redirecting to the provider.
+    ///
+    /// The optional `redirect` query parameter is a local path to return to afterward.
+    async fn oidc_login(&self, req: Request<::hyper::Body>) -> ResponseResult {
+        let rp = self
+            .oidc
+            .as_ref()
+            .ok_or_else(|| not_found("OpenID Connect is not configured"))?;
+        let mut redirect = "/".to_owned();
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                if key == "redirect" && is_local_path(&value) {
+                    redirect = value.into_owned();
+                }
+            }
+        }
+        let url = rp
+            .login_url(&redirect, self.db.clocks().realtime().sec)
+            .await
+            .map_err(|e| {
+                warn!("Unable to start OpenID Connect login: {}", e);
+                plain_response(StatusCode::BAD_GATEWAY, e.to_string())
+            })?;
+        Ok(Response::builder()
+            .header(header::LOCATION, url)
+            .status(StatusCode::SEE_OTHER)
+            .body(b""[..].into())
+            .unwrap())
+    }
+
+    /// Completes OpenID Connect login: redeems the provider's code and starts a session.
+    async fn oidc_callback(&self, req: Request<::hyper::Body>) -> ResponseResult {
+        let rp = self
+            .oidc
+            .as_ref()
+            .ok_or_else(|| not_found("OpenID Connect is not configured"))?;
+        let (mut code, mut state, mut error) = (None, None, None);
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                match &*key {
+                    "code" => code = Some(value.into_owned()),
+                    "state" => state = Some(value.into_owned()),
+                    "error" => error = Some(value.into_owned()),
+                    _ => {}
+                }
+            }
+        }
+        if let Some(e) = error {
+            warn!("OpenID Connect provider returned error {:?}", e);
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                format!("provider returned error {}", e),
+            ));
+        }
+        let code = code.ok_or_else(|| bad_req("missing code"))?;
+        let state = state.ok_or_else(|| bad_req("missing state"))?;
+        let login = rp
+            .process_callback(&code, &state, self.db.clocks().realtime().sec)
+            .await
+            .map_err(|e| {
+                warn!("OpenID Connect login failed: {}", e);
+                plain_response(StatusCode::UNAUTHORIZED, e.to_string())
+            })?;
+        self.sso_session(
+            &req,
+            "OpenID Connect",
+            &login.username,
+            login.permissions,
+            login.redirect,
+        )
+    }
+
+    /// Starts a session for a user authenticated by single sign-on and redirects to `redirect`.
+    /// `permissions` overrides the user's own, if set. The user must already exist.
+    fn sso_session(
+        &self,
+        req: &Request<::hyper::Body>,
+        method: &str,
+        username: &str,
+        permissions: Option<db::Permissions>,
+        redirect: String,
+    ) -> ResponseResult {
+        let authreq = self.authreq(req.headers());
+        let domain = domain(req)?;
+        let is_secure = self.cookie_is_secure(req);
+
+        // The session cookie is set on a cross-site request from the identity provider and
+        // must be sent on the redirect that follows, so it can't be SameSite=Strict.
+        let flags = (auth::SessionFlag::HttpOnly as i32)
+            | (auth::SessionFlag::SameSite as i32)
+            | if is_secure {
+                auth::SessionFlag::Secure as i32
+            } else {
+                0
+            };
+        let mut l = self.db.lock();
+        let (uid, user_permissions) = match l.get_user(username) {
+            None => {
+                warn!("{} login for unknown user {:?}", method, username);
+                return Err(plain_response(StatusCode::UNAUTHORIZED, "no such user"));
+            }
+            Some(u) => (u.id, u.permissions.clone()),
+        };
+        let permissions = permissions.unwrap_or(user_permissions);
+        let (sid, _) = l
+            .make_session(authreq, uid, Some(domain), flags, permissions)
+            .map_err(|e| plain_response(StatusCode::UNAUTHORIZED, e.to_string()))?;
+        info!("{} login for user {:?}", method, username);
+        Ok(Response::builder()
+            .header(header::SET_COOKIE, session_cookie(&sid, is_secure, "Lax"))
+            .header(header::LOCATION, redirect)
+            .status(StatusCode::SEE_OTHER)
+            .body(b""[..].into())
+            .unwrap())
+    }
+
+    async fn logout(&self, mut req: Request<hyper::Body>) -> ResponseResult {
+        let r = extract_json_body(&mut req).await?;
+        let r: json::LogoutRequest =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+
+        let mut res = Response::new(b""[..].into());
+        if let Some(sid) = extract_sid(req.headers()) {
+            let authreq = self.authreq(req.headers());
+            let mut l = self.db.lock();
+            let hash = sid.hash();
+            let need_revoke = match l.authenticate_session(authreq.clone(), &hash) {
+                Ok((s, _)) => {
+                    if !csrf_matches(r.csrf, s.csrf()) {
+                        warn!("logout request with missing/incorrect csrf");
+                        return Err(bad_req("logout with incorrect csrf token"));
+                    }
+                    info!("revoking session");
+                    true
+                }
+                Err(e) => {
+                    // TODO: distinguish "no such session", "session is no longer valid", and
+                    // "user ... is disabled" (which are all client error / bad state) from database
+                    // errors.
+                    warn!("logout failed: {}", e);
+                    false
+                }
+            };
+            if need_revoke {
+                // TODO: inline this above with non-lexical lifetimes.
+                l.revoke_session(auth::RevocationReason::LoggedOut, None, authreq, &hash)
+                    .map_err(internal_server_err)?;
+            }
+
+            // By now the session is invalid (whether it was valid to start with or not).
+            // Clear useless cookie.
+            res.headers_mut().append(
+                header::SET_COOKIE,
+                HeaderValue::from_str("s=; Max-Age=0; Path=/").unwrap(),
+            );
+        }
+        *res.status_mut() = StatusCode::NO_CONTENT;
+        Ok(res)
+    }
+
+    async fn post_signals(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if !caller.permissions.update_signals {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "update_signals required",
+            ));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostSignalsRequest =
             serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
         let mut l = self.db.lock();
         let now = recording::Time::new(self.db.clocks().realtime());
@@ -1158,10 +3308,1923 @@ impl Service {
             json::PostSignalsEndBase::Now => {
                 now + recording::Duration(r.rel_end_time_90k.unwrap_or(0))
             }
-        };
-        l.update_signals(start..end, &r.signal_ids, &r.states)
-            .map_err(from_base_error)?;
-        serve_json(&req, &json::PostSignalsResponse { time_90k: now.0 })
+        };
+        l.update_signals(start..end, &r.signal_ids, &r.states)
+            .map_err(from_base_error)?;
+        serve_json(&req, &json::PostSignalsResponse { time_90k: now.0 })
+    }
+
+    /// Maps a request's cameras to signal associations, treating cameras the caller may not
+    /// access as nonexistent.
+    fn signal_cameras(
+        l: &db::LockedDatabase,
+        caller: &Caller,
+        cameras: &BTreeMap<Uuid, json::SignalCameraType>,
+    ) -> Result<Vec<db::signal::SignalCamera>, Response<Body>> {
+        cameras
+            .iter()
+            .map(|(&uuid, &type_)| match l.get_camera(uuid) {
+                Some(c) if caller.may_access_camera(c.id) => Ok(db::signal::SignalCamera {
+                    camera_id: c.id,
+                    type_: type_.to_db(),
+                }),
+                _ => Err(not_found(format!("no such camera {}", uuid))),
+            })
+            .collect()
+    }
+
+    async fn new_signal(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if *req.method() != http::method::Method::POST {
+            return Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST expected",
+            ));
+        }
+        if !caller.permissions.update_signals {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "update_signals required",
+            ));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostSignalRequest =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let states = r
+            .states
+            .into_iter()
+            .map(|s| db::signal::TypeState {
+                value: s.value,
+                name: s.name,
+                motion: s.motion,
+                color: s.color,
+            })
+            .collect();
+        let mut l = self.db.lock();
+        let cameras = Service::signal_cameras(&l, &caller, &r.cameras)?;
+        let id = l
+            .add_signal(
+                r.source.unwrap_or_else(Uuid::new_v4),
+                r.type_,
+                r.short_name,
+                states,
+                cameras,
+            )
+            .map_err(from_base_error)?;
+        drop(l);
+        serve_json(&req, &json::PostSignalResponse { id })
+    }
+
+    async fn signal(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        id: u32,
+    ) -> ResponseResult {
+        use http::method::Method;
+        match *req.method() {
+            Method::GET | Method::HEAD => {
+                let l = self.db.lock();
+                let s = l
+                    .signals_by_id()
+                    .get(&id)
+                    .ok_or_else(|| not_found(format!("no such signal {}", id)))?;
+                return serve_json(&req, &json::Signal::wrap(s, &l, false));
+            }
+            Method::POST | Method::DELETE => {}
+            _ => {
+                return Err(plain_response(
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "GET, HEAD, POST, or DELETE expected",
+                ))
+            }
+        }
+        if !caller.permissions.update_signals {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "update_signals required",
+            ));
+        }
+        if *req.method() == Method::DELETE {
+            self.db.lock().delete_signal(id).map_err(from_base_error)?;
+        } else {
+            let r = extract_json_body(&mut req).await?;
+            let r: json::UpdateSignalRequest =
+                serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+            let mut l = self.db.lock();
+            let cameras = Service::signal_cameras(&l, &caller, &r.cameras)?;
+            l.update_signal(id, r.short_name, cameras)
+                .map_err(from_base_error)?;
+        }
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(b""[..].into())
+            .unwrap())
+    }
+
+    async fn protect(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if *req.method() != http::method::Method::POST {
+            return Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST expected",
+            ));
+        }
+        if !caller.permissions.protect_recordings {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "protect_recordings required",
+            ));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostProtectRequest =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let mut l = self.db.lock();
+        let now = recording::Time::new(self.db.clocks().realtime());
+        let start = match (r.start_time_90k, r.duration_90k) {
+            (Some(_), Some(_)) => {
+                return Err(bad_req(
+                    "specify at most one of startTime90k and duration90k",
+                ))
+            }
+            (Some(s), None) => recording::Time(s),
+            (None, d) => now - recording::Duration(d.unwrap_or(DEFAULT_PROTECT_DURATION_90K)),
+        };
+        let upload_command = if r.upload {
+            Some(
+                l.get_config("protect_upload_command")
+                    .map_err(internal_server_err)?
+                    .ok_or_else(|| bad_req("no protect_upload_command is configured"))?,
+            )
+        } else {
+            None
+        };
+        let cameras = match r.cameras {
+            Some(c) => c,
+            None => l
+                .cameras_by_id()
+                .values()
+                .filter(|c| caller.may_access_camera(c.id))
+                .map(|c| c.uuid)
+                .collect(),
+        };
+        let mut stream_ids = Vec::new();
+        for &uuid in &cameras {
+            let camera = l
+                .get_camera(uuid)
+                .filter(|c| caller.may_access_camera(c.id))
+                .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?;
+            stream_ids.extend(camera.streams.iter().filter_map(|&s| s));
+        }
+        let recordings = protect_streams(
+            l,
+            &cameras,
+            &stream_ids,
+            start..now,
+            !r.unprotect,
+            upload_command,
+        )
+        .map_err(internal_server_err)?;
+        serve_json(
+            &req,
+            &json::PostProtectResponse {
+                start_time_90k: start.0,
+                end_time_90k: now.0,
+                recordings,
+            },
+        )
+    }
+
+    async fn post_note(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if *req.method() != http::method::Method::POST {
+            return Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST expected",
+            ));
+        }
+        if !caller.permissions.write_notes {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "write_notes required",
+            ));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostNoteRequest =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let now = self.db.clocks().realtime();
+        let start = r
+            .start_time_90k
+            .map(recording::Time)
+            .unwrap_or_else(|| recording::Time::new(now));
+        let end = r.end_time_90k.map(recording::Time).unwrap_or(start);
+        let mut l = self.db.lock();
+        let camera_id = match r.camera_uuid {
+            None => {
+                caller.require_all_cameras()?;
+                None
+            }
+            Some(uuid) => Some(
+                l.get_camera(uuid)
+                    .filter(|c| caller.may_access_camera(c.id))
+                    .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?
+                    .id,
+            ),
+        };
+        let id = l
+            .add_note(&db::search::NoteToInsert {
+                camera_id,
+                time: start..end,
+                creation_time_sec: now.sec,
+                author: caller.session.map(|s| s.username),
+                text: r.text,
+            })
+            .map_err(from_base_error)?;
+        serve_json(&req, &json::PostNoteResponse { id })
+    }
+
+    fn delete_note(&self, req: &Request<hyper::Body>, caller: Caller, id: i64) -> ResponseResult {
+        if *req.method() != http::method::Method::DELETE {
+            return Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "DELETE expected",
+            ));
+        }
+        if !caller.permissions.write_notes {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "write_notes required",
+            ));
+        }
+        caller.require_all_cameras()?;
+        self.db.lock().delete_note(id).map_err(from_base_error)?;
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(b""[..].into())
+            .unwrap())
+    }
+
+    async fn bookmarks(&self, req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        use http::method::Method;
+        match *req.method() {
+            Method::POST => self.post_bookmark(req, caller).await,
+            Method::GET | Method::HEAD => self.list_bookmarks(&req, caller),
+            _ => Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST, GET, or HEAD expected",
+            )),
+        }
+    }
+
+    async fn post_bookmark(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if !caller.permissions.protect_recordings {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "protect_recordings required",
+            ));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostBookmarkRequest =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let now = self.db.clocks().realtime();
+        let mut l = self.db.lock();
+        let camera_id = match r.camera_uuid {
+            None => {
+                caller.require_all_cameras()?;
+                None
+            }
+            Some(uuid) => Some(
+                l.get_camera(uuid)
+                    .filter(|c| caller.may_access_camera(c.id))
+                    .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?
+                    .id,
+            ),
+        };
+        let time = recording::Time(r.start_time_90k)..recording::Time(r.end_time_90k);
+        let id = l
+            .add_bookmark(&db::bookmark::BookmarkToInsert {
+                camera_id,
+                time: time.clone(),
+                label: r.label,
+                creation_time_sec: now.sec,
+                author: caller.session.map(|s| s.username),
+            })
+            .map_err(from_base_error)?;
+        drop(l);
+        info!(
+            "added bookmark {} protecting {} to {} on {}",
+            id,
+            time.start,
+            time.end,
+            r.camera_uuid
+                .map(|u| u.to_string())
+                .unwrap_or_else(|| "all cameras".to_owned())
+        );
+        serve_json(&req, &json::PostBookmarkResponse { id })
+    }
+
+    fn list_bookmarks(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        let mut time = recording::Time::min_value()..recording::Time::max_value();
+        let mut camera_uuid = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startTime90k" => {
+                        time.start = recording::Time::parse(value)
+                            .map_err(|_| bad_req("unparseable startTime90k"))?
+                    }
+                    "endTime90k" => {
+                        time.end = recording::Time::parse(value)
+                            .map_err(|_| bad_req("unparseable endTime90k"))?
+                    }
+                    "cameraUuid" => {
+                        camera_uuid = Some(
+                            Uuid::parse_str(value)
+                                .map_err(|_| bad_req("unparseable cameraUuid"))?,
+                        )
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let l = self.db.lock();
+        let camera_id = match camera_uuid {
+            None => None,
+            Some(uuid) => Some(
+                l.get_camera(uuid)
+                    .filter(|c| caller.may_access_camera(c.id))
+                    .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?
+                    .id,
+            ),
+        };
+        let mut out = json::Bookmarks {
+            bookmarks: Vec::new(),
+        };
+        l.list_bookmarks(time, &mut |b| {
+            if camera_id.map(|c| b.applies_to(c)).unwrap_or(true)
+                && b.camera_id
+                    .map(|c| caller.may_access_camera(c))
+                    .unwrap_or(true)
+            {
+                out.bookmarks.push(json::Bookmark {
+                    id: b.id,
+                    camera_uuid: b.camera_id.map(|c| l.cameras_by_id().get(&c).unwrap().uuid),
+                    start_time_90k: b.time.start.0,
+                    end_time_90k: b.time.end.0,
+                    label: b.label,
+                    creation_time_sec: b.creation_time_sec,
+                    author: b.author,
+                });
+            }
+            Ok(())
+        })
+        .map_err(from_base_error)?;
+        serve_json(req, &out)
+    }
+
+    fn delete_bookmark(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        id: i64,
+    ) -> ResponseResult {
+        if *req.method() != http::method::Method::DELETE {
+            return Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "DELETE expected",
+            ));
+        }
+        if !caller.permissions.protect_recordings {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "protect_recordings required",
+            ));
+        }
+        caller.require_all_cameras()?;
+        self.db
+            .lock()
+            .delete_bookmark(id)
+            .map_err(from_base_error)?;
+        info!("deleted bookmark {}", id);
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(b""[..].into())
+            .unwrap())
+    }
+
+    fn export_dir(&self) -> Result<&std::path::Path, Response<Body>> {
+        self.export_dir
+            .as_ref()
+            .map(|d| d.as_path())
+            .ok_or_else(|| {
+                plain_response(
+                    StatusCode::PRECONDITION_FAILED,
+                    "exports aren't enabled; see --export-dir",
+                )
+            })
+    }
+
+    /// Returns the given export job as JSON, if the caller may see all of its streams.
+    fn export_json(
+        l: &db::LockedDatabase,
+        caller: &Caller,
+        j: db::export::ExportJob,
+    ) -> Option<json::Export> {
+        let mut streams = Vec::with_capacity(j.stream_ids.len());
+        for &id in &j.stream_ids {
+            let s = l.streams_by_id().get(&id)?;
+            if !caller.may_access_camera(s.camera_id) {
+                return None;
+            }
+            let c = l.cameras_by_id().get(&s.camera_id)?;
+            streams.push(format!("{}/{}", c.uuid, s.type_));
+        }
+        Some(json::Export {
+            id: j.id,
+            state: j.state.as_str(),
+            creation_time_sec: j.creation_time_sec,
+            author: j.author,
+            start_time_90k: j.time.start.0,
+            end_time_90k: j.time.end.0,
+            streams,
+            sign: j.sign,
+            mask: j.mask,
+            progress_bytes: j.progress_bytes,
+            total_bytes: j.total_bytes,
+            file_name: j.file_name,
+            error: j.error,
+            finish_time_sec: j.finish_time_sec,
+        })
+    }
+
+    async fn exports(&self, req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        use http::method::Method;
+        match *req.method() {
+            Method::POST => self.post_export(req, caller).await,
+            Method::GET | Method::HEAD => self.list_exports(&req, caller),
+            _ => Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST, GET, or HEAD expected",
+            )),
+        }
+    }
+
+    async fn post_export(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        self.export_dir()?;
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostExportRequest =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        if r.streams.is_empty() || r.streams.len() > MAX_PLAYBACK_STREAMS {
+            return Err(bad_req(format!(
+                "between 1 and {} streams required",
+                MAX_PLAYBACK_STREAMS
+            )));
+        }
+        if r.start_time_90k >= r.end_time_90k {
+            return Err(bad_req("startTime90k must be before endTime90k"));
+        }
+        let time = recording::Time(r.start_time_90k)..recording::Time(r.end_time_90k);
+        let now = self.db.clocks().realtime();
+        let mut l = self.db.lock();
+        let mut stream_ids = Vec::with_capacity(r.streams.len());
+        for s in &r.streams {
+            let (uuid, type_) =
+                parse_playback_stream(s).ok_or_else(|| bad_req(format!("bad stream {:?}", s)))?;
+            let stream_id = l
+                .get_camera(uuid)
+                .filter(|c| caller.may_access_camera(c.id))
+                .and_then(|c| c.streams[type_.index()])
+                .ok_or_else(|| not_found(format!("no such stream {}", s)))?;
+            if !stream_ids.contains(&stream_id) {
+                stream_ids.push(stream_id);
+            }
+        }
+        let id = l
+            .add_export_job(&db::export::ExportJobToInsert {
+                creation_time_sec: now.sec,
+                author: caller.session.map(|s| s.username),
+                time: time.clone(),
+                stream_ids,
+                sign: r.sign,
+                mask: r.mask,
+            })
+            .map_err(from_base_error)?;
+        drop(l);
+        info!(
+            "queued export {} of {} to {} from {}",
+            id,
+            time.start,
+            time.end,
+            r.streams.join(", ")
+        );
+        serve_json(&req, &json::PostExportResponse { id })
+    }
+
+    fn list_exports(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        let l = self.db.lock();
+        let mut out = json::Exports {
+            exports: Vec::new(),
+        };
+        l.list_export_jobs(&mut |j| {
+            out.exports.extend(Service::export_json(&l, &caller, j));
+            Ok(())
+        })
+        .map_err(from_base_error)?;
+        drop(l);
+        serve_json(req, &out)
+    }
+
+    async fn verify_export(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if *req.method() != http::method::Method::POST {
+            return Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST expected",
+            ));
+        }
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::VerifyExportRequest =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let valid = evidence::verify(&r.signature, r.manifest.as_bytes());
+        let signer = evidence::Signer::load(&self.db.lock()).map_err(internal_server_err)?;
+        let signed_by_server = match signer {
+            Some(s) if valid => {
+                base64::decode(&r.signature.public_key).ok().as_deref() == Some(s.public_key())
+            }
+            _ => false,
+        };
+        serve_json(
+            &req,
+            &json::VerifyExportResponse {
+                valid,
+                signed_by_server,
+            },
+        )
+    }
+
+    fn export(&self, req: &Request<hyper::Body>, caller: Caller, id: i64) -> ResponseResult {
+        use http::method::Method;
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        let no_such_export = || not_found(format!("no such export {}", id));
+        let mut l = self.db.lock();
+        let j = l.get_export_job(id).map_err(from_base_error)?;
+        let out = Service::export_json(&l, &caller, j).ok_or_else(no_such_export)?;
+        match *req.method() {
+            Method::GET | Method::HEAD => {
+                drop(l);
+                serve_json(req, &out)
+            }
+            Method::DELETE => {
+                // Cancel a queued or running job, leaving its row for the exporter to notice.
+                // A finished job is deleted along with its file.
+                let now = self.db.clocks().realtime();
+                if l.cancel_export_job(id, now.sec)
+                    .map_err(from_base_error)?
+                    .is_finished()
+                {
+                    let j = l.delete_export_job(id).map_err(from_base_error)?;
+                    drop(l);
+                    if let Some(f) = j.file_name {
+                        let p = self.export_dir()?.join(f);
+                        if let Err(e) = std::fs::remove_file(&p) {
+                            if e.kind() != std::io::ErrorKind::NotFound {
+                                warn!("unable to remove export file {}: {}", p.display(), e);
+                            }
+                        }
+                    }
+                    info!("deleted export {}", id);
+                } else {
+                    info!("canceled export {}", id);
+                }
+                Ok(Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(b""[..].into())
+                    .unwrap())
+            }
+            _ => Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, or DELETE expected",
+            )),
+        }
+    }
+
+    async fn export_download(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        id: i64,
+    ) -> ResponseResult {
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        let dir = self.export_dir()?;
+        let (state, out) = {
+            let l = self.db.lock();
+            let j = l.get_export_job(id).map_err(from_base_error)?;
+            let state = j.state;
+            let out = Service::export_json(&l, &caller, j)
+                .ok_or_else(|| not_found(format!("no such export {}", id)))?;
+            (state, out)
+        };
+        let file_name = match out.file_name {
+            Some(f) if state == db::export::ExportState::Done => f,
+            _ => {
+                return Err(plain_response(
+                    StatusCode::CONFLICT,
+                    format!("export {} is {}", id, state.as_str()),
+                ))
+            }
+        };
+        let path = dir.join(&file_name);
+        let mut hdrs = http::HeaderMap::new();
+        let content_type = if file_name.ends_with(".zip") {
+            "application/zip"
+        } else {
+            "video/mp4"
+        };
+        hdrs.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        hdrs.insert(
+            header::CONTENT_DISPOSITION,
+            HeaderValue::from_str(&format!("attachment; filename=\"{}\"", file_name))
+                .map_err(internal_server_err)?,
+        );
+        let e = tokio::task::spawn_blocking(move || {
+            let f = std::fs::File::open(&path)?;
+            http_serve::ChunkedReadFile::new(f, hdrs)
+        })
+        .await
+        .map_err(internal_server_err)?
+        .map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                not_found(format!("export {}'s file is missing", id))
+            } else {
+                internal_server_err(e)
+            }
+        })?;
+        Ok(http_serve::serve(e, req))
+    }
+
+    /// Executes a GraphQL query; see `graphql.rs` and `design/api.md`.
+    #[cfg(feature = "graphql")]
+    async fn graphql(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        let r = extract_json_body(&mut req).await?;
+        let r: graphql::Request = serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let ctx = graphql::Context::new(self.db.clone(), caller);
+        let (ok, body) = graphql::execute(&self.graphql_schema, &ctx, &r);
+        Ok(Response::builder()
+            .status(if ok {
+                StatusCode::OK
+            } else {
+                StatusCode::BAD_REQUEST
+            })
+            .header(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            )
+            .body(body.into())
+            .expect("hardcoded head should be valid"))
+    }
+
+    #[cfg(not(feature = "graphql"))]
+    async fn graphql(&self, _req: Request<hyper::Body>, _caller: Caller) -> ResponseResult {
+        Err(not_found("built without the graphql feature"))
+    }
+
+    /// Predicts the footage each stream would keep under hypothetical retention limits; see
+    /// `db/retention.rs` and `design/api.md`.
+    async fn retention_simulation(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+    ) -> ResponseResult {
+        if *req.method() != http::method::Method::POST {
+            return Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST expected",
+            ));
+        }
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::RetentionSimulationRequest =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let window_days = r.window_days.unwrap_or(db::retention::DEFAULT_WINDOW_DAYS);
+        if !(1..=db::retention::MAX_WINDOW_DAYS).contains(&window_days) {
+            return Err(bad_req(format!(
+                "windowDays must be between 1 and {}",
+                db::retention::MAX_WINDOW_DAYS
+            )));
+        }
+        let window = db::retention::window(
+            recording::Time::new(self.db.clocks().realtime()),
+            window_days,
+        );
+        let l = self.db.lock();
+        let mut limits = FnvHashMap::default();
+        for (s, limit) in &r.streams {
+            let (uuid, type_) =
+                parse_playback_stream(s).ok_or_else(|| bad_req(format!("bad stream {:?}", s)))?;
+            let stream_id = l
+                .get_camera(uuid)
+                .filter(|c| caller.may_access_camera(c.id))
+                .and_then(|c| c.streams[type_.index()])
+                .ok_or_else(|| not_found(format!("no such stream {}", s)))?;
+            let limit = limit
+                .to_limit()
+                .map_err(|e| bad_req(format!("stream {}: {}", s, e)))?;
+            limits.insert(stream_id, limit);
+        }
+        let mut out = json::RetentionSimulation {
+            streams: Vec::new(),
+        };
+        for (&id, s) in l.streams_by_id() {
+            if s.sample_file_dir_id.is_none() || !caller.may_access_camera(s.camera_id) {
+                continue;
+            }
+            let usage = l
+                .stream_usage(id, window.clone())
+                .map_err(internal_server_err)?;
+            out.streams.extend(json::RetentionSimulationStream::new(
+                &l,
+                id,
+                &usage,
+                limits.get(&id).copied(),
+            ));
+        }
+        drop(l);
+        serve_json(&req, &out)
+    }
+
+    fn search(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        let mut time = recording::Time::min_value()..recording::Time::max_value();
+        let mut q = None;
+        let mut limit = DEFAULT_SEARCH_LIMIT;
+        if let Some(query) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "q" => q = Some(value.to_owned()),
+                    "startTime90k" => {
+                        time.start = recording::Time::parse(value)
+                            .map_err(|_| bad_req("unparseable startTime90k"))?
+                    }
+                    "endTime90k" => {
+                        time.end = recording::Time::parse(value)
+                            .map_err(|_| bad_req("unparseable endTime90k"))?
+                    }
+                    "limit" => {
+                        limit = u32::from_str(value)
+                            .ok()
+                            .filter(|&l| l > 0 && l <= MAX_SEARCH_LIMIT)
+                            .ok_or_else(|| bad_req("bad limit"))?
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let q = q.ok_or_else(|| bad_req("q is required"))?;
+        let db = self.db.lock();
+        let mut out = json::SearchResults::default();
+        db.search(&q, time, limit, &mut |r| {
+            let camera_id = match r.hit {
+                db::search::SearchHit::Camera { camera_id } => Some(camera_id),
+                db::search::SearchHit::Note { camera_id, .. } => camera_id,
+                db::search::SearchHit::Detection { recording_id, .. } => db
+                    .streams_by_id()
+                    .get(&recording_id.stream())
+                    .map(|s| s.camera_id),
+            };
+            if !camera_id
+                .map(|c| caller.may_access_camera(c))
+                .unwrap_or(true)
+            {
+                return Ok(());
+            }
+            let mut result = json::SearchResult {
+                type_: "",
+                id: None,
+                camera_uuid: None,
+                stream: None,
+                recording_id: None,
+                start_time_90k: None,
+                end_time_90k: None,
+                score: r.score,
+                snippet: r.snippet,
+            };
+            let camera_uuid = |id| db.cameras_by_id().get(&id).map(|c| c.uuid);
+            match r.hit {
+                db::search::SearchHit::Camera { camera_id } => {
+                    result.type_ = "camera";
+                    result.camera_uuid = camera_uuid(camera_id);
+                }
+                db::search::SearchHit::Note {
+                    id,
+                    camera_id,
+                    time,
+                } => {
+                    result.type_ = "note";
+                    result.id = Some(id);
+                    result.camera_uuid = camera_id.and_then(camera_uuid);
+                    result.start_time_90k = Some(time.start.0);
+                    result.end_time_90k = Some(time.end.0);
+                }
+                db::search::SearchHit::Detection {
+                    id,
+                    recording_id,
+                    time,
+                } => {
+                    result.type_ = "detection";
+                    result.id = Some(id);
+                    if let Some(s) = db.streams_by_id().get(&recording_id.stream()) {
+                        result.camera_uuid = camera_uuid(s.camera_id);
+                        result.stream = Some(s.type_.as_str());
+                    }
+                    result.recording_id = Some(recording_id.recording());
+                    result.start_time_90k = Some(time.start.0);
+                    result.end_time_90k = Some(time.end.0);
+                }
+            }
+            out.results.push(result);
+            Ok(())
+        })
+        .map_err(internal_server_err)?;
+        serve_json(req, &out)
+    }
+
+    /// Answers an ACME `HTTP-01` challenge for the certificate authority; see `tls.rs`.
+    fn acme_challenge(&self, token: &str) -> ResponseResult {
+        match self.acme_challenges.as_ref().and_then(|c| c.get(token)) {
+            Some(proof) => Ok(plain_response(StatusCode::OK, proof)),
+            None => Err(not_found("no such challenge")),
+        }
+    }
+
+    /// Reports that the server is up and able to answer requests; see `design/api.md`.
+    fn health_live(&self, req: &Request<hyper::Body>) -> ResponseResult {
+        serve_json(
+            req,
+            &json::Health {
+                status: json::HealthStatus::Ok,
+                db: None,
+                dirs: Vec::new(),
+                streams: Vec::new(),
+            },
+        )
+    }
+
+    /// Reports the liveness of each stream the caller may access; see `design/api.md`.
+    fn events(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if *req.method() != http::method::Method::GET {
+            return Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET expected",
+            ));
+        }
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        let rx = self.events.subscribe();
+
+        // A single-slot channel, so a slow client falls behind on the broadcast channel (and is
+        // told how many events it missed) rather than buffering here.
+        let (tx, body_rx) = futures::channel::mpsc::channel(1);
+        tokio::spawn(relay_events(caller, rx, tx));
+        let body: BodyStream = Box::new(body_rx);
+        Ok(Response::builder()
+            .header(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("text/event-stream"),
+            )
+            .body(body.into())
+            .unwrap())
+    }
+
+    fn health(&self, req: &Request<hyper::Body>, caller: &Caller) -> ResponseResult {
+        let liveness = self.health.streams();
+        let now = self.db.clocks().monotonic();
+        let silence_after = self.health.silence_after();
+        let db = self.db.lock();
+        let mut streams = Vec::with_capacity(liveness.len());
+        for (id, s) in db.streams_by_id() {
+            let h = match liveness.get(id) {
+                None => continue,
+                Some(h) => h,
+            };
+            let c = match db.cameras_by_id().get(&s.camera_id) {
+                Some(c) if caller.may_access_camera(c.id) => c,
+                _ => continue,
+            };
+            let silent_for = h.silent_for(now);
+            streams.push(json::StreamLiveness {
+                camera_uuid: c.uuid,
+                stream: s.type_.as_str(),
+                connected: h.connected,
+                last_frame_90k: h.last_frame.map(|(_, t)| t.0),
+                silent_for_ms: silent_for.num_milliseconds(),
+                silent: silent_for >= silence_after,
+                reconnects: h.reconnects,
+                bitrate_bps: h.bitrate_bps,
+                fps: h.fps,
+            });
+        }
+        drop(db);
+        serve_json(
+            req,
+            &json::Liveness {
+                silence_threshold_sec: silence_after.num_seconds(),
+                streams,
+            },
+        )
+    }
+
+    /// Reports the state of each subsystem; see `design/api.md`.
+    ///
+    /// Only a failing database makes the server as a whole `failed` (and the response a 503).
+    /// A failed directory or stream is a partial failure, so it makes the server `degraded`.
+    fn health_ready(&self, req: &Request<hyper::Body>) -> ResponseResult {
+        use json::HealthStatus;
+        let db = self.db.lock();
+        let (flush_failures, last_flush_error) = db.flush_failures();
+        let db_health = json::DbHealth {
+            status: if db.flush_failing() {
+                HealthStatus::Failed
+            } else {
+                HealthStatus::Ok
+            },
+            flush_failures,
+            last_flush_error: last_flush_error.map(str::to_owned),
+        };
+        let mut worst_part = HealthStatus::Ok;
+        let mut dirs = Vec::with_capacity(db.sample_file_dirs_by_id().len());
+        for (&id, d) in db.sample_file_dirs_by_id() {
+            let queue = self.syncer_queues.get(&id).map(|q| q.status());
+            let status = if d.unhealthy_since_sec.is_some() {
+                HealthStatus::Failed
+            } else if queue.as_ref().map(|q| q.depth >= q.capacity) == Some(true) {
+                HealthStatus::Degraded
+            } else {
+                HealthStatus::Ok
+            };
+            worst_part = cmp::max(worst_part, status);
+            dirs.push(json::DirHealth {
+                id,
+                status,
+                unhealthy_since_sec: d.unhealthy_since_sec,
+                syncer_queue_depth: queue.as_ref().map(|q| q.depth),
+                syncer_queue_capacity: queue.as_ref().map(|q| q.capacity),
+                syncer_stalls: queue.as_ref().map(|q| q.stalls),
+            });
+        }
+        let mut streams = Vec::new();
+        for s in db.streams_by_id().values() {
+            if !s.record {
+                continue;
+            }
+            let c = match db.cameras_by_id().get(&s.camera_id) {
+                None => continue,
+                Some(c) => c,
+            };
+            let recording = s.is_recording();
+            let status = if s.sample_file_dir_id.is_none() || s.disk_full {
+                HealthStatus::Failed
+            } else if !recording || s.multi_homed.is_some() {
+                HealthStatus::Degraded
+            } else {
+                HealthStatus::Ok
+            };
+            worst_part = cmp::max(worst_part, status);
+            streams.push(json::StreamHealth {
+                camera_uuid: c.uuid,
+                stream: s.type_.as_str(),
+                status,
+                recording,
+                disk_full: s.disk_full,
+                multi_homed: s.multi_homed.clone(),
+            });
+        }
+        drop(db);
+        let status = cmp::max(
+            db_health.status,
+            cmp::min(worst_part, HealthStatus::Degraded),
+        );
+        let mut resp = serve_json(
+            req,
+            &json::Health {
+                status,
+                db: Some(db_health),
+                dirs,
+                streams,
+            },
+        )?;
+        if status == HealthStatus::Failed {
+            *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        }
+        Ok(resp)
+    }
+
+    /// Returns recently logged lines as plain text; see `design/api.md`.
+    async fn user_notifications(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+    ) -> ResponseResult {
+        use http::method::Method;
+        let user_id = caller
+            .user_id
+            .ok_or_else(|| plain_response(StatusCode::UNAUTHORIZED, "session required"))?;
+        match *req.method() {
+            Method::GET | Method::HEAD => {
+                let p = self
+                    .db
+                    .lock()
+                    .get_notification_policy(user_id)
+                    .map_err(internal_server_err)?;
+                serve_json(
+                    &req,
+                    &p.map(json::NotificationPolicy::from).unwrap_or_default(),
+                )
+            }
+            Method::POST => {
+                let r = extract_json_body(&mut req).await?;
+                let r: json::NotificationPolicy =
+                    serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+                let p = r.into_db().map_err(bad_req)?;
+                self.db
+                    .lock()
+                    .set_notification_policy(user_id, Some(&p))
+                    .map_err(from_base_error)?;
+                Ok(Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(b""[..].into())
+                    .unwrap())
+            }
+            Method::DELETE => {
+                self.db
+                    .lock()
+                    .set_notification_policy(user_id, None)
+                    .map_err(from_base_error)?;
+                Ok(Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(b""[..].into())
+                    .unwrap())
+            }
+            _ => Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, POST, or DELETE expected",
+            )),
+        }
+    }
+
+    fn user_sessions(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        use http::method::Method;
+        let user_id = caller
+            .user_id
+            .ok_or_else(|| plain_response(StatusCode::UNAUTHORIZED, "session required"))?;
+        match *req.method() {
+            Method::GET | Method::HEAD => {
+                let sessions = self
+                    .db
+                    .lock()
+                    .list_sessions(user_id)
+                    .map_err(internal_server_err)?;
+                serve_json(
+                    req,
+                    &json::UserSessions {
+                        sessions: sessions
+                            .into_iter()
+                            .map(|s| json::UserSession::new(s, caller.session_id))
+                            .collect(),
+                    },
+                )
+            }
+            Method::DELETE => {
+                // Revoke every other session; the caller can log out to end its own.
+                let authreq = self.authreq(req.headers());
+                self.db
+                    .lock()
+                    .revoke_all_sessions(
+                        auth::RevocationReason::RevokedByUser,
+                        authreq,
+                        user_id,
+                        caller.session_id,
+                    )
+                    .map_err(internal_server_err)?;
+                Ok(Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(b""[..].into())
+                    .unwrap())
+            }
+            _ => Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET or DELETE expected",
+            )),
+        }
+    }
+
+    fn user_session(&self, req: &Request<hyper::Body>, caller: Caller, id: i32) -> ResponseResult {
+        if *req.method() != http::method::Method::DELETE {
+            return Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "DELETE expected",
+            ));
+        }
+        let user_id = caller
+            .user_id
+            .ok_or_else(|| plain_response(StatusCode::UNAUTHORIZED, "session required"))?;
+        let authreq = self.authreq(req.headers());
+        let found = self
+            .db
+            .lock()
+            .revoke_session_by_id(auth::RevocationReason::RevokedByUser, authreq, user_id, id)
+            .map_err(internal_server_err)?;
+        if !found {
+            return Err(not_found(format!("no such session {}", id)));
+        }
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(b""[..].into())
+            .unwrap())
+    }
+
+    fn heatmap(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if !caller.permissions.read_playback_heat {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "read_playback_heat required",
+            ));
+        }
+        let mut time = recording::Time::min_value()..recording::Time::max_value();
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startTime90k" => {
+                        time.start = recording::Time::parse(value)
+                            .map_err(|_| bad_req("unparseable startTime90k"))?
+                    }
+                    "endTime90k" => {
+                        time.end = recording::Time::parse(value)
+                            .map_err(|_| bad_req("unparseable endTime90k"))?
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let mut out = json::Heatmap {
+            bucket_duration_90k: db::playback::BUCKET_DURATION.0,
+            buckets: Vec::new(),
+        };
+        let db = self.db.lock();
+        db.list_playback_heat(time, &mut |row| {
+            let s = match db.streams_by_id().get(&row.stream_id) {
+                None => return,
+                Some(s) => s,
+            };
+            let c = match db.cameras_by_id().get(&s.camera_id) {
+                Some(c) if caller.may_access_camera(c.id) => c,
+                _ => return,
+            };
+            out.buckets.push(json::HeatmapBucket {
+                camera_uuid: c.uuid,
+                stream: s.type_.as_str(),
+                start_time_90k: row.start.0,
+                views: row.views,
+            });
+        })
+        .map_err(internal_server_err)?;
+        drop(db);
+        serve_json(req, &out)
+    }
+
+    fn timeline(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        let (mut start, mut end, mut bucket) = (None, None, None);
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startTime90k" => {
+                        start = Some(
+                            recording::Time::parse(value)
+                                .map_err(|_| bad_req("unparseable startTime90k"))?,
+                        )
+                    }
+                    "endTime90k" => {
+                        end = Some(
+                            recording::Time::parse(value)
+                                .map_err(|_| bad_req("unparseable endTime90k"))?,
+                        )
+                    }
+                    "bucket90k" => {
+                        bucket = Some(recording::Duration(
+                            i64::from_str(value)
+                                .ok()
+                                .filter(|&b| b > 0)
+                                .ok_or_else(|| bad_req("bad bucket90k"))?,
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let (start, end, bucket) = match (start, end, bucket) {
+            (Some(s), Some(e), Some(b)) => (s, e, b),
+            _ => {
+                return Err(bad_req(
+                    "startTime90k, endTime90k, and bucket90k are required",
+                ))
+            }
+        };
+        if end <= start || (end - start).0 / bucket.0 >= MAX_TIMELINE_BUCKETS {
+            return Err(bad_req("bad time range or too many buckets"));
+        }
+        let mut out = json::Timeline {
+            bucket_duration_90k: bucket.0,
+            buckets: Vec::new(),
+            signal_changes: Vec::new(),
+        };
+        let db = self.db.lock();
+        db.list_timeline(start..end, bucket, &mut |row| {
+            let s = match db.streams_by_id().get(&row.stream_id) {
+                None => return,
+                Some(s) => s,
+            };
+            let c = match db.cameras_by_id().get(&s.camera_id) {
+                Some(c) if caller.may_access_camera(c.id) => c,
+                _ => return,
+            };
+            out.buckets.push(json::TimelineBucket {
+                camera_uuid: c.uuid,
+                stream: s.type_.as_str(),
+                start_time_90k: row.start.0,
+                recordings: row.recordings,
+                duration_90k: row.duration.0,
+                sample_file_bytes: row.sample_file_bytes,
+                detections: row.detections,
+            });
+        })
+        .map_err(internal_server_err)?;
+
+        // Signals associated with no cameras, such as a building's alarm, are visible to all.
+        db.list_changes_by_time(start..end, &mut |c| {
+            let visible = match db.signals_by_id().get(&c.signal) {
+                None => false,
+                Some(s) => {
+                    s.cameras.is_empty()
+                        || s.cameras
+                            .iter()
+                            .any(|sc| caller.may_access_camera(sc.camera_id))
+                }
+            };
+            if visible {
+                out.signal_changes.push(json::TimelineSignalChange {
+                    signal_id: c.signal,
+                    time_90k: c.when.0,
+                    state: c.state,
+                });
+            }
+        });
+        drop(db);
+        serve_json(req, &out)
+    }
+
+    /// Lists the segments of several streams within a common range, aligned to the local
+    /// clock, for synchronized playback; see `design/api.md`.
+    fn playback(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        let (mut start, mut end) = (None, None);
+        let mut streams = Vec::new();
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startTime90k" => {
+                        start = Some(
+                            recording::Time::parse(value)
+                                .map_err(|_| bad_req("unparseable startTime90k"))?,
+                        )
+                    }
+                    "endTime90k" => {
+                        end = Some(
+                            recording::Time::parse(value)
+                                .map_err(|_| bad_req("unparseable endTime90k"))?,
+                        )
+                    }
+                    "stream" => streams.push(
+                        parse_playback_stream(value)
+                            .ok_or_else(|| bad_req(format!("unparseable stream {:?}", value)))?,
+                    ),
+                    _ => {}
+                }
+            }
+        }
+        let range = match (start, end) {
+            (Some(s), Some(e)) if s < e && e - s <= MAX_PLAYBACK_DURATION => s..e,
+            (Some(_), Some(_)) => return Err(bad_req("bad time range")),
+            _ => return Err(bad_req("startTime90k and endTime90k are required")),
+        };
+        if streams.is_empty() || streams.len() > MAX_PLAYBACK_STREAMS {
+            return Err(bad_req(format!(
+                "between 1 and {} streams are required",
+                MAX_PLAYBACK_STREAMS
+            )));
+        }
+        let mut out = json::Playback {
+            start_time_90k: range.start.0,
+            end_time_90k: range.end.0,
+            streams: Vec::with_capacity(streams.len()),
+        };
+        let db = self.db.lock();
+        for (uuid, type_) in streams {
+            let stream_id = match db.get_camera(uuid) {
+                Some(c) if caller.may_access_camera(c.id) => c.streams[type_.index()],
+                _ => None,
+            }
+            .ok_or_else(|| not_found(format!("no such stream {}/{}", uuid, type_)))?;
+            let segments = RangeSegment::list(&db, stream_id, &range)
+                .map_err(internal_server_err)?
+                .iter()
+                .map(|s| s.to_json(&db, None, s.rel.clone()))
+                .collect();
+            out.streams.push(json::PlaybackStream {
+                camera_uuid: uuid,
+                stream: type_.as_str(),
+                segments,
+            });
+        }
+        drop(db);
+        serve_json(req, &out)
+    }
+
+    /// Lists the intervals in which a stream wasn't recorded, with reasons; see `design/api.md`.
+    fn stream_gaps(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        type_: db::StreamType,
+    ) -> ResponseResult {
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        let (mut start, mut end) = (None, None);
+        let mut min_gap = DEFAULT_MIN_GAP;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startTime90k" => {
+                        start = Some(
+                            recording::Time::parse(value)
+                                .map_err(|_| bad_req("unparseable startTime90k"))?,
+                        )
+                    }
+                    "endTime90k" => {
+                        end = Some(
+                            recording::Time::parse(value)
+                                .map_err(|_| bad_req("unparseable endTime90k"))?,
+                        )
+                    }
+                    "minGap90k" => {
+                        min_gap = recording::Duration(
+                            i64::from_str(value)
+                                .ok()
+                                .filter(|&g| g > 0)
+                                .ok_or_else(|| bad_req("bad minGap90k"))?,
+                        )
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // Don't report the future as a gap.
+        let now = recording::Time::new(self.db.clocks().realtime());
+        let range = match (start, end) {
+            (Some(s), Some(e)) if s < e && e - s <= MAX_GAPS_DURATION => s..cmp::min(e, now),
+            (Some(_), Some(_)) => return Err(bad_req("bad time range")),
+            _ => return Err(bad_req("startTime90k and endTime90k are required")),
+        };
+        let db = self.db.lock();
+        let stream_id = db
+            .get_camera(uuid)
+            .and_then(|c| c.streams[type_.index()])
+            .ok_or_else(|| not_found(format!("no such stream {}/{}", uuid, type_)))?;
+        let gaps = if range.start < range.end {
+            db.list_gaps(stream_id, range.clone(), min_gap)
+                .map_err(internal_server_err)?
+        } else {
+            Vec::new()
+        };
+        drop(db);
+        let out = json::Gaps {
+            total_duration_90k: gaps.iter().map(|g| (g.time.end - g.time.start).0).sum(),
+            gaps: gaps
+                .iter()
+                .map(|g| json::Gap {
+                    start_time_90k: g.time.start.0,
+                    end_time_90k: g.time.end.0,
+                    reason: g.reason.as_str(),
+                })
+                .collect(),
+        };
+        serve_json(req, &out)
+    }
+
+    /// Lists the recordings covering a range, preferring the main stream and filling its gaps
+    /// from the sub stream; see `design/api.md`.
+    fn camera_coverage(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        let (mut start, mut end) = (None, None);
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startTime90k" => {
+                        start = Some(
+                            recording::Time::parse(value)
+                                .map_err(|_| bad_req("unparseable startTime90k"))?,
+                        )
+                    }
+                    "endTime90k" => {
+                        end = Some(
+                            recording::Time::parse(value)
+                                .map_err(|_| bad_req("unparseable endTime90k"))?,
+                        )
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let range = match (start, end) {
+            (Some(s), Some(e)) if s < e && e - s <= MAX_PLAYBACK_DURATION => s..e,
+            (Some(_), Some(_)) => return Err(bad_req("bad time range")),
+            _ => return Err(bad_req("startTime90k and endTime90k are required")),
+        };
+        let db = self.db.lock();
+        let camera = db
+            .get_camera(uuid)
+            .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?;
+        let list = |type_: db::StreamType| match camera.streams[type_.index()] {
+            None => Ok(Vec::new()),
+            Some(id) => RangeSegment::list(&db, id, &range).map_err(internal_server_err),
+        };
+        let main = list(db::StreamType::MAIN)?;
+        let sub = list(db::StreamType::SUB)?;
+        let covered: Vec<_> = main.iter().map(|s| s.time(&s.rel)).collect();
+        let mut segments: Vec<_> = main
+            .iter()
+            .map(|s| s.to_json(&db, Some(db::StreamType::MAIN.as_str()), s.rel.clone()))
+            .collect();
+        for s in &sub {
+            for t in uncovered(s.time(&s.rel), &covered, MIN_COVERAGE_FILL) {
+                let rel = (t.start - s.local_start).0 as i32..(t.end - s.local_start).0 as i32;
+                segments.push(s.to_json(&db, Some(db::StreamType::SUB.as_str()), rel));
+            }
+        }
+        drop(db);
+        segments.sort_by_key(|s| s.start_time_90k);
+        serve_json(
+            req,
+            &json::Coverage {
+                start_time_90k: range.start.0,
+                end_time_90k: range.end.0,
+                segments,
+            },
+        )
+    }
+
+    /// Lists committed recordings starting at or after `startTime90k`, with everything another
+    /// instance needs to write them again; see `replication`.
+    fn stream_replica(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        type_: db::StreamType,
+    ) -> ResponseResult {
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        let mut start = recording::Time(0);
+        let mut limit = DEFAULT_REPLICA_LIMIT;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startTime90k" => {
+                        start = recording::Time::parse(value)
+                            .map_err(|_| bad_req("unparseable startTime90k"))?
+                    }
+                    "limit" => {
+                        limit = usize::from_str(value)
+                            .ok()
+                            .filter(|&l| l > 0 && l <= MAX_REPLICA_LIMIT)
+                            .ok_or_else(|| bad_req("bad limit"))?
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let db = self.db.lock();
+        let stream_id = db
+            .get_camera(uuid)
+            .and_then(|c| c.streams[type_.index()])
+            .ok_or_else(|| not_found(format!("no such stream {}/{}", uuid, type_)))?;
+        let mut rows = Vec::new();
+        db.list_recordings_by_time(
+            stream_id,
+            start..recording::Time(i64::max_value()),
+            &mut |r| {
+                if r.start >= start && (r.flags & db::RecordingFlags::Uncommitted as i32) == 0 {
+                    rows.push(r);
+                }
+                Ok(())
+            },
+        )
+        .map_err(internal_server_err)?;
+        rows.sort_by_key(|r| (r.start, r.id.recording()));
+        rows.truncate(limit);
+        let mut out = json::Replica::default();
+        for r in rows {
+            let video_index = db
+                .with_recording_playback(r.id, &mut |p| Ok(base64::encode(p.video_index)))
+                .map_err(internal_server_err)?;
+            let mut blake3 = None;
+            db.list_sample_files(r.id..db::CompositeId(r.id.0 + 1), &mut |f| {
+                if let Some(db::scrub::Digest::Blake3(ref d)) = f.digest {
+                    blake3 = Some(strutil::hex(d));
+                }
+                Ok(())
+            })
+            .map_err(internal_server_err)?;
+            if !out
+                .video_sample_entries
+                .contains_key(&r.video_sample_entry_id)
+            {
+                let e = db
+                    .video_sample_entries_by_id()
+                    .get(&r.video_sample_entry_id)
+                    .unwrap();
+                out.video_sample_entries.insert(
+                    e.id,
+                    json::ReplicaVideoSampleEntry {
+                        width: e.width,
+                        height: e.height,
+                        rfc6381_codec: e.rfc6381_codec.clone(),
+                        data: base64::encode(&e.data),
+                        encoder: e.encoder.clone(),
+                    },
+                );
+            }
+            out.recordings.push(json::ReplicaRecording {
+                id: r.id.recording(),
+                start_time_90k: r.start.0,
+                duration_90k: r.duration_90k,
+                sample_file_bytes: r.sample_file_bytes,
+                video_sample_entry_id: r.video_sample_entry_id,
+                video_index,
+                blake3,
+            });
+        }
+        serve_json(req, &out)
+    }
+
+    /// Serves a committed recording's sample data (decrypted, if need be) from byte `offset`
+    /// on, so that an interrupted replication transfer can resume where it left off.
+    async fn stream_replica_data(
+        self: Arc<Self>,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        type_: db::StreamType,
+        recording_id: i32,
+    ) -> ResponseResult {
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        let mut offset = 0;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                if key == "offset" {
+                    offset = u64::from_str(&value).map_err(|_| bad_req("bad offset"))?;
+                }
+            }
+        }
+        let (id, flags, len) = {
+            let db = self.db.lock();
+            let stream_id = db
+                .get_camera(uuid)
+                .and_then(|c| c.streams[type_.index()])
+                .ok_or_else(|| not_found(format!("no such stream {}/{}", uuid, type_)))?;
+            let mut row = None;
+            db.list_recordings_by_id(stream_id, recording_id..recording_id + 1, &mut |r| {
+                if (r.flags & db::RecordingFlags::Uncommitted as i32) == 0 {
+                    row = Some((r.id, r.flags, r.sample_file_bytes as u64));
+                }
+                Ok(())
+            })
+            .map_err(internal_server_err)?;
+            row.ok_or_else(|| not_found(format!("no committed recording {}", recording_id)))?
+        };
+        if offset > len {
+            return Err(bad_req(format!("offset is past the end ({} bytes)", len)));
+        }
+        let dir = self
+            .dirs_by_stream_id
+            .get(&id.stream())
+            .and_then(|d| d.get(id, flags))
+            .cloned()
+            .ok_or_else(|| not_found(format!("{}: stream not found", id)))?;
+        let data =
+            tokio::task::spawn_blocking(move || read_sample_data(&dir, id, flags, offset..len))
+                .await
+                .map_err(internal_server_err)?
+                .map_err(internal_server_err)?;
+        Ok(Response::builder()
+            .header(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/octet-stream"),
+            )
+            .body(data.into())
+            .unwrap())
+    }
+
+    fn sample_files(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if !caller.permissions.view_video {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "view_video required",
+            ));
+        }
+        let mut ids = Vec::new();
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                if key == "name" {
+                    let id = db::dir::parse_id(value.as_bytes())
+                        .map_err(|()| bad_req(format!("bad sample file name {:?}", value)))?;
+                    ids.push(id..db::CompositeId(id.0 + 1));
+                }
+            }
+        }
+        if ids.is_empty() {
+            ids.push(db::CompositeId(0)..db::CompositeId(i64::max_value()));
+        }
+        let db = self.db.lock();
+        let mut out = json::SampleFiles {
+            sample_files: Vec::new(),
+        };
+        for r in ids {
+            let mut rows = Vec::new();
+            db.list_sample_files(r, &mut |row| {
+                rows.push(row);
+                Ok(())
+            })
+            .map_err(internal_server_err)?;
+            out.sample_files.extend(
+                rows.iter()
+                    .filter(|row| {
+                        db.streams_by_id()
+                            .get(&row.id.stream())
+                            .map(|s| caller.may_access_camera(s.camera_id))
+                            .unwrap_or(false)
+                    })
+                    .filter_map(|row| json::SampleFile::new(&db, row)),
+            );
+        }
+        serve_json(req, &out)
+    }
+
+    fn logs(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if !caller.permissions.read_logs {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "read_logs required",
+            ));
+        }
+        let recent = self
+            .logs
+            .as_ref()
+            .ok_or_else(|| not_found("log capture is not enabled; see --log-dir"))?;
+        let (mut module, mut stream) = (None, None);
+        let mut limit = DEFAULT_LOG_LIMIT;
+        if let Some(query) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+                match key.borrow() {
+                    "module" => module = Some(value.into_owned()),
+                    "stream" => stream = Some(value.into_owned()),
+                    "limit" => {
+                        limit = usize::from_str(&value)
+                            .ok()
+                            .filter(|&l| l > 0 && l <= MAX_LOG_LIMIT)
+                            .ok_or_else(|| bad_req("bad limit"))?
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let filter = logs::Filter {
+            module: module.as_deref(),
+            stream: stream.as_deref(),
+        };
+        Ok(plain_response(StatusCode::OK, recent.query(&filter, limit)))
+    }
+
+    async fn log_filter(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if !caller.permissions.read_logs {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "read_logs required",
+            ));
+        }
+        let filter = match *req.method() {
+            http::Method::GET | http::Method::HEAD => logs::filter(),
+            http::Method::POST => {
+                if !caller.permissions.update_log_filter {
+                    return Err(plain_response(
+                        StatusCode::FORBIDDEN,
+                        "update_log_filter required",
+                    ));
+                }
+                let r = extract_json_body(&mut req).await?;
+                let r: json::LogFilter =
+                    serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+                let filter =
+                    logs::set_filter(r.filter.as_deref()).map_err(|e| bad_req(e.to_string()))?;
+                info!("Log filter set to {:?}", &filter);
+                Some(filter)
+            }
+            _ => {
+                return Err(plain_response(
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "GET, HEAD, or POST expected",
+                ))
+            }
+        };
+        serve_json(&req, &json::LogFilter { filter })
+    }
+
+    async fn post_detections(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        type_: db::StreamType,
+    ) -> ResponseResult {
+        if !caller.permissions.update_detections {
+            return Err(plain_response(
+                StatusCode::UNAUTHORIZED,
+                "update_detections required",
+            ));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostDetectionsRequest =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let detections: Vec<_> = r
+            .detections
+            .into_iter()
+            .map(|d| db::detection::DetectionToInsert {
+                recording_id: d.recording_id,
+                time: recording::Time(d.start_time_90k)..recording::Time(d.end_time_90k),
+                label: d.label,
+                confidence: d.confidence,
+                source: d.source,
+            })
+            .collect();
+        let mut l = self.db.lock();
+        let camera = l.get_camera(uuid).ok_or_else(|| {
+            plain_response(StatusCode::NOT_FOUND, format!("no such camera {}", uuid))
+        })?;
+        let stream_id = camera.streams[type_.index()].ok_or_else(|| {
+            plain_response(
+                StatusCode::NOT_FOUND,
+                format!("no such stream {}/{}", uuid, type_),
+            )
+        })?;
+        l.add_detections(stream_id, &detections)
+            .map_err(from_base_error)?;
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(b""[..].into())
+            .unwrap())
+    }
+
+    fn get_detections(
+        &self,
+        req: &Request<hyper::Body>,
+        uuid: Uuid,
+        type_: db::StreamType,
+    ) -> ResponseResult {
+        let mut time = recording::Time::min_value()..recording::Time::max_value();
+        let mut label = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startTime90k" => {
+                        time.start = recording::Time::parse(value)
+                            .map_err(|_| bad_req("unparseable startTime90k"))?
+                    }
+                    "endTime90k" => {
+                        time.end = recording::Time::parse(value)
+                            .map_err(|_| bad_req("unparseable endTime90k"))?
+                    }
+                    "label" => label = Some(value.to_owned()),
+                    _ => {}
+                }
+            }
+        }
+
+        let db = self.db.lock();
+        let camera = db.get_camera(uuid).ok_or_else(|| {
+            plain_response(StatusCode::NOT_FOUND, format!("no such camera {}", uuid))
+        })?;
+        let stream_id = camera.streams[type_.index()].ok_or_else(|| {
+            plain_response(
+                StatusCode::NOT_FOUND,
+                format!("no such stream {}/{}", uuid, type_),
+            )
+        })?;
+        let mut out = json::Detections::default();
+        db.list_detections(stream_id, time, label.as_deref(), &mut |d| {
+            out.detections.push(json::Detection {
+                id: d.id,
+                recording_id: d.recording_id.recording(),
+                start_time_90k: d.time.start.0,
+                end_time_90k: d.time.end.0,
+                label: d.label,
+                confidence: d.confidence,
+                source: d.source,
+            });
+            Ok(())
+        })
+        .map_err(internal_server_err)?;
+        serve_json(req, &out)
     }
 
     fn get_signals(&self, req: &Request<hyper::Body>) -> ResponseResult {
@@ -1194,13 +5257,33 @@ impl Service {
         serve_json(req, &signals)
     }
 
-    fn authenticate(
+    /// Authenticates a request from its headers, as for both HTTP and gRPC requests.
+    ///
+    /// `peer` is set for HTTP requests over a Unix socket. A session or token takes precedence
+    /// over the peer's credentials.
+    pub(crate) fn authenticate(
         &self,
-        req: &Request<hyper::Body>,
+        hdrs: &header::HeaderMap,
+        peer: Option<&UnixPeer>,
         unauth_path: bool,
     ) -> Result<Caller, base::Error> {
-        if let Some(sid) = extract_sid(req) {
-            let authreq = self.authreq(req);
+        if let Some(token) = extract_bearer(hdrs) {
+            let authreq = self.authreq(hdrs);
+            let mut db = self.db.lock();
+            if let Some(Ok((t, u))) = token.map(|t| db.authenticate_token(authreq, &t.hash())) {
+                return Ok(Caller {
+                    permissions: t.permissions.clone(),
+                    session: None,
+                    session_id: None,
+                    user_id: Some(u.id),
+                    cameras: u.cameras.clone(),
+                });
+            }
+            bail_t!(Unauthenticated, "invalid access token");
+        }
+
+        if let Some(sid) = extract_sid(hdrs) {
+            let authreq = self.authreq(hdrs);
 
             // TODO: real error handling! this assumes all errors are due to lack of
             // authentication, when they could be logic errors in SQL or such.
@@ -1215,15 +5298,33 @@ impl Service {
                         username: u.username.clone(),
                         csrf: s.csrf(),
                     }),
+                    session_id: Some(s.id),
+                    user_id: Some(u.id),
+                    cameras: u.cameras.clone(),
                 });
             }
             info!("authenticate_session failed");
         }
 
+        if let (Some(a), Some(p)) = (self.unix_peer_auth.as_ref(), peer) {
+            if a.uids.contains(&p.uid) || a.gids.contains(&p.gid) {
+                return Ok(Caller {
+                    permissions: a.permissions.clone(),
+                    session: None,
+                    session_id: None,
+                    user_id: None,
+                    cameras: None,
+                });
+            }
+        }
+
         if let Some(s) = self.allow_unauthenticated_permissions.as_ref() {
             return Ok(Caller {
                 permissions: s.clone(),
                 session: None,
+                session_id: None,
+                user_id: None,
+                cameras: None,
             });
         }
 
@@ -1231,6 +5332,9 @@ impl Service {
             return Ok(Caller {
                 permissions: db::Permissions::default(),
                 session: None,
+                session_id: None,
+                user_id: None,
+                cameras: None,
             });
         }
 
@@ -1277,6 +5381,21 @@ impl<'a> StaticFileRequest<'a> {
             mime,
         })
     }
+
+    fn add_headers(&self, hdrs: &mut http::HeaderMap) {
+        hdrs.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static(if self.immutable {
+                // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Cache-Control#Caching_static_assets
+                "public, max-age=604800, immutable"
+            } else {
+                // index.html names the current content-hashed assets, so it must be revalidated
+                // to pick up a new UI after an upgrade.
+                "public, no-cache"
+            }),
+        );
+        hdrs.insert(header::CONTENT_TYPE, HeaderValue::from_static(self.mime));
+    }
 }
 
 #[cfg(test)]
@@ -1284,10 +5403,10 @@ mod tests {
     use super::{Segments, StaticFileRequest};
     use db::testutil::{self, TestDb};
     use futures::future::FutureExt;
-    use log::info;
     use std::collections::HashMap;
     use std::str::FromStr;
     use std::sync::Arc;
+    use tracing::info;
 
     struct Server {
         db: TestDb<base::clock::RealClocks>,
@@ -1307,7 +5426,20 @@ mod tests {
                     ui_dir: None,
                     allow_unauthenticated_permissions,
                     trust_forward_hdrs: true,
+                    cookie_same_site: super::SameSite::Strict,
+                    secure_cookies: false,
+                    allowed_origins: vec!["https://nvr.example.com".to_owned()],
                     time_zone_name: "".to_owned(),
+                    syncer_queues: Default::default(),
+                    health: Default::default(),
+                    events: Default::default(),
+                    logs: None,
+                    record_playback_heat: true,
+                    read_ahead_bytes: None,
+                    load_thresholds: None,
+                    acme_challenges: None,
+                    unix_peer_auth: None,
+                    export_dir: None,
                 })
                 .unwrap(),
             );
@@ -1387,12 +5519,46 @@ mod tests {
         }
     }
 
+    #[test]
+    fn continue_tokens() {
+        for &id in &[0, 1, 12345, i32::max_value()] {
+            assert_eq!(
+                super::decode_continue(&super::encode_continue(id)),
+                Some(id)
+            );
+        }
+        assert_eq!(super::decode_continue(""), None);
+        assert_eq!(super::decode_continue("12345"), None);
+        assert_eq!(super::decode_continue("!!"), None);
+    }
+
+    #[test]
+    fn align_to_range() {
+        use db::recording::Time;
+        let range = Time(1000)..Time(2000);
+        assert_eq!(
+            super::align_to_range(Time(500), 1000, &range),
+            Some(500..1000)
+        );
+        assert_eq!(
+            super::align_to_range(Time(1500), 1000, &range),
+            Some(0..500)
+        );
+        assert_eq!(super::align_to_range(Time(1200), 100, &range), Some(0..100));
+        assert_eq!(super::align_to_range(Time(0), 1000, &range), None);
+        assert_eq!(super::align_to_range(Time(2000), 1000, &range), None);
+    }
+
     #[test]
     fn paths() {
         use super::Path;
         use uuid::Uuid;
         let cam_uuid = Uuid::parse_str("35144640-ff1e-4619-b0d5-4c74c185741c").unwrap();
         assert_eq!(Path::decode("/foo"), Path::Static);
+        assert_eq!(
+            Path::decode("/.well-known/acme-challenge/abc"),
+            Path::AcmeChallenge("abc".to_owned())
+        );
         assert_eq!(Path::decode("/api/"), Path::TopLevel);
         assert_eq!(
             Path::decode("/api/init/07cec464126825088ea86a07eddd6a00afa71559.mp4"),
@@ -1427,6 +5593,18 @@ mod tests {
             Path::Camera(cam_uuid)
         );
         assert_eq!(Path::decode("/api/cameras/asdf/"), Path::NotFound);
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/ptz"),
+            Path::CameraPtz(cam_uuid)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/privacy"),
+            Path::CameraPrivacy(cam_uuid)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/talkback"),
+            Path::CameraTalkback(cam_uuid)
+        );
         assert_eq!(
             Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/recordings"),
             Path::StreamRecordings(cam_uuid, db::StreamType::MAIN)
@@ -1459,16 +5637,164 @@ mod tests {
             Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/live.m4s"),
             Path::StreamLiveMp4Segments(cam_uuid, db::StreamType::MAIN)
         );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/live.m3u8"),
+            Path::StreamHlsPlaylist(cam_uuid, db::StreamType::MAIN)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/live/42.m4s"),
+            Path::StreamHlsSegment(cam_uuid, db::StreamType::MAIN, 42)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/replica"),
+            Path::StreamReplica(cam_uuid, db::StreamType::MAIN)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/replica/7.bin"),
+            Path::StreamReplicaData(cam_uuid, db::StreamType::MAIN, 7)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/sub/mjpeg"),
+            Path::StreamMjpeg(cam_uuid, db::StreamType::SUB)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/retentionPreview"),
+            Path::StreamRetentionPreview(cam_uuid, db::StreamType::MAIN)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/detections"),
+            Path::StreamDetections(cam_uuid, db::StreamType::MAIN)
+        );
+        assert_eq!(
+            Path::decode(
+                "/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/sub/recordings/42/thumbnail"
+            ),
+            Path::StreamThumbnail(cam_uuid, db::StreamType::SUB, 42)
+        );
+        assert_eq!(
+            Path::decode(
+                "/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/sub/recordings/-1/thumbnail"
+            ),
+            Path::NotFound
+        );
         assert_eq!(
             Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/junk"),
             Path::NotFound
         );
         assert_eq!(Path::decode("/api/login"), Path::Login);
+        assert_eq!(Path::decode("/api/login/saml"), Path::SamlLogin);
+        assert_eq!(Path::decode("/api/login/saml/acs"), Path::SamlAcs);
+        assert_eq!(Path::decode("/api/login/oidc"), Path::OidcLogin);
+        assert_eq!(Path::decode("/api/login/oidc/callback"), Path::OidcCallback);
         assert_eq!(Path::decode("/api/logout"), Path::Logout);
         assert_eq!(Path::decode("/api/signals"), Path::Signals);
+        assert_eq!(Path::decode("/api/signals/new"), Path::NewSignal);
+        assert_eq!(Path::decode("/api/signals/12"), Path::Signal(12));
+        assert_eq!(Path::decode("/api/signals/-1"), Path::NotFound);
+        assert_eq!(Path::decode("/api/protect"), Path::Protect);
+        assert_eq!(Path::decode("/api/bookmarks"), Path::Bookmarks);
+        assert_eq!(
+            Path::decode("/api/retentionSimulation"),
+            Path::RetentionSimulation
+        );
+        assert_eq!(Path::decode("/api/graphql"), Path::Graphql);
+        assert_eq!(Path::decode("/api/events"), Path::Events);
+        assert_eq!(Path::decode("/api/bookmarks/42"), Path::Bookmark(42));
+        assert_eq!(Path::decode("/api/exports"), Path::Exports);
+        assert_eq!(Path::decode("/api/exports/42"), Path::Export(42));
+        assert_eq!(Path::decode("/api/exports/verify"), Path::ExportVerify);
+        assert_eq!(
+            Path::decode("/api/exports/42/download"),
+            Path::ExportDownload(42)
+        );
+        assert_eq!(Path::decode("/api/exports/42/junk"), Path::NotFound);
+        assert_eq!(Path::decode("/api/bookmarks/junk"), Path::NotFound);
+        assert_eq!(Path::decode("/api/notes"), Path::Notes);
+        assert_eq!(Path::decode("/api/notes/42"), Path::Note(42));
+        assert_eq!(Path::decode("/api/notes/junk"), Path::NotFound);
+        assert_eq!(Path::decode("/api/search"), Path::Search);
+        assert_eq!(Path::decode("/api/health"), Path::Health);
+        assert_eq!(Path::decode("/api/health/live"), Path::HealthLive);
+        assert_eq!(Path::decode("/api/health/ready"), Path::HealthReady);
+        assert_eq!(Path::decode("/api/logs"), Path::Logs);
+        assert_eq!(Path::decode("/api/logs/filter"), Path::LogFilter);
+        assert_eq!(
+            Path::decode("/api/user/notifications"),
+            Path::UserNotifications
+        );
+        assert_eq!(Path::decode("/api/user/sessions"), Path::UserSessions);
+        assert_eq!(Path::decode("/api/user/sessions/42"), Path::UserSession(42));
+        assert_eq!(Path::decode("/api/user/sessions/junk"), Path::NotFound);
+        assert_eq!(Path::decode("/api/heatmap"), Path::Heatmap);
+        assert_eq!(Path::decode("/api/timeline"), Path::Timeline);
+        assert_eq!(Path::decode("/api/playback"), Path::Playback);
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/sub/gaps"),
+            Path::StreamGaps(cam_uuid, db::StreamType::SUB)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/sub/privacyMask"),
+            Path::StreamPrivacyMask(cam_uuid, db::StreamType::SUB)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/coverage"),
+            Path::CameraCoverage(cam_uuid)
+        );
+        assert_eq!(Path::decode("/api/sampleFiles"), Path::SampleFiles);
         assert_eq!(Path::decode("/api/junk"), Path::NotFound);
     }
 
+    #[test]
+    fn uncovered() {
+        use db::recording::{Duration, Time};
+        let covered = [Time(100)..Time(200), Time(300)..Time(400)];
+        let min = Duration(10);
+        assert_eq!(
+            super::uncovered(Time(0)..Time(500), &covered, min),
+            vec![
+                Time(0)..Time(100),
+                Time(200)..Time(300),
+                Time(400)..Time(500)
+            ]
+        );
+        assert_eq!(
+            super::uncovered(Time(150)..Time(350), &covered, min),
+            vec![Time(200)..Time(300)]
+        );
+        assert_eq!(
+            super::uncovered(Time(195)..Time(305), &covered, min),
+            Vec::<std::ops::Range<Time>>::new()
+        );
+        assert_eq!(
+            super::uncovered(Time(120)..Time(180), &covered, min),
+            Vec::<std::ops::Range<Time>>::new()
+        );
+        assert_eq!(
+            super::uncovered(Time(350)..Time(600), &covered, min),
+            vec![Time(400)..Time(600)]
+        );
+    }
+
+    #[test]
+    fn bitrate() {
+        assert_eq!(super::bitrate_bps(1_000_000, 90_000 * 8), 1_000_000);
+        assert_eq!(super::bitrate_bps(1_000_000, 0), 0);
+    }
+
+    #[test]
+    fn playback_streams() {
+        let uuid = uuid::Uuid::parse_str("35144640-ff1e-4619-b0d5-4c74c185741c").unwrap();
+        assert_eq!(
+            super::parse_playback_stream("35144640-ff1e-4619-b0d5-4c74c185741c/sub"),
+            Some((uuid, db::StreamType::SUB))
+        );
+        assert_eq!(
+            super::parse_playback_stream("35144640-ff1e-4619-b0d5-4c74c185741c"),
+            None
+        );
+        assert_eq!(super::parse_playback_stream("junk/main"), None);
+    }
+
     #[test]
     fn static_file() {
         testutil::init();
@@ -1674,7 +6000,7 @@ mod tests {
         // But it should work with the csrf token.
         // Retrieve that from the toplevel API request.
         let toplevel: serde_json::Value = cli
-            .post(&format!("{}/api/", &s.base_url))
+            .get(&format!("{}/api/", &s.base_url))
             .header(reqwest::header::COOKIE, cookie.header())
             .send()
             .await
@@ -1714,6 +6040,202 @@ mod tests {
         assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
     }
 
+    #[tokio::test]
+    async fn csrf() {
+        testutil::init();
+        let s = Server::new(None);
+        let cli = reqwest::Client::new();
+        let mut p = HashMap::new();
+        p.insert("username", "slamb");
+        p.insert("password", "hunter2");
+        let resp = cli
+            .post(&format!("{}/api/login", &s.base_url))
+            .json(&p)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        let cookie = SessionCookie::new(resp.headers());
+        let toplevel: serde_json::Value = cli
+            .get(&format!("{}/api/", &s.base_url))
+            .header(reqwest::header::COOKIE, cookie.header())
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let csrf = toplevel["session"]["csrf"].as_str().unwrap().to_owned();
+        let protect_url = format!("{}/api/protect", &s.base_url);
+        let host = s.base_url.trim_start_matches("http://").to_owned();
+
+        // A POST without the token should be rejected.
+        let resp = cli
+            .post(&protect_url)
+            .header(reqwest::header::COOKIE, cookie.header())
+            .json(&HashMap::<String, String>::new())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+
+        // So should one with the token from a foreign origin.
+        let resp = cli
+            .post(&protect_url)
+            .header(reqwest::header::COOKIE, cookie.header())
+            .header("X-CSRF-Token", &csrf)
+            .header(reqwest::header::ORIGIN, "https://evil.example.com")
+            .json(&HashMap::<String, String>::new())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+
+        // With the token from the same or an allowed origin, it should reach the handler, which
+        // rejects it for lack of the protect_recordings permission.
+        for origin in &[
+            format!("http://{}", host),
+            "https://nvr.example.com".to_owned(),
+        ] {
+            let resp = cli
+                .post(&protect_url)
+                .header(reqwest::header::COOKIE, cookie.header())
+                .header("X-CSRF-Token", &csrf)
+                .header(reqwest::header::ORIGIN, &origin[..])
+                .json(&HashMap::<String, String>::new())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn access_token() {
+        testutil::init();
+        let s = Server::new(None);
+        let token = {
+            let mut l = s.db.db.lock();
+            let uid = l.get_user("slamb").unwrap().id;
+            let (raw, _) = l
+                .create_token(uid, None, db::Permissions::default(), 0)
+                .unwrap();
+            base64::encode_config(&raw, base64::STANDARD_NO_PAD)
+        };
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .get(&format!("{}/api/", &s.base_url))
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+
+        let resp = cli
+            .get(&format!("{}/api/", &s.base_url))
+            .header(reqwest::header::AUTHORIZATION, "Bearer bogus")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let id = *s.db.db.lock().tokens_by_id().keys().next().unwrap();
+        s.db.db.lock().revoke_token(id, 0).unwrap();
+        let resp = cli
+            .get(&format!("{}/api/", &s.base_url))
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn camera_restricted_user() {
+        testutil::init();
+        let s = Server::new(None);
+        {
+            let mut l = s.db.db.lock();
+            let mut c = l.get_user("slamb").unwrap().change();
+            c.permissions.view_video = true;
+            c.cameras = Some(Default::default());
+            l.apply_user_change(c).unwrap();
+        }
+        let cli = reqwest::Client::new();
+        let mut p = HashMap::new();
+        p.insert("username", "slamb");
+        p.insert("password", "hunter2");
+        let resp = cli
+            .post(&format!("{}/api/login", &s.base_url))
+            .json(&p)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        let cookie = SessionCookie::new(resp.headers());
+
+        // The top level omits the camera, and camera-specific paths act as if it doesn't exist.
+        let resp = cli
+            .get(&format!("{}/api/", &s.base_url))
+            .header(reqwest::header::COOKIE, cookie.header())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let top: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(top["cameras"].as_array().unwrap().len(), 0);
+        for path in &["/", "/main/recordings", "/main/view.mp4", "/main/live.m4s"] {
+            let resp = cli
+                .get(&format!(
+                    "{}/api/cameras/{}{}",
+                    &s.base_url, s.db.test_camera_uuid, path
+                ))
+                .header(reqwest::header::COOKIE, cookie.header())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND, "{}", path);
+        }
+
+        // Granting the camera takes effect on the existing session.
+        {
+            let mut l = s.db.db.lock();
+            let mut c = l.get_user("slamb").unwrap().change();
+            c.cameras = Some(l.cameras_by_id().keys().cloned().collect());
+            l.apply_user_change(c).unwrap();
+        }
+        let resp = cli
+            .get(&format!(
+                "{}/api/cameras/{}/main/recordings",
+                &s.base_url, s.db.test_camera_uuid
+            ))
+            .header(reqwest::header::COOKIE, cookie.header())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn log_filter_needs_update_permission() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.read_logs = true;
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+        let url = format!("{}/api/logs/filter", &s.base_url);
+        let resp = cli.get(&url).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let before: serde_json::Value = resp.json().await.unwrap();
+
+        let mut p = HashMap::new();
+        p.insert("filter", "trace");
+        let resp = cli.post(&url).json(&p).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+        let after: serde_json::Value = cli.get(&url).send().await.unwrap().json().await.unwrap();
+        assert_eq!(before, after);
+    }
+
     #[tokio::test]
     async fn view_without_segments() {
         testutil::init();
@@ -1759,7 +6281,20 @@ mod bench {
                     ui_dir: None,
                     allow_unauthenticated_permissions: Some(db::Permissions::default()),
                     trust_forward_hdrs: false,
+                    cookie_same_site: super::SameSite::Strict,
+                    secure_cookies: false,
+                    allowed_origins: Vec::new(),
                     time_zone_name: "".to_owned(),
+                    syncer_queues: Default::default(),
+                    health: Default::default(),
+                    events: Default::default(),
+                    logs: None,
+                    record_playback_heat: true,
+                    read_ahead_bytes: None,
+                    load_thresholds: None,
+                    acme_challenges: None,
+                    unix_peer_auth: None,
+                    export_dir: None,
                 })
                 .unwrap(),
             );
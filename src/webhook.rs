@@ -0,0 +1,960 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2020 The Moonfire NVR Authors
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Webhook notifications.
+//!
+//! When the `webhook_urls` key is set in the `config` table (to one or more whitespace-separated
+//! URLs), `moonfire-nvr run` POSTs a JSON notification to each URL when:
+//!
+//! * a signal changes state (`signalChanged`).
+//! * a stream which should be recording hasn't recorded for `webhook_offline_sec` (default 60)
+//!   seconds (`cameraOffline`), and again when it recovers (`cameraOnline`).
+//! * a database flush fails, so recent recordings haven't been saved (`flushFailed`).
+//! * a stream is paused because its sample file directory is full (`diskFull`).
+//! * a streamer has received no frames for `health_silence_sec` (default 10) seconds
+//!   (`streamSilent`), and again when frames resume (`streamResumed`). See `crate::health`.
+//!
+//! Each notification is an object with a `type` and `time90k` (as in the JSON API) plus
+//! type-specific fields; see `Event`. Failed deliveries are retried with exponential backoff.
+//!
+//! Users may also have notification policies (see `db::notify`), editable via
+//! `/api/user/notifications`. A user's `webhookUrl` receives the same notifications, except:
+//!
+//! * `cameraOffline` is sent once a stream has been offline for the user's `offlineAfterSec`,
+//!   and `cameraOnline` only follows a `cameraOffline` sent to that user.
+//! * nothing is sent during the user's quiet hours (in the server's local time zone).
+//!   Notifications aren't deferred until the quiet hours end; they're dropped.
+//!
+//! A policy's escalation emails its address (via `sendmail`, or the program named by the
+//! `notify_sendmail` config key) once a stream has been offline for the given time, even during
+//! quiet hours.
+//!
+//! The dispatcher runs on its own thread and is driven by `base::clock`, polling the database
+//! for changes, so it's testable with simulated clocks.
+
+use crate::health;
+use base::clock::Clocks;
+use db::notify::NotificationPolicy;
+use db::recording;
+use failure::{bail, format_err, Error};
+use fnv::FnvHashMap;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc};
+use std::time::Duration as StdDuration;
+use time::{Duration, Timespec};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+const DEFAULT_OFFLINE_SEC: i64 = 60;
+const DEFAULT_SENDMAIL: &'static str = "/usr/sbin/sendmail";
+
+/// How often to poll the database for changes.
+const POLL_INTERVAL: StdDuration = StdDuration::from_secs(1);
+
+/// The maximum number of delivery attempts for a single notification.
+const MAX_ATTEMPTS: u32 = 10;
+
+/// The maximum delay between retries.
+const MAX_BACKOFF_SEC: i64 = 300;
+
+/// The maximum number of undelivered notifications; the oldest are dropped beyond this.
+const MAX_QUEUED: usize = 1000;
+
+/// Sends a notification body to a URL. This is a trait for testability.
+pub trait Transport {
+    fn post(&self, url: &str, body: &[u8]) -> Result<(), Error>;
+}
+
+/// Sends notifications via HTTP(S).
+pub struct HttpTransport(reqwest::blocking::Client);
+
+impl HttpTransport {
+    pub fn new() -> Result<Self, Error> {
+        Ok(HttpTransport(
+            reqwest::blocking::Client::builder()
+                .timeout(StdDuration::from_secs(10))
+                .build()?,
+        ))
+    }
+}
+
+impl Transport for HttpTransport {
+    fn post(&self, url: &str, body: &[u8]) -> Result<(), Error> {
+        let resp = self
+            .0
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_vec())
+            .send()?;
+        if !resp.status().is_success() {
+            bail!("HTTP status {}", resp.status());
+        }
+        Ok(())
+    }
+}
+
+/// Sends an email message (including headers) to an address. This is a trait for testability.
+pub trait Mailer {
+    fn send(&self, to: &str, message: &[u8]) -> Result<(), Error>;
+}
+
+/// Sends email via a `sendmail`-compatible program.
+pub struct SendmailMailer(String);
+
+impl Mailer for SendmailMailer {
+    fn send(&self, to: &str, message: &[u8]) -> Result<(), Error> {
+        let mut child = Command::new(&self.0)
+            .args(&["-i", "--", to])
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| format_err!("unable to run {}: {}", &self.0, e))?;
+        let result = child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(message);
+        let status = child.wait()?;
+        result?;
+        if !status.success() {
+            bail!("{} failed with {}", &self.0, status);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Event {
+    #[serde(rename_all = "camelCase")]
+    SignalChanged {
+        signal: u32,
+        short_name: String,
+        old_state: u16,
+        new_state: u16,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    CameraOffline {
+        camera: Uuid,
+        short_name: String,
+        stream: &'static str,
+        offline_sec: i64,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    CameraOnline {
+        camera: Uuid,
+        short_name: String,
+        stream: &'static str,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    FlushFailed { failures: u64, error: String },
+
+    #[serde(rename_all = "camelCase")]
+    DiskFull {
+        camera: Uuid,
+        short_name: String,
+        stream: &'static str,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    StreamSilent {
+        camera: Uuid,
+        short_name: String,
+        stream: &'static str,
+        silent_sec: i64,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    StreamResumed {
+        camera: Uuid,
+        short_name: String,
+        stream: &'static str,
+    },
+}
+
+impl Event {
+    /// Returns a human-readable description, for email.
+    fn describe(&self) -> String {
+        match self {
+            Event::SignalChanged {
+                short_name,
+                old_state,
+                new_state,
+                ..
+            } => format!(
+                "signal {} changed from state {} to {}",
+                short_name, old_state, new_state
+            ),
+            Event::CameraOffline {
+                short_name,
+                stream,
+                offline_sec,
+                ..
+            } => format!(
+                "{} {} stream has been offline for {} seconds",
+                short_name, stream, offline_sec
+            ),
+            Event::CameraOnline {
+                short_name, stream, ..
+            } => format!("{} {} stream is back online", short_name, stream),
+            Event::FlushFailed { failures, error } => format!(
+                "database flush has failed {} times; latest error: {}",
+                failures, error
+            ),
+            Event::DiskFull {
+                short_name, stream, ..
+            } => format!(
+                "{} {} stream is paused because its disk is full",
+                short_name, stream
+            ),
+            Event::StreamSilent {
+                short_name,
+                stream,
+                silent_sec,
+                ..
+            } => format!(
+                "{} {} stream has received no frames for {} seconds",
+                short_name, stream, silent_sec
+            ),
+            Event::StreamResumed {
+                short_name, stream, ..
+            } => format!("{} {} stream is receiving frames again", short_name, stream),
+        }
+    }
+
+    /// Returns true for events whose timing depends on per-user policy.
+    fn is_camera_status(&self) -> bool {
+        match self {
+            Event::CameraOffline { .. } | Event::CameraOnline { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Notification<'a> {
+    time_90k: i64,
+
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Target {
+    /// A URL to POST a JSON notification to.
+    Webhook(Arc<String>),
+
+    /// An email address to send a plain text message to.
+    Email(Arc<String>),
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Target::Webhook(u) => f.write_str(u),
+            Target::Email(e) => write!(f, "mailto:{}", e),
+        }
+    }
+}
+
+/// A notification waiting to be delivered to one target.
+struct Delivery {
+    target: Target,
+    body: Arc<Vec<u8>>,
+    attempts: u32,
+
+    /// When to make the next attempt, on the monotonic clock.
+    next_attempt: Timespec,
+}
+
+/// Per-stream state for detecting offline cameras.
+struct StreamState {
+    /// The last time (on the monotonic clock) the stream was known to be recording or started
+    /// being expected to record.
+    last_ok: Timespec,
+    offline: bool,
+    disk_full: bool,
+    silent: bool,
+}
+
+/// Per-user, per-stream state for applying notification policies.
+#[derive(Default)]
+struct UserStreamState {
+    /// If a `cameraOffline` was sent (or suppressed by quiet hours) for the current outage.
+    notified: bool,
+
+    /// If the current outage has been escalated.
+    escalated: bool,
+}
+
+/// The current status of a stream which should be recording, as seen by `poll`.
+struct StreamStatus {
+    id: i32,
+    camera: Uuid,
+    short_name: String,
+    stream: &'static str,
+
+    /// How long the stream has been offline, or `None` if it's recording.
+    offline_for: Option<Duration>,
+}
+
+pub struct Dispatcher<C: Clocks + Clone> {
+    db: Arc<db::Database<C>>,
+    health: health::Monitor,
+    urls: Vec<Arc<String>>,
+    offline_after: Duration,
+    queue: VecDeque<Delivery>,
+    signal_states: BTreeMap<u32, u16>,
+    streams: FnvHashMap<i32, StreamState>,
+    user_streams: FnvHashMap<(i32, i32), UserStreamState>,
+    flush_failures: u64,
+    sendmail: String,
+}
+
+impl<C: Clocks + Clone> Dispatcher<C> {
+    /// Returns a dispatcher as configured in the database, or `None` if neither webhooks nor
+    /// notification policies are configured.
+    pub fn new(db: &Arc<db::Database<C>>, health: &health::Monitor) -> Result<Option<Self>, Error> {
+        let l = db.lock();
+        let urls: Vec<Arc<String>> = match l.get_config("webhook_urls")? {
+            None => Vec::new(),
+            Some(u) => u
+                .split_whitespace()
+                .map(|u| Arc::new(u.to_owned()))
+                .collect(),
+        };
+        if urls.is_empty() {
+            let mut any_policies = false;
+            l.list_notification_policies(&mut |_, _| any_policies = true)?;
+            if !any_policies {
+                return Ok(None);
+            }
+        }
+        for u in &urls {
+            url::Url::parse(u).map_err(|e| format_err!("bad webhook url {:?}: {}", u, e))?;
+        }
+        let offline_sec = match l.get_config("webhook_offline_sec")? {
+            None => DEFAULT_OFFLINE_SEC,
+            Some(s) => {
+                i64::from_str(&s).map_err(|_| format_err!("bad webhook_offline_sec {:?}", s))?
+            }
+        };
+        let sendmail = l
+            .get_config("notify_sendmail")?
+            .unwrap_or_else(|| DEFAULT_SENDMAIL.to_owned());
+        let (flush_failures, _) = l.flush_failures();
+        drop(l);
+        let mut d = Dispatcher {
+            db: db.clone(),
+            health: health.clone(),
+            urls,
+            offline_after: Duration::seconds(offline_sec),
+            queue: VecDeque::new(),
+            signal_states: BTreeMap::new(),
+            streams: FnvHashMap::default(),
+            user_streams: FnvHashMap::default(),
+            flush_failures,
+            sendmail,
+        };
+        d.signal_states = d.current_signal_states();
+        Ok(Some(d))
+    }
+
+    /// Returns a description of the destinations, for logging.
+    pub fn urls(&self) -> String {
+        self.urls
+            .iter()
+            .map(|u| u.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Runs until `shutdown_rx` receives a message or its sender is dropped.
+    pub fn run(mut self, shutdown_rx: mpsc::Receiver<()>) {
+        // Create the HTTP client here, as the blocking client can't be created from within the
+        // tokio runtime.
+        let transport = match HttpTransport::new() {
+            Ok(t) => t,
+            Err(e) => {
+                warn!("webhook: unable to create HTTP client: {}", e);
+                return;
+            }
+        };
+        let mailer = SendmailMailer(self.sendmail.clone());
+        let clocks = self.db.clocks();
+        loop {
+            match clocks.recv_timeout(&shutdown_rx, POLL_INTERVAL) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Ok(()) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+            self.poll();
+            self.deliver(&transport, &mailer);
+        }
+        if !self.queue.is_empty() {
+            warn!(
+                "webhook: dropping {} undelivered notifications on shutdown",
+                self.queue.len()
+            );
+        }
+    }
+
+    fn current_signal_states(&self) -> BTreeMap<u32, u16> {
+        let l = self.db.lock();
+        let mut states: BTreeMap<u32, u16> = l.signals_by_id().keys().map(|&id| (id, 0)).collect();
+        let now = recording::Time::new(self.db.clocks().realtime());
+        l.list_changes_by_time(now..now, &mut |c| {
+            states.insert(c.signal, c.state);
+        });
+        states
+    }
+
+    /// Checks the database for changes, enqueueing notifications for any found.
+    fn poll(&mut self) {
+        let mut events = Vec::new();
+        let mut statuses = Vec::new();
+        let mut policies = Vec::new();
+        let states = self.current_signal_states();
+        let now = self.db.clocks().monotonic();
+        let liveness = self.health.streams();
+        {
+            let l = self.db.lock();
+            for (&id, &new_state) in &states {
+                let old_state = self.signal_states.get(&id).cloned().unwrap_or(0);
+                if old_state != new_state {
+                    events.push(Event::SignalChanged {
+                        signal: id,
+                        short_name: l
+                            .signals_by_id()
+                            .get(&id)
+                            .map(|s| s.short_name.clone())
+                            .unwrap_or_default(),
+                        old_state,
+                        new_state,
+                    });
+                }
+            }
+
+            for s in l.streams_by_id().values() {
+                let c = match l.cameras_by_id().get(&s.camera_id) {
+                    None => continue,
+                    Some(c) => c,
+                };
+                if !s.record {
+                    self.streams.remove(&s.id);
+                    continue;
+                }
+                let state = self.streams.entry(s.id).or_insert(StreamState {
+                    last_ok: now,
+                    offline: false,
+                    disk_full: false,
+                    silent: false,
+                });
+                if s.disk_full && !state.disk_full {
+                    events.push(Event::DiskFull {
+                        camera: c.uuid,
+                        short_name: c.short_name.clone(),
+                        stream: s.type_.as_str(),
+                    });
+                }
+                state.disk_full = s.disk_full;
+                if let Some(silent_for) = liveness.get(&s.id).map(|h| h.silent_for(now)) {
+                    let silent = silent_for >= self.health.silence_after();
+                    if silent && !state.silent {
+                        events.push(Event::StreamSilent {
+                            camera: c.uuid,
+                            short_name: c.short_name.clone(),
+                            stream: s.type_.as_str(),
+                            silent_sec: silent_for.num_seconds(),
+                        });
+                    } else if !silent && state.silent {
+                        events.push(Event::StreamResumed {
+                            camera: c.uuid,
+                            short_name: c.short_name.clone(),
+                            stream: s.type_.as_str(),
+                        });
+                    }
+                    state.silent = silent;
+                }
+                statuses.push(StreamStatus {
+                    id: s.id,
+                    camera: c.uuid,
+                    short_name: c.short_name.clone(),
+                    stream: s.type_.as_str(),
+                    offline_for: if s.is_recording() {
+                        None
+                    } else {
+                        Some(now - state.last_ok)
+                    },
+                });
+                if s.is_recording() {
+                    state.last_ok = now;
+                    if state.offline {
+                        state.offline = false;
+                        events.push(Event::CameraOnline {
+                            camera: c.uuid,
+                            short_name: c.short_name.clone(),
+                            stream: s.type_.as_str(),
+                        });
+                    }
+                } else if !state.offline && now - state.last_ok >= self.offline_after {
+                    state.offline = true;
+                    events.push(Event::CameraOffline {
+                        camera: c.uuid,
+                        short_name: c.short_name.clone(),
+                        stream: s.type_.as_str(),
+                        offline_sec: (now - state.last_ok).num_seconds(),
+                    });
+                }
+            }
+
+            let (failures, error) = l.flush_failures();
+            if failures > self.flush_failures {
+                events.push(Event::FlushFailed {
+                    failures,
+                    error: error.unwrap_or_default().to_owned(),
+                });
+            }
+            self.flush_failures = failures;
+
+            if let Err(e) = l.list_notification_policies(&mut |u, p| policies.push((u, p))) {
+                warn!("webhook: unable to list notification policies: {}", e);
+            }
+        }
+        self.signal_states = states;
+        for e in &events {
+            let targets: Vec<_> = self.urls.iter().cloned().map(Target::Webhook).collect();
+            self.enqueue(&targets, e);
+        }
+        self.apply_policies(&policies, &events, &statuses);
+    }
+
+    /// Enqueues notifications to users as dictated by their policies. `events` are the events
+    /// found by this poll for the server-wide webhooks; `statuses` describe all streams which
+    /// should be recording.
+    fn apply_policies(
+        &mut self,
+        policies: &[(i32, NotificationPolicy)],
+        events: &[Event],
+        statuses: &[StreamStatus],
+    ) {
+        let tm = time::at(self.db.clocks().realtime());
+        let minute = (tm.tm_hour * 60 + tm.tm_min) as u16;
+        let mut to_send = Vec::new();
+        for (user_id, p) in policies {
+            let quiet = p.quiet_hours.map(|q| q.contains(minute)).unwrap_or(false);
+            let webhook = match p.webhook_url {
+                Some(ref u) if !quiet => Some(Target::Webhook(Arc::new(u.clone()))),
+                _ => None,
+            };
+            if let Some(ref w) = webhook {
+                for e in events.iter().filter(|e| !e.is_camera_status()) {
+                    to_send.push((w.clone(), e.clone()));
+                }
+            }
+            let offline_after = p
+                .offline_after_sec
+                .map(Duration::seconds)
+                .unwrap_or(self.offline_after);
+            for s in statuses {
+                let state = self.user_streams.entry((*user_id, s.id)).or_default();
+                let offline_for = match s.offline_for {
+                    None => {
+                        if let (true, Some(w)) = (state.notified, &webhook) {
+                            to_send.push((
+                                w.clone(),
+                                Event::CameraOnline {
+                                    camera: s.camera,
+                                    short_name: s.short_name.clone(),
+                                    stream: s.stream,
+                                },
+                            ));
+                        }
+                        *state = UserStreamState::default();
+                        continue;
+                    }
+                    Some(d) => d,
+                };
+                let offline = Event::CameraOffline {
+                    camera: s.camera,
+                    short_name: s.short_name.clone(),
+                    stream: s.stream,
+                    offline_sec: offline_for.num_seconds(),
+                };
+                if !state.notified && offline_for >= offline_after {
+                    state.notified = true;
+                    if let Some(ref w) = webhook {
+                        to_send.push((w.clone(), offline.clone()));
+                    }
+                }
+                if let Some(ref esc) = p.escalation {
+                    if !state.escalated && offline_for >= Duration::seconds(esc.after_sec) {
+                        state.escalated = true;
+                        to_send.push((Target::Email(Arc::new(esc.email.clone())), offline));
+                    }
+                }
+            }
+        }
+
+        // Forget state for users whose policies were removed and streams no longer recording.
+        self.user_streams.retain(|&(user_id, stream_id), _| {
+            policies.iter().any(|&(u, _)| u == user_id)
+                && statuses.iter().any(|s| s.id == stream_id)
+        });
+
+        for (target, event) in to_send {
+            self.enqueue(&[target], &event);
+        }
+    }
+
+    fn enqueue(&mut self, targets: &[Target], event: &Event) {
+        if targets.is_empty() {
+            return;
+        }
+        info!(
+            "webhook: {:?} to {}",
+            event,
+            targets
+                .iter()
+                .map(Target::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let time_90k = recording::Time::new(self.db.clocks().realtime());
+        let json = Arc::new(
+            serde_json::to_vec(&Notification {
+                time_90k: time_90k.0,
+                event,
+            })
+            .expect("notifications are serializable"),
+        );
+        let now = self.db.clocks().monotonic();
+        for target in targets {
+            let body = match target {
+                Target::Webhook(_) => json.clone(),
+                Target::Email(to) => {
+                    let description = event.describe().replace(char::is_control, " ");
+                    Arc::new(
+                        format!(
+                            "To: {}\r\nSubject: Moonfire NVR: {}\r\n\r\n{} at {}.\r\n",
+                            to, description, description, time_90k
+                        )
+                        .into_bytes(),
+                    )
+                }
+            };
+            if self.queue.len() >= MAX_QUEUED {
+                warn!("webhook: queue is full; dropping oldest notification");
+                self.queue.pop_front();
+            }
+            self.queue.push_back(Delivery {
+                target: target.clone(),
+                body,
+                attempts: 0,
+                next_attempt: now,
+            });
+        }
+    }
+
+    /// Attempts all deliveries which are due, rescheduling those which fail.
+    fn deliver(&mut self, transport: &dyn Transport, mailer: &dyn Mailer) {
+        let clocks = self.db.clocks();
+        let mut i = 0;
+        while i < self.queue.len() {
+            let d = &mut self.queue[i];
+            if d.next_attempt > clocks.monotonic() {
+                i += 1;
+                continue;
+            }
+            d.attempts += 1;
+            let result = match d.target {
+                Target::Webhook(ref url) => transport.post(url, &d.body),
+                Target::Email(ref to) => mailer.send(to, &d.body),
+            };
+            match result {
+                Ok(()) => {
+                    debug!("webhook: delivered to {}", d.target);
+                    self.queue.remove(i);
+                }
+                Err(e) if d.attempts >= MAX_ATTEMPTS => {
+                    warn!(
+                        "webhook: giving up on {} after {} attempts: {}",
+                        d.target, d.attempts, e
+                    );
+                    self.queue.remove(i);
+                }
+                Err(e) => {
+                    let backoff =
+                        Duration::seconds(MAX_BACKOFF_SEC.min(1 << (d.attempts - 1).min(30)));
+                    warn!(
+                        "webhook: delivery to {} failed; will retry in {}: {}",
+                        d.target, backoff, e
+                    );
+                    d.next_attempt = clocks.monotonic() + backoff;
+                    i += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::clock::SimulatedClocks;
+    use db::testutil;
+    use parking_lot::Mutex;
+
+    /// A transport which fails the first `failures` attempts and records the rest.
+    #[derive(Default)]
+    struct FakeTransport {
+        failures: Mutex<u32>,
+        delivered: Mutex<Vec<(String, serde_json::Value)>>,
+    }
+
+    impl Transport for FakeTransport {
+        fn post(&self, url: &str, body: &[u8]) -> Result<(), Error> {
+            let mut f = self.failures.lock();
+            if *f > 0 {
+                *f -= 1;
+                bail!("simulated failure");
+            }
+            self.delivered
+                .lock()
+                .push((url.to_owned(), serde_json::from_slice(body).unwrap()));
+            Ok(())
+        }
+    }
+
+    impl Mailer for FakeTransport {
+        fn send(&self, to: &str, message: &[u8]) -> Result<(), Error> {
+            self.delivered.lock().push((
+                format!("mailto:{}", to),
+                serde_json::Value::String(String::from_utf8(message.to_owned()).unwrap()),
+            ));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn offline_with_retries() {
+        testutil::init();
+        let clocks = SimulatedClocks::new(Timespec::new(1_500_000_000, 0));
+        let tdb = testutil::TestDb::new(clocks.clone());
+        tdb.db
+            .lock()
+            .set_config("webhook_urls", Some("http://a/ http://b/"))
+            .unwrap();
+        tdb.db
+            .lock()
+            .set_config("webhook_offline_sec", Some("30"))
+            .unwrap();
+        let t = FakeTransport::default();
+        let mut d = Dispatcher::new(&tdb.db, &health::Monitor::default())
+            .unwrap()
+            .unwrap();
+
+        // The test stream is expected to record but never does.
+        d.poll();
+        d.deliver(&t, &t);
+        clocks.sleep(Duration::seconds(29));
+        d.poll();
+        d.deliver(&t, &t);
+        assert!(t.delivered.lock().is_empty());
+
+        // Fail both URLs' first attempts, then the first URL's second attempt.
+        *t.failures.lock() = 3;
+        clocks.sleep(Duration::seconds(1));
+        d.poll();
+        d.deliver(&t, &t);
+        assert_eq!(d.queue.len(), 2);
+        clocks.sleep(Duration::seconds(1)); // first retry after 1 second
+        d.deliver(&t, &t);
+        assert_eq!(t.delivered.lock().len(), 1);
+        assert_eq!(t.delivered.lock()[0].0, "http://b/");
+        clocks.sleep(Duration::seconds(1)); // second retry after 2 seconds
+        d.deliver(&t, &t);
+        assert_eq!(t.delivered.lock().len(), 1);
+        clocks.sleep(Duration::seconds(1));
+        d.deliver(&t, &t);
+        assert!(d.queue.is_empty());
+        let delivered = t.delivered.lock();
+        assert_eq!(delivered[1].0, "http://a/");
+        assert_eq!(delivered[1].1["type"], "cameraOffline");
+        assert_eq!(delivered[1].1["offlineSec"], 30);
+        assert_eq!(delivered[1].1["shortName"], "test camera");
+        assert_eq!(delivered[1].1["stream"], "main");
+        drop(delivered);
+
+        // It's only reported once.
+        clocks.sleep(Duration::seconds(60));
+        d.poll();
+        assert!(d.queue.is_empty());
+    }
+
+    #[test]
+    fn policies() {
+        testutil::init();
+
+        // 1500000000 is 19:40 in testutil's America/Los_Angeles.
+        let clocks = SimulatedClocks::new(Timespec::new(1_500_000_000, 0));
+        let tdb = testutil::TestDb::new(clocks.clone());
+        let mut l = tdb.db.lock();
+        let quiet = l
+            .apply_user_change(db::UserChange::add_user("quiet".to_owned()))
+            .unwrap()
+            .id;
+        let loud = l
+            .apply_user_change(db::UserChange::add_user("loud".to_owned()))
+            .unwrap()
+            .id;
+        l.set_notification_policy(
+            quiet,
+            Some(&NotificationPolicy {
+                webhook_url: Some("http://quiet/".to_owned()),
+                quiet_hours: Some(db::notify::QuietHours {
+                    start_min: 19 * 60,
+                    end_min: 7 * 60,
+                }),
+                offline_after_sec: None,
+                escalation: Some(db::notify::Escalation {
+                    after_sec: 1800,
+                    email: "oncall@example.com".to_owned(),
+                }),
+            }),
+        )
+        .unwrap();
+        l.set_notification_policy(
+            loud,
+            Some(&NotificationPolicy {
+                webhook_url: Some("http://loud/".to_owned()),
+                offline_after_sec: Some(300),
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+        drop(l);
+        let t = FakeTransport::default();
+        let mut d = Dispatcher::new(&tdb.db, &health::Monitor::default())
+            .unwrap()
+            .unwrap();
+        d.poll();
+        clocks.sleep(Duration::seconds(299));
+        d.poll();
+        d.deliver(&t, &t);
+        assert!(t.delivered.lock().is_empty());
+
+        // The loud user is notified after 5 minutes; the quiet one not at all.
+        clocks.sleep(Duration::seconds(1));
+        d.poll();
+        d.deliver(&t, &t);
+        {
+            let delivered = t.delivered.lock();
+            assert_eq!(delivered.len(), 1);
+            assert_eq!(delivered[0].0, "http://loud/");
+            assert_eq!(delivered[0].1["type"], "cameraOffline");
+            assert_eq!(delivered[0].1["offlineSec"], 300);
+        }
+
+        // The escalation ignores quiet hours.
+        clocks.sleep(Duration::seconds(1500));
+        d.poll();
+        d.deliver(&t, &t);
+        let delivered = t.delivered.lock();
+        assert_eq!(delivered.len(), 2);
+        assert_eq!(delivered[1].0, "mailto:oncall@example.com");
+        let msg = delivered[1].1.as_str().unwrap();
+        assert!(msg.starts_with(
+            "To: oncall@example.com\r\n\
+             Subject: Moonfire NVR: test camera main stream has been offline for 1800 seconds\r\n"
+        ));
+    }
+
+    #[test]
+    fn silence() {
+        testutil::init();
+        let clocks = SimulatedClocks::new(Timespec::new(1_500_000_000, 0));
+        let tdb = testutil::TestDb::new(clocks.clone());
+        let mut l = tdb.db.lock();
+        l.set_config("webhook_urls", Some("http://a/")).unwrap();
+        l.set_config("webhook_offline_sec", Some("3600")).unwrap();
+        drop(l);
+        let health = health::Monitor::default();
+        let mut tracker = health.tracker(testutil::TEST_STREAM_ID, clocks.monotonic());
+        let t = FakeTransport::default();
+        let mut d = Dispatcher::new(&tdb.db, &health).unwrap().unwrap();
+
+        tracker.connected();
+        tracker.frame(
+            clocks.monotonic(),
+            recording::Time::new(clocks.realtime()),
+            1000,
+        );
+        clocks.sleep(Duration::seconds(9));
+        d.poll();
+        d.deliver(&t, &t);
+        assert!(t.delivered.lock().is_empty());
+
+        // Silence is reported once, then the recovery.
+        clocks.sleep(Duration::seconds(1));
+        d.poll();
+        clocks.sleep(Duration::seconds(5));
+        d.poll();
+        d.deliver(&t, &t);
+        tracker.frame(
+            clocks.monotonic(),
+            recording::Time::new(clocks.realtime()),
+            1000,
+        );
+        d.poll();
+        d.deliver(&t, &t);
+        let delivered = t.delivered.lock();
+        assert_eq!(delivered.len(), 2);
+        assert_eq!(delivered[0].1["type"], "streamSilent");
+        assert_eq!(delivered[0].1["silentSec"], 10);
+        assert_eq!(delivered[0].1["stream"], "main");
+        assert_eq!(delivered[1].1["type"], "streamResumed");
+    }
+
+    #[test]
+    fn unconfigured() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(SimulatedClocks::new(Timespec::new(0, 0)));
+        assert!(Dispatcher::new(&tdb.db, &health::Monitor::default())
+            .unwrap()
+            .is_none());
+    }
+}
@@ -94,6 +94,14 @@ module.exports = (env, args) => {
         threshold: 10240,
         minRatio: 0.8,
       }),
+      new CompressionPlugin({
+        filename: '[path].br[query]',
+        algorithm: 'brotliCompress',
+        test: /\.js$|\.css$|\.html$/,
+        compressionOptions: {level: 11},
+        threshold: 10240,
+        minRatio: 0.8,
+      }),
     ],
   });
 };